@@ -12,17 +12,25 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::collections::HashMap;
 use std::fmt;
+use std::fs::read_dir;
 use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use clap::ArgMatches;
 use futures::future::{ready, BoxFuture};
 use futures::stream::{StreamExt, TryStreamExt};
+use tokio::fs::File;
 use tokio::io::{stdin, stdout, AsyncWriteExt, Stdin};
 
+use file_store::hash::HashAlgorithm;
+use file_store::inventory::{Format, Inventory};
 use file_store::utils::ReaderStream;
 use file_store::{
-    ConnectFuture, ObjectInfo, ObjectPath, StorageBackend, StorageError, TransferError,
+    ConnectFuture, ObjectInfo, ObjectPath, ObjectType, StorageBackend, StorageError, TransferError,
+    UploadInfo,
 };
 
 #[derive(Debug)]
@@ -94,17 +102,214 @@ pub fn ls(
     })
 }
 
+pub fn export(
+    connect: ConnectFuture,
+    args: &ArgMatches<'_>,
+) -> BoxFuture<'static, Result<(), ErrorResult>> {
+    let prefix_arg = args.value_of("prefix").map(String::from);
+    let format = match args.value_of("format") {
+        Some("csv") => Format::Csv,
+        _ => Format::JsonLines,
+    };
+    let algorithm = match args.value_of("hash") {
+        Some("sha1") => Some(HashAlgorithm::Sha1),
+        Some("md5") => Some(HashAlgorithm::Md5),
+        _ => None,
+    };
+
+    Box::pin(async move {
+        let fs = connect.await?;
+        let prefix = match prefix_arg {
+            Some(p) => ObjectPath::new(p)?,
+            None => ObjectPath::empty(),
+        };
+
+        let mut inventory = Inventory::new(format);
+        if let Some(algorithm) = algorithm {
+            inventory = inventory.hashes(fs.clone(), algorithm);
+        }
+
+        let mut stream = inventory.export(fs.list_objects(prefix).await?);
+        let mut stdout = stdout();
+        loop {
+            match stream.next().await {
+                Some(Ok(data)) => {
+                    stdout.write_all(&data).await?;
+                }
+                Some(Err(e)) => return Err(e.into()),
+                None => return Ok(()),
+            }
+        }
+    })
+}
+
+pub fn get(
+    connect: ConnectFuture,
+    args: &ArgMatches<'_>,
+) -> BoxFuture<'static, Result<(), ErrorResult>> {
+    let path = args.value_of("PATH").map(String::from).unwrap();
+    let file = args.value_of("FILE").map(PathBuf::from);
+
+    Box::pin(async move {
+        let fs = connect.await?;
+        let path = ObjectPath::new(path)?;
+        let target = match file {
+            Some(f) => f,
+            None => match path.parts().last() {
+                Some(name) => PathBuf::from(name),
+                None => {
+                    return Err(ErrorResult {
+                        message: String::from("Unable to determine a local file name."),
+                    })
+                }
+            },
+        };
+
+        let mut stream = fs.get_file_stream(path).await?;
+        let mut file = File::create(target).await?;
+        while let Some(data) = stream.next().await {
+            file.write_all(&data?).await?;
+        }
+        file.flush().await?;
+        Ok(())
+    })
+}
+
 pub fn put(
     connect: ConnectFuture,
     args: &ArgMatches<'_>,
 ) -> BoxFuture<'static, Result<(), ErrorResult>> {
     let path = args.value_of("PATH").map(String::from).unwrap();
+    let file = args.value_of("FILE").map(PathBuf::from);
 
     Box::pin(async move {
         let fs = connect.await?;
         let path = ObjectPath::new(path)?;
-        let stream = ReaderStream::<Stdin>::stream(stdin(), 1_000_000, 500_000);
-        fs.write_file_from_stream(path, stream).await?;
+        match file {
+            Some(file) => upload(&fs, &file, UploadInfo::from(path)).await?,
+            None => {
+                let stream = ReaderStream::<Stdin>::stream(stdin(), 1_000_000, 500_000);
+                fs.write_file_from_stream(path, stream).await?;
+            }
+        }
+        Ok(())
+    })
+}
+
+pub fn cp(
+    connect: ConnectFuture,
+    args: &ArgMatches<'_>,
+) -> BoxFuture<'static, Result<(), ErrorResult>> {
+    let source = args.value_of("SOURCE").map(String::from).unwrap();
+    let target = args.value_of("TARGET").map(String::from).unwrap();
+
+    Box::pin(async move {
+        let fs = connect.await?;
+        let source = ObjectPath::new(source)?;
+        let target = ObjectPath::new(target)?;
+
+        Ok(fs.copy_file(source, target).await?)
+    })
+}
+
+async fn upload<B>(fs: &B, file: &Path, info: UploadInfo) -> Result<(), ErrorResult>
+where
+    B: StorageBackend,
+{
+    let file = File::open(file.to_owned()).await?;
+    let stream = ReaderStream::<File>::stream(file, 1_000_000, 500_000);
+    fs.write_file_from_stream(info, stream).await?;
+    Ok(())
+}
+
+fn local_files(
+    dir: &Path,
+    base: &ObjectPath,
+    files: &mut Vec<(PathBuf, ObjectPath)>,
+) -> io::Result<()> {
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        let name = match entry.file_name().into_string() {
+            Ok(n) => n,
+            Err(_) => continue,
+        };
+
+        let mut path = base.clone();
+        path.push_part(&name);
+
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            local_files(&entry.path(), &path, files)?;
+        } else if file_type.is_file() {
+            files.push((entry.path(), path));
+        }
+    }
+
+    Ok(())
+}
+
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+pub fn sync(
+    connect: ConnectFuture,
+    args: &ArgMatches<'_>,
+) -> BoxFuture<'static, Result<(), ErrorResult>> {
+    let dir = args.value_of("DIR").map(PathBuf::from).unwrap();
+    let prefix = args.value_of("PREFIX").map(String::from);
+    let dry_run = args.is_present("dry-run");
+
+    Box::pin(async move {
+        let fs = connect.await?;
+        let prefix = match prefix {
+            Some(p) => ObjectPath::new(p)?,
+            None => ObjectPath::empty(),
+        };
+
+        let mut files = Vec::new();
+        local_files(&dir, &prefix, &mut files)?;
+
+        let mut remote: HashMap<ObjectPath, (u64, Option<u64>)> = HashMap::new();
+        let mut list_prefix = prefix.clone();
+        if !list_prefix.is_empty() {
+            list_prefix = ObjectPath::new(format!("{}/", list_prefix))?;
+        }
+        let mut stream = fs.list_objects(list_prefix).await?;
+        while let Some(object) = stream.next().await {
+            let object = object?;
+            if object.object_type() == ObjectType::File {
+                remote.insert(
+                    object.path(),
+                    (object.len(), object.modified().map(seconds)),
+                );
+            }
+        }
+
+        for (file, path) in files {
+            let metadata = file.metadata()?;
+            let modified = metadata.modified().ok();
+            let unchanged = match remote.get(&path) {
+                Some((len, remote_modified)) => {
+                    *len == metadata.len() && *remote_modified == modified.map(seconds)
+                }
+                None => false,
+            };
+
+            if unchanged {
+                continue;
+            }
+
+            println!("{}", path);
+            if !dry_run {
+                let mut info = UploadInfo::from(path);
+                info.modified = modified;
+                upload(&fs, &file, info).await?;
+            }
+        }
+
         Ok(())
     })
 }
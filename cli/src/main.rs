@@ -97,7 +97,11 @@ fn main() {
 
     let future = match backend_args.subcommand() {
         ("ls", Some(args)) => ls(fsfuture, args),
+        ("export", Some(args)) => export(fsfuture, args),
+        ("get", Some(args)) => get(fsfuture, args),
         ("put", Some(args)) => put(fsfuture, args),
+        ("cp", Some(args)) => cp(fsfuture, args),
+        ("sync", Some(args)) => sync(fsfuture, args),
         ("cat", Some(args)) => cat(fsfuture, args),
         ("rm", Some(args)) => rm(fsfuture, args),
         _ => {
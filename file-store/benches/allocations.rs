@@ -0,0 +1,99 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Measures the memory allocated while transferring 1GB through the file
+//! backend. Run with `cargo bench --bench allocations`.
+//!
+//! Ideally the amount allocated is a small fraction of the data transferred.
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use futures::stream::{iter, StreamExt};
+use tempfile::tempdir;
+use tokio::runtime::Runtime;
+
+use file_store::backends::file::FileBackend;
+use file_store::*;
+
+const MB: usize = 1024 * 1024;
+const CHUNK_SIZE: usize = MB;
+const CHUNK_COUNT: usize = 1024;
+
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::SeqCst);
+        ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn measure<F>(name: &str, runtime: &Runtime, future: F)
+where
+    F: std::future::Future<Output = ()>,
+{
+    let allocated = ALLOCATED.load(Ordering::SeqCst);
+    let allocations = ALLOCATIONS.load(Ordering::SeqCst);
+    let start = Instant::now();
+
+    runtime.block_on(future);
+
+    let elapsed = start.elapsed();
+    let allocated = ALLOCATED.load(Ordering::SeqCst) - allocated;
+    let allocations = ALLOCATIONS.load(Ordering::SeqCst) - allocations;
+    println!(
+        "{}: {} allocations, {}MB allocated for {}MB transferred in {:?}",
+        name,
+        allocations,
+        allocated / MB,
+        CHUNK_SIZE * CHUNK_COUNT / MB,
+        elapsed
+    );
+}
+
+fn main() {
+    let temp = tempdir().unwrap();
+    let runtime = Runtime::new().unwrap();
+    let fs = runtime.block_on(FileBackend::connect(temp.path())).unwrap();
+
+    let chunk = Data::from(vec![0x5a; CHUNK_SIZE]);
+
+    measure("write", &runtime, async {
+        let stream = iter((0..CHUNK_COUNT).map(|_| Ok::<_, StorageError>(chunk.clone())));
+        fs.write_file_from_stream("largefile", stream)
+            .await
+            .unwrap();
+    });
+
+    measure("read", &runtime, async {
+        let mut stream = fs.get_file_stream("largefile").await.unwrap();
+        let mut length = 0;
+        while let Some(data) = stream.next().await {
+            length += data.unwrap().len();
+        }
+        assert_eq!(length, CHUNK_SIZE * CHUNK_COUNT);
+    });
+}
@@ -0,0 +1,77 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+
+use std::str;
+
+use file_store::ObjectPath;
+
+fuzz_target!(|data: &[u8]| {
+    let s = match str::from_utf8(data) {
+        Ok(s) => s,
+        Err(_) => return,
+    };
+
+    let path = match ObjectPath::new(s) {
+        Ok(path) => path,
+        Err(_) => {
+            assert!(s.starts_with('/'));
+            return;
+        }
+    };
+
+    assert_eq!(path.to_string(), s);
+    assert_eq!(path.parts().join("/"), s);
+
+    // Popping or unshifting every part must give back the same parts.
+    let mut popped = Vec::new();
+    let mut remaining = path.clone();
+    while let Some(part) = remaining.pop_part() {
+        popped.insert(0, part);
+    }
+    assert!(remaining.is_empty());
+    assert_eq!(popped, path.parts());
+
+    let mut unshifted = Vec::new();
+    let mut remaining = path.clone();
+    while let Some(part) = remaining.unshift_part() {
+        unshifted.push(part);
+    }
+    assert!(remaining.is_empty());
+    // Unshifting the last part of a directory prefix leaves an empty path so
+    // the trailing empty part is never returned.
+    if !s.ends_with('/') {
+        assert_eq!(unshifted, path.parts());
+    }
+
+    // Splitting at any separator and joining again gives back the path.
+    for (pos, _) in s.match_indices('/') {
+        let prefix = ObjectPath::new(&s[..pos]).unwrap();
+        let suffix = match ObjectPath::new(&s[pos + 1..]) {
+            Ok(suffix) => suffix,
+            Err(_) => continue,
+        };
+
+        if prefix.is_empty() || suffix.is_empty() {
+            continue;
+        }
+
+        let joined = prefix.join(&suffix);
+        assert_eq!(joined, path);
+        assert!(joined.starts_with(&prefix));
+    }
+});
@@ -0,0 +1,452 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Archiving a prefix into a single tar object and extracting it again.
+//!
+//! [`archive`](fn.archive.html) streams every file under a prefix into a
+//! ustar archive written to a store and [`extract`](fn.extract.html) streams
+//! the files in such an archive back out into a prefix. Neither holds more
+//! than a few chunks of data in memory at once so archives of any size can be
+//! moved between any backends.
+//!
+//! Only the path, size and modification time of files are stored. Paths too
+//! long for the ustar format cannot be archived. When extracting, entries
+//! other than regular files are skipped.
+use std::cmp::min;
+use std::convert::TryInto;
+use std::str;
+use std::time::{Duration, UNIX_EPOCH};
+
+use futures::channel::mpsc;
+use futures::future::join;
+use futures::sink::SinkExt;
+use futures::stream::{iter, StreamExt, TryStreamExt};
+
+use crate::sync::{directory, list_files, seconds};
+use crate::types::stream::LengthCheckedStream;
+use crate::types::*;
+use crate::utils::DEFAULT_WRITE_BUFFER_DEPTH;
+use crate::{ObjectInfo, StorageBackend};
+
+/// The size of tar headers, file contents are padded to a multiple of this.
+const BLOCK: usize = 512;
+
+/// Future returned by [`archive`](fn.archive.html).
+pub type ArchiveFuture = WrappedFuture<Result<(), TransferError>>;
+/// Future returned by [`extract`](fn.extract.html).
+pub type ExtractFuture = WrappedFuture<Result<Vec<ObjectPath>, TransferError>>;
+
+fn padding(len: u64) -> usize {
+    (BLOCK - (len % BLOCK as u64) as usize) % BLOCK
+}
+
+/// Writes a number to a header field as octal or, if it is too large, in the
+/// GNU base-256 encoding.
+fn set_number(field: &mut [u8], value: u64) {
+    let digits = field.len() - 1;
+    if value < 1 << (3 * digits) {
+        let text = format!("{:0width$o}", value, width = digits);
+        field[..digits].copy_from_slice(text.as_bytes());
+        field[digits] = 0;
+    } else {
+        for (i, byte) in field.iter_mut().rev().enumerate() {
+            *byte = if i < 8 { (value >> (8 * i)) as u8 } else { 0 };
+        }
+        field[0] |= 0x80;
+    }
+}
+
+fn number(field: &[u8]) -> Option<u64> {
+    if field[0] & 0x80 != 0 {
+        let mut value = u64::from(field[0] & 0x7f);
+        for byte in &field[1..] {
+            value = value.checked_mul(256)? + u64::from(*byte);
+        }
+        Some(value)
+    } else {
+        let text = str::from_utf8(field).ok()?;
+        let text = text.trim_matches(|c| c == '\0' || c == ' ');
+        if text.is_empty() {
+            Some(0)
+        } else {
+            u64::from_str_radix(text, 8).ok()
+        }
+    }
+}
+
+fn field_text(field: &[u8]) -> Option<&str> {
+    let end = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    str::from_utf8(&field[..end]).ok()
+}
+
+fn checksum(header: &[u8]) -> u64 {
+    header
+        .iter()
+        .enumerate()
+        .map(|(i, b)| {
+            if i >= 148 && i < 156 {
+                u64::from(b' ')
+            } else {
+                u64::from(*b)
+            }
+        })
+        .sum()
+}
+
+/// Splits a path into the ustar prefix and name fields.
+fn split_name(path: &str) -> Option<(&str, &str)> {
+    if path.len() <= 100 {
+        return Some(("", path));
+    }
+
+    path.match_indices('/')
+        .map(|(pos, _)| (&path[..pos], &path[pos + 1..]))
+        .find(|(prefix, name)| prefix.len() <= 155 && name.len() <= 100 && !name.is_empty())
+}
+
+fn header(name: &str, object: &Object) -> StorageResult<Data> {
+    let (prefix, name) = split_name(name).ok_or_else(|| {
+        error::invalid_path(
+            object.path(),
+            Some("The path is too long to store in an archive."),
+        )
+    })?;
+
+    let mut header = vec![0; BLOCK];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    set_number(&mut header[100..108], 0o644);
+    set_number(&mut header[108..116], 0);
+    set_number(&mut header[116..124], 0);
+    set_number(&mut header[124..136], object.len());
+    set_number(
+        &mut header[136..148],
+        object.modified().map(seconds).unwrap_or(0),
+    );
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+    let sum = format!("{:06o}\0 ", checksum(&header));
+    header[148..156].copy_from_slice(sum.as_bytes());
+    Ok(Data::from(header))
+}
+
+/// Streams the files under `prefix` in `source` into a tar archive at `path`
+/// in `target`.
+///
+/// Files are archived in path order with paths relative to the prefix. A file
+/// that shrinks while it is being archived makes the archive fail and anything
+/// added to a file after the prefix was listed is left out.
+pub fn archive<S, T, P, Q>(source: &S, prefix: P, target: &T, path: Q) -> ArchiveFuture
+where
+    S: StorageBackend + Sync,
+    T: StorageBackend + Sync,
+    P: TryInto<ObjectPath>,
+    P::Error: Into<StorageError>,
+    Q: TryInto<UploadInfo>,
+    Q::Error: Into<StorageError>,
+{
+    async fn run<S, T>(
+        source: S,
+        prefix: ObjectPath,
+        target: T,
+        mut info: UploadInfo,
+    ) -> Result<(), TransferError>
+    where
+        S: StorageBackend + Sync,
+        T: StorageBackend + Sync,
+    {
+        let mut files: Vec<(String, Object)> = list_files(&source, &prefix)
+            .await
+            .map_err(TransferError::SourceError)?
+            .into_iter()
+            .collect();
+        files.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut entries = Vec::new();
+        let mut length = 2 * BLOCK as u64;
+        for (name, object) in files {
+            let header = header(&name, &object).map_err(TransferError::SourceError)?;
+            length += BLOCK as u64 + object.len() + padding(object.len()) as u64;
+            entries.push((header, object));
+        }
+
+        let stream = iter(entries)
+            .then(move |(header, object)| {
+                let source = source.clone();
+                async move {
+                    let len = object.len();
+                    let mut remaining = len;
+                    // Anything beyond the expected length would corrupt the
+                    // archive.
+                    let data =
+                        LengthCheckedStream::new(source.get_file_stream(object.path()).await?, len)
+                            .map_ok(move |mut data| {
+                                if data.len() as u64 > remaining {
+                                    data.truncate(remaining as usize);
+                                }
+                                remaining -= data.len() as u64;
+                                data
+                            });
+
+                    Ok::<_, StorageError>(
+                        iter(vec![Ok(header)])
+                            .chain(data)
+                            .chain(iter(vec![Ok(Data::from(vec![0; padding(len)]))])),
+                    )
+                }
+            })
+            .try_flatten()
+            .chain(iter(vec![Ok(Data::from(vec![0; 2 * BLOCK]))]));
+
+        info.options.content_length = Some(length);
+        target.write_file_from_stream(info, stream).await
+    }
+
+    let prefix = match prefix.try_into().map_err(Into::into).and_then(directory) {
+        Ok(p) => p,
+        Err(e) => return ArchiveFuture::from_value(Err(TransferError::SourceError(e))),
+    };
+
+    let info = match path.try_into() {
+        Ok(i) => i,
+        Err(e) => return ArchiveFuture::from_value(Err(TransferError::TargetError(e.into()))),
+    };
+
+    ArchiveFuture::from_future(run(source.clone(), prefix, target.clone(), info))
+}
+
+/// An entry in a tar archive.
+struct Entry {
+    path: String,
+    len: u64,
+    modified: u64,
+    kind: u8,
+}
+
+impl Entry {
+    fn parse(header: &[u8]) -> StorageResult<Entry> {
+        let invalid = || error::invalid_data(Some("The archive is not a valid tar file."));
+
+        if number(&header[148..156]) != Some(checksum(header)) {
+            return Err(invalid());
+        }
+
+        let name = field_text(&header[..100]).ok_or_else(invalid)?;
+        let prefix = if &header[257..263] == b"ustar\0" {
+            field_text(&header[345..500]).ok_or_else(invalid)?
+        } else {
+            ""
+        };
+
+        Ok(Entry {
+            path: if prefix.is_empty() {
+                name.to_owned()
+            } else {
+                format!("{}/{}", prefix, name)
+            },
+            len: number(&header[124..136]).ok_or_else(invalid)?,
+            modified: number(&header[136..148]).ok_or_else(invalid)?,
+            kind: header[156],
+        })
+    }
+
+    fn is_file(&self) -> bool {
+        (self.kind == b'0' || self.kind == 0 || self.kind == b'7') && !self.path.ends_with('/')
+    }
+
+    /// Gets the path to extract the entry to, refusing anything that would
+    /// end up outside of the target prefix.
+    fn relative(&self) -> StorageResult<ObjectPath> {
+        let parts: Vec<&str> = self
+            .path
+            .split('/')
+            .filter(|p| !p.is_empty() && *p != ".")
+            .collect();
+        if parts.is_empty() || parts.contains(&"..") {
+            return Err(error::invalid_data(Some(&format!(
+                "The archive contains an invalid path: {}",
+                self.path
+            ))));
+        }
+
+        ObjectPath::new(parts.join("/"))
+    }
+}
+
+/// Reads an archive a piece at a time.
+struct Reader {
+    stream: DataStream,
+    pending: Data,
+}
+
+impl Reader {
+    /// Waits for more data, returning false if the archive has ended.
+    async fn more(&mut self) -> StorageResult<bool> {
+        while self.pending.is_empty() {
+            match self.stream.next().await {
+                Some(data) => self.pending = data?,
+                None => return Ok(false),
+            }
+        }
+        Ok(true)
+    }
+
+    async fn fill(&mut self) -> StorageResult<()> {
+        if self.more().await? {
+            Ok(())
+        } else {
+            Err(error::invalid_data(Some("The archive ended unexpectedly.")))
+        }
+    }
+
+    async fn read(&mut self, len: usize) -> StorageResult<Vec<u8>> {
+        let mut buffer = Vec::with_capacity(len);
+        while buffer.len() < len {
+            self.fill().await?;
+            let count = min(len - buffer.len(), self.pending.len());
+            buffer.extend_from_slice(&self.pending.split_to(count));
+        }
+        Ok(buffer)
+    }
+
+    async fn skip(&mut self, mut len: u64) -> StorageResult<()> {
+        while len > 0 {
+            self.fill().await?;
+            let count = min(len, self.pending.len() as u64);
+            self.pending.split_to(count as usize);
+            len -= count;
+        }
+        Ok(())
+    }
+
+    /// Passes the next `len` bytes to `sender`.
+    async fn forward(
+        &mut self,
+        mut len: u64,
+        mut sender: mpsc::Sender<StorageResult<Data>>,
+    ) -> StorageResult<()> {
+        while len > 0 {
+            if let Err(e) = self.fill().await {
+                // Make sure the partial file isn't kept.
+                let _ = sender
+                    .send(Err(error::cancelled(Some("Reading the archive failed."))))
+                    .await;
+                return Err(e);
+            }
+
+            let count = min(len, self.pending.len() as u64);
+            len -= count;
+            if sender
+                .send(Ok(self.pending.split_to(count as usize)))
+                .await
+                .is_err()
+            {
+                // The write has failed.
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Extracts the files in the tar archive at `path` in `source` to `prefix` in
+/// `target`, replacing any files already there.
+///
+/// Returns the paths of the extracted files relative to the prefix. Files are
+/// extracted one at a time in the order they appear in the archive and the
+/// first failure stops the extraction.
+pub fn extract<S, T, P, Q>(source: &S, path: P, target: &T, prefix: Q) -> ExtractFuture
+where
+    S: StorageBackend + Sync,
+    T: StorageBackend + Sync,
+    P: TryInto<ObjectPath>,
+    P::Error: Into<StorageError>,
+    Q: TryInto<ObjectPath>,
+    Q::Error: Into<StorageError>,
+{
+    async fn run<S, T>(
+        source: S,
+        path: ObjectPath,
+        target: T,
+        prefix: ObjectPath,
+    ) -> Result<Vec<ObjectPath>, TransferError>
+    where
+        S: StorageBackend + Sync,
+        T: StorageBackend + Sync,
+    {
+        let mut reader = Reader {
+            stream: source
+                .get_file_stream(path)
+                .await
+                .map_err(TransferError::SourceError)?,
+            pending: Data::new(),
+        };
+
+        let mut extracted = Vec::new();
+        // Some archivers leave off the empty blocks that mark the end.
+        while reader.more().await.map_err(TransferError::SourceError)? {
+            let header = reader
+                .read(BLOCK)
+                .await
+                .map_err(TransferError::SourceError)?;
+            if header.iter().all(|b| *b == 0) {
+                break;
+            }
+
+            let entry = Entry::parse(&header).map_err(TransferError::SourceError)?;
+            if entry.is_file() {
+                let relative = entry.relative().map_err(TransferError::SourceError)?;
+                let mut info = UploadInfo::from(prefix.join(&relative));
+                info.modified = Some(UNIX_EPOCH + Duration::from_secs(entry.modified));
+                info.options.content_length = Some(entry.len);
+
+                let (sender, receiver) = mpsc::channel(DEFAULT_WRITE_BUFFER_DEPTH);
+                let (written, forwarded) = join(
+                    target.write_file_from_stream(info, receiver),
+                    reader.forward(entry.len, sender),
+                )
+                .await;
+                forwarded.map_err(TransferError::SourceError)?;
+                written?;
+                extracted.push(relative);
+            } else {
+                reader
+                    .skip(entry.len)
+                    .await
+                    .map_err(TransferError::SourceError)?;
+            }
+
+            reader
+                .skip(padding(entry.len) as u64)
+                .await
+                .map_err(TransferError::SourceError)?;
+        }
+
+        Ok(extracted)
+    }
+
+    let path = match path.try_into() {
+        Ok(p) => p,
+        Err(e) => return ExtractFuture::from_value(Err(TransferError::SourceError(e.into()))),
+    };
+
+    let prefix = match prefix.try_into().map_err(Into::into).and_then(directory) {
+        Ok(p) => p,
+        Err(e) => return ExtractFuture::from_value(Err(TransferError::TargetError(e))),
+    };
+
+    ExtractFuture::from_future(run(source.clone(), path, target.clone(), prefix))
+}
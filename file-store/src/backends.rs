@@ -18,12 +18,38 @@
 //! Normally you just crate a [`FileStore`](../enum.FileStore.html) from the
 //! backend and then everything else is done by calls to the `FileStore` which
 //! generally behave the same regardless of the backend.
+//!
+//! [`connect_url`](fn.connect_url.html) connects to a backend described by a
+//! url, picking the backend from the url's scheme. The "file" scheme takes
+//! the path to the root directory, `file:///srv/files`, and the "b2" scheme
+//! takes the application key and an optional prefix,
+//! `b2://key_id:key@bucket/dir`. Crates that implement their own backends can
+//! add schemes with [`register_scheme`](fn.register_scheme.html):
+//!
+//! ```no_run
+//! # use file_store::backends::{connect_url, register_scheme};
+//! # use file_store::ConnectFuture;
+//! # fn connect_memory(url: &str) -> ConnectFuture { unimplemented!() }
+//! # async fn example() {
+//! register_scheme("memory", connect_memory);
+//! let store = connect_url("memory://test").await.unwrap();
+//! # }
+//! ```
 #[cfg(feature = "b2")]
 pub mod b2;
 #[cfg(feature = "file")]
 pub mod file;
 
+use std::collections::HashMap;
 use std::fmt;
+use std::sync::{Arc, RwLock};
+
+use lazy_static::lazy_static;
+#[cfg(feature = "b2")]
+use percent_encoding::percent_decode_str;
+
+use crate::types::error;
+use crate::types::*;
 
 /// An enumeration of the available backends.
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -34,6 +60,8 @@ pub enum Backend {
     #[cfg(feature = "b2")]
     /// The [b2 backend](b2/index.html). Included with the "b2" feature.
     B2,
+    /// A backend implemented outside of this crate, identified by name.
+    Custom(&'static str),
 }
 
 impl fmt::Display for Backend {
@@ -43,6 +71,132 @@ impl fmt::Display for Backend {
             Backend::File => f.pad("file"),
             #[cfg(feature = "b2")]
             Backend::B2 => f.pad("b2"),
+            Backend::Custom(name) => f.pad(name),
         }
     }
 }
+
+type SchemeConnector = Arc<dyn Fn(&str) -> ConnectFuture + Send + Sync>;
+
+lazy_static! {
+    static ref SCHEMES: RwLock<HashMap<String, SchemeConnector>> = {
+        let mut schemes: HashMap<String, SchemeConnector> = HashMap::new();
+        #[cfg(feature = "file")]
+        schemes.insert(String::from("file"), Arc::new(connect_file));
+        #[cfg(feature = "b2")]
+        schemes.insert(String::from("b2"), Arc::new(connect_b2));
+        RwLock::new(schemes)
+    };
+}
+
+/// Splits a url into its lowercased scheme and the rest with any leading
+/// `//` removed.
+fn split_url(url: &str) -> StorageResult<(String, &str)> {
+    match url.find(':') {
+        Some(pos) if pos > 0 => {
+            let rest = &url[pos + 1..];
+            Ok((
+                url[..pos].to_lowercase(),
+                if rest.starts_with("//") {
+                    &rest[2..]
+                } else {
+                    rest
+                },
+            ))
+        }
+        _ => Err(error::invalid_settings(Some(&format!(
+            "The url '{}' has no scheme.",
+            url
+        )))),
+    }
+}
+
+#[cfg(feature = "file")]
+fn connect_file(url: &str) -> ConnectFuture {
+    match split_url(url) {
+        Ok((_, path)) => file::FileBackend::connect(std::path::Path::new(path)),
+        Err(e) => ConnectFuture::from_value(Err(e)),
+    }
+}
+
+#[cfg(feature = "b2")]
+fn connect_b2(url: &str) -> ConnectFuture {
+    fn builder(url: &str) -> StorageResult<b2::B2BackendBuilder> {
+        let invalid =
+            |detail: &str| error::invalid_settings(Some(&format!("Invalid b2 url: {}", detail)));
+        let decode = |s: &str| -> StorageResult<String> {
+            percent_decode_str(s)
+                .decode_utf8()
+                .map(|s| s.into_owned())
+                .map_err(|e| invalid(&e.to_string()))
+        };
+
+        let (_, rest) = split_url(url)?;
+        let pos = match rest.rfind('@') {
+            Some(pos) => pos,
+            None => return Err(invalid("no application key given")),
+        };
+
+        let (key_id, key) = match rest[..pos].find(':') {
+            Some(split) => (&rest[..split], &rest[split + 1..pos]),
+            None => return Err(invalid("no application key given")),
+        };
+
+        let builder = b2::B2Backend::builder(&decode(key_id)?, &decode(key)?);
+        let prefix = decode(&rest[pos + 1..])?;
+        if prefix.is_empty() {
+            Ok(builder)
+        } else {
+            Ok(builder.prefix(ObjectPath::new(prefix)?))
+        }
+    }
+
+    match builder(url) {
+        Ok(builder) => builder.connect(),
+        Err(e) => ConnectFuture::from_value(Err(e)),
+    }
+}
+
+/// Adds support for a url scheme to [`connect_url`](fn.connect_url.html).
+///
+/// The connector is passed the entire url. Schemes are not case sensitive
+/// and registering a scheme that is already registered, including the
+/// built-in ones, replaces it.
+pub fn register_scheme<F>(scheme: &str, connector: F)
+where
+    F: Fn(&str) -> ConnectFuture + Send + Sync + 'static,
+{
+    let mut schemes = match SCHEMES.write() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    schemes.insert(scheme.to_lowercase(), Arc::new(connector));
+}
+
+/// Connects to the backend described by a url.
+///
+/// See the [`backends`](index.html) module for the supported schemes. Fails
+/// with an [`InvalidSettings`](../enum.StorageErrorKind.html#variant.InvalidSettings)
+/// error if no backend is registered for the url's scheme.
+pub fn connect_url(url: &str) -> ConnectFuture {
+    let scheme = match split_url(url) {
+        Ok((scheme, _)) => scheme,
+        Err(e) => return ConnectFuture::from_value(Err(e)),
+    };
+
+    let connector = {
+        let schemes = match SCHEMES.read() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        schemes.get(&scheme).cloned()
+    };
+
+    match connector {
+        Some(connector) => connector(url),
+        None => ConnectFuture::from_value(Err(error::invalid_settings(Some(&format!(
+            "No backend is registered for the scheme '{}'.",
+            scheme
+        ))))),
+    }
+}
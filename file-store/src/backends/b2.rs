@@ -31,7 +31,10 @@
 //! In order to be compatible with other backends, but still include some useful
 //! functionality file versioning (if enabled for the bucket) is currently
 //! handled as follows:
-//! * Deleting a file will delete all of its versions.
+//! * Deleting a file will delete all of its versions, or hide the file if the
+//!   backend was built with
+//!   [`hide_on_delete`](struct.B2BackendBuilder.html#method.hide_on_delete).
+//! * Hidden files are not listed and cannot be read.
 //! * Replacing a file will add a new version.
 //!
 //! Setting a file's mimetype on upload is not currently supported. The backend
@@ -41,13 +44,42 @@
 //!
 //! The last modified time of an uploaded file will be set to the time that the
 //! upload began.
+//!
+//! Bucket settings such as the bucket type and CORS rules can be read and
+//! changed with [`bucket_settings`](struct.B2Backend.html#method.bucket_settings)
+//! and [`update_bucket`](struct.B2Backend.html#method.update_bucket), for
+//! instance to let browsers upload files directly to a bucket.
+//!
+//! Large file uploads that fail without
+//! [`delete_on_failure`](../../struct.WriteOptions.html#structfield.delete_on_failure)
+//! leave their parts behind in B2. These can be found with
+//! [`unfinished_uploads`](struct.B2Backend.html#method.unfinished_uploads) and
+//! cleaned up with
+//! [`cancel_unfinished_uploads`](struct.B2Backend.html#method.cancel_unfinished_uploads).
+//!
+//! With the "wasm" feature the backend also builds for `wasm32-unknown-unknown`
+//! and sends its requests with the JavaScript runtime's `fetch`, for example to
+//! upload directly to a bucket from a browser. See the
+//! [`transport`](../../transport/index.html) module for what differs there.
 
+mod buckets;
 mod client;
+mod uploads;
+
+pub use buckets::{BucketSettings, BucketSettingsFuture, BucketType, BucketUpdate, CorsRule};
+pub use uploads::{UnfinishedUpload, UnfinishedUploadsFuture};
 
+/// Future returned by [`B2Backend::presigned_url`](struct.B2Backend.html#method.presigned_url).
+pub type PresignedUrlFuture = WrappedFuture<StorageResult<String>>;
+
+use std::collections::HashMap;
 use std::convert::{Infallible, TryInto};
 use std::future::Future;
+use std::net::IpAddr;
+use std::ops::Range;
 use std::pin::Pin;
 use std::slice::Iter;
+use std::sync::Arc;
 use std::task::{Context, Poll};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
@@ -55,35 +87,41 @@ use bytes::IntoBuf;
 use futures::channel::mpsc::{channel, Sender};
 use futures::future::{ready, TryFutureExt};
 use futures::sink::SinkExt;
-use futures::stream::{Stream, StreamExt, TryStreamExt};
-use hyper::client::connect::HttpConnector;
-use hyper::client::Client as HyperClient;
-use hyper_tls::HttpsConnector;
+use futures::stream::{empty, iter, Stream, StreamExt, TryStreamExt};
+use hyper::Chunk;
 use log::{error, trace};
 use sha1::Sha1;
-use tokio_executor::spawn;
 
 use storage_types::b2::v2::requests::*;
 use storage_types::b2::v2::responses::*;
-use storage_types::b2::v2::{FileAction, UserFileInfo, LAST_MODIFIED_KEY};
+use storage_types::b2::v2::{percent_encode, FileAction, UserFileInfo, LAST_MODIFIED_KEY};
 
 use super::Backend;
-use crate::types::stream::{MergedStreams, ResultStreamPoll};
+use crate::instrument::Operation;
+use crate::transport::runtime::{spawn, Instant};
+use crate::transport::{
+    Capture, Cassette, HttpClient, Proxy, ProxySettings, Resolver, TransportSettings,
+};
+use crate::types::stream::{range_stream, LengthCheckedStream, MergedStreams, ResultStreamPoll};
 use crate::types::*;
-use crate::utils::{into_data_stream, Acquired, CloningPool, Pool};
+use crate::utils::{
+    buffered, into_data_stream, Acquired, CloningPool, Pool, DEFAULT_WRITE_BUFFER_DEPTH,
+};
 use crate::{FileStore, StorageBackend};
-use client::{B2APIState, B2Client, B2API};
+use client::{server_side_encryption, B2APIState, B2Client, B2API};
 
 const TOTAL_MAX_SMALL_FILE_SIZE: u64 = 5 * 1000 * 1000 * 1000;
 const DEFAULT_MAX_SMALL_FILE_SIZE: u64 = 200 * 1000 * 1000;
 const DEFAULT_REQUEST_LIMIT: usize = 20;
+const DEFAULT_BUCKET_CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+const MAX_PART_SIZE: u64 = 5 * 1000 * 1000 * 1000;
+const PARTS_PER_DOUBLING: usize = 1000;
+const TARGET_PART_DURATION: Duration = Duration::from_secs(30);
+const MAX_FILE_NAME_LENGTH: usize = 1024;
+const MAX_SEGMENT_LENGTH: usize = 250;
 
-type ClientPool = CloningPool<HyperClient<HttpsConnector<HttpConnector>>>;
-type Client = Acquired<
-    HyperClient<HttpsConnector<HttpConnector>>,
-    HyperClient<HttpsConnector<HttpConnector>>,
-    Infallible,
->;
+type ClientPool = CloningPool<HttpClient>;
+type Client = Acquired<HttpClient, HttpClient, Infallible>;
 
 #[derive(Clone, Debug)]
 struct FileVersions {
@@ -101,12 +139,28 @@ impl FileVersions {
         &self.versions[self.versions.len() - 1]
     }
 
+    /// The latest version that isn't a hide marker, this is what will be
+    /// visible if the file is unhidden.
+    fn current(&self) -> &FileInfo {
+        self.versions
+            .iter()
+            .rev()
+            .find(|v| v.action != FileAction::Hide)
+            .unwrap_or_else(|| self.latest())
+    }
+
+    fn is_hidden(&self) -> bool {
+        self.latest().action == FileAction::Hide
+    }
+
     fn iter(&self) -> Iter<FileInfo> {
         self.versions.iter()
     }
 }
 
 /// The B2 implementation for [`Object`](../../enum.Object.html).
+///
+/// A hidden file is described by the version that unhiding it would restore.
 #[derive(Clone, Debug)]
 pub struct B2Object {
     path: ObjectPath,
@@ -114,8 +168,16 @@ pub struct B2Object {
 }
 
 impl B2Object {
-    fn versions(&self) -> Iter<FileInfo> {
-        self.versions.iter()
+    /// The hex encoded SHA-1 hash of the file's content as recorded by B2.
+    ///
+    /// Large files have no hash of their own so this uses the
+    /// `large_file_sha1` file info when the uploader set it.
+    pub fn content_sha1(&self) -> Option<&str> {
+        let version = self.versions.current();
+        match version.content_sha1.as_ref().map(String::as_str) {
+            Some(hash) if hash != "none" && !hash.starts_with("unverified:") => Some(hash),
+            _ => version.file_info.get("large_file_sha1").map(String::as_str),
+        }
     }
 }
 
@@ -125,11 +187,11 @@ impl ObjectInfo for B2Object {
     }
 
     fn len(&self) -> u64 {
-        self.versions.latest().content_length
+        self.versions.current().content_length
     }
 
     fn object_type(&self) -> ObjectType {
-        match &self.versions.latest().action {
+        match &self.versions.current().action {
             FileAction::Upload => ObjectType::File,
             FileAction::Folder => ObjectType::Directory,
             _ => ObjectType::Unknown,
@@ -137,7 +199,7 @@ impl ObjectInfo for B2Object {
     }
 
     fn modified(&self) -> Option<SystemTime> {
-        let version = self.versions.latest();
+        let version = self.versions.current();
         if version.action != FileAction::Upload {
             return None;
         }
@@ -182,9 +244,18 @@ struct B2Settings {
     key_id: String,
     key: String,
     host: String,
+    download_host: Option<String>,
     prefix: ObjectPath,
     max_small_file_size: u64,
-    user_agent: String,
+    adaptive_part_size: bool,
+    resume_large_files: bool,
+    hide_on_delete: bool,
+    bucket_cache_ttl: Duration,
+    encryption: Option<Encryption>,
+    retry: RetryPolicy,
+    min_upload_throughput: Option<(u64, Duration)>,
+    upload_deadline: Option<Duration>,
+    transport: TransportSettings,
 }
 
 struct PartData {
@@ -193,13 +264,112 @@ struct PartData {
     hash: String,
 }
 
+impl PartData {
+    fn new(data: Vec<Data>) -> PartData {
+        let mut hasher = Sha1::new();
+        let mut length: u64 = 0;
+        for chunk in data.iter() {
+            hasher.update(chunk);
+            length += chunk.len() as u64;
+        }
+
+        PartData {
+            data,
+            length,
+            hash: hasher.hexdigest(),
+        }
+    }
+}
+
+/// Splits off any data beyond the first `limit` bytes, splitting a chunk if
+/// necessary, and returns it.
+fn split_part(buffers: &mut Vec<Data>, limit: u64) -> Vec<Data> {
+    let mut length: u64 = 0;
+    for index in 0..buffers.len() {
+        let chunk_length = buffers[index].len() as u64;
+        if length + chunk_length > limit {
+            let mut remainder = buffers.split_off(index);
+            let keep = (limit - length) as usize;
+            if keep > 0 {
+                buffers.push(remainder[0].split_to(keep));
+            }
+            return remainder;
+        }
+        length += chunk_length;
+    }
+
+    Vec::new()
+}
+
+fn data_length(buffers: &[Data]) -> u64 {
+    buffers.iter().map(|chunk| chunk.len() as u64).sum()
+}
+
+/// Chooses the size of each part of a large file upload.
+///
+/// In adaptive mode parts grow so that each takes roughly
+/// `TARGET_PART_DURATION` to upload at the throughput seen so far, and double
+/// every `PARTS_PER_DOUBLING` parts so that very large files stay well within
+/// B2's limit on the number of parts.
+struct PartSizer {
+    size: u64,
+    adaptive: bool,
+    uploaded: u64,
+    elapsed: Duration,
+}
+
+impl PartSizer {
+    fn new(size: u64, adaptive: bool) -> PartSizer {
+        PartSizer {
+            size,
+            adaptive,
+            uploaded: 0,
+            elapsed: Duration::from_secs(0),
+        }
+    }
+
+    /// Records that a part of `length` bytes took `elapsed` to upload.
+    fn record(&mut self, length: u64, elapsed: Duration) {
+        self.uploaded += length;
+        self.elapsed += elapsed;
+    }
+
+    /// Gets the size to use for the next part given the number already started.
+    fn next_size(&mut self, parts: usize) -> u64 {
+        if !self.adaptive {
+            return self.size;
+        }
+
+        if parts > 0 && parts % PARTS_PER_DOUBLING == 0 {
+            self.size *= 2;
+        }
+
+        let millis = self.elapsed.as_millis() as u64;
+        if millis > 0 {
+            let throughput = self.uploaded * 1000 / millis;
+            let target = throughput * TARGET_PART_DURATION.as_secs();
+            if target > self.size {
+                self.size = target;
+            }
+        }
+
+        self.size = self.size.min(MAX_PART_SIZE);
+        self.size
+    }
+}
+
+/// The result of uploading a part, either the part's length and how long it
+/// took or the part number and the error.
+type PartResult = Result<(u64, Duration), (usize, StorageError)>;
+
 async fn part_upload(
     client: B2API,
     path: ObjectPath,
     file_id: String,
     part: usize,
     part_data: PartData,
-    mut sender: Sender<Result<(), (usize, StorageError)>>,
+    key: Option<CustomerKey>,
+    mut sender: Sender<PartResult>,
 ) {
     trace!(
         "Starting large file part upload to {} with {} bytes in {} chunks.",
@@ -208,6 +378,8 @@ async fn part_upload(
         part_data.data.len()
     );
 
+    let start = Instant::now();
+    let length = part_data.length;
     let part_url = match client
         .b2_get_upload_part_url(path.clone(), GetUploadPartUrlRequest { file_id })
         .await
@@ -226,31 +398,266 @@ async fn part_upload(
             part_data.length,
             part_data.hash,
             part_data.data,
+            key,
         )
         .await
     {
         return sender.send(Err((part, e))).await.unwrap();
     }
 
-    sender.send(Ok(())).await.unwrap();
+    sender.send(Ok((length, start.elapsed()))).await.unwrap();
+}
+
+/// Parts of an unfinished large file that have already been uploaded, keyed by
+/// part number.
+type UploadedParts = HashMap<usize, UploadPartResponse>;
+
+/// Whether an unfinished large file was started with the encryption that a new
+/// upload would request. Without a request the bucket's default applies which
+/// would be the same again, unless the file used a customer's key.
+fn same_encryption(
+    existing: &Option<FileEncryption>,
+    expected: &Option<ServerSideEncryption>,
+) -> bool {
+    let mode = existing
+        .as_ref()
+        .and_then(|e| e.mode.as_ref())
+        .map(String::as_str);
+    let algorithm = existing
+        .as_ref()
+        .and_then(|e| e.algorithm.as_ref())
+        .map(String::as_str);
+
+    match expected {
+        Some(expected) => {
+            mode == Some(expected.mode.as_str()) && algorithm == Some(expected.algorithm.as_str())
+        }
+        None => mode != Some("SSE-C"),
+    }
+}
+
+/// Checks that the uploaded parts of an unfinished large file can be reused.
+///
+/// Parts are reused where they are, so they must run from the first part
+/// without gaps and, when the length of the new content is known, fit within
+/// it. Otherwise the new upload could end up with fewer parts and B2 refuses
+/// to finish a file with parts that aren't listed.
+fn parts_fit(parts: &UploadedParts, content_length: Option<u64>) -> bool {
+    let mut total = 0;
+    for number in 1..=parts.len() {
+        match parts.get(&number) {
+            Some(part) => total += part.content_length,
+            None => return false,
+        }
+    }
+
+    match content_length {
+        Some(length) => total <= length,
+        None => true,
+    }
+}
+
+/// Finds an unfinished large file with the same name, file info and
+/// encryption, returning its ID and the parts already uploaded. A file whose
+/// parts cannot be reused is cancelled so the upload starts again.
+async fn find_unfinished(
+    client: &B2API,
+    bucket_id: &str,
+    file_name: &str,
+    file_info: &UserFileInfo,
+    info: &UploadInfo,
+) -> StorageResult<Option<(String, UploadedParts)>> {
+    let path = &info.path;
+    let encryption = info
+        .options
+        .encryption
+        .as_ref()
+        .and_then(server_side_encryption);
+    let mut start_file_id = None;
+    let file_id = loop {
+        let request = ListUnfinishedLargeFilesRequest {
+            bucket_id: bucket_id.to_owned(),
+            name_prefix: Some(file_name.to_owned()),
+            start_file_id,
+            max_file_count: None,
+        };
+
+        let response = client
+            .b2_list_unfinished_large_files(path.clone(), request)
+            .await?;
+
+        let found = response.files.into_iter().find(|file| {
+            file.file_name == file_name
+                && &file.file_info == file_info
+                && same_encryption(&file.server_side_encryption, &encryption)
+        });
+        if let Some(file_id) = found.and_then(|file| file.file_id) {
+            break file_id;
+        }
+
+        match response.next_file_id {
+            Some(id) => start_file_id = Some(id),
+            None => return Ok(None),
+        }
+    };
+
+    let mut parts = UploadedParts::new();
+    let mut start_part_number = None;
+    loop {
+        let request = ListPartsRequest {
+            file_id: file_id.clone(),
+            start_part_number,
+            max_part_count: None,
+        };
+
+        let response = client.b2_list_parts(path.clone(), request).await?;
+        for part in response.parts {
+            parts.insert(part.part_number, part);
+        }
+
+        match response.next_part_number {
+            Some(next) => start_part_number = Some(next),
+            None => break,
+        }
+    }
+
+    if !parts_fit(&parts, info.options.content_length) {
+        trace!(
+            "Cancelling unfinished large file upload to {} with mismatched parts.",
+            path
+        );
+        client
+            .b2_cancel_large_file(path.clone(), CancelLargeFileRequest { file_id })
+            .await?;
+        return Ok(None);
+    }
+
+    trace!(
+        "Resuming large file upload to {} with {} existing parts.",
+        path,
+        parts.len()
+    );
+    Ok(Some((file_id, parts)))
+}
+
+/// Checks whether a part with the same content was uploaded previously.
+fn is_uploaded(parts: &UploadedParts, part: usize, data: &PartData) -> bool {
+    match parts.get(&part) {
+        Some(existing) => {
+            existing.content_length == data.length && existing.content_sha1 == data.hash
+        }
+        None => false,
+    }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn large_upload<S>(
     client: B2API,
     recommended_part_size: u64,
     info: UploadInfo,
     bucket_id: String,
     file_name: String,
-    first_part: PartData,
-    mut stream: Pin<Box<S>>,
+    initial: Vec<Data>,
+    stream: Pin<Box<S>>,
 ) -> Result<(), TransferError>
 where
     S: Stream<Item = StorageResult<Data>> + Send + 'static,
 {
     trace!("Starting large file upload to {}.", info.path);
-    let mut part_count: usize = 1;
-    let (sender, mut receiver) = channel::<Result<(), (usize, StorageError)>>(0);
+    let mut file_info = UserFileInfo::new();
+    if let Some(time) = info.modified {
+        if let Ok(duration) = time.duration_since(UNIX_EPOCH) {
+            file_info.insert(
+                LAST_MODIFIED_KEY.to_owned(),
+                duration.as_millis().to_string(),
+            );
+        }
+    }
+
+    let unfinished = if client.settings().resume_large_files {
+        find_unfinished(&client, &bucket_id, &file_name, &file_info, &info)
+            .await
+            .map_err(TransferError::TargetError)?
+    } else {
+        None
+    };
+
+    let (file_id, uploaded) = match unfinished {
+        Some(found) => found,
+        None => {
+            let request = StartLargeFileRequest {
+                bucket_id,
+                file_name,
+                content_type: String::from("b2/x-auto"),
+                file_info: Some(file_info),
+                server_side_encryption: info
+                    .options
+                    .encryption
+                    .as_ref()
+                    .and_then(server_side_encryption),
+            };
+
+            let result = client
+                .b2_start_large_file(info.path.clone(), request)
+                .await
+                .map_err(TransferError::TargetError)?;
+
+            match result.file_id {
+                Some(s) => (s, UploadedParts::new()),
+                None => {
+                    return Err(TransferError::TargetError(error::invalid_data(Some(
+                        "Attempt to request large file upload failed.",
+                    ))))
+                }
+            }
+        }
+    };
+
+    let delete_on_failure = info.options.delete_on_failure;
+    let result = upload_parts(
+        client.clone(),
+        recommended_part_size,
+        info.path.clone(),
+        file_id.clone(),
+        uploaded,
+        customer_key(&info.options.encryption),
+        initial,
+        stream,
+    )
+    .await;
+
+    if result.is_err() && delete_on_failure {
+        trace!("Cancelling failed large file upload to {}.", info.path);
+        if let Err(e) = client
+            .b2_cancel_large_file(info.path, CancelLargeFileRequest { file_id })
+            .await
+        {
+            error!("Failed to cancel large file upload: {}", e);
+        }
+    }
+
+    result
+}
+
+/// A source of a compose pinned to the version that was current when the
+/// compose started.
+struct ComposeSource {
+    path: ObjectPath,
+    file_id: String,
+    length: u64,
+    customer_key: bool,
+}
 
+/// Joins the sources into a new large file by copying each one as a part so
+/// that none of the data has to be downloaded.
+async fn copy_parts(
+    client: B2API,
+    info: UploadInfo,
+    bucket_id: String,
+    file_name: String,
+    sources: Vec<ComposeSource>,
+) -> Result<(), TransferError> {
+    trace!("Composing {} from {} parts.", info.path, sources.len());
     let mut file_info = UserFileInfo::new();
     if let Some(time) = info.modified {
         if let Ok(duration) = time.duration_since(UNIX_EPOCH) {
@@ -266,13 +673,17 @@ where
         file_name,
         content_type: String::from("b2/x-auto"),
         file_info: Some(file_info),
+        server_side_encryption: info
+            .options
+            .encryption
+            .as_ref()
+            .and_then(server_side_encryption),
     };
 
     let result = client
         .b2_start_large_file(info.path.clone(), request)
         .await
         .map_err(TransferError::TargetError)?;
-
     let file_id = match result.file_id {
         Some(s) => s,
         None => {
@@ -282,72 +693,215 @@ where
         }
     };
 
-    let mut hashes = vec![first_part.hash.clone()];
+    let mut part_sha1_array = Vec::new();
+    let mut result = Ok(());
+    for (index, source) in sources.into_iter().enumerate() {
+        let request = CopyPartRequest {
+            source_file_id: source.file_id,
+            large_file_id: file_id.clone(),
+            part_number: index + 1,
+            range: None,
+        };
 
-    spawn(part_upload(
-        client.clone(),
-        info.path.clone(),
-        file_id.clone(),
-        part_count,
-        first_part,
-        sender.clone(),
-    ));
+        match client.b2_copy_part(source.path, request).await {
+            Ok(response) => part_sha1_array.push(response.content_sha1),
+            Err(e) => {
+                result = match e.kind() {
+                    StorageErrorKind::NotFound(_) => Err(TransferError::SourceError(e)),
+                    _ => Err(TransferError::TargetError(e)),
+                };
+                break;
+            }
+        }
+    }
 
-    let mut hasher = Sha1::new();
-    let mut length: u64 = 0;
-    let mut buffers: Vec<Data> = Default::default();
+    if result.is_ok() {
+        let request = FinishLargeFileRequest {
+            file_id: file_id.clone(),
+            part_sha1_array,
+        };
+        result = client
+            .b2_finish_large_file(info.path.clone(), request)
+            .await
+            .map(|_| ())
+            .map_err(TransferError::TargetError);
+    }
+
+    // Copied parts cost nothing to copy again so there is nothing worth
+    // keeping for a later attempt.
+    if result.is_err() {
+        trace!("Cancelling failed compose of {}.", info.path);
+        if let Err(e) = client
+            .b2_cancel_large_file(info.path, CancelLargeFileRequest { file_id })
+            .await
+        {
+            error!("Failed to cancel large file upload: {}", e);
+        }
+    }
+
+    result
+}
+
+/// Starts the uploads of each part of a large file in turn.
+struct PartUploads {
+    client: B2API,
+    path: ObjectPath,
+    file_id: String,
+    uploaded: UploadedParts,
+    key: Option<CustomerKey>,
+    sender: Sender<PartResult>,
+    hashes: Vec<String>,
+    pending: usize,
+}
+
+impl PartUploads {
+    /// The length of the next part if it was uploaded before. Resumed parts
+    /// are cut at the same place so they can be skipped.
+    fn uploaded_length(&self) -> Option<u64> {
+        self.uploaded
+            .get(&(self.hashes.len() + 1))
+            .map(|part| part.content_length)
+    }
+
+    /// Whether `length` bytes of data make up the next part.
+    fn is_full(&self, length: u64, part_size: u64) -> bool {
+        match self.uploaded_length() {
+            Some(uploaded) => length >= uploaded,
+            None => length > part_size,
+        }
+    }
+
+    /// The most data that can go in the next part.
+    fn limit(&self) -> u64 {
+        self.uploaded_length().unwrap_or(MAX_PART_SIZE)
+    }
+
+    fn start(&mut self, data: Vec<Data>) {
+        let part = self.hashes.len() + 1;
+        let part_data = PartData::new(data);
+        self.hashes.push(part_data.hash.clone());
+
+        if !is_uploaded(&self.uploaded, part, &part_data) {
+            self.pending += 1;
+            spawn(part_upload(
+                self.client.clone(),
+                self.path.clone(),
+                self.file_id.clone(),
+                part,
+                part_data,
+                self.key.clone(),
+                self.sender.clone(),
+            ));
+        }
+    }
+}
+
+/// Wraps the body of a download, checking that the expected length arrives.
+fn download_stream<S>(length: Option<u64>, body: S) -> DataStream
+where
+    S: Stream<Item = Result<Chunk, hyper::Error>> + Send + 'static,
+{
+    let stream = body.map(|result| match result {
+        Ok(chunk) => Result::<Data, StorageError>::Ok(chunk.into_bytes()),
+        Err(e) => Result::<Data, StorageError>::Err(e.into()),
+    });
+
+    match length {
+        Some(length) => DataStream::from_stream(LengthCheckedStream::new(stream, length)),
+        None => DataStream::from_stream(stream),
+    }
+}
+
+/// The key that parts and downloads must supply for the given encryption.
+fn customer_key(encryption: &Option<Encryption>) -> Option<CustomerKey> {
+    match encryption {
+        Some(Encryption::Customer(key)) => Some(key.clone()),
+        _ => None,
+    }
+}
+
+/// Uploads the parts of a large file and then finishes it.
+///
+/// The data already read from the stream makes up the first part. A part is
+/// started once the data collected exceeds the current part size but no part
+/// is ever larger than B2's maximum, chunks are split where necessary.
+#[allow(clippy::too_many_arguments)]
+async fn upload_parts<S>(
+    client: B2API,
+    recommended_part_size: u64,
+    path: ObjectPath,
+    file_id: String,
+    uploaded: UploadedParts,
+    key: Option<CustomerKey>,
+    initial: Vec<Data>,
+    mut stream: Pin<Box<S>>,
+) -> Result<(), TransferError>
+where
+    S: Stream<Item = StorageResult<Data>> + Send + 'static,
+{
+    let (sender, mut receiver) = channel::<PartResult>(0);
+    let mut parts = PartUploads {
+        client: client.clone(),
+        path: path.clone(),
+        file_id: file_id.clone(),
+        uploaded,
+        key,
+        sender,
+        hashes: Vec::new(),
+        pending: 0,
+    };
+
+    let mut buffers = initial;
+    if parts.uploaded_length().is_none() {
+        let remainder = split_part(&mut buffers, MAX_PART_SIZE);
+        parts.start(buffers);
+        buffers = remainder;
+    }
+    let mut length = data_length(&buffers);
+
+    let mut sizer = PartSizer::new(recommended_part_size, client.settings().adaptive_part_size);
+    let mut part_size = sizer.next_size(parts.hashes.len());
+
+    let fail = |part_number: usize, e: StorageError| {
+        error!(
+            "Part {} of large file upload to {} failed: {}",
+            part_number, path, e
+        );
+        TransferError::TargetError(e)
+    };
 
     loop {
+        // Collect the results of any parts that have finished.
+        while let Ok(Some(result)) = receiver.try_next() {
+            match result {
+                Ok((part_length, elapsed)) => {
+                    parts.pending -= 1;
+                    sizer.record(part_length, elapsed);
+                }
+                Err((part_number, e)) => return Err(fail(part_number, e)),
+            }
+        }
+
         match stream.next().await {
             Some(Ok(data)) => {
                 length += data.len() as u64;
-                hasher.update(&data);
                 buffers.push(data);
 
-                if length > recommended_part_size {
-                    // Start part upload.
-                    part_count += 1;
-
-                    let hash = hasher.hexdigest();
-                    hashes.push(hash.clone());
-                    spawn(part_upload(
-                        client.clone(),
-                        info.path.clone(),
-                        file_id.clone(),
-                        part_count,
-                        PartData {
-                            data: buffers.drain(..).collect(),
-                            length,
-                            hash,
-                        },
-                        sender.clone(),
-                    ));
-
-                    hasher = Sha1::new();
-                    length = 0;
+                while parts.is_full(length, part_size) {
+                    let remainder = split_part(&mut buffers, parts.limit());
+                    parts.start(buffers);
+                    buffers = remainder;
+                    length = data_length(&buffers);
+                    part_size = sizer.next_size(parts.hashes.len());
                 }
             }
             Some(Err(e)) => return Err(TransferError::SourceError(e)),
             None => {
-                // Got all data, finish uploads.
-                if length > 0 {
-                    // Start part upload.
-                    part_count += 1;
-
-                    let hash = hasher.hexdigest();
-                    hashes.push(hash.clone());
-                    spawn(part_upload(
-                        client.clone(),
-                        info.path.clone(),
-                        file_id.clone(),
-                        part_count,
-                        PartData {
-                            data: buffers.drain(..).collect(),
-                            length,
-                            hash,
-                        },
-                        sender.clone(),
-                    ));
+                // Got all data, upload whatever remains.
+                while !buffers.is_empty() {
+                    let remainder = split_part(&mut buffers, parts.limit());
+                    parts.start(buffers);
+                    buffers = remainder;
                 }
 
                 break;
@@ -357,36 +911,45 @@ where
 
     trace!(
         "All parts ({}) started for large file upload to {}, waiting for completion.",
-        part_count,
-        info.path
+        parts.hashes.len(),
+        path
     );
     // Wait for parts to finish uploading.
-    while part_count > 0 {
+    while parts.pending > 0 {
         match receiver.next().await {
-            Some(Ok(())) => part_count -= 1,
-            Some(Err((part_number, e))) => {
-                error!(
-                    "Part {} of large file upload to {} failed: {}",
-                    part_number, info.path, e
-                );
-                return Err(TransferError::TargetError(e));
-            }
+            Some(Ok(_)) => parts.pending -= 1,
+            Some(Err((part_number, e))) => return Err(fail(part_number, e)),
             None => break,
         }
     }
 
     trace!(
         "All parts ({}) for large file upload to {} are complete.",
-        hashes.len(),
-        info.path
+        parts.hashes.len(),
+        path
     );
 
+    // A resumed file with more parts than were uploaded now can never be
+    // finished.
+    if parts.uploaded.keys().any(|&part| part > parts.hashes.len()) {
+        if let Err(e) = client
+            .b2_cancel_large_file(path.clone(), CancelLargeFileRequest { file_id })
+            .await
+        {
+            error!("Failed to cancel large file upload: {}", e);
+        }
+
+        return Err(TransferError::TargetError(error::invalid_data(Some(
+            "The resumed large file had more parts than the new content.",
+        ))));
+    }
+
     client
         .b2_finish_large_file(
-            info.path,
+            path,
             FinishLargeFileRequest {
                 file_id,
-                part_sha1_array: hashes,
+                part_sha1_array: parts.hashes,
             },
         )
         .await
@@ -434,6 +997,7 @@ async fn small_upload(
             part_data.length,
             part_data.hash,
             part_data.data,
+            info.options.encryption.unwrap_or(Encryption::None),
         )
         .await?;
 
@@ -460,7 +1024,6 @@ where
         max_small_file_size = session.absolute_minimum_part_size
     }
 
-    let mut hasher = Sha1::new();
     let mut length: u64 = 0;
     let mut buffers: Vec<Data> = Default::default();
     let mut stream = Box::pin(stream);
@@ -469,7 +1032,6 @@ where
         match stream.next().await {
             Some(Ok(data)) => {
                 length += data.len() as u64;
-                hasher.update(&data);
                 buffers.push(data);
 
                 if length > max_small_file_size {
@@ -480,11 +1042,7 @@ where
                         info,
                         bucket_id,
                         file_name,
-                        PartData {
-                            data: buffers,
-                            length,
-                            hash: hasher.hexdigest(),
-                        },
+                        buffers,
                         stream,
                     )
                     .await;
@@ -493,19 +1051,9 @@ where
             Some(Err(e)) => return Err(TransferError::SourceError(e)),
             None => {
                 // Got all data, upload it as a regular file.
-                return small_upload(
-                    client,
-                    info,
-                    bucket_id,
-                    file_name,
-                    PartData {
-                        data: buffers,
-                        length,
-                        hash: hasher.hexdigest(),
-                    },
-                )
-                .await
-                .map_err(TransferError::TargetError);
+                return small_upload(client, info, bucket_id, file_name, PartData::new(buffers))
+                    .await
+                    .map_err(TransferError::TargetError);
             }
         }
     }
@@ -708,54 +1256,356 @@ impl B2Backend {
                 key_id: key_id.to_owned(),
                 key: key.to_owned(),
                 host: B2_API_HOST.to_owned(),
+                download_host: None,
                 prefix: ObjectPath::empty(),
                 max_small_file_size: DEFAULT_MAX_SMALL_FILE_SIZE,
-                user_agent: format!(
-                    "{}/{} ({})",
-                    env!("CARGO_PKG_NAME"),
-                    env!("CARGO_PKG_VERSION"),
-                    env!("CARGO_PKG_REPOSITORY")
-                ),
+                adaptive_part_size: false,
+                resume_large_files: false,
+                hide_on_delete: false,
+                bucket_cache_ttl: DEFAULT_BUCKET_CACHE_TTL,
+                encryption: None,
+                retry: Default::default(),
+                min_upload_throughput: None,
+                upload_deadline: None,
+                transport: Default::default(),
             },
             max_requests: DEFAULT_REQUEST_LIMIT,
+            lazy_authentication: false,
         }
     }
 
-    /// Creates a new [`B2API`](struct.B2API.html) that can be used for
-    /// making B2 API calls.
-    fn client(&self) -> B2API {
-        B2API::new(&self.state)
+    /// Gets the capture of requests made to B2 if one was set when building
+    /// this backend.
+    pub fn capture(&self) -> Option<&Capture> {
+        self.state.settings.transport.capture.as_ref()
     }
 
-    async fn expand_path(
-        client: B2API,
-        prefix: ObjectPath,
-        path: ObjectPath,
-    ) -> StorageResult<(Bucket, String)> {
-        let mut file_part = prefix.join(&path);
-        let bucket_name = match file_part.unshift_part() {
-            Some(b) => b,
-            None => return Err(error::not_found(path, None)),
+    /// Lists the hidden files with the given prefix.
+    ///
+    /// See [`hide_on_delete`](struct.B2BackendBuilder.html#method.hide_on_delete).
+    pub fn list_hidden<P>(&self, prefix: P) -> ObjectStreamFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        let prefix = match prefix.try_into() {
+            Ok(p) => p,
+            Err(e) => return ObjectStreamFuture::from_value(Err(e.into())),
         };
 
-        if file_part.is_empty() {
-            return Err(error::not_found(path, None));
-        }
+        let operation = Operation::new(Backend::B2, "list_hidden", &prefix);
+        ObjectStreamFuture::from_future(operation.run(object_list(
+            self.client(),
+            self.state.settings.prefix.clone(),
+            prefix,
+            None,
+            true,
+        )))
+    }
 
-        let request = ListBucketsRequest {
-            account_id: client.account_info().await?.account_id,
-            bucket_id: None,
-            bucket_name: Some(bucket_name),
-            bucket_types: Default::default(),
-        };
+    /// Restores a hidden file by deleting its hide markers.
+    ///
+    /// Fails as not found if the file is not hidden.
+    pub fn unhide<P>(&self, path: P) -> OperationCompleteFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        async fn unhide(
+            client: B2API,
+            backend_prefix: ObjectPath,
+            path: ObjectPath,
+        ) -> StorageResult<()> {
+            let (_, versions) = file_versions(client.clone(), backend_prefix, path.clone()).await?;
+            if !versions.is_hidden() {
+                return Err(error::not_found(path, Some("File is not hidden.")));
+            }
 
-        let mut buckets = client.b2_list_buckets(path.clone(), request).await?.buckets;
-        if buckets.len() != 1 {
-            return Err(error::not_found(path, None));
+            for info in versions
+                .iter()
+                .rev()
+                .take_while(|v| v.action == FileAction::Hide)
+            {
+                delete_version(&client, &path, info).await?;
+            }
+
+            Ok(())
         }
 
-        Ok((buckets.remove(0), file_part.to_string()))
-    }
+        let path = match path.try_into() {
+            Ok(p) => p,
+            Err(e) => return OperationCompleteFuture::from_value(Err(e.into())),
+        };
+
+        let operation = Operation::new(Backend::B2, "unhide", &path);
+        OperationCompleteFuture::from_future(operation.run(unhide(
+            self.client(),
+            self.state.settings.prefix.clone(),
+            path,
+        )))
+    }
+
+    /// Lists the large file uploads under the given prefix that were started
+    /// but never finished or cancelled.
+    ///
+    /// The prefix must include a bucket name.
+    pub fn unfinished_uploads<P>(&self, prefix: P) -> UnfinishedUploadsFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        let prefix = match prefix.try_into() {
+            Ok(p) => p,
+            Err(e) => return UnfinishedUploadsFuture::from_value(Err(e.into())),
+        };
+
+        let operation = Operation::new(Backend::B2, "unfinished_uploads", &prefix);
+        UnfinishedUploadsFuture::from_future(operation.run(unfinished_uploads(
+            self.client(),
+            self.state.settings.prefix.clone(),
+            prefix,
+        )))
+    }
+
+    /// Cancels an unfinished upload, deleting any parts already uploaded.
+    pub fn cancel_upload(&self, upload: &UnfinishedUpload) -> OperationCompleteFuture {
+        async fn cancel(client: B2API, path: ObjectPath, file_id: String) -> StorageResult<()> {
+            client
+                .b2_cancel_large_file(path, CancelLargeFileRequest { file_id })
+                .await?;
+            Ok(())
+        }
+
+        let path = upload.path().clone();
+        let operation = Operation::new(Backend::B2, "cancel_upload", &path);
+        OperationCompleteFuture::from_future(operation.run(cancel(
+            self.client(),
+            path,
+            upload.id().to_owned(),
+        )))
+    }
+
+    /// Cancels the unfinished uploads under the given prefix that were started
+    /// before `cutoff`, resolving to the uploads cancelled.
+    ///
+    /// Uploads started after the cutoff are left alone as they may still be
+    /// in progress.
+    pub fn cancel_unfinished_uploads<P>(
+        &self,
+        prefix: P,
+        cutoff: SystemTime,
+    ) -> UnfinishedUploadsFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        async fn cancel(
+            client: B2API,
+            backend_prefix: ObjectPath,
+            prefix: ObjectPath,
+            cutoff: SystemTime,
+        ) -> StorageResult<Vec<UnfinishedUpload>> {
+            let mut cancelled = Vec::new();
+            for upload in unfinished_uploads(client.clone(), backend_prefix, prefix).await? {
+                if upload.started() >= cutoff {
+                    continue;
+                }
+
+                trace!("Cancelling unfinished upload to {}.", upload.path());
+                client
+                    .b2_cancel_large_file(
+                        upload.path().clone(),
+                        CancelLargeFileRequest {
+                            file_id: upload.id().to_owned(),
+                        },
+                    )
+                    .await?;
+                cancelled.push(upload);
+            }
+
+            Ok(cancelled)
+        }
+
+        let prefix = match prefix.try_into() {
+            Ok(p) => p,
+            Err(e) => return UnfinishedUploadsFuture::from_value(Err(e.into())),
+        };
+
+        let operation = Operation::new(Backend::B2, "cancel_unfinished_uploads", &prefix);
+        UnfinishedUploadsFuture::from_future(operation.run(cancel(
+            self.client(),
+            self.state.settings.prefix.clone(),
+            prefix,
+            cutoff,
+        )))
+    }
+
+    /// Generates a url that can be used to download the file at the given path
+    /// without any other credentials until `valid_for` has passed.
+    ///
+    /// B2 allows these urls to be valid for between one second and one week.
+    /// Files encrypted with a customer key cannot be downloaded this way.
+    pub fn presigned_url<P>(&self, path: P, valid_for: Duration) -> PresignedUrlFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        async fn presign(
+            client: B2API,
+            backend_prefix: ObjectPath,
+            path: ObjectPath,
+            valid_for: Duration,
+        ) -> StorageResult<String> {
+            let mut file_name = backend_prefix.join(&path);
+            let bucket_name = match file_name.unshift_part() {
+                Some(b) => b,
+                None => {
+                    return Err(error::invalid_path(
+                        path,
+                        Some("Object paths cannot be empty."),
+                    ))
+                }
+            };
+
+            let bucket = match client.bucket(path.clone(), bucket_name.clone()).await? {
+                Some(b) => b,
+                None => return Err(error::not_found(path, Some("Bucket does not exist."))),
+            };
+
+            let file_name = file_name.to_string();
+            let response = client
+                .b2_get_download_authorization(
+                    path,
+                    GetDownloadAuthorizationRequest {
+                        bucket_id: bucket.bucket_id,
+                        file_name_prefix: file_name.clone(),
+                        valid_duration_in_seconds: valid_for.as_secs(),
+                    },
+                )
+                .await?;
+
+            Ok(format!(
+                "{}/file/{}/{}?Authorization={}",
+                client.download_url().await?,
+                percent_encode(&bucket_name),
+                percent_encode(&file_name),
+                percent_encode(&response.authorization_token)
+            ))
+        }
+
+        let path = match path.try_into() {
+            Ok(p) => p,
+            Err(e) => return PresignedUrlFuture::from_value(Err(e.into())),
+        };
+
+        if path.is_dir_prefix() {
+            return PresignedUrlFuture::from_value(Err(error::invalid_path(
+                path,
+                Some("Object paths cannot be empty or end with a '/' character."),
+            )));
+        }
+
+        if valid_for < Duration::from_secs(1) || valid_for > Duration::from_secs(7 * 24 * 60 * 60) {
+            return PresignedUrlFuture::from_value(Err(error::invalid_settings(Some(
+                "Presigned urls must be valid for between one second and one week.",
+            ))));
+        }
+
+        let operation = Operation::new(Backend::B2, "presigned_url", &path);
+        PresignedUrlFuture::from_future(operation.run(presign(
+            self.client(),
+            self.state.settings.prefix.clone(),
+            path,
+            valid_for,
+        )))
+    }
+
+    /// Gets the current settings of the named bucket.
+    ///
+    /// The name is used as is, it is not affected by any prefix this backend
+    /// was configured with.
+    pub fn bucket_settings(&self, bucket: &str) -> BucketSettingsFuture {
+        async fn get(client: B2API, path: ObjectPath) -> StorageResult<BucketSettings> {
+            let name = path.to_string();
+            client.invalidate_bucket(&name);
+            match client.bucket(path.clone(), name).await? {
+                Some(bucket) => Ok(bucket.into()),
+                None => Err(error::not_found(path, Some("Bucket does not exist."))),
+            }
+        }
+
+        let path = match ObjectPath::new(bucket) {
+            Ok(p) => p,
+            Err(e) => return BucketSettingsFuture::from_value(Err(e)),
+        };
+
+        let operation = Operation::new(Backend::B2, "bucket_settings", &path);
+        BucketSettingsFuture::from_future(operation.run(get(self.client(), path)))
+    }
+
+    /// Changes the settings of the named bucket, returning the new settings.
+    ///
+    /// The name is used as is, it is not affected by any prefix this backend
+    /// was configured with.
+    pub fn update_bucket(&self, bucket: &str, update: BucketUpdate) -> BucketSettingsFuture {
+        async fn update(
+            client: B2API,
+            path: ObjectPath,
+            update: BucketUpdate,
+        ) -> StorageResult<BucketSettings> {
+            let bucket = match client.bucket(path.clone(), path.to_string()).await? {
+                Some(b) => b,
+                None => return Err(error::not_found(path, Some("Bucket does not exist."))),
+            };
+
+            let request = update.into_request(bucket.account_id, bucket.bucket_id);
+            let bucket = match client.b2_update_bucket(path.clone(), request).await {
+                Ok(b) => b,
+                Err(e) => {
+                    client.invalidate_bucket(&bucket.bucket_name);
+                    return Err(e);
+                }
+            };
+
+            client.cache_bucket(bucket.clone());
+            Ok(bucket.into())
+        }
+
+        let path = match ObjectPath::new(bucket) {
+            Ok(p) => p,
+            Err(e) => return BucketSettingsFuture::from_value(Err(e)),
+        };
+
+        let operation = Operation::new(Backend::B2, "update_bucket", &path);
+        BucketSettingsFuture::from_future(operation.run(update(self.client(), path, update)))
+    }
+
+    /// Creates a new [`B2API`](struct.B2API.html) that can be used for
+    /// making B2 API calls.
+    fn client(&self) -> B2API {
+        B2API::new(&self.state)
+    }
+
+    async fn expand_path(
+        client: B2API,
+        prefix: ObjectPath,
+        path: ObjectPath,
+    ) -> StorageResult<(Bucket, String)> {
+        let mut file_part = prefix.join(&path);
+        let bucket_name = match file_part.unshift_part() {
+            Some(b) => b,
+            None => return Err(error::not_found(path, None)),
+        };
+
+        if file_part.is_empty() {
+            return Err(error::not_found(path, None));
+        }
+
+        match client.bucket(path.clone(), bucket_name).await? {
+            Some(bucket) => Ok((bucket, file_part.to_string())),
+            None => Err(error::not_found(path, None)),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -764,6 +1614,7 @@ impl B2Backend {
 pub struct B2BackendBuilder {
     settings: B2Settings,
     max_requests: usize,
+    lazy_authentication: bool,
 }
 
 impl B2BackendBuilder {
@@ -775,6 +1626,18 @@ impl B2BackendBuilder {
         self
     }
 
+    /// Sets the host that files are downloaded from.
+    ///
+    /// Normally files are downloaded from the host that B2 returns when
+    /// authorizing. This replaces it, for instance with a CDN in front of B2
+    /// or a CNAME for the B2 download host, while API calls still go to the
+    /// API host. The host should include the scheme, e.g.
+    /// `https://cdn.example.com`.
+    pub fn download_host(mut self, host: &str) -> B2BackendBuilder {
+        self.settings.download_host = Some(host.trim_end_matches('/').to_owned());
+        self
+    }
+
     /// Sets a path prefix for this storage.
     ///
     /// Essentially sets the 'root directory' for this storage, any paths
@@ -807,6 +1670,60 @@ impl B2BackendBuilder {
         self
     }
 
+    /// Grows the part size of large file uploads as the upload progresses.
+    ///
+    /// By default every part uses the size recommended by B2. With adaptive
+    /// sizing parts are sized to take around 30 seconds each at the throughput
+    /// achieved by earlier parts, and the size doubles every 1000 parts so that
+    /// very large files do not exceed B2's limit of 10000 parts. Defaults to
+    /// false.
+    pub fn adaptive_part_size(mut self, adaptive: bool) -> B2BackendBuilder {
+        self.settings.adaptive_part_size = adaptive;
+        self
+    }
+
+    /// Resumes unfinished large file uploads.
+    ///
+    /// A large file upload that fails partway is left unfinished in B2. With
+    /// this enabled a later upload to the same path with the same modification
+    /// time and encryption continues that file, skipping any parts that were
+    /// already uploaded with the same content. An unfinished file whose parts
+    /// don't fit within the new
+    /// [`content_length`](../../struct.WriteOptions.html#structfield.content_length)
+    /// is cancelled and the upload starts again. Uploads written with
+    /// [`delete_on_failure`](../../struct.WriteOptions.html#structfield.delete_on_failure)
+    /// set are cancelled instead and cannot be resumed. Defaults to false.
+    pub fn resume_large_files(mut self, resume: bool) -> B2BackendBuilder {
+        self.settings.resume_large_files = resume;
+        self
+    }
+
+    /// Hides files instead of deleting them.
+    ///
+    /// With this enabled deleting a file uses `b2_hide_file` which keeps every
+    /// version of the file in the bucket, to be removed by the bucket's
+    /// lifecycle rules or restored with
+    /// [`unhide`](struct.B2Backend.html#method.unhide). Hidden files are not
+    /// listed and cannot be read. Defaults to false which deletes every
+    /// version of the file.
+    pub fn hide_on_delete(mut self, hide: bool) -> B2BackendBuilder {
+        self.settings.hide_on_delete = hide;
+        self
+    }
+
+    /// Sets how files are encrypted when written.
+    ///
+    /// Writes can choose differently with their
+    /// [`encryption`](../../struct.WriteOptions.html#structfield.encryption)
+    /// option. With [`Encryption::Customer`](../../types/enum.Encryption.html#variant.Customer)
+    /// the key is also used to read files unless a read supplies its own, so
+    /// copies and moves decrypt and encrypt with this key. When unset the
+    /// bucket's default encryption applies.
+    pub fn encryption(mut self, encryption: Encryption) -> B2BackendBuilder {
+        self.settings.encryption = Some(encryption);
+        self
+    }
+
     /// Limits the number of API requests that can be called in parallel.
     ///
     /// This also limits the number of parallel threads for downloads and
@@ -816,9 +1733,221 @@ impl B2BackendBuilder {
         self
     }
 
+    /// Sets how failed API calls and uploads are retried.
+    ///
+    /// Dropped connections, server errors, expired authorization and rate
+    /// limiting are considered transient. Rate limited calls wait for the time
+    /// B2 asks for or otherwise the policy's backoff. Defaults to
+    /// [`RetryPolicy::default`](../../struct.RetryPolicy.html).
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> B2BackendBuilder {
+        self.settings.retry = policy;
+        self
+    }
+
+    /// Fails uploads that send fewer than `bytes_per_second` bytes per second
+    /// on average over a `window`, so a connection that hangs doesn't stall a
+    /// write forever.
+    ///
+    /// Throughput is checked at the end of each window. Once all of the data
+    /// has been sent B2 must respond within a window too. Stalled uploads fail
+    /// with a [`ConnectionFailed`](../../enum.StorageErrorKind.html#variant.ConnectionFailed)
+    /// error which is transient so they are retried according to the
+    /// [`retry_policy`](struct.B2BackendBuilder.html#method.retry_policy). A
+    /// minimum of zero only fails uploads that stop entirely. By default
+    /// uploads are not watched.
+    pub fn min_upload_throughput(
+        mut self,
+        bytes_per_second: u64,
+        window: Duration,
+    ) -> B2BackendBuilder {
+        self.settings.min_upload_throughput = Some((bytes_per_second, window));
+        self
+    }
+
+    /// Fails each upload of a file or part that hasn't completed within
+    /// `deadline`, however fast it is sending.
+    ///
+    /// Like [`min_upload_throughput`](struct.B2BackendBuilder.html#method.min_upload_throughput)
+    /// this fails with a transient [`ConnectionFailed`](../../enum.StorageErrorKind.html#variant.ConnectionFailed)
+    /// error so the upload is retried according to the
+    /// [`retry_policy`](struct.B2BackendBuilder.html#method.retry_policy),
+    /// each attempt getting the full deadline. By default uploads have no
+    /// deadline.
+    pub fn upload_deadline(mut self, deadline: Duration) -> B2BackendBuilder {
+        self.settings.upload_deadline = Some(deadline);
+        self
+    }
+
+    /// Sets how long the details of a bucket are remembered.
+    ///
+    /// Most operations need the bucket's ID which requires an extra API call
+    /// to look up from its name. Results are cached for this long, or until an
+    /// operation on the bucket fails as not found. A zero duration disables
+    /// the cache. Defaults to 5 minutes.
+    pub fn bucket_cache_ttl(mut self, ttl: Duration) -> B2BackendBuilder {
+        self.settings.bucket_cache_ttl = ttl;
+        self
+    }
+
     /// Sets the User-Agent for all requests to B2.
     pub fn user_agent(mut self, user_agent: &str) -> B2BackendBuilder {
-        self.settings.user_agent = user_agent.to_owned();
+        self.settings.transport.user_agent = user_agent.to_owned();
+        self
+    }
+
+    /// Adds a header to send with every request to B2.
+    ///
+    /// Headers are appended so calling this multiple times with the same name
+    /// will send multiple values. A User-Agent header replaces the default
+    /// User-Agent. Invalid header names or values will cause
+    /// [`connect`](struct.B2BackendBuilder.html#method.connect) to fail.
+    pub fn header(mut self, name: &str, value: &str) -> B2BackendBuilder {
+        self.settings
+            .transport
+            .headers
+            .push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Connects to B2 through the given proxy.
+    ///
+    /// By default the proxies configured by the `https_proxy`, `http_proxy`,
+    /// `all_proxy` and `no_proxy` environment variables are used. All but
+    /// `http_proxy` may also be given in uppercase, `HTTP_PROXY` is ignored as
+    /// it can be set from a request header in CGI environments.
+    pub fn proxy(mut self, proxy: Proxy) -> B2BackendBuilder {
+        self.settings.transport.proxy = ProxySettings::Proxy(proxy);
+        self
+    }
+
+    /// Connects directly to B2 ignoring any proxies configured in the
+    /// environment.
+    pub fn no_proxy(mut self) -> B2BackendBuilder {
+        self.settings.transport.proxy = ProxySettings::Direct;
+        self
+    }
+
+    /// Sets whether connections to B2 are kept open to be reused for later
+    /// requests. Defaults to true.
+    pub fn keep_alive(mut self, keep_alive: bool) -> B2BackendBuilder {
+        self.settings.transport.pool.keep_alive = keep_alive;
+        self
+    }
+
+    /// Sets how long an idle connection is kept open before being closed.
+    ///
+    /// `None` keeps idle connections open until the server closes them. The
+    /// default is 90 seconds.
+    pub fn idle_connection_timeout(mut self, timeout: Option<Duration>) -> B2BackendBuilder {
+        self.settings.transport.pool.idle_timeout = timeout;
+        self
+    }
+
+    /// Limits the number of idle connections kept open to any one host.
+    ///
+    /// Connections over this limit are closed as soon as their request
+    /// completes rather than being returned to the pool. By default there is
+    /// no limit beyond that set by
+    /// [`limit_requests`](struct.B2BackendBuilder.html#method.limit_requests).
+    pub fn max_idle_connections_per_host(mut self, max: usize) -> B2BackendBuilder {
+        self.settings.transport.pool.max_idle_per_host = max;
+        self
+    }
+
+    /// Sets whether to only use HTTP/2 for connections. Defaults to false.
+    ///
+    /// With HTTP/2 parallel requests to a host, like the many list calls made
+    /// when listing a large bucket, are multiplexed over a single connection
+    /// rather than each waiting for a connection of their own, which helps on
+    /// links with high latency.
+    pub fn http2_only(mut self, http2_only: bool) -> B2BackendBuilder {
+        self.settings.transport.pool.http2_only = http2_only;
+        self
+    }
+
+    /// Sets how long to wait for a connection to B2 to be established before
+    /// the request fails.
+    ///
+    /// `None`, the default, waits as long as the operating system allows.
+    /// Failed connections are retried according to the
+    /// [`retry_policy`](struct.B2BackendBuilder.html#method.retry_policy).
+    pub fn connect_timeout(mut self, timeout: Option<Duration>) -> B2BackendBuilder {
+        self.settings.transport.connection.timeout = timeout;
+        self
+    }
+
+    /// Sets the interval between TCP keepalive probes on connections to B2.
+    ///
+    /// Probes stop idle connections being dropped by firewalls and detect
+    /// connections that have silently died. `None`, the default, disables
+    /// them.
+    pub fn tcp_keepalive(mut self, interval: Option<Duration>) -> B2BackendBuilder {
+        self.settings.transport.connection.keepalive = interval;
+        self
+    }
+
+    /// Sets whether to disable Nagle's algorithm on connections to B2 so small
+    /// requests are sent without delay. Defaults to false.
+    pub fn tcp_nodelay(mut self, nodelay: bool) -> B2BackendBuilder {
+        self.settings.transport.connection.nodelay = nodelay;
+        self
+    }
+
+    /// Connects to the given addresses for a host instead of looking it up.
+    ///
+    /// This works like an entry in `/etc/hosts` for this backend only, for
+    /// example to point it at a test server. The host name is still used for
+    /// TLS so the server's certificate must be valid for it.
+    pub fn resolve(mut self, host: &str, addresses: &[IpAddr]) -> B2BackendBuilder {
+        self.settings
+            .transport
+            .resolver
+            .overrides
+            .insert(host.to_lowercase(), addresses.to_vec());
+        self
+    }
+
+    /// Looks up host names with the given resolver instead of the system's
+    /// resolver.
+    ///
+    /// Hosts given to [`resolve`](struct.B2BackendBuilder.html#method.resolve)
+    /// are not looked up.
+    pub fn resolver<R>(mut self, resolver: R) -> B2BackendBuilder
+    where
+        R: Resolver,
+    {
+        self.settings.transport.resolver.resolver = Some(Arc::new(resolver));
+        self
+    }
+
+    /// Records the requests made to B2 in the given cassette, or answers them
+    /// from it if it is replaying.
+    ///
+    /// See [`Cassette`](../../transport/struct.Cassette.html) for details.
+    pub fn cassette(mut self, cassette: Cassette) -> B2BackendBuilder {
+        self.settings.transport.cassette = Some(cassette);
+        self
+    }
+
+    /// Captures sanitized copies of the requests made to B2 for reporting
+    /// problems with the service.
+    ///
+    /// See [`Capture`](../../transport/struct.Capture.html) for details.
+    pub fn capture(mut self, capture: Capture) -> B2BackendBuilder {
+        self.settings.transport.capture = Some(capture);
+        self
+    }
+
+    /// Sets whether to delay authenticating with B2 until it is needed.
+    ///
+    /// By default [`connect`](struct.B2BackendBuilder.html#method.connect)
+    /// authenticates so that bad credentials are reported immediately. When
+    /// this is enabled connecting never touches the network and invalid
+    /// credentials will instead cause the first operation to fail. Use
+    /// [`authorize`](../../trait.StorageBackend.html#method.authorize) to
+    /// authenticate at a time of your choosing.
+    pub fn lazy_authentication(mut self, lazy: bool) -> B2BackendBuilder {
+        self.lazy_authentication = lazy;
         self
     }
 
@@ -827,17 +1956,7 @@ impl B2BackendBuilder {
     pub fn connect(self) -> ConnectFuture {
         ConnectFuture::from_future(async {
             trace!("Connecting to B2 with settings {:?}", self.settings);
-            let connector = match HttpsConnector::new() {
-                Ok(c) => c,
-                Err(e) => {
-                    return Err(error::connection_failed(Some(&format!(
-                        "Could not create http connection: {}.",
-                        e
-                    ))))
-                }
-            };
-
-            let client = HyperClient::builder().build(connector);
+            let client = self.settings.transport.build_client()?;
             let clients = ClientPool::new(client, Some(self.max_requests));
 
             let auth_tokens = Pool::new(
@@ -856,12 +1975,15 @@ impl B2BackendBuilder {
                     next_id: Default::default(),
                     clients,
                     auth_tokens,
+                    buckets: Default::default(),
                 },
             };
 
             // Make sure we can connect.
-            let b2_client = backend.client();
-            b2_client.account_info().await?;
+            if !self.lazy_authentication {
+                let b2_client = backend.client();
+                b2_client.account_info().await?;
+            }
 
             Ok(FileStore::from(backend))
         })
@@ -873,29 +1995,34 @@ async fn object_list(
     backend_prefix: ObjectPath,
     prefix: ObjectPath,
     delimiter: Option<String>,
+    hidden: bool,
 ) -> StorageResult<ObjectStream> {
     let mut file_part = backend_prefix.join(&prefix);
     let bucket = file_part.unshift_part();
 
-    let mut request = ListBucketsRequest {
-        account_id: client.account_info().await?.account_id,
-        bucket_id: None,
-        bucket_name: None,
-        bucket_types: Default::default(),
-    };
-
-    if let Some(ref bucket_name) = bucket {
+    let bucket_name = bucket.clone().unwrap_or_else(String::new);
+    let path = ObjectPath::new(bucket_name.clone())?;
+    let buckets = match bucket {
         // Only include the bucket named `bucket`.
-        request.bucket_name = Some(bucket_name.clone());
-    }
+        Some(name) => client
+            .bucket(path, name)
+            .await?
+            .into_iter()
+            .collect::<Vec<Bucket>>(),
+        None => {
+            let request = ListBucketsRequest {
+                account_id: client.account_info().await?.account_id,
+                bucket_id: None,
+                bucket_name: None,
+                bucket_types: Default::default(),
+            };
 
-    let bucket_name = bucket.unwrap_or_else(String::new);
-    let path = ObjectPath::new(bucket_name.clone())?;
-    let listers = client
-        .b2_list_buckets(path, request)
-        .await?
-        .buckets
-        .drain(..)
+            client.b2_list_buckets(path, request).await?.buckets
+        }
+    };
+
+    let listers = buckets
+        .into_iter()
         .filter(|b| b.bucket_name.starts_with(&bucket_name))
         .map(move |b| {
             let options = ListFileVersionsRequest {
@@ -909,7 +2036,16 @@ async fn object_list(
 
             let requestor = FileVersionsRequestor::new(client.clone(), prefix.clone(), options);
             let temp_prefix = backend_prefix.clone();
+            let invalidator = client.clone();
+            let name = b.bucket_name.clone();
             ListStream::new(requestor)
+                .map_err(move |e| {
+                    if let StorageErrorKind::NotFound(_) = e.kind() {
+                        invalidator.invalidate_bucket(&name);
+                    }
+                    e
+                })
+                .try_filter(move |versions| ready(versions.is_hidden() == hidden))
                 .and_then(move |i| ready(new_object(&b.bucket_name, i, &temp_prefix)))
         })
         .fold(MergedStreams::new(), |mut m, s| {
@@ -920,11 +2056,187 @@ async fn object_list(
     Ok(ObjectStream::from_stream(listers))
 }
 
+async fn delete_version(client: &B2API, path: &ObjectPath, info: &FileInfo) -> StorageResult<()> {
+    let file_id = match info.file_id {
+        Some(ref id) => id.to_owned(),
+        None => {
+            return Err(error::internal_error(Some(
+                "Expected object to have a file id.",
+            )))
+        }
+    };
+
+    client
+        .b2_delete_file_version(
+            path.clone(),
+            DeleteFileVersionRequest {
+                file_name: info.file_name.clone(),
+                file_id,
+            },
+        )
+        .await?;
+    Ok(())
+}
+
+/// Lists every unfinished large file upload under a prefix.
+async fn unfinished_uploads(
+    client: B2API,
+    backend_prefix: ObjectPath,
+    prefix: ObjectPath,
+) -> StorageResult<Vec<UnfinishedUpload>> {
+    let mut file_part = backend_prefix.join(&prefix);
+    let bucket_name = match file_part.unshift_part() {
+        Some(b) => b,
+        None => {
+            return Err(error::invalid_path(
+                prefix,
+                Some("Unfinished uploads can only be listed within a bucket."),
+            ))
+        }
+    };
+
+    let bucket = match client.bucket(prefix.clone(), bucket_name.clone()).await? {
+        Some(b) => b,
+        None => return Err(error::not_found(prefix, Some("Bucket does not exist."))),
+    };
+
+    let name_prefix = if file_part.is_empty() {
+        None
+    } else {
+        Some(file_part.to_string())
+    };
+
+    let mut uploads = Vec::new();
+    let mut start_file_id = None;
+    loop {
+        let request = ListUnfinishedLargeFilesRequest {
+            bucket_id: bucket.bucket_id.clone(),
+            name_prefix: name_prefix.clone(),
+            start_file_id,
+            max_file_count: None,
+        };
+
+        let response = match client
+            .b2_list_unfinished_large_files(prefix.clone(), request)
+            .await
+        {
+            Ok(r) => r,
+            Err(e) => {
+                if let StorageErrorKind::NotFound(_) = e.kind() {
+                    client.invalidate_bucket(&bucket_name);
+                }
+                return Err(e);
+            }
+        };
+
+        for file in response.files {
+            uploads.push(UnfinishedUpload::new(&bucket_name, file, &backend_prefix)?);
+        }
+
+        match response.next_file_id {
+            Some(id) => start_file_id = Some(id),
+            None => return Ok(uploads),
+        }
+    }
+}
+
+/// Finds every version of the file at the given path, including any hide
+/// markers.
+async fn file_versions(
+    client: B2API,
+    backend_prefix: ObjectPath,
+    path: ObjectPath,
+) -> StorageResult<(Bucket, FileVersions)> {
+    let (bucket, file) =
+        B2Backend::expand_path(client.clone(), backend_prefix, path.clone()).await?;
+
+    let options = ListFileVersionsRequest {
+        bucket_id: bucket.bucket_id.clone(),
+        start_file_name: None,
+        start_file_id: None,
+        max_file_count: None,
+        prefix: Some(file.clone()),
+        delimiter: Some(String::from("/")),
+    };
+
+    let requestor = FileVersionsRequestor::new(client.clone(), path.clone(), options);
+    let mut files: Vec<FileVersions> = match ListStream::new(requestor)
+        .try_filter(|versions| ready(versions.latest().file_name == file))
+        .try_collect()
+        .await
+    {
+        Ok(files) => files,
+        Err(e) => {
+            if let StorageErrorKind::NotFound(_) = e.kind() {
+                client.invalidate_bucket(&bucket.bucket_name);
+            }
+            return Err(e);
+        }
+    };
+    if files.len() != 1 {
+        return Err(error::not_found(path, None));
+    }
+
+    Ok((bucket, files.remove(0)))
+}
+
 impl StorageBackend for B2Backend {
     fn backend_type(&self) -> Backend {
         Backend::B2
     }
 
+    fn authorize(&self) -> OperationCompleteFuture {
+        let client = self.client();
+        OperationCompleteFuture::from_future(async move {
+            client.account_info().await?;
+            Ok(())
+        })
+    }
+
+    fn validate_path(&self, path: &ObjectPath) -> StorageResult<()> {
+        if path.is_dir_prefix() {
+            return Err(error::invalid_path(
+                path.clone(),
+                Some("Object paths cannot be empty or end with a '/' character."),
+            ));
+        }
+
+        // The first part is the bucket which B2 looks up, only the rest is the
+        // file name.
+        let mut file_name = self.state.settings.prefix.join(path);
+        file_name.unshift_part();
+
+        let name = file_name.to_string();
+        if name.len() > MAX_FILE_NAME_LENGTH {
+            return Err(error::invalid_path(
+                path.clone(),
+                Some(&format!(
+                    "B2 file names cannot be longer than {} bytes.",
+                    MAX_FILE_NAME_LENGTH
+                )),
+            ));
+        }
+
+        if name.chars().any(|c| c < ' ' || c == '\u{7f}') {
+            return Err(error::invalid_path(
+                path.clone(),
+                Some("B2 file names cannot contain control characters."),
+            ));
+        }
+
+        if name.split('/').any(|part| part.len() > MAX_SEGMENT_LENGTH) {
+            return Err(error::invalid_path(
+                path.clone(),
+                Some(&format!(
+                    "Each part of a B2 file name cannot be longer than {} bytes.",
+                    MAX_SEGMENT_LENGTH
+                )),
+            ));
+        }
+
+        Ok(())
+    }
+
     fn list_objects<P>(&self, prefix: P) -> ObjectStreamFuture
     where
         P: TryInto<ObjectPath>,
@@ -935,12 +2247,14 @@ impl StorageBackend for B2Backend {
             Err(e) => return ObjectStreamFuture::from_value(Err(e.into())),
         };
 
-        ObjectStreamFuture::from_future(object_list(
+        let operation = Operation::new(Backend::B2, "list_objects", &prefix);
+        ObjectStreamFuture::from_future(operation.run(object_list(
             self.client(),
             self.state.settings.prefix.clone(),
             prefix,
             None,
-        ))
+            false,
+        )))
     }
 
     fn list_directory<P>(&self, dir: P) -> ObjectStreamFuture
@@ -957,12 +2271,14 @@ impl StorageBackend for B2Backend {
             path.push_part("");
         }
 
-        ObjectStreamFuture::from_future(object_list(
+        let operation = Operation::new(Backend::B2, "list_directory", &path);
+        ObjectStreamFuture::from_future(operation.run(object_list(
             self.client(),
             self.state.settings.prefix.clone(),
             path,
             Some(String::from("/")),
-        ))
+            false,
+        )))
     }
 
     fn get_object<P>(&self, path: P) -> ObjectFuture
@@ -975,29 +2291,13 @@ impl StorageBackend for B2Backend {
             backend_prefix: ObjectPath,
             path: ObjectPath,
         ) -> StorageResult<Object> {
-            let (bucket, file) =
-                B2Backend::expand_path(client.clone(), backend_prefix.clone(), path.clone())
-                    .await?;
-
-            let options = ListFileVersionsRequest {
-                bucket_id: bucket.bucket_id.clone(),
-                start_file_name: None,
-                start_file_id: None,
-                max_file_count: None,
-                prefix: Some(file.clone()),
-                delimiter: Some(String::from("/")),
-            };
-
-            let requestor = FileVersionsRequestor::new(client.clone(), path.clone(), options);
-            let mut files: Vec<FileVersions> = ListStream::new(requestor)
-                .try_filter(|versions| ready(versions.latest().file_name == file))
-                .try_collect()
-                .await?;
-            if files.len() != 1 {
+            let (bucket, versions) =
+                file_versions(client, backend_prefix.clone(), path.clone()).await?;
+            if versions.is_hidden() {
                 return Err(error::not_found(path, None));
             }
 
-            new_object(&bucket.bucket_name, files.remove(0), &backend_prefix)
+            new_object(&bucket.bucket_name, versions, &backend_prefix)
         }
 
         let path = match path.try_into() {
@@ -1005,19 +2305,25 @@ impl StorageBackend for B2Backend {
             Err(e) => return ObjectFuture::from_value(Err(e.into())),
         };
 
-        if path.is_dir_prefix() {
-            return ObjectFuture::from_value(Err(error::invalid_path(
-                path,
-                Some("Object paths cannot be empty or end with a '/' character."),
-            )));
+        if let Err(e) = self.validate_path(&path) {
+            return ObjectFuture::from_value(Err(e));
         }
 
         let client = self.client();
         let prefix = self.state.settings.prefix.clone();
-        ObjectFuture::from_future(get(client, prefix, path))
+        let operation = Operation::new(Backend::B2, "get_object", &path);
+        ObjectFuture::from_future(operation.run(get(client, prefix, path)))
     }
 
     fn get_file_stream<P>(&self, path: P) -> DataStreamFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        self.get_file_stream_with_options(path, Default::default())
+    }
+
+    fn get_file_stream_with_options<P>(&self, path: P, options: ReadOptions) -> DataStreamFuture
     where
         P: TryInto<ObjectPath>,
         P::Error: Into<StorageError>,
@@ -1027,11 +2333,8 @@ impl StorageBackend for B2Backend {
             Err(e) => return DataStreamFuture::from_value(Err(e.into())),
         };
 
-        if path.is_dir_prefix() {
-            return DataStreamFuture::from_value(Err(error::invalid_path(
-                path,
-                Some("Object paths cannot be empty or end with a '/' character."),
-            )));
+        if let Err(e) = self.validate_path(&path) {
+            return DataStreamFuture::from_value(Err(e));
         }
 
         let mut file_name = self.state.settings.prefix.join(&path);
@@ -1045,17 +2348,43 @@ impl StorageBackend for B2Backend {
             }
         };
 
+        let key = options
+            .customer_key
+            .or_else(|| customer_key(&self.state.settings.encryption));
+        let operation = Operation::new(Backend::B2, "get_file_stream", &path);
         let future = self
             .client()
-            .b2_download_file_by_name(path, bucket, file_name.to_string())
-            .map_ok(|body| {
-                DataStream::from_stream(body.map(|result| match result {
-                    Ok(chunk) => Result::<Data, StorageError>::Ok(chunk.into_bytes()),
-                    Err(e) => Result::<Data, StorageError>::Err(e.into()),
-                }))
-            });
+            .b2_download_file_by_name(path, bucket, file_name.to_string(), key)
+            .map_ok(|(length, body)| download_stream(length, body));
 
-        DataStreamFuture::from_future(future)
+        DataStreamFuture::from_future(operation.read(future))
+    }
+
+    fn get_object_range(&self, object: &Object, range: Range<u64>) -> DataStreamFuture {
+        let file_id = match object {
+            Object::B2(ref b2_object) => b2_object.versions.current().file_id.clone(),
+            _ => None,
+        };
+        let file_id = match file_id {
+            Some(id) => id,
+            None => return range_stream(self.get_file_stream(object.path()), range),
+        };
+
+        // B2 rejects ranges that start beyond the end of the file.
+        let range = range.start..range.end.min(object.len());
+        if range.start >= range.end {
+            return DataStreamFuture::from_value(Ok(DataStream::from_stream(empty())));
+        }
+
+        let path = object.path();
+        let key = customer_key(&self.state.settings.encryption);
+        let operation = Operation::new(Backend::B2, "get_object_range", &path);
+        let future = self
+            .client()
+            .b2_download_file_by_id(path, file_id, Some(range), key)
+            .map_ok(|(length, body)| download_stream(length, body));
+
+        DataStreamFuture::from_future(operation.read(future))
     }
 
     fn delete_object<P>(&self, path: P) -> OperationCompleteFuture
@@ -1063,37 +2392,30 @@ impl StorageBackend for B2Backend {
         P: TryInto<ObjectPath>,
         P::Error: Into<StorageError>,
     {
-        async fn delete(backend: B2Backend, path: ObjectPath) -> StorageResult<()> {
-            let object: B2Object = match backend.clone().get_object(path.clone()).await?.try_into()
-            {
-                Ok(o) => o,
-                Err(_) => {
-                    return Err(error::internal_error(Some(
-                        "Failed to convert retrieved object to the expected type.",
-                    )));
+        async fn delete(
+            client: B2API,
+            backend_prefix: ObjectPath,
+            path: ObjectPath,
+            hide: bool,
+        ) -> StorageResult<()> {
+            let (bucket, versions) =
+                file_versions(client.clone(), backend_prefix, path.clone()).await?;
+
+            if hide {
+                if versions.is_hidden() {
+                    return Err(error::not_found(path, None));
                 }
-            };
 
-            for info in object.versions() {
-                match info.file_id {
-                    Some(ref id) => {
-                        backend
-                            .client()
-                            .b2_delete_file_version(
-                                path.clone(),
-                                DeleteFileVersionRequest {
-                                    file_name: info.file_name.clone(),
-                                    file_id: id.to_owned(),
-                                },
-                            )
-                            .await?;
-                    }
-                    None => {
-                        return Err(error::internal_error(Some(
-                            "Expected object to have a file id.",
-                        )));
-                    }
-                }
+                let request = HideFileRequest {
+                    bucket_id: bucket.bucket_id,
+                    file_name: versions.latest().file_name.clone(),
+                };
+                client.b2_hide_file(path, request).await?;
+                return Ok(());
+            }
+
+            for info in versions.iter() {
+                delete_version(&client, &path, info).await?;
             }
 
             Ok(())
@@ -1103,7 +2425,13 @@ impl StorageBackend for B2Backend {
             Ok(p) => p,
             Err(e) => return OperationCompleteFuture::from_value(Err(e.into())),
         };
-        OperationCompleteFuture::from_future(delete(self.clone(), path))
+        let operation = Operation::new(Backend::B2, "delete_object", &path);
+        OperationCompleteFuture::from_future(operation.run(delete(
+            self.client(),
+            self.state.settings.prefix.clone(),
+            path,
+            self.state.settings.hide_on_delete,
+        )))
     }
 
     fn write_file_from_stream<S, I, E, P>(&self, info: P, stream: S) -> WriteCompleteFuture
@@ -1118,51 +2446,229 @@ impl StorageBackend for B2Backend {
             client: B2API,
             max_small_file_size: u64,
             prefix: ObjectPath,
+            existing: Option<ObjectFuture>,
             info: UploadInfo,
             stream: S,
         ) -> Result<(), TransferError>
         where
             S: Stream<Item = StorageResult<Data>> + Send + 'static,
         {
+            // B2 has no exclusive create so this is only a best effort check.
+            if let Some(existing) = existing {
+                match existing.await {
+                    Ok(_) => {
+                        return Err(TransferError::TargetError(error::already_exists(
+                            info.path, None,
+                        )))
+                    }
+                    Err(e) => match e.kind() {
+                        StorageErrorKind::NotFound(_) => (),
+                        _ => return Err(TransferError::TargetError(e)),
+                    },
+                }
+            }
+
             let (bucket, file) =
                 B2Backend::expand_path(client.clone(), prefix.clone(), info.path.clone())
                     .await
                     .map_err(TransferError::SourceError)?;
 
-            perform_upload(
-                client,
+            let result = perform_upload(
+                client.clone(),
                 max_small_file_size,
                 info,
                 bucket.bucket_id,
                 file,
                 stream,
             )
-            .await
+            .await;
+
+            if let Err(TransferError::TargetError(ref e)) = result {
+                if let StorageErrorKind::NotFound(_) = e.kind() {
+                    client.invalidate_bucket(&bucket.bucket_name);
+                }
+            }
+
+            result
         }
 
-        let info = match info.try_into() {
+        let mut info: UploadInfo = match info.try_into() {
             Ok(i) => i,
             Err(e) => {
                 return WriteCompleteFuture::from_value(Err(TransferError::TargetError(e.into())))
             }
         };
 
+        if info.options.encryption.is_none() {
+            info.options.encryption = self.state.settings.encryption.clone();
+        }
+
         let path = info.path.clone();
-        if path.is_dir_prefix() {
-            return WriteCompleteFuture::from_value(Err(TransferError::TargetError(
-                error::invalid_path(
-                    path,
-                    Some("Object paths cannot be empty or end with a '/' character."),
-                ),
-            )));
+        if let Err(e) = self.validate_path(&path) {
+            return WriteCompleteFuture::from_value(Err(TransferError::TargetError(e)));
         }
 
-        WriteCompleteFuture::from_future(upload(
-            self.client(),
-            self.state.settings.max_small_file_size,
-            self.state.settings.prefix.clone(),
-            info,
-            into_data_stream(stream),
-        ))
+        let operation = Operation::new(Backend::B2, "write_file_from_stream", &path);
+        let existing = match info.options.mode {
+            WriteMode::Overwrite => None,
+            WriteMode::FailIfExists => Some(self.get_object(path)),
+        };
+
+        let client = self.client();
+        let max_small_file_size = self.state.settings.max_small_file_size;
+        let prefix = self.state.settings.prefix.clone();
+        let depth = info
+            .options
+            .buffer_depth
+            .unwrap_or(DEFAULT_WRITE_BUFFER_DEPTH);
+        let stream = operation.write(into_data_stream(stream));
+        WriteCompleteFuture::from_future(operation.run(buffered(stream, depth, move |receiver| {
+            upload(
+                client,
+                max_small_file_size,
+                prefix,
+                existing,
+                info,
+                receiver,
+            )
+        })))
+    }
+
+    /// Concatenates the files at the source paths, in order, into the target
+    /// file.
+    ///
+    /// When every source but the last is at least B2's minimum part size the
+    /// sources are copied server-side as the parts of a new large file.
+    /// Otherwise they are streamed through. Either way the sources are read
+    /// by the id of the version that was current when the compose started so
+    /// the target may be one of the sources.
+    fn compose<S, P, I>(&self, sources: S, target: I) -> CopyCompleteFuture
+    where
+        S: IntoIterator<Item = P>,
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+        I: TryInto<UploadInfo>,
+        I::Error: Into<StorageError>,
+    {
+        async fn compose(
+            backend: B2Backend,
+            sources: Vec<ObjectPath>,
+            info: UploadInfo,
+        ) -> Result<(), TransferError> {
+            let client = backend.client();
+            let prefix = backend.state.settings.prefix.clone();
+
+            let mut pinned = Vec::new();
+            for path in sources {
+                let (_, versions) = file_versions(client.clone(), prefix.clone(), path.clone())
+                    .await
+                    .map_err(TransferError::SourceError)?;
+                let current = versions.current();
+                match current.file_id {
+                    Some(ref file_id) if !versions.is_hidden() => pinned.push(ComposeSource {
+                        path,
+                        file_id: file_id.clone(),
+                        length: current.content_length,
+                        customer_key: current
+                            .server_side_encryption
+                            .as_ref()
+                            .and_then(|encryption| encryption.mode.as_ref())
+                            .map_or(false, |mode| mode == "SSE-C"),
+                    }),
+                    _ => return Err(TransferError::SourceError(error::not_found(path, None))),
+                }
+            }
+
+            let session = client
+                .account_info()
+                .await
+                .map_err(TransferError::TargetError)?;
+            let last = pinned.len().saturating_sub(1);
+            let server_side = pinned.len() > 1
+                && customer_key(&info.options.encryption).is_none()
+                && pinned.iter().enumerate().all(|(index, source)| {
+                    !source.customer_key
+                        && source.length <= MAX_PART_SIZE
+                        && (index == last || source.length >= session.absolute_minimum_part_size)
+                });
+
+            if !server_side {
+                let key = customer_key(&backend.state.settings.encryption);
+                let streams: Vec<DataStreamFuture> = pinned
+                    .into_iter()
+                    .map(|source| {
+                        DataStreamFuture::from_future(
+                            client
+                                .clone()
+                                .b2_download_file_by_id(
+                                    source.path,
+                                    source.file_id,
+                                    None,
+                                    key.clone(),
+                                )
+                                .map_ok(|(length, body)| download_stream(length, body)),
+                        )
+                    })
+                    .collect();
+                let stream = iter(streams).then(|stream| stream).try_flatten();
+                return backend
+                    .write_file_from_stream(info, DataStream::from_stream(stream))
+                    .await;
+            }
+
+            // B2 has no exclusive create so this is only a best effort check.
+            if info.options.mode == WriteMode::FailIfExists {
+                match file_versions(client.clone(), prefix.clone(), info.path.clone()).await {
+                    Ok((_, versions)) => {
+                        if !versions.is_hidden() {
+                            return Err(TransferError::TargetError(error::already_exists(
+                                info.path, None,
+                            )));
+                        }
+                    }
+                    Err(e) => match e.kind() {
+                        StorageErrorKind::NotFound(_) => (),
+                        _ => return Err(TransferError::TargetError(e)),
+                    },
+                }
+            }
+
+            let (bucket, file) = B2Backend::expand_path(client.clone(), prefix, info.path.clone())
+                .await
+                .map_err(TransferError::TargetError)?;
+            let operation = Operation::new(Backend::B2, "compose", &info.path);
+            operation
+                .run(copy_parts(client, info, bucket.bucket_id, file, pinned))
+                .await
+        }
+
+        let mut paths = Vec::new();
+        for source in sources {
+            match source.try_into() {
+                Ok(p) => paths.push(p),
+                Err(e) => {
+                    return CopyCompleteFuture::from_value(Err(TransferError::SourceError(
+                        e.into(),
+                    )))
+                }
+            }
+        }
+
+        let mut info: UploadInfo = match target.try_into() {
+            Ok(i) => i,
+            Err(e) => {
+                return CopyCompleteFuture::from_value(Err(TransferError::TargetError(e.into())))
+            }
+        };
+
+        if info.options.encryption.is_none() {
+            info.options.encryption = self.state.settings.encryption.clone();
+        }
+
+        if let Err(e) = self.validate_path(&info.path) {
+            return CopyCompleteFuture::from_value(Err(TransferError::TargetError(e)));
+        }
+
+        CopyCompleteFuture::from_future(compose(self.clone(), paths, info))
     }
 }
@@ -0,0 +1,195 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The settings of B2 buckets.
+use std::collections::HashMap;
+use std::time::Duration;
+
+use serde_json::Value;
+
+use storage_types::b2::v2;
+use storage_types::b2::v2::responses::Bucket;
+use storage_types::b2::v2::Map;
+
+use crate::types::*;
+
+/// Future returned when reading or changing the settings of a bucket.
+pub type BucketSettingsFuture = WrappedFuture<StorageResult<BucketSettings>>;
+
+/// Who can download the files in a bucket.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BucketType {
+    /// Anyone can download files.
+    Public,
+    /// Downloads require authorization.
+    Private,
+    /// A bucket holding snapshots, these cannot be set by an update.
+    Snapshot,
+    /// A type this library does not know about.
+    Unknown(String),
+}
+
+impl From<v2::BucketType> for BucketType {
+    fn from(bucket_type: v2::BucketType) -> BucketType {
+        match bucket_type {
+            v2::BucketType::Public => BucketType::Public,
+            v2::BucketType::Private => BucketType::Private,
+            v2::BucketType::Snapshot => BucketType::Snapshot,
+            v2::BucketType::Unknown(s) => BucketType::Unknown(s),
+        }
+    }
+}
+
+impl From<BucketType> for v2::BucketType {
+    fn from(bucket_type: BucketType) -> v2::BucketType {
+        match bucket_type {
+            BucketType::Public => v2::BucketType::Public,
+            BucketType::Private => v2::BucketType::Private,
+            BucketType::Snapshot => v2::BucketType::Snapshot,
+            BucketType::Unknown(s) => v2::BucketType::Unknown(s),
+        }
+    }
+}
+
+/// A rule allowing browsers to make cross-origin requests to a bucket.
+///
+/// See B2's [CORS documentation](https://www.backblaze.com/b2/docs/cors_rules.html)
+/// for the accepted values.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CorsRule {
+    /// A name for the rule, unique within the bucket.
+    pub name: String,
+    /// The origins the rule applies to, `*` matches any origin.
+    pub allowed_origins: Vec<String>,
+    /// The B2 or S3 operations allowed, e.g. `b2_download_file_by_name` or
+    /// `b2_upload_file`.
+    pub allowed_operations: Vec<String>,
+    /// The headers a browser may send in requests.
+    pub allowed_headers: Vec<String>,
+    /// The response headers a browser may read.
+    pub expose_headers: Vec<String>,
+    /// How long browsers may cache the result of a preflight request.
+    pub max_age: Duration,
+}
+
+impl From<v2::responses::CorsRule> for CorsRule {
+    fn from(rule: v2::responses::CorsRule) -> CorsRule {
+        CorsRule {
+            name: rule.cors_rule_name,
+            allowed_origins: rule.allowed_origins,
+            allowed_operations: rule.allowed_operations,
+            allowed_headers: rule.allowed_headers.unwrap_or_default(),
+            expose_headers: rule.expose_headers.unwrap_or_default(),
+            max_age: Duration::from_secs(rule.max_age_seconds),
+        }
+    }
+}
+
+impl From<CorsRule> for v2::responses::CorsRule {
+    fn from(rule: CorsRule) -> v2::responses::CorsRule {
+        fn non_empty(values: Vec<String>) -> Option<Vec<String>> {
+            if values.is_empty() {
+                None
+            } else {
+                Some(values)
+            }
+        }
+
+        v2::responses::CorsRule {
+            cors_rule_name: rule.name,
+            allowed_origins: rule.allowed_origins,
+            allowed_operations: rule.allowed_operations,
+            allowed_headers: non_empty(rule.allowed_headers),
+            expose_headers: non_empty(rule.expose_headers),
+            max_age_seconds: rule.max_age.as_secs(),
+        }
+    }
+}
+
+/// The settings of a bucket.
+#[derive(Clone, Debug)]
+pub struct BucketSettings {
+    /// The bucket's name.
+    pub name: String,
+    /// Who can download the files in the bucket.
+    pub bucket_type: BucketType,
+    /// Arbitrary information stored with the bucket.
+    pub bucket_info: HashMap<String, String>,
+    /// The CORS rules for the bucket.
+    pub cors_rules: Vec<CorsRule>,
+    /// Increases every time the settings change.
+    pub revision: u64,
+}
+
+impl From<Bucket> for BucketSettings {
+    fn from(bucket: Bucket) -> BucketSettings {
+        BucketSettings {
+            name: bucket.bucket_name,
+            bucket_type: bucket.bucket_type.into(),
+            bucket_info: bucket
+                .bucket_info
+                .into_iter()
+                .map(|(key, value)| match value {
+                    Value::String(s) => (key, s),
+                    value => (key, value.to_string()),
+                })
+                .collect(),
+            cors_rules: bucket.cors_rules.into_iter().map(CorsRule::from).collect(),
+            revision: bucket.revision,
+        }
+    }
+}
+
+/// Changes to make to the settings of a bucket.
+///
+/// Settings left as `None` are not changed. Bucket info and CORS rules are
+/// replaced as a whole.
+#[derive(Clone, Debug, Default)]
+pub struct BucketUpdate {
+    /// Changes who can download the files in the bucket.
+    pub bucket_type: Option<BucketType>,
+    /// Replaces the information stored with the bucket.
+    pub bucket_info: Option<HashMap<String, String>>,
+    /// Replaces the CORS rules for the bucket.
+    pub cors_rules: Option<Vec<CorsRule>>,
+    /// Only makes the changes if the bucket's settings are still at this
+    /// revision, otherwise the update fails.
+    pub if_revision_is: Option<u64>,
+}
+
+impl BucketUpdate {
+    pub(super) fn into_request(
+        self,
+        account_id: String,
+        bucket_id: String,
+    ) -> v2::requests::UpdateBucketRequest {
+        v2::requests::UpdateBucketRequest {
+            account_id,
+            bucket_id,
+            bucket_type: self.bucket_type.map(v2::BucketType::from),
+            bucket_info: self.bucket_info.map(|info| {
+                info.into_iter()
+                    .map(|(key, value)| (key, Value::String(value)))
+                    .collect::<Map>()
+            }),
+            cors_rules: self.cors_rules.map(|rules| {
+                rules
+                    .into_iter()
+                    .map(v2::responses::CorsRule::from)
+                    .collect()
+            }),
+            if_revision_is: self.if_revision_is,
+        }
+    }
+}
@@ -16,43 +16,120 @@
 //!
 //! Mainly split out to ensure that only the expected methods can be called
 //! to ensure that limits are enforced correctly.
+use std::collections::HashMap;
 use std::fmt;
 use std::future::Future;
-use std::io::Read;
-use std::ops::Deref;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::ops::{Deref, Range};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use base64::encode;
-use futures::stream::{iter, Stream, StreamExt};
+use futures::future::{pending, select, Either};
+use futures::stream::{iter, Stream, StreamExt, TryStreamExt};
 use http::header;
 use http::method::Method;
+use http::request::Builder;
 use hyper::body::Body;
 use hyper::Chunk;
 use hyper::{Request, Response};
 use log::{error, trace, warn};
 use serde::de::DeserializeOwned;
-use serde_json::{from_str, to_string};
+use serde_json::{from_slice, to_string};
 
 use storage_types::b2::v2::requests::*;
 use storage_types::b2::v2::responses::*;
 use storage_types::b2::v2::{
     percent_encode, UserFileInfo, B2_HEADER_CONTENT_SHA1, B2_HEADER_FILE_INFO_PREFIX,
-    B2_HEADER_FILE_NAME, B2_HEADER_PART_NUMBER,
+    B2_HEADER_FILE_NAME, B2_HEADER_PART_NUMBER, B2_HEADER_SSE, B2_HEADER_SSE_C_ALGORITHM,
+    B2_HEADER_SSE_C_KEY, B2_HEADER_SSE_C_KEY_MD5, SSE_ALGORITHM,
 };
 
 use super::{B2Settings, Client, ClientPool};
+use crate::transport::runtime::{delay_for, Instant};
+use crate::transport::TransportError;
 use crate::types::stream::AfterStream;
 use crate::types::*;
 use crate::utils::Pool;
 
-const MAX_API_RETRIES: usize = 5;
-
 #[derive(Debug)]
 struct B2Error {
     error: StorageError,
     needs_auth: bool,
     can_retry: bool,
+    retry_after: Option<Duration>,
+}
+
+impl B2Error {
+    /// Returns how long to wait before retrying after this error.
+    ///
+    /// Only rate limiting responses are delayed, using the server's
+    /// `Retry-After` if given and otherwise the retry policy's backoff.
+    fn backoff(&self, policy: &RetryPolicy, tries: usize) -> Option<Duration> {
+        match self.error.kind() {
+            StorageErrorKind::RateLimited => Some(match self.retry_after {
+                Some(delay) => delay.min(policy.max_backoff()),
+                None => policy.backoff_delay(tries),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Parses a `Retry-After` header given as a number of seconds.
+fn parse_retry_after(response: &Response<Body>) -> Option<Duration> {
+    response
+        .headers()
+        .get(header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|s| s.trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
+}
+
+/// Builds the body for an upload, counting the bytes as they are sent.
+fn counted_body(data: Vec<Data>, sent: Arc<AtomicU64>) -> Body {
+    Body::wrap_stream(iter(data).map(move |chunk| {
+        sent.fetch_add(chunk.len() as u64, Ordering::SeqCst);
+        Ok::<_, StorageError>(chunk)
+    }))
+}
+
+/// Resolves once fewer than `bytes_per_second` bytes have been sent across a
+/// whole window. The window restarts each time it is checked.
+async fn stalled(sent: Arc<AtomicU64>, bytes_per_second: u64, window: Duration) -> B2Error {
+    let minimum = (u128::from(bytes_per_second) * window.as_millis() / 1000) as u64;
+    let mut last: u64 = 0;
+    loop {
+        delay_for(window).await;
+        let total = sent.load(Ordering::SeqCst);
+        if total - last < minimum.max(1) {
+            return B2Error {
+                error: error::connection_failed(Some(&format!(
+                    "The upload stalled, sending {} bytes in {:?}.",
+                    total - last,
+                    window
+                ))),
+                needs_auth: false,
+                can_retry: true,
+                retry_after: None,
+            };
+        }
+        last = total;
+    }
+}
+
+/// Resolves once an upload has been running for longer than `deadline`.
+async fn expired(deadline: Duration) -> B2Error {
+    delay_for(deadline).await;
+    B2Error {
+        error: error::connection_failed(Some(&format!(
+            "The upload did not complete within {:?}.",
+            deadline
+        ))),
+        needs_auth: false,
+        can_retry: true,
+        retry_after: None,
+    }
 }
 
 impl From<B2Error> for StorageError {
@@ -70,6 +147,7 @@ impl From<hyper::error::Error> for B2Error {
                 error,
                 needs_auth: can_retry,
                 can_retry,
+                retry_after: None,
             }
         }
 
@@ -98,6 +176,27 @@ impl From<hyper::error::Error> for B2Error {
     }
 }
 
+impl From<TransportError> for B2Error {
+    fn from(error: TransportError) -> B2Error {
+        match error {
+            TransportError::Http(e) => e.into(),
+            TransportError::Cassette(error) => B2Error {
+                error,
+                needs_auth: false,
+                can_retry: false,
+                retry_after: None,
+            },
+            #[cfg(target_arch = "wasm32")]
+            TransportError::Fetch(error) => B2Error {
+                error,
+                needs_auth: false,
+                can_retry: true,
+                retry_after: None,
+            },
+        }
+    }
+}
+
 impl From<hyper::error::Error> for StorageError {
     fn from(hyper_error: hyper::error::Error) -> StorageError {
         let b2_error: B2Error = hyper_error.into();
@@ -117,21 +216,23 @@ impl From<serde_json::error::Error> for StorageError {
     }
 }
 
-fn generate_error(method: &str, client_id: usize, path: &ObjectPath, response: &str) -> B2Error {
+fn generate_error(method: &str, client_id: usize, path: &ObjectPath, response: &[u8]) -> B2Error {
     fn error(error: StorageError) -> B2Error {
         B2Error {
             error,
             needs_auth: false,
             can_retry: false,
+            retry_after: None,
         }
     }
 
-    let error_info: ErrorResponse = match from_str(response) {
+    let error_info: ErrorResponse = match from_slice(response) {
         Ok(r) => r,
         Err(e) => {
             error!(
                 "Client {:04}: Unable to parse ErrorResponse structure from {}.",
-                client_id, response
+                client_id,
+                String::from_utf8_lossy(response)
             );
             return error(error::invalid_data(Some(&format!(
                 "Unable to parse error response from {}: {}.",
@@ -161,7 +262,7 @@ fn generate_error(method: &str, client_id: usize, path: &ObjectPath, response: &
             error(error::not_found(path.to_owned(), Some(&error_info.message)))
         }
         (400, "out_of_range") => error(error::internal_error(Some(&error_info.message))),
-        (400, "cap_exceeded") => error(error::over_quota(Some(&error_info.code))),
+        (400, "cap_exceeded") => error(error::quota_exceeded(Some(&error_info.message))),
 
         (401, "unsupported") => error(error::access_denied(Some(&error_info.message))),
         (401, "unauthorized") => error(error::access_denied(Some(&error_info.message))),
@@ -169,14 +270,19 @@ fn generate_error(method: &str, client_id: usize, path: &ObjectPath, response: &
             error: error::access_expired(Some(&error_info.message)),
             needs_auth: true,
             can_retry: true,
+            retry_after: None,
         },
         (401, "expired_auth_token") => B2Error {
             error: error::access_expired(Some(&error_info.message)),
             needs_auth: true,
             can_retry: true,
+            retry_after: None,
         },
 
-        (403, "cap_exceeded") => error(error::over_quota(Some(&error_info.code))),
+        (403, "cap_exceeded")
+        | (403, "storage_cap_exceeded")
+        | (403, "transaction_cap_exceeded")
+        | (403, "download_cap_exceeded") => error(error::quota_exceeded(Some(&error_info.message))),
 
         (404, "not_found") => error(error::not_found(path.clone(), Some(&error_info.message))),
 
@@ -186,25 +292,29 @@ fn generate_error(method: &str, client_id: usize, path: &ObjectPath, response: &
             error: error::connection_closed(Some(&error_info.message)),
             needs_auth: true,
             can_retry: true,
+            retry_after: None,
         },
 
         (416, "range_not_satisfiable") => error(error::internal_error(Some(&error_info.message))),
 
-        (429, "too_many_requests") => B2Error {
-            error: error::over_quota(Some(&error_info.message)),
+        (429, _) => B2Error {
+            error: error::rate_limited(Some(&error_info.message)),
             needs_auth: true,
             can_retry: true,
+            retry_after: None,
         },
 
         (500, "internal_error") => B2Error {
             error: error::service_error(Some(&error_info.message)),
             needs_auth: true,
             can_retry: true,
+            retry_after: None,
         },
-        (503, "bad_request") => B2Error {
-            error: error::service_error(Some(&error_info.message)),
+        (503, _) => B2Error {
+            error: error::rate_limited(Some(&error_info.message)),
             needs_auth: true,
             can_retry: true,
+            retry_after: None,
         },
 
         (status, _) => {
@@ -215,18 +325,21 @@ fn generate_error(method: &str, client_id: usize, path: &ObjectPath, response: &
                     error: error::access_expired(Some(&error_info.message)),
                     needs_auth: true,
                     can_retry: true,
+                    retry_after: None,
                 }
             } else if status >= 500 && status < 600 {
                 B2Error {
                     error: error::service_error(Some(&error_info.message)),
                     needs_auth: true,
                     can_retry: true,
+                    retry_after: None,
                 }
             } else {
                 B2Error {
                     error: error::other_error(Some(&error_info.message)),
                     needs_auth: true,
                     can_retry: true,
+                    retry_after: None,
                 }
             }
         }
@@ -246,6 +359,42 @@ macro_rules! b2_api {
     }
 }
 
+/// The encryption settings to send when starting a large file.
+pub(super) fn server_side_encryption(encryption: &Encryption) -> Option<ServerSideEncryption> {
+    match encryption {
+        Encryption::None => None,
+        Encryption::Managed => Some(ServerSideEncryption {
+            mode: String::from("SSE-B2"),
+            algorithm: SSE_ALGORITHM.to_owned(),
+            customer_key: None,
+            customer_key_md5: None,
+        }),
+        Encryption::Customer(key) => Some(ServerSideEncryption {
+            mode: String::from("SSE-C"),
+            algorithm: SSE_ALGORITHM.to_owned(),
+            customer_key: Some(encode(key.key())),
+            customer_key_md5: Some(encode(&md5::compute(key.key()).0)),
+        }),
+    }
+}
+
+/// Adds the headers that supply a customer's key to encrypt or decrypt with.
+fn add_customer_key(builder: &mut Builder, key: &CustomerKey) {
+    builder
+        .header(B2_HEADER_SSE_C_ALGORITHM, SSE_ALGORITHM)
+        .header(B2_HEADER_SSE_C_KEY, encode(key.key()))
+        .header(B2_HEADER_SSE_C_KEY_MD5, encode(&md5::compute(key.key()).0));
+}
+
+/// Reads the entire body of a response.
+///
+/// The chunks are concatenated into a single buffer without blocking and the
+/// JSON is then parsed directly from that buffer, avoiding a copy into a
+/// `String` which matters for large listing responses.
+async fn read_body(body: Body) -> B2Result<Chunk> {
+    Ok(body.try_concat().await?)
+}
+
 #[derive(Debug, Clone)]
 pub(super) struct B2Client {}
 
@@ -276,13 +425,12 @@ impl B2Client {
         if response.status().is_success() {
             Ok(response)
         } else {
+            let retry_after = parse_retry_after(&response);
             let (_, body) = response.into_parts();
-
-            let mut data: String = String::new();
-            BlockingStreamReader::from_stream(body)
-                .read_to_string(&mut data)
-                .unwrap();
-            Err(generate_error(method, id, &path, &data))
+            let data = read_body(body).await?;
+            let mut error = generate_error(method, id, &path, &data);
+            error.retry_after = retry_after;
+            Err(error)
         }
     }
 
@@ -298,16 +446,12 @@ impl B2Client {
     {
         let response = B2Client::request(id, method, path, &client, request).await?;
         let (_, body) = response.into_parts();
-
-        let mut data: String = String::new();
-        BlockingStreamReader::from_stream(body)
-            .read_to_string(&mut data)
-            .unwrap();
+        let data = read_body(body).await?;
 
         // Make sure that client stays alive until the request is complete.
         client.release();
 
-        match from_str(&data) {
+        match from_slice(&data) {
             Ok(r) => {
                 trace!("Client {:04}: {} api method returned {:?}", id, method, r);
                 Ok(r)
@@ -321,6 +465,7 @@ impl B2Client {
                     ))),
                     needs_auth: false,
                     can_retry: true,
+                    retry_after: None,
                 })
             }
         }
@@ -340,11 +485,12 @@ impl B2Client {
             secret
         );
 
-        let request = Request::builder()
+        let request = settings
+            .transport
+            .request_builder()
             .method(Method::GET)
             .uri(B2Client::api_url(&settings.host, "b2_authorize_account"))
             .header(header::AUTHORIZATION, secret)
-            .header(header::USER_AGENT, settings.user_agent)
             .body(Body::empty())?;
 
         let empty = ObjectPath::empty();
@@ -353,12 +499,45 @@ impl B2Client {
     }
 }
 
+/// Remembers the buckets found by name so that every operation doesn't need
+/// to call `b2_list_buckets`.
+#[derive(Debug, Default)]
+pub(super) struct BucketCache {
+    buckets: Mutex<HashMap<String, (Bucket, Instant)>>,
+}
+
+impl BucketCache {
+    fn get(&self, name: &str, ttl: Duration) -> Option<Bucket> {
+        let mut buckets = self.buckets.lock().unwrap();
+        match buckets.get(name) {
+            Some((bucket, found)) if found.elapsed() < ttl => Some(bucket.clone()),
+            Some(_) => {
+                buckets.remove(name);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&self, bucket: Bucket, ttl: Duration) {
+        if ttl > Duration::from_secs(0) {
+            let mut buckets = self.buckets.lock().unwrap();
+            buckets.insert(bucket.bucket_name.clone(), (bucket, Instant::now()));
+        }
+    }
+
+    fn remove(&self, name: &str) {
+        self.buckets.lock().unwrap().remove(name);
+    }
+}
+
 #[derive(Debug)]
 pub(super) struct B2APIState {
     pub settings: B2Settings,
     pub clients: ClientPool,
     pub next_id: Arc<AtomicUsize>,
     pub auth_tokens: Pool<(B2Settings, ClientPool), AuthorizeAccountResponse, StorageError>,
+    pub buckets: Arc<BucketCache>,
 }
 
 impl Clone for B2APIState {
@@ -368,6 +547,7 @@ impl Clone for B2APIState {
             clients: self.clients.clone(),
             next_id: self.next_id.clone(),
             auth_tokens: self.auth_tokens.clone(),
+            buckets: self.buckets.clone(),
         }
     }
 }
@@ -392,12 +572,78 @@ impl B2API {
         }
     }
 
+    /// Waits before the next attempt after an error, returning false if the
+    /// retry policy doesn't allow another.
+    async fn retry(&self, error: &B2Error, tries: usize, started: Instant) -> bool {
+        let policy = &self.state.settings.retry;
+        let delay = error.backoff(policy, tries);
+        if !policy.should_retry(
+            &error.error.kind(),
+            error.can_retry,
+            tries,
+            started.elapsed(),
+            delay.unwrap_or_default(),
+        ) {
+            return false;
+        }
+
+        if let Some(delay) = delay {
+            warn!(
+                "Client {:04}: Rate limited, retrying in {:?}",
+                self.id, delay
+            );
+            delay_for(delay).await;
+        }
+
+        true
+    }
+
+    /// Waits for an upload, failing it with a transient error if a minimum
+    /// upload throughput is set and the upload falls below it or if an upload
+    /// deadline is set and the upload doesn't complete in time.
+    async fn watch<F, R>(&self, upload: F, sent: Arc<AtomicU64>) -> B2Result<R>
+    where
+        F: Future<Output = B2Result<R>>,
+    {
+        let settings = &self.state.settings;
+        if settings.min_upload_throughput.is_none() && settings.upload_deadline.is_none() {
+            return upload.await;
+        }
+
+        let watchdog = match settings.min_upload_throughput {
+            Some((bytes_per_second, window)) => {
+                Either::Left(stalled(sent, bytes_per_second, window))
+            }
+            None => Either::Right(pending()),
+        };
+        let deadline = match settings.upload_deadline {
+            Some(deadline) => Either::Left(expired(deadline)),
+            None => Either::Right(pending()),
+        };
+
+        let failure = async move {
+            match select(Box::pin(watchdog), Box::pin(deadline)).await {
+                Either::Left((error, _)) => error,
+                Either::Right((error, _)) => error,
+            }
+        };
+
+        match select(Box::pin(upload), Box::pin(failure)).await {
+            Either::Left((result, _)) => result,
+            Either::Right((error, _)) => {
+                warn!("Client {:04}: {}", self.id, error.error);
+                Err(error)
+            }
+        }
+    }
+
     async fn b2_api_call<S, Q>(self, method: &str, path: ObjectPath, request: S) -> StorageResult<Q>
     where
         S: serde::ser::Serialize + Clone + fmt::Debug,
         for<'de> Q: serde::de::Deserialize<'de> + fmt::Debug,
     {
         let mut tries: usize = 0;
+        let started = Instant::now();
         loop {
             let mut auth_info = self.state.auth_tokens.acquire().await?;
 
@@ -410,11 +656,14 @@ impl B2API {
             );
             let data = to_string(&request)?;
 
-            let request = Request::builder()
+            let request = self
+                .state
+                .settings
+                .transport
+                .request_builder()
                 .method(Method::POST)
                 .uri(B2Client::api_url(&auth_info.api_url, method))
                 .header(header::AUTHORIZATION, &auth_info.authorization_token)
-                .header(header::USER_AGENT, &self.state.settings.user_agent)
                 .body(data.into())?;
 
             let client = self.state.clients.acquire().await;
@@ -428,7 +677,7 @@ impl B2API {
 
                     tries += 1;
 
-                    if !e.can_retry || tries >= MAX_API_RETRIES {
+                    if !self.retry(&e, tries, started).await {
                         return Err(e.into());
                     }
                 }
@@ -436,6 +685,52 @@ impl B2API {
         }
     }
 
+    /// Finds the bucket with the given name, returning `None` if it doesn't
+    /// exist. Results are cached for the configured time.
+    pub async fn bucket(&self, path: ObjectPath, name: String) -> StorageResult<Option<Bucket>> {
+        let ttl = self.state.settings.bucket_cache_ttl;
+        if let Some(bucket) = self.state.buckets.get(&name, ttl) {
+            trace!("Client {:04}: Found bucket {} in the cache", self.id, name);
+            return Ok(Some(bucket));
+        }
+
+        let request = ListBucketsRequest {
+            account_id: self.account_info().await?.account_id,
+            bucket_id: None,
+            bucket_name: Some(name),
+            bucket_types: Default::default(),
+        };
+
+        let mut buckets = self.b2_list_buckets(path, request).await?.buckets;
+        if buckets.len() != 1 {
+            return Ok(None);
+        }
+
+        let bucket = buckets.remove(0);
+        self.state.buckets.insert(bucket.clone(), ttl);
+        Ok(Some(bucket))
+    }
+
+    /// Remembers the current details of a bucket, replacing anything already
+    /// cached.
+    pub fn cache_bucket(&self, bucket: Bucket) {
+        self.state
+            .buckets
+            .insert(bucket, self.state.settings.bucket_cache_ttl);
+    }
+
+    /// Forgets any cached information about the named bucket.
+    ///
+    /// Called when an operation fails as not found in case the bucket was
+    /// deleted or recreated.
+    pub fn invalidate_bucket(&self, name: &str) {
+        self.state.buckets.remove(name);
+    }
+
+    pub fn settings(&self) -> &B2Settings {
+        &self.state.settings
+    }
+
     pub async fn account_info(&self) -> StorageResult<AuthorizeAccountResponse> {
         let auth_info = self.state.auth_tokens.acquire().await?;
         let mut account_info = auth_info.deref().clone();
@@ -443,50 +738,102 @@ impl B2API {
         Ok(account_info)
     }
 
+    /// The base url that files are downloaded from.
+    pub async fn download_url(&self) -> StorageResult<String> {
+        if let Some(ref host) = self.state.settings.download_host {
+            return Ok(host.clone());
+        }
+
+        let auth_info = self.state.auth_tokens.acquire().await?;
+        Ok(auth_info.download_url.clone())
+    }
+
     pub async fn b2_download_file_by_name(
         self,
         path: ObjectPath,
         bucket: String,
         file: String,
-    ) -> StorageResult<impl Stream<Item = Result<Chunk, hyper::Error>>> {
+        key: Option<CustomerKey>,
+    ) -> StorageResult<(Option<u64>, impl Stream<Item = Result<Chunk, hyper::Error>>)> {
+        let target = format!(
+            "/file/{}/{}",
+            percent_encode(&bucket),
+            percent_encode(&file)
+        );
+        self.download(path, "b2_download_file_by_name", target, None, key)
+            .await
+    }
+
+    /// Downloads the given range of a file by its id.
+    pub async fn b2_download_file_by_id(
+        self,
+        path: ObjectPath,
+        file_id: String,
+        range: Option<Range<u64>>,
+        key: Option<CustomerKey>,
+    ) -> StorageResult<(Option<u64>, impl Stream<Item = Result<Chunk, hyper::Error>>)> {
+        let target = format!(
+            "/b2api/v2/b2_download_file_by_id?fileId={}",
+            percent_encode(&file_id)
+        );
+        self.download(path, "b2_download_file_by_id", target, range, key)
+            .await
+    }
+
+    /// Downloads from a url relative to the download url.
+    async fn download(
+        self,
+        path: ObjectPath,
+        method: &'static str,
+        target: String,
+        range: Option<Range<u64>>,
+        key: Option<CustomerKey>,
+    ) -> StorageResult<(Option<u64>, impl Stream<Item = Result<Chunk, hyper::Error>>)> {
         let mut tries: usize = 0;
+        let started = Instant::now();
         loop {
             let mut auth_info = self.state.auth_tokens.acquire().await?;
 
             trace!(
                 "Client {:04}: Starting {} api call (attempt {})",
                 self.id,
-                "b2_download_file_by_name",
+                method,
                 tries + 1,
             );
 
-            let request = Request::builder()
+            let download_url = match self.state.settings.download_host {
+                Some(ref host) => host,
+                None => &auth_info.download_url,
+            };
+
+            let mut builder = self.state.settings.transport.request_builder();
+            builder
                 .method(Method::GET)
                 .header(header::AUTHORIZATION, &auth_info.authorization_token)
-                .header(header::USER_AGENT, &self.state.settings.user_agent)
-                .uri(format!(
-                    "{}/file/{}/{}",
-                    auth_info.download_url,
-                    percent_encode(&bucket),
-                    percent_encode(&file)
-                ))
-                .body(Body::empty())?;
+                .uri(format!("{}{}", download_url, target));
+            if let Some(ref range) = range {
+                builder.header(
+                    header::RANGE,
+                    format!("bytes={}-{}", range.start, range.end - 1),
+                );
+            }
+            if let Some(ref key) = key {
+                add_customer_key(&mut builder, key);
+            }
+            let request = builder.body(Body::empty())?;
 
             let mut client = self.state.clients.acquire().await;
-            match B2Client::request(
-                self.id,
-                "b2_download_file_by_name",
-                path.clone(),
-                &client,
-                request,
-            )
-            .await
-            {
+            match B2Client::request(self.id, method, path.clone(), &client, request).await {
                 Ok(response) => {
+                    let length = response
+                        .headers()
+                        .get(header::CONTENT_LENGTH)
+                        .and_then(|v| v.to_str().ok())
+                        .and_then(|s| s.parse::<u64>().ok());
                     let (_, body) = response.into_parts();
                     let stream = AfterStream::after(body, move || client.release());
 
-                    return Ok(stream);
+                    return Ok((length, stream));
                 }
                 Err(e) => {
                     client.release();
@@ -496,7 +843,7 @@ impl B2API {
 
                     tries += 1;
 
-                    if !e.can_retry || tries >= MAX_API_RETRIES {
+                    if !self.retry(&e, tries, started).await {
                         return Err(e.into());
                     }
                 }
@@ -516,16 +863,17 @@ impl B2API {
         length: u64,
         hash: String,
         data: Vec<Data>,
+        encryption: Encryption,
     ) -> StorageResult<UploadFileResponse> {
         let mut tries: usize = 0;
+        let started = Instant::now();
 
         loop {
-            let mut builder = Request::builder();
+            let mut builder = self.state.settings.transport.request_builder();
             builder
                 .method(Method::POST)
                 .uri(&url)
                 .header(header::AUTHORIZATION, &auth)
-                .header(header::USER_AGENT, &self.state.settings.user_agent)
                 .header(B2_HEADER_FILE_NAME, percent_encode(&file_name))
                 .header(header::CONTENT_TYPE, &content_type)
                 .header(header::CONTENT_LENGTH, length)
@@ -535,19 +883,26 @@ impl B2API {
                 builder.header(&format!("{}{}", B2_HEADER_FILE_INFO_PREFIX, key), value);
             }
 
-            let request = builder.body(Body::wrap_stream(
-                iter(data.clone()).map(Ok::<_, StorageError>),
-            ))?;
+            match encryption {
+                Encryption::None => (),
+                Encryption::Managed => {
+                    builder.header(B2_HEADER_SSE, SSE_ALGORITHM);
+                }
+                Encryption::Customer(ref key) => add_customer_key(&mut builder, key),
+            }
+
+            let sent = Arc::new(AtomicU64::new(0));
+            let request = builder.body(counted_body(data.clone(), sent.clone()))?;
 
             let client = self.state.clients.acquire().await;
-            match B2Client::basic_request(self.id, "b2_upload_file", path.clone(), client, request)
-                .await
-            {
+            let upload =
+                B2Client::basic_request(self.id, "b2_upload_file", path.clone(), client, request);
+            match self.watch(upload, sent).await {
                 Ok(response) => return Ok(response),
                 Err(e) => {
                     tries += 1;
 
-                    if !e.can_retry || tries >= MAX_API_RETRIES {
+                    if !self.retry(&e, tries, started).await {
                         return Err(e.into());
                     }
                 }
@@ -555,6 +910,7 @@ impl B2API {
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub async fn b2_upload_part(
         self,
         path: ObjectPath,
@@ -563,31 +919,35 @@ impl B2API {
         length: u64,
         hash: String,
         data: Vec<Data>,
+        key: Option<CustomerKey>,
     ) -> StorageResult<UploadPartResponse> {
         let mut tries: usize = 0;
+        let started = Instant::now();
 
         loop {
-            let request = Request::builder()
+            let mut builder = self.state.settings.transport.request_builder();
+            builder
                 .method(Method::POST)
                 .uri(&upload_url.upload_url)
                 .header(header::AUTHORIZATION, &upload_url.authorization_token)
-                .header(header::USER_AGENT, &self.state.settings.user_agent)
                 .header(B2_HEADER_PART_NUMBER, part)
                 .header(header::CONTENT_LENGTH, length)
-                .header(B2_HEADER_CONTENT_SHA1, &hash)
-                .body(Body::wrap_stream(
-                    iter(data.clone()).map(Ok::<_, StorageError>),
-                ))?;
+                .header(B2_HEADER_CONTENT_SHA1, &hash);
+            if let Some(ref key) = key {
+                add_customer_key(&mut builder, key);
+            }
+            let sent = Arc::new(AtomicU64::new(0));
+            let request = builder.body(counted_body(data.clone(), sent.clone()))?;
 
             let client = self.state.clients.acquire().await;
-            match B2Client::basic_request(self.id, "b2_upload_part", path.clone(), client, request)
-                .await
-            {
+            let upload =
+                B2Client::basic_request(self.id, "b2_upload_part", path.clone(), client, request);
+            match self.watch(upload, sent).await {
                 Ok(response) => return Ok(response),
                 Err(e) => {
                     tries += 1;
 
-                    if !e.can_retry || tries >= MAX_API_RETRIES {
+                    if !self.retry(&e, tries, started).await {
                         return Err(e.into());
                     }
                 }
@@ -596,6 +956,7 @@ impl B2API {
     }
 
     b2_api!(b2_list_buckets, ListBucketsRequest, ListBucketsResponse);
+    b2_api!(b2_update_bucket, UpdateBucketRequest, UpdateBucketResponse);
     b2_api!(b2_get_file_info, GetFileInfoRequest, GetFileInfoResponse);
     b2_api!(
         b2_list_file_names,
@@ -612,6 +973,7 @@ impl B2API {
         DeleteFileVersionRequest,
         DeleteFileVersionResponse
     );
+    b2_api!(b2_hide_file, HideFileRequest, HideFileResponse);
     b2_api!(b2_get_upload_url, GetUploadUrlRequest, GetUploadUrlResponse);
     b2_api!(
         b2_start_large_file,
@@ -623,9 +985,26 @@ impl B2API {
         GetUploadPartUrlRequest,
         GetUploadPartUrlResponse
     );
+    b2_api!(b2_copy_part, CopyPartRequest, CopyPartResponse);
     b2_api!(
         b2_finish_large_file,
         FinishLargeFileRequest,
         FinishLargeFileResponse
     );
+    b2_api!(
+        b2_list_unfinished_large_files,
+        ListUnfinishedLargeFilesRequest,
+        ListUnfinishedLargeFilesResponse
+    );
+    b2_api!(b2_list_parts, ListPartsRequest, ListPartsResponse);
+    b2_api!(
+        b2_cancel_large_file,
+        CancelLargeFileRequest,
+        CancelLargeFileResponse
+    );
+    b2_api!(
+        b2_get_download_authorization,
+        GetDownloadAuthorizationRequest,
+        GetDownloadAuthorizationResponse
+    );
 }
@@ -0,0 +1,74 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Large file uploads that were started but never finished.
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use storage_types::b2::v2::responses::FileInfo;
+
+use crate::types::*;
+
+/// Future returned when listing or cancelling unfinished uploads.
+pub type UnfinishedUploadsFuture = WrappedFuture<StorageResult<Vec<UnfinishedUpload>>>;
+
+/// A large file upload that was started but never finished or cancelled.
+///
+/// B2 keeps the parts of these uploads, and charges for storing them, until
+/// the upload is cancelled.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnfinishedUpload {
+    path: ObjectPath,
+    file_id: String,
+    started: SystemTime,
+}
+
+impl UnfinishedUpload {
+    pub(super) fn new(bucket: &str, info: FileInfo, prefix: &ObjectPath) -> StorageResult<Self> {
+        let file_id = match info.file_id {
+            Some(id) => id,
+            None => {
+                return Err(error::internal_error(Some(
+                    "Expected unfinished upload to have a file id.",
+                )))
+            }
+        };
+
+        let mut path = ObjectPath::new(&info.file_name)?;
+        path.shift_part(bucket);
+        for _ in prefix.parts() {
+            path.unshift_part();
+        }
+
+        Ok(UnfinishedUpload {
+            path,
+            file_id,
+            started: UNIX_EPOCH + Duration::from_millis(info.upload_timestamp),
+        })
+    }
+
+    /// The path the file would have been written to.
+    pub fn path(&self) -> &ObjectPath {
+        &self.path
+    }
+
+    /// B2's ID for the upload.
+    pub fn id(&self) -> &str {
+        &self.file_id
+    }
+
+    /// When the upload was started.
+    pub fn started(&self) -> SystemTime {
+        self.started
+    }
+}
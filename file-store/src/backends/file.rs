@@ -19,42 +19,63 @@
 //! used as the root of the files visible through the returned
 //! [`FileStore`](../../enum.FileStore.html).
 //!
-//! Directories and symlinks cannot be created but will be visible through
-//! [`list_objects`](../../enum.FileStore.html#method.list_objects) and
-//! [`get_object`](../../enum.FileStore.html#method.get_objects).
+//! Directories and symlinks cannot be created directly but will be visible
+//! through [`list_objects`](../../enum.FileStore.html#method.list_objects) and
+//! [`get_object`](../../enum.FileStore.html#method.get_objects). Writing or
+//! copying a file creates any missing directories in its path.
 //! [`delete_object`](../../enum.FileStore.html#method.delete_object) and
 //! [`write_file_from_stream`](../../enum.FileStore.html#method.write_file_from_stream)
 //! will remove these (in the directory case recursively).
+use std::cmp::Ordering;
+use std::collections::{HashSet, VecDeque};
 use std::convert::TryInto;
-use std::fs::Metadata;
+use std::ffi::OsString;
+use std::fs::{self, Metadata};
 use std::io;
-use std::path::{Path, PathBuf};
+#[cfg(unix)]
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
 use std::pin::Pin;
+use std::str;
 use std::task::{Context, Poll};
-use std::time::SystemTime;
+use std::time::{Instant, SystemTime};
 
 use bytes::IntoBuf;
 use filetime::{set_file_mtime, FileTime};
 use futures::future::{ready, Future, FutureExt, TryFutureExt};
-use futures::stream::{empty, once, Stream, StreamExt, TryStreamExt};
+use futures::stream::{
+    empty, iter, FuturesOrdered, FuturesUnordered, Stream, StreamExt, TryStreamExt,
+};
 use log::{trace, warn};
+use tokio_executor::blocking;
 use tokio_fs::DirEntry;
 use tokio_io::AsyncWriteExt;
 
 use super::Backend;
+use crate::instrument::Operation;
+use crate::trash;
 use crate::types::error;
-use crate::types::stream::{MergedStreams, ResultStreamPoll};
+use crate::types::stream::{LengthCheckedStream, ResultStreamPoll};
 use crate::types::*;
-use crate::utils::{into_data_stream, ReaderStream};
+use crate::utils::{
+    buffered, into_data_stream, Limiter, Permit, ReaderStream, DEFAULT_WRITE_BUFFER_DEPTH,
+};
 use crate::{FileStore, Object, ObjectInfo, StorageBackend};
 
 // When reading from a file we start requesting INITIAL_BUFFER_SIZE bytes. As
 // data is read the available space is reduced until it reaches MIN_BUFFER_SIZE
-// at which point we allocate a new buffer of INITIAL_BUFFER_SIZE.
+// at which point we allocate a new buffer of INITIAL_BUFFER_SIZE. Both can be
+// changed with the builder or per read with ReadOptions.
 const MB: usize = 1024 * 1024;
 const INITIAL_BUFFER_SIZE: usize = 20 * MB;
 const MIN_BUFFER_SIZE: usize = MB;
 
+// The number of directories read in parallel when listing objects.
+const DEFAULT_LIST_CONCURRENCY: usize = 16;
+
+// The longest file name most filesystems allow.
+const MAX_NAME_LENGTH: usize = 255;
+
 async fn read_dir<P>(path: P) -> io::Result<tokio_fs::ReadDir>
 where
     P: AsRef<Path> + Send + 'static,
@@ -69,6 +90,39 @@ where
     result
 }
 
+async fn create_dir_all<P>(path: P) -> io::Result<()>
+where
+    P: AsRef<Path> + Send + 'static,
+{
+    let path = path.as_ref().to_owned();
+    let result = tokio_fs::create_dir_all(path.clone()).await;
+    match result {
+        Ok(_) => trace!("tokio_fs::create_dir_all {} success", path.display()),
+        Err(ref e) => trace!("tokio_fs::create_dir_all {} failed: {}", path.display(), e),
+    }
+
+    result
+}
+
+async fn rename(source: PathBuf, target: PathBuf) -> io::Result<()> {
+    let result = tokio_fs::rename(source.clone(), target.clone()).await;
+    match result {
+        Ok(_) => trace!(
+            "tokio_fs::rename {} to {} success",
+            source.display(),
+            target.display()
+        ),
+        Err(ref e) => trace!(
+            "tokio_fs::rename {} to {} failed: {}",
+            source.display(),
+            target.display(),
+            e
+        ),
+    }
+
+    result
+}
+
 async fn remove_dir<P>(path: P) -> io::Result<()>
 where
     P: AsRef<Path> + Send + 'static,
@@ -88,7 +142,18 @@ where
     P: AsRef<Path> + Send + 'static,
 {
     let path = path.as_ref().to_owned();
-    let result = tokio_fs::remove_file(path.clone()).await;
+    let mut result = tokio_fs::remove_file(path.clone()).await;
+
+    // Windows refuses to delete files with the read-only attribute set.
+    #[cfg(windows)]
+    {
+        if let Err(ref e) = result {
+            if e.kind() == io::ErrorKind::PermissionDenied && clear_readonly(&path) {
+                result = tokio_fs::remove_file(path.clone()).await;
+            }
+        }
+    }
+
     match result {
         Ok(_) => trace!("tokio_fs::remove_file {} success", path.display()),
         Err(ref e) => trace!("tokio_fs::remove_file {} failed: {}", path.display(), e),
@@ -97,6 +162,71 @@ where
     result
 }
 
+#[cfg(unix)]
+async fn set_permissions<P>(path: P, permissions: fs::Permissions) -> io::Result<()>
+where
+    P: AsRef<Path> + Send + 'static,
+{
+    let path = path.as_ref().to_owned();
+    let result = tokio_fs::set_permissions(path.clone(), permissions).await;
+    match result {
+        Ok(_) => trace!("tokio_fs::set_permissions {} success", path.display()),
+        Err(ref e) => trace!("tokio_fs::set_permissions {} failed: {}", path.display(), e),
+    }
+
+    result
+}
+
+/// Syncs a written file and the directory containing it to disk.
+async fn sync_file(path: PathBuf) -> io::Result<()> {
+    let mut file = File::open(path.clone()).await?;
+    let result = file.sync_all().await;
+    match result {
+        Ok(_) => trace!("tokio_fs::File::sync_all {} success", path.display()),
+        Err(ref e) => trace!("tokio_fs::File::sync_all {} failed: {}", path.display(), e),
+    }
+    result?;
+
+    // Directories cannot be opened as files on Windows.
+    #[cfg(unix)]
+    {
+        if let Some(parent) = path.parent() {
+            let mut directory = File::open(parent.to_owned()).await?;
+            directory.sync_all().await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Clears the read-only attribute of a file returning true if it was set.
+#[cfg(windows)]
+fn clear_readonly(path: &Path) -> bool {
+    let mut permissions = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata.permissions(),
+        Err(_) => return false,
+    };
+
+    if !permissions.readonly() {
+        return false;
+    }
+
+    permissions.set_readonly(false);
+    fs::set_permissions(path, permissions).is_ok()
+}
+
+/// Gets the metadata for a directory entry, following symlinks when `follow`
+/// is true. Broken symlinks return the metadata of the link itself.
+async fn entry_metadata(path: PathBuf, follow: bool) -> io::Result<Metadata> {
+    if follow {
+        if let Ok(metadata) = tokio_fs::metadata(path.clone()).await {
+            return Ok(metadata);
+        }
+    }
+
+    symlink_metadata(path).await
+}
+
 async fn symlink_metadata<P>(path: P) -> io::Result<Metadata>
 where
     P: AsRef<Path> + Send + 'static,
@@ -145,11 +275,41 @@ impl File {
 
         result
     }
+
+    pub async fn create_new<P>(path: P) -> io::Result<tokio_fs::File>
+    where
+        P: AsRef<Path> + 'static,
+    {
+        let path = path.as_ref().to_owned();
+        let result = tokio_fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path.clone())
+            .await;
+        match result {
+            Ok(_) => trace!("tokio_fs::File::create_new {} success", path.display()),
+            Err(ref e) => trace!(
+                "tokio_fs::File::create_new {} failed: {}",
+                path.display(),
+                e
+            ),
+        }
+
+        result
+    }
 }
 
 fn get_storage_error(error: io::Error, path: ObjectPath) -> StorageError {
+    #[cfg(unix)]
+    {
+        if error.raw_os_error() == Some(libc::ENOSPC) {
+            return error::insufficient_space(Some(&error.to_string()));
+        }
+    }
+
     match error.kind() {
         io::ErrorKind::NotFound => error::not_found(path, Some(&error.to_string())),
+        io::ErrorKind::AlreadyExists => error::already_exists(path, Some(&error.to_string())),
         _ => error::other_error(Some(&error.to_string())),
     }
 }
@@ -210,42 +370,221 @@ impl ObjectInfo for FileObject {
             .as_ref()
             .and_then(|m| if m.is_file() { m.modified().ok() } else { None })
     }
+
+    fn created(&self) -> Option<SystemTime> {
+        self.metadata.as_ref().and_then(|m| m.created().ok())
+    }
+
+    fn accessed(&self) -> Option<SystemTime> {
+        self.metadata.as_ref().and_then(|m| m.accessed().ok())
+    }
+}
+
+#[cfg(unix)]
+impl FileObject {
+    /// Gets the object's permission bits, including the setuid, setgid and
+    /// sticky bits.
+    pub fn mode(&self) -> Option<u32> {
+        self.metadata.as_ref().map(|m| m.mode() & 0o7777)
+    }
+
+    /// Gets the id of the user that owns the object.
+    pub fn uid(&self) -> Option<u32> {
+        self.metadata.as_ref().map(|m| m.uid())
+    }
+
+    /// Gets the id of the group that owns the object.
+    pub fn gid(&self) -> Option<u32> {
+        self.metadata.as_ref().map(|m| m.gid())
+    }
 }
 
 fn get_object(path: ObjectPath, metadata: Option<Metadata>) -> Object {
     Object::from(FileObject { path, metadata })
 }
 
+/// How file names that are not valid unicode are handled when listing.
+///
+/// Object paths must be valid unicode so these files cannot be represented
+/// exactly. With `Lossy` or `Escape` the object is listed but its path
+/// cannot be used to access the file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NonUnicodeNames {
+    /// Fail the listing with an error. This is the default.
+    Fail,
+    /// Leave the file out of the listing, logging a warning.
+    Skip,
+    /// Replace invalid sequences with `U+FFFD REPLACEMENT CHARACTER`.
+    Lossy,
+    /// Percent-encode invalid bytes and any `%` characters in the name.
+    Escape,
+}
+
+impl Default for NonUnicodeNames {
+    fn default() -> NonUnicodeNames {
+        NonUnicodeNames::Fail
+    }
+}
+
+/// Percent-encodes the bytes of a name that are not valid UTF-8.
+fn escape_name(bytes: &[u8]) -> String {
+    let mut result = String::new();
+    let mut remaining = bytes;
+    loop {
+        match str::from_utf8(remaining) {
+            Ok(valid) => {
+                result.push_str(&valid.replace('%', "%25"));
+                return result;
+            }
+            Err(e) => {
+                let (valid, rest) = remaining.split_at(e.valid_up_to());
+                if let Ok(valid) = str::from_utf8(valid) {
+                    result.push_str(&valid.replace('%', "%25"));
+                }
+
+                let invalid = e.error_len().unwrap_or_else(|| rest.len());
+                for byte in &rest[..invalid] {
+                    result.push_str(&format!("%{:02X}", byte));
+                }
+                remaining = &rest[invalid..];
+            }
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 struct FileSpace {
     base: PathBuf,
+    files: Limiter,
+    names: NonUnicodeNames,
 }
 
 impl FileSpace {
+    /// Converts an `ObjectPath` to a path on the local filesystem.
+    ///
+    /// Every part must be a plain file name so that the result cannot escape
+    /// the root, only a trailing empty part (a directory prefix) is allowed.
     fn get_std_path(&self, path: &ObjectPath) -> StorageResult<PathBuf> {
+        let parts = path.parts();
         let mut result = self.base.clone();
-        for part in path.parts() {
-            result.push(part);
+        for (index, part) in parts.iter().enumerate() {
+            if part.is_empty() && index == parts.len() - 1 {
+                break;
+            }
+
+            if part.len() > MAX_NAME_LENGTH {
+                return Err(error::invalid_path(
+                    path.clone(),
+                    Some(&format!(
+                        "Path parts cannot be longer than {} bytes.",
+                        MAX_NAME_LENGTH
+                    )),
+                ));
+            }
+
+            if part.contains('\0') {
+                return Err(error::invalid_path(
+                    path.clone(),
+                    Some("Path parts cannot contain null characters."),
+                ));
+            }
+
+            // On Windows a ':' would address an alternate data stream.
+            if cfg!(windows) && part.contains(':') {
+                return Err(error::invalid_path(
+                    path.clone(),
+                    Some("Path parts cannot contain ':'."),
+                ));
+            }
+
+            let mut components = Path::new(part).components();
+            match (components.next(), components.next()) {
+                (Some(Component::Normal(_)), None) => result.push(part),
+                _ => {
+                    return Err(error::invalid_path(
+                        path.clone(),
+                        Some("Path parts cannot be empty, '.', '..' or contain separators."),
+                    ))
+                }
+            }
         }
 
         Ok(result)
     }
+
+    /// Converts a file name found in a directory to a string according to the
+    /// configured policy. Returns `None` if the file should be skipped.
+    fn file_name(&self, path: &Path, name: OsString) -> Option<StorageResult<String>> {
+        let name = match name.into_string() {
+            Ok(s) => return Some(Ok(s)),
+            Err(name) => name,
+        };
+
+        match self.names {
+            NonUnicodeNames::Fail => Some(Err(error::invalid_data(Some(&format!(
+                "The file name of {} is not valid unicode",
+                path.display()
+            ))))),
+            NonUnicodeNames::Skip => {
+                warn!(
+                    "Skipping {} as its name is not valid unicode.",
+                    path.display()
+                );
+                None
+            }
+            NonUnicodeNames::Lossy => Some(Ok(name.to_string_lossy().into_owned())),
+            #[cfg(unix)]
+            NonUnicodeNames::Escape => {
+                use std::os::unix::ffi::OsStrExt;
+                Some(Ok(escape_name(name.as_bytes())))
+            }
+            #[cfg(not(unix))]
+            NonUnicodeNames::Escape => Some(Ok(escape_name(name.to_string_lossy().as_bytes()))),
+        }
+    }
+}
+
+/// Keeps `permit` alive for as long as the stream.
+fn with_permit<S>(stream: S, permit: Permit) -> impl Stream<Item = S::Item>
+where
+    S: Stream,
+{
+    stream.map(move |item| {
+        let _permit = &permit;
+        item
+    })
 }
 
 fn directory_stream(
     space: &FileSpace,
     path: ObjectPath,
+    follow: bool,
 ) -> impl Stream<Item = StorageResult<(ObjectPath, Option<Metadata>)>> {
     #[allow(clippy::needless_lifetimes)]
     async fn build_base(
         space: &FileSpace,
         path: ObjectPath,
-    ) -> StorageResult<impl Stream<Item = StorageResult<DirEntry>>> {
+    ) -> StorageResult<Option<impl Stream<Item = StorageResult<DirEntry>>>> {
         let target = space.get_std_path(&path)?;
-        Ok(wrap_stream(
-            wrap_future(read_dir(target.clone()), path.clone()).await?,
-            path,
-        ))
+        let permit = space.files.acquire().await;
+        let entries = match read_dir(target.clone()).await {
+            Ok(entries) => entries,
+            Err(e) => {
+                // A directory that doesn't exist or is a file has no contents.
+                let missing = e.kind() == io::ErrorKind::NotFound
+                    || symlink_metadata(target)
+                        .await
+                        .map(|m| !m.is_dir())
+                        .unwrap_or(false);
+                if missing {
+                    return Ok(None);
+                }
+
+                return Err(get_storage_error(e, path));
+            }
+        };
+
+        Ok(Some(with_permit(wrap_stream(entries, path), permit)))
     }
 
     async fn start_stream(
@@ -253,36 +592,35 @@ fn directory_stream(
         path: ObjectPath,
     ) -> impl Stream<Item = StorageResult<(ObjectPath, Option<Metadata>)>> {
         let stream = match build_base(&space, path.clone()).await {
-            Ok(s) => s,
-            Err(e) => {
-                return once(ready::<StorageResult<(ObjectPath, Option<Metadata>)>>(Err(
-                    e,
-                )))
-                .left_stream()
-            }
+            Ok(Some(s)) => s,
+            Ok(None) => return iter(None::<FileList>).left_stream(),
+            Err(e) => return iter(Some(Err(e))).left_stream(),
         };
 
         stream
             .and_then(move |direntry| {
                 let fname = direntry.file_name();
                 let mut path = path.clone();
-                wrap_future(symlink_metadata(direntry.path()), path.clone()).map(move |result| {
-                    let filename = match fname.into_string() {
-                        Ok(f) => f,
-                        Err(_) => {
-                            return Err(error::invalid_data(Some("Unable to convert OSString.")))
-                        }
-                    };
-
-                    path.push_part(&filename);
-                    let maybe_meta = match result {
-                        Ok(m) => Some(m),
-                        Err(_) => None,
-                    };
-
-                    Ok((path, maybe_meta))
-                })
+                let space = space.clone();
+                wrap_future(entry_metadata(direntry.path(), follow), path.clone()).map(
+                    move |result| {
+                        let filename = match space.file_name(&direntry.path(), fname) {
+                            Some(Ok(f)) => f,
+                            Some(Err(e)) => return Err(e),
+                            None => return Ok(None),
+                        };
+
+                        path.push_part(&filename);
+                        let maybe_meta = match result {
+                            Ok(m) => Some(m),
+                            Err(_) => None,
+                        };
+
+                        Ok(Some((path, maybe_meta)))
+                    },
+                )
             })
+            .filter_map(|result| ready(result.transpose()))
             .right_stream()
     }
 
@@ -290,28 +628,191 @@ fn directory_stream(
 }
 
 type FileList = StorageResult<(ObjectPath, Option<Metadata>)>;
+type DirectoryFuture = WrappedFuture<Vec<FileList>>;
+
+/// Reads all of the entries in a directory. When `ordered` is true the entries
+/// are sorted by path, when `follow` is true symlinks are followed.
+fn read_directory(
+    space: &FileSpace,
+    path: ObjectPath,
+    ordered: bool,
+    follow: bool,
+) -> DirectoryFuture {
+    let entries = directory_stream(space, path, follow).collect::<Vec<FileList>>();
+    DirectoryFuture::from_future(entries.map(move |mut entries| {
+        if ordered {
+            // Errors sort after any entries.
+            entries.sort_by(|a, b| match (a, b) {
+                (Ok((a, _)), Ok((b, _))) => a.cmp(b),
+                (Ok(_), Err(_)) => Ordering::Less,
+                (Err(_), Ok(_)) => Ordering::Greater,
+                (Err(_), Err(_)) => Ordering::Equal,
+            });
+        }
+
+        entries
+    }))
+}
+
+/// Identifies a directory regardless of the path used to reach it.
+#[cfg(unix)]
+type DirectoryId = (u64, u64);
+#[cfg(not(unix))]
+type DirectoryId = PathBuf;
+
+#[cfg(unix)]
+fn directory_id(_path: &Path, metadata: &Metadata) -> Option<DirectoryId> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn directory_id(path: &Path, _metadata: &Metadata) -> Option<DirectoryId> {
+    fs::canonicalize(path).ok()
+}
+
+/// Checks whether `source` and `target` are the same file, either by path or
+/// through a hard link.
+fn same_file(
+    source: &Path,
+    source_metadata: &Metadata,
+    target: &Path,
+    target_metadata: &Metadata,
+) -> bool {
+    if source == target {
+        return true;
+    }
+
+    match (
+        directory_id(source, source_metadata),
+        directory_id(target, target_metadata),
+    ) {
+        (Some(source_id), Some(target_id)) => source_id == target_id,
+        _ => false,
+    }
+}
+
+enum DirectoryReads {
+    Ordered(FuturesOrdered<DirectoryFuture>),
+    Unordered(FuturesUnordered<DirectoryFuture>),
+}
+
+impl DirectoryReads {
+    fn len(&self) -> usize {
+        match self {
+            DirectoryReads::Ordered(reads) => reads.len(),
+            DirectoryReads::Unordered(reads) => reads.len(),
+        }
+    }
+
+    fn push(&mut self, future: DirectoryFuture) {
+        match self {
+            DirectoryReads::Ordered(reads) => reads.push(future),
+            DirectoryReads::Unordered(reads) => reads.push(future),
+        }
+    }
+
+    fn poll_next(&mut self, cx: &mut Context) -> Poll<Option<Vec<FileList>>> {
+        match self {
+            DirectoryReads::Ordered(reads) => reads.poll_next_unpin(cx),
+            DirectoryReads::Unordered(reads) => reads.poll_next_unpin(cx),
+        }
+    }
+}
+
+/// Lists every object beneath a prefix.
+///
+/// Up to `list_concurrency` directories are read at once. In unordered mode
+/// results are returned as soon as each directory has been read. In ordered
+/// mode directories are returned in the order they were discovered with their
+/// entries sorted, so the same tree always lists in the same order.
+///
+/// When following symlinks every directory is only listed once, so links that
+/// lead back to a directory already seen are not descended into.
 struct FileLister {
-    stream: Pin<Box<MergedStreams<FileList>>>,
     space: FileSpace,
     prefix: ObjectPath,
+    settings: FileSettings,
+    pending: VecDeque<ObjectPath>,
+    reads: DirectoryReads,
+    entries: VecDeque<FileList>,
+    visited: HashSet<DirectoryId>,
 }
 
 impl FileLister {
-    fn list(space: FileSpace, mut prefix: ObjectPath) -> FileLister {
+    fn list(space: FileSpace, mut prefix: ObjectPath, settings: &FileSettings) -> FileLister {
+        let reads = if settings.ordered_listing {
+            DirectoryReads::Ordered(FuturesOrdered::new())
+        } else {
+            DirectoryReads::Unordered(FuturesUnordered::new())
+        };
+
         let mut lister = FileLister {
-            stream: Box::pin(MergedStreams::new()),
             space,
             prefix: prefix.clone(),
+            settings: settings.clone(),
+            pending: VecDeque::new(),
+            reads,
+            entries: VecDeque::new(),
+            visited: HashSet::new(),
         };
 
         prefix.pop_part();
 
-        lister.add_directory(prefix);
+        if settings.follow_symlinks {
+            if let Ok(root) = lister.space.get_std_path(&prefix) {
+                if let Some(id) = fs::metadata(&root)
+                    .ok()
+                    .and_then(|metadata| directory_id(&root, &metadata))
+                {
+                    lister.visited.insert(id);
+                }
+            }
+        }
+
+        lister.pending.push_back(prefix);
         lister
     }
 
-    fn add_directory(&mut self, path: ObjectPath) {
-        self.stream.push(directory_stream(&self.space, path));
+    /// Checks whether a directory found while listing should be read.
+    fn should_read(&mut self, path: &ObjectPath, metadata: &Metadata) -> bool {
+        if !self.settings.follow_symlinks {
+            return true;
+        }
+
+        let target = match self.space.get_std_path(path) {
+            Ok(t) => t,
+            Err(_) => return false,
+        };
+
+        match directory_id(&target, metadata) {
+            Some(id) => {
+                if self.visited.insert(id) {
+                    true
+                } else {
+                    warn!("Not listing {} again, symlinks may form a cycle.", path);
+                    false
+                }
+            }
+            None => true,
+        }
+    }
+
+    fn start_reads(&mut self) {
+        while self.reads.len() < self.settings.list_concurrency.max(1) {
+            match self.pending.pop_front() {
+                Some(path) => {
+                    let future = read_directory(
+                        &self.space,
+                        path,
+                        self.settings.ordered_listing,
+                        self.settings.follow_symlinks,
+                    );
+                    self.reads.push(future);
+                }
+                None => break,
+            }
+        }
     }
 }
 
@@ -320,19 +821,27 @@ impl Stream for FileLister {
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> ResultStreamPoll<Object> {
         loop {
-            match self.stream.as_mut().poll_next(cx) {
-                Poll::Ready(Some(Ok((path, maybe_metadata)))) => {
+            match self.entries.pop_front() {
+                Some(Ok((path, maybe_metadata))) => {
                     if path.starts_with(&self.prefix) {
                         if let Some(ref metadata) = maybe_metadata {
-                            if metadata.is_dir() {
-                                self.add_directory(path.clone());
+                            if metadata.is_dir() && self.should_read(&path, metadata) {
+                                self.pending.push_back(path.clone());
                             }
                         }
 
                         return Poll::Ready(Some(Ok(get_object(path, maybe_metadata))));
                     }
+
+                    continue;
                 }
-                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                None => (),
+            }
+
+            self.start_reads();
+            match self.reads.poll_next(cx) {
+                Poll::Ready(Some(entries)) => self.entries = entries.into(),
                 Poll::Ready(None) => return Poll::Ready(None),
                 Poll::Pending => return Poll::Pending,
             }
@@ -340,12 +849,105 @@ impl Stream for FileLister {
     }
 }
 
+/// Gets the space available to unprivileged users on the filesystem holding
+/// the given path.
+#[cfg(unix)]
+#[allow(clippy::identity_conversion)]
+fn available_space(path: &Path) -> Option<u64> {
+    use std::ffi::CString;
+    use std::mem;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+
+    // The field types vary between platforms.
+    Some(u64::from(stat.f_bavail).saturating_mul(u64::from(stat.f_frsize)))
+}
+
+#[cfg(not(unix))]
+fn available_space(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// Fails if there is not enough space to write `length` bytes to `target`.
+///
+/// The target is checked before anything is removed so when overwriting the
+/// space used by the file being replaced counts as available. Missing parent
+/// directories are checked on the nearest existing ancestor.
+async fn check_space(target: &Path, length: u64, mode: WriteMode) -> StorageResult<()> {
+    let available = match target.ancestors().skip(1).find_map(available_space) {
+        Some(available) => available,
+        None => return Ok(()),
+    };
+
+    let replaced = match mode {
+        WriteMode::Overwrite => match symlink_metadata(target.to_owned()).await {
+            Ok(ref m) if m.is_file() => m.len(),
+            _ => 0,
+        },
+        WriteMode::FailIfExists => 0,
+    };
+    let available = available.saturating_add(replaced);
+
+    if length > available {
+        Err(error::insufficient_space(Some(&format!(
+            "{} bytes are needed but only {} bytes are available",
+            length, available
+        ))))
+    } else {
+        Ok(())
+    }
+}
+
+/// Creates any missing parent directories of a file about to be written.
+async fn create_parent(target: &Path, path: &ObjectPath) -> StorageResult<()> {
+    match target.parent() {
+        Some(parent) => wrap_future(create_dir_all(parent.to_owned()), path.clone()).await,
+        None => Ok(()),
+    }
+}
+
+/// Renames an object, creating any missing parent directories of the target.
+/// Fails if something already exists at the target.
+async fn move_object(
+    space: FileSpace,
+    source: ObjectPath,
+    target: ObjectPath,
+) -> StorageResult<()> {
+    let source_path = space.get_std_path(&source)?;
+    let target_path = space.get_std_path(&target)?;
+    wrap_future(symlink_metadata(source_path.clone()), source).await?;
+
+    match symlink_metadata(target_path.clone()).await {
+        Ok(_) => return Err(error::already_exists(target, None)),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(get_storage_error(e, target)),
+    }
+
+    create_parent(&target_path, &target).await?;
+    wrap_future(rename(source_path, target_path), target).await
+}
+
 #[allow(clippy::needless_lifetimes)]
-async fn delete_directory(space: FileSpace, path: ObjectPath) -> StorageResult<()> {
+async fn delete_directory(
+    space: FileSpace,
+    settings: FileSettings,
+    path: ObjectPath,
+) -> StorageResult<()> {
     let mut dir_path = path.clone();
     dir_path.push_part("");
 
-    let allfiles = FileLister::list(space.clone(), dir_path)
+    // Never follow symlinks out of the directory being deleted.
+    let settings = FileSettings {
+        follow_symlinks: false,
+        ..settings
+    };
+
+    let allfiles = FileLister::list(space.clone(), dir_path, &settings)
         .try_collect::<Vec<Object>>()
         .await?;
     let nondirectories = allfiles
@@ -360,7 +962,9 @@ async fn delete_directory(space: FileSpace, path: ObjectPath) -> StorageResult<(
         wrap_future(remove_file(target), file.path()).await?;
     }
 
-    for dir in directories {
+    // Directories are always listed before their contents so remove them in
+    // reverse.
+    for dir in directories.rev() {
         let target = space.get_std_path(&dir.path())?;
         wrap_future(remove_dir(target), dir.path()).await?;
     }
@@ -369,11 +973,94 @@ async fn delete_directory(space: FileSpace, path: ObjectPath) -> StorageResult<(
     wrap_future(remove_dir(target), path).await
 }
 
+/// Removes whatever exists at the path so a new file can be written there.
+async fn clear_target(
+    space: FileSpace,
+    settings: FileSettings,
+    path: ObjectPath,
+) -> StorageResult<()> {
+    let target = space.get_std_path(&path)?;
+
+    match symlink_metadata(target.clone()).await {
+        Ok(m) => {
+            if m.is_dir() {
+                delete_directory(space, settings, path).await?;
+            } else {
+                wrap_future(remove_file(target.clone()), path).await?;
+            }
+        }
+        Err(e) => {
+            if e.kind() != io::ErrorKind::NotFound {
+                return Err(get_storage_error(e, path));
+            }
+        }
+    };
+
+    Ok(())
+}
+
+/// Copies a file using `std::fs::copy` on the runtime's blocking pool.
+///
+/// The standard library uses the platform's fastest mechanism for this,
+/// `copy_file_range` on Linux (which some filesystems implement as a reflink)
+/// and `fcopyfile` on macOS, falling back to reading and writing.
+async fn copy(source: PathBuf, target: PathBuf) -> io::Result<u64> {
+    let description = format!("{} to {}", source.display(), target.display());
+    let result = blocking::run(move || fs::copy(source, target)).await;
+
+    match result {
+        Ok(_) => trace!("std::fs::copy {} success", description),
+        Err(ref e) => trace!("std::fs::copy {} failed: {}", description, e),
+    }
+
+    result
+}
+
+#[derive(Clone, Debug)]
+struct FileSettings {
+    list_concurrency: usize,
+    ordered_listing: bool,
+    read_buffer_size: usize,
+    min_read_buffer_size: usize,
+    max_operations: Option<usize>,
+    max_open_files: Option<usize>,
+    non_unicode_names: NonUnicodeNames,
+    follow_symlinks: bool,
+    trash: Option<ObjectPath>,
+    retry: RetryPolicy,
+}
+
+impl Default for FileSettings {
+    fn default() -> FileSettings {
+        FileSettings {
+            list_concurrency: DEFAULT_LIST_CONCURRENCY,
+            ordered_listing: false,
+            read_buffer_size: INITIAL_BUFFER_SIZE,
+            min_read_buffer_size: MIN_BUFFER_SIZE,
+            max_operations: None,
+            max_open_files: None,
+            non_unicode_names: Default::default(),
+            follow_symlinks: false,
+            trash: None,
+            retry: Default::default(),
+        }
+    }
+}
+
 /// The backend implementation for local file storage. Only included when the
 /// `file` feature is enabled.
 #[derive(Clone, Debug)]
 pub struct FileBackend {
     space: FileSpace,
+    settings: FileSettings,
+    operations: Limiter,
+}
+
+fn limiter(limit: Option<usize>) -> Limiter {
+    match limit {
+        Some(l) => Limiter::new(l),
+        None => Limiter::unlimited(),
+    }
 }
 
 impl FileBackend {
@@ -383,17 +1070,275 @@ impl FileBackend {
     /// The root path provided must be a directory and is used as the base of
     /// the visible storage.
     pub fn connect(root: &Path) -> ConnectFuture {
-        let target = root.to_owned();
+        FileBackend::builder(root).connect()
+    }
+
+    /// Creates a new [`FileBackendBuilder`](struct.FileBackendBuilder.html).
+    pub fn builder(root: &Path) -> FileBackendBuilder {
+        FileBackendBuilder {
+            root: root.to_owned(),
+            settings: Default::default(),
+        }
+    }
+
+    /// Sets the permission bits of the object at the given path.
+    ///
+    /// Only the lowest 12 bits of `mode` are used, the same bits returned by
+    /// [`FileObject::mode`](struct.FileObject.html#method.mode). Symlinks are
+    /// followed so this changes the permissions of the link's target.
+    #[cfg(unix)]
+    pub fn set_permissions<P>(&self, path: P, mode: u32) -> OperationCompleteFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        async fn chmod(space: FileSpace, path: ObjectPath, mode: u32) -> StorageResult<()> {
+            let target = space.get_std_path(&path)?;
+            let permissions = fs::Permissions::from_mode(mode & 0o7777);
+            wrap_future(set_permissions(target, permissions), path).await
+        }
+
+        let path = match path.try_into() {
+            Ok(p) => p,
+            Err(e) => return OperationCompleteFuture::from_value(Err(e.into())),
+        };
+
+        let operation = Operation::new(Backend::File, "set_permissions", &path);
+        OperationCompleteFuture::from_future(operation.run(self.limited(chmod(
+            self.space.clone(),
+            path,
+            mode,
+        ))))
+    }
+
+    /// Renames an object without replacing anything at the target.
+    pub(crate) fn rename_object(
+        &self,
+        source: ObjectPath,
+        target: ObjectPath,
+    ) -> OperationCompleteFuture {
+        let operation = Operation::new(Backend::File, "rename_object", &source);
+        OperationCompleteFuture::from_future(operation.run(self.limited(move_object(
+            self.space.clone(),
+            source,
+            target,
+        ))))
+    }
+
+    /// Gets the path on the local filesystem of an object.
+    #[cfg(all(feature = "changes", target_os = "linux"))]
+    pub(crate) fn std_path(&self, path: &ObjectPath) -> StorageResult<PathBuf> {
+        self.space.get_std_path(path)
+    }
+
+    /// Runs the future once the operation limit allows.
+    fn limited<F>(&self, future: F) -> impl Future<Output = F::Output> + Send + 'static
+    where
+        F: Future + Send + 'static,
+    {
+        let operations = self.operations.clone();
+        async move {
+            let _permit = operations.acquire().await;
+            future.await
+        }
+    }
+
+    /// Runs the operation once the operation limit allows, attempting it again
+    /// for as long as the retry policy allows.
+    fn retried<F, R, T>(&self, mut operation: F) -> impl Future<Output = R::Output> + Send + 'static
+    where
+        F: FnMut() -> R + Send + 'static,
+        R: Future<Output = StorageResult<T>> + Send + 'static,
+        T: Send + 'static,
+    {
+        let operations = self.operations.clone();
+        let policy = self.settings.retry.clone();
+        async move {
+            let started = Instant::now();
+            let mut attempts: usize = 0;
+            loop {
+                let result = {
+                    let _permit = operations.acquire().await;
+                    operation().await
+                };
+                attempts += 1;
+
+                match result {
+                    Err(e) => {
+                        if !policy.should_retry(
+                            &e.kind(),
+                            false,
+                            attempts,
+                            started.elapsed(),
+                            Default::default(),
+                        ) {
+                            return Err(e);
+                        }
+                    }
+                    result => return result,
+                }
+            }
+        }
+    }
+}
+
+/// Used to build a [`FileBackend`](struct.FileBackend.html) with some custom
+/// settings.
+#[derive(Clone, Debug)]
+pub struct FileBackendBuilder {
+    root: PathBuf,
+    settings: FileSettings,
+}
+
+impl FileBackendBuilder {
+    /// Limits the number of directories read at once when listing objects.
+    ///
+    /// Defaults to 16. Setting this to 1 reads one directory at a time.
+    pub fn list_concurrency(mut self, directories: usize) -> FileBackendBuilder {
+        self.settings.list_concurrency = directories;
+        self
+    }
+
+    /// Sets whether listings are returned in a deterministic order.
+    ///
+    /// By default objects from
+    /// [`list_objects`](../../enum.FileStore.html#method.list_objects) are
+    /// returned as soon as their directory has been read. When ordered the
+    /// contents of each directory are sorted and directories are returned in
+    /// the order they were found, so listing the same tree always gives the
+    /// same results in the same order. This may be slower when some directories
+    /// take longer to read than others.
+    pub fn ordered_listing(mut self, ordered: bool) -> FileBackendBuilder {
+        self.settings.ordered_listing = ordered;
+        self
+    }
+
+    /// Sets the size of the buffers used when reading files.
+    ///
+    /// Every open file stream holds a buffer of this size so lowering it
+    /// reduces memory use when reading many files at once. Defaults to 20MB.
+    /// This can also be set for a single read with
+    /// [`ReadOptions`](../../struct.ReadOptions.html).
+    pub fn read_buffer_size(mut self, size: usize) -> FileBackendBuilder {
+        self.settings.read_buffer_size = size;
+        self
+    }
+
+    /// Sets the space left in a read buffer at which a new buffer is allocated.
+    ///
+    /// Defaults to 1MB. Values larger than the
+    /// [read buffer size](struct.FileBackendBuilder.html#method.read_buffer_size)
+    /// are treated as the read buffer size.
+    pub fn min_read_buffer_size(mut self, size: usize) -> FileBackendBuilder {
+        self.settings.min_read_buffer_size = size;
+        self
+    }
+
+    /// Sets how failed operations are retried.
+    ///
+    /// Filesystem errors are not considered transient so by default nothing
+    /// is retried. Use [`RetryPolicy::attempts_for`](../../struct.RetryPolicy.html#method.attempts_for)
+    /// to retry kinds of errors that can be transient on a particular
+    /// filesystem, like network mounts. Listings, getting objects, opening
+    /// files for reading and deleting are retried immediately. Writes, copies
+    /// and moves are not retried.
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> FileBackendBuilder {
+        self.settings.retry = policy;
+        self
+    }
+
+    /// Limits the number of operations that can run at once.
+    ///
+    /// Operations over the limit wait for earlier ones to complete. The limit
+    /// applies until an operation's future resolves, streams returned by
+    /// listings and reads are covered by
+    /// [`limit_open_files`](struct.FileBackendBuilder.html#method.limit_open_files)
+    /// instead. By default there is no limit.
+    pub fn limit_operations(mut self, operations: usize) -> FileBackendBuilder {
+        self.settings.max_operations = Some(operations);
+        self
+    }
+
+    /// Limits the number of files and directories held open at once.
+    ///
+    /// Reading a file or directory holds it open until the stream returned is
+    /// finished with or dropped. Once the limit is reached further operations
+    /// wait for a stream to be dropped so take care not to hold on to streams
+    /// while waiting for others to start. By default there is no limit.
+    pub fn limit_open_files(mut self, files: usize) -> FileBackendBuilder {
+        self.settings.max_open_files = Some(files);
+        self
+    }
+
+    /// Sets how file names that are not valid unicode are handled when listing.
+    ///
+    /// By default such a name fails the listing, see
+    /// [`NonUnicodeNames`](enum.NonUnicodeNames.html) for the alternatives.
+    pub fn non_unicode_names(mut self, policy: NonUnicodeNames) -> FileBackendBuilder {
+        self.settings.non_unicode_names = policy;
+        self
+    }
+
+    /// Sets whether listing and getting objects follows symlinks.
+    ///
+    /// By default symlinks are reported as symlinks with no size. When
+    /// following, links to files and directories are reported as the file or
+    /// directory they point to, with the target's size, and the contents of
+    /// linked directories are listed too. A directory reached more than once,
+    /// for example through a link to one of its ancestors, is only listed the
+    /// first time. Linked files can also be read and copied. Broken links are
+    /// still reported as symlinks. Deleting or overwriting never follows
+    /// symlinks.
+    pub fn follow_symlinks(mut self, follow: bool) -> FileBackendBuilder {
+        self.settings.follow_symlinks = follow;
+        self
+    }
+
+    /// Moves deleted objects into a trash under the given prefix instead of
+    /// removing them.
+    ///
+    /// Objects are renamed into the trash so this is as quick as deleting
+    /// them. The trash is left out of listings and deleting objects within it
+    /// removes them permanently. Use a [`Trash`](../../trash/struct.Trash.html)
+    /// with the same prefix to restore or purge deleted objects. An empty
+    /// prefix disables the trash, this is the default.
+    pub fn trash(mut self, prefix: ObjectPath) -> FileBackendBuilder {
+        self.settings.trash = if prefix.is_empty() {
+            None
+        } else {
+            Some(prefix)
+        };
+        self
+    }
+
+    /// Creates a new file based [`FileStore`](../../enum.FileStore.html) using
+    /// this builder's settings.
+    pub fn connect(self) -> ConnectFuture {
         ConnectFuture::from_future(async move {
             let metadata =
-                wrap_future(symlink_metadata(target.clone()), ObjectPath::empty()).await?;
+                wrap_future(symlink_metadata(self.root.clone()), ObjectPath::empty()).await?;
+
+            // On Windows the canonical path uses the `\\?\` prefix which lifts
+            // the usual limit on path lengths.
+            #[cfg(windows)]
+            let root = fs::canonicalize(&self.root)
+                .map_err(|e| get_storage_error(e, ObjectPath::empty()))?;
+            #[cfg(not(windows))]
+            let root = self.root;
+
             if !metadata.is_dir() {
                 Err(error::invalid_settings(Some(
                     "Root path is not a directory.",
                 )))
             } else {
                 Ok(FileStore::from(FileBackend {
-                    space: FileSpace { base: target },
+                    space: FileSpace {
+                        base: root,
+                        files: limiter(self.settings.max_open_files),
+                        names: self.settings.non_unicode_names,
+                    },
+                    operations: limiter(self.settings.max_operations),
+                    settings: self.settings,
                 }))
             }
         })
@@ -405,13 +1350,30 @@ impl StorageBackend for FileBackend {
         Backend::File
     }
 
+    fn validate_path(&self, path: &ObjectPath) -> StorageResult<()> {
+        if path.is_dir_prefix() {
+            return Err(error::invalid_path(
+                path.clone(),
+                Some("Object paths cannot be empty or end with a '/' character."),
+            ));
+        }
+
+        self.space.get_std_path(path).map(|_| ())
+    }
+
     fn list_objects<P>(&self, prefix: P) -> ObjectStreamFuture
     where
         P: TryInto<ObjectPath>,
         P::Error: Into<StorageError>,
     {
-        async fn list(space: FileSpace, prefix: ObjectPath) -> StorageResult<ObjectStream> {
-            Ok(ObjectStream::from_stream(FileLister::list(space, prefix)))
+        async fn list(
+            space: FileSpace,
+            settings: FileSettings,
+            prefix: ObjectPath,
+        ) -> StorageResult<ObjectStream> {
+            Ok(ObjectStream::from_stream(FileLister::list(
+                space, prefix, &settings,
+            )))
         }
 
         let path = match prefix.try_into() {
@@ -419,7 +1381,17 @@ impl StorageBackend for FileBackend {
             Err(e) => return ObjectStreamFuture::from_value(Err(e.into())),
         };
 
-        ObjectStreamFuture::from_future(list(self.space.clone(), path))
+        let trash = self.settings.trash.clone();
+        let listed = path.clone();
+        let operation = Operation::new(Backend::File, "list_objects", &path);
+        ObjectStreamFuture::from_future(
+            operation.run(
+                self.limited(
+                    list(self.space.clone(), self.settings.clone(), path)
+                        .map_ok(move |stream| trash::hide(stream, trash, &listed)),
+                ),
+            ),
+        )
     }
 
     fn list_directory<P>(&self, dir: P) -> ObjectStreamFuture
@@ -427,42 +1399,55 @@ impl StorageBackend for FileBackend {
         P: TryInto<ObjectPath>,
         P::Error: Into<StorageError>,
     {
-        async fn list(space: FileSpace, directory: ObjectPath) -> StorageResult<ObjectStream> {
+        async fn list(
+            space: FileSpace,
+            directory: ObjectPath,
+            follow: bool,
+        ) -> StorageResult<ObjectStream> {
             let path = space.get_std_path(&directory)?;
-            let metadata = wrap_future(symlink_metadata(path.clone()), directory.clone()).await?;
+            let metadata = match entry_metadata(path.clone(), follow).await {
+                Ok(m) => m,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                    return Ok(ObjectStream::from_stream(empty()));
+                }
+                Err(e) => return Err(get_storage_error(e, directory)),
+            };
+
             if !metadata.is_dir() {
                 let stream = ObjectStream::from_stream(empty());
                 return Ok(stream);
             }
 
-            Ok(ObjectStream::from_stream(
+            let permit = space.files.acquire().await;
+            Ok(ObjectStream::from_stream(with_permit(
                 wrap_stream(
                     wrap_future(read_dir(path.clone()), directory.clone()).await?,
                     directory.clone(),
                 )
                 .and_then(move |entry| {
                     let path_base = directory.clone();
-                    wrap_future(symlink_metadata(entry.path()), directory.clone()).map(
+                    let space = space.clone();
+                    wrap_future(entry_metadata(entry.path(), follow), directory.clone()).map(
                         move |result| match result {
                             Ok(metadata) => {
-                                let file_name = match entry.file_name().into_string() {
-                                    Ok(s) => s,
-                                    Err(_) => {
-                                        return Err(error::invalid_data(Some(
-                                            "Unable to convert OSString.",
-                                        )))
-                                    }
-                                };
+                                let file_name =
+                                    match space.file_name(&entry.path(), entry.file_name()) {
+                                        Some(Ok(s)) => s,
+                                        Some(Err(e)) => return Err(e),
+                                        None => return Ok(None),
+                                    };
 
                                 let mut path = path_base.clone();
                                 path.push_part(&file_name);
-                                Ok(get_object(path, Some(metadata)))
+                                Ok(Some(get_object(path, Some(metadata))))
                             }
                             Err(e) => Err(e),
                         },
                     )
-                }),
-            ))
+                })
+                .filter_map(|result| ready(result.transpose())),
+                permit,
+            )))
         }
 
         let mut path = match dir.try_into() {
@@ -474,7 +1459,17 @@ impl StorageBackend for FileBackend {
             path.pop_part();
         }
 
-        ObjectStreamFuture::from_future(list(self.space.clone(), path))
+        let trash = self.settings.trash.clone();
+        let listed = path.clone();
+        let operation = Operation::new(Backend::File, "list_directory", &path);
+        let space = self.space.clone();
+        let follow = self.settings.follow_symlinks;
+        ObjectStreamFuture::from_future(
+            operation.run(
+                self.retried(move || list(space.clone(), path.clone(), follow))
+                    .map_ok(move |stream| trash::hide(stream, trash, &listed)),
+            ),
+        )
     }
 
     fn get_object<P>(&self, path: P) -> ObjectFuture
@@ -482,10 +1477,10 @@ impl StorageBackend for FileBackend {
         P: TryInto<ObjectPath>,
         P::Error: Into<StorageError>,
     {
-        async fn get(space: FileSpace, path: ObjectPath) -> StorageResult<Object> {
+        async fn get(space: FileSpace, path: ObjectPath, follow: bool) -> StorageResult<Object> {
             let target = space.get_std_path(&path)?;
 
-            match symlink_metadata(target.clone()).await {
+            match entry_metadata(target.clone(), follow).await {
                 Ok(m) => Ok(get_object(path, Some(m))),
                 Err(e) => {
                     if e.kind() == io::ErrorKind::NotFound {
@@ -502,14 +1497,16 @@ impl StorageBackend for FileBackend {
             Err(e) => return ObjectFuture::from_value(Err(e.into())),
         };
 
-        if path.is_dir_prefix() {
-            return ObjectFuture::from_value(Err(error::invalid_path(
-                path,
-                Some("Object paths cannot be empty or end with a '/' character."),
-            )));
+        if let Err(e) = self.validate_path(&path) {
+            return ObjectFuture::from_value(Err(e));
         }
 
-        ObjectFuture::from_future(get(self.space.clone(), path))
+        let operation = Operation::new(Backend::File, "get_object", &path);
+        let space = self.space.clone();
+        let follow = self.settings.follow_symlinks;
+        ObjectFuture::from_future(
+            operation.run(self.retried(move || get(space.clone(), path.clone(), follow))),
+        )
     }
 
     fn get_file_stream<P>(&self, path: P) -> DataStreamFuture
@@ -517,45 +1514,337 @@ impl StorageBackend for FileBackend {
         P: TryInto<ObjectPath>,
         P::Error: Into<StorageError>,
     {
-        async fn read(space: FileSpace, path: ObjectPath) -> StorageResult<DataStream> {
+        self.get_file_stream_with_options(path, Default::default())
+    }
+
+    fn get_file_stream_with_options<P>(&self, path: P, options: ReadOptions) -> DataStreamFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        async fn read(
+            space: FileSpace,
+            path: ObjectPath,
+            buffer_size: usize,
+            min_buffer_size: usize,
+            follow: bool,
+        ) -> StorageResult<DataStream> {
             let target = space.get_std_path(&path)?;
 
-            let metadata = wrap_future(symlink_metadata(target.clone()), path.clone()).await?;
+            let metadata =
+                wrap_future(entry_metadata(target.clone(), follow), path.clone()).await?;
             if !metadata.is_file() {
                 return Err(error::not_found(path, None));
             }
 
+            let permit = space.files.acquire().await;
             let file = wrap_future(File::open(target), path.clone()).await?;
-            Ok(DataStream::from_stream(
-                ReaderStream::<tokio_fs::File>::stream(file, INITIAL_BUFFER_SIZE, MIN_BUFFER_SIZE)
-                    .map_err(move |e| get_storage_error(e, path.clone())),
-            ))
+            Ok(DataStream::from_stream(with_permit(
+                LengthCheckedStream::new(
+                    ReaderStream::<tokio_fs::File>::stream(file, buffer_size, min_buffer_size)
+                        .map_err(move |e| get_storage_error(e, path.clone())),
+                    metadata.len(),
+                ),
+                permit,
+            )))
         }
 
+        let buffer_size = options
+            .buffer_size
+            .unwrap_or(self.settings.read_buffer_size)
+            .max(1);
+        let min_buffer_size = options
+            .min_buffer_size
+            .unwrap_or(self.settings.min_read_buffer_size)
+            .min(buffer_size);
+
         match path.try_into() {
-            Ok(p) => DataStreamFuture::from_future(read(self.space.clone(), p)),
+            Ok(p) => {
+                let operation = Operation::new(Backend::File, "get_file_stream", &p);
+                let space = self.space.clone();
+                let follow = self.settings.follow_symlinks;
+                DataStreamFuture::from_future(operation.read(self.retried(move || {
+                    read(
+                        space.clone(),
+                        p.clone(),
+                        buffer_size,
+                        min_buffer_size,
+                        follow,
+                    )
+                })))
+            }
             Err(e) => DataStreamFuture::from_value(Err(e.into())),
         }
     }
 
+    fn copy_file<P, I>(&self, source: P, target: I) -> CopyCompleteFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+        I: TryInto<UploadInfo>,
+        I::Error: Into<StorageError>,
+    {
+        async fn copy_local(
+            space: FileSpace,
+            settings: FileSettings,
+            source: ObjectPath,
+            info: UploadInfo,
+        ) -> Result<(), TransferError> {
+            let source_path = space
+                .get_std_path(&source)
+                .map_err(TransferError::SourceError)?;
+            let metadata = wrap_future(
+                entry_metadata(source_path.clone(), settings.follow_symlinks),
+                source.clone(),
+            )
+            .await
+            .map_err(TransferError::SourceError)?;
+            if !metadata.is_file() {
+                return Err(TransferError::SourceError(error::not_found(source, None)));
+            }
+
+            let files = space.files.clone();
+            let target = space
+                .get_std_path(&info.path)
+                .map_err(TransferError::TargetError)?;
+            check_space(&target, metadata.len(), info.options.mode)
+                .await
+                .map_err(TransferError::TargetError)?;
+            if info.options.mode == WriteMode::Overwrite {
+                // Clearing the target would delete the source.
+                if let Ok(existing) = symlink_metadata(target.clone()).await {
+                    if same_file(&source_path, &metadata, &target, &existing) {
+                        return Err(TransferError::TargetError(error::invalid_path(
+                            info.path,
+                            Some("Cannot copy a file over itself."),
+                        )));
+                    }
+                }
+
+                clear_target(space, settings, info.path.clone())
+                    .await
+                    .map_err(TransferError::TargetError)?;
+            }
+            create_parent(&target, &info.path)
+                .await
+                .map_err(TransferError::TargetError)?;
+
+            let _permit = files.acquire().await;
+            let claimed = info.options.mode == WriteMode::FailIfExists;
+            if claimed {
+                // Claim the target before copying over the empty file.
+                wrap_future(File::create_new(target.clone()), info.path.clone())
+                    .await
+                    .map_err(TransferError::TargetError)?;
+            }
+
+            let delete_on_failure = info.options.delete_on_failure;
+            let result = copy_data(source_path, target.clone(), metadata, info).await;
+
+            // A claimed target didn't exist before so never leave it behind.
+            if result.is_err() && (claimed || delete_on_failure) {
+                if let Err(e) = remove_file(target).await {
+                    if e.kind() != io::ErrorKind::NotFound {
+                        warn!("Failed to delete partially copied file: {}", e);
+                    }
+                }
+            }
+
+            result
+        }
+
+        async fn copy_data(
+            source_path: PathBuf,
+            target: PathBuf,
+            metadata: Metadata,
+            info: UploadInfo,
+        ) -> Result<(), TransferError> {
+            wrap_future(copy(source_path, target.clone()), info.path.clone())
+                .await
+                .map_err(TransferError::TargetError)?;
+
+            let modified = match info.modified {
+                Some(time) => Some(time),
+                None if info.options.preserve_modified => metadata.modified().ok(),
+                None => None,
+            };
+            if let Some(time) = modified {
+                if let Err(e) = set_file_mtime(&target, FileTime::from_system_time(time)) {
+                    warn!("Failed to set file modification time: {}", e);
+                }
+            }
+
+            if info.options.durable {
+                wrap_future(sync_file(target), info.path)
+                    .await
+                    .map_err(TransferError::TargetError)?;
+            }
+
+            Ok(())
+        }
+
+        let source = match source.try_into() {
+            Ok(p) => p,
+            Err(e) => {
+                return CopyCompleteFuture::from_value(Err(TransferError::SourceError(e.into())))
+            }
+        };
+
+        let info = match target.try_into() {
+            Ok(i) => i,
+            Err(e) => {
+                return CopyCompleteFuture::from_value(Err(TransferError::TargetError(e.into())))
+            }
+        };
+
+        let operation = Operation::new(Backend::File, "copy_file", &source);
+        CopyCompleteFuture::from_future(operation.run(self.limited(copy_local(
+            self.space.clone(),
+            self.settings.clone(),
+            source,
+            info,
+        ))))
+    }
+
+    fn move_file<P, I>(&self, source: P, target: I) -> MoveCompleteFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+        I: TryInto<UploadInfo>,
+        I::Error: Into<StorageError>,
+    {
+        // Renaming replaces an existing target atomically so readers never see
+        // it missing or partly written. Symlinks, and files that cannot be
+        // renamed to the target such as those on another device, are copied
+        // and then deleted instead.
+        async fn move_local(
+            backend: FileBackend,
+            source: ObjectPath,
+            info: UploadInfo,
+        ) -> Result<(), TransferError> {
+            let space = backend.space.clone();
+            let source_path = space
+                .get_std_path(&source)
+                .map_err(TransferError::SourceError)?;
+            let metadata = wrap_future(symlink_metadata(source_path.clone()), source.clone())
+                .await
+                .map_err(TransferError::SourceError)?;
+
+            let target = space
+                .get_std_path(&info.path)
+                .map_err(TransferError::TargetError)?;
+            let existing = symlink_metadata(target.clone()).await.ok();
+            if let Some(ref existing) = existing {
+                if same_file(&source_path, &metadata, &target, existing) {
+                    return Err(TransferError::TargetError(error::invalid_path(
+                        info.path,
+                        Some("Cannot move a file over itself."),
+                    )));
+                }
+            }
+
+            let renamed = if metadata.is_file() {
+                match (info.options.mode, existing) {
+                    (WriteMode::FailIfExists, Some(_)) => {
+                        return Err(TransferError::TargetError(error::already_exists(
+                            info.path, None,
+                        )))
+                    }
+                    (WriteMode::Overwrite, Some(ref existing)) if existing.is_dir() => {
+                        clear_target(space, backend.settings.clone(), info.path.clone())
+                            .await
+                            .map_err(TransferError::TargetError)?;
+                    }
+                    _ => (),
+                }
+                create_parent(&target, &info.path)
+                    .await
+                    .map_err(TransferError::TargetError)?;
+
+                rename(source_path, target.clone()).await.is_ok()
+            } else {
+                false
+            };
+
+            if !renamed {
+                backend.copy_file(source.clone(), info).await?;
+                return backend
+                    .delete_object(source)
+                    .await
+                    .map_err(TransferError::SourceError);
+            }
+
+            if let Some(time) = info.modified {
+                if let Err(e) = set_file_mtime(&target, FileTime::from_system_time(time)) {
+                    warn!("Failed to set file modification time: {}", e);
+                }
+            }
+
+            if info.options.durable {
+                wrap_future(sync_file(target), info.path)
+                    .await
+                    .map_err(TransferError::TargetError)?;
+            }
+
+            Ok(())
+        }
+
+        let source = match source.try_into() {
+            Ok(p) => p,
+            Err(e) => {
+                return MoveCompleteFuture::from_value(Err(TransferError::SourceError(e.into())))
+            }
+        };
+
+        let info = match target.try_into() {
+            Ok(i) => i,
+            Err(e) => {
+                return MoveCompleteFuture::from_value(Err(TransferError::TargetError(e.into())))
+            }
+        };
+
+        let operation = Operation::new(Backend::File, "move_file", &source);
+        MoveCompleteFuture::from_future(operation.run(move_local(self.clone(), source, info)))
+    }
+
     fn delete_object<P>(&self, path: P) -> OperationCompleteFuture
     where
         P: TryInto<ObjectPath>,
         P::Error: Into<StorageError>,
     {
-        async fn delete(space: FileSpace, path: ObjectPath) -> StorageResult<()> {
+        async fn delete(
+            space: FileSpace,
+            settings: FileSettings,
+            path: ObjectPath,
+        ) -> StorageResult<()> {
+            if let Some(ref trash) = settings.trash {
+                if !trash::contains(trash, &path) {
+                    let target = trash::location(trash, &path, SystemTime::now());
+                    return move_object(space, path, target).await;
+                }
+            }
+
             let target = space.get_std_path(&path)?;
             let metadata = wrap_future(symlink_metadata(target.clone()), path.clone()).await?;
 
             if !metadata.is_dir() {
                 wrap_future(remove_file(target.clone()), path.clone()).await
             } else {
-                delete_directory(space, path).await
+                delete_directory(space, settings, path).await
             }
         }
 
         match path.try_into() {
-            Ok(p) => OperationCompleteFuture::from_future(delete(self.space.clone(), p)),
+            Ok(p) => {
+                let operation = Operation::new(Backend::File, "delete_object", &p);
+                let space = self.space.clone();
+                let settings = self.settings.clone();
+                OperationCompleteFuture::from_future(
+                    operation.run(
+                        self.retried(move || delete(space.clone(), settings.clone(), p.clone())),
+                    ),
+                )
+            }
             Err(e) => OperationCompleteFuture::from_value(Err(e.into())),
         }
     }
@@ -570,39 +1859,70 @@ impl StorageBackend for FileBackend {
     {
         async fn write<S>(
             space: FileSpace,
+            settings: FileSettings,
             info: UploadInfo,
-            mut stream: S,
+            stream: S,
         ) -> Result<(), TransferError>
         where
             S: Stream<Item = StorageResult<Data>> + Send + Unpin + 'static,
         {
+            let files = space.files.clone();
+            let mode = info.options.mode;
             let target = space
                 .get_std_path(&info.path)
                 .map_err(TransferError::TargetError)?;
+            if let Some(length) = info.options.content_length {
+                check_space(&target, length, mode)
+                    .await
+                    .map_err(TransferError::TargetError)?;
+            }
+            if mode == WriteMode::Overwrite {
+                clear_target(space, settings, info.path.clone())
+                    .await
+                    .map_err(TransferError::TargetError)?;
+            }
+            create_parent(&target, &info.path)
+                .await
+                .map_err(TransferError::TargetError)?;
 
-            match symlink_metadata(target.clone()).await {
-                Ok(m) => {
-                    if m.is_dir() {
-                        delete_directory(space, info.path.clone())
-                            .await
-                            .map_err(TransferError::TargetError)?;
-                    } else {
-                        wrap_future(remove_file(target.clone()), info.path.clone())
-                            .await
-                            .map_err(TransferError::TargetError)?;
-                    }
+            let permit = files.acquire().await;
+
+            // Creating exclusively is the existence check so a failure here
+            // leaves nothing behind to delete.
+            let file = match mode {
+                WriteMode::Overwrite => {
+                    wrap_future(File::create(target.clone()), info.path.clone()).await
                 }
-                Err(e) => {
+                WriteMode::FailIfExists => {
+                    wrap_future(File::create_new(target.clone()), info.path.clone()).await
+                }
+            }
+            .map_err(TransferError::TargetError)?;
+
+            let delete_on_failure = info.options.delete_on_failure;
+            let result = write_data(target.clone(), file, info, stream, permit).await;
+
+            if result.is_err() && delete_on_failure {
+                if let Err(e) = remove_file(target).await {
                     if e.kind() != io::ErrorKind::NotFound {
-                        return Err(TransferError::TargetError(get_storage_error(e, info.path)));
+                        warn!("Failed to delete partially written file: {}", e);
                     }
                 }
-            };
+            }
 
-            let mut file = wrap_future(File::create(target.clone()), info.path.clone())
-                .await
-                .map_err(TransferError::TargetError)?;
+            result
+        }
 
+        async fn write_data<S>(
+            target: PathBuf,
+            mut file: tokio_fs::File,
+            info: UploadInfo,
+            mut stream: S,
+            _permit: Permit,
+        ) -> Result<(), TransferError>
+        where
+            S: Stream<Item = StorageResult<Data>> + Send + Unpin + 'static,
+        {
             loop {
                 let option = stream.next().await;
                 if let Some(result) = option {
@@ -634,6 +1954,12 @@ impl StorageBackend for FileBackend {
                 }
             }
 
+            if info.options.durable {
+                wrap_future(sync_file(target), info.path)
+                    .await
+                    .map_err(TransferError::TargetError)?;
+            }
+
             Ok(())
         }
 
@@ -644,10 +1970,18 @@ impl StorageBackend for FileBackend {
             }
         };
 
-        WriteCompleteFuture::from_future(write(
-            self.space.clone(),
-            info,
-            Box::pin(into_data_stream(stream)),
-        ))
+        let space = self.space.clone();
+        let settings = self.settings.clone();
+        let depth = info
+            .options
+            .buffer_depth
+            .unwrap_or(DEFAULT_WRITE_BUFFER_DEPTH);
+        let operation = Operation::new(Backend::File, "write_file_from_stream", &info.path);
+        let stream = operation.write(into_data_stream(stream));
+        WriteCompleteFuture::from_future(operation.run(self.limited(buffered(
+            stream,
+            depth,
+            move |receiver| write(space, settings, info, receiver),
+        ))))
     }
 }
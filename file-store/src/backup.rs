@@ -0,0 +1,482 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Point-in-time backups of the files under a prefix.
+//!
+//! A [`Repository`](struct.Repository.html) keeps backups under a prefix of a
+//! store. Each backup is recorded as a [`Manifest`](struct.Manifest.html)
+//! listing the path, size, modification time and SHA-1 hash of every file
+//! that was backed up. The contents of files are stored once per hash so
+//! files that are the same in many backups, or in many places, only take up
+//! space once. The repository is laid out as:
+//!
+//! * `<prefix>/manifests/<time>` holds the manifest of the backup taken at
+//!   `<time>` milliseconds since the unix epoch.
+//! * `<prefix>/data/<hash>` holds the contents of files with the given hash.
+//!
+//! Backups are incremental. Files whose size and modification time match the
+//! most recent manifest are assumed to be unchanged and are not read again.
+//! Other files are read to calculate their hash and are only uploaded if the
+//! repository does not already hold that content.
+//!
+//! Any manifest can be restored to a prefix of any store.
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::str;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::stream::{iter, StreamExt, TryStreamExt};
+
+use crate::sync::{directory, list_files, seconds};
+use crate::types::*;
+use crate::utils::hash;
+use crate::{FileStore, ObjectInfo, StorageBackend};
+
+/// The number of files backed up or restored at once.
+const CONCURRENCY: usize = 4;
+
+const MANIFEST_HEADER: &str = "file-store backup 1";
+
+/// Future returned by [`Repository::backup`](struct.Repository.html#method.backup).
+pub type BackupFuture = WrappedFuture<Result<BackupSummary, TransferError>>;
+/// Future returned by [`Repository::restore`](struct.Repository.html#method.restore).
+pub type RestoreFuture = WrappedFuture<Result<(), TransferError>>;
+/// Future returned by [`Repository::manifests`](struct.Repository.html#method.manifests).
+pub type ManifestListFuture = WrappedFuture<StorageResult<Vec<Manifest>>>;
+
+/// A file recorded in a manifest.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ManifestEntry {
+    path: ObjectPath,
+    len: u64,
+    modified: Option<SystemTime>,
+    hash: String,
+}
+
+impl ManifestEntry {
+    /// Gets the path of the file relative to the prefix that was backed up.
+    pub fn path(&self) -> &ObjectPath {
+        &self.path
+    }
+
+    /// Gets the size of the file.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Checks whether the file was empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Gets the modification time of the file, to the nearest second.
+    pub fn modified(&self) -> Option<SystemTime> {
+        self.modified
+    }
+
+    /// Gets the hex encoded SHA-1 hash of the file's contents.
+    pub fn hash(&self) -> &str {
+        &self.hash
+    }
+}
+
+/// The files captured by a backup.
+#[derive(Clone, Debug)]
+pub struct Manifest {
+    created: SystemTime,
+    entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    /// Gets the time the backup was taken.
+    pub fn created(&self) -> SystemTime {
+        self.created
+    }
+
+    /// Gets the files in the backup sorted by path.
+    pub fn entries(&self) -> &[ManifestEntry] {
+        &self.entries
+    }
+
+    fn parse(created: SystemTime, data: &[u8]) -> StorageResult<Manifest> {
+        let invalid = || error::invalid_data(Some("The backup manifest is not valid."));
+
+        let text = str::from_utf8(data).map_err(|_| invalid())?;
+        let mut lines = text.lines();
+        if lines.next() != Some(MANIFEST_HEADER) {
+            return Err(invalid());
+        }
+
+        let mut entries = Vec::new();
+        for line in lines {
+            let fields: Vec<&str> = line.splitn(4, ' ').collect();
+            if fields.len() != 4 {
+                return Err(invalid());
+            }
+
+            let modified = match fields[1] {
+                "-" => None,
+                m => Some(m.parse().map_err(|_| invalid())?),
+            };
+            entries.push(ManifestEntry {
+                len: fields[0].parse().map_err(|_| invalid())?,
+                modified: modified.map(|m| UNIX_EPOCH + Duration::from_secs(m)),
+                hash: fields[2].to_owned(),
+                path: ObjectPath::new(fields[3])?,
+            });
+        }
+
+        Ok(Manifest { created, entries })
+    }
+
+    fn format(&self) -> String {
+        let mut text = format!("{}\n", MANIFEST_HEADER);
+        for entry in &self.entries {
+            let modified = match entry.modified {
+                Some(modified) => seconds(modified).to_string(),
+                None => "-".to_owned(),
+            };
+            text.push_str(&format!(
+                "{} {} {} {}\n",
+                entry.len, modified, entry.hash, entry.path
+            ));
+        }
+        text
+    }
+}
+
+/// What a backup did.
+#[derive(Clone, Debug)]
+pub struct BackupSummary {
+    /// The manifest of the new backup.
+    pub manifest: Manifest,
+    /// The files whose contents were uploaded to the repository.
+    pub uploaded: Vec<ObjectPath>,
+    /// The number of bytes uploaded.
+    pub bytes: u64,
+}
+
+/// Manages backups kept under a prefix of a store.
+///
+/// See the [`backup`](index.html) module.
+#[derive(Clone, Debug)]
+pub struct Repository {
+    store: FileStore,
+    prefix: ObjectPath,
+}
+
+impl Repository {
+    /// Creates a repository that keeps backups under the given prefix of the
+    /// store.
+    pub fn new(store: FileStore, prefix: ObjectPath) -> StorageResult<Repository> {
+        Ok(Repository {
+            store,
+            prefix: directory(prefix)?,
+        })
+    }
+
+    fn path(&self, directory: &str, name: &str) -> ObjectPath {
+        let mut path = self.prefix.clone();
+        path.push_part(directory);
+        path.push_part(name);
+        path
+    }
+
+    /// Lists the backups in the repository, oldest first.
+    pub fn manifests(&self) -> ManifestListFuture {
+        ManifestListFuture::from_future(manifests(self.clone()))
+    }
+
+    /// Backs up the files under `prefix` in `source`.
+    ///
+    /// Errors from the source store are reported as source errors and errors
+    /// from the repository as target errors. Nothing is recorded unless every
+    /// file is backed up.
+    pub fn backup<S, P>(&self, source: &S, prefix: P) -> BackupFuture
+    where
+        S: StorageBackend + Sync,
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        let prefix = match prefix.try_into().map_err(Into::into).and_then(directory) {
+            Ok(p) => p,
+            Err(e) => return BackupFuture::from_value(Err(TransferError::SourceError(e))),
+        };
+
+        BackupFuture::from_future(backup(self.clone(), source.clone(), prefix))
+    }
+
+    /// Restores the files in a manifest to `prefix` in `target`, replacing any
+    /// files already there.
+    ///
+    /// Errors from the repository are reported as source errors and errors
+    /// from the target store as target errors.
+    pub fn restore<T, P>(&self, manifest: &Manifest, target: &T, prefix: P) -> RestoreFuture
+    where
+        T: StorageBackend + Sync,
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        let prefix = match prefix.try_into().map_err(Into::into).and_then(directory) {
+            Ok(p) => p,
+            Err(e) => return RestoreFuture::from_value(Err(TransferError::TargetError(e))),
+        };
+
+        RestoreFuture::from_future(restore(
+            self.clone(),
+            manifest.clone(),
+            target.clone(),
+            prefix,
+        ))
+    }
+}
+
+/// Lists the names of the objects directly inside a directory of the
+/// repository.
+async fn names(repository: &Repository, directory: &str) -> StorageResult<Vec<String>> {
+    let mut path = repository.prefix.clone();
+    path.push_part(directory);
+    path.push_part("");
+
+    let objects: Vec<Object> = repository
+        .store
+        .list_directory(path)
+        .await?
+        .try_collect()
+        .await?;
+    Ok(objects
+        .into_iter()
+        .filter(|o| o.object_type() == ObjectType::File)
+        .filter_map(|o| {
+            o.path()
+                .parts()
+                .into_iter()
+                .filter(|p| !p.is_empty())
+                .last()
+                .map(str::to_owned)
+        })
+        .collect())
+}
+
+/// Lists the times of the backups in the repository, oldest first.
+async fn manifest_times(repository: &Repository) -> StorageResult<Vec<u64>> {
+    let mut times: Vec<u64> = names(repository, "manifests")
+        .await?
+        .iter()
+        .filter_map(|n| n.parse().ok())
+        .collect();
+    times.sort();
+    Ok(times)
+}
+
+async fn load_manifest(repository: &Repository, millis: u64) -> StorageResult<Manifest> {
+    let data = repository
+        .store
+        .get_file_stream(repository.path("manifests", &millis.to_string()))
+        .await?
+        .try_concat()
+        .await?;
+    Manifest::parse(UNIX_EPOCH + Duration::from_millis(millis), &data)
+}
+
+async fn manifests(repository: Repository) -> StorageResult<Vec<Manifest>> {
+    let mut manifests = Vec::new();
+    for millis in manifest_times(&repository).await? {
+        manifests.push(load_manifest(&repository, millis).await?);
+    }
+    Ok(manifests)
+}
+
+/// Gets the manifest entry for a file, only reading it if it has changed since
+/// the previous backup.
+async fn entry<S>(
+    source: S,
+    object: Object,
+    relative: ObjectPath,
+    previous: Option<ManifestEntry>,
+) -> Result<ManifestEntry, TransferError>
+where
+    S: StorageBackend + Sync,
+{
+    let modified = object.modified().map(seconds);
+    if let Some(previous) = previous {
+        if previous.len == object.len() && previous.modified.map(seconds) == modified {
+            return Ok(previous);
+        }
+    }
+
+    Ok(ManifestEntry {
+        path: relative,
+        len: object.len(),
+        modified: modified.map(|m| UNIX_EPOCH + Duration::from_secs(m)),
+        hash: hash(&source, object.path())
+            .await
+            .map_err(TransferError::SourceError)?,
+    })
+}
+
+/// Uploads the contents of a file to the repository.
+async fn upload<S>(
+    repository: Repository,
+    source: S,
+    path: ObjectPath,
+    entry: ManifestEntry,
+) -> Result<(), TransferError>
+where
+    S: StorageBackend + Sync,
+{
+    let mut info = UploadInfo::from(repository.path("data", &entry.hash));
+    info.options.content_length = Some(entry.len);
+    let stream = source
+        .get_file_stream(path)
+        .await
+        .map_err(TransferError::SourceError)?;
+    repository.store.write_file_from_stream(info, stream).await
+}
+
+async fn backup<S>(
+    repository: Repository,
+    source: S,
+    prefix: ObjectPath,
+) -> Result<BackupSummary, TransferError>
+where
+    S: StorageBackend + Sync,
+{
+    let latest = manifest_times(&repository)
+        .await
+        .map_err(TransferError::TargetError)?
+        .pop();
+    let previous: HashMap<String, ManifestEntry> = match latest {
+        Some(millis) => load_manifest(&repository, millis)
+            .await
+            .map_err(TransferError::TargetError)?
+            .entries
+            .into_iter()
+            .map(|e| (e.path.to_string(), e))
+            .collect(),
+        None => HashMap::new(),
+    };
+    let mut stored: HashSet<String> = names(&repository, "data")
+        .await
+        .map_err(TransferError::TargetError)?
+        .into_iter()
+        .collect();
+
+    let mut files: Vec<(String, Object)> = list_files(&source, &prefix)
+        .await
+        .map_err(TransferError::SourceError)?
+        .into_iter()
+        .collect();
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut tasks = Vec::new();
+    let mut paths = Vec::new();
+    for (path, object) in files {
+        let relative = ObjectPath::new(&path).map_err(TransferError::SourceError)?;
+        paths.push(object.path());
+        tasks.push(entry(
+            source.clone(),
+            object,
+            relative,
+            previous.get(&path).cloned(),
+        ));
+    }
+
+    let entries: Vec<ManifestEntry> = iter(tasks).buffered(CONCURRENCY).try_collect().await?;
+
+    // Upload whatever content the repository doesn't hold yet, once per hash.
+    let mut uploads = Vec::new();
+    let mut uploaded = Vec::new();
+    let mut bytes = 0;
+    for (entry, path) in entries.iter().zip(paths) {
+        if stored.insert(entry.hash.clone()) {
+            uploaded.push(entry.path.clone());
+            bytes += entry.len;
+            uploads.push(upload(
+                repository.clone(),
+                source.clone(),
+                path,
+                entry.clone(),
+            ));
+        }
+    }
+
+    iter(uploads)
+        .buffer_unordered(CONCURRENCY)
+        .try_collect::<Vec<()>>()
+        .await?;
+
+    // Manifests are named by time so each must be later than the last.
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+        .max(latest.map_or(0, |l| l + 1));
+    let manifest = Manifest {
+        created: UNIX_EPOCH + Duration::from_millis(millis),
+        entries,
+    };
+
+    let data = Data::from(manifest.format());
+    repository
+        .store
+        .write_file_from_stream(
+            repository.path("manifests", &millis.to_string()),
+            iter(vec![Ok::<Data, StorageError>(data)]),
+        )
+        .await?;
+
+    Ok(BackupSummary {
+        manifest,
+        uploaded,
+        bytes,
+    })
+}
+
+async fn restore<T>(
+    repository: Repository,
+    manifest: Manifest,
+    target: T,
+    prefix: ObjectPath,
+) -> Result<(), TransferError>
+where
+    T: StorageBackend + Sync,
+{
+    let tasks: Vec<_> = manifest
+        .entries
+        .into_iter()
+        .map(|entry| {
+            let repository = repository.clone();
+            let target = target.clone();
+            let path = prefix.join(&entry.path);
+            async move {
+                let mut info = UploadInfo::from(path);
+                info.modified = entry.modified;
+                info.options.content_length = Some(entry.len);
+                let stream = repository
+                    .store
+                    .get_file_stream(repository.path("data", &entry.hash))
+                    .await
+                    .map_err(TransferError::SourceError)?;
+                target.write_file_from_stream(info, stream).await
+            }
+        })
+        .collect();
+
+    iter(tasks)
+        .buffer_unordered(CONCURRENCY)
+        .try_collect::<Vec<()>>()
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,272 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A synchronous API for accessing storage.
+//!
+//! [`BlockingFileStore`](struct.BlockingFileStore.html) wraps a
+//! [`FileStore`](../enum.FileStore.html) along with a runtime to drive it. Every
+//! operation blocks the current thread until it completes. This is useful for
+//! command line tools and other code that doesn't otherwise use futures.
+//!
+//! Included with the "blocking" feature.
+//!
+//! ```no_run
+//! use std::path::Path;
+//!
+//! use file_store::backends::file::FileBackend;
+//! use file_store::blocking::BlockingFileStore;
+//!
+//! let fs = BlockingFileStore::connect(FileBackend::connect(Path::new("/tmp"))).unwrap();
+//! for object in fs.list_objects("").unwrap() {
+//!     println!("{}", object.unwrap().path());
+//! }
+//! ```
+use std::convert::TryInto;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+use futures::stream::{iter, StreamExt};
+use tokio::runtime::Runtime;
+
+use crate::backends::Backend;
+use crate::types::*;
+use crate::utils::ChunkReader;
+use crate::{FileStore, StorageBackend};
+
+/// Provides blocking access to a storage backend.
+///
+/// Cloning is cheap, all clones share the same runtime.
+#[derive(Clone)]
+pub struct BlockingFileStore {
+    store: FileStore,
+    runtime: Arc<Runtime>,
+}
+
+impl fmt::Debug for BlockingFileStore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BlockingFileStore")
+            .field("store", &self.store)
+            .finish()
+    }
+}
+
+fn new_runtime() -> StorageResult<Runtime> {
+    Runtime::new()
+        .map_err(|e| error::internal_error(Some(&format!("Unable to create runtime: {}", e))))
+}
+
+impl BlockingFileStore {
+    /// Wraps an already connected [`FileStore`](../enum.FileStore.html).
+    pub fn new(store: FileStore) -> StorageResult<BlockingFileStore> {
+        Ok(BlockingFileStore {
+            store,
+            runtime: Arc::new(new_runtime()?),
+        })
+    }
+
+    /// Waits for the given connection future to complete.
+    ///
+    /// Pass the result of any backend's `connect` function.
+    pub fn connect(future: ConnectFuture) -> StorageResult<BlockingFileStore> {
+        let runtime = new_runtime()?;
+        let store = runtime.block_on(future)?;
+
+        Ok(BlockingFileStore {
+            store,
+            runtime: Arc::new(runtime),
+        })
+    }
+
+    /// Gets the underlying asynchronous [`FileStore`](../enum.FileStore.html).
+    pub fn store(&self) -> &FileStore {
+        &self.store
+    }
+
+    /// Retrieves the type of the underlying backend.
+    pub fn backend_type(&self) -> Backend {
+        self.store.backend_type()
+    }
+
+    /// Authenticates with the backend if it has not already done so.
+    ///
+    /// See [`StorageBackend::authorize`](../trait.StorageBackend.html#method.authorize).
+    pub fn authorize(&self) -> StorageResult<()> {
+        self.runtime.block_on(self.store.authorize())
+    }
+
+    /// Lists the objects that are prefixed by the given prefix.
+    ///
+    /// See [`StorageBackend::list_objects`](../trait.StorageBackend.html#tymethod.list_objects).
+    pub fn list_objects<P>(&self, prefix: P) -> StorageResult<ObjectIterator>
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        let stream = self.runtime.block_on(self.store.list_objects(prefix))?;
+        Ok(ObjectIterator {
+            stream,
+            runtime: self.runtime.clone(),
+        })
+    }
+
+    /// Lists the objects that exist in the given (possibly virtual) directory.
+    ///
+    /// See [`StorageBackend::list_directory`](../trait.StorageBackend.html#tymethod.list_directory).
+    pub fn list_directory<P>(&self, dir: P) -> StorageResult<ObjectIterator>
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        let stream = self.runtime.block_on(self.store.list_directory(dir))?;
+        Ok(ObjectIterator {
+            stream,
+            runtime: self.runtime.clone(),
+        })
+    }
+
+    /// Gets info about the object at the given path.
+    ///
+    /// See [`StorageBackend::get_object`](../trait.StorageBackend.html#tymethod.get_object).
+    pub fn get_object<P>(&self, path: P) -> StorageResult<Object>
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        self.runtime.block_on(self.store.get_object(path))
+    }
+
+    /// Gets info about the object at the given path if there is one.
+    ///
+    /// See [`StorageBackend::get_object_opt`](../trait.StorageBackend.html#method.get_object_opt).
+    pub fn get_object_opt<P>(&self, path: P) -> StorageResult<Option<Object>>
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        self.runtime.block_on(self.store.get_object_opt(path))
+    }
+
+    /// Gets a reader for the file at the given path.
+    ///
+    /// See [`StorageBackend::get_file_stream`](../trait.StorageBackend.html#tymethod.get_file_stream).
+    pub fn get_file_reader<P>(&self, path: P) -> StorageResult<FileReader>
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        let stream = self.runtime.block_on(self.store.get_file_stream(path))?;
+        Ok(FileReader {
+            stream,
+            runtime: self.runtime.clone(),
+            current: Data::new(),
+        })
+    }
+
+    /// Copies a file from one path to another.
+    ///
+    /// See [`StorageBackend::copy_file`](../trait.StorageBackend.html#method.copy_file).
+    pub fn copy_file<P, I>(&self, source: P, target: I) -> Result<(), TransferError>
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+        I: TryInto<UploadInfo>,
+        I::Error: Into<StorageError>,
+    {
+        self.runtime.block_on(self.store.copy_file(source, target))
+    }
+
+    /// Moves a file from one path to another.
+    ///
+    /// See [`StorageBackend::move_file`](../trait.StorageBackend.html#method.move_file).
+    pub fn move_file<P, I>(&self, source: P, target: I) -> Result<(), TransferError>
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+        I: TryInto<UploadInfo>,
+        I::Error: Into<StorageError>,
+    {
+        self.runtime.block_on(self.store.move_file(source, target))
+    }
+
+    /// Deletes the object at the given path.
+    ///
+    /// See [`StorageBackend::delete_object`](../trait.StorageBackend.html#tymethod.delete_object).
+    pub fn delete_object<P>(&self, path: P) -> StorageResult<()>
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        self.runtime.block_on(self.store.delete_object(path))
+    }
+
+    /// Writes the contents of a reader to the file at the given path.
+    ///
+    /// The reader is read on the current thread until it returns no more data.
+    /// Any error from the reader is returned as a
+    /// [`SourceError`](../enum.TransferError.html#variant.SourceError).
+    ///
+    /// See [`StorageBackend::write_file_from_stream`](../trait.StorageBackend.html#tymethod.write_file_from_stream).
+    pub fn write_file<P, R>(&self, info: P, reader: R) -> Result<(), TransferError>
+    where
+        P: TryInto<UploadInfo>,
+        P::Error: Into<StorageError>,
+        R: io::Read + Send + 'static,
+    {
+        let stream = iter(ChunkReader::new(reader));
+        self.runtime
+            .block_on(self.store.write_file_from_stream(info, stream))
+    }
+}
+
+/// An iterator over the objects returned by a listing.
+///
+/// Each call to `next` blocks until the next object is available.
+pub struct ObjectIterator {
+    stream: ObjectStream,
+    runtime: Arc<Runtime>,
+}
+
+impl Iterator for ObjectIterator {
+    type Item = StorageResult<Object>;
+
+    fn next(&mut self) -> Option<StorageResult<Object>> {
+        self.runtime.block_on(self.stream.next())
+    }
+}
+
+/// Reads the data of a file.
+///
+/// Reading blocks until data is available from the storage backend.
+pub struct FileReader {
+    stream: DataStream,
+    runtime: Arc<Runtime>,
+    current: Data,
+}
+
+impl io::Read for FileReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.current.is_empty() {
+            match self.runtime.block_on(self.stream.next()) {
+                Some(Ok(data)) => self.current = data,
+                Some(Err(e)) => return Err(e.into()),
+                None => return Ok(0),
+            }
+        }
+
+        let count = buf.len().min(self.current.len());
+        buf[..count].copy_from_slice(&self.current.split_to(count));
+        Ok(count)
+    }
+}
@@ -0,0 +1,547 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Caching the files of a slow store in a faster one.
+//!
+//! A [`Cache`](struct.Cache.html) keeps copies of the files read from a store
+//! in a second store, usually the file backend on a local disk, and serves
+//! later reads from the copies. The cache store should not be used for
+//! anything else. [`Cache::store`](struct.Cache.html#method.store) returns the
+//! wrapped store. When the copies grow larger than the capacity the least
+//! recently used are removed.
+//!
+//! Nothing tells the cache about changes other processes make to the original
+//! store so applications manage coherence themselves.
+//! [`invalidate`](struct.Cache.html#method.invalidate) and
+//! [`invalidate_prefix`](struct.Cache.html#method.invalidate_prefix) remove
+//! copies that may be stale and [`pin`](struct.Cache.html#method.pin) keeps a
+//! file cached regardless of the capacity.
+//!
+//! In [`WritePolicy::WriteThrough`](enum.WritePolicy.html#variant.WriteThrough),
+//! the default, writes go to both stores before completing. In
+//! [`WritePolicy::WriteBack`](enum.WritePolicy.html#variant.WriteBack) writes only
+//! go to the cache and are written to the original store by
+//! [`flush`](struct.Cache.html#method.flush). Until then they are not
+//! included in listings. Only the copies made by this `Cache` are tracked,
+//! anything left in the cache store by an earlier process is replaced when
+//! next read.
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::backends::Backend;
+use crate::dynamic::{self, DynamicStore};
+use crate::types::*;
+use crate::{FileStore, ObjectInfo, StorageBackend};
+
+/// Future returned by [`Cache::flush`](struct.Cache.html#method.flush).
+pub type FlushFuture = WrappedFuture<StorageResult<Vec<ObjectPath>>>;
+
+/// When writes reach the original store.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WritePolicy {
+    /// Writes complete once the file is in both the cache and the original
+    /// store.
+    WriteThrough,
+    /// Writes complete once the file is in the cache. It is written to the
+    /// original store by [`Cache::flush`](struct.Cache.html#method.flush).
+    WriteBack,
+}
+
+/// Statistics about a [`Cache`](struct.Cache.html).
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CacheStats {
+    /// The number of reads served from the cache.
+    pub hits: u64,
+    /// The number of reads that had to fetch the file from the original store.
+    pub misses: u64,
+    /// The number of copies removed to stay within the capacity.
+    pub evictions: u64,
+    /// The number of files in the cache.
+    pub files: usize,
+    /// The total size of the files in the cache.
+    pub size: u64,
+    /// The number of pinned paths.
+    pub pinned: usize,
+    /// The number of files waiting to be written to the original store.
+    pub dirty: usize,
+}
+
+#[derive(Clone, Debug)]
+struct Entry {
+    len: u64,
+    last_used: u64,
+    /// Set while the file has not been written to the original store.
+    dirty: Option<UploadInfo>,
+}
+
+#[derive(Debug)]
+struct CacheState {
+    policy: WritePolicy,
+    entries: HashMap<ObjectPath, Entry>,
+    pinned: HashSet<ObjectPath>,
+    clock: u64,
+    stats: CacheStats,
+}
+
+#[derive(Debug)]
+struct CacheInner {
+    store: FileStore,
+    cache: FileStore,
+    capacity: u64,
+    state: Mutex<CacheState>,
+}
+
+/// Caches the files of a store in another store.
+///
+/// Clones share the same cache. See the [`cache`](index.html) module.
+#[derive(Clone, Debug)]
+pub struct Cache {
+    inner: Arc<CacheInner>,
+}
+
+fn ignore_not_found(result: StorageResult<()>) -> StorageResult<()> {
+    match result {
+        Err(e) => match e.kind() {
+            StorageErrorKind::NotFound(_) => Ok(()),
+            _ => Err(e),
+        },
+        result => result,
+    }
+}
+
+impl Cache {
+    /// Creates a cache keeping up to `capacity` bytes of copies of the files
+    /// in `store` in `cache`.
+    pub fn new(store: FileStore, cache: FileStore, capacity: u64) -> Cache {
+        Cache {
+            inner: Arc::new(CacheInner {
+                store,
+                cache,
+                capacity,
+                state: Mutex::new(CacheState {
+                    policy: WritePolicy::WriteThrough,
+                    entries: HashMap::new(),
+                    pinned: HashSet::new(),
+                    clock: 0,
+                    stats: Default::default(),
+                }),
+            }),
+        }
+    }
+
+    /// Wraps the original store so reads are served from the cache where
+    /// possible and writes follow the current [`WritePolicy`](enum.WritePolicy.html).
+    pub fn store(&self) -> FileStore {
+        FileStore::from(DynamicStore::new(CachedStore(self.clone())))
+    }
+
+    fn state(&self) -> MutexGuard<CacheState> {
+        match self.inner.state.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// The current write policy.
+    pub fn policy(&self) -> WritePolicy {
+        self.state().policy
+    }
+
+    /// Changes the write policy. Switching to write-through does not write the
+    /// files waiting to be written, call [`flush`](#method.flush) for that.
+    pub fn set_policy(&self, policy: WritePolicy) {
+        self.state().policy = policy;
+    }
+
+    /// Returns the current statistics.
+    pub fn stats(&self) -> CacheStats {
+        let state = self.state();
+        let mut stats = state.stats.clone();
+        stats.files = state.entries.len();
+        stats.size = state.entries.values().map(|e| e.len).sum();
+        stats.pinned = state.pinned.len();
+        stats.dirty = state.entries.values().filter(|e| e.dirty.is_some()).count();
+        stats
+    }
+
+    /// Removes the cached copy of a file so the next read fetches it from the
+    /// original store again. Files waiting to be written to the original store
+    /// are kept.
+    pub fn invalidate(&self, path: ObjectPath) -> OperationCompleteFuture {
+        let removed = self.remove_entries(|p| *p == path);
+        OperationCompleteFuture::from_future(remove_copies(self.clone(), removed))
+    }
+
+    /// Removes the cached copies of the files with the given prefix. Files
+    /// waiting to be written to the original store are kept.
+    pub fn invalidate_prefix(&self, prefix: ObjectPath) -> OperationCompleteFuture {
+        let removed = self.remove_entries(|p| p.starts_with(&prefix));
+        OperationCompleteFuture::from_future(remove_copies(self.clone(), removed))
+    }
+
+    /// Keeps a file in the cache regardless of the capacity, fetching it now
+    /// if necessary. An invalidated pinned file is fetched again when next
+    /// read.
+    pub fn pin(&self, path: ObjectPath) -> OperationCompleteFuture {
+        self.state().pinned.insert(path.clone());
+        OperationCompleteFuture::from_future(pin(self.clone(), path))
+    }
+
+    /// Allows a pinned file to be removed from the cache again.
+    pub fn unpin(&self, path: &ObjectPath) {
+        self.state().pinned.remove(path);
+    }
+
+    /// Writes the files waiting in the cache to the original store, resolving
+    /// to the paths written.
+    pub fn flush(&self) -> FlushFuture {
+        FlushFuture::from_future(flush(self.clone(), None))
+    }
+
+    fn remove_entries<F>(&self, filter: F) -> Vec<ObjectPath>
+    where
+        F: Fn(&ObjectPath) -> bool,
+    {
+        let mut state = self.state();
+        let removed: Vec<ObjectPath> = state
+            .entries
+            .iter()
+            .filter(|(path, entry)| entry.dirty.is_none() && filter(*path))
+            .map(|(path, _)| path.clone())
+            .collect();
+
+        for path in &removed {
+            state.entries.remove(path);
+        }
+        removed
+    }
+
+    /// Marks a cached file as used, returning whether it is cached.
+    fn touch(&self, path: &ObjectPath) -> bool {
+        let mut state = self.state();
+        state.clock += 1;
+        let clock = state.clock;
+        match state.entries.get_mut(path) {
+            Some(entry) => {
+                entry.last_used = clock;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn is_dirty(&self, path: &ObjectPath) -> bool {
+        self.state()
+            .entries
+            .get(path)
+            .map_or(false, |e| e.dirty.is_some())
+    }
+
+    /// Records a new copy, returning the copies that must be removed to stay
+    /// within the capacity.
+    fn insert(&self, path: ObjectPath, len: u64, dirty: Option<UploadInfo>) -> Vec<ObjectPath> {
+        let mut state = self.state();
+        state.clock += 1;
+        let entry = Entry {
+            len,
+            last_used: state.clock,
+            dirty,
+        };
+        state.entries.insert(path.clone(), entry);
+
+        let mut size: u64 = state.entries.values().map(|e| e.len).sum();
+        let mut candidates: Vec<(u64, ObjectPath)> = state
+            .entries
+            .iter()
+            .filter(|(p, e)| **p != path && e.dirty.is_none() && !state.pinned.contains(*p))
+            .map(|(p, e)| (e.last_used, p.clone()))
+            .collect();
+        candidates.sort();
+
+        let mut evicted = Vec::new();
+        for (_, candidate) in candidates {
+            if size <= self.inner.capacity {
+                break;
+            }
+
+            if let Some(entry) = state.entries.remove(&candidate) {
+                size -= entry.len;
+                state.stats.evictions += 1;
+                evicted.push(candidate);
+            }
+        }
+        evicted
+    }
+
+    fn forget(&self, path: &ObjectPath) -> Option<Entry> {
+        self.state().entries.remove(path)
+    }
+}
+
+async fn remove_copies(cache: Cache, paths: Vec<ObjectPath>) -> StorageResult<()> {
+    for path in paths {
+        ignore_not_found(cache.inner.cache.delete_object(path).await)?;
+    }
+    Ok(())
+}
+
+/// Copies a file from the original store into the cache.
+async fn fetch(cache: &Cache, path: &ObjectPath) -> StorageResult<()> {
+    let object = cache.inner.store.get_object(path.clone()).await?;
+    let stream = cache.inner.store.get_file_stream(path.clone()).await?;
+    cache
+        .inner
+        .cache
+        .write_file_from_stream(UploadInfo::from(path.clone()), stream)
+        .await
+        .map_err(|e| match e {
+            TransferError::SourceError(e) => e,
+            TransferError::TargetError(e) => e,
+        })?;
+
+    let evicted = cache.insert(path.clone(), object.len(), None);
+    // Failing to remove old copies only wastes space.
+    let _ = remove_copies(cache.clone(), evicted).await;
+    Ok(())
+}
+
+async fn pin(cache: Cache, path: ObjectPath) -> StorageResult<()> {
+    if !cache.touch(&path) {
+        fetch(&cache, &path).await?;
+    }
+    Ok(())
+}
+
+async fn read(cache: Cache, path: ObjectPath, options: ReadOptions) -> StorageResult<DataStream> {
+    if cache.touch(&path) {
+        match cache
+            .inner
+            .cache
+            .get_file_stream_with_options(path.clone(), options.clone())
+            .await
+        {
+            Ok(stream) => {
+                cache.state().stats.hits += 1;
+                return Ok(stream);
+            }
+            Err(e) => match e.kind() {
+                // Removed from the cache store by something else.
+                StorageErrorKind::NotFound(_) if !cache.is_dirty(&path) => {
+                    cache.forget(&path);
+                }
+                _ => return Err(e),
+            },
+        }
+    }
+
+    cache.state().stats.misses += 1;
+    let object = cache.inner.store.get_object(path.clone()).await?;
+    let pinned = cache.state().pinned.contains(&path);
+    if object.len() > cache.inner.capacity && !pinned {
+        return cache
+            .inner
+            .store
+            .get_file_stream_with_options(path, options)
+            .await;
+    }
+
+    fetch(&cache, &path).await?;
+    cache
+        .inner
+        .cache
+        .get_file_stream_with_options(path, options)
+        .await
+}
+
+async fn write(cache: Cache, info: UploadInfo, stream: DataStream) -> Result<(), TransferError> {
+    let path = info.path.clone();
+    cache.forget(&path);
+
+    cache
+        .inner
+        .cache
+        .write_file_from_stream(UploadInfo::from(path.clone()), stream)
+        .await?;
+
+    let object = cache
+        .inner
+        .cache
+        .get_object(path.clone())
+        .await
+        .map_err(TransferError::TargetError)?;
+
+    let dirty = match cache.policy() {
+        WritePolicy::WriteThrough => {
+            let copy = cache
+                .inner
+                .cache
+                .get_file_stream(path.clone())
+                .await
+                .map_err(TransferError::TargetError)?;
+            cache.inner.store.write_file_from_stream(info, copy).await?;
+            None
+        }
+        WritePolicy::WriteBack => Some(info),
+    };
+
+    let evicted = cache.insert(path, object.len(), dirty);
+    let _ = remove_copies(cache, evicted).await;
+    Ok(())
+}
+
+/// Writes dirty files to the original store. Only `path` is written if given.
+async fn flush(cache: Cache, path: Option<ObjectPath>) -> StorageResult<Vec<ObjectPath>> {
+    let dirty: Vec<UploadInfo> = cache
+        .state()
+        .entries
+        .iter()
+        .filter(|(p, _)| path.as_ref().map_or(true, |path| *p == path))
+        .filter_map(|(_, e)| e.dirty.clone())
+        .collect();
+
+    let mut flushed = Vec::new();
+    for info in dirty {
+        let path = info.path.clone();
+        let stream = cache.inner.cache.get_file_stream(path.clone()).await?;
+        cache
+            .inner
+            .store
+            .write_file_from_stream(info, stream)
+            .await
+            .map_err(|e| match e {
+                TransferError::SourceError(e) => e,
+                TransferError::TargetError(e) => e,
+            })?;
+
+        if let Some(entry) = cache.state().entries.get_mut(&path) {
+            entry.dirty = None;
+        }
+        flushed.push(path);
+    }
+
+    Ok(flushed)
+}
+
+async fn delete(cache: Cache, path: ObjectPath) -> StorageResult<()> {
+    let entry = cache.forget(&path);
+    ignore_not_found(cache.inner.cache.delete_object(path.clone()).await)?;
+
+    let result = cache.inner.store.delete_object(path).await;
+    match entry {
+        // The file may never have reached the original store.
+        Some(Entry { dirty: Some(_), .. }) => ignore_not_found(result),
+        _ => result,
+    }
+}
+
+async fn copy(
+    cache: Cache,
+    source: ObjectPath,
+    target: UploadInfo,
+    remove: bool,
+) -> Result<(), TransferError> {
+    flush(cache.clone(), Some(source.clone()))
+        .await
+        .map_err(TransferError::SourceError)?;
+
+    let mut stale = vec![target.path.clone()];
+    if remove {
+        stale.push(source.clone());
+    }
+    for path in &stale {
+        cache.forget(path);
+    }
+
+    if remove {
+        cache.inner.store.move_file(source, target).await?;
+    } else {
+        cache.inner.store.copy_file(source, target).await?;
+    }
+
+    let _ = remove_copies(cache, stale).await;
+    Ok(())
+}
+
+/// The backend of the store returned by
+/// [`Cache::store`](struct.Cache.html#method.store).
+struct CachedStore(Cache);
+
+// Only StorageBackend is in scope so calls on the wrapped store are not
+// ambiguous.
+impl dynamic::DynamicBackend for CachedStore {
+    fn backend_type(&self) -> Backend {
+        self.0.inner.store.backend_type()
+    }
+
+    fn authorize(&self) -> OperationCompleteFuture {
+        self.0.inner.store.authorize()
+    }
+
+    fn validate_path(&self, path: &ObjectPath) -> StorageResult<()> {
+        self.0.inner.store.validate_path(path)
+    }
+
+    fn list_objects(&self, prefix: ObjectPath) -> ObjectStreamFuture {
+        self.0.inner.store.list_objects(prefix)
+    }
+
+    fn list_directory(&self, dir: ObjectPath) -> ObjectStreamFuture {
+        self.0.inner.store.list_directory(dir)
+    }
+
+    fn get_object(&self, path: ObjectPath) -> ObjectFuture {
+        if self.0.is_dirty(&path) {
+            return self.0.inner.cache.get_object(path);
+        }
+
+        self.0.inner.store.get_object(path)
+    }
+
+    fn get_file_stream(&self, path: ObjectPath) -> DataStreamFuture {
+        self.get_file_stream_with_options(path, Default::default())
+    }
+
+    fn get_file_stream_with_options(
+        &self,
+        path: ObjectPath,
+        options: ReadOptions,
+    ) -> DataStreamFuture {
+        // Encrypted files are not copied out of the original store.
+        if options.customer_key.is_some() {
+            return self
+                .0
+                .inner
+                .store
+                .get_file_stream_with_options(path, options);
+        }
+
+        DataStreamFuture::from_future(read(self.0.clone(), path, options))
+    }
+
+    fn copy_file(&self, source: ObjectPath, target: UploadInfo) -> CopyCompleteFuture {
+        CopyCompleteFuture::from_future(copy(self.0.clone(), source, target, false))
+    }
+
+    fn move_file(&self, source: ObjectPath, target: UploadInfo) -> MoveCompleteFuture {
+        MoveCompleteFuture::from_future(copy(self.0.clone(), source, target, true))
+    }
+
+    fn delete_object(&self, path: ObjectPath) -> OperationCompleteFuture {
+        OperationCompleteFuture::from_future(delete(self.0.clone(), path))
+    }
+
+    fn write_file_from_stream(&self, info: UploadInfo, stream: DataStream) -> WriteCompleteFuture {
+        WriteCompleteFuture::from_future(write(self.0.clone(), info, stream))
+    }
+}
@@ -0,0 +1,413 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Treating the paths in a store as case-insensitive.
+//!
+//! Applications moving data from case-insensitive filesystems, like those on
+//! Windows or SMB shares, often rely on `File.TXT` and `file.txt` being the
+//! same file. [`CaseInsensitive::store`](struct.CaseInsensitive.html#method.store)
+//! wraps a case-sensitive store so that paths are matched regardless of case.
+//! Every operation uses the case of the file or directory that already exists
+//! so writing `File.TXT` replaces `file.txt` rather than creating a second
+//! file. New files keep the case they are written with. Moving a file to a
+//! path that differs only in case renames it. Listings show the real case of
+//! paths and a partial file name at the end of a listing prefix is still
+//! matched case-sensitively.
+//!
+//! Paths are compared by their Unicode lowercase form. The wrapper keeps an
+//! index of the store's files in memory, built by listing the store the first
+//! time it is needed. Changes made through the wrapper keep it up to date,
+//! [`rebuild`](struct.CaseInsensitive.html#method.rebuild) picks up changes
+//! made elsewhere. Files that already differ only in case are reported by
+//! [`conflicts`](struct.CaseInsensitive.html#method.conflicts), the one that
+//! sorts first is the one used.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::backends::Backend;
+use crate::dynamic::{self, DynamicStore};
+use crate::sync::list_files;
+use crate::types::*;
+use crate::{FileStore, StorageBackend};
+
+/// Future returned by
+/// [`CaseInsensitive::conflicts`](struct.CaseInsensitive.html#method.conflicts).
+pub type ConflictsFuture = WrappedFuture<StorageResult<Vec<Vec<ObjectPath>>>>;
+
+fn fold_parts(parts: &[&str]) -> String {
+    parts
+        .iter()
+        .map(|part| part.to_lowercase())
+        .collect::<Vec<String>>()
+        .join("/")
+}
+
+fn fold(path: &ObjectPath) -> String {
+    fold_parts(&path.parts())
+}
+
+#[derive(Debug, Default)]
+struct Index {
+    /// Files keyed by their folded path.
+    files: HashMap<String, ObjectPath>,
+    /// Directories keyed by their folded path.
+    dirs: HashMap<String, ObjectPath>,
+    /// Files that differ only in case, keyed by their folded path.
+    conflicts: HashMap<String, Vec<ObjectPath>>,
+}
+
+impl Index {
+    fn insert(&mut self, path: &ObjectPath) {
+        let parts = path.parts();
+        for end in 1..parts.len() {
+            self.dirs
+                .entry(fold_parts(&parts[..end]))
+                .or_insert_with(|| ObjectPath::new(parts[..end].join("/")).unwrap_or_default());
+        }
+
+        let key = fold(path);
+        match self.files.get(&key) {
+            Some(existing) if existing != path => {
+                let conflicts = self
+                    .conflicts
+                    .entry(key)
+                    .or_insert_with(|| vec![existing.clone()]);
+                if !conflicts.contains(path) {
+                    conflicts.push(path.clone());
+                    conflicts.sort();
+                }
+            }
+            Some(_) => (),
+            None => {
+                self.files.insert(key, path.clone());
+            }
+        }
+    }
+
+    fn remove_file(&mut self, path: &ObjectPath) {
+        let key = fold(path);
+        if let Some(conflicts) = self.conflicts.get_mut(&key) {
+            conflicts.retain(|p| p != path);
+            if let Some(first) = conflicts.first() {
+                self.files.insert(key.clone(), first.clone());
+            }
+            if conflicts.len() < 2 {
+                self.conflicts.remove(&key);
+            }
+            return;
+        }
+
+        self.files.remove(&key);
+    }
+
+    /// Removes a file or everything in a directory.
+    fn remove(&mut self, path: &ObjectPath) {
+        self.remove_file(path);
+
+        let key = fold(path);
+        let nested = format!("{}/", key);
+        let contents: Vec<ObjectPath> = self
+            .files
+            .iter()
+            .filter(|(k, _)| k.starts_with(&nested))
+            .map(|(_, p)| p.clone())
+            .collect();
+        for file in contents {
+            self.remove_file(&file);
+        }
+        self.dirs
+            .retain(|k, _| k != &key && !k.starts_with(&nested));
+    }
+
+    /// Finds the path that matches this one ignoring case.
+    fn resolve(&self, path: &ObjectPath) -> ObjectPath {
+        let key = fold(path);
+        if let Some(actual) = self.files.get(&key).or_else(|| self.dirs.get(&key)) {
+            return actual.clone();
+        }
+
+        let parts = path.parts();
+        for end in (1..parts.len()).rev() {
+            if let Some(dir) = self.dirs.get(&fold_parts(&parts[..end])) {
+                let mut resolved = dir.clone();
+                for part in &parts[end..] {
+                    resolved.push_part(part);
+                }
+                return resolved;
+            }
+        }
+
+        path.clone()
+    }
+}
+
+/// Makes the paths of a store case-insensitive.
+///
+/// Clones share the same index. See the [`casefold`](index.html) module.
+#[derive(Clone, Debug)]
+pub struct CaseInsensitive {
+    store: FileStore,
+    index: Arc<Mutex<Option<Index>>>,
+}
+
+impl CaseInsensitive {
+    /// Creates a case-insensitive layer over the store.
+    pub fn new(store: FileStore) -> CaseInsensitive {
+        CaseInsensitive {
+            store,
+            index: Default::default(),
+        }
+    }
+
+    /// Wraps the store so that paths are matched regardless of case.
+    pub fn store(&self) -> FileStore {
+        FileStore::from(DynamicStore::new(CaseInsensitiveStore(self.clone())))
+    }
+
+    fn index(&self) -> MutexGuard<Option<Index>> {
+        match self.index.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Lists the store again to pick up changes made other than through the
+    /// wrapped store.
+    pub fn rebuild(&self) -> OperationCompleteFuture {
+        OperationCompleteFuture::from_future(rebuild(self.clone()))
+    }
+
+    /// Resolves to the groups of files in the store whose paths differ only
+    /// in case.
+    pub fn conflicts(&self) -> ConflictsFuture {
+        ConflictsFuture::from_future(conflicts(self.clone()))
+    }
+}
+
+async fn build(store: &FileStore) -> StorageResult<Index> {
+    let files = match list_files(store, &ObjectPath::empty()).await {
+        Ok(files) => files,
+        Err(e) => match e.kind() {
+            StorageErrorKind::NotFound(_) => HashMap::new(),
+            _ => return Err(e),
+        },
+    };
+
+    let mut names: Vec<&String> = files.keys().collect();
+    names.sort();
+
+    let mut index = Index::default();
+    for name in names {
+        index.insert(&ObjectPath::new(name)?);
+    }
+    Ok(index)
+}
+
+async fn rebuild(layer: CaseInsensitive) -> StorageResult<()> {
+    let index = build(&layer.store).await?;
+    *layer.index() = Some(index);
+    Ok(())
+}
+
+/// Builds the index if it hasn't been yet.
+async fn loaded(layer: &CaseInsensitive) -> StorageResult<()> {
+    if layer.index().is_some() {
+        return Ok(());
+    }
+
+    let index = build(&layer.store).await?;
+    let mut current = layer.index();
+    if current.is_none() {
+        *current = Some(index);
+    }
+    Ok(())
+}
+
+async fn resolve(layer: &CaseInsensitive, path: &ObjectPath) -> StorageResult<ObjectPath> {
+    loaded(layer).await?;
+    Ok(match *layer.index() {
+        Some(ref index) => index.resolve(path),
+        None => path.clone(),
+    })
+}
+
+fn update<F>(layer: &CaseInsensitive, f: F)
+where
+    F: FnOnce(&mut Index),
+{
+    if let Some(ref mut index) = *layer.index() {
+        f(index);
+    }
+}
+
+async fn conflicts(layer: CaseInsensitive) -> StorageResult<Vec<Vec<ObjectPath>>> {
+    loaded(&layer).await?;
+    let mut conflicts: Vec<Vec<ObjectPath>> = match *layer.index() {
+        Some(ref index) => index.conflicts.values().cloned().collect(),
+        None => Vec::new(),
+    };
+    conflicts.sort();
+    Ok(conflicts)
+}
+
+async fn list_objects(layer: CaseInsensitive, prefix: ObjectPath) -> StorageResult<ObjectStream> {
+    let prefix = resolve(&layer, &prefix).await?;
+    layer.store.list_objects(prefix).await
+}
+
+async fn list_directory(layer: CaseInsensitive, dir: ObjectPath) -> StorageResult<ObjectStream> {
+    let dir = resolve(&layer, &dir).await?;
+    layer.store.list_directory(dir).await
+}
+
+async fn get_object(layer: CaseInsensitive, path: ObjectPath) -> StorageResult<Object> {
+    let path = resolve(&layer, &path).await?;
+    layer.store.get_object(path).await
+}
+
+async fn read(
+    layer: CaseInsensitive,
+    path: ObjectPath,
+    options: ReadOptions,
+) -> StorageResult<DataStream> {
+    let path = resolve(&layer, &path).await?;
+    layer
+        .store
+        .get_file_stream_with_options(path, options)
+        .await
+}
+
+async fn copy(
+    layer: CaseInsensitive,
+    source: ObjectPath,
+    mut target: UploadInfo,
+) -> Result<(), TransferError> {
+    let source = resolve(&layer, &source)
+        .await
+        .map_err(TransferError::SourceError)?;
+    target.path = resolve(&layer, &target.path)
+        .await
+        .map_err(TransferError::TargetError)?;
+
+    let path = target.path.clone();
+    layer.store.copy_file(source, target).await?;
+    update(&layer, |index| index.insert(&path));
+    Ok(())
+}
+
+async fn move_file(
+    layer: CaseInsensitive,
+    source: ObjectPath,
+    mut target: UploadInfo,
+) -> Result<(), TransferError> {
+    let source = resolve(&layer, &source)
+        .await
+        .map_err(TransferError::SourceError)?;
+    // Moving to a path that differs only in case renames the file.
+    if fold(&source) != fold(&target.path) {
+        target.path = resolve(&layer, &target.path)
+            .await
+            .map_err(TransferError::TargetError)?;
+    }
+
+    let path = target.path.clone();
+    layer.store.move_file(source.clone(), target).await?;
+    update(&layer, |index| {
+        index.remove(&source);
+        index.insert(&path);
+    });
+    Ok(())
+}
+
+async fn delete(layer: CaseInsensitive, path: ObjectPath) -> StorageResult<()> {
+    let path = resolve(&layer, &path).await?;
+    layer.store.delete_object(path.clone()).await?;
+    update(&layer, |index| index.remove(&path));
+    Ok(())
+}
+
+async fn write(
+    layer: CaseInsensitive,
+    mut info: UploadInfo,
+    stream: DataStream,
+) -> Result<(), TransferError> {
+    info.path = resolve(&layer, &info.path)
+        .await
+        .map_err(TransferError::TargetError)?;
+
+    let path = info.path.clone();
+    layer.store.write_file_from_stream(info, stream).await?;
+    update(&layer, |index| index.insert(&path));
+    Ok(())
+}
+
+/// The backend of the store returned by
+/// [`CaseInsensitive::store`](struct.CaseInsensitive.html#method.store).
+struct CaseInsensitiveStore(CaseInsensitive);
+
+// Only StorageBackend is in scope so calls on the wrapped store are not
+// ambiguous.
+impl dynamic::DynamicBackend for CaseInsensitiveStore {
+    fn backend_type(&self) -> Backend {
+        self.0.store.backend_type()
+    }
+
+    fn authorize(&self) -> OperationCompleteFuture {
+        self.0.store.authorize()
+    }
+
+    fn validate_path(&self, path: &ObjectPath) -> StorageResult<()> {
+        self.0.store.validate_path(path)
+    }
+
+    fn list_objects(&self, prefix: ObjectPath) -> ObjectStreamFuture {
+        ObjectStreamFuture::from_future(list_objects(self.0.clone(), prefix))
+    }
+
+    fn list_directory(&self, dir: ObjectPath) -> ObjectStreamFuture {
+        ObjectStreamFuture::from_future(list_directory(self.0.clone(), dir))
+    }
+
+    fn get_object(&self, path: ObjectPath) -> ObjectFuture {
+        ObjectFuture::from_future(get_object(self.0.clone(), path))
+    }
+
+    fn get_file_stream(&self, path: ObjectPath) -> DataStreamFuture {
+        self.get_file_stream_with_options(path, Default::default())
+    }
+
+    fn get_file_stream_with_options(
+        &self,
+        path: ObjectPath,
+        options: ReadOptions,
+    ) -> DataStreamFuture {
+        DataStreamFuture::from_future(read(self.0.clone(), path, options))
+    }
+
+    fn copy_file(&self, source: ObjectPath, target: UploadInfo) -> CopyCompleteFuture {
+        CopyCompleteFuture::from_future(copy(self.0.clone(), source, target))
+    }
+
+    fn move_file(&self, source: ObjectPath, target: UploadInfo) -> MoveCompleteFuture {
+        MoveCompleteFuture::from_future(move_file(self.0.clone(), source, target))
+    }
+
+    fn delete_object(&self, path: ObjectPath) -> OperationCompleteFuture {
+        OperationCompleteFuture::from_future(delete(self.0.clone(), path))
+    }
+
+    fn write_file_from_stream(&self, info: UploadInfo, stream: DataStream) -> WriteCompleteFuture {
+        WriteCompleteFuture::from_future(write(self.0.clone(), info, stream))
+    }
+}
@@ -0,0 +1,379 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Following the files created, modified and deleted in a store.
+//!
+//! A [`ChangeFeed`](trait.ChangeFeed.html) produces a stream of
+//! [`Change`](struct.Change.html)s for the files under a prefix, giving
+//! indexers and other consumers one way to follow any store. Prefixes are
+//! treated as directories. Two feeds are included:
+//!
+//! * [`PollingFeed`](struct.PollingFeed.html) lists the prefix periodically and
+//!   reports the differences between listings. It works with every backend
+//!   and can persist a cursor so that changes made while nothing was
+//!   following the store are reported when it next starts.
+//! * [`WatchFeed`](struct.WatchFeed.html) uses inotify to hear about changes to
+//!   the file backend as they happen. It is only available on Linux and only
+//!   reports changes made while the stream exists.
+//!
+//! [`feed`](fn.feed.html) picks the best feed for a store.
+use std::collections::{HashMap, VecDeque};
+use std::str::from_utf8;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::stream::{iter, unfold, TryStreamExt};
+use tokio_timer::delay_for;
+
+#[cfg(all(feature = "file", target_os = "linux"))]
+use crate::backends::file::FileBackend;
+use crate::sync::{directory, list_files};
+use crate::trash::encode;
+use crate::types::*;
+use crate::{FileStore, ObjectInfo, StorageBackend};
+
+#[cfg(all(feature = "file", target_os = "linux"))]
+mod inotify;
+
+const CURSOR_HEADER: &str = "file-store changes 1";
+
+/// Stream returned by [`ChangeFeed::changes`](trait.ChangeFeed.html#tymethod.changes).
+pub type ChangeStream = WrappedStream<StorageResult<Change>>;
+
+/// What happened to a file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChangeKind {
+    /// The file was created.
+    Created,
+    /// The file's data changed.
+    Modified,
+    /// The file was deleted.
+    Deleted,
+}
+
+/// A change to a file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Change {
+    path: ObjectPath,
+    kind: ChangeKind,
+}
+
+impl Change {
+    fn new(path: &str, kind: ChangeKind) -> StorageResult<Change> {
+        Ok(Change {
+            path: ObjectPath::new(path)?,
+            kind,
+        })
+    }
+
+    /// The path of the file that changed.
+    pub fn path(&self) -> &ObjectPath {
+        &self.path
+    }
+
+    /// What happened to the file.
+    pub fn kind(&self) -> ChangeKind {
+        self.kind
+    }
+}
+
+/// Produces the changes made to the files in a store.
+pub trait ChangeFeed: Send + Sync {
+    /// Returns a stream of the changes made to files under `prefix`. The
+    /// stream never ends, failures are reported as errors in the stream and
+    /// following continues afterwards where possible.
+    fn changes(&self, prefix: ObjectPath) -> ChangeStream;
+}
+
+/// Picks the best feed for a store.
+///
+/// The file backend is watched where that is supported, otherwise the store
+/// is listed every `interval`.
+pub fn feed(store: &FileStore, interval: Duration) -> Box<dyn ChangeFeed> {
+    #[cfg(all(feature = "file", target_os = "linux"))]
+    {
+        if let Ok(feed) = WatchFeed::new(store) {
+            return Box::new(feed);
+        }
+    }
+
+    Box::new(PollingFeed::new(store.clone(), interval))
+}
+
+/// The length and modification time of every file seen under a prefix.
+type Snapshot = HashMap<String, (u64, Option<u64>)>;
+
+fn millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+async fn snapshot(store: &FileStore, prefix: &ObjectPath) -> StorageResult<Snapshot> {
+    Ok(list_files(store, prefix)
+        .await?
+        .values()
+        .map(|o| (o.path().to_string(), (o.len(), o.modified().map(millis))))
+        .collect())
+}
+
+fn compare(previous: &Snapshot, current: &Snapshot) -> StorageResult<Vec<Change>> {
+    let mut changes = Vec::new();
+    for (path, state) in current {
+        match previous.get(path) {
+            None => changes.push(Change::new(path, ChangeKind::Created)?),
+            Some(old) if old != state => changes.push(Change::new(path, ChangeKind::Modified)?),
+            _ => (),
+        }
+    }
+
+    for path in previous.keys() {
+        if !current.contains_key(path) {
+            changes.push(Change::new(path, ChangeKind::Deleted)?);
+        }
+    }
+
+    changes.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(changes)
+}
+
+fn parse_cursor(data: &[u8]) -> StorageResult<Snapshot> {
+    let text =
+        from_utf8(data).map_err(|_| error::invalid_data(Some("The cursor is not valid UTF-8.")))?;
+    let mut lines = text.lines();
+    if lines.next() != Some(CURSOR_HEADER) {
+        return Err(error::invalid_data(Some(
+            "The cursor has an unknown format.",
+        )));
+    }
+
+    let mut snapshot = Snapshot::new();
+    for line in lines {
+        let mut fields = line.splitn(3, ' ');
+        let len = fields.next().and_then(|f| f.parse::<u64>().ok());
+        let modified = match fields.next() {
+            Some("-") => Some(None),
+            Some(f) => f.parse::<u64>().ok().map(Some),
+            None => None,
+        };
+        match (len, modified, fields.next()) {
+            (Some(len), Some(modified), Some(path)) => {
+                snapshot.insert(path.to_owned(), (len, modified));
+            }
+            _ => {
+                return Err(error::invalid_data(Some(
+                    "The cursor contains an invalid line.",
+                )))
+            }
+        }
+    }
+
+    Ok(snapshot)
+}
+
+fn format_cursor(snapshot: &Snapshot) -> String {
+    let mut paths: Vec<&String> = snapshot.keys().collect();
+    paths.sort();
+
+    let mut text = format!("{}\n", CURSOR_HEADER);
+    for path in paths {
+        let (len, modified) = snapshot[path];
+        let modified = modified.map_or_else(|| "-".to_owned(), |m| m.to_string());
+        text.push_str(&format!("{} {} {}\n", len, modified, path));
+    }
+    text
+}
+
+async fn load_cursor(store: &FileStore, path: &ObjectPath) -> StorageResult<Option<Snapshot>> {
+    let stream = match store.get_file_stream(path.clone()).await {
+        Ok(stream) => stream,
+        Err(e) => match e.kind() {
+            StorageErrorKind::NotFound(_) => return Ok(None),
+            _ => return Err(e),
+        },
+    };
+
+    let data = stream.map_ok(|data| data.to_vec()).try_concat().await?;
+    parse_cursor(&data).map(Some)
+}
+
+async fn save_cursor(
+    store: &FileStore,
+    path: &ObjectPath,
+    snapshot: &Snapshot,
+) -> StorageResult<()> {
+    let data = Data::from(format_cursor(snapshot));
+    store
+        .write_file_from_stream(path.clone(), iter(vec![Ok::<Data, StorageError>(data)]))
+        .await
+        .map_err(|e| match e {
+            TransferError::SourceError(e) => e,
+            TransferError::TargetError(e) => e,
+        })
+}
+
+/// Finds changes by listing a store periodically.
+///
+/// The first listing only records what exists unless a cursor from an earlier
+/// run is available. Files are considered modified when their length or
+/// modification time changes.
+///
+/// See the [`changes`](index.html) module.
+#[derive(Clone, Debug)]
+pub struct PollingFeed {
+    store: FileStore,
+    interval: Duration,
+    cursors: Option<(FileStore, ObjectPath)>,
+}
+
+impl PollingFeed {
+    /// Creates a feed listing the store every `interval`.
+    pub fn new(store: FileStore, interval: Duration) -> PollingFeed {
+        PollingFeed {
+            store,
+            interval,
+            cursors: None,
+        }
+    }
+
+    /// Keeps a cursor for each followed prefix under `prefix` of `store`.
+    ///
+    /// A cursor records the last listing whose changes have all been taken
+    /// from the stream so a new stream for the same prefix reports what
+    /// changed in between. Changes taken from a stream that is then dropped
+    /// before the next listing may be reported again.
+    pub fn cursors(mut self, store: FileStore, prefix: ObjectPath) -> PollingFeed {
+        self.cursors = Some((store, prefix));
+        self
+    }
+}
+
+struct PollState {
+    feed: PollingFeed,
+    prefix: ObjectPath,
+    cursor: Option<ObjectPath>,
+    first: bool,
+    /// The last listing, once known.
+    previous: Option<Snapshot>,
+    /// Whether `previous` needs to be saved to the cursor.
+    unsaved: bool,
+    pending: VecDeque<Change>,
+}
+
+async fn poll(state: &mut PollState) -> StorageResult<()> {
+    if state.first {
+        state.first = false;
+    } else {
+        delay_for(state.feed.interval).await;
+    }
+
+    if let (Some((store, _)), Some(path)) = (&state.feed.cursors, &state.cursor) {
+        if state.previous.is_none() {
+            state.previous = load_cursor(store, path).await?;
+        } else if state.unsaved {
+            // Every change from the last listing has been taken.
+            if let Some(ref previous) = state.previous {
+                save_cursor(store, path, previous).await?;
+            }
+            state.unsaved = false;
+        }
+    }
+
+    let current = snapshot(&state.feed.store, &state.prefix).await?;
+    if let Some(ref previous) = state.previous {
+        state.pending.extend(compare(previous, &current)?);
+    }
+    state.previous = Some(current);
+    state.unsaved = true;
+
+    Ok(())
+}
+
+impl ChangeFeed for PollingFeed {
+    fn changes(&self, prefix: ObjectPath) -> ChangeStream {
+        let prefix = match directory(prefix) {
+            Ok(prefix) => prefix,
+            Err(e) => return ChangeStream::from_stream(iter(vec![Err(e)])),
+        };
+
+        let cursor = self.cursors.as_ref().map(|(_, dir)| {
+            let mut path = dir.clone();
+            path.push_part(&format!("{}.cursor", encode(&prefix)));
+            path
+        });
+
+        let state = PollState {
+            feed: self.clone(),
+            prefix,
+            cursor,
+            first: true,
+            previous: None,
+            unsaved: false,
+            pending: VecDeque::new(),
+        };
+
+        ChangeStream::from_stream(unfold(state, |mut state| async move {
+            loop {
+                if let Some(change) = state.pending.pop_front() {
+                    return Some((Ok(change), state));
+                }
+
+                if let Err(e) = poll(&mut state).await {
+                    return Some((Err(e), state));
+                }
+            }
+        }))
+    }
+}
+
+/// Hears about changes to the file backend from the operating system.
+///
+/// Only changes made while a stream exists are reported. Files with names
+/// that are not valid unicode are ignored. Each stream uses a thread that
+/// notices the stream has been dropped when the next change happens.
+///
+/// See the [`changes`](index.html) module.
+#[cfg(all(feature = "file", target_os = "linux"))]
+#[derive(Clone, Debug)]
+pub struct WatchFeed {
+    backend: FileBackend,
+}
+
+#[cfg(all(feature = "file", target_os = "linux"))]
+impl WatchFeed {
+    /// Creates a feed for a store using the file backend, failing for any
+    /// other backend.
+    pub fn new(store: &FileStore) -> StorageResult<WatchFeed> {
+        match store {
+            FileStore::File(ref backend) => Ok(WatchFeed {
+                backend: backend.clone(),
+            }),
+            _ => Err(error::invalid_settings(Some(
+                "Only stores using the file backend can be watched.",
+            ))),
+        }
+    }
+}
+
+#[cfg(all(feature = "file", target_os = "linux"))]
+impl ChangeFeed for WatchFeed {
+    fn changes(&self, prefix: ObjectPath) -> ChangeStream {
+        let result = directory(prefix)
+            .and_then(|prefix| Ok((self.backend.std_path(&ObjectPath::empty())?, prefix)));
+
+        match result {
+            Ok((root, prefix)) => inotify::watch(root, &prefix),
+            Err(e) => ChangeStream::from_stream(iter(vec![Err(e)])),
+        }
+    }
+}
@@ -0,0 +1,260 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Watching a directory tree with inotify.
+use std::collections::{HashMap, HashSet};
+use std::ffi::CString;
+use std::fs::read_dir;
+use std::io;
+use std::mem::size_of;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::ptr;
+use std::str::from_utf8;
+use std::thread;
+
+use futures::channel::mpsc::{unbounded, UnboundedSender};
+use futures::stream::iter;
+
+use super::{Change, ChangeKind, ChangeStream};
+use crate::types::*;
+
+const EVENTS: u32 = libc::IN_CREATE
+    | libc::IN_CLOSE_WRITE
+    | libc::IN_DELETE
+    | libc::IN_MOVED_FROM
+    | libc::IN_MOVED_TO;
+
+fn join(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_owned()
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+struct Watcher {
+    fd: libc::c_int,
+    root: PathBuf,
+    /// The directory each watch is for, relative to the root.
+    watches: HashMap<libc::c_int, String>,
+    /// The files known to exist, relative to the root.
+    files: HashSet<String>,
+    sender: UnboundedSender<StorageResult<Change>>,
+    /// Set once the stream has been dropped.
+    closed: bool,
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+impl Watcher {
+    fn send(&mut self, path: &str, kind: ChangeKind) {
+        self.send_result(Change::new(path, kind));
+    }
+
+    fn send_result(&mut self, result: StorageResult<Change>) {
+        if self.sender.unbounded_send(result).is_err() {
+            self.closed = true;
+        }
+    }
+
+    /// Watches a directory and everything inside it.
+    fn add(&mut self, dir: &str, report: bool) -> io::Result<()> {
+        let std_path = self.root.join(dir);
+        let name = CString::new(std_path.as_os_str().as_bytes())?;
+
+        // Watch before reading the directory so nothing created in between is
+        // missed.
+        let wd = unsafe { libc::inotify_add_watch(self.fd, name.as_ptr(), EVENTS) };
+        if wd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.watches.insert(wd, dir.to_owned());
+
+        for entry in read_dir(&std_path)? {
+            let entry = entry?;
+            let name = match entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            let path = join(dir, &name);
+
+            if entry.file_type()?.is_dir() {
+                match self.add(&path, report) {
+                    // Deleted since it was listed.
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                    result => result?,
+                }
+            } else if self.files.insert(path.clone()) && report {
+                self.send(&path, ChangeKind::Created);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Forgets a directory that has been deleted or moved away.
+    fn forget(&mut self, dir: &str) {
+        let prefix = format!("{}/", dir);
+
+        let mut removed: Vec<String> = self
+            .files
+            .iter()
+            .filter(|path| path.starts_with(&prefix))
+            .cloned()
+            .collect();
+        removed.sort();
+        for path in removed {
+            self.files.remove(&path);
+            self.send(&path, ChangeKind::Deleted);
+        }
+
+        let watches: Vec<libc::c_int> = self
+            .watches
+            .iter()
+            .filter(|(_, path)| *path == dir || path.starts_with(&prefix))
+            .map(|(wd, _)| *wd)
+            .collect();
+        for wd in watches {
+            // Fails harmlessly if the directory was deleted.
+            unsafe {
+                libc::inotify_rm_watch(self.fd, wd);
+            }
+            self.watches.remove(&wd);
+        }
+    }
+
+    fn handle(&mut self, wd: libc::c_int, mask: u32, name: Option<&str>) {
+        if mask & libc::IN_Q_OVERFLOW != 0 {
+            self.send_result(Err(error::other_error(Some(
+                "Too many changes happened at once, some were missed.",
+            ))));
+            return;
+        }
+
+        if mask & libc::IN_IGNORED != 0 {
+            self.watches.remove(&wd);
+            return;
+        }
+
+        let path = match (self.watches.get(&wd), name) {
+            (Some(dir), Some(name)) => join(dir, name),
+            _ => return,
+        };
+
+        if mask & libc::IN_ISDIR != 0 {
+            if mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0 {
+                if let Err(e) = self.add(&path, true) {
+                    if e.kind() != io::ErrorKind::NotFound {
+                        self.send_result(Err(e.into()));
+                    }
+                }
+            } else if mask & (libc::IN_DELETE | libc::IN_MOVED_FROM) != 0 {
+                self.forget(&path);
+            }
+        } else if mask & libc::IN_CREATE != 0 {
+            // A file found when its directory was added may be reported again.
+            if self.files.insert(path.clone()) {
+                self.send(&path, ChangeKind::Created);
+            }
+        } else if mask & (libc::IN_MOVED_TO | libc::IN_CLOSE_WRITE) != 0 {
+            if self.files.insert(path.clone()) {
+                self.send(&path, ChangeKind::Created);
+            } else {
+                self.send(&path, ChangeKind::Modified);
+            }
+        } else if mask & (libc::IN_DELETE | libc::IN_MOVED_FROM) != 0 && self.files.remove(&path) {
+            self.send(&path, ChangeKind::Deleted);
+        }
+    }
+
+    fn run(mut self) {
+        let mut buffer = [0u8; 4096];
+        let header = size_of::<libc::inotify_event>();
+
+        while !self.closed {
+            let len = unsafe {
+                libc::read(
+                    self.fd,
+                    buffer.as_mut_ptr() as *mut libc::c_void,
+                    buffer.len(),
+                )
+            };
+            if len < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                self.send_result(Err(e.into()));
+                return;
+            }
+
+            let len = len as usize;
+            let mut offset = 0;
+            while offset + header <= len {
+                let event: libc::inotify_event = unsafe {
+                    ptr::read_unaligned(buffer.as_ptr().add(offset) as *const libc::inotify_event)
+                };
+                let start = offset + header;
+                offset = start + event.len as usize;
+
+                // The name is padded with nul bytes.
+                let name = &buffer[start..offset];
+                let name = &name[..name.iter().position(|b| *b == 0).unwrap_or(name.len())];
+                let name = if name.is_empty() {
+                    None
+                } else {
+                    match from_utf8(name) {
+                        Ok(name) => Some(name),
+                        Err(_) => continue,
+                    }
+                };
+
+                self.handle(event.wd, event.mask, name);
+            }
+        }
+    }
+}
+
+/// Starts watching a directory, relative to the root, returning the changes.
+pub(super) fn watch(root: PathBuf, dir: &ObjectPath) -> ChangeStream {
+    let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+    if fd < 0 {
+        let error = io::Error::last_os_error().into();
+        return ChangeStream::from_stream(iter(vec![Err(error)]));
+    }
+
+    let (sender, receiver) = unbounded();
+    let mut watcher = Watcher {
+        fd,
+        root,
+        watches: HashMap::new(),
+        files: HashSet::new(),
+        sender,
+        closed: false,
+    };
+
+    if let Err(e) = watcher.add(&dir.to_string(), false) {
+        return ChangeStream::from_stream(iter(vec![Err(e.into())]));
+    }
+
+    thread::spawn(move || watcher.run());
+    ChangeStream::from_stream(receiver)
+}
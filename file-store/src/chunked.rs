@@ -0,0 +1,693 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Storing files larger than a backend allows by splitting them into chunks.
+//!
+//! A [`ChunkedStore`](struct.ChunkedStore.html) wraps a store so that files
+//! larger than a threshold are written as a number of chunks of at most that
+//! size, kept under a prefix of the store, while the file's own path holds a
+//! small manifest listing the chunks. Reading the file through the wrapped
+//! store reassembles the chunks and listings report the length of the whole
+//! file.
+//!
+//! Each chunk is stored as `<prefix>/<name>/<time>/<index>` where `<name>` is
+//! the file's path with `%` and `/` characters percent encoded and `<time>` is
+//! the number of milliseconds since the unix epoch when the write started. A
+//! manifest is a text file starting with the line `file-store chunked 1`
+//! followed by the length of the file and then the length and path of each
+//! chunk in order.
+//!
+//! Data is streamed into the chunks as it is written so memory use doesn't
+//! depend on the threshold. This means a file that turns out to fit in a
+//! single chunk is moved into place once written, unless its
+//! [`content_length`](../struct.WriteOptions.html#structfield.content_length)
+//! was given up front.
+//!
+//! Objects under the prefix are not included when listing objects outside of
+//! it. Copying or moving a chunked file reads and writes all of its data
+//! again. Deleting a directory with the file backend leaves the chunks of any
+//! chunked files inside it behind.
+use std::cmp::min;
+use std::collections::{HashMap, VecDeque};
+use std::str::from_utf8;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures::channel::mpsc;
+use futures::future::join;
+use futures::sink::SinkExt;
+use futures::stream::{iter, unfold, StreamExt, TryStreamExt};
+
+use crate::backends::Backend;
+use crate::dynamic::{self, DynamicStore};
+use crate::sync::{directory, list_files};
+use crate::trash::{contains, encode, hide};
+use crate::types::stream::LengthCheckedStream;
+use crate::types::*;
+use crate::utils::DEFAULT_WRITE_BUFFER_DEPTH;
+use crate::{FileStore, ObjectInfo, StorageBackend};
+
+const MANIFEST_HEADER: &str = "file-store chunked 1\n";
+/// Files starting with the manifest header but larger than this are not
+/// manifests.
+const MAX_MANIFEST_LEN: usize = 16 * 1024 * 1024;
+
+/// The chunks that make up a file.
+struct Manifest {
+    len: u64,
+    chunks: Vec<(u64, ObjectPath)>,
+}
+
+impl Manifest {
+    fn parse(data: &[u8]) -> StorageResult<Manifest> {
+        let text = from_utf8(data)
+            .map_err(|_| error::invalid_data(Some("The manifest is not valid UTF-8.")))?;
+        if !text.starts_with(MANIFEST_HEADER) {
+            return Err(error::invalid_data(Some(
+                "The manifest has an unknown format.",
+            )));
+        }
+
+        let mut lines = text[MANIFEST_HEADER.len()..].lines();
+        let len = lines
+            .next()
+            .and_then(|line| line.parse::<u64>().ok())
+            .ok_or_else(|| error::invalid_data(Some("The manifest has no length.")))?;
+
+        let mut chunks = Vec::new();
+        for line in lines {
+            let mut fields = line.splitn(2, ' ');
+            let chunk_len = fields.next().and_then(|f| f.parse::<u64>().ok());
+            match (chunk_len, fields.next().map(ObjectPath::new)) {
+                (Some(chunk_len), Some(Ok(path))) => chunks.push((chunk_len, path)),
+                _ => {
+                    return Err(error::invalid_data(Some(
+                        "The manifest contains an invalid chunk.",
+                    )))
+                }
+            }
+        }
+
+        if chunks.iter().map(|(chunk_len, _)| chunk_len).sum::<u64>() != len {
+            return Err(error::invalid_data(Some(
+                "The manifest's chunks do not match its length.",
+            )));
+        }
+
+        Ok(Manifest { len, chunks })
+    }
+
+    fn to_data(&self) -> Data {
+        let mut text = format!("{}{}\n", MANIFEST_HEADER, self.len);
+        for (len, path) in &self.chunks {
+            text.push_str(&format!("{} {}\n", len, path));
+        }
+        Data::from(text)
+    }
+}
+
+fn ignore_not_found<T: Default>(result: StorageResult<T>) -> StorageResult<T> {
+    match result {
+        Err(e) => match e.kind() {
+            StorageErrorKind::NotFound(_) => Ok(Default::default()),
+            _ => Err(e),
+        },
+        result => result,
+    }
+}
+
+/// Reads a data stream in pieces.
+struct Source {
+    stream: DataStream,
+    pending: Data,
+}
+
+impl Source {
+    async fn more(&mut self) -> StorageResult<bool> {
+        while self.pending.is_empty() {
+            match self.stream.next().await {
+                Some(data) => self.pending = data?,
+                None => return Ok(false),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Reads until at least `len` bytes are pending or the stream ends.
+    async fn peek(&mut self, len: usize) -> StorageResult<&[u8]> {
+        while self.pending.len() < len {
+            match self.stream.next().await {
+                Some(data) => {
+                    let mut joined = self.pending.to_vec();
+                    joined.extend_from_slice(&data?);
+                    self.pending = Data::from(joined);
+                }
+                None => break,
+            }
+        }
+        Ok(&self.pending)
+    }
+
+    /// Passes up to `len` bytes to `sender`, returning how many were passed.
+    async fn forward(
+        &mut self,
+        len: u64,
+        mut sender: mpsc::Sender<StorageResult<Data>>,
+    ) -> StorageResult<u64> {
+        let mut sent = 0;
+        while sent < len {
+            match self.more().await {
+                Ok(true) => (),
+                Ok(false) => break,
+                Err(e) => {
+                    // Make sure the partial chunk isn't kept.
+                    let _ = sender
+                        .send(Err(error::cancelled(Some("Reading the file failed."))))
+                        .await;
+                    return Err(e);
+                }
+            }
+
+            let count = min(len - sent, self.pending.len() as u64);
+            sent += count;
+            if sender
+                .send(Ok(self.pending.split_to(count as usize)))
+                .await
+                .is_err()
+            {
+                // The write has failed.
+                break;
+            }
+        }
+        Ok(sent)
+    }
+
+    fn into_stream(self) -> DataStream {
+        let pending = if self.pending.is_empty() {
+            None
+        } else {
+            Some(Ok(self.pending))
+        };
+        DataStream::from_stream(iter(pending).chain(self.stream))
+    }
+}
+
+/// Splits large files into chunks.
+///
+/// See the [`chunked`](index.html) module.
+#[derive(Clone, Debug)]
+pub struct ChunkedStore {
+    store: FileStore,
+    prefix: ObjectPath,
+    threshold: u64,
+}
+
+impl ChunkedStore {
+    /// Creates a layer that splits files larger than `threshold` bytes into
+    /// chunks kept under the given prefix of the store.
+    pub fn new(
+        store: FileStore,
+        prefix: ObjectPath,
+        threshold: u64,
+    ) -> StorageResult<ChunkedStore> {
+        let prefix = directory(prefix)?;
+        if prefix.is_empty() {
+            return Err(error::invalid_path(
+                prefix,
+                Some("Chunks cannot be kept at the root of the store."),
+            ));
+        }
+
+        if threshold == 0 {
+            return Err(error::invalid_settings(Some(
+                "The chunk threshold must be larger than zero.",
+            )));
+        }
+
+        Ok(ChunkedStore {
+            store,
+            prefix,
+            threshold,
+        })
+    }
+
+    /// Wraps the store so that files written through the returned store are
+    /// split into chunks when needed and read back whole.
+    pub fn store(&self) -> FileStore {
+        FileStore::from(DynamicStore::new(ChunkedBackend(self.clone())))
+    }
+
+    fn chunk_dir(&self, path: &ObjectPath) -> ObjectPath {
+        let mut dir = self.prefix.clone();
+        dir.push_part(&encode(path));
+        dir
+    }
+
+    fn is_chunk(&self, path: &ObjectPath) -> bool {
+        contains(&self.prefix, path)
+    }
+}
+
+/// Gets the length of the newest chunks of chunked files keyed by their
+/// encoded names. Only includes the named file if one is given.
+async fn chunked_lengths(
+    layer: &ChunkedStore,
+    name: Option<String>,
+) -> StorageResult<HashMap<String, u64>> {
+    let mut dir = layer.prefix.clone();
+    if let Some(ref name) = name {
+        dir.push_part(name);
+    }
+
+    let mut newest: HashMap<String, (u64, u64)> = HashMap::new();
+    for (relative, object) in ignore_not_found(list_files(&layer.store, &dir).await)? {
+        let mut parts: Vec<&str> = relative.split('/').collect();
+        if let Some(ref name) = name {
+            parts.insert(0, name);
+        }
+
+        if parts.len() != 3 {
+            continue;
+        }
+
+        let generation = match parts[1].parse::<u64>() {
+            Ok(generation) => generation,
+            Err(_) => continue,
+        };
+
+        let entry = newest.entry(parts[0].to_owned()).or_insert((generation, 0));
+        if generation > entry.0 {
+            *entry = (generation, 0);
+        }
+        if generation == entry.0 {
+            entry.1 += object.len();
+        }
+    }
+
+    Ok(newest
+        .into_iter()
+        .map(|(name, (_, len))| (name, len))
+        .collect())
+}
+
+/// Reports the length of the whole file for chunked files.
+fn resolve(object: Object, lengths: &HashMap<String, u64>) -> Object {
+    if object.object_type() != ObjectType::File {
+        return object;
+    }
+
+    match lengths.get(&encode(&object.path())) {
+        Some(len) => Object::from(CustomObject::new(
+            object.path(),
+            ObjectType::File,
+            *len,
+            object.modified(),
+        )),
+        None => object,
+    }
+}
+
+async fn list(
+    layer: ChunkedStore,
+    listing: ObjectStreamFuture,
+    listed: ObjectPath,
+) -> StorageResult<ObjectStream> {
+    let stream = hide(listing.await?, Some(layer.prefix.clone()), &listed);
+    let lengths = chunked_lengths(&layer, None).await?;
+    Ok(ObjectStream::from_stream(
+        stream.map_ok(move |object| resolve(object, &lengths)),
+    ))
+}
+
+async fn get_object(layer: ChunkedStore, path: ObjectPath) -> StorageResult<Object> {
+    let object = layer.store.get_object(path.clone()).await?;
+    if layer.is_chunk(&path) {
+        return Ok(object);
+    }
+
+    let lengths = chunked_lengths(&layer, Some(encode(&path))).await?;
+    Ok(resolve(object, &lengths))
+}
+
+async fn read(
+    layer: ChunkedStore,
+    path: ObjectPath,
+    options: ReadOptions,
+) -> StorageResult<DataStream> {
+    let mut source = Source {
+        stream: layer
+            .store
+            .get_file_stream_with_options(path, options.clone())
+            .await?,
+        pending: Data::new(),
+    };
+
+    if !source
+        .peek(MANIFEST_HEADER.len())
+        .await?
+        .starts_with(MANIFEST_HEADER.as_bytes())
+    {
+        return Ok(source.into_stream());
+    }
+
+    let mut data = source.pending.to_vec();
+    while let Some(chunk) = source.stream.next().await {
+        data.extend_from_slice(&chunk?);
+        if data.len() > MAX_MANIFEST_LEN {
+            return Err(error::invalid_data(Some("The manifest is too large.")));
+        }
+    }
+    let manifest = Manifest::parse(&data)?;
+
+    let chunks: VecDeque<(u64, ObjectPath)> = manifest.chunks.into_iter().collect();
+    let current: Option<LengthCheckedStream<DataStream>> = None;
+    let state = (layer.store, options, chunks, current);
+    Ok(DataStream::from_stream(unfold(
+        state,
+        |(store, options, mut chunks, mut current)| async move {
+            loop {
+                if let Some(ref mut stream) = current {
+                    if let Some(data) = StreamExt::next(stream).await {
+                        return Some((data, (store, options, chunks, current)));
+                    }
+                }
+
+                let (len, path) = chunks.pop_front()?;
+                match store
+                    .get_file_stream_with_options(path, options.clone())
+                    .await
+                {
+                    Ok(stream) => current = Some(LengthCheckedStream::new(stream, len)),
+                    Err(e) => {
+                        chunks.clear();
+                        return Some((Err(e), (store, options, chunks, None)));
+                    }
+                }
+            }
+        },
+    )))
+}
+
+/// Deletes one set of chunks.
+async fn remove_generation(store: &FileStore, dir: ObjectPath) -> StorageResult<()> {
+    if store.backend_type() == Backend::File {
+        // Deleting the directory deletes everything inside it.
+        return ignore_not_found(store.delete_object(dir).await);
+    }
+
+    for object in ignore_not_found(list_files(store, &dir).await)?.values() {
+        ignore_not_found(store.delete_object(object.path()).await)?;
+    }
+    Ok(())
+}
+
+/// Deletes the chunks of a file other than the generation given.
+async fn remove_chunks(
+    layer: &ChunkedStore,
+    path: &ObjectPath,
+    keep: Option<u64>,
+) -> StorageResult<()> {
+    let dir = layer.chunk_dir(path);
+    if keep.is_none() && layer.store.backend_type() == Backend::File {
+        return remove_generation(&layer.store, dir).await;
+    }
+
+    let keep = keep.map(|generation| generation.to_string());
+    let mut generations: Vec<String> = Vec::new();
+    for relative in ignore_not_found(list_files(&layer.store, &dir).await)?.keys() {
+        let generation = relative.split('/').next().unwrap_or_default().to_owned();
+        if Some(&generation) != keep.as_ref() && !generations.contains(&generation) {
+            generations.push(generation);
+        }
+    }
+
+    for generation in generations {
+        let mut generation_dir = dir.clone();
+        generation_dir.push_part(&generation);
+        remove_generation(&layer.store, generation_dir).await?;
+    }
+    Ok(())
+}
+
+async fn write_chunks(
+    layer: &ChunkedStore,
+    source: &mut Source,
+    dir: &ObjectPath,
+    options: &WriteOptions,
+) -> Result<Vec<(u64, ObjectPath)>, TransferError> {
+    let mut options = options.clone();
+    options.content_length = None;
+    options.mode = WriteMode::Overwrite;
+
+    let mut chunks = Vec::new();
+    loop {
+        let mut path = dir.clone();
+        path.push_part(&chunks.len().to_string());
+        let info = UploadInfo {
+            path: path.clone(),
+            modified: None,
+            options: options.clone(),
+        };
+
+        let (sender, receiver) = mpsc::channel(DEFAULT_WRITE_BUFFER_DEPTH);
+        let (written, forwarded) = join(
+            layer.store.write_file_from_stream(info, receiver),
+            source.forward(layer.threshold, sender),
+        )
+        .await;
+        let len = forwarded.map_err(TransferError::SourceError)?;
+        written?;
+        chunks.push((len, path));
+
+        if !source.more().await.map_err(TransferError::SourceError)? {
+            return Ok(chunks);
+        }
+    }
+}
+
+async fn write(
+    layer: ChunkedStore,
+    info: UploadInfo,
+    stream: DataStream,
+) -> Result<(), TransferError> {
+    let mut source = Source {
+        stream,
+        pending: Data::new(),
+    };
+
+    // Files that look like manifests are always stored as chunks so they
+    // aren't mistaken for one.
+    let looks_like_manifest = source
+        .peek(MANIFEST_HEADER.len())
+        .await
+        .map_err(TransferError::SourceError)?
+        .starts_with(MANIFEST_HEADER.as_bytes());
+
+    let fits = info
+        .options
+        .content_length
+        .map_or(false, |len| len <= layer.threshold);
+    if fits && !looks_like_manifest {
+        layer
+            .store
+            .write_file_from_stream(info.clone(), source.into_stream())
+            .await?;
+        return remove_chunks(&layer, &info.path, None)
+            .await
+            .map_err(TransferError::TargetError);
+    }
+
+    let generation = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+    let mut dir = layer.chunk_dir(&info.path);
+    dir.push_part(&generation.to_string());
+
+    let mut chunks = match write_chunks(&layer, &mut source, &dir, &info.options).await {
+        Ok(chunks) => chunks,
+        Err(e) => {
+            let _ = remove_generation(&layer.store, dir).await;
+            return Err(e);
+        }
+    };
+
+    let single = chunks.len() == 1 && !looks_like_manifest;
+    let result = if single {
+        let (_, path) = chunks.remove(0);
+        layer.store.move_file(path, info.clone()).await
+    } else {
+        let manifest = Manifest {
+            len: chunks.iter().map(|(len, _)| len).sum(),
+            chunks,
+        };
+        let mut info = info.clone();
+        info.options.content_length = None;
+        layer
+            .store
+            .write_file_from_stream(info, iter(vec![Ok::<_, StorageError>(manifest.to_data())]))
+            .await
+    };
+
+    if let Err(e) = result {
+        let _ = remove_generation(&layer.store, dir).await;
+        return Err(e);
+    }
+
+    let keep = if single { None } else { Some(generation) };
+    remove_chunks(&layer, &info.path, keep)
+        .await
+        .map_err(TransferError::TargetError)
+}
+
+async fn is_chunked(layer: &ChunkedStore, path: &ObjectPath) -> StorageResult<bool> {
+    Ok(!chunked_lengths(layer, Some(encode(path))).await?.is_empty())
+}
+
+async fn copy(
+    layer: ChunkedStore,
+    source: ObjectPath,
+    mut target: UploadInfo,
+) -> Result<(), TransferError> {
+    if !is_chunked(&layer, &source)
+        .await
+        .map_err(TransferError::SourceError)?
+    {
+        layer.store.copy_file(source, target.clone()).await?;
+        return remove_chunks(&layer, &target.path, None)
+            .await
+            .map_err(TransferError::TargetError);
+    }
+
+    if target.modified.is_none() && target.options.preserve_modified {
+        target.modified = layer
+            .store
+            .get_object(source.clone())
+            .await
+            .map_err(TransferError::SourceError)?
+            .modified();
+    }
+
+    let stream = read(layer.clone(), source, Default::default())
+        .await
+        .map_err(TransferError::SourceError)?;
+    write(layer, target, stream).await
+}
+
+async fn delete(layer: ChunkedStore, path: ObjectPath) -> StorageResult<()> {
+    layer.store.delete_object(path.clone()).await?;
+    remove_chunks(&layer, &path, None).await
+}
+
+async fn move_file(
+    layer: ChunkedStore,
+    source: ObjectPath,
+    target: UploadInfo,
+) -> Result<(), TransferError> {
+    if is_chunked(&layer, &source)
+        .await
+        .map_err(TransferError::SourceError)?
+    {
+        copy(layer.clone(), source.clone(), target).await?;
+        return delete(layer, source)
+            .await
+            .map_err(TransferError::SourceError);
+    }
+
+    layer.store.move_file(source, target.clone()).await?;
+    remove_chunks(&layer, &target.path, None)
+        .await
+        .map_err(TransferError::TargetError)
+}
+
+/// The backend of the store returned by
+/// [`ChunkedStore::store`](struct.ChunkedStore.html#method.store).
+struct ChunkedBackend(ChunkedStore);
+
+// Only StorageBackend is in scope so calls on the wrapped store are not
+// ambiguous.
+impl dynamic::DynamicBackend for ChunkedBackend {
+    fn backend_type(&self) -> Backend {
+        self.0.store.backend_type()
+    }
+
+    fn authorize(&self) -> OperationCompleteFuture {
+        self.0.store.authorize()
+    }
+
+    fn validate_path(&self, path: &ObjectPath) -> StorageResult<()> {
+        self.0.store.validate_path(path)
+    }
+
+    fn list_objects(&self, prefix: ObjectPath) -> ObjectStreamFuture {
+        let listing = self.0.store.list_objects(prefix.clone());
+        ObjectStreamFuture::from_future(list(self.0.clone(), listing, prefix))
+    }
+
+    fn list_directory(&self, dir: ObjectPath) -> ObjectStreamFuture {
+        let listing = self.0.store.list_directory(dir.clone());
+        ObjectStreamFuture::from_future(list(self.0.clone(), listing, dir))
+    }
+
+    fn get_object(&self, path: ObjectPath) -> ObjectFuture {
+        ObjectFuture::from_future(get_object(self.0.clone(), path))
+    }
+
+    fn get_file_stream(&self, path: ObjectPath) -> DataStreamFuture {
+        self.get_file_stream_with_options(path, Default::default())
+    }
+
+    fn get_file_stream_with_options(
+        &self,
+        path: ObjectPath,
+        options: ReadOptions,
+    ) -> DataStreamFuture {
+        if self.0.is_chunk(&path) {
+            return self.0.store.get_file_stream_with_options(path, options);
+        }
+
+        DataStreamFuture::from_future(read(self.0.clone(), path, options))
+    }
+
+    fn copy_file(&self, source: ObjectPath, target: UploadInfo) -> CopyCompleteFuture {
+        if self.0.is_chunk(&source) || self.0.is_chunk(&target.path) {
+            return self.0.store.copy_file(source, target);
+        }
+
+        CopyCompleteFuture::from_future(copy(self.0.clone(), source, target))
+    }
+
+    fn move_file(&self, source: ObjectPath, target: UploadInfo) -> MoveCompleteFuture {
+        if self.0.is_chunk(&source) || self.0.is_chunk(&target.path) {
+            return self.0.store.move_file(source, target);
+        }
+
+        MoveCompleteFuture::from_future(move_file(self.0.clone(), source, target))
+    }
+
+    fn delete_object(&self, path: ObjectPath) -> OperationCompleteFuture {
+        if self.0.is_chunk(&path) {
+            return self.0.store.delete_object(path);
+        }
+
+        OperationCompleteFuture::from_future(delete(self.0.clone(), path))
+    }
+
+    fn write_file_from_stream(&self, info: UploadInfo, stream: DataStream) -> WriteCompleteFuture {
+        if self.0.is_chunk(&info.path) {
+            return self.0.store.write_file_from_stream(info, stream);
+        }
+
+        WriteCompleteFuture::from_future(write(self.0.clone(), info, stream))
+    }
+}
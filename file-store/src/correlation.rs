@@ -0,0 +1,180 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Correlating everything done for a single call to a store.
+//!
+//! Every backend operation has an [`OperationId`](struct.OperationId.html).
+//! It is included in the operation's `tracing` span with the "tracing"
+//! feature, in the [`OperationInfo`](../observe/struct.OperationInfo.html)
+//! given to observers and in any
+//! [`StorageError`](../struct.StorageError.html) created while the operation
+//! runs, so the many requests made by something like a large upload can be
+//! traced together.
+//!
+//! Operations are started when the store's method is called and use the
+//! current id, if there is one, or otherwise generate a new one. Use
+//! [`OperationId::scope`](struct.OperationId.html#method.scope) to start
+//! operations with an id of your own, for example one taken from an incoming
+//! request, or [`with_operation_id`](fn.with_operation_id.html) to use one for
+//! everything an async block does:
+//!
+//! ```no_run
+//! # use file_store::{FileStore, StorageBackend};
+//! # use file_store::correlation::{with_operation_id, OperationId};
+//! # async fn example(store: FileStore) {
+//! let id = OperationId::new(0x1234);
+//! let result = id.scope(|| store.get_object("file.txt")).await;
+//! if let Err(e) = result {
+//!     println!("Operation {:?} failed: {}", e.operation_id(), e);
+//! }
+//!
+//! with_operation_id(id, async {
+//!     store.delete_object("old.txt").await?;
+//!     store.delete_object("older.txt").await
+//! })
+//! .await
+//! .unwrap();
+//! # }
+//! ```
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+
+use futures::stream::Stream;
+
+thread_local! {
+    static CURRENT: Cell<Option<OperationId>> = Cell::new(None);
+}
+
+static NEXT: AtomicU64 = AtomicU64::new(0);
+
+/// Identifies a call to a store.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct OperationId(u64);
+
+impl OperationId {
+    /// Creates an id with the given value.
+    pub fn new(id: u64) -> OperationId {
+        OperationId(id)
+    }
+
+    /// Generates a new id. Ids are random so are unlikely to be repeated even
+    /// by different processes.
+    pub fn generate() -> OperationId {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(NEXT.fetch_add(1, Ordering::Relaxed));
+        OperationId(hasher.finish())
+    }
+
+    /// The id's value.
+    pub fn value(self) -> u64 {
+        self.0
+    }
+
+    /// The id that operations started on this thread right now would use.
+    pub fn current() -> Option<OperationId> {
+        CURRENT.with(Cell::get)
+    }
+
+    /// Runs the function with this as the current id.
+    pub fn scope<F, R>(self, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        struct Reset(Option<OperationId>);
+
+        impl Drop for Reset {
+            fn drop(&mut self) {
+                CURRENT.with(|current| current.set(self.0));
+            }
+        }
+
+        let _reset = Reset(CURRENT.with(|current| current.replace(Some(self))));
+        f()
+    }
+}
+
+impl fmt::Display for OperationId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+/// A future or stream that is polled with an
+/// [`OperationId`](struct.OperationId.html) as the current id.
+///
+/// Returned by [`with_operation_id`](fn.with_operation_id.html).
+pub struct WithOperationId<T> {
+    inner: Pin<Box<T>>,
+    id: OperationId,
+}
+
+impl<T> WithOperationId<T> {
+    /// The id used.
+    pub fn operation_id(&self) -> OperationId {
+        self.id
+    }
+}
+
+impl<T> fmt::Debug for WithOperationId<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WithOperationId")
+            .field("id", &self.id)
+            .finish()
+    }
+}
+
+impl<T> Future for WithOperationId<T>
+where
+    T: Future,
+{
+    type Output = T::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<T::Output> {
+        let this = self.get_mut();
+        let inner = &mut this.inner;
+        this.id.scope(|| inner.as_mut().poll(cx))
+    }
+}
+
+impl<T> Stream for WithOperationId<T>
+where
+    T: Stream,
+{
+    type Item = T::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T::Item>> {
+        let this = self.get_mut();
+        let inner = &mut this.inner;
+        this.id.scope(|| inner.as_mut().poll_next(cx))
+    }
+}
+
+/// Polls a future or stream with the given id as the current id, so every
+/// operation it starts uses it.
+///
+/// Operations already started when creating the future passed in do not use
+/// the id, create it inside
+/// [`OperationId::scope`](struct.OperationId.html#method.scope) for that.
+pub fn with_operation_id<T>(id: OperationId, inner: T) -> WithOperationId<T> {
+    WithOperationId {
+        inner: Box::pin(inner),
+        id,
+    }
+}
@@ -0,0 +1,271 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Decompressing files as they are read.
+//!
+//! [`FileStore::decompress_reads`](../enum.FileStore.html#method.decompress_reads)
+//! wraps a store so that gzip and zstd compressed files read through it are
+//! decompressed on the fly, letting consumers see plain data however the files
+//! were stored:
+//!
+//! ```no_run
+//! # use file_store::{FileStore, StorageBackend};
+//! # async fn example(store: FileStore) {
+//! let logs = store.decompress_reads();
+//! // Whether stored as app.log, app.log.gz or app.log.zst.
+//! let stream = logs.get_file_stream("app.log.gz").await.unwrap();
+//! # }
+//! ```
+//!
+//! The compression is found from the file's extension, `.gz` for gzip and
+//! `.zst` or `.zstd` for zstd. None of the backends record a content encoding
+//! so files with any other extension are checked for the magic bytes that
+//! start compressed data instead. Files that aren't compressed are passed
+//! through untouched.
+//!
+//! [`decompress_stream`](fn.decompress_stream.html) decompresses a single
+//! stream of data when the compression is already known.
+//!
+//! Decompression happens as data arrives so the whole file is never held in
+//! memory. The lengths reported for files are those of the stored data.
+//!
+//! Included with the "decompress" feature.
+use std::io::{self, Write};
+use std::mem;
+
+use futures::stream::{iter, unfold, StreamExt};
+
+use crate::types::*;
+use crate::FileStore;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// The compression formats that can be decompressed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// gzip.
+    Gzip,
+    /// Zstandard.
+    Zstd,
+}
+
+impl Compression {
+    /// Finds the compression from a file's extension.
+    pub fn from_path(path: &ObjectPath) -> Option<Compression> {
+        let name = path.parts().last()?.to_lowercase();
+        if name.ends_with(".gz") {
+            Some(Compression::Gzip)
+        } else if name.ends_with(".zst") || name.ends_with(".zstd") {
+            Some(Compression::Zstd)
+        } else {
+            None
+        }
+    }
+
+    /// Finds the compression from the magic bytes at the start of a file's
+    /// data.
+    pub fn from_magic(data: &[u8]) -> Option<Compression> {
+        if data.starts_with(GZIP_MAGIC) {
+            Some(Compression::Gzip)
+        } else if data.starts_with(ZSTD_MAGIC) {
+            Some(Compression::Zstd)
+        } else {
+            None
+        }
+    }
+}
+
+enum Decoder {
+    Gzip(flate2::write::GzDecoder<Vec<u8>>),
+    Zstd(zstd::stream::write::Decoder<Vec<u8>>),
+}
+
+impl Decoder {
+    fn new(compression: Compression) -> io::Result<Decoder> {
+        Ok(match compression {
+            Compression::Gzip => Decoder::Gzip(flate2::write::GzDecoder::new(Vec::new())),
+            Compression::Zstd => Decoder::Zstd(zstd::stream::write::Decoder::new(Vec::new())?),
+        })
+    }
+
+    /// Decompresses some data returning whatever output is ready.
+    fn decode(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(match self {
+            Decoder::Gzip(decoder) => {
+                decoder.write_all(data)?;
+                decoder.flush()?;
+                decoder.get_mut().split_off(0)
+            }
+            Decoder::Zstd(decoder) => {
+                decoder.write_all(data)?;
+                decoder.flush()?;
+                decoder.get_mut().split_off(0)
+            }
+        })
+    }
+
+    /// Returns the remaining output, failing if the data was incomplete.
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Decoder::Gzip(decoder) => decoder.finish(),
+            Decoder::Zstd(mut decoder) => {
+                decoder.flush()?;
+                Ok(decoder.into_inner())
+            }
+        }
+    }
+}
+
+enum State {
+    /// Waiting for enough data to look for magic bytes.
+    Detect(Vec<u8>),
+    Plain,
+    Decoding(Decoder),
+    Done,
+}
+
+fn decode_error(e: io::Error) -> StorageError {
+    error::invalid_data(Some(&format!("The compressed data is not valid: {}", e)))
+}
+
+/// Puts the decoder back after decompressing some data, returning the output
+/// if there was any.
+fn decoded(
+    state: &mut State,
+    decoder: Decoder,
+    result: io::Result<Vec<u8>>,
+) -> Option<StorageResult<Data>> {
+    match result {
+        Ok(ref output) if output.is_empty() => {
+            *state = State::Decoding(decoder);
+            None
+        }
+        Ok(output) => {
+            *state = State::Decoding(decoder);
+            Some(Ok(Data::from(output)))
+        }
+        Err(e) => Some(Err(decode_error(e))),
+    }
+}
+
+/// Steps the decompression, returning the next chunk of output or `None` once
+/// there is nothing more to return.
+async fn next(stream: &mut DataStream, state: &mut State) -> Option<StorageResult<Data>> {
+    loop {
+        let data = match stream.next().await {
+            Some(Ok(data)) => Some(data),
+            Some(Err(e)) => {
+                *state = State::Done;
+                return Some(Err(e));
+            }
+            None => None,
+        };
+
+        match (mem::replace(state, State::Done), data) {
+            (State::Done, _) | (State::Plain, None) => return None,
+            (State::Plain, Some(data)) => {
+                *state = State::Plain;
+                return Some(Ok(data));
+            }
+            (State::Detect(mut buffer), Some(data)) => {
+                buffer.extend_from_slice(&data);
+                if buffer.len() < ZSTD_MAGIC.len() {
+                    *state = State::Detect(buffer);
+                    continue;
+                }
+
+                let compression = match Compression::from_magic(&buffer) {
+                    Some(compression) => compression,
+                    None => {
+                        *state = State::Plain;
+                        return Some(Ok(Data::from(buffer)));
+                    }
+                };
+                let mut decoder = match Decoder::new(compression) {
+                    Ok(decoder) => decoder,
+                    Err(e) => return Some(Err(decode_error(e))),
+                };
+                let result = decoder.decode(&buffer);
+                if let Some(output) = decoded(state, decoder, result) {
+                    return Some(output);
+                }
+            }
+            // Too short to be compressed.
+            (State::Detect(buffer), None) => {
+                if buffer.is_empty() {
+                    return None;
+                }
+                return Some(Ok(Data::from(buffer)));
+            }
+            (State::Decoding(mut decoder), Some(data)) => {
+                let result = decoder.decode(&data);
+                if let Some(output) = decoded(state, decoder, result) {
+                    return Some(output);
+                }
+            }
+            (State::Decoding(decoder), None) => match decoder.finish() {
+                Ok(ref output) if output.is_empty() => return None,
+                Ok(output) => return Some(Ok(Data::from(output))),
+                Err(e) => return Some(Err(decode_error(e))),
+            },
+        }
+    }
+}
+
+fn decode_stream(stream: DataStream, state: State) -> DataStream {
+    DataStream::from_stream(unfold(
+        (stream, state),
+        |(mut stream, mut state)| async move {
+            let data = next(&mut stream, &mut state).await?;
+            Some((data, (stream, state)))
+        },
+    ))
+}
+
+fn failed(e: io::Error) -> DataStream {
+    DataStream::from_stream(iter(vec![Err(decode_error(e))]))
+}
+
+/// Decompresses a stream of data compressed with the given format.
+///
+/// The stream fails with an `InvalidData` error if the data is not valid.
+pub fn decompress_stream(stream: DataStream, compression: Compression) -> DataStream {
+    match Decoder::new(compression) {
+        Ok(decoder) => decode_stream(stream, State::Decoding(decoder)),
+        Err(e) => failed(e),
+    }
+}
+
+/// Decompresses the data of the file at `path` if its extension or content
+/// shows that it is compressed, otherwise passes the data through.
+///
+/// This can be used as a [`Transform`](../transform/trait.Transform.html).
+pub fn decompress_file(path: &ObjectPath, stream: DataStream) -> DataStream {
+    match Compression::from_path(path).map(Decoder::new) {
+        Some(Ok(decoder)) => decode_stream(stream, State::Decoding(decoder)),
+        Some(Err(e)) => failed(e),
+        None => decode_stream(stream, State::Detect(Vec::new())),
+    }
+}
+
+impl FileStore {
+    /// Wraps this store so that compressed files read through the returned
+    /// store are decompressed.
+    ///
+    /// See the [`decompress`](decompress/index.html) module.
+    pub fn decompress_reads(self) -> FileStore {
+        self.transform_reads(decompress_file)
+    }
+}
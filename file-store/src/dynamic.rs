@@ -0,0 +1,457 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Support for using storage backends as trait objects.
+//!
+//! [`StorageBackend`](../trait.StorageBackend.html) uses generic methods so
+//! that callers can pass anything convertible to a path. That makes it
+//! impossible to use as a trait object. [`DynamicBackend`](trait.DynamicBackend.html)
+//! offers the same functionality using only concrete types so it can be used as
+//! `Box<dyn DynamicBackend>` or `Arc<dyn DynamicBackend>`.
+//!
+//! Every [`StorageBackend`](../trait.StorageBackend.html) that is also `Sync`
+//! implements `DynamicBackend` automatically. Going the other way,
+//! [`DynamicStore`](struct.DynamicStore.html) wraps any `DynamicBackend` and
+//! can be turned into a [`FileStore`](../enum.FileStore.html). This allows
+//! third-party crates to provide their own backends.
+//!
+//! Both traits have methods of the same names so avoid bringing both into
+//! scope at once, otherwise method calls become ambiguous.
+use std::convert::TryInto;
+use std::fmt;
+use std::ops::Range;
+use std::sync::Arc;
+
+use bytes::IntoBuf;
+use futures::future::TryFutureExt;
+use futures::stream::Stream;
+
+use crate::backends::Backend;
+use crate::types::stream::{compose_streams, directories_only, range_stream};
+use crate::types::*;
+use crate::utils::into_data_stream;
+use crate::{ObjectInfo, StorageBackend};
+
+/// An object safe version of [`StorageBackend`](../trait.StorageBackend.html).
+///
+/// The methods here behave exactly the same as their equivalents in
+/// [`StorageBackend`](../trait.StorageBackend.html), they just take concrete
+/// types as arguments.
+pub trait DynamicBackend: Send + Sync + 'static {
+    /// Retrieves the type of this backend.
+    fn backend_type(&self) -> Backend;
+
+    /// Authenticates with the backend if it has not already done so.
+    fn authorize(&self) -> OperationCompleteFuture {
+        OperationCompleteFuture::from_value(Ok(()))
+    }
+
+    /// Checks that the backend could store an object at the given path.
+    fn validate_path(&self, path: &ObjectPath) -> StorageResult<()> {
+        let _ = path;
+        Ok(())
+    }
+
+    /// Lists the objects that are prefixed by the given prefix.
+    fn list_objects(&self, prefix: ObjectPath) -> ObjectStreamFuture;
+
+    /// Lists the objects that exist in the given (possibly virtual) directory.
+    fn list_directory(&self, dir: ObjectPath) -> ObjectStreamFuture;
+
+    /// Lists just the directories in the given (possibly virtual) directory.
+    fn list_prefixes(&self, dir: ObjectPath) -> ObjectStreamFuture {
+        directories_only(self.list_directory(dir))
+    }
+
+    /// Gets info about the object at the given path.
+    fn get_object(&self, path: ObjectPath) -> ObjectFuture;
+
+    /// Gets a stream of data for the file at the given path.
+    fn get_file_stream(&self, path: ObjectPath) -> DataStreamFuture;
+
+    /// Gets a stream of data for the file at the given path using the given
+    /// options.
+    fn get_file_stream_with_options(
+        &self,
+        path: ObjectPath,
+        options: ReadOptions,
+    ) -> DataStreamFuture {
+        let _ = options;
+        self.get_file_stream(path)
+    }
+
+    /// Gets a stream of the bytes within `range` of a file that was returned
+    /// by this backend.
+    fn get_object_range(&self, object: &Object, range: Range<u64>) -> DataStreamFuture {
+        range_stream(self.get_file_stream(object.path()), range)
+    }
+
+    /// Copies a file from one path to another within this backend.
+    fn copy_file(&self, source: ObjectPath, target: UploadInfo) -> CopyCompleteFuture;
+
+    /// Moves a file from one path to another within this backend.
+    fn move_file(&self, source: ObjectPath, target: UploadInfo) -> MoveCompleteFuture;
+
+    /// Concatenates the files at the source paths, in order, into the target
+    /// file. A source that is also the target is opened before the target is
+    /// written.
+    fn compose(&self, sources: Vec<ObjectPath>, target: UploadInfo) -> CopyCompleteFuture {
+        compose_streams(
+            sources,
+            target,
+            |path| self.get_file_stream(path),
+            |info, stream| self.write_file_from_stream(info, stream),
+        )
+    }
+
+    /// Deletes the object at the given path.
+    fn delete_object(&self, path: ObjectPath) -> OperationCompleteFuture;
+
+    /// Requests that an archived object is restored so that it can be read
+    /// for the given number of days.
+    fn restore_object(&self, path: ObjectPath, days: u32) -> OperationCompleteFuture {
+        let _ = days;
+        OperationCompleteFuture::from_future(self.get_object(path).map_ok(|_| ()))
+    }
+
+    /// Writes a stream of data to the file at the given path.
+    fn write_file_from_stream(&self, info: UploadInfo, stream: DataStream) -> WriteCompleteFuture;
+}
+
+impl<B> DynamicBackend for B
+where
+    B: StorageBackend + Sync,
+{
+    fn backend_type(&self) -> Backend {
+        StorageBackend::backend_type(self)
+    }
+
+    fn authorize(&self) -> OperationCompleteFuture {
+        StorageBackend::authorize(self)
+    }
+
+    fn validate_path(&self, path: &ObjectPath) -> StorageResult<()> {
+        StorageBackend::validate_path(self, path)
+    }
+
+    fn list_objects(&self, prefix: ObjectPath) -> ObjectStreamFuture {
+        StorageBackend::list_objects(self, prefix)
+    }
+
+    fn list_directory(&self, dir: ObjectPath) -> ObjectStreamFuture {
+        StorageBackend::list_directory(self, dir)
+    }
+
+    fn list_prefixes(&self, dir: ObjectPath) -> ObjectStreamFuture {
+        StorageBackend::list_prefixes(self, dir)
+    }
+
+    fn get_object(&self, path: ObjectPath) -> ObjectFuture {
+        StorageBackend::get_object(self, path)
+    }
+
+    fn get_file_stream(&self, path: ObjectPath) -> DataStreamFuture {
+        StorageBackend::get_file_stream(self, path)
+    }
+
+    fn get_file_stream_with_options(
+        &self,
+        path: ObjectPath,
+        options: ReadOptions,
+    ) -> DataStreamFuture {
+        StorageBackend::get_file_stream_with_options(self, path, options)
+    }
+
+    fn get_object_range(&self, object: &Object, range: Range<u64>) -> DataStreamFuture {
+        StorageBackend::get_object_range(self, object, range)
+    }
+
+    fn copy_file(&self, source: ObjectPath, target: UploadInfo) -> CopyCompleteFuture {
+        StorageBackend::copy_file(self, source, target)
+    }
+
+    fn move_file(&self, source: ObjectPath, target: UploadInfo) -> MoveCompleteFuture {
+        StorageBackend::move_file(self, source, target)
+    }
+
+    fn compose(&self, sources: Vec<ObjectPath>, target: UploadInfo) -> CopyCompleteFuture {
+        StorageBackend::compose(self, sources, target)
+    }
+
+    fn delete_object(&self, path: ObjectPath) -> OperationCompleteFuture {
+        StorageBackend::delete_object(self, path)
+    }
+
+    fn restore_object(&self, path: ObjectPath, days: u32) -> OperationCompleteFuture {
+        StorageBackend::restore_object(self, path, days)
+    }
+
+    fn write_file_from_stream(&self, info: UploadInfo, stream: DataStream) -> WriteCompleteFuture {
+        StorageBackend::write_file_from_stream(self, info, stream)
+    }
+}
+
+/// Wraps a [`DynamicBackend`](trait.DynamicBackend.html) trait object so it
+/// can be used as a [`StorageBackend`](../trait.StorageBackend.html).
+///
+/// Use `FileStore::from` to create a [`FileStore`](../enum.FileStore.html)
+/// from this.
+#[derive(Clone)]
+pub struct DynamicStore {
+    backend: Arc<dyn DynamicBackend>,
+}
+
+impl DynamicStore {
+    /// Wraps the given backend.
+    pub fn new<B>(backend: B) -> DynamicStore
+    where
+        B: DynamicBackend,
+    {
+        DynamicStore {
+            backend: Arc::new(backend),
+        }
+    }
+
+    /// Gets the wrapped backend.
+    pub fn backend(&self) -> &Arc<dyn DynamicBackend> {
+        &self.backend
+    }
+
+    /// Converts the target of a write and checks that the backend could store
+    /// it before any work is done.
+    fn target<I>(&self, target: I) -> StorageResult<UploadInfo>
+    where
+        I: TryInto<UploadInfo>,
+        I::Error: Into<StorageError>,
+    {
+        let info = target.try_into().map_err(Into::into)?;
+        self.backend.validate_path(&info.path)?;
+        Ok(info)
+    }
+}
+
+impl From<Arc<dyn DynamicBackend>> for DynamicStore {
+    fn from(backend: Arc<dyn DynamicBackend>) -> DynamicStore {
+        DynamicStore { backend }
+    }
+}
+
+impl From<Box<dyn DynamicBackend>> for DynamicStore {
+    fn from(backend: Box<dyn DynamicBackend>) -> DynamicStore {
+        DynamicStore {
+            backend: backend.into(),
+        }
+    }
+}
+
+impl fmt::Debug for DynamicStore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DynamicStore")
+            .field("backend", &self.backend.backend_type())
+            .finish()
+    }
+}
+
+impl StorageBackend for DynamicStore {
+    fn backend_type(&self) -> Backend {
+        self.backend.backend_type()
+    }
+
+    fn authorize(&self) -> OperationCompleteFuture {
+        self.backend.authorize()
+    }
+
+    fn validate_path(&self, path: &ObjectPath) -> StorageResult<()> {
+        self.backend.validate_path(path)
+    }
+
+    fn list_objects<P>(&self, prefix: P) -> ObjectStreamFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        match prefix.try_into() {
+            Ok(p) => self.backend.list_objects(p),
+            Err(e) => ObjectStreamFuture::from_value(Err(e.into())),
+        }
+    }
+
+    fn list_directory<P>(&self, dir: P) -> ObjectStreamFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        match dir.try_into() {
+            Ok(p) => self.backend.list_directory(p),
+            Err(e) => ObjectStreamFuture::from_value(Err(e.into())),
+        }
+    }
+
+    fn list_prefixes<P>(&self, dir: P) -> ObjectStreamFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        match dir.try_into() {
+            Ok(p) => self.backend.list_prefixes(p),
+            Err(e) => ObjectStreamFuture::from_value(Err(e.into())),
+        }
+    }
+
+    fn get_object<P>(&self, path: P) -> ObjectFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        match path.try_into() {
+            Ok(p) => self.backend.get_object(p),
+            Err(e) => ObjectFuture::from_value(Err(e.into())),
+        }
+    }
+
+    fn get_file_stream<P>(&self, path: P) -> DataStreamFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        match path.try_into() {
+            Ok(p) => self.backend.get_file_stream(p),
+            Err(e) => DataStreamFuture::from_value(Err(e.into())),
+        }
+    }
+
+    fn get_file_stream_with_options<P>(&self, path: P, options: ReadOptions) -> DataStreamFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        match path.try_into() {
+            Ok(p) => self.backend.get_file_stream_with_options(p, options),
+            Err(e) => DataStreamFuture::from_value(Err(e.into())),
+        }
+    }
+
+    fn get_object_range(&self, object: &Object, range: Range<u64>) -> DataStreamFuture {
+        self.backend.get_object_range(object, range)
+    }
+
+    fn copy_file<P, I>(&self, source: P, target: I) -> CopyCompleteFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+        I: TryInto<UploadInfo>,
+        I::Error: Into<StorageError>,
+    {
+        let source = match source.try_into() {
+            Ok(p) => p,
+            Err(e) => {
+                return CopyCompleteFuture::from_value(Err(TransferError::SourceError(e.into())))
+            }
+        };
+
+        let target = match self.target(target) {
+            Ok(i) => i,
+            Err(e) => return CopyCompleteFuture::from_value(Err(TransferError::TargetError(e))),
+        };
+
+        self.backend.copy_file(source, target)
+    }
+
+    fn move_file<P, I>(&self, source: P, target: I) -> MoveCompleteFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+        I: TryInto<UploadInfo>,
+        I::Error: Into<StorageError>,
+    {
+        let source = match source.try_into() {
+            Ok(p) => p,
+            Err(e) => {
+                return MoveCompleteFuture::from_value(Err(TransferError::SourceError(e.into())))
+            }
+        };
+
+        let target = match self.target(target) {
+            Ok(i) => i,
+            Err(e) => return MoveCompleteFuture::from_value(Err(TransferError::TargetError(e))),
+        };
+
+        self.backend.move_file(source, target)
+    }
+
+    fn compose<S, P, I>(&self, sources: S, target: I) -> CopyCompleteFuture
+    where
+        S: IntoIterator<Item = P>,
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+        I: TryInto<UploadInfo>,
+        I::Error: Into<StorageError>,
+    {
+        let mut paths = Vec::new();
+        for source in sources {
+            match source.try_into() {
+                Ok(p) => paths.push(p),
+                Err(e) => {
+                    return CopyCompleteFuture::from_value(Err(TransferError::SourceError(
+                        e.into(),
+                    )))
+                }
+            }
+        }
+
+        match self.target(target) {
+            Ok(i) => self.backend.compose(paths, i),
+            Err(e) => CopyCompleteFuture::from_value(Err(TransferError::TargetError(e))),
+        }
+    }
+
+    fn delete_object<P>(&self, path: P) -> OperationCompleteFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        match path.try_into() {
+            Ok(p) => self.backend.delete_object(p),
+            Err(e) => OperationCompleteFuture::from_value(Err(e.into())),
+        }
+    }
+
+    fn restore_object<P>(&self, path: P, days: u32) -> OperationCompleteFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        match path.try_into() {
+            Ok(p) => self.backend.restore_object(p, days),
+            Err(e) => OperationCompleteFuture::from_value(Err(e.into())),
+        }
+    }
+
+    fn write_file_from_stream<S, I, E, P>(&self, info: P, stream: S) -> WriteCompleteFuture
+    where
+        S: Stream<Item = Result<I, E>> + Send + 'static,
+        I: IntoBuf + 'static,
+        E: Into<StorageError> + 'static,
+        P: TryInto<UploadInfo>,
+        P::Error: Into<StorageError>,
+    {
+        let info = match self.target(info) {
+            Ok(i) => i,
+            Err(e) => return WriteCompleteFuture::from_value(Err(TransferError::TargetError(e))),
+        };
+
+        let stream = DataStream::from_stream(into_data_stream(stream));
+        self.backend.write_file_from_stream(info, stream)
+    }
+}
@@ -0,0 +1,132 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Deleting files once they reach a certain age.
+//!
+//! Backends like the file backend have no way to expire old files on their
+//! own. A [`Sweeper`](struct.Sweeper.html) holds a set of rules, each
+//! deleting files under a prefix once their modification time is older than
+//! a maximum age, and applies them either once with
+//! [`sweep`](struct.Sweeper.html#method.sweep) or repeatedly with
+//! [`schedule`](struct.Sweeper.html#method.schedule).
+//!
+//! Files without a modification time never expire. Directories left empty by
+//! the file backend are not removed.
+use std::time::{Duration, SystemTime};
+
+use futures::stream::unfold;
+use tokio_timer::delay_for;
+
+use crate::sync::{directory, list_files};
+use crate::types::*;
+use crate::{FileStore, ObjectInfo, StorageBackend};
+
+/// Future returned by [`Sweeper::sweep`](struct.Sweeper.html#method.sweep).
+pub type SweepFuture = WrappedFuture<StorageResult<Vec<ObjectPath>>>;
+/// Stream returned by [`Sweeper::schedule`](struct.Sweeper.html#method.schedule).
+pub type SweepStream = WrappedStream<StorageResult<Vec<ObjectPath>>>;
+
+#[derive(Clone, Debug)]
+struct Rule {
+    prefix: ObjectPath,
+    max_age: Duration,
+}
+
+/// Deletes old files from a store.
+///
+/// See the [`expire`](index.html) module.
+#[derive(Clone, Debug)]
+pub struct Sweeper {
+    store: FileStore,
+    rules: Vec<Rule>,
+}
+
+impl Sweeper {
+    /// Creates a sweeper for the store with no rules.
+    pub fn new(store: FileStore) -> Sweeper {
+        Sweeper {
+            store,
+            rules: Vec::new(),
+        }
+    }
+
+    /// Adds a rule deleting files under `prefix` that were last modified more
+    /// than `max_age` ago. Prefixes are treated as directories and a file
+    /// matching more than one rule is deleted if any of them says it has
+    /// expired.
+    pub fn expire(mut self, prefix: ObjectPath, max_age: Duration) -> Sweeper {
+        self.rules.push(Rule { prefix, max_age });
+        self
+    }
+
+    /// Deletes every file that has expired, resolving to the paths deleted.
+    pub fn sweep(&self) -> SweepFuture {
+        SweepFuture::from_future(sweep(self.clone(), SystemTime::now()))
+    }
+
+    /// Sweeps immediately and then again every `interval` for as long as the
+    /// returned stream is polled. Each item is the result of one sweep, a
+    /// failed sweep doesn't stop later ones.
+    pub fn schedule(&self, interval: Duration) -> SweepStream {
+        SweepStream::from_stream(unfold(
+            (self.clone(), true),
+            move |(sweeper, first)| async move {
+                if !first {
+                    delay_for(interval).await;
+                }
+
+                let result = sweep(sweeper.clone(), SystemTime::now()).await;
+                Some((result, (sweeper, false)))
+            },
+        ))
+    }
+}
+
+async fn sweep(sweeper: Sweeper, now: SystemTime) -> StorageResult<Vec<ObjectPath>> {
+    let mut expired = Vec::new();
+    for rule in &sweeper.rules {
+        let cutoff = match now.checked_sub(rule.max_age) {
+            Some(cutoff) => cutoff,
+            None => continue,
+        };
+
+        let prefix = directory(rule.prefix.clone())?;
+        for object in list_files(&sweeper.store, &prefix).await?.values() {
+            if object.modified().map_or(false, |m| m < cutoff) {
+                expired.push(object.path());
+            }
+        }
+    }
+    expired.sort();
+    expired.dedup();
+
+    let mut deleted = Vec::new();
+    for path in expired {
+        match sweeper.store.delete_object(path.clone()).await {
+            Ok(()) => deleted.push(path),
+            // Something else got to it first.
+            Err(ref e) if is_not_found(e) => (),
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(deleted)
+}
+
+fn is_not_found(error: &StorageError) -> bool {
+    match error.kind() {
+        StorageErrorKind::NotFound(_) => true,
+        _ => false,
+    }
+}
@@ -0,0 +1,213 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Working with a single object through a handle.
+//!
+//! [`FileStore::object`](../enum.FileStore.html#method.object) returns an
+//! [`ObjectHandle`](struct.ObjectHandle.html) that remembers the store and
+//! path so they do not need to be passed to every operation:
+//!
+//! ```no_run
+//! # use file_store::{FileStore, ObjectInfo};
+//! # async fn example(store: FileStore) {
+//! let file = store.object("dir/file.txt").unwrap();
+//! file.write("Hello").await.unwrap();
+//! println!("{} bytes", file.metadata().await.unwrap().len());
+//! let start = file.read_range(0..2).await.unwrap();
+//! # }
+//! ```
+//!
+//! A handle can also be created for an object that has already been fetched
+//! or listed with [`FileStore::handle_for`](../enum.FileStore.html#method.handle_for).
+//! Ranges are then read using the object's metadata, which saves looking the
+//! file up again on backends like B2.
+//!
+//! [`ObjectHandle::read_parallel`](struct.ObjectHandle.html#method.read_parallel)
+//! reads several ranges of a file at once, returning each with its offset.
+use std::convert::TryInto;
+use std::ops::Range;
+
+use bytes::BytesMut;
+use futures::stream::{iter, StreamExt};
+
+use crate::types::stream::range_stream;
+use crate::types::*;
+use crate::{FileStore, ObjectInfo, StorageBackend};
+
+/// A handle to the object at a path in a store.
+///
+/// Creating a handle does not check that the object exists.
+#[derive(Clone, Debug)]
+pub struct ObjectHandle {
+    store: FileStore,
+    path: ObjectPath,
+    object: Option<Object>,
+}
+
+impl ObjectHandle {
+    /// The store the object is in.
+    pub fn store(&self) -> &FileStore {
+        &self.store
+    }
+
+    /// The path of the object.
+    pub fn path(&self) -> &ObjectPath {
+        &self.path
+    }
+
+    /// The information about the object the handle was created from, if it
+    /// was created with
+    /// [`FileStore::handle_for`](../enum.FileStore.html#method.handle_for).
+    /// This may be out of date.
+    pub fn object(&self) -> Option<&Object> {
+        self.object.as_ref()
+    }
+
+    /// Gets information about the object.
+    pub fn metadata(&self) -> ObjectFuture {
+        self.store.get_object(self.path.clone())
+    }
+
+    /// Reads the file's data.
+    pub fn read(&self) -> DataStreamFuture {
+        self.store.get_file_stream(self.path.clone())
+    }
+
+    /// Reads the bytes of the file within the given range. The stream ends
+    /// early if the file is shorter than the range.
+    ///
+    /// Handles created from an object with
+    /// [`FileStore::handle_for`](../enum.FileStore.html#method.handle_for) read
+    /// the range using the object's metadata, see
+    /// [`StorageBackend::get_object_range`](../trait.StorageBackend.html#method.get_object_range).
+    pub fn read_range(&self, range: Range<u64>) -> DataStreamFuture {
+        match self.object {
+            Some(ref object) => self.store.get_object_range(object, range),
+            None => range_stream(self.store.get_file_stream(self.path.clone()), range),
+        }
+    }
+
+    /// Reads the file as parts of `part_size` bytes, reading up to
+    /// `concurrency` parts at once.
+    ///
+    /// Each part is returned whole, annotated with its offset in the file, as
+    /// soon as it has been read so parts arrive out of order. This suits
+    /// writing into a preallocated buffer or sparse file. At most
+    /// `concurrency` parts are held in memory at once.
+    pub fn read_parallel(&self, part_size: u64, concurrency: usize) -> OffsetDataStreamFuture {
+        async fn read_part(
+            store: FileStore,
+            object: Object,
+            range: Range<u64>,
+        ) -> StorageResult<(u64, Data)> {
+            let start = range.start;
+            let mut buffer = BytesMut::with_capacity((range.end - range.start) as usize);
+            let mut stream = store.get_object_range(&object, range).await?;
+            while let Some(data) = stream.next().await {
+                buffer.extend_from_slice(&data?);
+            }
+            Ok((start, buffer.freeze()))
+        }
+
+        let handle = self.clone();
+        OffsetDataStreamFuture::from_future(async move {
+            let object = match handle.object {
+                Some(object) => object,
+                None => handle.store.get_object(handle.path).await?,
+            };
+
+            let len = object.len();
+            let part_size = part_size.max(1);
+            let mut ranges = Vec::new();
+            let mut start = 0;
+            while start < len {
+                let end = len.min(start.saturating_add(part_size));
+                ranges.push(start..end);
+                start = end;
+            }
+
+            let store = handle.store;
+            let parts = iter(ranges)
+                .map(move |range| read_part(store.clone(), object.clone(), range))
+                .buffer_unordered(concurrency.max(1));
+            Ok(OffsetDataStream::from_stream(parts))
+        })
+    }
+
+    /// Replaces the file's data.
+    pub fn write<D>(&self, data: D) -> WriteCompleteFuture
+    where
+        D: Into<Data>,
+    {
+        self.store.write_file_from_stream(
+            self.path.clone(),
+            iter(vec![Ok::<Data, StorageError>(data.into())]),
+        )
+    }
+
+    /// Deletes the object.
+    pub fn delete(&self) -> OperationCompleteFuture {
+        self.store.delete_object(self.path.clone())
+    }
+
+    /// Copies the file to another path in the same store.
+    pub fn copy_to<P>(&self, target: P) -> CopyCompleteFuture
+    where
+        P: TryInto<UploadInfo>,
+        P::Error: Into<StorageError>,
+    {
+        self.store.copy_file(self.path.clone(), target)
+    }
+
+    /// Moves the file to another path in the same store. The handle keeps
+    /// referring to the old path.
+    pub fn move_to<P>(&self, target: P) -> MoveCompleteFuture
+    where
+        P: TryInto<UploadInfo>,
+        P::Error: Into<StorageError>,
+    {
+        self.store.move_file(self.path.clone(), target)
+    }
+}
+
+impl FileStore {
+    /// Returns a handle to the object at the given path.
+    ///
+    /// See the [`handle`](handle/index.html) module.
+    pub fn object<P>(&self, path: P) -> StorageResult<ObjectHandle>
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        Ok(ObjectHandle {
+            store: self.clone(),
+            path: path.try_into().map_err(Into::into)?,
+            object: None,
+        })
+    }
+
+    /// Returns a handle to an object returned from this store by
+    /// [`get_object`](trait.StorageBackend.html#tymethod.get_object) or a
+    /// listing. The handle keeps the object's metadata to read ranges of the
+    /// file without looking it up again.
+    ///
+    /// See the [`handle`](handle/index.html) module.
+    pub fn handle_for(&self, object: Object) -> ObjectHandle {
+        ObjectHandle {
+            store: self.clone(),
+            path: object.path(),
+            object: Some(object),
+        }
+    }
+}
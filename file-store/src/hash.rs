@@ -0,0 +1,130 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Hashing the content of files.
+//!
+//! [`FileStore::compute_hash`](../enum.FileStore.html#method.compute_hash)
+//! resolves to the hex encoded hash of a file. Where the backend already
+//! records a hash with the chosen algorithm that is used, otherwise the file
+//! is read and hashed. Currently only B2 records hashes, SHA-1 ones.
+//!
+//! Included with the "hash" feature.
+use std::convert::TryInto;
+
+use futures::stream::StreamExt;
+
+use crate::types::*;
+use crate::{FileStore, StorageBackend};
+
+/// Future returned by
+/// [`FileStore::compute_hash`](../enum.FileStore.html#method.compute_hash).
+pub type HashFuture = WrappedFuture<StorageResult<String>>;
+
+/// The algorithms that files can be hashed with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum HashAlgorithm {
+    /// SHA-1.
+    Sha1,
+    /// MD5.
+    Md5,
+}
+
+enum Hasher {
+    Sha1(sha1::Sha1),
+    Md5(md5::Context),
+}
+
+impl Hasher {
+    fn new(algorithm: HashAlgorithm) -> Hasher {
+        match algorithm {
+            HashAlgorithm::Sha1 => Hasher::Sha1(sha1::Sha1::new()),
+            HashAlgorithm::Md5 => Hasher::Md5(md5::Context::new()),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha1(hasher) => hasher.update(data),
+            Hasher::Md5(context) => context.consume(data),
+        }
+    }
+
+    fn hexdigest(self) -> String {
+        match self {
+            Hasher::Sha1(hasher) => hasher.hexdigest(),
+            Hasher::Md5(context) => format!("{:x}", context.compute()),
+        }
+    }
+}
+
+#[cfg(feature = "b2")]
+async fn stored_hash(
+    store: &FileStore,
+    path: &ObjectPath,
+    algorithm: HashAlgorithm,
+) -> StorageResult<Option<String>> {
+    // Only trust the hash when talking to B2 directly, wrapping stores may
+    // change the data read.
+    match (store, algorithm) {
+        (FileStore::B2(_), HashAlgorithm::Sha1) => (),
+        _ => return Ok(None),
+    }
+
+    match store.get_object(path.clone()).await? {
+        Object::B2(ref object) => Ok(object.content_sha1().map(str::to_lowercase)),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(not(feature = "b2"))]
+async fn stored_hash(
+    _store: &FileStore,
+    _path: &ObjectPath,
+    _algorithm: HashAlgorithm,
+) -> StorageResult<Option<String>> {
+    Ok(None)
+}
+
+async fn compute_hash(
+    store: FileStore,
+    path: ObjectPath,
+    algorithm: HashAlgorithm,
+) -> StorageResult<String> {
+    if let Some(hash) = stored_hash(&store, &path, algorithm).await? {
+        return Ok(hash);
+    }
+
+    let mut stream = store.get_file_stream(path).await?;
+    let mut hasher = Hasher::new(algorithm);
+    while let Some(data) = stream.next().await {
+        hasher.update(&data?);
+    }
+    Ok(hasher.hexdigest())
+}
+
+impl FileStore {
+    /// Resolves to the lowercase hex encoded hash of a file's content.
+    ///
+    /// See the [`hash`](hash/index.html) module.
+    pub fn compute_hash<P>(&self, path: P, algorithm: HashAlgorithm) -> HashFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        match path.try_into() {
+            Ok(path) => HashFuture::from_future(compute_hash(self.clone(), path, algorithm)),
+            Err(e) => HashFuture::from_value(Err(e.into())),
+        }
+    }
+}
@@ -0,0 +1,495 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A searchable index of the files in a store.
+//!
+//! An [`Index`](struct.Index.html) records the path, size, modification time,
+//! content type and optionally the SHA-1 hash of files so that questions like
+//! "which png files over 10MB were modified last week" can be answered with a
+//! [`Query`](struct.Query.html) without listing the store again.
+//!
+//! [`Index::refresh`](struct.Index.html#method.refresh) lists a prefix of the
+//! store and updates the index to match, only reading files to hash them if
+//! their size or modification time has changed.
+//! [`Index::update`](struct.Index.html#method.update) updates a single path
+//! which is cheaper when the caller already knows what has changed. An index
+//! can be saved to and loaded from any store.
+//!
+//! Updating an index consumes it and resolves to the updated index so no
+//! locking is needed.
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::str;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::stream::{iter, TryStreamExt};
+
+use crate::sync::{directory, list_files, seconds};
+use crate::types::*;
+use crate::utils::{content_type, hash};
+use crate::{ObjectInfo, StorageBackend};
+
+const INDEX_HEADER: &str = "file-store index 1";
+
+/// Future that resolves to an updated [`Index`](struct.Index.html).
+pub type IndexFuture = WrappedFuture<StorageResult<Index>>;
+
+/// A file recorded in an [`Index`](struct.Index.html).
+#[derive(Clone, Debug, PartialEq)]
+pub struct IndexEntry {
+    path: ObjectPath,
+    len: u64,
+    modified: Option<SystemTime>,
+    hash: Option<String>,
+    content_type: String,
+}
+
+impl IndexEntry {
+    fn new(object: &Object, hash: Option<String>) -> IndexEntry {
+        IndexEntry {
+            path: object.path(),
+            len: object.len(),
+            modified: object
+                .modified()
+                .map(|m| UNIX_EPOCH + Duration::from_secs(seconds(m))),
+            hash,
+            content_type: content_type(&object.path()).to_owned(),
+        }
+    }
+
+    /// Gets the path of the file.
+    pub fn path(&self) -> &ObjectPath {
+        &self.path
+    }
+
+    /// Gets the size of the file.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Checks whether the file is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Gets the modification time of the file, to the nearest second.
+    pub fn modified(&self) -> Option<SystemTime> {
+        self.modified
+    }
+
+    /// Gets the hex encoded SHA-1 hash of the file's contents if the index
+    /// records hashes.
+    pub fn hash(&self) -> Option<&str> {
+        self.hash.as_ref().map(String::as_str)
+    }
+
+    /// Gets the content type of the file, guessed from its extension.
+    pub fn content_type(&self) -> &str {
+        &self.content_type
+    }
+
+    /// Checks whether the entry still describes the object.
+    fn is_current(&self, object: &Object, hashes: bool) -> bool {
+        self.len == object.len()
+            && self.modified.map(seconds) == object.modified().map(seconds)
+            && (!hashes || self.hash.is_some())
+    }
+}
+
+/// A search of an [`Index`](struct.Index.html).
+///
+/// An empty query matches every file, each criteria that is set narrows it
+/// down.
+#[derive(Clone, Debug, Default)]
+pub struct Query {
+    prefix: Option<ObjectPath>,
+    extension: Option<String>,
+    content_type: Option<String>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    modified_after: Option<SystemTime>,
+    modified_before: Option<SystemTime>,
+    hash: Option<String>,
+}
+
+impl Query {
+    /// Creates a query that matches every file.
+    pub fn new() -> Query {
+        Default::default()
+    }
+
+    /// Only matches files inside the given directory.
+    pub fn prefix(mut self, prefix: ObjectPath) -> Query {
+        self.prefix = Some(prefix);
+        self
+    }
+
+    /// Only matches files with the given extension, ignoring case.
+    pub fn extension(mut self, extension: &str) -> Query {
+        self.extension = Some(extension.trim_start_matches('.').to_lowercase());
+        self
+    }
+
+    /// Only matches files with the given content type. A type ending in `/`,
+    /// like `image/`, matches every subtype.
+    pub fn content_type(mut self, content_type: &str) -> Query {
+        self.content_type = Some(content_type.to_owned());
+        self
+    }
+
+    /// Only matches files of at least the given size.
+    pub fn min_size(mut self, size: u64) -> Query {
+        self.min_size = Some(size);
+        self
+    }
+
+    /// Only matches files of at most the given size.
+    pub fn max_size(mut self, size: u64) -> Query {
+        self.max_size = Some(size);
+        self
+    }
+
+    /// Only matches files modified at or after the given time.
+    pub fn modified_after(mut self, time: SystemTime) -> Query {
+        self.modified_after = Some(time);
+        self
+    }
+
+    /// Only matches files modified before the given time.
+    pub fn modified_before(mut self, time: SystemTime) -> Query {
+        self.modified_before = Some(time);
+        self
+    }
+
+    /// Only matches files with the given hash. Never matches anything in an
+    /// index without hashes.
+    pub fn hash(mut self, hash: &str) -> Query {
+        self.hash = Some(hash.to_lowercase());
+        self
+    }
+
+    fn matches(&self, entry: &IndexEntry) -> bool {
+        if let Some(ref extension) = self.extension {
+            let name = entry.path.parts().last().cloned().unwrap_or("");
+            match name.rfind('.') {
+                Some(pos) if name[pos + 1..].to_lowercase() == *extension => (),
+                _ => return false,
+            }
+        }
+
+        if let Some(ref content_type) = self.content_type {
+            let matched = if content_type.ends_with('/') {
+                entry.content_type.starts_with(content_type.as_str())
+            } else {
+                entry.content_type == *content_type
+            };
+            if !matched {
+                return false;
+            }
+        }
+
+        if self.min_size.map_or(false, |s| entry.len < s)
+            || self.max_size.map_or(false, |s| entry.len > s)
+        {
+            return false;
+        }
+
+        if self.modified_after.is_some() || self.modified_before.is_some() {
+            let modified = match entry.modified {
+                Some(modified) => modified,
+                None => return false,
+            };
+            if self.modified_after.map_or(false, |t| modified < t)
+                || self.modified_before.map_or(false, |t| modified >= t)
+            {
+                return false;
+            }
+        }
+
+        match self.hash {
+            Some(ref hash) => entry.hash.as_ref() == Some(hash),
+            None => true,
+        }
+    }
+}
+
+/// Gets the key that every path inside a directory starts with.
+fn key_prefix(directory: &ObjectPath) -> String {
+    if directory.is_empty() {
+        String::new()
+    } else {
+        format!("{}/", directory)
+    }
+}
+
+/// An index of the files in a store.
+///
+/// See the [`index`](index.html) module.
+#[derive(Clone, Debug, Default)]
+pub struct Index {
+    entries: BTreeMap<String, IndexEntry>,
+    hashes: bool,
+}
+
+impl Index {
+    /// Creates an empty index that does not record hashes.
+    pub fn new() -> Index {
+        Default::default()
+    }
+
+    /// Creates an empty index that records the hash of every file. Files must
+    /// be read to hash them so this makes refreshing slower.
+    pub fn with_hashes() -> Index {
+        Index {
+            entries: BTreeMap::new(),
+            hashes: true,
+        }
+    }
+
+    /// Checks whether the index records hashes.
+    pub fn has_hashes(&self) -> bool {
+        self.hashes
+    }
+
+    /// Gets the number of files in the index.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Checks whether the index is empty.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Gets the entry for a file.
+    pub fn get(&self, path: &ObjectPath) -> Option<&IndexEntry> {
+        self.entries.get(&path.to_string())
+    }
+
+    /// Iterates over every file in the index in path order.
+    pub fn entries(&self) -> impl Iterator<Item = &IndexEntry> {
+        self.entries.values()
+    }
+
+    /// Finds the files matching a query in path order.
+    pub fn search(&self, query: &Query) -> Vec<&IndexEntry> {
+        let prefix = match query.prefix {
+            Some(ref prefix) => match directory(prefix.clone()) {
+                Ok(directory) => key_prefix(&directory),
+                Err(_) => return Vec::new(),
+            },
+            None => String::new(),
+        };
+
+        self.entries
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .map(|(_, entry)| entry)
+            .filter(|entry| query.matches(entry))
+            .collect()
+    }
+
+    /// Lists the files under `prefix` in `store` and updates the index to
+    /// match, adding new files, updating changed files and removing deleted
+    /// files.
+    pub fn refresh<S, P>(self, store: &S, prefix: P) -> IndexFuture
+    where
+        S: StorageBackend + Sync,
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        async fn refresh<S>(mut index: Index, store: S, prefix: ObjectPath) -> StorageResult<Index>
+        where
+            S: StorageBackend + Sync,
+        {
+            let files = list_files(&store, &prefix).await?;
+            let key_prefix = key_prefix(&prefix);
+            let mut removed: Vec<String> = index
+                .entries
+                .range(key_prefix.clone()..)
+                .take_while(|(key, _)| key.starts_with(&key_prefix))
+                .map(|(key, _)| key.clone())
+                .collect();
+            removed.retain(|key| !files.contains_key(&key[key_prefix.len()..]));
+            for key in removed {
+                index.entries.remove(&key);
+            }
+
+            for object in files.values() {
+                index = index.apply(&store, object).await?;
+            }
+
+            Ok(index)
+        }
+
+        let prefix = match prefix.try_into().map_err(Into::into).and_then(directory) {
+            Ok(p) => p,
+            Err(e) => return IndexFuture::from_value(Err(e)),
+        };
+
+        IndexFuture::from_future(refresh(self, store.clone(), prefix))
+    }
+
+    /// Updates the index for a single file that may have been added, changed
+    /// or deleted.
+    pub fn update<S, P>(self, store: &S, path: P) -> IndexFuture
+    where
+        S: StorageBackend + Sync,
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        async fn update<S>(mut index: Index, store: S, path: ObjectPath) -> StorageResult<Index>
+        where
+            S: StorageBackend + Sync,
+        {
+            match store.get_object(path.clone()).await {
+                Ok(object) => {
+                    if object.object_type() == ObjectType::File {
+                        return index.apply(&store, &object).await;
+                    }
+                }
+                Err(e) => match e.kind() {
+                    StorageErrorKind::NotFound(_) => (),
+                    _ => return Err(e),
+                },
+            }
+
+            index.entries.remove(&path.to_string());
+            Ok(index)
+        }
+
+        let path = match path.try_into() {
+            Ok(p) => p,
+            Err(e) => return IndexFuture::from_value(Err(e.into())),
+        };
+
+        IndexFuture::from_future(update(self, store.clone(), path))
+    }
+
+    /// Records a file, hashing it if needed.
+    async fn apply<S>(mut self, store: &S, object: &Object) -> StorageResult<Index>
+    where
+        S: StorageBackend + Sync,
+    {
+        let key = object.path().to_string();
+        if let Some(entry) = self.entries.get(&key) {
+            if entry.is_current(object, self.hashes) {
+                return Ok(self);
+            }
+        }
+
+        let hash = if self.hashes {
+            Some(hash(store, object.path()).await?)
+        } else {
+            None
+        };
+        self.entries.insert(key, IndexEntry::new(object, hash));
+        Ok(self)
+    }
+
+    /// Saves the index to `path` in `store`.
+    pub fn save<S, P>(&self, store: &S, path: P) -> WriteCompleteFuture
+    where
+        S: StorageBackend,
+        P: TryInto<UploadInfo>,
+        P::Error: Into<StorageError>,
+    {
+        let data = Data::from(self.format());
+        store.write_file_from_stream(path, iter(vec![Ok::<Data, StorageError>(data)]))
+    }
+
+    /// Loads an index previously saved to `path` in `store`.
+    pub fn load<S, P>(store: &S, path: P) -> IndexFuture
+    where
+        S: StorageBackend + Sync,
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        async fn load<S>(store: S, path: ObjectPath) -> StorageResult<Index>
+        where
+            S: StorageBackend + Sync,
+        {
+            let data = store.get_file_stream(path).await?.try_concat().await?;
+            Index::parse(&data)
+        }
+
+        let path = match path.try_into() {
+            Ok(p) => p,
+            Err(e) => return IndexFuture::from_value(Err(e.into())),
+        };
+
+        IndexFuture::from_future(load(store.clone(), path))
+    }
+
+    fn format(&self) -> String {
+        let mut text = format!(
+            "{} {}\n",
+            INDEX_HEADER,
+            if self.hashes { "hashes" } else { "nohashes" }
+        );
+        for entry in self.entries.values() {
+            let modified = match entry.modified {
+                Some(modified) => seconds(modified).to_string(),
+                None => "-".to_owned(),
+            };
+            text.push_str(&format!(
+                "{} {} {} {} {}\n",
+                entry.len,
+                modified,
+                entry.hash.as_ref().map(String::as_str).unwrap_or("-"),
+                entry.content_type,
+                entry.path
+            ));
+        }
+        text
+    }
+
+    fn parse(data: &[u8]) -> StorageResult<Index> {
+        let invalid = || error::invalid_data(Some("The index is not valid."));
+
+        let text = str::from_utf8(data).map_err(|_| invalid())?;
+        let mut lines = text.lines();
+        let hashes = match lines.next() {
+            Some(header) if header == format!("{} hashes", INDEX_HEADER) => true,
+            Some(header) if header == format!("{} nohashes", INDEX_HEADER) => false,
+            _ => return Err(invalid()),
+        };
+
+        let mut entries = BTreeMap::new();
+        for line in lines {
+            let fields: Vec<&str> = line.splitn(5, ' ').collect();
+            if fields.len() != 5 {
+                return Err(invalid());
+            }
+
+            let modified = match fields[1] {
+                "-" => None,
+                m => Some(m.parse().map_err(|_| invalid())?),
+            };
+            let entry = IndexEntry {
+                path: ObjectPath::new(fields[4])?,
+                len: fields[0].parse().map_err(|_| invalid())?,
+                modified: modified.map(|m| UNIX_EPOCH + Duration::from_secs(m)),
+                hash: match fields[2] {
+                    "-" => None,
+                    h => Some(h.to_owned()),
+                },
+                content_type: fields[3].to_owned(),
+            };
+            entries.insert(entry.path.to_string(), entry);
+        }
+
+        Ok(Index { entries, hashes })
+    }
+}
@@ -0,0 +1,238 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Instrumentation of backend operations.
+//!
+//! Every operation has an [`OperationId`](../correlation/struct.OperationId.html)
+//! that is the current id whenever the operation is polled.
+//!
+//! With the "tracing" feature every operation runs inside a `tracing` span
+//! recording the operation id, the backend, the operation and the path. When the operation
+//! completes an event records how long it took and, for reads and writes, how
+//! many bytes were transferred. Reads are only complete once their data
+//! stream has been read to the end.
+//!
+//! Without the feature an [`Operation`](struct.Operation.html) only sets the
+//! current operation id.
+use crate::backends::Backend;
+use crate::correlation::OperationId;
+use crate::types::*;
+
+fn operation_id() -> OperationId {
+    OperationId::current().unwrap_or_else(OperationId::generate)
+}
+
+#[cfg(feature = "tracing")]
+pub(crate) use enabled::Operation;
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) use disabled::Operation;
+
+#[cfg(feature = "tracing")]
+mod enabled {
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+    use std::time::Instant;
+
+    use futures::future::TryFutureExt;
+    use futures::stream::Stream;
+    use tracing::{debug, debug_span, Span};
+
+    use super::*;
+
+    #[derive(Clone)]
+    pub(crate) struct Operation {
+        id: OperationId,
+        span: Span,
+        start: Instant,
+        bytes: Arc<AtomicU64>,
+    }
+
+    impl Operation {
+        pub fn new(backend: Backend, operation: &'static str, path: &ObjectPath) -> Operation {
+            let id = operation_id();
+            Operation {
+                id,
+                span: debug_span!(
+                    "operation",
+                    operation_id = %id,
+                    backend = %backend,
+                    operation,
+                    path = %path
+                ),
+                start: Instant::now(),
+                bytes: Default::default(),
+            }
+        }
+
+        fn complete(&self) {
+            let _entered = self.span.enter();
+            debug!(
+                duration_ms = self.start.elapsed().as_millis() as u64,
+                bytes = self.bytes.load(Ordering::Relaxed),
+                "complete"
+            );
+        }
+
+        /// Runs a future inside this operation's span. The operation is
+        /// complete when the future resolves.
+        pub fn run<F>(self, future: F) -> impl Future<Output = F::Output>
+        where
+            F: Future,
+        {
+            InSpan {
+                future: Box::pin(future),
+                operation: self,
+                completes: true,
+            }
+        }
+
+        /// Runs a future resolving to a data stream inside this operation's
+        /// span. The operation is complete once the stream ends.
+        pub fn read<F>(self, future: F) -> impl Future<Output = StorageResult<DataStream>>
+        where
+            F: Future<Output = StorageResult<DataStream>>,
+        {
+            let operation = self.clone();
+            InSpan {
+                future: Box::pin(future),
+                operation: self,
+                completes: false,
+            }
+            .map_ok(move |stream| {
+                DataStream::from_stream(Counted {
+                    stream,
+                    operation,
+                    completes: true,
+                })
+            })
+        }
+
+        /// Counts the bytes passing through a stream of data being written.
+        pub fn write<S>(&self, stream: S) -> impl Stream<Item = StorageResult<Data>>
+        where
+            S: Stream<Item = StorageResult<Data>>,
+        {
+            Counted {
+                stream: Box::pin(stream),
+                operation: self.clone(),
+                completes: false,
+            }
+        }
+    }
+
+    struct InSpan<F> {
+        future: Pin<Box<F>>,
+        operation: Operation,
+        completes: bool,
+    }
+
+    impl<F> Future for InSpan<F>
+    where
+        F: Future,
+    {
+        type Output = F::Output;
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<F::Output> {
+            let this = self.get_mut();
+            let future = &mut this.future;
+            let span = &this.operation.span;
+            let result = this.operation.id.scope(|| {
+                let _entered = span.enter();
+                future.as_mut().poll(cx)
+            });
+
+            if result.is_ready() && this.completes {
+                this.operation.complete();
+            }
+
+            result
+        }
+    }
+
+    struct Counted<S> {
+        stream: S,
+        operation: Operation,
+        completes: bool,
+    }
+
+    impl<S> Stream for Counted<S>
+    where
+        S: Stream<Item = StorageResult<Data>> + Unpin,
+    {
+        type Item = StorageResult<Data>;
+
+        fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+            let this = self.get_mut();
+            let stream = &mut this.stream;
+            let span = &this.operation.span;
+            let result = this.operation.id.scope(|| {
+                let _entered = span.enter();
+                Pin::new(stream).poll_next(cx)
+            });
+
+            match result {
+                Poll::Ready(Some(Ok(ref data))) => {
+                    this.operation
+                        .bytes
+                        .fetch_add(data.len() as u64, Ordering::Relaxed);
+                }
+                Poll::Ready(None) if this.completes => this.operation.complete(),
+                _ => (),
+            }
+
+            result
+        }
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+mod disabled {
+    use std::future::Future;
+
+    use futures::future::TryFutureExt;
+
+    use super::*;
+    use crate::correlation::{with_operation_id, WithOperationId};
+
+    pub(crate) struct Operation {
+        id: OperationId,
+    }
+
+    impl Operation {
+        pub fn new(_backend: Backend, _operation: &'static str, _path: &ObjectPath) -> Operation {
+            Operation { id: operation_id() }
+        }
+
+        pub fn run<F>(self, future: F) -> WithOperationId<F> {
+            with_operation_id(self.id, future)
+        }
+
+        pub fn read<F>(self, future: F) -> impl Future<Output = StorageResult<DataStream>>
+        where
+            F: Future<Output = StorageResult<DataStream>>,
+        {
+            let id = self.id;
+            with_operation_id(id, future)
+                .map_ok(move |stream| DataStream::from_stream(with_operation_id(id, stream)))
+        }
+
+        pub fn write<S>(&self, stream: S) -> S {
+            stream
+        }
+    }
+}
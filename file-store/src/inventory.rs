@@ -0,0 +1,157 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Exporting listings for inventory reports.
+//!
+//! An [`Inventory`](struct.Inventory.html) turns an
+//! [`ObjectStream`](../type.ObjectStream.html) into a
+//! [`DataStream`](../type.DataStream.html) of newline-delimited JSON or CSV
+//! with the path, size, type, last modified time and hash of every object.
+//! Objects are written as they are listed so the listing is never held in
+//! memory:
+//!
+//! ```no_run
+//! # use file_store::{FileStore, StorageBackend};
+//! # use file_store::hash::HashAlgorithm;
+//! # use file_store::inventory::{Format, Inventory};
+//! # async fn example(store: FileStore) {
+//! let objects = store.list_objects("").await.unwrap();
+//! let report = Inventory::new(Format::Csv)
+//!     .hashes(store.clone(), HashAlgorithm::Sha1)
+//!     .export(objects);
+//! store.write_file_from_stream("inventory.csv", report).await.unwrap();
+//! # }
+//! ```
+//!
+//! Modified times are in milliseconds since the Unix epoch. Hashes are only
+//! included when requested with [`hashes`](struct.Inventory.html#method.hashes)
+//! and only for files, otherwise they are `null` in JSON and empty in CSV.
+//!
+//! Included with the "inventory" feature.
+use std::time::UNIX_EPOCH;
+
+use futures::stream::{iter, StreamExt};
+use serde_json::json;
+
+use crate::hash::HashAlgorithm;
+use crate::types::*;
+use crate::{FileStore, ObjectInfo};
+
+const CSV_HEADER: &str = "path,size,type,modified,hash\n";
+
+/// The formats that listings can be exported in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// One JSON object per line.
+    JsonLines,
+    /// Comma separated values with a header line.
+    Csv,
+}
+
+fn modified(object: &Object) -> Option<u64> {
+    object
+        .modified()
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_millis() as u64)
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+fn line(format: Format, object: &Object, hash: Option<String>) -> Data {
+    match format {
+        Format::JsonLines => {
+            let value = json!({
+                "path": object.path().to_string(),
+                "size": object.len(),
+                "type": object.object_type().to_string(),
+                "modified": modified(object),
+                "hash": hash,
+            });
+            Data::from(format!("{}\n", value))
+        }
+        Format::Csv => Data::from(format!(
+            "{},{},{},{},{}\n",
+            csv_field(&object.path().to_string()),
+            object.len(),
+            object.object_type(),
+            modified(object).map(|m| m.to_string()).unwrap_or_default(),
+            hash.unwrap_or_default(),
+        )),
+    }
+}
+
+async fn export_object(
+    format: Format,
+    hashes: Option<(FileStore, HashAlgorithm)>,
+    object: StorageResult<Object>,
+) -> StorageResult<Data> {
+    let object = object?;
+    let hash = match hashes {
+        Some((ref store, algorithm)) if object.object_type() == ObjectType::File => {
+            Some(store.compute_hash(object.path(), algorithm).await?)
+        }
+        _ => None,
+    };
+
+    Ok(line(format, &object, hash))
+}
+
+/// Exports listings.
+///
+/// See the [`inventory`](index.html) module.
+#[derive(Clone, Debug)]
+pub struct Inventory {
+    format: Format,
+    hashes: Option<(FileStore, HashAlgorithm)>,
+}
+
+impl Inventory {
+    /// Creates an exporter for the given format.
+    pub fn new(format: Format) -> Inventory {
+        Inventory {
+            format,
+            hashes: None,
+        }
+    }
+
+    /// Includes the hash of every file, found with
+    /// [`FileStore::compute_hash`](../enum.FileStore.html#method.compute_hash)
+    /// on the store that was listed. Backends that don't record hashes have to
+    /// read every file.
+    pub fn hashes(mut self, store: FileStore, algorithm: HashAlgorithm) -> Inventory {
+        self.hashes = Some((store, algorithm));
+        self
+    }
+
+    /// Exports the objects. Errors from the listing, or while hashing, are
+    /// passed on in place of the object's line.
+    pub fn export(&self, objects: ObjectStream) -> DataStream {
+        let format = self.format;
+        let hashes = self.hashes.clone();
+        let lines = objects.then(move |object| export_object(format, hashes.clone(), object));
+
+        match format {
+            Format::JsonLines => DataStream::from_stream(lines),
+            Format::Csv => {
+                DataStream::from_stream(iter(vec![Ok(Data::from(CSV_HEADER))]).chain(lines))
+            }
+        }
+    }
+}
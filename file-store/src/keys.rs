@@ -0,0 +1,533 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Choosing the keys that files are encrypted with.
+//!
+//! Files written with
+//! [`Encryption::Customer`](../enum.Encryption.html#variant.Customer) can only
+//! be read with the same key. A [`KeyProvider`](trait.KeyProvider.html) hands
+//! out the key for each new file along with a
+//! [`WrappedKey`](struct.WrappedKey.html), a form of the key that is safe to
+//! store, and recovers the key from that form later. A key management service
+//! would generate a fresh data key for every file and wrap it with a master
+//! key it never reveals. [`StaticKeys`](struct.StaticKeys.html) is a simple
+//! provider holding a fixed set of keys where the wrapped form only names the
+//! key used.
+//!
+//! [`Keys::store`](struct.Keys.html#method.store) wraps a store so that every
+//! file written is encrypted with a key from the provider and the wrapped key
+//! is kept in a sidecar object at `<prefix>/<name>`, where `<name>` is the
+//! file's path with `%` and `/` characters percent encoded. Reads find the key
+//! from the sidecar. Files without a sidecar are read without a key. Copies
+//! and moves read and write the data again so that the target gets a key of
+//! its own. Objects under the prefix are not included when listing objects
+//! outside of it.
+//!
+//! Changing the provider's current key only affects files written afterwards.
+//! [`Keys::rotate`](struct.Keys.html#method.rotate) encrypts the files that
+//! still use older keys again.
+//!
+//! Backends that don't encrypt, like the file backend, ignore the key but the
+//! sidecars are still kept.
+use std::collections::HashMap;
+use std::fmt;
+use std::str::from_utf8;
+use std::sync::Arc;
+
+use futures::future::TryFutureExt;
+use futures::stream::{iter, TryStreamExt};
+
+use crate::backends::Backend;
+use crate::dynamic::{self, DynamicStore};
+use crate::sync::{directory, list_files};
+use crate::trash::{contains, decode, encode, hide};
+use crate::types::*;
+use crate::{FileStore, ObjectInfo, StorageBackend};
+
+const KEY_HEADER: &str = "file-store key 1";
+
+/// Future returned when recovering a key.
+pub type KeyFuture = WrappedFuture<StorageResult<CustomerKey>>;
+/// Future returned when generating a key for a new file.
+pub type NewKeyFuture = WrappedFuture<StorageResult<(CustomerKey, WrappedKey)>>;
+/// Future returned by [`Keys::rotate`](struct.Keys.html#method.rotate).
+pub type RotateFuture = WrappedFuture<StorageResult<Vec<ObjectPath>>>;
+
+/// A key in a form that is safe to store next to the data it encrypts.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WrappedKey {
+    key_id: String,
+    data: Vec<u8>,
+}
+
+impl WrappedKey {
+    /// Creates a wrapped key. `key_id` names the key that protects it and must
+    /// not contain new lines, `data` is whatever the provider needs to recover
+    /// the key.
+    pub fn new(key_id: &str, data: &[u8]) -> StorageResult<WrappedKey> {
+        if key_id.is_empty() || key_id.contains(|c| c == '\n' || c == '\r') {
+            return Err(error::invalid_settings(Some(
+                "Key ids cannot be empty or contain new lines.",
+            )));
+        }
+
+        Ok(WrappedKey {
+            key_id: key_id.to_owned(),
+            data: data.to_vec(),
+        })
+    }
+
+    /// The id of the key that protects this key.
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    /// The data needed to recover the key.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn parse(data: &[u8]) -> StorageResult<WrappedKey> {
+        let text = from_utf8(data)
+            .map_err(|_| error::invalid_data(Some("The key sidecar is not valid UTF-8.")))?;
+        let mut lines = text.lines();
+        if lines.next() != Some(KEY_HEADER) {
+            return Err(error::invalid_data(Some(
+                "The key sidecar has an unknown format.",
+            )));
+        }
+
+        match (lines.next(), lines.next().map(from_hex)) {
+            (Some(key_id), Some(Some(data))) => WrappedKey::new(key_id, &data),
+            _ => Err(error::invalid_data(Some(
+                "The key sidecar is missing the key.",
+            ))),
+        }
+    }
+
+    fn to_data(&self) -> Data {
+        Data::from(format!(
+            "{}\n{}\n{}\n",
+            KEY_HEADER,
+            self.key_id,
+            to_hex(&self.data)
+        ))
+    }
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 {
+        return None;
+    }
+
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Provides the keys that files are encrypted with.
+///
+/// See the [`keys`](index.html) module.
+pub trait KeyProvider: Send + Sync + 'static {
+    /// The id of the key that new files should be protected by.
+    fn current_key_id(&self) -> String;
+
+    /// Gets a key to encrypt a new file with and its wrapped form.
+    fn new_key(&self) -> NewKeyFuture;
+
+    /// Recovers a key from its wrapped form.
+    ///
+    /// Should fail with a [`NotFound`](../enum.StorageErrorKind.html#variant.NotFound)
+    /// error if the key that protects it is no longer available.
+    fn unwrap_key(&self, wrapped: &WrappedKey) -> KeyFuture;
+}
+
+/// A provider with a fixed set of keys.
+///
+/// New files are encrypted with the current key itself rather than a fresh
+/// data key so the wrapped form of a key only holds its id. Older keys must be
+/// kept for as long as files encrypted with them exist.
+#[derive(Clone)]
+pub struct StaticKeys {
+    keys: HashMap<String, CustomerKey>,
+    current: String,
+}
+
+impl fmt::Debug for StaticKeys {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("StaticKeys")
+            .field("current", &self.current)
+            .finish()
+    }
+}
+
+impl StaticKeys {
+    /// Creates a provider whose current key is the one given.
+    pub fn new(id: &str, key: CustomerKey) -> StorageResult<StaticKeys> {
+        // Checks the id.
+        WrappedKey::new(id, &[])?;
+
+        let mut keys = HashMap::new();
+        keys.insert(id.to_owned(), key);
+        Ok(StaticKeys {
+            keys,
+            current: id.to_owned(),
+        })
+    }
+
+    /// Adds a key that can still be used to read files.
+    pub fn add_key(mut self, id: &str, key: CustomerKey) -> StorageResult<StaticKeys> {
+        WrappedKey::new(id, &[])?;
+        self.keys.insert(id.to_owned(), key);
+        Ok(self)
+    }
+
+    /// Adds a key and makes it the one new files are encrypted with.
+    pub fn rotate(self, id: &str, key: CustomerKey) -> StorageResult<StaticKeys> {
+        let mut keys = self.add_key(id, key)?;
+        keys.current = id.to_owned();
+        Ok(keys)
+    }
+}
+
+impl KeyProvider for StaticKeys {
+    fn current_key_id(&self) -> String {
+        self.current.clone()
+    }
+
+    fn new_key(&self) -> NewKeyFuture {
+        let key = self.keys[&self.current].clone();
+        NewKeyFuture::from_value(WrappedKey::new(&self.current, &[]).map(|w| (key, w)))
+    }
+
+    fn unwrap_key(&self, wrapped: &WrappedKey) -> KeyFuture {
+        KeyFuture::from_value(match self.keys.get(wrapped.key_id()) {
+            Some(key) => Ok(key.clone()),
+            None => Err(error::not_found(
+                ObjectPath::empty(),
+                Some(&format!("The key '{}' is not available.", wrapped.key_id())),
+            )),
+        })
+    }
+}
+
+fn ignore_not_found(result: StorageResult<()>) -> StorageResult<()> {
+    match result {
+        Err(e) => match e.kind() {
+            StorageErrorKind::NotFound(_) => Ok(()),
+            _ => Err(e),
+        },
+        result => result,
+    }
+}
+
+fn storage_error(error: TransferError) -> StorageError {
+    match error {
+        TransferError::SourceError(e) => e,
+        TransferError::TargetError(e) => e,
+    }
+}
+
+/// Encrypts the files in a store with keys from a provider.
+///
+/// See the [`keys`](index.html) module.
+#[derive(Clone)]
+pub struct Keys {
+    store: FileStore,
+    prefix: ObjectPath,
+    provider: Arc<dyn KeyProvider>,
+}
+
+impl fmt::Debug for Keys {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Keys")
+            .field("store", &self.store)
+            .field("prefix", &self.prefix)
+            .finish()
+    }
+}
+
+impl Keys {
+    /// Creates a layer keeping the wrapped keys of files under the given
+    /// prefix of the store.
+    pub fn new<P>(store: FileStore, prefix: ObjectPath, provider: P) -> StorageResult<Keys>
+    where
+        P: KeyProvider,
+    {
+        let prefix = directory(prefix)?;
+        if prefix.is_empty() {
+            return Err(error::invalid_path(
+                prefix,
+                Some("Keys cannot be kept at the root of the store."),
+            ));
+        }
+
+        Ok(Keys {
+            store,
+            prefix,
+            provider: Arc::new(provider),
+        })
+    }
+
+    /// Wraps the store so that files are encrypted with keys from the
+    /// provider.
+    pub fn store(&self) -> FileStore {
+        FileStore::from(DynamicStore::new(KeyedStore(self.clone())))
+    }
+
+    fn sidecar(&self, path: &ObjectPath) -> ObjectPath {
+        let mut sidecar = self.prefix.clone();
+        sidecar.push_part(&encode(path));
+        sidecar
+    }
+
+    /// Encrypts every file with the given prefix that isn't protected by the
+    /// provider's current key again, resolving to the paths of the files
+    /// changed.
+    ///
+    /// Files without a sidecar are left alone. Each file is written while it
+    /// is still being read so this relies on the backend keeping the old data
+    /// readable until the read completes, as B2 and the file backend on unix
+    /// do.
+    pub fn rotate(&self, prefix: ObjectPath) -> RotateFuture {
+        RotateFuture::from_future(rotate(self.clone(), prefix))
+    }
+}
+
+async fn read_key(layer: &Keys, path: &ObjectPath) -> StorageResult<Option<WrappedKey>> {
+    let stream = match layer.store.get_file_stream(layer.sidecar(path)).await {
+        Ok(stream) => stream,
+        Err(e) => match e.kind() {
+            StorageErrorKind::NotFound(_) => return Ok(None),
+            _ => return Err(e),
+        },
+    };
+
+    let data = stream.map_ok(|data| data.to_vec()).try_concat().await?;
+    WrappedKey::parse(&data).map(Some)
+}
+
+async fn read(
+    layer: Keys,
+    path: ObjectPath,
+    mut options: ReadOptions,
+) -> StorageResult<DataStream> {
+    if let Some(wrapped) = read_key(&layer, &path).await? {
+        options.customer_key = Some(layer.provider.unwrap_key(&wrapped).await?);
+    }
+
+    layer
+        .store
+        .get_file_stream_with_options(path, options)
+        .await
+}
+
+async fn write(layer: Keys, mut info: UploadInfo, stream: DataStream) -> Result<(), TransferError> {
+    let (key, wrapped) = layer
+        .provider
+        .new_key()
+        .await
+        .map_err(TransferError::TargetError)?;
+    let sidecar = layer.sidecar(&info.path);
+    info.options.encryption = Some(Encryption::Customer(key));
+
+    layer.store.write_file_from_stream(info, stream).await?;
+    layer
+        .store
+        .write_file_from_stream(
+            sidecar,
+            iter(vec![Ok::<Data, StorageError>(wrapped.to_data())]),
+        )
+        .await
+}
+
+async fn copy(
+    layer: Keys,
+    source: ObjectPath,
+    mut target: UploadInfo,
+) -> Result<(), TransferError> {
+    if target.modified.is_none() && target.options.preserve_modified {
+        target.modified = layer
+            .store
+            .get_object(source.clone())
+            .await
+            .map_err(TransferError::SourceError)?
+            .modified();
+    }
+
+    let stream = read(layer.clone(), source, Default::default())
+        .await
+        .map_err(TransferError::SourceError)?;
+    write(layer, target, stream).await
+}
+
+async fn delete(layer: Keys, path: ObjectPath) -> StorageResult<()> {
+    layer.store.delete_object(path.clone()).await?;
+    ignore_not_found(layer.store.delete_object(layer.sidecar(&path)).await)
+}
+
+async fn move_file(
+    layer: Keys,
+    source: ObjectPath,
+    target: UploadInfo,
+) -> Result<(), TransferError> {
+    copy(layer.clone(), source.clone(), target).await?;
+    delete(layer, source)
+        .await
+        .map_err(TransferError::SourceError)
+}
+
+async fn rotate(layer: Keys, prefix: ObjectPath) -> StorageResult<Vec<ObjectPath>> {
+    let sidecars = match list_files(&layer.store, &layer.prefix).await {
+        Ok(sidecars) => sidecars,
+        Err(e) => match e.kind() {
+            StorageErrorKind::NotFound(_) => HashMap::new(),
+            _ => return Err(e),
+        },
+    };
+
+    let current = layer.provider.current_key_id();
+    let mut paths = Vec::new();
+    for name in sidecars.keys() {
+        if name.contains('/') {
+            continue;
+        }
+
+        let path = ObjectPath::new(decode(name))?;
+        if path.starts_with(&prefix) {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    let mut rotated = Vec::new();
+    for path in paths {
+        match read_key(&layer, &path).await? {
+            Some(ref wrapped) if wrapped.key_id() != current => (),
+            _ => continue,
+        }
+
+        let mut info = UploadInfo::from(path.clone());
+        info.options.preserve_modified = true;
+        copy(layer.clone(), path.clone(), info)
+            .await
+            .map_err(storage_error)?;
+        rotated.push(path);
+    }
+
+    Ok(rotated)
+}
+
+/// The backend of the store returned by
+/// [`Keys::store`](struct.Keys.html#method.store).
+struct KeyedStore(Keys);
+
+impl KeyedStore {
+    fn is_key(&self, path: &ObjectPath) -> bool {
+        contains(&self.0.prefix, path)
+    }
+}
+
+// Only StorageBackend is in scope so calls on the wrapped store are not
+// ambiguous.
+impl dynamic::DynamicBackend for KeyedStore {
+    fn backend_type(&self) -> Backend {
+        self.0.store.backend_type()
+    }
+
+    fn authorize(&self) -> OperationCompleteFuture {
+        self.0.store.authorize()
+    }
+
+    fn validate_path(&self, path: &ObjectPath) -> StorageResult<()> {
+        self.0.store.validate_path(path)
+    }
+
+    fn list_objects(&self, prefix: ObjectPath) -> ObjectStreamFuture {
+        let keys = self.0.prefix.clone();
+        ObjectStreamFuture::from_future(
+            self.0
+                .store
+                .list_objects(prefix.clone())
+                .map_ok(move |stream| hide(stream, Some(keys), &prefix)),
+        )
+    }
+
+    fn list_directory(&self, dir: ObjectPath) -> ObjectStreamFuture {
+        let keys = self.0.prefix.clone();
+        ObjectStreamFuture::from_future(
+            self.0
+                .store
+                .list_directory(dir.clone())
+                .map_ok(move |stream| hide(stream, Some(keys), &dir)),
+        )
+    }
+
+    fn get_object(&self, path: ObjectPath) -> ObjectFuture {
+        self.0.store.get_object(path)
+    }
+
+    fn get_file_stream(&self, path: ObjectPath) -> DataStreamFuture {
+        self.get_file_stream_with_options(path, Default::default())
+    }
+
+    fn get_file_stream_with_options(
+        &self,
+        path: ObjectPath,
+        options: ReadOptions,
+    ) -> DataStreamFuture {
+        if self.is_key(&path) {
+            return self.0.store.get_file_stream_with_options(path, options);
+        }
+
+        DataStreamFuture::from_future(read(self.0.clone(), path, options))
+    }
+
+    fn copy_file(&self, source: ObjectPath, target: UploadInfo) -> CopyCompleteFuture {
+        if self.is_key(&source) || self.is_key(&target.path) {
+            return self.0.store.copy_file(source, target);
+        }
+
+        CopyCompleteFuture::from_future(copy(self.0.clone(), source, target))
+    }
+
+    fn move_file(&self, source: ObjectPath, target: UploadInfo) -> MoveCompleteFuture {
+        if self.is_key(&source) || self.is_key(&target.path) {
+            return self.0.store.move_file(source, target);
+        }
+
+        MoveCompleteFuture::from_future(move_file(self.0.clone(), source, target))
+    }
+
+    fn delete_object(&self, path: ObjectPath) -> OperationCompleteFuture {
+        if self.is_key(&path) {
+            return self.0.store.delete_object(path);
+        }
+
+        OperationCompleteFuture::from_future(delete(self.0.clone(), path))
+    }
+
+    fn write_file_from_stream(&self, info: UploadInfo, stream: DataStream) -> WriteCompleteFuture {
+        if self.is_key(&info.path) {
+            return self.0.store.write_file_from_stream(info, stream);
+        }
+
+        WriteCompleteFuture::from_future(write(self.0.clone(), info, stream))
+    }
+}
@@ -31,16 +31,79 @@
 //!
 //! The [`FileStore`](enum.FileStore.html) is the main way to access storage. A
 //! [`FileStore`](enum.FileStore.html) is created from one of the backends.
+//!
+//! If you don't want to deal with futures then the "blocking" feature includes
+//! a synchronous API in the [`blocking`](blocking/index.html) module.
+//!
+//! With the "tracing" feature every backend operation is recorded as a
+//! [`tracing`](https://docs.rs/tracing) span including the backend, the
+//! operation, the path, the bytes transferred and how long it took.
 #![warn(missing_docs)]
 
+pub mod archive;
 #[macro_use]
 pub mod backends;
+#[cfg(feature = "backup")]
+pub mod backup;
+#[cfg(feature = "blocking")]
+pub mod blocking;
+pub mod cache;
+pub mod casefold;
+#[cfg(feature = "changes")]
+pub mod changes;
+pub mod chunked;
+pub mod correlation;
+#[cfg(feature = "decompress")]
+pub mod decompress;
+pub mod dynamic;
+#[cfg(feature = "expire")]
+pub mod expire;
+pub mod handle;
+#[cfg(feature = "hash")]
+pub mod hash;
+#[cfg(feature = "index")]
+pub mod index;
+mod instrument;
+#[cfg(feature = "inventory")]
+pub mod inventory;
+pub mod keys;
+#[cfg(feature = "file")]
+pub mod local;
+#[cfg(feature = "lock")]
+pub mod lock;
+#[cfg(feature = "migrate")]
+pub mod migrate;
+#[cfg(feature = "mount")]
+pub mod mount;
+pub mod observe;
+#[cfg(all(feature = "indicatif", feature = "transfers"))]
+pub mod progress;
+#[cfg(feature = "redirect")]
+pub mod redirect;
+#[cfg(feature = "serve")]
+pub mod serve;
+#[cfg(feature = "server")]
+pub mod server;
+#[cfg(feature = "tower")]
+pub mod service;
+pub mod sync;
+pub mod tags;
+pub mod tee;
+#[cfg(feature = "transfers")]
+pub mod transfers;
+pub mod transform;
+#[cfg(feature = "b2")]
+pub mod transport;
+pub mod trash;
 mod types;
+pub mod usage;
 pub mod utils;
+pub mod walk;
 
 pub use types::*;
 
 use std::convert::TryInto;
+use std::ops::Range;
 
 use bytes::IntoBuf;
 use enum_dispatch::enum_dispatch;
@@ -49,6 +112,8 @@ use futures::stream::Stream;
 
 use backends::b2::B2Backend;
 use backends::file::FileBackend;
+use dynamic::DynamicStore;
+use types::stream::{compose_streams, directories_only, range_stream};
 
 /// The trait that every storage backend must implement at a minimum.
 #[enum_dispatch]
@@ -56,12 +121,39 @@ pub trait StorageBackend: Clone + Send + 'static {
     /// Retrieves the type of this backend.
     fn backend_type(&self) -> backends::Backend;
 
+    /// Authenticates with the backend if it has not already done so.
+    ///
+    /// Backends normally authenticate while connecting or when first needed so
+    /// calling this is rarely necessary. It is useful with backends configured
+    /// to authenticate lazily in order to check the credentials at a known
+    /// time. Backends that do not need authentication complete immediately.
+    fn authorize(&self) -> OperationCompleteFuture {
+        OperationCompleteFuture::from_value(Ok(()))
+    }
+
+    /// Checks that the backend could store an object at the given path.
+    ///
+    /// Storage services restrict paths in different ways, limiting their
+    /// length or the characters they may contain. This fails with an
+    /// [`InvalidPath`](enum.StorageErrorKind.html#variant.InvalidPath) error
+    /// describing the problem for paths the backend cannot store without
+    /// making any requests. Backends check paths before reading or writing
+    /// objects so that problems are reported clearly rather than as an error
+    /// from the service.
+    fn validate_path(&self, path: &ObjectPath) -> StorageResult<()> {
+        let _ = path;
+        Ok(())
+    }
+
     /// Lists the objects that are prefixed by the given prefix.
     ///
     /// This will return the entire directory structure under the given prefix.
     /// Be sure to include a trailing `/` if you only want to include objects
     /// inside that (possibly virtual) directory. This will only include
     /// directory objects if those actually exists in the underlying storage.
+    ///
+    /// Listing a prefix that matches no objects returns an empty stream rather
+    /// than a [`NotFound`](enum.StorageErrorKind.html#variant.NotFound) error.
     fn list_objects<P>(&self, prefix: P) -> ObjectStreamFuture
     where
         P: TryInto<ObjectPath>,
@@ -74,11 +166,31 @@ pub trait StorageBackend: Clone + Send + 'static {
     /// additional `/` character are returned. This will include directory
     /// objects even if the underlying storage doesn't actually support
     /// directories to indicate that there may be deeper objects not included.
+    ///
+    /// Listing a directory that does not exist, or is a file, returns an empty
+    /// stream rather than a [`NotFound`](enum.StorageErrorKind.html#variant.NotFound)
+    /// error.
     fn list_directory<P>(&self, dir: P) -> ObjectStreamFuture
     where
         P: TryInto<ObjectPath>,
         P::Error: Into<StorageError>;
 
+    /// Lists just the directories in the given (possibly virtual) directory,
+    /// the common prefixes of the objects beneath it.
+    ///
+    /// This is the same as
+    /// [`list_directory`](trait.StorageBackend.html#tymethod.list_directory)
+    /// without the files, which is what is needed to browse a tree of files.
+    /// Backends that can list the prefixes without the files do so, otherwise
+    /// the files are filtered out of the directory listing.
+    fn list_prefixes<P>(&self, dir: P) -> ObjectStreamFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        directories_only(self.list_directory(dir))
+    }
+
     /// Gets info about the object at the given path.
     ///
     /// This will return a [`NotFound`](enum.StorageErrorKind.html#variant.NotFound)
@@ -88,6 +200,30 @@ pub trait StorageBackend: Clone + Send + 'static {
         P: TryInto<ObjectPath>,
         P::Error: Into<StorageError>;
 
+    /// Gets info about the object at the given path if there is one.
+    ///
+    /// This behaves exactly like
+    /// [`get_object`](trait.StorageBackend.html#tymethod.get_object) except
+    /// that it resolves to `None` rather than returning a
+    /// [`NotFound`](enum.StorageErrorKind.html#variant.NotFound) error when
+    /// no object exists at the given path.
+    fn get_object_opt<P>(&self, path: P) -> OptionalObjectFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        let object = self.get_object(path);
+        OptionalObjectFuture::from_future(async move {
+            match object.await {
+                Ok(object) => Ok(Some(object)),
+                Err(e) => match e.kind() {
+                    StorageErrorKind::NotFound(_) => Ok(None),
+                    _ => Err(e),
+                },
+            }
+        })
+    }
+
     /// Gets a stream of data for the file at the given path.
     ///
     /// The data returned is not necessarily in any particular chunk size.
@@ -101,6 +237,34 @@ pub trait StorageBackend: Clone + Send + 'static {
         P: TryInto<ObjectPath>,
         P::Error: Into<StorageError>;
 
+    /// Gets a stream of data for the file at the given path using the given
+    /// options.
+    ///
+    /// Backends that have no use for the [`ReadOptions`](struct.ReadOptions.html)
+    /// behave exactly the same as
+    /// [`get_file_stream`](trait.StorageBackend.html#tymethod.get_file_stream).
+    fn get_file_stream_with_options<P>(&self, path: P, options: ReadOptions) -> DataStreamFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        let _ = options;
+        self.get_file_stream(path)
+    }
+
+    /// Gets a stream of the bytes within `range` of a file that was returned
+    /// by this backend, either from
+    /// [`get_object`](trait.StorageBackend.html#tymethod.get_object) or a
+    /// listing. The stream ends early if the file is shorter than the range.
+    ///
+    /// Backends use the object's metadata to request just the range without
+    /// looking the file up by path again, B2 for example downloads by the file
+    /// id. Otherwise the file is read from the start and the bytes before the
+    /// range are skipped.
+    fn get_object_range(&self, object: &Object, range: Range<u64>) -> DataStreamFuture {
+        range_stream(self.get_file_stream(object.path()), range)
+    }
+
     /// Copies a file from one path to another within this `Backend`.
     ///
     /// Normally this will be an efficient operation but in some cases it will
@@ -108,7 +272,9 @@ pub trait StorageBackend: Clone + Send + 'static {
     /// location.
     ///
     /// Various properties of the file such as last modification time may not be
-    /// copied to the new file.
+    /// copied to the new file unless
+    /// [`WriteOptions::preserve_modified`](struct.WriteOptions.html#structfield.preserve_modified)
+    /// is set.
     fn copy_file<P, I>(&self, source: P, target: I) -> CopyCompleteFuture
     where
         P: TryInto<ObjectPath>,
@@ -116,8 +282,37 @@ pub trait StorageBackend: Clone + Send + 'static {
         I: TryInto<UploadInfo>,
         I::Error: Into<StorageError>,
     {
-        let source = DataStream::from_stream(self.get_file_stream(source).try_flatten_stream());
-        self.write_file_from_stream(target, source)
+        let source = match source.try_into() {
+            Ok(p) => p,
+            Err(e) => {
+                return CopyCompleteFuture::from_value(Err(TransferError::SourceError(e.into())))
+            }
+        };
+
+        let mut info = match target.try_into() {
+            Ok(i) => i,
+            Err(e) => {
+                return CopyCompleteFuture::from_value(Err(TransferError::TargetError(e.into())))
+            }
+        };
+
+        if !info.options.preserve_modified || info.modified.is_some() {
+            let stream = DataStream::from_stream(self.get_file_stream(source).try_flatten_stream());
+            return self.write_file_from_stream(info, stream);
+        }
+
+        let backend = self.clone();
+        CopyCompleteFuture::from_future(async move {
+            let object = backend
+                .get_object(source.clone())
+                .await
+                .map_err(TransferError::SourceError)?;
+            info.modified = object.modified();
+
+            let stream =
+                DataStream::from_stream(backend.get_file_stream(source).try_flatten_stream());
+            backend.write_file_from_stream(info, stream).await
+        })
     }
 
     /// Moves a file from one path to another within this `Backend`.
@@ -150,6 +345,54 @@ pub trait StorageBackend: Clone + Send + 'static {
         }))
     }
 
+    /// Concatenates the files at the source paths, in order, into the target
+    /// file. The sources are left in place.
+    ///
+    /// Storage services that can join objects without downloading them do
+    /// this server-side, the B2 backend uses B2's part copying when the
+    /// sources are large enough. Otherwise the sources are read one after
+    /// another and streamed through to the target. A source that is also the
+    /// target is opened before the target is written so it is not cleared
+    /// before it is read. Errors reading a source are
+    /// [`SourceError`](enum.TransferError.html#variant.SourceError)s and
+    /// may leave a partial target behind unless
+    /// [`delete_on_failure`](struct.WriteOptions.html#structfield.delete_on_failure)
+    /// is set.
+    fn compose<S, P, I>(&self, sources: S, target: I) -> CopyCompleteFuture
+    where
+        S: IntoIterator<Item = P>,
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+        I: TryInto<UploadInfo>,
+        I::Error: Into<StorageError>,
+    {
+        let mut paths = Vec::new();
+        for source in sources {
+            match source.try_into() {
+                Ok(p) => paths.push(p),
+                Err(e) => {
+                    return CopyCompleteFuture::from_value(Err(TransferError::SourceError(
+                        e.into(),
+                    )))
+                }
+            }
+        }
+
+        let info = match target.try_into() {
+            Ok(i) => i,
+            Err(e) => {
+                return CopyCompleteFuture::from_value(Err(TransferError::TargetError(e.into())))
+            }
+        };
+
+        compose_streams(
+            paths,
+            info,
+            |path| self.get_file_stream(path),
+            |info, stream| self.write_file_from_stream(info, stream),
+        )
+    }
+
     /// Deletes the object at the given path.
     ///
     /// For backends that support physical directories if the object at tbe path
@@ -162,6 +405,28 @@ pub trait StorageBackend: Clone + Send + 'static {
         P: TryInto<ObjectPath>,
         P::Error: Into<StorageError>;
 
+    /// Requests that an archived object is restored so that it can be read
+    /// for the given number of days.
+    ///
+    /// Restoring can take hours on services with cold storage tiers. This
+    /// completes once the request is accepted, after which
+    /// [`ObjectInfo::restore_status`](trait.ObjectInfo.html#method.restore_status)
+    /// reports [`PendingRestore`](enum.RestoreStatus.html#variant.PendingRestore)
+    /// until the object can be read. Backends without cold tiers, which
+    /// includes all those in this crate, can always read their objects so
+    /// this just checks that the object exists.
+    ///
+    /// This will return a [`NotFound`](enum.StorageErrorKind.html#variant.NotFound)
+    /// error if the object does not exist.
+    fn restore_object<P>(&self, path: P, days: u32) -> OperationCompleteFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        let _ = days;
+        OperationCompleteFuture::from_future(self.get_object(path).map_ok(|_| ()))
+    }
+
     /// Writes a stream of data to the file at the given path.
     ///
     /// Calling this will overwrite anything at the given path (notably on
@@ -170,12 +435,14 @@ pub trait StorageBackend: Clone + Send + 'static {
     /// this is that for network based backends not overwriting generally
     /// involves more API calls to check if something is there first. If you
     /// care about overwriting, call [`get_object`](trait.StorageBackend.html#method.get_file)
-    /// first and check the result.
+    /// first and check the result or set the upload's
+    /// [`mode`](types/struct.WriteOptions.html#structfield.mode) to
+    /// [`FailIfExists`](types/enum.WriteMode.html#variant.FailIfExists).
     ///
     /// If this operation fails there are no guarantees about the state of the
-    /// file. If that is an issue then you should consider always calling
-    /// [`delete_object`](trait.StorageBackend.html#method.delete_object) after a
-    /// failure.
+    /// file. If that is an issue then set
+    /// [`delete_on_failure`](types/struct.WriteOptions.html#structfield.delete_on_failure)
+    /// in the upload's options to have the backend clean up after a failure.
     ///
     /// The future returned will only resolve once all the data from the stream
     /// is succesfully written to storage. If the provided stream resolves to
@@ -200,7 +467,8 @@ pub trait StorageBackend: Clone + Send + 'static {
 /// Avoid using the backends directly if you want to keep your code compatible
 /// with all backends.
 ///
-/// You create a `FileStore` from one of the [backend implementations](backends/index.html).
+/// You create a `FileStore` from one of the [backend implementations](backends/index.html)
+/// or from any other backend wrapped in a [`DynamicStore`](dynamic/struct.DynamicStore.html).
 #[allow(clippy::large_enum_variant, missing_docs)]
 #[derive(Clone, Debug)]
 pub enum FileStore {
@@ -210,4 +478,6 @@ pub enum FileStore {
     #[doc(hidden)]
     #[cfg(feature = "b2")]
     B2(B2Backend),
+    #[doc(hidden)]
+    Dynamic(DynamicStore),
 }
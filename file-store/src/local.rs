@@ -0,0 +1,297 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Copying directory trees between the local filesystem and a store.
+//!
+//! [`upload_dir`](fn.upload_dir.html) copies every file beneath a local
+//! directory to the same relative path beneath a prefix of a store and
+//! [`download_dir`](fn.download_dir.html) does the reverse. Copies keep the
+//! modification time of the original.
+//!
+//! Stores other than the file backend have no real directories so an empty
+//! directory cannot be uploaded as it is. When
+//! [`DirOptions::empty_dir_marker`](struct.DirOptions.html#structfield.empty_dir_marker)
+//! is set an empty file with that name is uploaded into each empty directory
+//! and downloading creates the directory instead of the marker file. B2's web
+//! interface uses `.bzEmpty` for this.
+//!
+//! Included with the "file" feature.
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::path::{Path, PathBuf};
+
+use futures::future::TryFutureExt;
+use futures::stream::{iter, StreamExt, TryStreamExt};
+
+use crate::backends::file::FileBackend;
+use crate::sync::directory;
+use crate::types::*;
+use crate::walk::copy_file;
+use crate::{FileStore, ObjectInfo, StorageBackend};
+
+/// The number of files copied at once by default.
+const DEFAULT_CONCURRENCY: usize = 4;
+
+/// Future returned by [`upload_dir`](fn.upload_dir.html) and
+/// [`download_dir`](fn.download_dir.html).
+pub type DirFuture = WrappedFuture<Result<Vec<ObjectPath>, TransferError>>;
+
+/// What to do with symlinks found in a local directory being uploaded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SymlinkPolicy {
+    /// Uploads the file or directory the link points to. Links that lead back
+    /// to a directory already uploaded are not followed again.
+    Follow,
+    /// Ignores symlinks.
+    Skip,
+    /// Fails the upload.
+    Error,
+}
+
+impl Default for SymlinkPolicy {
+    fn default() -> SymlinkPolicy {
+        SymlinkPolicy::Skip
+    }
+}
+
+/// Options controlling an upload or download.
+#[derive(Clone, Debug)]
+pub struct DirOptions {
+    /// The number of files to copy at once. Defaults to 4.
+    pub concurrency: usize,
+    /// What to do with symlinks when uploading. Defaults to skipping them.
+    pub symlinks: SymlinkPolicy,
+    /// The name of the file that stands in for an empty directory. Defaults to
+    /// none, so empty directories are not uploaded.
+    pub empty_dir_marker: Option<String>,
+}
+
+impl Default for DirOptions {
+    fn default() -> DirOptions {
+        DirOptions {
+            concurrency: DEFAULT_CONCURRENCY,
+            symlinks: Default::default(),
+            empty_dir_marker: None,
+        }
+    }
+}
+
+/// Lists everything beneath a directory with paths relative to it.
+async fn list_relative(
+    store: &FileStore,
+    dir: &ObjectPath,
+) -> StorageResult<Vec<(ObjectPath, Object)>> {
+    let skip = dir.parts().len();
+    let mut prefix = dir.clone();
+    if !prefix.is_empty() {
+        prefix.push_part("");
+    }
+
+    let objects: Vec<Object> = store.list_objects(prefix).await?.try_collect().await?;
+    let mut listed = Vec::new();
+    for object in objects {
+        let path = object.path();
+        let parts: Vec<&str> = path.parts().into_iter().filter(|p| !p.is_empty()).collect();
+        if parts.len() > skip {
+            listed.push((ObjectPath::new(parts[skip..].join("/"))?, object));
+        }
+    }
+    Ok(listed)
+}
+
+/// Copies the given files, resolving to their relative paths.
+async fn copy_all(
+    source: FileStore,
+    target: FileStore,
+    target_dir: ObjectPath,
+    files: Vec<(ObjectPath, Object)>,
+    concurrency: usize,
+) -> Result<Vec<ObjectPath>, TransferError> {
+    let tasks = files.into_iter().map(|(relative, object)| {
+        let target_path = target_dir.join(&relative);
+        copy_file(source.clone(), object, target.clone(), target_path).map_ok(move |()| relative)
+    });
+
+    let mut copied: Vec<ObjectPath> = iter(tasks)
+        .buffer_unordered(concurrency.max(1))
+        .try_collect()
+        .await?;
+    copied.sort();
+    Ok(copied)
+}
+
+fn is_marker(path: &ObjectPath, marker: &Option<String>) -> bool {
+    match marker {
+        Some(marker) => path.parts().last() == Some(&marker.as_str()),
+        None => false,
+    }
+}
+
+/// Uploads the files beneath the local directory `local` to `prefix` in
+/// `target`, resolving to the relative paths uploaded.
+///
+/// Empty directories are only uploaded when a marker is configured, their
+/// markers are included in the paths returned. The first failure stops the
+/// upload, files already uploaded are left in place.
+pub fn upload_dir<P>(local: &Path, target: &FileStore, prefix: P, options: DirOptions) -> DirFuture
+where
+    P: TryInto<ObjectPath>,
+    P::Error: Into<StorageError>,
+{
+    async fn run(
+        local: PathBuf,
+        target: FileStore,
+        prefix: ObjectPath,
+        options: DirOptions,
+    ) -> Result<Vec<ObjectPath>, TransferError> {
+        let source = FileBackend::builder(&local)
+            .follow_symlinks(options.symlinks == SymlinkPolicy::Follow)
+            .connect()
+            .await
+            .map_err(TransferError::SourceError)?;
+
+        let listed = list_relative(&source, &ObjectPath::empty())
+            .await
+            .map_err(TransferError::SourceError)?;
+
+        let mut files = Vec::new();
+        let mut directories = Vec::new();
+        let mut parents = HashSet::new();
+        for (relative, object) in listed {
+            let mut parent = relative.clone();
+            parent.pop_part();
+            while !parent.is_empty() && parents.insert(parent.clone()) {
+                parent.pop_part();
+            }
+
+            match object.object_type() {
+                ObjectType::File => files.push((relative, object)),
+                ObjectType::Directory => directories.push(relative),
+                ObjectType::Symlink if options.symlinks == SymlinkPolicy::Error => {
+                    return Err(TransferError::SourceError(error::invalid_path(
+                        relative,
+                        Some("Uploading symlinks is not allowed."),
+                    )));
+                }
+                _ => (),
+            }
+        }
+
+        let mut copied = copy_all(
+            source,
+            target.clone(),
+            prefix.clone(),
+            files,
+            options.concurrency,
+        )
+        .await?;
+
+        if let Some(ref marker) = options.empty_dir_marker {
+            for dir in directories {
+                if parents.contains(&dir) {
+                    continue;
+                }
+
+                let mut relative = dir;
+                relative.push_part(marker);
+                target
+                    .write_file_from_stream(
+                        prefix.join(&relative),
+                        iter(vec![Ok::<Data, StorageError>(Data::new())]),
+                    )
+                    .await?;
+                copied.push(relative);
+            }
+            copied.sort();
+        }
+
+        Ok(copied)
+    }
+
+    let prefix = match prefix.try_into().map_err(Into::into).and_then(directory) {
+        Ok(p) => p,
+        Err(e) => return DirFuture::from_value(Err(TransferError::TargetError(e))),
+    };
+
+    DirFuture::from_future(run(local.to_owned(), target.clone(), prefix, options))
+}
+
+/// Downloads the files beneath `prefix` in `source` to the local directory
+/// `local`, creating it if necessary, resolving to the relative paths
+/// downloaded.
+///
+/// Marker files for empty directories create the directory and are not
+/// included in the paths returned. The first failure stops the download,
+/// files already downloaded are left in place.
+pub fn download_dir<P>(
+    source: &FileStore,
+    prefix: P,
+    local: &Path,
+    options: DirOptions,
+) -> DirFuture
+where
+    P: TryInto<ObjectPath>,
+    P::Error: Into<StorageError>,
+{
+    async fn run(
+        source: FileStore,
+        prefix: ObjectPath,
+        local: PathBuf,
+        options: DirOptions,
+    ) -> Result<Vec<ObjectPath>, TransferError> {
+        tokio_fs::create_dir_all(local.clone())
+            .await
+            .map_err(|e| TransferError::TargetError(e.into()))?;
+        let target = FileBackend::connect(&local)
+            .await
+            .map_err(TransferError::TargetError)?;
+
+        let listed = list_relative(&source, &prefix)
+            .await
+            .map_err(TransferError::SourceError)?;
+
+        let mut files = Vec::new();
+        for (relative, object) in listed {
+            if object.object_type() != ObjectType::File {
+                continue;
+            }
+
+            if is_marker(&relative, &options.empty_dir_marker) {
+                let mut dir = relative;
+                dir.pop_part();
+                tokio_fs::create_dir_all(local.join(dir.to_string()))
+                    .await
+                    .map_err(|e| TransferError::TargetError(e.into()))?;
+            } else {
+                files.push((relative, object));
+            }
+        }
+
+        copy_all(
+            source,
+            target,
+            ObjectPath::empty(),
+            files,
+            options.concurrency,
+        )
+        .await
+    }
+
+    let prefix = match prefix.try_into().map_err(Into::into).and_then(directory) {
+        Ok(p) => p,
+        Err(e) => return DirFuture::from_value(Err(TransferError::SourceError(e))),
+    };
+
+    DirFuture::from_future(run(source.clone(), prefix, local.to_owned(), options))
+}
@@ -0,0 +1,485 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Electing a single owner for a prefix among workers sharing a store.
+//!
+//! A [`LockManager`](struct.LockManager.html) keeps a lock file under a prefix
+//! of the store for every prefix that is locked. Acquiring a lock writes its
+//! file with [`FailIfExists`](../enum.WriteMode.html#variant.FailIfExists) so
+//! only one worker succeeds. The file records who holds the lock and when
+//! their lease expires. The holder keeps the lease alive with
+//! [`renew`](struct.Lease.html#method.renew) or by polling the stream returned
+//! by [`heartbeat`](struct.Lease.html#method.heartbeat) and other workers take
+//! over a lock once its lease has expired.
+//!
+//! Renewals and takeovers write the new lock alongside the old one and then
+//! move it over the lock so the lock never goes missing, on the file backend
+//! this is an atomic rename. A lock that exists but cannot be read yet is one
+//! that another worker is still writing and counts as held.
+//!
+//! Locks are only as strong as the backend's conditional writes. The file
+//! backend creates lock files atomically but other backends check for an
+//! existing lock before writing, so two workers racing for a free or expired
+//! lock may both write it. The lock is read back after writing so the loser of
+//! such a race normally notices, but holders should renew well before their
+//! lease expires and stop working on the prefix as soon as a renewal fails.
+//! Expiry is judged by each worker's own clock so clocks need to roughly
+//! agree.
+use std::str::from_utf8;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::future::TryFutureExt;
+use futures::stream::{iter, unfold, TryStreamExt};
+use log::warn;
+use tokio_timer::delay_for;
+
+use crate::sync::directory;
+use crate::trash::encode;
+use crate::types::*;
+use crate::{FileStore, StorageBackend};
+
+/// Future returned when acquiring a lock.
+pub type LeaseFuture = WrappedFuture<StorageResult<Lease>>;
+/// Future returned by [`LockManager::holder`](struct.LockManager.html#method.holder).
+pub type HolderFuture = WrappedFuture<StorageResult<Option<LockHolder>>>;
+/// Future returned by [`Lease::renew`](struct.Lease.html#method.renew).
+pub type RenewFuture = WrappedFuture<StorageResult<SystemTime>>;
+/// Stream returned by [`Lease::heartbeat`](struct.Lease.html#method.heartbeat).
+pub type HeartbeatStream = WrappedStream<StorageResult<SystemTime>>;
+
+static NEXT_TOKEN: AtomicUsize = AtomicUsize::new(0);
+
+fn millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// The time a lease taken now would expire, rounded to what a lock file can
+/// hold.
+fn expiry(ttl: Duration) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis(SystemTime::now() + ttl))
+}
+
+fn is_not_found(error: &StorageError) -> bool {
+    match error.kind() {
+        StorageErrorKind::NotFound(_) => true,
+        _ => false,
+    }
+}
+
+fn is_already_exists(error: &StorageError) -> bool {
+    match error.kind() {
+        StorageErrorKind::AlreadyExists(_) => true,
+        _ => false,
+    }
+}
+
+fn is_invalid_data(error: &StorageError) -> bool {
+    match error.kind() {
+        StorageErrorKind::InvalidData => true,
+        _ => false,
+    }
+}
+
+/// The contents of a lock file.
+#[derive(Clone, Debug, PartialEq)]
+struct LockFile {
+    token: String,
+    expires: SystemTime,
+    owner: String,
+}
+
+impl LockFile {
+    fn new(owner: &str, ttl: Duration) -> LockFile {
+        let token = format!(
+            "{}-{}-{}",
+            std::process::id(),
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_nanos())
+                .unwrap_or(0),
+            NEXT_TOKEN.fetch_add(1, Ordering::SeqCst)
+        );
+
+        LockFile {
+            token,
+            expires: expiry(ttl),
+            owner: owner.to_owned(),
+        }
+    }
+
+    fn parse(data: &[u8]) -> StorageResult<LockFile> {
+        let text = from_utf8(data)
+            .map_err(|_| error::invalid_data(Some("The lock is not valid UTF-8.")))?;
+        let mut lines = text.splitn(3, '\n');
+        match (
+            lines.next(),
+            lines.next().and_then(|line| line.parse::<u64>().ok()),
+            lines.next(),
+        ) {
+            (Some(token), Some(expires), Some(owner)) => Ok(LockFile {
+                token: token.to_owned(),
+                expires: UNIX_EPOCH + Duration::from_millis(expires),
+                owner: owner.to_owned(),
+            }),
+            _ => Err(error::invalid_data(Some("The lock has an unknown format."))),
+        }
+    }
+
+    fn to_data(&self) -> Data {
+        Data::from(format!(
+            "{}\n{}\n{}",
+            self.token,
+            millis(self.expires),
+            self.owner
+        ))
+    }
+
+    fn is_expired(&self) -> bool {
+        self.expires <= SystemTime::now()
+    }
+}
+
+async fn read_lock(store: &FileStore, path: &ObjectPath) -> StorageResult<Option<LockFile>> {
+    let stream = match store.get_file_stream(path.clone()).await {
+        Ok(stream) => stream,
+        Err(ref e) if is_not_found(e) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let data = stream.map_ok(|data| data.to_vec()).try_concat().await?;
+    LockFile::parse(&data).map(Some)
+}
+
+/// Reads a lock that is being contended. Exclusively created lock files are
+/// empty until their data is written so a lock that cannot be parsed is held
+/// by whoever is writing it.
+async fn read_contended(store: &FileStore, path: &ObjectPath) -> StorageResult<Option<LockFile>> {
+    match read_lock(store, path).await {
+        Err(ref e) if is_invalid_data(e) => Err(error::already_exists(
+            path.clone(),
+            Some("The lock is being written by another owner."),
+        )),
+        result => result,
+    }
+}
+
+async fn write_lock(
+    store: &FileStore,
+    path: &ObjectPath,
+    lock: &LockFile,
+    mode: WriteMode,
+) -> StorageResult<()> {
+    let mut info = UploadInfo::from(path.clone());
+    info.options.mode = mode;
+    store
+        .write_file_from_stream(info, iter(vec![Ok::<Data, StorageError>(lock.to_data())]))
+        .map_err(|e| match e {
+            TransferError::SourceError(e) => e,
+            TransferError::TargetError(e) => e,
+        })
+        .await
+}
+
+/// Replaces the contents of an existing lock. The new lock is written to a
+/// temporary file that is then moved over the lock so other workers never
+/// find it missing or empty.
+async fn replace_lock(store: &FileStore, path: &ObjectPath, lock: &LockFile) -> StorageResult<()> {
+    let mut temp = path.clone();
+    let name = temp.pop_part().unwrap_or_default();
+    temp.push_part(&format!(
+        "{}.{}-{}.tmp",
+        name,
+        lock.token,
+        millis(lock.expires)
+    ));
+
+    write_lock(store, &temp, lock, WriteMode::Overwrite).await?;
+    let result = store
+        .move_file(temp.clone(), path.clone())
+        .map_err(|e| match e {
+            TransferError::SourceError(e) => e,
+            TransferError::TargetError(e) => e,
+        })
+        .await;
+
+    if result.is_err() {
+        if let Err(e) = store.delete_object(temp).await {
+            if !is_not_found(&e) {
+                warn!("Failed to delete the temporary lock: {}", e);
+            }
+        }
+    }
+
+    result
+}
+
+/// Who holds a lock.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LockHolder {
+    owner: String,
+    expires: SystemTime,
+}
+
+impl LockHolder {
+    /// The owner given to the holder's [`LockManager`](struct.LockManager.html).
+    pub fn owner(&self) -> &str {
+        &self.owner
+    }
+
+    /// When the holder's lease expires unless renewed.
+    pub fn expires(&self) -> SystemTime {
+        self.expires
+    }
+
+    /// Whether the lease has expired, in which case anyone may take over the
+    /// lock.
+    pub fn is_expired(&self) -> bool {
+        self.expires <= SystemTime::now()
+    }
+}
+
+/// Acquires locks on prefixes of a store.
+///
+/// See the [`lock`](index.html) module.
+#[derive(Clone, Debug)]
+pub struct LockManager {
+    store: FileStore,
+    prefix: ObjectPath,
+    owner: String,
+    ttl: Duration,
+}
+
+impl LockManager {
+    /// Creates a manager keeping lock files under `prefix` of the store.
+    ///
+    /// `owner` identifies this worker to others looking at a lock and leases
+    /// last for `ttl` after being acquired or renewed.
+    pub fn new(
+        store: FileStore,
+        prefix: ObjectPath,
+        owner: &str,
+        ttl: Duration,
+    ) -> StorageResult<LockManager> {
+        let prefix = directory(prefix)?;
+        if prefix.is_empty() {
+            return Err(error::invalid_path(
+                prefix,
+                Some("Locks cannot be kept at the root of the store."),
+            ));
+        }
+
+        if ttl == Duration::from_secs(0) {
+            return Err(error::invalid_settings(Some(
+                "The lease duration must be longer than zero.",
+            )));
+        }
+
+        Ok(LockManager {
+            store,
+            prefix,
+            owner: owner.to_owned(),
+            ttl,
+        })
+    }
+
+    fn lock_path(&self, locked: ObjectPath) -> StorageResult<ObjectPath> {
+        let mut path = self.prefix.clone();
+        path.push_part(&format!("{}.lock", encode(&directory(locked)?)));
+        Ok(path)
+    }
+
+    /// Tries once to acquire the lock for `prefix`.
+    ///
+    /// Fails with an [`AlreadyExists`](../enum.StorageErrorKind.html#variant.AlreadyExists)
+    /// error if another worker holds a lease that hasn't expired.
+    pub fn try_acquire(&self, prefix: ObjectPath) -> LeaseFuture {
+        let path = match self.lock_path(prefix) {
+            Ok(path) => path,
+            Err(e) => return LeaseFuture::from_value(Err(e)),
+        };
+
+        LeaseFuture::from_future(try_acquire(self.clone(), path))
+    }
+
+    /// Acquires the lock for `prefix`, trying again every `retry` while
+    /// another worker holds it.
+    pub fn acquire(&self, prefix: ObjectPath, retry: Duration) -> LeaseFuture {
+        let path = match self.lock_path(prefix) {
+            Ok(path) => path,
+            Err(e) => return LeaseFuture::from_value(Err(e)),
+        };
+
+        let manager = self.clone();
+        LeaseFuture::from_future(async move {
+            loop {
+                match try_acquire(manager.clone(), path.clone()).await {
+                    Err(ref e) if is_already_exists(e) => delay_for(retry).await,
+                    result => return result,
+                }
+            }
+        })
+    }
+
+    /// Gets whoever holds the lock for `prefix`, including holders whose lease
+    /// has expired.
+    pub fn holder(&self, prefix: ObjectPath) -> HolderFuture {
+        let path = match self.lock_path(prefix) {
+            Ok(path) => path,
+            Err(e) => return HolderFuture::from_value(Err(e)),
+        };
+
+        let store = self.store.clone();
+        HolderFuture::from_future(async move {
+            Ok(read_lock(&store, &path).await?.map(|lock| LockHolder {
+                owner: lock.owner,
+                expires: lock.expires,
+            }))
+        })
+    }
+}
+
+async fn try_acquire(manager: LockManager, path: ObjectPath) -> StorageResult<Lease> {
+    let lock = LockFile::new(&manager.owner, manager.ttl);
+
+    match write_lock(&manager.store, &path, &lock, WriteMode::FailIfExists).await {
+        Ok(()) => (),
+        Err(ref e) if is_already_exists(e) => match read_contended(&manager.store, &path).await? {
+            Some(ref current) if !current.is_expired() => {
+                return Err(error::already_exists(
+                    path,
+                    Some(&format!("The lock is held by {}.", current.owner)),
+                ))
+            }
+            // Take over the expired lease.
+            Some(_) => replace_lock(&manager.store, &path, &lock).await?,
+            // Released since we tried.
+            None => write_lock(&manager.store, &path, &lock, WriteMode::FailIfExists).await?,
+        },
+        Err(e) => return Err(e),
+    }
+
+    // Another worker may have written the lock at the same time.
+    match read_contended(&manager.store, &path).await? {
+        Some(ref current) if current.token == lock.token => Ok(Lease {
+            store: manager.store,
+            path,
+            ttl: manager.ttl,
+            lock: Arc::new(Mutex::new(lock)),
+        }),
+        _ => Err(error::already_exists(
+            path,
+            Some("Another owner acquired the lock at the same time."),
+        )),
+    }
+}
+
+/// A lock that has been acquired.
+///
+/// The lease expires unless it is renewed. Dropping it does not release the
+/// lock, other workers will have to wait for it to expire.
+#[derive(Clone, Debug)]
+pub struct Lease {
+    store: FileStore,
+    path: ObjectPath,
+    ttl: Duration,
+    lock: Arc<Mutex<LockFile>>,
+}
+
+impl Lease {
+    /// The path of the lock file.
+    pub fn path(&self) -> &ObjectPath {
+        &self.path
+    }
+
+    /// When the lease expires unless renewed.
+    pub fn expires(&self) -> SystemTime {
+        self.lock.lock().unwrap().expires
+    }
+
+    /// Extends the lease, resolving to when it will now expire.
+    ///
+    /// Fails with an [`AccessExpired`](../enum.StorageErrorKind.html#variant.AccessExpired)
+    /// error if the lease has already expired or another worker has taken
+    /// over the lock.
+    pub fn renew(&self) -> RenewFuture {
+        RenewFuture::from_future(renew(self.clone()))
+    }
+
+    /// Renews the lease every `interval` for as long as the returned stream
+    /// is polled. Each item is the result of one renewal. The stream ends
+    /// after a renewal finds the lease lost, other failures are retried at
+    /// the next interval.
+    pub fn heartbeat(&self, interval: Duration) -> HeartbeatStream {
+        HeartbeatStream::from_stream(unfold(Some(self.clone()), move |lease| async move {
+            let lease = lease?;
+            delay_for(interval).await;
+
+            match renew(lease.clone()).await {
+                Err(e) => match e.kind() {
+                    StorageErrorKind::AccessExpired => Some((Err(e), None)),
+                    _ => Some((Err(e), Some(lease))),
+                },
+                result => Some((result, Some(lease))),
+            }
+        }))
+    }
+
+    /// Releases the lock so another worker can acquire it straight away.
+    ///
+    /// Does nothing if the lock has already been taken over by another
+    /// worker.
+    pub fn release(self) -> OperationCompleteFuture {
+        OperationCompleteFuture::from_future(release(self))
+    }
+}
+
+async fn renew(lease: Lease) -> StorageResult<SystemTime> {
+    let mut lock = lease.lock.lock().unwrap().clone();
+    if lock.is_expired() {
+        return Err(error::access_expired(Some("The lease has expired.")));
+    }
+
+    match read_lock(&lease.store, &lease.path).await? {
+        Some(ref current) if current.token == lock.token => (),
+        _ => {
+            return Err(error::access_expired(Some(
+                "The lock has been taken over by another owner.",
+            )))
+        }
+    }
+
+    lock.expires = expiry(lease.ttl);
+    replace_lock(&lease.store, &lease.path, &lock).await?;
+
+    let expires = lock.expires;
+    *lease.lock.lock().unwrap() = lock;
+    Ok(expires)
+}
+
+async fn release(lease: Lease) -> StorageResult<()> {
+    let token = lease.lock.lock().unwrap().token.clone();
+    match read_lock(&lease.store, &lease.path).await? {
+        Some(ref current) if current.token == token => {
+            match lease.store.delete_object(lease.path.clone()).await {
+                Err(ref e) if is_not_found(e) => Ok(()),
+                result => result,
+            }
+        }
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,208 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Moving files between stores with verification.
+//!
+//! [`move_between`](fn.move_between.html) copies a file to another store,
+//! checks that the copy has the same size and hash as the source and only
+//! then deletes the source. The source is never deleted unless the copy
+//! verified so a failure at any point leaves the original in place:
+//!
+//! ```no_run
+//! # use file_store::FileStore;
+//! # use file_store::migrate::{move_between, MoveOptions};
+//! # async fn example(source: FileStore, target: FileStore) {
+//! move_between(&source, "photos/cat.jpg", &target, "archive/cat.jpg", MoveOptions::default())
+//!     .await
+//!     .unwrap();
+//! # }
+//! ```
+//!
+//! Hashes come from
+//! [`FileStore::compute_hash`](../enum.FileStore.html#method.compute_hash) so
+//! backends that don't record hashes have their copy read back.
+//!
+//! Included with the "migrate" feature.
+use std::convert::TryInto;
+
+use crate::hash::HashAlgorithm;
+use crate::types::*;
+use crate::{FileStore, ObjectInfo, StorageBackend};
+
+/// What to do with the copy when it doesn't match the source.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnMismatch {
+    /// Deletes the copy leaving just the source.
+    KeepSource,
+    /// Leaves both files in place, for example to inspect the copy.
+    KeepBoth,
+}
+
+impl Default for OnMismatch {
+    fn default() -> OnMismatch {
+        OnMismatch::KeepSource
+    }
+}
+
+/// Options controlling a verified move.
+#[derive(Clone, Debug)]
+pub struct MoveOptions {
+    /// The algorithm used to compare the copy to the source. Defaults to SHA-1
+    /// which B2 records so its files don't need to be read back.
+    pub algorithm: HashAlgorithm,
+    /// What to do when the copy doesn't match the source. Defaults to
+    /// deleting the copy. The source is kept either way.
+    pub on_mismatch: OnMismatch,
+}
+
+impl Default for MoveOptions {
+    fn default() -> MoveOptions {
+        MoveOptions {
+            algorithm: HashAlgorithm::Sha1,
+            on_mismatch: Default::default(),
+        }
+    }
+}
+
+/// Checks that the copy matches the source, returning a description of the
+/// difference if not.
+async fn verify(
+    source: &FileStore,
+    source_object: &Object,
+    target: &FileStore,
+    target_path: &ObjectPath,
+    algorithm: HashAlgorithm,
+) -> Result<Option<String>, TransferError> {
+    let target_object = target
+        .get_object(target_path.clone())
+        .await
+        .map_err(TransferError::TargetError)?;
+    if target_object.len() != source_object.len() {
+        return Ok(Some(format!(
+            "the copy is {} bytes but the source is {} bytes",
+            target_object.len(),
+            source_object.len()
+        )));
+    }
+
+    let source_hash = source
+        .compute_hash(source_object.path(), algorithm)
+        .await
+        .map_err(TransferError::SourceError)?;
+    let target_hash = target
+        .compute_hash(target_path.clone(), algorithm)
+        .await
+        .map_err(TransferError::TargetError)?;
+    if source_hash != target_hash {
+        return Ok(Some(format!(
+            "the copy has hash {} but the source has hash {}",
+            target_hash, source_hash
+        )));
+    }
+
+    Ok(None)
+}
+
+/// Moves a file from one store to another, deleting the source only once the
+/// copy has been verified to have the same size and hash.
+///
+/// A copy that doesn't match fails with an `InvalidData` target error. The
+/// stores can be the same store or use entirely different backends.
+///
+/// See the [`migrate`](index.html) module.
+pub fn move_between<P, Q>(
+    source: &FileStore,
+    source_path: P,
+    target: &FileStore,
+    target_path: Q,
+    options: MoveOptions,
+) -> MoveCompleteFuture
+where
+    P: TryInto<ObjectPath>,
+    P::Error: Into<StorageError>,
+    Q: TryInto<ObjectPath>,
+    Q::Error: Into<StorageError>,
+{
+    async fn run(
+        source: FileStore,
+        source_path: ObjectPath,
+        target: FileStore,
+        target_path: ObjectPath,
+        options: MoveOptions,
+    ) -> Result<(), TransferError> {
+        let source_object = source
+            .get_object(source_path.clone())
+            .await
+            .map_err(TransferError::SourceError)?;
+        if source_object.object_type() != ObjectType::File {
+            return Err(TransferError::SourceError(error::not_found(
+                source_path,
+                Some("Only files can be moved."),
+            )));
+        }
+
+        let mut info = UploadInfo::from(target_path.clone());
+        info.modified = source_object.modified();
+        info.options.content_length = Some(source_object.len());
+        let stream = source
+            .get_file_stream(source_path.clone())
+            .await
+            .map_err(TransferError::SourceError)?;
+        target.write_file_from_stream(info, stream).await?;
+
+        let mismatch = verify(
+            &source,
+            &source_object,
+            &target,
+            &target_path,
+            options.algorithm,
+        )
+        .await?;
+        if let Some(mismatch) = mismatch {
+            if options.on_mismatch == OnMismatch::KeepSource {
+                target
+                    .delete_object(target_path)
+                    .await
+                    .map_err(TransferError::TargetError)?;
+            }
+
+            return Err(TransferError::TargetError(error::invalid_data(Some(
+                &format!("The copy of {} did not verify, {}.", source_path, mismatch),
+            ))));
+        }
+
+        source
+            .delete_object(source_path)
+            .await
+            .map_err(TransferError::SourceError)
+    }
+
+    let source_path = match source_path.try_into() {
+        Ok(p) => p,
+        Err(e) => return MoveCompleteFuture::from_value(Err(TransferError::SourceError(e.into()))),
+    };
+
+    let target_path = match target_path.try_into() {
+        Ok(p) => p,
+        Err(e) => return MoveCompleteFuture::from_value(Err(TransferError::TargetError(e.into()))),
+    };
+
+    MoveCompleteFuture::from_future(run(
+        source.clone(),
+        source_path,
+        target.clone(),
+        target_path,
+        options,
+    ))
+}
@@ -0,0 +1,1213 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Exposes a [`FileStore`](../enum.FileStore.html) as a FUSE filesystem.
+//!
+//! This is intended for browsing storage with normal tools during development
+//! and debugging. Files can be read, created, overwritten, renamed and deleted.
+//! Directories only exist while they contain files, as is the case for most
+//! backends, so a directory made through the mount is only known to the mount
+//! until files are written into it. Renaming a directory moves every file
+//! inside it one at a time so is not atomic.
+//!
+//! Attributes and directory listings are cached for a short time, see
+//! [`MountOptions`](struct.MountOptions.html). Files opened for writing are
+//! held in memory and uploaded when they are closed, writes that would grow a
+//! file past [`max_file_size`](struct.MountOptions.html#structfield.max_file_size)
+//! fail with `EFBIG`.
+//!
+//! Included with the "mount" feature.
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::io::{self, Cursor, Read};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use fuse::{
+    FileAttr, FileType, Filesystem, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty,
+    ReplyEntry, ReplyOpen, ReplyWrite, Request,
+};
+use libc::{
+    c_int, EACCES, EEXIST, EFBIG, EINVAL, EIO, EISDIR, ENOENT, ENOSPC, ENOTDIR, ENOTEMPTY,
+    O_ACCMODE, O_EXCL, O_RDONLY, O_TRUNC,
+};
+use log::{error, trace};
+use time::Timespec;
+
+use crate::blocking::{BlockingFileStore, FileReader};
+use crate::types::*;
+use crate::FileStore;
+
+const ROOT_INODE: u64 = 1;
+
+/// Options controlling how a store is mounted.
+#[derive(Clone, Debug)]
+pub struct MountOptions {
+    /// How long file attributes are cached for.
+    pub attribute_ttl: Duration,
+    /// How long directory listings are cached for.
+    pub directory_ttl: Duration,
+    /// Additional options passed to FUSE, e.g. `-o ro`.
+    pub fuse_options: Vec<String>,
+    /// The largest file that can be written. Files being written are held in
+    /// memory so this bounds the memory used by each open file. Defaults to
+    /// 1GB.
+    pub max_file_size: u64,
+}
+
+impl Default for MountOptions {
+    fn default() -> MountOptions {
+        MountOptions {
+            attribute_ttl: Duration::from_secs(1),
+            directory_ttl: Duration::from_secs(5),
+            fuse_options: Vec::new(),
+            max_file_size: 1024 * 1024 * 1024,
+        }
+    }
+}
+
+/// Mounts the store at the given mountpoint.
+///
+/// This blocks until the filesystem is unmounted.
+pub fn mount(store: FileStore, mountpoint: &Path, options: MountOptions) -> io::Result<()> {
+    let fuse_options: Vec<String> = options.fuse_options.clone();
+    let fs = StoreFilesystem::new(BlockingFileStore::new(store)?, options);
+    let args: Vec<&OsStr> = fuse_options.iter().map(OsStr::new).collect();
+    fuse::mount(fs, &mountpoint, &args)
+}
+
+fn errno(error: &StorageError) -> c_int {
+    match error.kind() {
+        StorageErrorKind::NotFound(_) => ENOENT,
+        StorageErrorKind::AlreadyExists(_) => EEXIST,
+        StorageErrorKind::AccessDenied | StorageErrorKind::AccessExpired => EACCES,
+        StorageErrorKind::InvalidPath(_) | StorageErrorKind::ObjectPathParse(_) => EINVAL,
+        StorageErrorKind::InsufficientSpace => ENOSPC,
+        _ => EIO,
+    }
+}
+
+fn transfer_errno(error: &TransferError) -> c_int {
+    match error {
+        TransferError::SourceError(e) => errno(e),
+        TransferError::TargetError(e) => errno(e),
+    }
+}
+
+fn parent_path(path: &ObjectPath) -> ObjectPath {
+    let mut parent = path.clone();
+    parent.pop_part();
+    parent
+}
+
+/// Maps a path at or inside `from` to the same place inside `to`.
+fn rebase(path: &ObjectPath, from: &ObjectPath, to: &ObjectPath) -> Option<ObjectPath> {
+    if path == from {
+        return Some(to.clone());
+    }
+
+    let path = path.to_string();
+    let from = format!("{}/", from);
+    if path.starts_with(&from) {
+        ObjectPath::new(format!("{}/{}", to, &path[from.len()..])).ok()
+    } else {
+        None
+    }
+}
+
+/// Resizes the contents of a file being written, failing if it would grow
+/// past `limit`.
+fn resize(buffer: &mut Vec<u8>, size: u64, limit: u64) -> Result<(), c_int> {
+    if size > limit {
+        return Err(EFBIG);
+    }
+
+    buffer.resize(size as usize, 0);
+    Ok(())
+}
+
+/// Writes data into the contents of a file being written, growing it if
+/// needed.
+fn write_at(buffer: &mut Vec<u8>, offset: u64, data: &[u8], limit: u64) -> Result<(), c_int> {
+    let end = offset.checked_add(data.len() as u64).ok_or(EFBIG)?;
+    if end > buffer.len() as u64 {
+        resize(buffer, end, limit)?;
+    }
+
+    let offset = offset as usize;
+    buffer[offset..offset + data.len()].copy_from_slice(data);
+    Ok(())
+}
+
+fn timespec(time: SystemTime) -> Timespec {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => Timespec::new(d.as_secs() as i64, d.subsec_nanos() as i32),
+        Err(_) => Timespec::new(0, 0),
+    }
+}
+
+struct Entry {
+    name: String,
+    inode: u64,
+    kind: FileType,
+}
+
+struct Handle {
+    inode: u64,
+    path: ObjectPath,
+    /// The reader for a file opened for reading and its position.
+    reader: Option<(FileReader, u64)>,
+    /// The contents of a file opened for writing.
+    buffer: Option<Vec<u8>>,
+    dirty: bool,
+}
+
+struct StoreFilesystem {
+    store: BlockingFileStore,
+    options: MountOptions,
+    uid: u32,
+    gid: u32,
+    next_inode: u64,
+    paths: HashMap<u64, ObjectPath>,
+    inodes: HashMap<ObjectPath, u64>,
+    attributes: HashMap<u64, (FileAttr, Instant)>,
+    directories: HashMap<u64, (Vec<Entry>, Instant)>,
+    /// Directories made through the mount that may not exist in the store.
+    new_directories: HashSet<ObjectPath>,
+    next_handle: u64,
+    handles: HashMap<u64, Handle>,
+}
+
+impl StoreFilesystem {
+    fn new(store: BlockingFileStore, options: MountOptions) -> StoreFilesystem {
+        let mut fs = StoreFilesystem {
+            store,
+            options,
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+            next_inode: ROOT_INODE + 1,
+            paths: HashMap::new(),
+            inodes: HashMap::new(),
+            attributes: HashMap::new(),
+            directories: HashMap::new(),
+            new_directories: HashSet::new(),
+            next_handle: 1,
+            handles: HashMap::new(),
+        };
+
+        fs.paths.insert(ROOT_INODE, ObjectPath::empty());
+        fs.inodes.insert(ObjectPath::empty(), ROOT_INODE);
+        fs
+    }
+
+    fn ttl(&self) -> Timespec {
+        let ttl = self.options.attribute_ttl;
+        Timespec::new(ttl.as_secs() as i64, ttl.subsec_nanos() as i32)
+    }
+
+    fn inode(&mut self, path: &ObjectPath) -> u64 {
+        if let Some(inode) = self.inodes.get(path) {
+            return *inode;
+        }
+
+        let inode = self.next_inode;
+        self.next_inode += 1;
+        self.paths.insert(inode, path.clone());
+        self.inodes.insert(path.clone(), inode);
+        inode
+    }
+
+    fn child_path(&self, parent: u64, name: &OsStr) -> Result<ObjectPath, c_int> {
+        let mut path = self.paths.get(&parent).cloned().ok_or(ENOENT)?;
+        path.push_part(name.to_str().ok_or(EINVAL)?);
+        Ok(path)
+    }
+
+    fn attr(
+        &self,
+        inode: u64,
+        kind: FileType,
+        size: u64,
+        modified: Option<SystemTime>,
+    ) -> FileAttr {
+        let time = timespec(modified.unwrap_or(UNIX_EPOCH));
+        FileAttr {
+            ino: inode,
+            size,
+            blocks: (size + 511) / 512,
+            atime: time,
+            mtime: time,
+            ctime: time,
+            crtime: time,
+            kind,
+            perm: if kind == FileType::Directory {
+                0o755
+            } else {
+                0o644
+            },
+            nlink: if kind == FileType::Directory { 2 } else { 1 },
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            flags: 0,
+        }
+    }
+
+    fn object_attr(&mut self, object: &Object) -> FileAttr {
+        let inode = self.inode(&object.path());
+        let kind = match object.object_type() {
+            ObjectType::Directory => FileType::Directory,
+            ObjectType::Symlink => FileType::Symlink,
+            _ => FileType::RegularFile,
+        };
+
+        let attr = self.attr(inode, kind, object.len(), object.modified());
+        self.attributes.insert(inode, (attr, Instant::now()));
+        attr
+    }
+
+    fn invalidate_parent(&mut self, path: &ObjectPath) {
+        let parent = parent_path(path);
+        if let Some(inode) = self.inodes.get(&parent).cloned() {
+            self.directories.remove(&inode);
+        }
+    }
+
+    fn list(&mut self, inode: u64) -> Result<&Vec<Entry>, c_int> {
+        let fresh = match self.directories.get(&inode) {
+            Some((_, time)) => time.elapsed() < self.options.directory_ttl,
+            None => false,
+        };
+
+        if !fresh {
+            let path = self.paths.get(&inode).cloned().ok_or(ENOENT)?;
+            trace!("Listing directory {}", path);
+
+            let mut entries = Vec::new();
+            let objects = self
+                .store
+                .list_directory(path.clone())
+                .map_err(|e| errno(&e))?;
+            for object in objects {
+                let object = object.map_err(|e| errno(&e))?;
+                let attr = self.object_attr(&object);
+                entries.push(Entry {
+                    name: object
+                        .path()
+                        .parts()
+                        .last()
+                        .cloned()
+                        .unwrap_or("")
+                        .to_owned(),
+                    inode: attr.ino,
+                    kind: attr.kind,
+                });
+            }
+
+            let created: Vec<ObjectPath> = self
+                .new_directories
+                .iter()
+                .filter(|dir| parent_path(dir) == path)
+                .cloned()
+                .collect();
+            for dir in created {
+                let name = dir.parts().last().cloned().unwrap_or("").to_owned();
+                if entries.iter().any(|entry| entry.name == name) {
+                    continue;
+                }
+
+                entries.push(Entry {
+                    name,
+                    inode: self.inode(&dir),
+                    kind: FileType::Directory,
+                });
+            }
+
+            self.directories.insert(inode, (entries, Instant::now()));
+        }
+
+        Ok(&self.directories[&inode].0)
+    }
+
+    fn lookup_inner(&mut self, parent: u64, name: &OsStr) -> Result<FileAttr, c_int> {
+        let name = name.to_str().ok_or(ENOENT)?;
+        let inode = self
+            .list(parent)?
+            .iter()
+            .find(|entry| entry.name == name)
+            .map(|entry| entry.inode)
+            .ok_or(ENOENT)?;
+        self.getattr_inner(inode)
+    }
+
+    fn getattr_inner(&mut self, inode: u64) -> Result<FileAttr, c_int> {
+        if inode == ROOT_INODE {
+            return Ok(self.attr(ROOT_INODE, FileType::Directory, 0, None));
+        }
+
+        // Files being written report their current size.
+        for handle in self.handles.values() {
+            if let (true, Some(buffer)) = (handle.inode == inode, handle.buffer.as_ref()) {
+                let size = buffer.len() as u64;
+                return Ok(self.attr(inode, FileType::RegularFile, size, Some(SystemTime::now())));
+            }
+        }
+
+        if let Some((attr, time)) = self.attributes.get(&inode) {
+            if time.elapsed() < self.options.attribute_ttl {
+                return Ok(*attr);
+            }
+        }
+
+        let path = self.paths.get(&inode).cloned().ok_or(ENOENT)?;
+        if self.new_directories.contains(&path) {
+            return Ok(self.attr(inode, FileType::Directory, 0, None));
+        }
+
+        match self.store.get_object(path.clone()) {
+            Ok(object) => Ok(self.object_attr(&object)),
+            Err(e) => {
+                // Virtual directories don't exist as objects, look for them in
+                // the parent listing.
+                let parent = self.inode(&parent_path(&path));
+                self.directories.remove(&parent);
+                let found = self.list(parent)?.iter().any(|entry| entry.inode == inode);
+                match (found, self.attributes.get(&inode)) {
+                    (true, Some((attr, _))) => Ok(*attr),
+                    _ => Err(errno(&e)),
+                }
+            }
+        }
+    }
+
+    fn open_handle(
+        &mut self,
+        inode: u64,
+        path: ObjectPath,
+        buffer: Option<Vec<u8>>,
+        dirty: bool,
+    ) -> u64 {
+        let handle = self.next_handle;
+        self.next_handle += 1;
+        self.handles.insert(
+            handle,
+            Handle {
+                inode,
+                path,
+                reader: None,
+                buffer,
+                dirty,
+            },
+        );
+        handle
+    }
+
+    fn upload(&mut self, handle: u64) -> Result<(), c_int> {
+        let (path, data) = match self.handles.get_mut(&handle) {
+            Some(h) if h.dirty => {
+                h.dirty = false;
+                (h.path.clone(), h.buffer.clone().unwrap_or_default())
+            }
+            Some(_) => return Ok(()),
+            None => return Err(EINVAL),
+        };
+
+        trace!("Uploading {} bytes to {}", data.len(), path);
+        let inode = self.inode(&path);
+        self.attributes.remove(&inode);
+        self.invalidate_parent(&path);
+        self.store.write_file(path, Cursor::new(data)).map_err(|e| {
+            error!("Failed to upload file: {}", e);
+            transfer_errno(&e)
+        })
+    }
+
+    fn truncate(&mut self, ino: u64, fh: Option<u64>, size: u64) -> Result<(), c_int> {
+        let handle = match fh {
+            Some(fh) if self.handles.contains_key(&fh) => fh,
+            _ if size == 0 => match self.paths.get(&ino).cloned() {
+                Some(path) => self.open_handle(ino, path, Some(Vec::new()), true),
+                None => return Err(ENOENT),
+            },
+            _ => return Err(EINVAL),
+        };
+
+        let limit = self.options.max_file_size;
+        let result = match self.handles.get_mut(&handle) {
+            Some(Handle {
+                buffer: Some(buffer),
+                dirty,
+                ..
+            }) => resize(buffer, size, limit).map(|()| *dirty = true),
+            _ => Err(EACCES),
+        };
+
+        // Truncating a file that isn't open uploads it immediately.
+        if Some(handle) != fh {
+            let result = result.and_then(|()| self.upload(handle));
+            self.handles.remove(&handle);
+            result
+        } else {
+            result
+        }
+    }
+
+    fn open_inner(&mut self, ino: u64, flags: u32) -> Result<u64, c_int> {
+        let path = self.paths.get(&ino).cloned().ok_or(ENOENT)?;
+
+        let flags = flags as c_int;
+        let buffer = if flags & O_ACCMODE == O_RDONLY {
+            None
+        } else if flags & O_TRUNC != 0 {
+            Some(Vec::new())
+        } else {
+            let limit = self.options.max_file_size;
+            let mut data = Vec::new();
+            self.store
+                .get_file_reader(path.clone())
+                .map_err(|e| errno(&e))?
+                .take(limit.saturating_add(1))
+                .read_to_end(&mut data)
+                .map_err(|_| EIO)?;
+            if data.len() as u64 > limit {
+                return Err(EFBIG);
+            }
+            Some(data)
+        };
+
+        Ok(self.open_handle(ino, path, buffer, flags & O_TRUNC != 0))
+    }
+
+    fn write_inner(&mut self, fh: u64, offset: i64, data: &[u8]) -> Result<(), c_int> {
+        let limit = self.options.max_file_size;
+        let handle = self.handles.get_mut(&fh).ok_or(EINVAL)?;
+        let buffer = handle.buffer.as_mut().ok_or(EACCES)?;
+        if offset < 0 {
+            return Err(EINVAL);
+        }
+
+        write_at(buffer, offset as u64, data, limit)?;
+        handle.dirty = true;
+        Ok(())
+    }
+
+    fn read_inner(&mut self, fh: u64, offset: i64, size: u32) -> Result<Vec<u8>, c_int> {
+        let offset = offset as u64;
+        let store = self.store.clone();
+        let handle = self.handles.get_mut(&fh).ok_or(EINVAL)?;
+
+        if let Some(buffer) = handle.buffer.as_ref() {
+            let start = (offset as usize).min(buffer.len());
+            let end = (start + size as usize).min(buffer.len());
+            return Ok(buffer[start..end].to_vec());
+        }
+
+        // Reads are normally sequential, only reopen the file when seeking
+        // backwards.
+        let reopen = match handle.reader {
+            Some((_, position)) => position > offset,
+            None => true,
+        };
+
+        if reopen {
+            let reader = store
+                .get_file_reader(handle.path.clone())
+                .map_err(|e| errno(&e))?;
+            handle.reader = Some((reader, 0));
+        }
+
+        let (reader, position) = handle.reader.as_mut().unwrap();
+        if *position < offset {
+            let skip = offset - *position;
+            *position +=
+                io::copy(&mut reader.by_ref().take(skip), &mut io::sink()).map_err(|_| EIO)?;
+        }
+
+        let mut data = Vec::with_capacity(size as usize);
+        let count = reader
+            .by_ref()
+            .take(u64::from(size))
+            .read_to_end(&mut data)
+            .map_err(|_| EIO)?;
+        *position += count as u64;
+        Ok(data)
+    }
+
+    fn create_inner(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        flags: u32,
+    ) -> Result<(u64, FileAttr), c_int> {
+        let path = self.child_path(parent, name)?;
+
+        // The cached listing may be stale, look for the file again before
+        // deciding whether it already exists.
+        self.directories.remove(&parent);
+        match self.lookup_inner(parent, name) {
+            Ok(_) if flags as c_int & O_EXCL != 0 => return Err(EEXIST),
+            Ok(ref attr) if attr.kind == FileType::Directory => return Err(EISDIR),
+            Ok(attr) => {
+                let handle = self.open_inner(attr.ino, flags)?;
+                return Ok((handle, self.getattr_inner(attr.ino)?));
+            }
+            Err(ENOENT) => (),
+            Err(e) => return Err(e),
+        }
+
+        let inode = self.inode(&path);
+        let handle = self.open_handle(inode, path, Some(Vec::new()), true);
+        if let Err(e) = self.upload(handle) {
+            self.handles.remove(&handle);
+            return Err(e);
+        }
+
+        let attr = self.attr(inode, FileType::RegularFile, 0, Some(SystemTime::now()));
+        Ok((handle, attr))
+    }
+
+    fn release_inner(&mut self, fh: u64) -> Result<(), c_int> {
+        let result = self.upload(fh);
+        self.handles.remove(&fh);
+        result
+    }
+
+    fn mkdir_inner(&mut self, parent: u64, name: &OsStr) -> Result<FileAttr, c_int> {
+        let path = self.child_path(parent, name)?;
+        let name = name.to_str().ok_or(EINVAL)?;
+        if self.list(parent)?.iter().any(|entry| entry.name == name) {
+            return Err(EEXIST);
+        }
+
+        let inode = self.inode(&path);
+        self.new_directories.insert(path);
+        self.directories.remove(&parent);
+        Ok(self.attr(inode, FileType::Directory, 0, None))
+    }
+
+    fn rmdir_inner(&mut self, parent: u64, name: &OsStr) -> Result<(), c_int> {
+        let path = self.child_path(parent, name)?;
+        let inode = self.inode(&path);
+        if self.getattr_inner(inode)?.kind != FileType::Directory {
+            return Err(ENOTDIR);
+        }
+
+        self.directories.remove(&inode);
+        if !self.list(inode)?.is_empty() {
+            return Err(ENOTEMPTY);
+        }
+
+        self.directories.remove(&parent);
+        self.new_directories.remove(&path);
+        match self.store.delete_object(path) {
+            // Virtual directories disappear once they are empty.
+            Ok(()) => Ok(()),
+            Err(ref e) if errno(e) == ENOENT => Ok(()),
+            Err(e) => Err(errno(&e)),
+        }
+    }
+
+    /// Moves every file inside a directory. The store has no notion of
+    /// directories so each file is moved individually.
+    fn move_directory(&mut self, source: &ObjectPath, target: &ObjectPath) -> Result<(), c_int> {
+        let prefix = ObjectPath::new(format!("{}/", source)).map_err(|e| errno(&e))?;
+        let objects = self
+            .store
+            .list_objects(prefix)
+            .map_err(|e| errno(&e))?
+            .collect::<StorageResult<Vec<Object>>>()
+            .map_err(|e| errno(&e))?;
+
+        for object in objects {
+            if object.object_type() == ObjectType::Directory {
+                continue;
+            }
+
+            let path = object.path();
+            let moved = rebase(&path, source, target).ok_or(EINVAL)?;
+            self.store
+                .move_file(path, moved)
+                .map_err(|e| transfer_errno(&e))?;
+        }
+
+        match self.store.delete_object(source.clone()) {
+            Ok(()) => Ok(()),
+            Err(ref e) if errno(e) == ENOENT => Ok(()),
+            Err(e) => Err(errno(&e)),
+        }
+    }
+
+    /// Points every inode, handle and new directory at or inside `source` at
+    /// the same place inside `target`.
+    fn remap(&mut self, source: &ObjectPath, target: &ObjectPath) {
+        if let Some(replaced) = self.inodes.remove(target) {
+            self.paths.remove(&replaced);
+            self.attributes.remove(&replaced);
+            self.directories.remove(&replaced);
+        }
+
+        let moved: Vec<(u64, ObjectPath)> = self
+            .paths
+            .iter()
+            .filter_map(|(inode, path)| rebase(path, source, target).map(|p| (*inode, p)))
+            .collect();
+        for (inode, path) in moved {
+            if let Some(old) = self.paths.insert(inode, path.clone()) {
+                self.inodes.remove(&old);
+            }
+            self.inodes.insert(path, inode);
+            self.attributes.remove(&inode);
+            self.directories.remove(&inode);
+        }
+
+        self.new_directories.remove(target);
+        let created: Vec<ObjectPath> = self
+            .new_directories
+            .iter()
+            .filter(|dir| rebase(dir, source, target).is_some())
+            .cloned()
+            .collect();
+        for dir in created {
+            self.new_directories.remove(&dir);
+            if let Some(moved) = rebase(&dir, source, target) {
+                self.new_directories.insert(moved);
+            }
+        }
+
+        for handle in self.handles.values_mut() {
+            if let Some(moved) = rebase(&handle.path, source, target) {
+                handle.path = moved;
+            }
+        }
+    }
+
+    fn rename_inner(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+    ) -> Result<(), c_int> {
+        let source = self.child_path(parent, name)?;
+        let target = self.child_path(newparent, newname)?;
+        let kind = self.lookup_inner(parent, name)?.kind;
+        if source == target {
+            return Ok(());
+        }
+
+        if kind == FileType::Directory && rebase(&target, &source, &target).is_some() {
+            // A directory cannot be moved inside itself.
+            return Err(EINVAL);
+        }
+
+        self.directories.remove(&newparent);
+        match self.lookup_inner(newparent, newname) {
+            Ok(ref attr) if attr.kind == FileType::Directory => {
+                if kind != FileType::Directory {
+                    return Err(EISDIR);
+                }
+
+                self.directories.remove(&attr.ino);
+                if !self.list(attr.ino)?.is_empty() {
+                    return Err(ENOTEMPTY);
+                }
+            }
+            Ok(_) if kind == FileType::Directory => return Err(ENOTDIR),
+            Ok(_) | Err(ENOENT) => (),
+            Err(e) => return Err(e),
+        }
+
+        self.directories.remove(&parent);
+        self.directories.remove(&newparent);
+
+        if kind == FileType::Directory {
+            self.move_directory(&source, &target)?;
+        } else {
+            self.store
+                .move_file(source.clone(), target.clone())
+                .map_err(|e| transfer_errno(&e))?;
+        }
+
+        self.remap(&source, &target);
+        if kind == FileType::Directory {
+            // Keep the directory visible even if it had no files to move.
+            self.new_directories.insert(target);
+        }
+        Ok(())
+    }
+}
+
+impl Filesystem for StoreFilesystem {
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        match self.lookup_inner(parent, name) {
+            Ok(attr) => reply.entry(&self.ttl(), &attr, 0),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        match self.getattr_inner(ino) {
+            Ok(attr) => reply.attr(&self.ttl(), &attr),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn setattr(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        _mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<Timespec>,
+        _mtime: Option<Timespec>,
+        fh: Option<u64>,
+        _crtime: Option<Timespec>,
+        _chgtime: Option<Timespec>,
+        _bkuptime: Option<Timespec>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        // Only truncation is supported.
+        if let Some(size) = size {
+            if let Err(e) = self.truncate(ino, fh, size) {
+                return reply.error(e);
+            }
+        }
+
+        self.getattr(req, ino, reply)
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let parent = match self.paths.get(&ino).cloned() {
+            Some(mut p) => {
+                p.pop_part();
+                self.inode(&p)
+            }
+            None => return reply.error(ENOENT),
+        };
+
+        let entries: Vec<(u64, FileType, String)> = match self.list(ino) {
+            Ok(entries) => entries
+                .iter()
+                .map(|e| (e.inode, e.kind, e.name.clone()))
+                .collect(),
+            Err(e) => return reply.error(e),
+        };
+
+        let all = vec![
+            (ino, FileType::Directory, ".".to_owned()),
+            (parent, FileType::Directory, "..".to_owned()),
+        ]
+        .into_iter()
+        .chain(entries.into_iter());
+
+        for (i, (inode, kind, name)) in all.enumerate().skip(offset as usize) {
+            if reply.add(inode, (i + 1) as i64, kind, name) {
+                break;
+            }
+        }
+
+        reply.ok();
+    }
+
+    fn open(&mut self, _req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+        match self.open_inner(ino, flags) {
+            Ok(handle) => reply.opened(handle, flags),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        reply: ReplyData,
+    ) {
+        match self.read_inner(fh, offset, size) {
+            Ok(data) => reply.data(&data),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        _flags: u32,
+        reply: ReplyWrite,
+    ) {
+        match self.write_inner(fh, offset, data) {
+            Ok(()) => reply.written(data.len() as u32),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn flush(&mut self, _req: &Request, _ino: u64, fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        match self.upload(fh) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request,
+        _ino: u64,
+        fh: u64,
+        _flags: u32,
+        _lock_owner: u64,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        match self.release_inner(fh) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn create(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        _mode: u32,
+        flags: u32,
+        reply: ReplyCreate,
+    ) {
+        match self.create_inner(parent, name, flags) {
+            Ok((handle, attr)) => reply.created(&self.ttl(), &attr, 0, handle, flags),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn mkdir(&mut self, _req: &Request, parent: u64, name: &OsStr, _mode: u32, reply: ReplyEntry) {
+        match self.mkdir_inner(parent, name) {
+            Ok(attr) => reply.entry(&self.ttl(), &attr, 0),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let path = match self.child_path(parent, name) {
+            Ok(p) => p,
+            Err(e) => return reply.error(e),
+        };
+
+        let inode = self.inode(&path);
+        match self.getattr_inner(inode) {
+            Ok(attr) if attr.kind == FileType::Directory => return reply.error(EISDIR),
+            Ok(_) => (),
+            Err(e) => return reply.error(e),
+        }
+
+        self.attributes.remove(&inode);
+        self.directories.remove(&parent);
+        match self.store.delete_object(path) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.rmdir_inner(parent, name) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn rename(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEmpty,
+    ) {
+        match self.rename_inner(parent, name, newparent, newname) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_at() {
+        let mut buffer = Vec::new();
+        assert_eq!(write_at(&mut buffer, 0, b"hello", 10), Ok(()));
+        assert_eq!(buffer, b"hello");
+
+        // Writes past the end fill the gap with zeroes.
+        assert_eq!(write_at(&mut buffer, 7, b"ab", 10), Ok(()));
+        assert_eq!(buffer, b"hello\0\0ab");
+
+        assert_eq!(write_at(&mut buffer, 1, b"EL", 10), Ok(()));
+        assert_eq!(buffer, b"hELlo\0\0ab");
+
+        // Writes may fill the file up to the limit but not past it.
+        assert_eq!(write_at(&mut buffer, 9, b"c", 10), Ok(()));
+        assert_eq!(write_at(&mut buffer, 9, b"cd", 10), Err(EFBIG));
+        assert_eq!(
+            write_at(&mut buffer, u64::max_value(), b"a", 10),
+            Err(EFBIG)
+        );
+        assert_eq!(buffer, b"hELlo\0\0abc");
+    }
+
+    #[test]
+    fn test_resize() {
+        let mut buffer = b"hello".to_vec();
+        assert_eq!(resize(&mut buffer, 2, 10), Ok(()));
+        assert_eq!(buffer, b"he");
+        assert_eq!(resize(&mut buffer, 4, 10), Ok(()));
+        assert_eq!(buffer, b"he\0\0");
+        assert_eq!(resize(&mut buffer, 11, 10), Err(EFBIG));
+        assert_eq!(buffer, b"he\0\0");
+    }
+
+    #[test]
+    fn test_rebase() {
+        let path = |p: &str| ObjectPath::new(p).unwrap();
+        let (from, to) = (path("a/b"), path("c"));
+        assert_eq!(rebase(&path("a/b"), &from, &to), Some(path("c")));
+        assert_eq!(rebase(&path("a/b/d/e"), &from, &to), Some(path("c/d/e")));
+        assert_eq!(rebase(&path("a/bc"), &from, &to), None);
+        assert_eq!(rebase(&path("a"), &from, &to), None);
+    }
+
+    #[cfg(feature = "file")]
+    mod store {
+        use std::fs::{create_dir_all, read_to_string, write};
+
+        use libc::{O_CREAT, O_RDWR, O_WRONLY};
+        use tempfile::{tempdir, TempDir};
+
+        use super::super::*;
+        use crate::backends::file::FileBackend;
+
+        fn mounted() -> (TempDir, StoreFilesystem) {
+            let dir = tempdir().unwrap();
+            create_dir_all(dir.path().join("dir/sub")).unwrap();
+            write(dir.path().join("dir/file.txt"), "Hello world").unwrap();
+            write(dir.path().join("dir/sub/deep.txt"), "Deep").unwrap();
+            write(dir.path().join("top.txt"), "Top").unwrap();
+
+            let store = BlockingFileStore::connect(FileBackend::connect(dir.path())).unwrap();
+            let fs = StoreFilesystem::new(store, MountOptions::default());
+            (dir, fs)
+        }
+
+        fn name(name: &str) -> &OsStr {
+            OsStr::new(name)
+        }
+
+        fn contents(dir: &TempDir, path: &str) -> String {
+            read_to_string(dir.path().join(path)).unwrap()
+        }
+
+        #[test]
+        fn test_lookup_read() {
+            let (_dir, mut fs) = mounted();
+
+            let dir = fs.lookup_inner(ROOT_INODE, name("dir")).unwrap();
+            assert_eq!(dir.kind, FileType::Directory);
+            let file = fs.lookup_inner(dir.ino, name("file.txt")).unwrap();
+            assert_eq!(file.kind, FileType::RegularFile);
+            assert_eq!(file.size, 11);
+            assert_eq!(
+                fs.lookup_inner(dir.ino, name("missing")).err(),
+                Some(ENOENT)
+            );
+
+            // Looking up again finds the same inode.
+            let again = fs.lookup_inner(dir.ino, name("file.txt")).unwrap();
+            assert_eq!(again.ino, file.ino);
+
+            let fh = fs.open_inner(file.ino, O_RDONLY as u32).unwrap();
+            assert_eq!(fs.read_inner(fh, 0, 5), Ok(b"Hello".to_vec()));
+            assert_eq!(fs.read_inner(fh, 6, 100), Ok(b"world".to_vec()));
+            assert_eq!(fs.read_inner(fh, 11, 10), Ok(Vec::new()));
+
+            // Seeking backwards reopens the file.
+            assert_eq!(fs.read_inner(fh, 2, 3), Ok(b"llo".to_vec()));
+            assert_eq!(fs.release_inner(fh), Ok(()));
+            assert_eq!(fs.read_inner(fh, 0, 5), Err(EINVAL));
+        }
+
+        #[test]
+        fn test_write_truncate() {
+            let (dir, mut fs) = mounted();
+
+            let flags = (O_WRONLY | O_CREAT) as u32;
+            let (fh, attr) = fs.create_inner(ROOT_INODE, name("new.txt"), flags).unwrap();
+            assert_eq!(attr.size, 0);
+            assert_eq!(contents(&dir, "new.txt"), "");
+
+            assert_eq!(fs.write_inner(fh, 0, b"hello"), Ok(()));
+            assert_eq!(fs.getattr_inner(attr.ino).map(|a| a.size), Ok(5));
+            assert_eq!(fs.write_inner(fh, -1, b"hello"), Err(EINVAL));
+            assert_eq!(fs.release_inner(fh), Ok(()));
+            assert_eq!(contents(&dir, "new.txt"), "hello");
+
+            // An exclusive create fails for existing files and directories.
+            let exclusive = (O_WRONLY | O_CREAT | O_EXCL) as u32;
+            assert_eq!(
+                fs.create_inner(ROOT_INODE, name("new.txt"), exclusive)
+                    .map(|_| ()),
+                Err(EEXIST)
+            );
+            assert_eq!(
+                fs.create_inner(ROOT_INODE, name("dir"), exclusive)
+                    .map(|_| ()),
+                Err(EEXIST)
+            );
+            assert_eq!(
+                fs.create_inner(ROOT_INODE, name("dir"), flags).map(|_| ()),
+                Err(EISDIR)
+            );
+
+            // Otherwise an existing file is opened without truncating it.
+            let (fh, existing) = fs.create_inner(ROOT_INODE, name("new.txt"), flags).unwrap();
+            assert_eq!(existing.ino, attr.ino);
+            assert_eq!(existing.size, 5);
+            assert_eq!(fs.write_inner(fh, 5, b"!"), Ok(()));
+            assert_eq!(fs.release_inner(fh), Ok(()));
+            assert_eq!(contents(&dir, "new.txt"), "hello!");
+
+            // Truncating an open file only changes it once it is closed.
+            let fh = fs.open_inner(attr.ino, O_RDWR as u32).unwrap();
+            assert_eq!(fs.read_inner(fh, 0, 100), Ok(b"hello!".to_vec()));
+            assert_eq!(fs.truncate(attr.ino, Some(fh), 2), Ok(()));
+            assert_eq!(fs.read_inner(fh, 0, 100), Ok(b"he".to_vec()));
+            assert_eq!(contents(&dir, "new.txt"), "hello!");
+            assert_eq!(fs.release_inner(fh), Ok(()));
+            assert_eq!(contents(&dir, "new.txt"), "he");
+
+            // A file that isn't open can only be emptied.
+            assert_eq!(fs.truncate(attr.ino, None, 1), Err(EINVAL));
+            assert_eq!(fs.truncate(attr.ino, None, 0), Ok(()));
+            assert_eq!(contents(&dir, "new.txt"), "");
+
+            // Writes fail for files opened for reading.
+            let fh = fs.open_inner(attr.ino, O_RDONLY as u32).unwrap();
+            assert_eq!(fs.write_inner(fh, 0, b"hello"), Err(EACCES));
+            assert_eq!(fs.release_inner(fh), Ok(()));
+        }
+
+        #[test]
+        fn test_rename() {
+            let (dir, mut fs) = mounted();
+
+            let top = fs.lookup_inner(ROOT_INODE, name("top.txt")).unwrap();
+            let source = fs.lookup_inner(ROOT_INODE, name("dir")).unwrap();
+            let file = fs.lookup_inner(source.ino, name("file.txt")).unwrap();
+            let sub = fs.lookup_inner(source.ino, name("sub")).unwrap();
+            let deep = fs.lookup_inner(sub.ino, name("deep.txt")).unwrap();
+
+            // Files keep their inode.
+            assert_eq!(
+                fs.rename_inner(ROOT_INODE, name("top.txt"), source.ino, name("moved.txt")),
+                Ok(())
+            );
+            assert!(!dir.path().join("top.txt").exists());
+            assert_eq!(contents(&dir, "dir/moved.txt"), "Top");
+            assert_eq!(
+                fs.lookup_inner(ROOT_INODE, name("top.txt")).err(),
+                Some(ENOENT)
+            );
+            let moved = fs.lookup_inner(source.ino, name("moved.txt")).unwrap();
+            assert_eq!(moved.ino, top.ino);
+
+            // Directories can't replace files, be moved into themselves or
+            // replace directories that aren't empty and files can't replace
+            // directories.
+            write(dir.path().join("file"), "File").unwrap();
+            create_dir_all(dir.path().join("full")).unwrap();
+            write(dir.path().join("full/file"), "File").unwrap();
+            fs.directories.clear();
+            assert_eq!(
+                fs.rename_inner(ROOT_INODE, name("dir"), ROOT_INODE, name("file")),
+                Err(ENOTDIR)
+            );
+            assert_eq!(
+                fs.rename_inner(ROOT_INODE, name("dir"), sub.ino, name("dir")),
+                Err(EINVAL)
+            );
+            assert_eq!(
+                fs.rename_inner(ROOT_INODE, name("dir"), ROOT_INODE, name("full")),
+                Err(ENOTEMPTY)
+            );
+            assert_eq!(
+                fs.rename_inner(ROOT_INODE, name("file"), ROOT_INODE, name("full")),
+                Err(EISDIR)
+            );
+
+            // Directories move everything inside them.
+            assert_eq!(
+                fs.rename_inner(ROOT_INODE, name("dir"), ROOT_INODE, name("renamed")),
+                Ok(())
+            );
+            assert!(!dir.path().join("dir").exists());
+            assert_eq!(contents(&dir, "renamed/file.txt"), "Hello world");
+            assert_eq!(contents(&dir, "renamed/moved.txt"), "Top");
+            assert_eq!(contents(&dir, "renamed/sub/deep.txt"), "Deep");
+            assert_eq!(fs.lookup_inner(ROOT_INODE, name("dir")).err(), Some(ENOENT));
+
+            let target = fs.lookup_inner(ROOT_INODE, name("renamed")).unwrap();
+            assert_eq!(target.ino, source.ino);
+            let found = fs.lookup_inner(target.ino, name("sub")).unwrap();
+            assert_eq!(found.ino, sub.ino);
+            let found = fs.lookup_inner(sub.ino, name("deep.txt")).unwrap();
+            assert_eq!(found.ino, deep.ino);
+
+            let fh = fs.open_inner(file.ino, O_RDONLY as u32).unwrap();
+            assert_eq!(fs.read_inner(fh, 0, 100), Ok(b"Hello world".to_vec()));
+            assert_eq!(fs.release_inner(fh), Ok(()));
+
+            // Directories made through the mount move too.
+            let empty = fs.mkdir_inner(ROOT_INODE, name("empty")).unwrap();
+            assert_eq!(
+                fs.rename_inner(ROOT_INODE, name("empty"), target.ino, name("other")),
+                Ok(())
+            );
+            assert_eq!(
+                fs.lookup_inner(ROOT_INODE, name("empty")).err(),
+                Some(ENOENT)
+            );
+            let other = fs.lookup_inner(target.ino, name("other")).unwrap();
+            assert_eq!(other.ino, empty.ino);
+            assert_eq!(other.kind, FileType::Directory);
+        }
+    }
+}
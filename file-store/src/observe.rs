@@ -0,0 +1,356 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Observing the operations performed on a storage backend.
+//!
+//! An [`Observer`](trait.Observer.html) is told when every operation on a
+//! [`FileStore`](../enum.FileStore.html) starts, how many bytes it transfers
+//! and when it completes or fails. Use
+//! [`FileStore::observe`](../enum.FileStore.html#method.observe) to attach an
+//! observer, every call made through the returned store is then reported
+//! which makes it simple to build audit logs or dashboards.
+//!
+//! Reading a file is complete once its data stream has ended, a stream that
+//! is dropped before the end is never reported as complete.
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::future::TryFutureExt;
+use futures::stream::Stream;
+
+use crate::backends::Backend;
+use crate::correlation::OperationId;
+use crate::dynamic::{self, DynamicStore};
+use crate::types::*;
+use crate::{FileStore, StorageBackend};
+
+/// The kinds of operations that can be performed on a storage backend.
+///
+/// Each matches one of the methods of
+/// [`StorageBackend`](../trait.StorageBackend.html).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OperationKind {
+    /// Listing the objects with a prefix.
+    ListObjects,
+    /// Listing the objects in a directory.
+    ListDirectory,
+    /// Getting info about an object.
+    GetObject,
+    /// Reading a file.
+    GetFileStream,
+    /// Copying a file.
+    CopyFile,
+    /// Moving a file.
+    MoveFile,
+    /// Deleting an object.
+    DeleteObject,
+    /// Writing a file.
+    WriteFile,
+}
+
+/// Describes an operation reported to an [`Observer`](trait.Observer.html).
+#[derive(Clone, Debug)]
+pub struct OperationInfo {
+    /// Identifies this operation amongst all of those reported for the same
+    /// store.
+    pub id: u64,
+    /// The id that correlates this operation with the logs and errors it
+    /// produces. See the [`correlation`](../correlation/index.html) module.
+    pub operation_id: OperationId,
+    /// The type of the backend performing the operation.
+    pub backend: Backend,
+    /// The kind of operation.
+    pub kind: OperationKind,
+    /// The path that the operation acts on. For copies and moves this is the
+    /// source.
+    pub path: ObjectPath,
+    /// The target of copies and moves.
+    pub target: Option<ObjectPath>,
+}
+
+/// Receives notifications of the operations performed on a storage backend.
+///
+/// Every method does nothing by default so implementations only need to
+/// handle the notifications they are interested in. Notifications are sent
+/// while the operation is being polled so they should be quick to handle.
+pub trait Observer: Send + Sync + 'static {
+    /// Called when an operation starts.
+    fn on_operation_start(&self, operation: &OperationInfo) {
+        let _ = operation;
+    }
+
+    /// Called as data is read from or written to a file.
+    fn on_bytes_transferred(&self, operation: &OperationInfo, bytes: u64) {
+        let _ = (operation, bytes);
+    }
+
+    /// Called when an operation completes successfully.
+    fn on_operation_complete(&self, operation: &OperationInfo, duration: Duration) {
+        let _ = (operation, duration);
+    }
+
+    /// Called when an operation fails.
+    fn on_operation_error(&self, operation: &OperationInfo, error: &StorageError) {
+        let _ = (operation, error);
+    }
+}
+
+trait ObservedError {
+    fn storage_error(&self) -> &StorageError;
+}
+
+impl ObservedError for StorageError {
+    fn storage_error(&self) -> &StorageError {
+        self
+    }
+}
+
+impl ObservedError for TransferError {
+    fn storage_error(&self) -> &StorageError {
+        match self {
+            TransferError::SourceError(e) => e,
+            TransferError::TargetError(e) => e,
+        }
+    }
+}
+
+struct Tracker {
+    observer: Arc<dyn Observer>,
+    operation: OperationInfo,
+    start: Instant,
+}
+
+impl Tracker {
+    fn transferred(&self, bytes: u64) {
+        self.observer.on_bytes_transferred(&self.operation, bytes);
+    }
+
+    fn complete(&self) {
+        self.observer
+            .on_operation_complete(&self.operation, self.start.elapsed());
+    }
+
+    fn failed(&self, error: &StorageError) {
+        self.observer.on_operation_error(&self.operation, error);
+    }
+
+    /// Runs the function, which should start the operation on the wrapped
+    /// store, with this operation's id as the current id.
+    fn scope<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        self.operation.operation_id.scope(f)
+    }
+}
+
+async fn observed<F, T, E>(tracker: Arc<Tracker>, future: F) -> Result<T, E>
+where
+    F: Future<Output = Result<T, E>>,
+    E: ObservedError,
+{
+    let result = future.await;
+    match result {
+        Ok(_) => tracker.complete(),
+        Err(ref e) => tracker.failed(e.storage_error()),
+    }
+    result
+}
+
+/// Reports the data passing through a stream. When reading the operation is
+/// complete once the stream ends.
+struct ObservedStream {
+    stream: DataStream,
+    tracker: Arc<Tracker>,
+    completes: bool,
+    finished: bool,
+}
+
+impl ObservedStream {
+    fn new(stream: DataStream, tracker: Arc<Tracker>, completes: bool) -> ObservedStream {
+        ObservedStream {
+            stream,
+            tracker,
+            completes,
+            finished: false,
+        }
+    }
+}
+
+impl Stream for ObservedStream {
+    type Item = StorageResult<Data>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let result = Pin::new(&mut self.stream).poll_next(cx);
+
+        match result {
+            Poll::Ready(Some(Ok(ref data))) => self.tracker.transferred(data.len() as u64),
+            Poll::Ready(Some(Err(ref e))) if self.completes && !self.finished => {
+                self.finished = true;
+                self.tracker.failed(e);
+            }
+            Poll::Ready(None) if self.completes && !self.finished => {
+                self.finished = true;
+                self.tracker.complete();
+            }
+            _ => (),
+        }
+
+        result
+    }
+}
+
+/// Wraps a store reporting every operation to an observer.
+struct ObservedStore {
+    store: FileStore,
+    observer: Arc<dyn Observer>,
+    next_id: AtomicU64,
+}
+
+impl ObservedStore {
+    fn start(
+        &self,
+        kind: OperationKind,
+        path: ObjectPath,
+        target: Option<ObjectPath>,
+    ) -> Arc<Tracker> {
+        let operation = OperationInfo {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            operation_id: OperationId::current().unwrap_or_else(OperationId::generate),
+            backend: self.store.backend_type(),
+            kind,
+            path,
+            target,
+        };
+        self.observer.on_operation_start(&operation);
+
+        Arc::new(Tracker {
+            observer: self.observer.clone(),
+            operation,
+            start: Instant::now(),
+        })
+    }
+}
+
+// Only StorageBackend is in scope so calls on the wrapped store are not
+// ambiguous.
+impl dynamic::DynamicBackend for ObservedStore {
+    fn backend_type(&self) -> Backend {
+        self.store.backend_type()
+    }
+
+    fn authorize(&self) -> OperationCompleteFuture {
+        self.store.authorize()
+    }
+
+    fn validate_path(&self, path: &ObjectPath) -> StorageResult<()> {
+        self.store.validate_path(path)
+    }
+
+    fn list_objects(&self, prefix: ObjectPath) -> ObjectStreamFuture {
+        let tracker = self.start(OperationKind::ListObjects, prefix.clone(), None);
+        let future = tracker.scope(|| self.store.list_objects(prefix));
+        ObjectStreamFuture::from_future(observed(tracker, future))
+    }
+
+    fn list_directory(&self, dir: ObjectPath) -> ObjectStreamFuture {
+        let tracker = self.start(OperationKind::ListDirectory, dir.clone(), None);
+        let future = tracker.scope(|| self.store.list_directory(dir));
+        ObjectStreamFuture::from_future(observed(tracker, future))
+    }
+
+    fn get_object(&self, path: ObjectPath) -> ObjectFuture {
+        let tracker = self.start(OperationKind::GetObject, path.clone(), None);
+        let future = tracker.scope(|| self.store.get_object(path));
+        ObjectFuture::from_future(observed(tracker, future))
+    }
+
+    fn get_file_stream(&self, path: ObjectPath) -> DataStreamFuture {
+        self.get_file_stream_with_options(path, Default::default())
+    }
+
+    fn get_file_stream_with_options(
+        &self,
+        path: ObjectPath,
+        options: ReadOptions,
+    ) -> DataStreamFuture {
+        let tracker = self.start(OperationKind::GetFileStream, path.clone(), None);
+        let failed = tracker.clone();
+        DataStreamFuture::from_future(
+            tracker
+                .scope(|| self.store.get_file_stream_with_options(path, options))
+                .map_ok(move |stream| {
+                    DataStream::from_stream(ObservedStream::new(stream, tracker, true))
+                })
+                .map_err(move |e| {
+                    failed.failed(&e);
+                    e
+                }),
+        )
+    }
+
+    fn copy_file(&self, source: ObjectPath, target: UploadInfo) -> CopyCompleteFuture {
+        let tracker = self.start(
+            OperationKind::CopyFile,
+            source.clone(),
+            Some(target.path.clone()),
+        );
+        let future = tracker.scope(|| self.store.copy_file(source, target));
+        CopyCompleteFuture::from_future(observed(tracker, future))
+    }
+
+    fn move_file(&self, source: ObjectPath, target: UploadInfo) -> MoveCompleteFuture {
+        let tracker = self.start(
+            OperationKind::MoveFile,
+            source.clone(),
+            Some(target.path.clone()),
+        );
+        let future = tracker.scope(|| self.store.move_file(source, target));
+        MoveCompleteFuture::from_future(observed(tracker, future))
+    }
+
+    fn delete_object(&self, path: ObjectPath) -> OperationCompleteFuture {
+        let tracker = self.start(OperationKind::DeleteObject, path.clone(), None);
+        let future = tracker.scope(|| self.store.delete_object(path));
+        OperationCompleteFuture::from_future(observed(tracker, future))
+    }
+
+    fn write_file_from_stream(&self, info: UploadInfo, stream: DataStream) -> WriteCompleteFuture {
+        let tracker = self.start(OperationKind::WriteFile, info.path.clone(), None);
+        let stream = ObservedStream::new(stream, tracker.clone(), false);
+        let future = tracker.scope(|| self.store.write_file_from_stream(info, stream));
+        WriteCompleteFuture::from_future(observed(tracker, future))
+    }
+}
+
+impl FileStore {
+    /// Wraps this store so that the given observer is told about every
+    /// operation performed through the returned store.
+    ///
+    /// See the [`observe`](observe/index.html) module.
+    pub fn observe<O>(self, observer: O) -> FileStore
+    where
+        O: Observer,
+    {
+        FileStore::from(DynamicStore::new(ObservedStore {
+            store: self,
+            observer: Arc::new(observer),
+            next_id: AtomicU64::new(0),
+        }))
+    }
+}
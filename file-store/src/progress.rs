@@ -0,0 +1,217 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Progress bars for transfers using [`indicatif`](https://docs.rs/indicatif).
+//!
+//! A [`TransferProgress`](struct.TransferProgress.html) shows a bar for every
+//! job added through it to a
+//! [`TransferManager`](../transfers/struct.TransferManager.html), updated from
+//! the manager's events:
+//!
+//! ```no_run
+//! # use file_store::{FileStore, ObjectPath};
+//! # use file_store::progress::TransferProgress;
+//! # use file_store::transfers::{TransferJob, TransferManager};
+//! # async fn example(source: FileStore, target: FileStore) {
+//! let manager = TransferManager::new();
+//! let mut progress = TransferProgress::new(&manager);
+//! for name in &["a.txt", "b.txt"] {
+//!     let path = ObjectPath::new(*name).unwrap();
+//!     let job = TransferJob::new(source.clone(), path.clone(), target.clone(), path);
+//!     progress.add(&manager, job, None);
+//! }
+//! progress.run().await;
+//! # }
+//! ```
+//!
+//! [`progress_stream`](fn.progress_stream.html) shows the progress of a single
+//! stream of data instead, for example when copying a file directly.
+//!
+//! Included with the "indicatif" and "transfers" features.
+use std::collections::HashMap;
+use std::fmt;
+use std::task::Poll;
+use std::thread;
+
+use futures::stream::{poll_fn, StreamExt};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+
+use crate::transfers::{JobEventKind, JobEvents, JobId, TransferJob, TransferManager};
+use crate::types::*;
+
+/// Future returned by [`TransferProgress::run`](struct.TransferProgress.html#method.run).
+pub type ProgressFuture = WrappedFuture<()>;
+
+/// The style used for bars when the length is known.
+pub fn bar_style() -> ProgressStyle {
+    ProgressStyle::default_bar()
+        .template("{prefix:.bold} [{bar:30}] {bytes}/{total_bytes} {msg}")
+        .progress_chars("=> ")
+}
+
+/// The style used for bars when the length is not known.
+pub fn spinner_style() -> ProgressStyle {
+    ProgressStyle::default_spinner().template("{prefix:.bold} {spinner} {bytes} {msg}")
+}
+
+fn new_bar(len: Option<u64>) -> ProgressBar {
+    match len {
+        Some(len) => {
+            let bar = ProgressBar::new(len);
+            bar.set_style(bar_style());
+            bar
+        }
+        None => {
+            let bar = ProgressBar::new_spinner();
+            bar.set_style(spinner_style());
+            bar
+        }
+    }
+}
+
+/// Shows a bar for each of a set of transfer jobs.
+///
+/// See the [`progress`](index.html) module.
+pub struct TransferProgress {
+    multi: MultiProgress,
+    events: JobEvents,
+    bars: HashMap<JobId, ProgressBar>,
+}
+
+impl fmt::Debug for TransferProgress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TransferProgress")
+            .field("jobs", &self.bars.len())
+            .finish()
+    }
+}
+
+impl TransferProgress {
+    /// Creates a reporter for jobs on the given manager drawing to stderr.
+    pub fn new(manager: &TransferManager) -> TransferProgress {
+        TransferProgress::with_draw_target(manager, ProgressDrawTarget::stderr())
+    }
+
+    /// Creates a reporter for jobs on the given manager drawing to the given
+    /// target.
+    pub fn with_draw_target(
+        manager: &TransferManager,
+        target: ProgressDrawTarget,
+    ) -> TransferProgress {
+        TransferProgress {
+            multi: MultiProgress::with_draw_target(target),
+            events: manager.events(),
+            bars: HashMap::new(),
+        }
+    }
+
+    /// Adds a job to the manager and shows a bar for it labelled with the
+    /// target path. Pass the length of the source file, if known, to show how
+    /// much of it remains.
+    pub fn add(&mut self, manager: &TransferManager, job: TransferJob, len: Option<u64>) -> JobId {
+        let bar = self.multi.add(new_bar(len));
+        bar.set_prefix(&job.target_path().to_string());
+
+        let id = manager.add(job);
+        self.bars.insert(id, bar);
+        id
+    }
+
+    /// Updates the bars until every job added has finished.
+    ///
+    /// The bars are drawn by a separate thread as `indicatif` requires so no
+    /// more jobs can be added once this is called.
+    pub fn run(self) -> ProgressFuture {
+        async fn update(mut events: JobEvents, mut bars: HashMap<JobId, ProgressBar>) {
+            while !bars.is_empty() {
+                let event = match events.next().await {
+                    Some(event) => event,
+                    None => break,
+                };
+
+                let finished = match bars.get(&event.job) {
+                    Some(bar) => match event.kind {
+                        JobEventKind::Queued => {
+                            bar.set_message("queued");
+                            false
+                        }
+                        JobEventKind::Started => {
+                            bar.set_message("");
+                            false
+                        }
+                        JobEventKind::Paused => {
+                            bar.set_message("paused");
+                            false
+                        }
+                        JobEventKind::Progress(transferred) => {
+                            bar.set_position(transferred);
+                            false
+                        }
+                        JobEventKind::Completed => {
+                            bar.finish_with_message("done");
+                            true
+                        }
+                        JobEventKind::Failed(kind) => {
+                            bar.abandon_with_message(&format!("failed: {:?}", kind));
+                            true
+                        }
+                        JobEventKind::Cancelled => {
+                            bar.abandon_with_message("cancelled");
+                            true
+                        }
+                    },
+                    None => false,
+                };
+
+                if finished {
+                    bars.remove(&event.job);
+                }
+            }
+
+            // Should a manager be dropped early make sure the bars stop.
+            for (_, bar) in bars.drain() {
+                bar.abandon();
+            }
+        }
+
+        let TransferProgress {
+            multi,
+            events,
+            bars,
+        } = self;
+
+        // The thread exits once every bar has finished.
+        thread::spawn(move || multi.join());
+
+        ProgressFuture::from_future(update(events, bars))
+    }
+}
+
+/// Advances a bar as data passes through a stream. The bar is finished when
+/// the stream ends.
+pub fn progress_stream(stream: DataStream, bar: ProgressBar) -> DataStream {
+    let finish = bar.clone();
+    let stream = stream
+        .inspect(move |result| {
+            if let Ok(data) = result {
+                bar.inc(data.len() as u64);
+            }
+        })
+        .chain(poll_fn(move |_| {
+            finish.finish();
+            Poll::Ready(None)
+        }));
+
+    DataStream::from_stream(stream)
+}
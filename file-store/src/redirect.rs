@@ -0,0 +1,184 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Serving files to authenticated clients without proxying their data.
+//!
+//! A [`RedirectProxy`](struct.RedirectProxy.html) checks every request with an
+//! authentication function. `GET` requests that pass are answered with a
+//! `302 Found` redirect to a freshly generated presigned url so the client
+//! downloads the file directly from the backend. Where the backend cannot
+//! presign urls, and for `HEAD` requests, the file is served the same way as
+//! [`StaticFiles`](../serve/struct.StaticFiles.html) does.
+//!
+//! Currently only the B2 backend can presign urls.
+//!
+//! Included with the "redirect" feature.
+use std::convert::Infallible;
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::FutureExt;
+use http::header::{self, HeaderMap};
+use http::{Method, StatusCode};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server};
+
+use crate::serve::{
+    decode_path, empty_response, error_status, set_header, ServeFuture, StaticFiles,
+};
+use crate::types::*;
+use crate::FileStore;
+
+const DEFAULT_VALID_FOR: Duration = Duration::from_secs(5 * 60);
+
+type AuthFuture = Pin<Box<dyn Future<Output = bool> + Send>>;
+type Authenticate = dyn Fn(&ObjectPath, &HeaderMap) -> AuthFuture + Send + Sync;
+
+#[cfg(feature = "b2")]
+fn presign(
+    store: &FileStore,
+    path: ObjectPath,
+    valid_for: Duration,
+) -> Option<WrappedFuture<StorageResult<String>>> {
+    match store {
+        FileStore::B2(ref backend) => Some(backend.presigned_url(path, valid_for)),
+        _ => None,
+    }
+}
+
+#[cfg(not(feature = "b2"))]
+fn presign(
+    _store: &FileStore,
+    _path: ObjectPath,
+    _valid_for: Duration,
+) -> Option<WrappedFuture<StorageResult<String>>> {
+    None
+}
+
+/// Authenticates requests for the files beneath a path in a
+/// [`FileStore`](../enum.FileStore.html) and redirects them to presigned urls.
+///
+/// See the [`redirect`](index.html) module.
+#[derive(Clone)]
+pub struct RedirectProxy {
+    files: StaticFiles,
+    store: FileStore,
+    root: ObjectPath,
+    authenticate: Arc<Authenticate>,
+    valid_for: Duration,
+}
+
+impl fmt::Debug for RedirectProxy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RedirectProxy")
+            .field("store", &self.store)
+            .field("root", &self.root)
+            .field("valid_for", &self.valid_for)
+            .finish()
+    }
+}
+
+impl RedirectProxy {
+    /// Creates a new proxy for the files beneath `root`.
+    ///
+    /// `authenticate` is called with the path of the requested file and the
+    /// request's headers and resolves to whether the request is allowed.
+    /// Requests that are not allowed receive a `403 Forbidden` response.
+    pub fn new<F, R>(store: FileStore, root: ObjectPath, authenticate: F) -> RedirectProxy
+    where
+        F: Fn(&ObjectPath, &HeaderMap) -> R + Send + Sync + 'static,
+        R: Future<Output = bool> + Send + 'static,
+    {
+        RedirectProxy {
+            files: StaticFiles::new(store.clone(), root.clone()),
+            store,
+            root,
+            authenticate: Arc::new(move |path: &ObjectPath, headers: &HeaderMap| {
+                Box::pin(authenticate(path, headers)) as AuthFuture
+            }),
+            valid_for: DEFAULT_VALID_FOR,
+        }
+    }
+
+    /// Sets how long the presigned urls remain valid for. Defaults to five
+    /// minutes.
+    pub fn valid_for(mut self, valid_for: Duration) -> RedirectProxy {
+        self.valid_for = valid_for;
+        self
+    }
+
+    /// Binds to the given address and serves requests until an error occurs.
+    pub async fn run(self, addr: SocketAddr) -> StorageResult<()> {
+        let make_service = make_service_fn(move |_| {
+            let proxy = self.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    proxy.serve(&request).map(Ok::<_, Infallible>)
+                }))
+            }
+        });
+
+        Server::bind(&addr)
+            .serve(make_service)
+            .await
+            .map_err(|e| error::connection_failed(Some(&e.to_string())))
+    }
+
+    /// Generates the response for the given request.
+    ///
+    /// The path of the request's URI is decoded and used relative to the root.
+    pub fn serve<B>(&self, request: &Request<B>) -> ServeFuture {
+        match decode_path(&self.root, request.uri().path()) {
+            Some(path) => ServeFuture::from_future(respond(
+                self.clone(),
+                path,
+                request.method().clone(),
+                request.headers().clone(),
+            )),
+            None => ServeFuture::from_value(empty_response(StatusCode::BAD_REQUEST)),
+        }
+    }
+}
+
+async fn respond(
+    proxy: RedirectProxy,
+    path: ObjectPath,
+    method: Method,
+    headers: HeaderMap,
+) -> Response<Body> {
+    if !(proxy.authenticate)(&path, &headers).await {
+        return empty_response(StatusCode::FORBIDDEN);
+    }
+
+    if method == Method::GET {
+        if let Some(future) = presign(&proxy.store, path.clone(), proxy.valid_for) {
+            return match future.await {
+                Ok(url) => {
+                    let mut response = empty_response(StatusCode::FOUND);
+                    set_header(&mut response, header::LOCATION, &url);
+                    // The url stops working so must not be cached for longer.
+                    set_header(&mut response, header::CACHE_CONTROL, "no-store");
+                    response
+                }
+                Err(e) => empty_response(error_status(&e)),
+            };
+        }
+    }
+
+    proxy.files.serve_object(path, &method, &headers).await
+}
@@ -0,0 +1,347 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Serves files from storage over HTTP.
+//!
+//! [`StaticFiles`](struct.StaticFiles.html) turns HTTP requests into responses
+//! containing the files found under a root path in a
+//! [`FileStore`](../enum.FileStore.html). It handles `GET` and `HEAD` requests,
+//! single byte ranges, content types based on the file extension and
+//! conditional requests using `ETag` and `Last-Modified`.
+//!
+//! The responses use hyper's `Body` so can be returned directly from a hyper
+//! service, other frameworks can generally convert them.
+//!
+//! Included with the "serve" feature.
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use http::{Method, StatusCode};
+use hyper::{Body, Request, Response};
+use percent_encoding::percent_decode_str;
+
+use crate::types::*;
+use crate::utils::content_type;
+use crate::{FileStore, StorageBackend};
+
+/// A future that resolves to an HTTP response.
+pub type ServeFuture = WrappedFuture<Response<Body>>;
+
+fn etag(object: &Object) -> String {
+    match object
+        .modified()
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+    {
+        Some(d) => format!("W/\"{:x}-{:x}\"", object.len(), d.as_secs()),
+        None => format!("W/\"{:x}\"", object.len()),
+    }
+}
+
+pub(crate) fn empty_response(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+pub(crate) fn set_header(response: &mut Response<Body>, name: HeaderName, value: &str) {
+    if let Ok(value) = HeaderValue::from_str(value) {
+        response.headers_mut().insert(name, value);
+    }
+}
+
+pub(crate) fn error_status(error: &StorageError) -> StatusCode {
+    match error.kind() {
+        StorageErrorKind::NotFound(_)
+        | StorageErrorKind::InvalidPath(_)
+        | StorageErrorKind::ObjectPathParse(_) => StatusCode::NOT_FOUND,
+        StorageErrorKind::AccessDenied => StatusCode::FORBIDDEN,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+fn error_response(error: StorageError) -> Response<Body> {
+    empty_response(error_status(&error))
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: HeaderName) -> Option<&'a str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+fn matches_etag(headers: &HeaderMap, tag: &str) -> bool {
+    match header_str(headers, header::IF_NONE_MATCH) {
+        Some(value) => value.split(',').map(str::trim).any(|candidate| {
+            candidate == "*" || candidate.trim_start_matches("W/") == tag.trim_start_matches("W/")
+        }),
+        None => false,
+    }
+}
+
+fn not_modified_since(headers: &HeaderMap, modified: Option<SystemTime>) -> bool {
+    if headers.contains_key(header::IF_NONE_MATCH) {
+        return false;
+    }
+
+    let since = match header_str(headers, header::IF_MODIFIED_SINCE)
+        .and_then(|s| httpdate::parse_http_date(s).ok())
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+    {
+        Some(s) => s,
+        None => return false,
+    };
+
+    // HTTP dates only have second precision.
+    match modified.and_then(|m| m.duration_since(UNIX_EPOCH).ok()) {
+        Some(modified) => modified.as_secs() <= since.as_secs(),
+        None => false,
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    /// No range was requested or the header could not be understood.
+    Full,
+    /// A satisfiable range, inclusive of both ends.
+    Partial(u64, u64),
+    /// The range cannot be satisfied.
+    Unsatisfiable,
+}
+
+fn parse_range(headers: &HeaderMap, len: u64) -> ByteRange {
+    let spec = match header_str(headers, header::RANGE) {
+        Some(s) if s.starts_with("bytes=") => &s[6..],
+        _ => return ByteRange::Full,
+    };
+
+    // Multiple ranges are not supported, just send the full file.
+    if spec.contains(',') {
+        return ByteRange::Full;
+    }
+
+    let pos = match spec.find('-') {
+        Some(p) => p,
+        None => return ByteRange::Full,
+    };
+
+    let (start, end) = (spec[..pos].trim(), spec[pos + 1..].trim());
+    if start.is_empty() {
+        // A suffix range.
+        return match end.parse::<u64>() {
+            Ok(0) => ByteRange::Unsatisfiable,
+            Ok(_) if len == 0 => ByteRange::Unsatisfiable,
+            Ok(count) => ByteRange::Partial(len.saturating_sub(count), len - 1),
+            Err(_) => ByteRange::Full,
+        };
+    }
+
+    let start = match start.parse::<u64>() {
+        Ok(s) => s,
+        Err(_) => return ByteRange::Full,
+    };
+
+    if start >= len {
+        return ByteRange::Unsatisfiable;
+    }
+
+    if end.is_empty() {
+        return ByteRange::Partial(start, len - 1);
+    }
+
+    match end.parse::<u64>() {
+        Ok(end) if end >= start => ByteRange::Partial(start, end.min(len - 1)),
+        Ok(_) => ByteRange::Full,
+        Err(_) => ByteRange::Full,
+    }
+}
+
+/// Decodes a URI path into an `ObjectPath` relative to `root`.
+///
+/// Returns `None` if the path is not valid UTF-8 or contains `.` or `..` parts.
+pub(crate) fn decode_path(root: &ObjectPath, uri_path: &str) -> Option<ObjectPath> {
+    let decoded = percent_decode_str(uri_path).decode_utf8().ok()?;
+
+    let mut path = root.clone();
+    for part in decoded.split('/').filter(|p| !p.is_empty()) {
+        if part == "." || part == ".." {
+            return None;
+        }
+        path.push_part(part);
+    }
+
+    Some(path)
+}
+
+/// Serves the files beneath a path in a [`FileStore`](../enum.FileStore.html).
+#[derive(Clone, Debug)]
+pub struct StaticFiles {
+    store: FileStore,
+    root: ObjectPath,
+}
+
+impl StaticFiles {
+    /// Creates a new handler serving the files beneath `root`.
+    pub fn new(store: FileStore, root: ObjectPath) -> StaticFiles {
+        StaticFiles { store, root }
+    }
+
+    /// Generates the response for the given request.
+    ///
+    /// The path of the request's URI is decoded and used relative to the root.
+    /// Requests for paths containing `.` or `..` parts are rejected.
+    pub fn serve<B>(&self, request: &Request<B>) -> ServeFuture {
+        match decode_path(&self.root, request.uri().path()) {
+            Some(path) => self.serve_object(path, request.method(), request.headers()),
+            None => ServeFuture::from_value(empty_response(StatusCode::BAD_REQUEST)),
+        }
+    }
+
+    /// Generates the response for the object at the given path.
+    ///
+    /// Use this when your framework has already routed the request. Unlike
+    /// [`serve`](#method.serve) the path is not relative to the root.
+    pub fn serve_object(
+        &self,
+        path: ObjectPath,
+        method: &Method,
+        headers: &HeaderMap,
+    ) -> ServeFuture {
+        let head = match *method {
+            Method::GET => false,
+            Method::HEAD => true,
+            _ => {
+                let mut response = empty_response(StatusCode::METHOD_NOT_ALLOWED);
+                set_header(&mut response, header::ALLOW, "GET, HEAD");
+                return ServeFuture::from_value(response);
+            }
+        };
+
+        ServeFuture::from_future(serve(self.store.clone(), path, head, headers.clone()))
+    }
+}
+
+async fn serve(
+    store: FileStore,
+    path: ObjectPath,
+    head: bool,
+    headers: HeaderMap,
+) -> Response<Body> {
+    let object = match store.get_object(path.clone()).await {
+        Ok(o) => o,
+        Err(e) => return error_response(e),
+    };
+
+    if object.object_type() != ObjectType::File {
+        return empty_response(StatusCode::NOT_FOUND);
+    }
+
+    let tag = etag(&object);
+    let modified = object.modified().map(httpdate::fmt_http_date);
+
+    let mut response =
+        if matches_etag(&headers, &tag) || not_modified_since(&headers, object.modified()) {
+            empty_response(StatusCode::NOT_MODIFIED)
+        } else {
+            let len = object.len();
+            let (mut response, skip, count) = match parse_range(&headers, len) {
+                ByteRange::Full => (empty_response(StatusCode::OK), 0, len),
+                ByteRange::Partial(start, end) => {
+                    let mut response = empty_response(StatusCode::PARTIAL_CONTENT);
+                    set_header(
+                        &mut response,
+                        header::CONTENT_RANGE,
+                        &format!("bytes {}-{}/{}", start, end, len),
+                    );
+                    (response, start, end - start + 1)
+                }
+                ByteRange::Unsatisfiable => {
+                    let mut response = empty_response(StatusCode::RANGE_NOT_SATISFIABLE);
+                    set_header(
+                        &mut response,
+                        header::CONTENT_RANGE,
+                        &format!("bytes */{}", len),
+                    );
+                    return response;
+                }
+            };
+
+            set_header(&mut response, header::CONTENT_LENGTH, &count.to_string());
+            set_header(&mut response, header::CONTENT_TYPE, content_type(&path));
+
+            if !head && count > 0 {
+                // The object is already known so the backend can request just
+                // the range without looking the file up again.
+                let stream = match store.get_object_range(&object, skip..skip + count).await {
+                    Ok(s) => s,
+                    Err(e) => return error_response(e),
+                };
+
+                *response.body_mut() = Body::wrap_stream(stream);
+            }
+
+            response
+        };
+
+    set_header(&mut response, header::ACCEPT_RANGES, "bytes");
+    set_header(&mut response, header::ETAG, &tag);
+    if let Some(modified) = modified {
+        set_header(&mut response, header::LAST_MODIFIED, &modified);
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(value: &str, len: u64) -> ByteRange {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::RANGE, HeaderValue::from_str(value).unwrap());
+        parse_range(&headers, len)
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range(&HeaderMap::new(), 100), ByteRange::Full);
+
+        // Bounded ranges, clamped to the end of the file.
+        assert_eq!(range("bytes=0-9", 100), ByteRange::Partial(0, 9));
+        assert_eq!(range("bytes= 10 - 19 ", 100), ByteRange::Partial(10, 19));
+        assert_eq!(range("bytes=90-200", 100), ByteRange::Partial(90, 99));
+        assert_eq!(range("bytes=5-5", 100), ByteRange::Partial(5, 5));
+
+        // Open ended ranges.
+        assert_eq!(range("bytes=50-", 100), ByteRange::Partial(50, 99));
+        assert_eq!(range("bytes=99-", 100), ByteRange::Partial(99, 99));
+
+        // Suffix ranges.
+        assert_eq!(range("bytes=-10", 100), ByteRange::Partial(90, 99));
+        assert_eq!(range("bytes=-200", 100), ByteRange::Partial(0, 99));
+        assert_eq!(range("bytes=-0", 100), ByteRange::Unsatisfiable);
+        assert_eq!(range("bytes=-10", 0), ByteRange::Unsatisfiable);
+
+        // Unsatisfiable ranges.
+        assert_eq!(range("bytes=100-", 100), ByteRange::Unsatisfiable);
+        assert_eq!(range("bytes=150-200", 100), ByteRange::Unsatisfiable);
+        assert_eq!(range("bytes=0-", 0), ByteRange::Unsatisfiable);
+
+        // Anything not understood serves the whole file.
+        assert_eq!(range("items=0-9", 100), ByteRange::Full);
+        assert_eq!(range("bytes=0-9,20-29", 100), ByteRange::Full);
+        assert_eq!(range("bytes=9-0", 100), ByteRange::Full);
+        assert_eq!(range("bytes=a-9", 100), ByteRange::Full);
+        assert_eq!(range("bytes=0-b", 100), ByteRange::Full);
+        assert_eq!(range("bytes=-c", 100), ByteRange::Full);
+        assert_eq!(range("bytes=10", 100), ByteRange::Full);
+    }
+}
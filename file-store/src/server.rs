@@ -0,0 +1,252 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A simple REST server exposing the operations of a
+//! [`FileStore`](../enum.FileStore.html).
+//!
+//! This allows services not written in Rust to share a store's configuration
+//! and credentials by running it as a sidecar process. The protocol is:
+//!
+//! * `GET /list/{prefix}` lists the objects with the prefix as newline
+//!   delimited JSON.
+//! * `GET /dir/{path}` lists the objects in the directory as newline delimited
+//!   JSON.
+//! * `GET /info/{path}` returns a JSON description of the object.
+//! * `GET /files/{path}` and `HEAD /files/{path}` return the file's contents,
+//!   with the same support for ranges and conditional requests as
+//!   [`StaticFiles`](../serve/struct.StaticFiles.html).
+//! * `PUT /files/{path}` writes the request body to the file.
+//! * `DELETE /files/{path}` deletes the object.
+//! * `POST /copy/{path}` and `POST /move/{path}` copy or move the file to the
+//!   path given in the `Destination` header.
+//!
+//! Objects are described as `{"path": "...", "type": "file", "size": 0,
+//! "modified": 0}` where `modified` is in milliseconds since the epoch or
+//! `null`. Errors are returned with an appropriate status code and the error
+//! message as the body.
+//!
+//! No authentication is performed, only bind to addresses that are not
+//! publicly reachable.
+//!
+//! Included with the "server" feature.
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::time::UNIX_EPOCH;
+
+use futures::future::FutureExt;
+use futures::stream::{StreamExt, TryStreamExt};
+use http::header::{self, HeaderValue};
+use http::{Method, StatusCode};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server};
+use serde_json::json;
+
+use crate::serve::{decode_path, empty_response, error_status, set_header, StaticFiles};
+use crate::types::*;
+use crate::{FileStore, StorageBackend};
+
+/// A future that resolves to an HTTP response.
+pub type ResponseFuture = WrappedFuture<Response<Body>>;
+
+fn object_json(object: &Object) -> String {
+    json!({
+        "path": object.path().to_string(),
+        "type": object.object_type().to_string(),
+        "size": object.len(),
+        "modified": object
+            .modified()
+            .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_millis() as u64),
+    })
+    .to_string()
+}
+
+fn text_response(status: StatusCode, message: &str) -> Response<Body> {
+    let mut response = empty_response(status);
+    set_header(&mut response, header::CONTENT_TYPE, "text/plain");
+    *response.body_mut() = Body::from(message.to_owned());
+    response
+}
+
+fn storage_error(error: StorageError) -> Response<Body> {
+    text_response(error_status(&error), &error.to_string())
+}
+
+fn transfer_error(error: TransferError) -> Response<Body> {
+    match error {
+        TransferError::SourceError(e) => storage_error(e),
+        TransferError::TargetError(e) => storage_error(e),
+    }
+}
+
+fn listing(stream: ObjectStream) -> Response<Body> {
+    let lines = stream.map_ok(|object| format!("{}\n", object_json(&object)));
+
+    let mut response = empty_response(StatusCode::OK);
+    set_header(&mut response, header::CONTENT_TYPE, "application/x-ndjson");
+    *response.body_mut() = Body::wrap_stream(lines);
+    response
+}
+
+/// Handles requests for a [`FileStore`](../enum.FileStore.html).
+#[derive(Clone, Debug)]
+pub struct StorageServer {
+    store: FileStore,
+    files: StaticFiles,
+}
+
+impl StorageServer {
+    /// Creates a server for the given store.
+    pub fn new(store: FileStore) -> StorageServer {
+        StorageServer {
+            files: StaticFiles::new(store.clone(), ObjectPath::empty()),
+            store,
+        }
+    }
+
+    /// Binds to the given address and serves requests until an error occurs.
+    pub async fn run(self, addr: SocketAddr) -> StorageResult<()> {
+        let make_service = make_service_fn(move |_| {
+            let server = self.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    server.handle(request).map(Ok::<_, Infallible>)
+                }))
+            }
+        });
+
+        Server::bind(&addr)
+            .serve(make_service)
+            .await
+            .map_err(|e| error::connection_failed(Some(&e.to_string())))
+    }
+
+    /// Generates the response for a request.
+    pub fn handle(&self, request: Request<Body>) -> ResponseFuture {
+        let uri_path = request.uri().path().to_owned();
+        let mut parts = uri_path.trim_start_matches('/').splitn(2, '/');
+        let operation = parts.next().unwrap_or("").to_owned();
+        let path = match decode_path(&ObjectPath::empty(), parts.next().unwrap_or("")) {
+            Some(p) => p,
+            None => return ResponseFuture::from_value(empty_response(StatusCode::BAD_REQUEST)),
+        };
+
+        let method = request.method().clone();
+        match (method, operation.as_str()) {
+            (Method::GET, "list") => {
+                // Listing uses the raw prefix, which may end with a `/`.
+                let prefix = if uri_path.ends_with('/') && !path.is_empty() {
+                    ObjectPath::new(format!("{}/", path))
+                } else {
+                    Ok(path)
+                };
+
+                let store = self.store.clone();
+                ResponseFuture::from_future(async move {
+                    let result = match prefix {
+                        Ok(p) => store.list_objects(p).await,
+                        Err(e) => Err(e),
+                    };
+
+                    match result {
+                        Ok(stream) => listing(stream),
+                        Err(e) => storage_error(e),
+                    }
+                })
+            }
+            (Method::GET, "dir") => {
+                let future = self.store.list_directory(path);
+                ResponseFuture::from_future(async move {
+                    match future.await {
+                        Ok(stream) => listing(stream),
+                        Err(e) => storage_error(e),
+                    }
+                })
+            }
+            (Method::GET, "info") => {
+                let future = self.store.get_object(path);
+                ResponseFuture::from_future(async move {
+                    match future.await {
+                        Ok(object) => {
+                            let mut response = text_response(StatusCode::OK, &object_json(&object));
+                            set_header(&mut response, header::CONTENT_TYPE, "application/json");
+                            response
+                        }
+                        Err(e) => storage_error(e),
+                    }
+                })
+            }
+            (Method::GET, "files") | (Method::HEAD, "files") => {
+                self.files
+                    .serve_object(path, request.method(), request.headers())
+            }
+            (Method::PUT, "files") => {
+                let stream = request.into_body().map(|result| match result {
+                    Ok(chunk) => Ok(chunk.into_bytes()),
+                    Err(e) => Err(error::connection_closed(Some(&e.to_string()))),
+                });
+
+                let future = self.store.write_file_from_stream(path, stream);
+                ResponseFuture::from_future(async move {
+                    match future.await {
+                        Ok(()) => empty_response(StatusCode::NO_CONTENT),
+                        Err(e) => transfer_error(e),
+                    }
+                })
+            }
+            (Method::DELETE, "files") => {
+                let future = self.store.delete_object(path);
+                ResponseFuture::from_future(async move {
+                    match future.await {
+                        Ok(()) => empty_response(StatusCode::NO_CONTENT),
+                        Err(e) => storage_error(e),
+                    }
+                })
+            }
+            (Method::POST, "copy") | (Method::POST, "move") => {
+                let target = match request
+                    .headers()
+                    .get("Destination")
+                    .and_then(|v: &HeaderValue| v.to_str().ok())
+                    .and_then(|t| decode_path(&ObjectPath::empty(), t))
+                {
+                    Some(t) => t,
+                    None => {
+                        return ResponseFuture::from_value(text_response(
+                            StatusCode::BAD_REQUEST,
+                            "A valid Destination header is required.",
+                        ))
+                    }
+                };
+
+                let future = if operation == "copy" {
+                    self.store.copy_file(path, target)
+                } else {
+                    self.store.move_file(path, target)
+                };
+
+                ResponseFuture::from_future(async move {
+                    match future.await {
+                        Ok(()) => empty_response(StatusCode::NO_CONTENT),
+                        Err(e) => transfer_error(e),
+                    }
+                })
+            }
+            (_, "list") | (_, "dir") | (_, "info") | (_, "files") | (_, "copy") | (_, "move") => {
+                ResponseFuture::from_value(empty_response(StatusCode::METHOD_NOT_ALLOWED))
+            }
+            _ => ResponseFuture::from_value(empty_response(StatusCode::NOT_FOUND)),
+        }
+    }
+}
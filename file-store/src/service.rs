@@ -0,0 +1,125 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Integration with [tower](https://github.com/tower-rs/tower).
+//!
+//! [`FileStore`](../enum.FileStore.html) implements tower's `Service` trait
+//! accepting a [`StorageRequest`](enum.StorageRequest.html) and responding with
+//! a [`StorageResponse`](enum.StorageResponse.html). This allows wrapping
+//! storage operations in any tower middleware.
+//!
+//! The service is always ready, backends that limit the number of concurrent
+//! requests queue them internally. Errors from copies, moves and writes are
+//! returned as plain [`StorageError`s](../struct.StorageError.html) so it is
+//! not possible to tell whether they came from the source or target.
+//!
+//! Included with the "tower" feature.
+use std::task::{Context, Poll};
+
+use futures::future::TryFutureExt;
+use tower_service::Service;
+
+use crate::types::*;
+use crate::{FileStore, StorageBackend};
+
+/// A request for an operation on a storage backend.
+///
+/// Each variant matches one of the methods of
+/// [`StorageBackend`](../trait.StorageBackend.html).
+pub enum StorageRequest {
+    /// Lists the objects that are prefixed by the given prefix.
+    ListObjects(ObjectPath),
+    /// Lists the objects that exist in the given directory.
+    ListDirectory(ObjectPath),
+    /// Gets info about the object at the given path.
+    GetObject(ObjectPath),
+    /// Gets a stream of data for the file at the given path.
+    GetFileStream(ObjectPath),
+    /// Copies a file from one path to another.
+    CopyFile(ObjectPath, UploadInfo),
+    /// Moves a file from one path to another.
+    MoveFile(ObjectPath, UploadInfo),
+    /// Deletes the object at the given path.
+    DeleteObject(ObjectPath),
+    /// Writes a stream of data to the file at the given path.
+    WriteFile(UploadInfo, DataStream),
+}
+
+/// The response to a [`StorageRequest`](enum.StorageRequest.html).
+pub enum StorageResponse {
+    /// The result of a listing.
+    Objects(ObjectStream),
+    /// The result of getting an object.
+    Object(Object),
+    /// The result of getting a file stream.
+    Data(DataStream),
+    /// The operation completed with no result.
+    Complete,
+}
+
+/// A future that resolves to a [`StorageResponse`](enum.StorageResponse.html).
+pub type StorageResponseFuture = WrappedFuture<StorageResult<StorageResponse>>;
+
+fn transfer_error(error: TransferError) -> StorageError {
+    match error {
+        TransferError::SourceError(e) => e,
+        TransferError::TargetError(e) => e,
+    }
+}
+
+impl Service<StorageRequest> for FileStore {
+    type Response = StorageResponse;
+    type Error = StorageError;
+    type Future = StorageResponseFuture;
+
+    fn poll_ready(&mut self, _cx: &mut Context) -> Poll<StorageResult<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: StorageRequest) -> StorageResponseFuture {
+        match request {
+            StorageRequest::ListObjects(path) => StorageResponseFuture::from_future(
+                self.list_objects(path).map_ok(StorageResponse::Objects),
+            ),
+            StorageRequest::ListDirectory(path) => StorageResponseFuture::from_future(
+                self.list_directory(path).map_ok(StorageResponse::Objects),
+            ),
+            StorageRequest::GetObject(path) => StorageResponseFuture::from_future(
+                self.get_object(path).map_ok(StorageResponse::Object),
+            ),
+            StorageRequest::GetFileStream(path) => StorageResponseFuture::from_future(
+                self.get_file_stream(path).map_ok(StorageResponse::Data),
+            ),
+            StorageRequest::CopyFile(source, target) => StorageResponseFuture::from_future(
+                self.copy_file(source, target)
+                    .map_ok(|()| StorageResponse::Complete)
+                    .map_err(transfer_error),
+            ),
+            StorageRequest::MoveFile(source, target) => StorageResponseFuture::from_future(
+                self.move_file(source, target)
+                    .map_ok(|()| StorageResponse::Complete)
+                    .map_err(transfer_error),
+            ),
+            StorageRequest::DeleteObject(path) => StorageResponseFuture::from_future(
+                self.delete_object(path)
+                    .map_ok(|()| StorageResponse::Complete),
+            ),
+            StorageRequest::WriteFile(info, stream) => StorageResponseFuture::from_future(
+                self.write_file_from_stream(info, stream)
+                    .map_ok(|()| StorageResponse::Complete)
+                    .map_err(transfer_error),
+            ),
+        }
+    }
+}
@@ -0,0 +1,1053 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Syncing files between stores.
+//!
+//! [`sync`](fn.sync.html) makes the files under a prefix of the target store
+//! match those under a prefix of the source store. Files that are missing
+//! from the target or that have changed are copied, optionally files in the
+//! target that are not in the source are deleted. The two stores can be the
+//! same store or use entirely different backends.
+//!
+//! [`two_way`](fn.two_way.html) syncs changes in both directions. It keeps a
+//! manifest of what each file looked like after the last sync so that it can
+//! tell which side a file changed on and whether a missing file was deleted
+//! or is new. Files changed on both sides are resolved with a
+//! [`ConflictPolicy`](enum.ConflictPolicy.html).
+//!
+//! Copies keep the source's modification time so that comparing by
+//! modification time finds nothing to do when syncing again.
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::fmt;
+use std::str;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures::future::TryFutureExt;
+use futures::stream::{iter, StreamExt, TryStreamExt};
+
+use crate::types::*;
+use crate::{ObjectInfo, StorageBackend};
+
+/// The number of files copied at once by default.
+const DEFAULT_CONCURRENCY: usize = 4;
+
+/// Future returned by [`sync`](fn.sync.html).
+pub type SyncFuture = WrappedFuture<Result<SyncSummary, TransferError>>;
+
+/// Future returned by [`two_way`](fn.two_way.html).
+pub type TwoWayFuture = WrappedFuture<Result<TwoWaySummary, TransferError>>;
+
+/// How files are compared to decide whether they need copying.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Comparison {
+    /// Files of the same size are the same.
+    Size,
+    /// Files of the same size and modification time are the same.
+    ///
+    /// Times are compared to the second since not all backends store them
+    /// more precisely. Files with no modification time are always copied.
+    Modified,
+    /// Files with the same contents are the same.
+    ///
+    /// Files of the same size are read from both stores and compared which
+    /// is slow but catches every change.
+    Contents,
+}
+
+impl Default for Comparison {
+    fn default() -> Comparison {
+        Comparison::Modified
+    }
+}
+
+/// Options controlling a sync.
+#[derive(Clone, Debug)]
+pub struct SyncOptions {
+    /// How to decide whether a file has changed. Defaults to comparing size
+    /// and modification time.
+    pub comparison: Comparison,
+    /// The number of files to copy at once. Defaults to 4.
+    pub concurrency: usize,
+    /// Deletes files from the target that are not in the source.
+    pub delete: bool,
+    /// Works out what would change without changing anything.
+    pub dry_run: bool,
+}
+
+impl Default for SyncOptions {
+    fn default() -> SyncOptions {
+        SyncOptions {
+            comparison: Default::default(),
+            concurrency: DEFAULT_CONCURRENCY,
+            delete: false,
+            dry_run: false,
+        }
+    }
+}
+
+/// What a sync did.
+///
+/// Paths are relative to the prefixes that were synced.
+#[derive(Clone, Debug, Default)]
+pub struct SyncSummary {
+    /// The files copied to the target.
+    pub copied: Vec<ObjectPath>,
+    /// The files deleted from the target.
+    pub deleted: Vec<ObjectPath>,
+    /// The number of files that were already the same.
+    pub unchanged: usize,
+    /// The number of bytes copied.
+    pub bytes: u64,
+}
+
+/// Removes any trailing `/` characters from a prefix.
+pub(crate) fn directory(prefix: ObjectPath) -> StorageResult<ObjectPath> {
+    let parts: Vec<&str> = prefix
+        .parts()
+        .into_iter()
+        .filter(|p| !p.is_empty())
+        .collect();
+    ObjectPath::new(parts.join("/"))
+}
+
+/// Lists the files under a directory keyed by their path relative to it.
+pub(crate) async fn list_files<B>(
+    store: &B,
+    directory: &ObjectPath,
+) -> StorageResult<HashMap<String, Object>>
+where
+    B: StorageBackend,
+{
+    let skip = directory.parts().len();
+    let mut prefix = directory.clone();
+    if !prefix.is_empty() {
+        prefix.push_part("");
+    }
+
+    let objects: Vec<Object> = store.list_objects(prefix).await?.try_collect().await?;
+
+    Ok(objects
+        .into_iter()
+        .filter(|o| o.object_type() == ObjectType::File)
+        .map(|o| (o.path().parts()[skip..].join("/"), o))
+        .collect())
+}
+
+pub(crate) fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Compares two streams of data.
+async fn same_contents(
+    mut first: DataStream,
+    mut second: DataStream,
+) -> Result<bool, TransferError> {
+    let mut left = Data::new();
+    let mut right = Data::new();
+
+    loop {
+        if left.is_empty() {
+            match first.next().await {
+                Some(data) => left = data.map_err(TransferError::SourceError)?,
+                None => {
+                    // Skip any empty chunks left in the other stream.
+                    while right.is_empty() {
+                        match second.next().await {
+                            Some(data) => right = data.map_err(TransferError::TargetError)?,
+                            None => return Ok(true),
+                        }
+                    }
+                    return Ok(false);
+                }
+            }
+            continue;
+        }
+
+        if right.is_empty() {
+            match second.next().await {
+                Some(data) => right = data.map_err(TransferError::TargetError)?,
+                None => return Ok(false),
+            }
+            continue;
+        }
+
+        let length = left.len().min(right.len());
+        if left.split_to(length) != right.split_to(length) {
+            return Ok(false);
+        }
+    }
+}
+
+/// Checks whether a file needs to be copied over its existing copy.
+async fn changed<S, T>(
+    source: S,
+    source_object: Object,
+    target: T,
+    target_object: Object,
+    comparison: Comparison,
+) -> Result<bool, TransferError>
+where
+    S: StorageBackend,
+    T: StorageBackend,
+{
+    if source_object.len() != target_object.len() {
+        return Ok(true);
+    }
+
+    match comparison {
+        Comparison::Size => Ok(false),
+        Comparison::Modified => match (source_object.modified(), target_object.modified()) {
+            (Some(a), Some(b)) => Ok(seconds(a) != seconds(b)),
+            _ => Ok(true),
+        },
+        Comparison::Contents => {
+            let first = source
+                .get_file_stream(source_object.path())
+                .await
+                .map_err(TransferError::SourceError)?;
+            let second = target
+                .get_file_stream(target_object.path())
+                .await
+                .map_err(TransferError::TargetError)?;
+            Ok(!same_contents(first, second).await?)
+        }
+    }
+}
+
+/// Copies a file if it is missing from the target or has changed, returning
+/// the number of bytes copied.
+async fn sync_file<S, T>(
+    source: S,
+    source_object: Object,
+    target: T,
+    target_path: ObjectPath,
+    existing: Option<Object>,
+    options: SyncOptions,
+) -> Result<Option<u64>, TransferError>
+where
+    S: StorageBackend,
+    T: StorageBackend,
+{
+    if let Some(existing) = existing {
+        let changed = changed(
+            source.clone(),
+            source_object.clone(),
+            target.clone(),
+            existing,
+            options.comparison,
+        )
+        .await?;
+        if !changed {
+            return Ok(None);
+        }
+    }
+
+    if !options.dry_run {
+        let mut info = UploadInfo::from(target_path);
+        info.modified = source_object.modified();
+        info.options.content_length = Some(source_object.len());
+        let stream = source
+            .get_file_stream(source_object.path())
+            .await
+            .map_err(TransferError::SourceError)?;
+        target.write_file_from_stream(info, stream).await?;
+    }
+
+    Ok(Some(source_object.len()))
+}
+
+/// Makes the files under `target_prefix` in `target` match the files under
+/// `source_prefix` in `source`.
+///
+/// Prefixes are treated as directories so syncing `foo` includes `foo/bar`
+/// but not `foobar`. The first failure stops the sync, files already copied
+/// are left in place.
+pub fn sync<S, T, P, Q>(
+    source: &S,
+    source_prefix: P,
+    target: &T,
+    target_prefix: Q,
+    options: SyncOptions,
+) -> SyncFuture
+where
+    S: StorageBackend + Sync,
+    T: StorageBackend + Sync,
+    P: TryInto<ObjectPath>,
+    P::Error: Into<StorageError>,
+    Q: TryInto<ObjectPath>,
+    Q::Error: Into<StorageError>,
+{
+    async fn run<S, T>(
+        source: S,
+        source_prefix: ObjectPath,
+        target: T,
+        target_prefix: ObjectPath,
+        options: SyncOptions,
+    ) -> Result<SyncSummary, TransferError>
+    where
+        S: StorageBackend + Sync,
+        T: StorageBackend + Sync,
+    {
+        let source_files = list_files(&source, &source_prefix)
+            .await
+            .map_err(TransferError::SourceError)?;
+        let mut target_files = list_files(&target, &target_prefix)
+            .await
+            .map_err(TransferError::TargetError)?;
+
+        let mut summary = SyncSummary::default();
+
+        let mut files: Vec<(String, Object)> = source_files.into_iter().collect();
+        files.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut tasks = Vec::new();
+        for (path, source_object) in files {
+            let relative = ObjectPath::new(&path).map_err(TransferError::SourceError)?;
+            let existing = target_files.remove(&path);
+            tasks.push(
+                sync_file(
+                    source.clone(),
+                    source_object,
+                    target.clone(),
+                    target_prefix.join(&relative),
+                    existing,
+                    options.clone(),
+                )
+                .map_ok(move |copied| (relative, copied)),
+            );
+        }
+
+        let mut results = iter(tasks).buffer_unordered(options.concurrency.max(1));
+        while let Some(result) = results.next().await {
+            match result? {
+                (relative, Some(bytes)) => {
+                    summary.copied.push(relative);
+                    summary.bytes += bytes;
+                }
+                (_, None) => summary.unchanged += 1,
+            }
+        }
+
+        if options.delete {
+            let mut extra: Vec<(String, Object)> = target_files.into_iter().collect();
+            extra.sort_by(|a, b| a.0.cmp(&b.0));
+            for (path, object) in extra {
+                if !options.dry_run {
+                    target
+                        .delete_object(object.path())
+                        .await
+                        .map_err(TransferError::TargetError)?;
+                }
+                summary
+                    .deleted
+                    .push(ObjectPath::new(&path).map_err(TransferError::TargetError)?);
+            }
+        }
+
+        summary.copied.sort();
+        Ok(summary)
+    }
+
+    let source_prefix = match source_prefix
+        .try_into()
+        .map_err(Into::into)
+        .and_then(directory)
+    {
+        Ok(p) => p,
+        Err(e) => return SyncFuture::from_value(Err(TransferError::SourceError(e))),
+    };
+
+    let target_prefix = match target_prefix
+        .try_into()
+        .map_err(Into::into)
+        .and_then(directory)
+    {
+        Ok(p) => p,
+        Err(e) => return SyncFuture::from_value(Err(TransferError::TargetError(e))),
+    };
+
+    SyncFuture::from_future(run(
+        source.clone(),
+        source_prefix,
+        target.clone(),
+        target_prefix,
+        options,
+    ))
+}
+
+/// What to do with a file that has changed in both stores since the last
+/// two-way sync.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConflictPolicy {
+    /// The most recently modified version replaces the other. The first
+    /// store's version wins if both were modified at the same time.
+    NewerWins,
+    /// The second store's version is renamed to `name (conflicted copy).ext`
+    /// and both stores end up with both versions.
+    KeepBoth,
+    /// The sync fails with an
+    /// [`AlreadyExists`](../enum.StorageErrorKind.html#variant.AlreadyExists)
+    /// error.
+    Error,
+}
+
+impl Default for ConflictPolicy {
+    fn default() -> ConflictPolicy {
+        ConflictPolicy::KeepBoth
+    }
+}
+
+/// Options controlling a two-way sync.
+#[derive(Clone, Debug)]
+pub struct TwoWayOptions {
+    /// How to resolve files that have changed in both stores. Defaults to
+    /// keeping both versions.
+    pub conflicts: ConflictPolicy,
+    /// The number of files to sync at once. Defaults to 4.
+    pub concurrency: usize,
+    /// Works out what would change without changing anything, including the
+    /// manifest.
+    pub dry_run: bool,
+}
+
+impl Default for TwoWayOptions {
+    fn default() -> TwoWayOptions {
+        TwoWayOptions {
+            conflicts: Default::default(),
+            concurrency: DEFAULT_CONCURRENCY,
+            dry_run: false,
+        }
+    }
+}
+
+/// What a two-way sync did.
+///
+/// Paths are relative to the prefixes that were synced.
+#[derive(Clone, Debug, Default)]
+pub struct TwoWaySummary {
+    /// The files copied from the second store to the first.
+    pub to_first: Vec<ObjectPath>,
+    /// The files copied from the first store to the second.
+    pub to_second: Vec<ObjectPath>,
+    /// The files deleted from the first store.
+    pub deleted_first: Vec<ObjectPath>,
+    /// The files deleted from the second store.
+    pub deleted_second: Vec<ObjectPath>,
+    /// The files that had changed in both stores.
+    pub conflicts: Vec<ObjectPath>,
+    /// The number of files that had not changed.
+    pub unchanged: usize,
+}
+
+impl TwoWaySummary {
+    fn merge(&mut self, other: TwoWaySummary) {
+        self.to_first.extend(other.to_first);
+        self.to_second.extend(other.to_second);
+        self.deleted_first.extend(other.deleted_first);
+        self.deleted_second.extend(other.deleted_second);
+        self.conflicts.extend(other.conflicts);
+        self.unchanged += other.unchanged;
+    }
+
+    fn sort(&mut self) {
+        self.to_first.sort();
+        self.to_second.sort();
+        self.deleted_first.sort();
+        self.deleted_second.sort();
+        self.conflicts.sort();
+    }
+}
+
+const MANIFEST_HEADER: &str = "file-store sync 1";
+
+/// The size and modification time of a file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Version {
+    len: u64,
+    modified: Option<u64>,
+}
+
+impl Version {
+    fn of(object: &Object) -> Version {
+        Version {
+            len: object.len(),
+            modified: object.modified().map(seconds),
+        }
+    }
+
+    fn parse(len: &str, modified: &str) -> Option<Version> {
+        Some(Version {
+            len: len.parse().ok()?,
+            modified: match modified {
+                "-" => None,
+                m => Some(m.parse().ok()?),
+            },
+        })
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.modified {
+            Some(modified) => write!(f, "{} {}", self.len, modified),
+            None => write!(f, "{} -", self.len),
+        }
+    }
+}
+
+/// How each store's copy of a file looked after the last sync.
+#[derive(Clone, Copy, Debug)]
+struct Synced {
+    first: Version,
+    second: Version,
+}
+
+/// The state kept between two-way syncs, keyed by relative path.
+///
+/// It is stored as a header line followed by a line for each file holding the
+/// size and modification time in each store and then the path.
+type Manifest = HashMap<String, Synced>;
+
+fn parse_manifest(data: &[u8]) -> StorageResult<Manifest> {
+    let invalid = || error::invalid_data(Some("The sync manifest is not valid."));
+
+    let text = str::from_utf8(data).map_err(|_| invalid())?;
+    let mut lines = text.lines();
+    if lines.next() != Some(MANIFEST_HEADER) {
+        return Err(invalid());
+    }
+
+    let mut manifest = Manifest::new();
+    for line in lines {
+        let fields: Vec<&str> = line.splitn(5, ' ').collect();
+        if fields.len() != 5 {
+            return Err(invalid());
+        }
+
+        match (
+            Version::parse(fields[0], fields[1]),
+            Version::parse(fields[2], fields[3]),
+        ) {
+            (Some(first), Some(second)) => {
+                manifest.insert(fields[4].to_owned(), Synced { first, second });
+            }
+            _ => return Err(invalid()),
+        }
+    }
+
+    Ok(manifest)
+}
+
+fn format_manifest(manifest: &Manifest) -> String {
+    let mut paths: Vec<&String> = manifest.keys().collect();
+    paths.sort();
+
+    let mut text = format!("{}\n", MANIFEST_HEADER);
+    for path in paths {
+        let synced = &manifest[path];
+        text.push_str(&format!("{} {} {}\n", synced.first, synced.second, path));
+    }
+    text
+}
+
+async fn load_manifest<S>(store: &S, path: &ObjectPath) -> StorageResult<Manifest>
+where
+    S: StorageBackend,
+{
+    let stream = match store.get_file_stream(path.clone()).await {
+        Ok(stream) => stream,
+        Err(e) => match e.kind() {
+            StorageErrorKind::NotFound(_) => return Ok(Manifest::new()),
+            _ => return Err(e),
+        },
+    };
+
+    let data = stream.try_concat().await?;
+    parse_manifest(&data)
+}
+
+async fn save_manifest<S>(store: &S, path: ObjectPath, manifest: &Manifest) -> StorageResult<()>
+where
+    S: StorageBackend,
+{
+    let data = Data::from(format_manifest(manifest));
+    store
+        .write_file_from_stream(path, iter(vec![Ok::<Data, StorageError>(data)]))
+        .await
+        .map_err(|e| match e {
+            TransferError::SourceError(e) => e,
+            TransferError::TargetError(e) => e,
+        })
+}
+
+/// How one store's copy of a file has changed since the last sync.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Change {
+    /// The file has never been synced and does not exist.
+    Absent,
+    /// The file was synced but has since been deleted.
+    Deleted,
+    /// The file is the same as it was after the last sync.
+    Unchanged,
+    /// The file has been modified or was created since the last sync.
+    Changed,
+}
+
+fn change(object: Option<&Object>, known: Option<Version>) -> Change {
+    match (object, known) {
+        (None, None) => Change::Absent,
+        (None, Some(_)) => Change::Deleted,
+        (Some(object), Some(version)) if Version::of(object) == version => Change::Unchanged,
+        (Some(_), _) => Change::Changed,
+    }
+}
+
+/// Checks whether a file has changed differently in both stores.
+fn conflicted(first: &Object, second: &Object, known: Option<Synced>) -> bool {
+    change(Some(first), known.map(|k| k.first)) == Change::Changed
+        && change(Some(second), known.map(|k| k.second)) == Change::Changed
+        && Version::of(first) != Version::of(second)
+}
+
+/// Picks an unused name for the second store's version of a conflicted file.
+fn conflict_name(path: &str, taken: &HashSet<String>) -> String {
+    let (directory, name) = match path.rfind('/') {
+        Some(pos) => path.split_at(pos + 1),
+        None => ("", path),
+    };
+    let (stem, extension) = match name.rfind('.') {
+        Some(pos) if pos > 0 => name.split_at(pos),
+        _ => (name, ""),
+    };
+
+    let mut count = 1;
+    loop {
+        let candidate = if count == 1 {
+            format!("{}{} (conflicted copy){}", directory, stem, extension)
+        } else {
+            format!(
+                "{}{} (conflicted copy {}){}",
+                directory, stem, count, extension
+            )
+        };
+        if !taken.contains(&candidate) {
+            return candidate;
+        }
+        count += 1;
+    }
+}
+
+/// Copies a file between stores keeping its modification time, returning how
+/// the source and the new copy look.
+async fn transfer<S, T>(
+    source: &S,
+    object: &Object,
+    target: &T,
+    path: ObjectPath,
+) -> Result<(Version, Version), TransferError>
+where
+    S: StorageBackend,
+    T: StorageBackend,
+{
+    let mut info = UploadInfo::from(path.clone());
+    info.modified = object.modified();
+    info.options.content_length = Some(object.len());
+    let stream = source
+        .get_file_stream(object.path())
+        .await
+        .map_err(TransferError::SourceError)?;
+    target.write_file_from_stream(info, stream).await?;
+
+    let copy = target
+        .get_object(path)
+        .await
+        .map_err(TransferError::TargetError)?;
+    Ok((Version::of(object), Version::of(&copy)))
+}
+
+/// Swaps the direction of an error from a transfer from the second store to
+/// the first.
+fn reverse(error: TransferError) -> TransferError {
+    match error {
+        TransferError::SourceError(e) => TransferError::TargetError(e),
+        TransferError::TargetError(e) => TransferError::SourceError(e),
+    }
+}
+
+#[derive(Clone)]
+struct Stores<S, T> {
+    first: S,
+    first_prefix: ObjectPath,
+    second: T,
+    second_prefix: ObjectPath,
+}
+
+impl<S, T> Stores<S, T>
+where
+    S: StorageBackend + Sync,
+    T: StorageBackend + Sync,
+{
+    async fn to_second(
+        &self,
+        object: &Object,
+        relative: &ObjectPath,
+    ) -> Result<Synced, TransferError> {
+        let (first, second) = transfer(
+            &self.first,
+            object,
+            &self.second,
+            self.second_prefix.join(relative),
+        )
+        .await?;
+        Ok(Synced { first, second })
+    }
+
+    async fn to_first(
+        &self,
+        object: &Object,
+        relative: &ObjectPath,
+    ) -> Result<Synced, TransferError> {
+        let (second, first) = transfer(
+            &self.second,
+            object,
+            &self.first,
+            self.first_prefix.join(relative),
+        )
+        .await
+        .map_err(reverse)?;
+        Ok(Synced { first, second })
+    }
+
+    /// Keeps the second store's version of a file under a new name in both
+    /// stores.
+    async fn keep_second(
+        &self,
+        object: &Object,
+        relative: &ObjectPath,
+    ) -> Result<Synced, TransferError> {
+        let target = self.second_prefix.join(relative);
+        let mut info = UploadInfo::from(target.clone());
+        info.options.preserve_modified = true;
+        self.second
+            .copy_file(object.path(), info)
+            .await
+            .map_err(|e| match e {
+                TransferError::SourceError(e) => TransferError::TargetError(e),
+                e => e,
+            })?;
+
+        let copy = self
+            .second
+            .get_object(target)
+            .await
+            .map_err(TransferError::TargetError)?;
+        self.to_first(&copy, relative).await
+    }
+}
+
+/// The changes made while syncing a single file and the manifest entries to
+/// update.
+type Reconciled = (TwoWaySummary, Vec<(String, Option<Synced>)>);
+
+/// Syncs a single file.
+async fn reconcile<S, T>(
+    stores: Stores<S, T>,
+    path: String,
+    first: Option<Object>,
+    second: Option<Object>,
+    known: Option<Synced>,
+    conflict: Option<String>,
+    options: TwoWayOptions,
+) -> Result<Reconciled, TransferError>
+where
+    S: StorageBackend + Sync,
+    T: StorageBackend + Sync,
+{
+    let relative = ObjectPath::new(&path).map_err(TransferError::SourceError)?;
+    let first_change = change(first.as_ref(), known.map(|k| k.first));
+    let second_change = change(second.as_ref(), known.map(|k| k.second));
+    let dry_run = options.dry_run;
+
+    let mut summary = TwoWaySummary::default();
+    let mut updates = Vec::new();
+
+    match (first, second) {
+        (None, None) => updates.push((path, None)),
+        (Some(object), None) => {
+            if first_change == Change::Unchanged && second_change == Change::Deleted {
+                if !dry_run {
+                    stores
+                        .first
+                        .delete_object(object.path())
+                        .await
+                        .map_err(TransferError::SourceError)?;
+                }
+                updates.push((path, None));
+                summary.deleted_first.push(relative);
+            } else {
+                if !dry_run {
+                    updates.push((path, Some(stores.to_second(&object, &relative).await?)));
+                }
+                summary.to_second.push(relative);
+            }
+        }
+        (None, Some(object)) => {
+            if second_change == Change::Unchanged && first_change == Change::Deleted {
+                if !dry_run {
+                    stores
+                        .second
+                        .delete_object(object.path())
+                        .await
+                        .map_err(TransferError::TargetError)?;
+                }
+                updates.push((path, None));
+                summary.deleted_second.push(relative);
+            } else {
+                if !dry_run {
+                    updates.push((path, Some(stores.to_first(&object, &relative).await?)));
+                }
+                summary.to_first.push(relative);
+            }
+        }
+        (Some(a), Some(b)) => {
+            if conflicted(&a, &b, known) {
+                summary.conflicts.push(relative.clone());
+                match options.conflicts {
+                    ConflictPolicy::Error => {
+                        return Err(TransferError::TargetError(error::already_exists(
+                            b.path(),
+                            Some("The file has changed in both stores."),
+                        )));
+                    }
+                    ConflictPolicy::NewerWins => {
+                        if b.modified() > a.modified() {
+                            if !dry_run {
+                                updates.push((path, Some(stores.to_first(&b, &relative).await?)));
+                            }
+                            summary.to_first.push(relative);
+                        } else {
+                            if !dry_run {
+                                updates.push((path, Some(stores.to_second(&a, &relative).await?)));
+                            }
+                            summary.to_second.push(relative);
+                        }
+                    }
+                    ConflictPolicy::KeepBoth => {
+                        let name =
+                            conflict.unwrap_or_else(|| conflict_name(&path, &HashSet::new()));
+                        let renamed = ObjectPath::new(&name).map_err(TransferError::TargetError)?;
+                        if !dry_run {
+                            updates.push((name, Some(stores.keep_second(&b, &renamed).await?)));
+                            updates.push((path, Some(stores.to_second(&a, &relative).await?)));
+                        }
+                        summary.to_first.push(renamed);
+                        summary.to_second.push(relative);
+                    }
+                }
+            } else {
+                match (first_change, second_change) {
+                    (Change::Unchanged, Change::Unchanged) => summary.unchanged += 1,
+                    (Change::Changed, Change::Unchanged) => {
+                        if !dry_run {
+                            updates.push((path, Some(stores.to_second(&a, &relative).await?)));
+                        }
+                        summary.to_second.push(relative);
+                    }
+                    (Change::Unchanged, Change::Changed) => {
+                        if !dry_run {
+                            updates.push((path, Some(stores.to_first(&b, &relative).await?)));
+                        }
+                        summary.to_first.push(relative);
+                    }
+                    _ => {
+                        // Changed in both stores in the same way.
+                        let synced = Synced {
+                            first: Version::of(&a),
+                            second: Version::of(&b),
+                        };
+                        updates.push((path, Some(synced)));
+                        summary.unchanged += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    Ok((summary, updates))
+}
+
+/// Gets the path of an object relative to a directory if it is inside it.
+fn relative_to(directory: &ObjectPath, path: &ObjectPath) -> Option<String> {
+    let directory = directory.parts();
+    let parts = path.parts();
+    if parts.len() > directory.len() && parts.starts_with(&directory) {
+        Some(parts[directory.len()..].join("/"))
+    } else {
+        None
+    }
+}
+
+/// Syncs changes in both directions between the files under `first_prefix`
+/// in `first` and the files under `second_prefix` in `second`.
+///
+/// Files that have changed in one store are copied to the other and files
+/// deleted from one store are deleted from the other. Files are compared by
+/// size and modification time. The state used to spot these changes is kept
+/// in a manifest at `state` in the first store, which is never synced itself.
+/// Without a manifest, for example on the first sync, nothing is deleted and
+/// files that differ between the stores are conflicts.
+///
+/// Errors from the first store are reported as source errors and errors from
+/// the second store as target errors. The manifest is only saved once every
+/// file has been synced but files that were already copied when a failure
+/// happens are seen as unchanged the next time.
+pub fn two_way<S, T, P, Q, R>(
+    first: &S,
+    first_prefix: P,
+    second: &T,
+    second_prefix: Q,
+    state: R,
+    options: TwoWayOptions,
+) -> TwoWayFuture
+where
+    S: StorageBackend + Sync,
+    T: StorageBackend + Sync,
+    P: TryInto<ObjectPath>,
+    P::Error: Into<StorageError>,
+    Q: TryInto<ObjectPath>,
+    Q::Error: Into<StorageError>,
+    R: TryInto<ObjectPath>,
+    R::Error: Into<StorageError>,
+{
+    async fn run<S, T>(
+        stores: Stores<S, T>,
+        state: ObjectPath,
+        options: TwoWayOptions,
+    ) -> Result<TwoWaySummary, TransferError>
+    where
+        S: StorageBackend + Sync,
+        T: StorageBackend + Sync,
+    {
+        let mut manifest = load_manifest(&stores.first, &state)
+            .await
+            .map_err(TransferError::SourceError)?;
+        let mut first_files = list_files(&stores.first, &stores.first_prefix)
+            .await
+            .map_err(TransferError::SourceError)?;
+        let mut second_files = list_files(&stores.second, &stores.second_prefix)
+            .await
+            .map_err(TransferError::TargetError)?;
+
+        if let Some(relative) = relative_to(&stores.first_prefix, &state) {
+            first_files.remove(&relative);
+            second_files.remove(&relative);
+            manifest.remove(&relative);
+        }
+
+        let taken: HashSet<String> = first_files
+            .keys()
+            .chain(second_files.keys())
+            .chain(manifest.keys())
+            .cloned()
+            .collect();
+        let mut paths: Vec<String> = taken.iter().cloned().collect();
+        paths.sort();
+
+        let mut taken = taken;
+        let mut tasks = Vec::new();
+        for path in paths {
+            let first = first_files.remove(&path);
+            let second = second_files.remove(&path);
+            let known = manifest.get(&path).cloned();
+
+            let conflict = match (&first, &second) {
+                (Some(a), Some(b))
+                    if options.conflicts == ConflictPolicy::KeepBoth && conflicted(a, b, known) =>
+                {
+                    let name = conflict_name(&path, &taken);
+                    taken.insert(name.clone());
+                    Some(name)
+                }
+                _ => None,
+            };
+
+            tasks.push(reconcile(
+                stores.clone(),
+                path,
+                first,
+                second,
+                known,
+                conflict,
+                options.clone(),
+            ));
+        }
+
+        let mut summary = TwoWaySummary::default();
+        let mut results = iter(tasks).buffer_unordered(options.concurrency.max(1));
+        while let Some(result) = results.next().await {
+            let (changes, updates) = result?;
+            summary.merge(changes);
+            for (path, synced) in updates {
+                match synced {
+                    Some(synced) => manifest.insert(path, synced),
+                    None => manifest.remove(&path),
+                };
+            }
+        }
+
+        if !options.dry_run {
+            save_manifest(&stores.first, state, &manifest)
+                .await
+                .map_err(TransferError::SourceError)?;
+        }
+
+        summary.sort();
+        Ok(summary)
+    }
+
+    let first_prefix = match first_prefix
+        .try_into()
+        .map_err(Into::into)
+        .and_then(directory)
+    {
+        Ok(p) => p,
+        Err(e) => return TwoWayFuture::from_value(Err(TransferError::SourceError(e))),
+    };
+
+    let second_prefix = match second_prefix
+        .try_into()
+        .map_err(Into::into)
+        .and_then(directory)
+    {
+        Ok(p) => p,
+        Err(e) => return TwoWayFuture::from_value(Err(TransferError::TargetError(e))),
+    };
+
+    let state = match state.try_into() {
+        Ok(p) => p,
+        Err(e) => return TwoWayFuture::from_value(Err(TransferError::SourceError(e.into()))),
+    };
+
+    let stores = Stores {
+        first: first.clone(),
+        first_prefix,
+        second: second.clone(),
+        second_prefix,
+    };
+
+    TwoWayFuture::from_future(run(stores, state, options))
+}
@@ -0,0 +1,410 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Tagging files with small key-value pairs.
+//!
+//! Tags are kept apart from a file's data and can be changed without writing
+//! the file again. Neither backend has native tags so a
+//! [`TagStore`](struct.TagStore.html) keeps the tags of each file in a sidecar
+//! object at `<prefix>/<name>`, where `<name>` is the file's path with `%` and
+//! `/` characters percent encoded. Sidecars are small text files listing one
+//! `key=value` pair per line so tag keys cannot be empty or contain `=` and
+//! neither keys nor values can contain new lines.
+//!
+//! [`TagStore::store`](struct.TagStore.html#method.store) wraps a store so
+//! that tags follow the files they belong to. Copying or moving a file copies
+//! or moves its tags, writing a file clears them and deleting a file deletes
+//! them. Objects under the prefix are not included when listing objects
+//! outside of it. Deleting a directory with the file backend leaves the tags
+//! of the files inside it behind.
+use std::collections::{BTreeMap, HashMap};
+use std::str::from_utf8;
+
+use futures::future::{ready, TryFutureExt};
+use futures::stream::{iter, TryStreamExt};
+
+use crate::backends::Backend;
+use crate::dynamic::{self, DynamicStore};
+use crate::sync::{directory, list_files};
+use crate::trash::{contains, decode, encode, hide};
+use crate::types::*;
+use crate::{FileStore, ObjectInfo, StorageBackend};
+
+const TAGS_HEADER: &str = "file-store tags 1";
+
+/// The tags of a file.
+pub type Tags = BTreeMap<String, String>;
+
+/// Future returned by [`TagStore::get_tags`](struct.TagStore.html#method.get_tags).
+pub type TagsFuture = WrappedFuture<StorageResult<Tags>>;
+
+fn parse_tags(data: &[u8]) -> StorageResult<Tags> {
+    let text =
+        from_utf8(data).map_err(|_| error::invalid_data(Some("The tags are not valid UTF-8.")))?;
+    let mut lines = text.lines();
+    if lines.next() != Some(TAGS_HEADER) {
+        return Err(error::invalid_data(Some(
+            "The tags have an unknown format.",
+        )));
+    }
+
+    let mut tags = Tags::new();
+    for line in lines {
+        let mut fields = line.splitn(2, '=');
+        match (fields.next(), fields.next()) {
+            (Some(key), Some(value)) if !key.is_empty() => {
+                tags.insert(key.to_owned(), value.to_owned());
+            }
+            _ => {
+                return Err(error::invalid_data(Some(
+                    "The tags contain an invalid line.",
+                )))
+            }
+        }
+    }
+
+    Ok(tags)
+}
+
+fn format_tags(tags: &Tags) -> String {
+    let mut text = format!("{}\n", TAGS_HEADER);
+    for (key, value) in tags {
+        text.push_str(&format!("{}={}\n", key, value));
+    }
+    text
+}
+
+fn check_tags(tags: &Tags) -> StorageResult<()> {
+    for (key, value) in tags {
+        if key.is_empty() || key.contains(|c| c == '=' || c == '\n' || c == '\r') {
+            return Err(error::invalid_data(Some(&format!(
+                "The tag key '{}' is empty or contains '=' or a new line.",
+                key
+            ))));
+        }
+
+        if value.contains(|c| c == '\n' || c == '\r') {
+            return Err(error::invalid_data(Some(&format!(
+                "The value of the tag '{}' contains a new line.",
+                key
+            ))));
+        }
+    }
+    Ok(())
+}
+
+fn ignore_not_found(result: StorageResult<()>) -> StorageResult<()> {
+    match result {
+        Err(e) => match e.kind() {
+            StorageErrorKind::NotFound(_) => Ok(()),
+            _ => Err(e),
+        },
+        result => result,
+    }
+}
+
+async fn read_tags(store: &FileStore, sidecar: ObjectPath) -> StorageResult<Option<Tags>> {
+    let stream = match store.get_file_stream(sidecar).await {
+        Ok(stream) => stream,
+        Err(e) => match e.kind() {
+            StorageErrorKind::NotFound(_) => return Ok(None),
+            _ => return Err(e),
+        },
+    };
+
+    let data = stream.map_ok(|data| data.to_vec()).try_concat().await?;
+    parse_tags(&data).map(Some)
+}
+
+async fn write_tags(store: &FileStore, sidecar: ObjectPath, tags: &Tags) -> StorageResult<()> {
+    if tags.is_empty() {
+        return ignore_not_found(store.delete_object(sidecar).await);
+    }
+
+    let data = Data::from(format_tags(tags));
+    store
+        .write_file_from_stream(sidecar, iter(vec![Ok::<Data, StorageError>(data)]))
+        .await
+        .map_err(|e| match e {
+            TransferError::SourceError(e) => e,
+            TransferError::TargetError(e) => e,
+        })
+}
+
+fn matches(tags: &Tags, key: &str, value: Option<&str>) -> bool {
+    match (tags.get(key), value) {
+        (Some(found), Some(value)) => found == value,
+        (Some(_), None) => true,
+        (None, _) => false,
+    }
+}
+
+/// Keeps tags for the files in a store.
+///
+/// See the [`tags`](index.html) module.
+#[derive(Clone, Debug)]
+pub struct TagStore {
+    store: FileStore,
+    prefix: ObjectPath,
+}
+
+impl TagStore {
+    /// Creates a tag store keeping tags under the given prefix of the store.
+    pub fn new(store: FileStore, prefix: ObjectPath) -> StorageResult<TagStore> {
+        let prefix = directory(prefix)?;
+        if prefix.is_empty() {
+            return Err(error::invalid_path(
+                prefix,
+                Some("Tags cannot be kept at the root of the store."),
+            ));
+        }
+
+        Ok(TagStore { store, prefix })
+    }
+
+    /// Wraps the store so that tags follow the files they belong to and the
+    /// tag sidecars are hidden from listings.
+    pub fn store(&self) -> FileStore {
+        FileStore::from(DynamicStore::new(TaggedStore(self.clone())))
+    }
+
+    fn sidecar(&self, path: &ObjectPath) -> ObjectPath {
+        let mut sidecar = self.prefix.clone();
+        sidecar.push_part(&encode(path));
+        sidecar
+    }
+
+    /// Gets the tags of a file.
+    ///
+    /// Fails with a [`NotFound`](../enum.StorageErrorKind.html#variant.NotFound)
+    /// error if the file doesn't exist.
+    pub fn get_tags(&self, path: ObjectPath) -> TagsFuture {
+        TagsFuture::from_future(get_tags(self.clone(), path))
+    }
+
+    /// Replaces the tags of a file. Setting no tags removes the sidecar.
+    ///
+    /// Fails with a [`NotFound`](../enum.StorageErrorKind.html#variant.NotFound)
+    /// error if the file doesn't exist.
+    pub fn set_tags(&self, path: ObjectPath, tags: Tags) -> OperationCompleteFuture {
+        if let Err(e) = check_tags(&tags) {
+            return OperationCompleteFuture::from_value(Err(e));
+        }
+
+        OperationCompleteFuture::from_future(set_tags(self.clone(), path, tags))
+    }
+
+    /// Lists the objects with the given prefix that have the tag `key`, with
+    /// the given value if one is given.
+    pub fn list_tagged(
+        &self,
+        prefix: ObjectPath,
+        key: &str,
+        value: Option<&str>,
+    ) -> ObjectStreamFuture {
+        ObjectStreamFuture::from_future(list_tagged(
+            self.clone(),
+            prefix,
+            key.to_owned(),
+            value.map(str::to_owned),
+        ))
+    }
+}
+
+async fn get_tags(layer: TagStore, path: ObjectPath) -> StorageResult<Tags> {
+    if let Some(tags) = read_tags(&layer.store, layer.sidecar(&path)).await? {
+        return Ok(tags);
+    }
+
+    layer.store.get_object(path).await?;
+    Ok(Tags::new())
+}
+
+async fn set_tags(layer: TagStore, path: ObjectPath, tags: Tags) -> StorageResult<()> {
+    let object = layer.store.get_object(path.clone()).await?;
+    if object.object_type() != ObjectType::File {
+        return Err(error::invalid_path(path, Some("Only files can be tagged.")));
+    }
+
+    write_tags(&layer.store, layer.sidecar(&path), &tags).await
+}
+
+async fn list_tagged(
+    layer: TagStore,
+    prefix: ObjectPath,
+    key: String,
+    value: Option<String>,
+) -> StorageResult<ObjectStream> {
+    let sidecars = match list_files(&layer.store, &layer.prefix).await {
+        Ok(sidecars) => sidecars,
+        Err(e) => match e.kind() {
+            StorageErrorKind::NotFound(_) => HashMap::new(),
+            _ => return Err(e),
+        },
+    };
+
+    let mut tagged = Vec::new();
+    for (name, sidecar) in sidecars {
+        if name.contains('/') {
+            continue;
+        }
+
+        let path = ObjectPath::new(decode(&name))?;
+        if !path.starts_with(&prefix) {
+            continue;
+        }
+
+        if let Some(tags) = read_tags(&layer.store, sidecar.path()).await? {
+            if matches(&tags, &key, value.as_ref().map(String::as_str)) {
+                tagged.push(path);
+            }
+        }
+    }
+
+    let listing = layer.store.list_objects(prefix.clone()).await?;
+    Ok(ObjectStream::from_stream(
+        hide(listing, Some(layer.prefix), &prefix)
+            .try_filter(move |object| ready(tagged.contains(&object.path()))),
+    ))
+}
+
+async fn delete(layer: TagStore, path: ObjectPath) -> StorageResult<()> {
+    layer.store.delete_object(path.clone()).await?;
+    ignore_not_found(layer.store.delete_object(layer.sidecar(&path)).await)
+}
+
+async fn write(layer: TagStore, info: UploadInfo, stream: DataStream) -> Result<(), TransferError> {
+    let sidecar = layer.sidecar(&info.path);
+    layer.store.write_file_from_stream(info, stream).await?;
+    ignore_not_found(layer.store.delete_object(sidecar).await).map_err(TransferError::TargetError)
+}
+
+async fn copy(
+    layer: TagStore,
+    source: ObjectPath,
+    target: UploadInfo,
+    remove: bool,
+) -> Result<(), TransferError> {
+    let tags = read_tags(&layer.store, layer.sidecar(&source))
+        .await
+        .map_err(TransferError::SourceError)?
+        .unwrap_or_default();
+    let sidecar = layer.sidecar(&target.path);
+
+    if remove {
+        layer.store.move_file(source.clone(), target).await?;
+        ignore_not_found(layer.store.delete_object(layer.sidecar(&source)).await)
+            .map_err(TransferError::SourceError)?;
+    } else {
+        layer.store.copy_file(source, target).await?;
+    }
+
+    write_tags(&layer.store, sidecar, &tags)
+        .await
+        .map_err(TransferError::TargetError)
+}
+
+/// The backend of the store returned by
+/// [`TagStore::store`](struct.TagStore.html#method.store).
+struct TaggedStore(TagStore);
+
+impl TaggedStore {
+    fn is_tags(&self, path: &ObjectPath) -> bool {
+        contains(&self.0.prefix, path)
+    }
+}
+
+// Only StorageBackend is in scope so calls on the wrapped store are not
+// ambiguous.
+impl dynamic::DynamicBackend for TaggedStore {
+    fn backend_type(&self) -> Backend {
+        self.0.store.backend_type()
+    }
+
+    fn authorize(&self) -> OperationCompleteFuture {
+        self.0.store.authorize()
+    }
+
+    fn validate_path(&self, path: &ObjectPath) -> StorageResult<()> {
+        self.0.store.validate_path(path)
+    }
+
+    fn list_objects(&self, prefix: ObjectPath) -> ObjectStreamFuture {
+        let tags = self.0.prefix.clone();
+        ObjectStreamFuture::from_future(
+            self.0
+                .store
+                .list_objects(prefix.clone())
+                .map_ok(move |stream| hide(stream, Some(tags), &prefix)),
+        )
+    }
+
+    fn list_directory(&self, dir: ObjectPath) -> ObjectStreamFuture {
+        let tags = self.0.prefix.clone();
+        ObjectStreamFuture::from_future(
+            self.0
+                .store
+                .list_directory(dir.clone())
+                .map_ok(move |stream| hide(stream, Some(tags), &dir)),
+        )
+    }
+
+    fn get_object(&self, path: ObjectPath) -> ObjectFuture {
+        self.0.store.get_object(path)
+    }
+
+    fn get_file_stream(&self, path: ObjectPath) -> DataStreamFuture {
+        self.0.store.get_file_stream(path)
+    }
+
+    fn get_file_stream_with_options(
+        &self,
+        path: ObjectPath,
+        options: ReadOptions,
+    ) -> DataStreamFuture {
+        self.0.store.get_file_stream_with_options(path, options)
+    }
+
+    fn copy_file(&self, source: ObjectPath, target: UploadInfo) -> CopyCompleteFuture {
+        if self.is_tags(&source) || self.is_tags(&target.path) {
+            return self.0.store.copy_file(source, target);
+        }
+
+        CopyCompleteFuture::from_future(copy(self.0.clone(), source, target, false))
+    }
+
+    fn move_file(&self, source: ObjectPath, target: UploadInfo) -> MoveCompleteFuture {
+        if self.is_tags(&source) || self.is_tags(&target.path) {
+            return self.0.store.move_file(source, target);
+        }
+
+        MoveCompleteFuture::from_future(copy(self.0.clone(), source, target, true))
+    }
+
+    fn delete_object(&self, path: ObjectPath) -> OperationCompleteFuture {
+        if self.is_tags(&path) {
+            return self.0.store.delete_object(path);
+        }
+
+        OperationCompleteFuture::from_future(delete(self.0.clone(), path))
+    }
+
+    fn write_file_from_stream(&self, info: UploadInfo, stream: DataStream) -> WriteCompleteFuture {
+        if self.is_tags(&info.path) {
+            return self.0.store.write_file_from_stream(info, stream);
+        }
+
+        WriteCompleteFuture::from_future(write(self.0.clone(), info, stream))
+    }
+}
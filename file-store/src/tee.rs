@@ -0,0 +1,173 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Sharing one stream of data between several consumers.
+//!
+//! [`tee`](fn.tee.html) splits a [`DataStream`](../type.DataStream.html), for
+//! example from [`get_file_stream`](../trait.StorageBackend.html#method.get_file_stream),
+//! into a number of streams that each see every chunk of data. The file is
+//! only downloaded once and is never held in memory in full:
+//!
+//! ```no_run
+//! # use file_store::{FileStore, ObjectPath, StorageBackend};
+//! # use file_store::tee::tee;
+//! # async fn example(store: FileStore, backup: FileStore) {
+//! let path = ObjectPath::new("file.txt").unwrap();
+//! let stream = store.get_file_stream(path.clone()).await.unwrap();
+//! let mut streams = tee(stream, 2, 8);
+//! let local = streams.pop().unwrap();
+//! let remote = streams.pop().unwrap();
+//! # }
+//! ```
+//!
+//! Each consumer buffers a limited number of chunks. Once a consumer's buffer
+//! is full no more data is read from the source until that consumer catches up
+//! so the slowest consumer sets the pace. The consumers must therefore be read
+//! concurrently. Dropping a consumer stops it from holding the others back.
+//!
+//! An error from the source is passed to every consumer and ends the streams.
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+
+use futures::stream::{Stream, StreamExt};
+
+use crate::types::*;
+
+struct Shared {
+    source: DataStream,
+    buffer: usize,
+    queues: Vec<Option<VecDeque<StorageResult<Data>>>>,
+    wakers: Vec<Option<Waker>>,
+    complete: bool,
+}
+
+impl Shared {
+    fn is_full(&self) -> bool {
+        self.queues
+            .iter()
+            .any(|queue| queue.as_ref().map_or(false, |q| q.len() >= self.buffer))
+    }
+
+    fn push(&mut self, item: StorageResult<Data>) {
+        for queue in self.queues.iter_mut().filter_map(Option::as_mut) {
+            queue.push_back(item.clone());
+        }
+    }
+
+    fn wake_others(&mut self, index: usize) {
+        for (i, waker) in self.wakers.iter_mut().enumerate() {
+            if i != index {
+                if let Some(waker) = waker.take() {
+                    waker.wake();
+                }
+            }
+        }
+    }
+}
+
+/// One of the streams returned by [`tee`](fn.tee.html).
+struct TeeStream {
+    shared: Arc<Mutex<Shared>>,
+    index: usize,
+}
+
+impl TeeStream {
+    fn shared(&self) -> MutexGuard<Shared> {
+        match self.shared.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+impl Stream for TeeStream {
+    type Item = StorageResult<Data>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let index = self.index;
+        let mut shared = self.shared();
+
+        loop {
+            let next = shared.queues[index].as_mut().and_then(VecDeque::pop_front);
+            if let Some(item) = next {
+                // There may now be room to read more from the source.
+                shared.wake_others(index);
+                return Poll::Ready(Some(item));
+            }
+
+            if shared.complete {
+                return Poll::Ready(None);
+            }
+
+            if shared.is_full() {
+                shared.wakers[index] = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+
+            match shared.source.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(data))) => shared.push(Ok(data)),
+                Poll::Ready(Some(Err(e))) => {
+                    shared.push(Err(e));
+                    shared.complete = true;
+                }
+                Poll::Ready(None) => shared.complete = true,
+                Poll::Pending => {
+                    // Only the last consumer to poll the source is woken by
+                    // it so that one wakes the rest.
+                    shared.wakers[index] = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+            }
+
+            shared.wake_others(index);
+        }
+    }
+}
+
+impl Drop for TeeStream {
+    fn drop(&mut self) {
+        let index = self.index;
+        let mut shared = self.shared();
+        shared.queues[index] = None;
+        shared.wakers[index] = None;
+        // One of the others may have been waiting on this consumer or relying
+        // on it to be woken by the source.
+        shared.wake_others(index);
+    }
+}
+
+/// Splits a stream into `consumers` streams that each see all of its data.
+///
+/// Every consumer buffers at most `buffer` chunks, which must be at least one.
+/// See the [`tee`](index.html) module.
+pub fn tee(stream: DataStream, consumers: usize, buffer: usize) -> Vec<DataStream> {
+    let shared = Arc::new(Mutex::new(Shared {
+        source: stream,
+        buffer: buffer.max(1),
+        queues: (0..consumers).map(|_| Some(VecDeque::new())).collect(),
+        wakers: (0..consumers).map(|_| None).collect(),
+        complete: false,
+    }));
+
+    (0..consumers)
+        .map(|index| {
+            DataStream::from_stream(TeeStream {
+                shared: shared.clone(),
+                index,
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,544 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A queue of file transfers run in the background. Included with the feature
+//! "transfers".
+//!
+//! A [`TransferManager`](struct.TransferManager.html) accepts any number of
+//! [`TransferJob`s](struct.TransferJob.html), each copying a file from one
+//! [`FileStore`](../enum.FileStore.html) to another. Uploads and downloads are
+//! just transfers where one side is a local store.
+//!
+//! Only a limited number of jobs run at once, the rest wait in the queue with
+//! higher priority jobs starting first and jobs of the same priority starting
+//! in the order they were added. The data of all running jobs can be limited
+//! to a total bandwidth. Jobs can be paused, resumed and cancelled at any time
+//! and the progress of every job is reported as a stream of
+//! [`JobEvent`s](struct.JobEvent.html).
+//!
+//! Jobs are spawned onto the default executor so the manager must be used
+//! from within a tokio runtime.
+use std::cmp::{max, Ordering};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use futures::channel::mpsc::{unbounded, UnboundedSender};
+use futures::stream::Stream;
+use log::trace;
+use tokio_executor::spawn;
+use tokio_timer::{delay, Delay};
+
+use crate::transform::{apply, Transform};
+use crate::types::*;
+use crate::{FileStore, StorageBackend};
+
+const DEFAULT_CONCURRENCY: usize = 4;
+
+/// Stream returned by [`TransferManager::events`](struct.TransferManager.html#method.events).
+pub type JobEvents = WrappedStream<JobEvent>;
+
+/// Identifies a job added to a [`TransferManager`](struct.TransferManager.html).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct JobId(u64);
+
+/// A file to copy from one store to another.
+#[derive(Clone)]
+pub struct TransferJob {
+    source: FileStore,
+    source_path: ObjectPath,
+    target: FileStore,
+    target_path: ObjectPath,
+    priority: i32,
+    transform: Option<Arc<dyn Transform>>,
+}
+
+impl fmt::Debug for TransferJob {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TransferJob")
+            .field("source", &self.source)
+            .field("source_path", &self.source_path)
+            .field("target", &self.target)
+            .field("target_path", &self.target_path)
+            .field("priority", &self.priority)
+            .field("transformed", &self.transform.is_some())
+            .finish()
+    }
+}
+
+impl TransferJob {
+    /// Creates a job copying the file at `source_path` in `source` to
+    /// `target_path` in `target`, replacing any file already there.
+    pub fn new(
+        source: FileStore,
+        source_path: ObjectPath,
+        target: FileStore,
+        target_path: ObjectPath,
+    ) -> TransferJob {
+        TransferJob {
+            source,
+            source_path,
+            target,
+            target_path,
+            priority: 0,
+            transform: None,
+        }
+    }
+
+    /// Sets the priority of the job, higher priority jobs start first. The
+    /// default priority is 0.
+    pub fn priority(mut self, priority: i32) -> TransferJob {
+        self.priority = priority;
+        self
+    }
+
+    /// The path the file is copied to.
+    pub fn target_path(&self) -> &ObjectPath {
+        &self.target_path
+    }
+
+    /// Passes the file's data through a transform on the way to the target.
+    ///
+    /// See the [`transform`](../transform/index.html) module.
+    pub fn transform<T>(mut self, transform: T) -> TransferJob
+    where
+        T: Transform,
+    {
+        self.transform = Some(Arc::new(transform));
+        self
+    }
+}
+
+/// Where a job that has not yet finished is in the queue.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum JobStatus {
+    /// Waiting for other jobs to finish.
+    Queued,
+    /// Transferring data.
+    Running,
+    /// Paused, the job will not run until it is resumed.
+    Paused,
+}
+
+/// The kinds of events reported for a job.
+#[derive(Clone, Debug, PartialEq)]
+pub enum JobEventKind {
+    /// The job was added to the queue or was resumed.
+    Queued,
+    /// The job started or continued transferring data.
+    Started,
+    /// The job was paused.
+    Paused,
+    /// The job has transferred the given total number of bytes.
+    Progress(u64),
+    /// The job finished successfully.
+    Completed,
+    /// The job failed.
+    Failed(StorageErrorKind),
+    /// The job was cancelled.
+    Cancelled,
+}
+
+/// Something that happened to a job.
+#[derive(Clone, Debug, PartialEq)]
+pub struct JobEvent {
+    /// The job.
+    pub job: JobId,
+    /// What happened.
+    pub kind: JobEventKind,
+}
+
+struct Job {
+    transfer: Option<TransferJob>,
+    priority: i32,
+    status: JobStatus,
+    /// Whether the job is using one of the running slots.
+    active: bool,
+    cancelled: bool,
+    waker: Option<Waker>,
+}
+
+impl Job {
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+struct State {
+    concurrency: usize,
+    bandwidth: Option<u64>,
+    next_send: Instant,
+    next_id: u64,
+    running: usize,
+    jobs: BTreeMap<JobId, Job>,
+    listeners: Vec<UnboundedSender<JobEvent>>,
+}
+
+impl State {
+    fn emit(&mut self, job: JobId, kind: JobEventKind) {
+        let event = JobEvent { job, kind };
+        self.listeners
+            .retain(|listener| listener.unbounded_send(event.clone()).is_ok());
+    }
+
+    /// Finds the queued job that should run next.
+    fn next_job(&self) -> Option<JobId> {
+        self.jobs
+            .iter()
+            .filter(|(_, job)| job.status == JobStatus::Queued && !job.cancelled)
+            .max_by(|(a_id, a), (b_id, b)| match a.priority.cmp(&b.priority) {
+                Ordering::Equal => b_id.cmp(a_id),
+                ordering => ordering,
+            })
+            .map(|(id, _)| *id)
+    }
+
+    /// Returns when data of the given length may be sent.
+    fn reserve(&mut self, length: usize) -> Instant {
+        let now = Instant::now();
+        let bandwidth = match self.bandwidth {
+            Some(b) if b > 0 => b,
+            _ => return now,
+        };
+
+        let start = max(now, self.next_send);
+        let nanos = length as u128 * 1_000_000_000 / u128::from(bandwidth);
+        self.next_send = start + Duration::from_nanos(nanos as u64);
+        start
+    }
+}
+
+enum Check {
+    Run,
+    Wait,
+    Cancelled,
+}
+
+/// Runs queued file transfers.
+///
+/// See the [`transfers`](index.html) module.
+#[derive(Clone)]
+pub struct TransferManager {
+    state: Arc<Mutex<State>>,
+}
+
+impl Default for TransferManager {
+    fn default() -> TransferManager {
+        TransferManager::new()
+    }
+}
+
+impl TransferManager {
+    /// Creates a manager running up to four jobs at once with no bandwidth
+    /// limit.
+    pub fn new() -> TransferManager {
+        TransferManager {
+            state: Arc::new(Mutex::new(State {
+                concurrency: DEFAULT_CONCURRENCY,
+                bandwidth: None,
+                next_send: Instant::now(),
+                next_id: 0,
+                running: 0,
+                jobs: BTreeMap::new(),
+                listeners: Vec::new(),
+            })),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<State> {
+        self.state.lock().unwrap()
+    }
+
+    /// Sets how many jobs may run at once. Lowering this doesn't stop jobs
+    /// that are already running.
+    pub fn set_concurrency(&self, concurrency: usize) {
+        let mut state = self.lock();
+        state.concurrency = concurrency;
+        self.schedule(&mut state);
+    }
+
+    /// Limits the total data transferred by all jobs to the given number of
+    /// bytes per second, `None` removes the limit.
+    pub fn set_bandwidth(&self, bytes_per_second: Option<u64>) {
+        let mut state = self.lock();
+        state.bandwidth = bytes_per_second;
+        state.next_send = Instant::now();
+    }
+
+    /// Returns a stream of the events for every job from now on.
+    pub fn events(&self) -> JobEvents {
+        let (sender, receiver) = unbounded();
+        self.lock().listeners.push(sender);
+        JobEvents::from_stream(receiver)
+    }
+
+    /// Adds a job to the queue.
+    pub fn add(&self, transfer: TransferJob) -> JobId {
+        let mut state = self.lock();
+        let id = JobId(state.next_id);
+        state.next_id += 1;
+
+        state.jobs.insert(
+            id,
+            Job {
+                priority: transfer.priority,
+                transfer: Some(transfer),
+                status: JobStatus::Queued,
+                active: false,
+                cancelled: false,
+                waker: None,
+            },
+        );
+        state.emit(id, JobEventKind::Queued);
+        self.schedule(&mut state);
+        id
+    }
+
+    /// Gets the status of a job, `None` once the job has finished.
+    pub fn status(&self, id: JobId) -> Option<JobStatus> {
+        self.lock().jobs.get(&id).map(|job| job.status)
+    }
+
+    /// Changes the priority of a job that hasn't finished. This only affects
+    /// when a job starts, a running job is not interrupted.
+    pub fn set_priority(&self, id: JobId, priority: i32) {
+        let mut state = self.lock();
+        if let Some(job) = state.jobs.get_mut(&id) {
+            job.priority = priority;
+        }
+        self.schedule(&mut state);
+    }
+
+    /// Pauses a job. A running job stops transferring data and lets another
+    /// job run in its place.
+    pub fn pause(&self, id: JobId) {
+        let mut state = self.lock();
+        let was_active = match state.jobs.get_mut(&id) {
+            Some(job) if job.status != JobStatus::Paused && !job.cancelled => {
+                job.status = JobStatus::Paused;
+                let was_active = job.active;
+                job.active = false;
+                was_active
+            }
+            _ => return,
+        };
+
+        if was_active {
+            state.running -= 1;
+        }
+        state.emit(id, JobEventKind::Paused);
+        self.schedule(&mut state);
+    }
+
+    /// Returns a paused job to the queue.
+    pub fn resume(&self, id: JobId) {
+        let mut state = self.lock();
+        match state.jobs.get_mut(&id) {
+            Some(job) if job.status == JobStatus::Paused => job.status = JobStatus::Queued,
+            _ => return,
+        }
+
+        state.emit(id, JobEventKind::Queued);
+        self.schedule(&mut state);
+    }
+
+    /// Cancels a job. A job that has already started may leave a partial file
+    /// behind depending on the target's
+    /// [`delete_on_failure`](../struct.WriteOptions.html#structfield.delete_on_failure)
+    /// support.
+    pub fn cancel(&self, id: JobId) {
+        let mut state = self.lock();
+        let started = match state.jobs.get_mut(&id) {
+            Some(job) if !job.cancelled => {
+                job.cancelled = true;
+                job.wake();
+                job.transfer.is_none()
+            }
+            _ => return,
+        };
+
+        // Jobs that have started report the cancellation once they stop.
+        if !started {
+            state.jobs.remove(&id);
+            state.emit(id, JobEventKind::Cancelled);
+        }
+    }
+
+    /// Starts queued jobs while there are free slots.
+    fn schedule(&self, state: &mut State) {
+        while state.running < state.concurrency {
+            let id = match state.next_job() {
+                Some(id) => id,
+                None => return,
+            };
+
+            state.running += 1;
+            let transfer = {
+                let job = state.jobs.get_mut(&id).unwrap();
+                job.status = JobStatus::Running;
+                job.active = true;
+                job.wake();
+                job.transfer.take()
+            };
+            state.emit(id, JobEventKind::Started);
+
+            if let Some(transfer) = transfer {
+                trace!("Starting transfer job {:?}.", id);
+                spawn(run(self.clone(), id, transfer));
+            }
+        }
+    }
+
+    /// Decides whether a job's data may continue to flow.
+    fn check(&self, id: JobId, waker: &Waker) -> Check {
+        let mut state = self.lock();
+        match state.jobs.get_mut(&id) {
+            Some(job) if job.cancelled => Check::Cancelled,
+            Some(job) if job.status == JobStatus::Running => Check::Run,
+            Some(job) => {
+                job.waker = Some(waker.clone());
+                Check::Wait
+            }
+            None => Check::Cancelled,
+        }
+    }
+
+    fn finish(&self, id: JobId, result: Result<(), TransferError>) {
+        let mut state = self.lock();
+        let job = match state.jobs.remove(&id) {
+            Some(job) => job,
+            None => return,
+        };
+
+        if job.active {
+            state.running -= 1;
+        }
+
+        let kind = match result {
+            _ if job.cancelled => JobEventKind::Cancelled,
+            Ok(()) => JobEventKind::Completed,
+            Err(TransferError::SourceError(e)) => JobEventKind::Failed(e.kind()),
+            Err(TransferError::TargetError(e)) => JobEventKind::Failed(e.kind()),
+        };
+        state.emit(id, kind);
+        self.schedule(&mut state);
+    }
+}
+
+async fn run(manager: TransferManager, id: JobId, transfer: TransferJob) {
+    let result = copy(&manager, id, transfer).await;
+    manager.finish(id, result);
+}
+
+async fn copy(
+    manager: &TransferManager,
+    id: JobId,
+    transfer: TransferJob,
+) -> Result<(), TransferError> {
+    let mut stream = transfer
+        .source
+        .get_file_stream(transfer.source_path.clone())
+        .await
+        .map_err(TransferError::SourceError)?;
+    if let Some(ref transform) = transfer.transform {
+        stream = apply(transform.as_ref(), &transfer.source_path, stream);
+    }
+
+    let shaped = ShapedStream {
+        stream,
+        manager: manager.clone(),
+        id,
+        transferred: 0,
+        delay: None,
+        pending: None,
+    };
+
+    transfer
+        .target
+        .write_file_from_stream(transfer.target_path, shaped)
+        .await
+}
+
+/// Holds back a job's data while it is paused or over the bandwidth limit.
+struct ShapedStream {
+    stream: DataStream,
+    manager: TransferManager,
+    id: JobId,
+    transferred: u64,
+    delay: Option<Pin<Box<Delay>>>,
+    pending: Option<Data>,
+}
+
+impl ShapedStream {
+    fn release(&mut self, data: Data) -> Poll<Option<StorageResult<Data>>> {
+        self.transferred += data.len() as u64;
+        self.manager
+            .lock()
+            .emit(self.id, JobEventKind::Progress(self.transferred));
+        Poll::Ready(Some(Ok(data)))
+    }
+}
+
+impl Stream for ShapedStream {
+    type Item = StorageResult<Data>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        if let Some(delay) = self.delay.as_mut() {
+            if delay.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+
+        self.delay = None;
+        if let Some(data) = self.pending.take() {
+            return self.release(data);
+        }
+
+        match self.manager.check(self.id, cx.waker()) {
+            Check::Run => (),
+            Check::Wait => return Poll::Pending,
+            Check::Cancelled => {
+                return Poll::Ready(Some(Err(error::cancelled(Some(
+                    "The transfer was cancelled.",
+                )))))
+            }
+        }
+
+        match Pin::new(&mut self.stream).poll_next(cx) {
+            Poll::Ready(Some(Ok(data))) => {
+                let start = self.manager.lock().reserve(data.len());
+                if start <= Instant::now() {
+                    return self.release(data);
+                }
+
+                let mut wait = Box::pin(delay(start));
+                match wait.as_mut().poll(cx) {
+                    Poll::Ready(()) => self.release(data),
+                    Poll::Pending => {
+                        self.delay = Some(wait);
+                        self.pending = Some(data);
+                        Poll::Pending
+                    }
+                }
+            }
+            result => result,
+        }
+    }
+}
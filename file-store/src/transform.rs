@@ -0,0 +1,236 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Changing file data as it is read, written or transferred.
+//!
+//! A [`Transform`](trait.Transform.html) takes the data stream of a file and
+//! returns a new stream, for example encrypting, compressing or filtering the
+//! data. Transforms can be applied:
+//! * to everything read from a store, using
+//!   [`FileStore::transform_reads`](../enum.FileStore.html#method.transform_reads).
+//! * to everything written to a store, using
+//!   [`FileStore::transform_writes`](../enum.FileStore.html#method.transform_writes).
+//! * to a single file copied between stores, using
+//!   [`transfer`](fn.transfer.html).
+//!
+//! Any function taking the file's path and data stream and returning a new
+//! stream of data can be used as a transform.
+//!
+//! Copies and moves within a transformed store act on the data as it is
+//! stored so they are not transformed. The lengths reported for files are
+//! also those of the stored data.
+use std::convert::TryInto;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use futures::future::TryFutureExt;
+use futures::stream::Stream;
+
+use crate::backends::Backend;
+use crate::dynamic::{self, DynamicStore};
+use crate::types::*;
+use crate::{FileStore, StorageBackend};
+
+/// The data stream returned by a [`Transform`](trait.Transform.html).
+pub type TransformedStream = Pin<Box<dyn Stream<Item = StorageResult<Data>> + Send + 'static>>;
+
+/// Changes the data stream of a file.
+pub trait Transform: Send + Sync + 'static {
+    /// Returns the transformed data for the file at `path`. Errors from the
+    /// returned stream fail the read, write or transfer.
+    fn transform(&self, path: &ObjectPath, stream: DataStream) -> TransformedStream;
+}
+
+impl<F, S> Transform for F
+where
+    F: Fn(&ObjectPath, DataStream) -> S + Send + Sync + 'static,
+    S: Stream<Item = StorageResult<Data>> + Send + 'static,
+{
+    fn transform(&self, path: &ObjectPath, stream: DataStream) -> TransformedStream {
+        Box::pin(self(path, stream))
+    }
+}
+
+/// Applies a transform, wrapping the result as a [`DataStream`](../type.DataStream.html).
+pub(crate) fn apply(
+    transform: &dyn Transform,
+    path: &ObjectPath,
+    stream: DataStream,
+) -> DataStream {
+    DataStream::from_stream(transform.transform(path, stream))
+}
+
+/// Copies a file from one store to another, transforming its data on the way.
+pub fn transfer<P, Q, T>(
+    source: &FileStore,
+    source_path: P,
+    target: &FileStore,
+    target_info: Q,
+    transform: T,
+) -> CopyCompleteFuture
+where
+    P: TryInto<ObjectPath>,
+    P::Error: Into<StorageError>,
+    Q: TryInto<UploadInfo>,
+    Q::Error: Into<StorageError>,
+    T: Transform,
+{
+    let source_path = match source_path.try_into() {
+        Ok(p) => p,
+        Err(e) => return CopyCompleteFuture::from_value(Err(TransferError::SourceError(e.into()))),
+    };
+    let target_info = match target_info.try_into() {
+        Ok(i) => i,
+        Err(e) => return CopyCompleteFuture::from_value(Err(TransferError::TargetError(e.into()))),
+    };
+
+    CopyCompleteFuture::from_future(transfer_with(
+        source.clone(),
+        source_path,
+        target.clone(),
+        target_info,
+        Arc::new(transform),
+    ))
+}
+
+async fn transfer_with(
+    source: FileStore,
+    source_path: ObjectPath,
+    target: FileStore,
+    target_info: UploadInfo,
+    transform: Arc<dyn Transform>,
+) -> Result<(), TransferError> {
+    let stream = source
+        .get_file_stream(source_path.clone())
+        .await
+        .map_err(TransferError::SourceError)?;
+    let stream = apply(transform.as_ref(), &source_path, stream);
+    target.write_file_from_stream(target_info, stream).await
+}
+
+/// Which direction of data a store transforms.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Direction {
+    Read,
+    Write,
+}
+
+/// Wraps a store applying a transform to the data read or written.
+struct TransformedStore {
+    store: FileStore,
+    transform: Arc<dyn Transform>,
+    direction: Direction,
+}
+
+// Only StorageBackend is in scope so calls on the wrapped store are not
+// ambiguous.
+impl dynamic::DynamicBackend for TransformedStore {
+    fn backend_type(&self) -> Backend {
+        self.store.backend_type()
+    }
+
+    fn authorize(&self) -> OperationCompleteFuture {
+        self.store.authorize()
+    }
+
+    fn validate_path(&self, path: &ObjectPath) -> StorageResult<()> {
+        self.store.validate_path(path)
+    }
+
+    fn list_objects(&self, prefix: ObjectPath) -> ObjectStreamFuture {
+        self.store.list_objects(prefix)
+    }
+
+    fn list_directory(&self, dir: ObjectPath) -> ObjectStreamFuture {
+        self.store.list_directory(dir)
+    }
+
+    fn get_object(&self, path: ObjectPath) -> ObjectFuture {
+        self.store.get_object(path)
+    }
+
+    fn get_file_stream(&self, path: ObjectPath) -> DataStreamFuture {
+        self.get_file_stream_with_options(path, Default::default())
+    }
+
+    fn get_file_stream_with_options(
+        &self,
+        path: ObjectPath,
+        options: ReadOptions,
+    ) -> DataStreamFuture {
+        let future = self
+            .store
+            .get_file_stream_with_options(path.clone(), options);
+        if self.direction != Direction::Read {
+            return future;
+        }
+
+        let transform = self.transform.clone();
+        DataStreamFuture::from_future(
+            future.map_ok(move |stream| apply(transform.as_ref(), &path, stream)),
+        )
+    }
+
+    fn copy_file(&self, source: ObjectPath, target: UploadInfo) -> CopyCompleteFuture {
+        self.store.copy_file(source, target)
+    }
+
+    fn move_file(&self, source: ObjectPath, target: UploadInfo) -> MoveCompleteFuture {
+        self.store.move_file(source, target)
+    }
+
+    fn delete_object(&self, path: ObjectPath) -> OperationCompleteFuture {
+        self.store.delete_object(path)
+    }
+
+    fn write_file_from_stream(&self, info: UploadInfo, stream: DataStream) -> WriteCompleteFuture {
+        let stream = match self.direction {
+            Direction::Write => apply(self.transform.as_ref(), &info.path, stream),
+            Direction::Read => stream,
+        };
+        self.store.write_file_from_stream(info, stream)
+    }
+}
+
+impl FileStore {
+    fn transformed(self, transform: Arc<dyn Transform>, direction: Direction) -> FileStore {
+        FileStore::from(DynamicStore::new(TransformedStore {
+            store: self,
+            transform,
+            direction,
+        }))
+    }
+
+    /// Wraps this store so that the data of every file read through the
+    /// returned store is passed through the given transform.
+    ///
+    /// See the [`transform`](transform/index.html) module.
+    pub fn transform_reads<T>(self, transform: T) -> FileStore
+    where
+        T: Transform,
+    {
+        self.transformed(Arc::new(transform), Direction::Read)
+    }
+
+    /// Wraps this store so that the data of every file written through the
+    /// returned store is passed through the given transform.
+    ///
+    /// See the [`transform`](transform/index.html) module.
+    pub fn transform_writes<T>(self, transform: T) -> FileStore
+    where
+        T: Transform,
+    {
+        self.transformed(Arc::new(transform), Direction::Write)
+    }
+}
@@ -0,0 +1,372 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Configuration for the HTTP transport used by the cloud based backends.
+//!
+//! The backends that talk to their storage over HTTP all share the same
+//! underlying client. The types here allow tuning how that client connects,
+//! they are passed to the builder of each backend. A [`Cassette`](struct.Cassette.html)
+//! can record the requests a backend makes and replay them later without a
+//! network, mostly useful for tests. A [`Capture`](struct.Capture.html) keeps
+//! sanitized copies of recent requests to help with reporting problems. A
+//! [`Resolver`](trait.Resolver.html) replaces the system's resolver for looking
+//! up host names.
+//!
+//! When compiled to `wasm32-unknown-unknown` with the "wasm" feature requests
+//! are sent with the JavaScript runtime's `fetch` instead. The runtime manages
+//! its own connections so proxies, custom resolvers and fixed addresses set on
+//! the builder are rejected and the connection pool and connection settings
+//! are ignored. Request and response bodies are held in memory while they are
+//! sent and the storage service must allow the page's origin through CORS.
+mod capture;
+mod cassette;
+#[cfg(target_arch = "wasm32")]
+mod fetch;
+#[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+mod proxy;
+mod resolve;
+pub(crate) mod runtime;
+
+#[cfg(all(target_arch = "wasm32", not(feature = "wasm")))]
+compile_error!("The \"wasm\" feature is needed to build the HTTP backends for WebAssembly.");
+
+use std::fmt;
+use std::time::Duration;
+
+use http::header::{self, HeaderName, HeaderValue};
+use http::request::Builder;
+#[cfg(not(target_arch = "wasm32"))]
+use hyper::client::connect::HttpConnector;
+#[cfg(not(target_arch = "wasm32"))]
+use hyper::client::Client as HyperClient;
+use hyper::{Body, Request, Response};
+#[cfg(not(target_arch = "wasm32"))]
+use hyper_tls::HttpsConnector;
+#[cfg(not(target_arch = "wasm32"))]
+use native_tls::TlsConnector;
+
+use crate::types::error;
+use crate::types::*;
+
+pub use capture::{Capture, Exchange};
+pub use cassette::Cassette;
+#[cfg(target_arch = "wasm32")]
+use fetch::FetchClient;
+pub use proxy::Proxy;
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) use proxy::{Proxies, ProxyConnector};
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) use resolve::DnsResolver;
+pub(crate) use resolve::ResolverSettings;
+pub use resolve::{ResolveFuture, Resolver};
+
+/// The connector used by all HTTP based backends.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) type Connector = HttpsConnector<ProxyConnector<HttpConnector<DnsResolver>>>;
+
+/// The client that actually sends requests.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) type Client = HyperClient<Connector>;
+#[cfg(target_arch = "wasm32")]
+pub(crate) type Client = FetchClient;
+
+/// An error from sending a request.
+#[derive(Debug)]
+pub(crate) enum TransportError {
+    /// The request failed.
+    Http(hyper::Error),
+    /// The request could not be replayed from a cassette.
+    Cassette(StorageError),
+    /// The runtime's fetch failed.
+    #[cfg(target_arch = "wasm32")]
+    Fetch(StorageError),
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransportError::Http(e) => e.fmt(f),
+            TransportError::Cassette(e) => e.fmt(f),
+            #[cfg(target_arch = "wasm32")]
+            TransportError::Fetch(e) => e.fmt(f),
+        }
+    }
+}
+
+/// Sends a request straight to the server.
+#[cfg(not(target_arch = "wasm32"))]
+async fn send_direct(
+    client: &Client,
+    request: Request<Body>,
+) -> Result<Response<Body>, TransportError> {
+    client.request(request).await.map_err(TransportError::Http)
+}
+
+/// Sends a request straight to the server.
+#[cfg(target_arch = "wasm32")]
+async fn send_direct(
+    client: &Client,
+    request: Request<Body>,
+) -> Result<Response<Body>, TransportError> {
+    client.request(request).await
+}
+
+/// The client used by all HTTP based backends.
+#[derive(Clone, Debug)]
+pub(crate) struct HttpClient {
+    client: Client,
+    cassette: Option<Cassette>,
+    capture: Option<Capture>,
+    custom_headers: Vec<HeaderName>,
+}
+
+impl HttpClient {
+    /// Sends a request, or replays it if a replaying cassette is in use.
+    pub async fn request(&self, request: Request<Body>) -> Result<Response<Body>, TransportError> {
+        match self.capture {
+            Some(ref capture) => {
+                let (exchange, request) = capture.start(request, &self.custom_headers).await?;
+                let result = self.send(request).await;
+                capture.finish(exchange, result).await
+            }
+            None => self.send(request).await,
+        }
+    }
+
+    async fn send(&self, request: Request<Body>) -> Result<Response<Body>, TransportError> {
+        match self.cassette {
+            Some(ref cassette) => cassette.send(&self.client, request).await,
+            None => send_direct(&self.client, request).await,
+        }
+    }
+}
+
+/// How a backend should decide which proxy to use.
+#[derive(Clone, Debug)]
+pub(crate) enum ProxySettings {
+    /// Use the proxies configured by the standard environment variables.
+    Environment,
+    /// Connect directly to the storage service.
+    Direct,
+    /// Always use the given proxy.
+    Proxy(Proxy),
+}
+
+impl Default for ProxySettings {
+    fn default() -> ProxySettings {
+        ProxySettings::Environment
+    }
+}
+
+impl ProxySettings {
+    #[cfg(not(target_arch = "wasm32"))]
+    fn proxies(&self) -> StorageResult<Proxies> {
+        match self {
+            ProxySettings::Environment => Proxies::from_env(),
+            ProxySettings::Direct => Ok(Proxies::none()),
+            ProxySettings::Proxy(proxy) => Ok(Proxies::all(proxy.clone())),
+        }
+    }
+}
+
+/// Settings for the pool of idle connections kept open by the client.
+#[derive(Clone, Debug)]
+pub(crate) struct PoolSettings {
+    /// Whether connections are kept open and reused at all.
+    pub keep_alive: bool,
+    /// How long an idle connection is kept open for. `None` means forever.
+    pub idle_timeout: Option<Duration>,
+    /// The maximum number of idle connections kept open to each host.
+    pub max_idle_per_host: usize,
+    /// Only use HTTP/2 connections.
+    pub http2_only: bool,
+}
+
+impl Default for PoolSettings {
+    fn default() -> PoolSettings {
+        PoolSettings {
+            keep_alive: true,
+            idle_timeout: Some(Duration::from_secs(90)),
+            max_idle_per_host: std::usize::MAX,
+            http2_only: false,
+        }
+    }
+}
+
+/// Settings for the TCP connections opened by the client.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ConnectionSettings {
+    /// How long to wait for a connection to be established. `None` waits as
+    /// long as the operating system allows.
+    pub timeout: Option<Duration>,
+    /// The interval for TCP keepalive probes. `None` disables them.
+    pub keepalive: Option<Duration>,
+    /// Whether to disable Nagle's algorithm.
+    pub nodelay: bool,
+}
+
+/// The settings for the HTTP transport shared by the cloud based backends.
+#[derive(Clone)]
+pub(crate) struct TransportSettings {
+    pub proxy: ProxySettings,
+    pub pool: PoolSettings,
+    pub connection: ConnectionSettings,
+    pub resolver: ResolverSettings,
+    pub user_agent: String,
+    pub headers: Vec<(String, String)>,
+    pub cassette: Option<Cassette>,
+    pub capture: Option<Capture>,
+}
+
+impl Default for TransportSettings {
+    fn default() -> TransportSettings {
+        TransportSettings {
+            proxy: Default::default(),
+            pool: Default::default(),
+            connection: Default::default(),
+            resolver: Default::default(),
+            user_agent: format!(
+                "{}/{} ({})",
+                env!("CARGO_PKG_NAME"),
+                env!("CARGO_PKG_VERSION"),
+                env!("CARGO_PKG_REPOSITORY")
+            ),
+            headers: Vec::new(),
+            cassette: None,
+            capture: None,
+        }
+    }
+}
+
+impl fmt::Debug for TransportSettings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Custom headers often carry credentials so only their names are shown.
+        let headers: Vec<&str> = self.headers.iter().map(|(name, _)| name.as_str()).collect();
+        f.debug_struct("TransportSettings")
+            .field("proxy", &self.proxy)
+            .field("pool", &self.pool)
+            .field("connection", &self.connection)
+            .field("resolver", &self.resolver)
+            .field("user_agent", &self.user_agent)
+            .field("headers", &headers)
+            .field("cassette", &self.cassette)
+            .field("capture", &self.capture)
+            .finish()
+    }
+}
+
+impl TransportSettings {
+    fn validate_headers(&self) -> StorageResult<()> {
+        HeaderValue::from_str(&self.user_agent)
+            .map_err(|e| error::invalid_settings(Some(&format!("Invalid User-Agent: {}", e))))?;
+
+        for (name, value) in self.headers.iter() {
+            HeaderName::from_bytes(name.as_bytes()).map_err(|e| {
+                error::invalid_settings(Some(&format!("Invalid header name '{}': {}", name, e)))
+            })?;
+            HeaderValue::from_str(value).map_err(|e| {
+                error::invalid_settings(Some(&format!(
+                    "Invalid value for header '{}': {}",
+                    name, e
+                )))
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// The names of the additional headers, which captures redact.
+    fn custom_headers(&self) -> Vec<HeaderName> {
+        self.headers
+            .iter()
+            .filter_map(|(name, _)| HeaderName::from_bytes(name.as_bytes()).ok())
+            .collect()
+    }
+
+    /// Creates a request builder with the User-Agent and any additional
+    /// headers already set. A User-Agent among the additional headers replaces
+    /// the default.
+    pub fn request_builder(&self) -> Builder {
+        let mut builder = Request::builder();
+        let custom_agent = self
+            .headers
+            .iter()
+            .any(|(name, _)| name.eq_ignore_ascii_case(header::USER_AGENT.as_str()));
+        if !custom_agent {
+            builder.header(header::USER_AGENT, self.user_agent.as_str());
+        }
+        for (name, value) in self.headers.iter() {
+            builder.header(name.as_str(), value.as_str());
+        }
+        builder
+    }
+
+    /// Builds a new client using these settings.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn build_client(&self) -> StorageResult<HttpClient> {
+        self.validate_headers()?;
+
+        let tls = TlsConnector::new().map_err(|e| {
+            error::connection_failed(Some(&format!("Could not create tls connector: {}.", e)))
+        })?;
+
+        let mut http = HttpConnector::new_with_resolver(self.resolver.build());
+        http.enforce_http(false);
+        http.set_connect_timeout(self.connection.timeout);
+        http.set_keepalive(self.connection.keepalive);
+        http.set_nodelay(self.connection.nodelay);
+
+        let connector = ProxyConnector::new(http, self.proxy.proxies()?);
+        let https = HttpsConnector::from((connector, tls.into()));
+
+        let client = HyperClient::builder()
+            .keep_alive(self.pool.keep_alive)
+            .keep_alive_timeout(self.pool.idle_timeout)
+            .max_idle_per_host(self.pool.max_idle_per_host)
+            .http2_only(self.pool.http2_only)
+            .build(https);
+
+        Ok(HttpClient {
+            client,
+            cassette: self.cassette.clone(),
+            capture: self.capture.clone(),
+            custom_headers: self.custom_headers(),
+        })
+    }
+
+    /// Builds a new client that sends requests with the runtime's `fetch`.
+    #[cfg(target_arch = "wasm32")]
+    pub fn build_client(&self) -> StorageResult<HttpClient> {
+        self.validate_headers()?;
+
+        if let ProxySettings::Proxy(_) = self.proxy {
+            return Err(error::invalid_settings(Some(
+                "Proxies cannot be used from WebAssembly, the runtime's settings apply.",
+            )));
+        }
+
+        if self.resolver.resolver.is_some() || !self.resolver.overrides.is_empty() {
+            return Err(error::invalid_settings(Some(
+                "Host names cannot be resolved from WebAssembly, the runtime resolves them.",
+            )));
+        }
+
+        Ok(HttpClient {
+            client: FetchClient,
+            cassette: self.cassette.clone(),
+            capture: self.capture.clone(),
+            custom_headers: self.custom_headers(),
+        })
+    }
+}
@@ -0,0 +1,312 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Capturing sanitized HTTP traffic for bug reports.
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use futures::stream::TryStreamExt;
+use http::header::{self, HeaderMap, HeaderName};
+use hyper::{Body, Request, Response};
+use log::warn;
+use serde::Serialize;
+use serde_json::Value;
+
+use super::TransportError;
+use crate::types::error;
+use crate::types::*;
+
+/// Bodies larger than this are left out of captures.
+const MAX_BODY_SIZE: u64 = 64 * 1024;
+
+const REDACTED: &str = "<redacted>";
+
+/// A request and its response captured by a [`Capture`](struct.Capture.html).
+///
+/// Authorization headers, the custom headers set on the backend's builder and
+/// any tokens or keys in bodies or the URI's query are replaced with
+/// `<redacted>`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Exchange {
+    /// The request method.
+    pub method: String,
+    /// The full URI requested.
+    pub uri: String,
+    /// The request's headers.
+    pub request_headers: Vec<(String, String)>,
+    /// The request's body, `None` if it was too large to capture.
+    pub request_body: Option<String>,
+    /// The response status, `None` if the request failed.
+    pub status: Option<u16>,
+    /// The response's headers.
+    pub response_headers: Vec<(String, String)>,
+    /// The response's body, `None` if it was too large to capture or of
+    /// unknown length.
+    pub response_body: Option<String>,
+    /// Why the request failed, if it did.
+    pub error: Option<String>,
+}
+
+/// Captures sanitized copies of the HTTP requests a backend makes.
+///
+/// Meant to be turned on when reporting a bug about how a storage service
+/// behaves. The most recent exchanges are kept in memory, older ones are
+/// discarded once the capacity is reached. Optionally every exchange is also
+/// appended to a file as a line of JSON.
+///
+/// Credentials are redacted before anything is stored but other details of
+/// the requests, such as file and bucket names, are kept. Request and response
+/// bodies are only captured when they are known to be small so file contents
+/// are generally left out.
+#[derive(Clone, Debug)]
+pub struct Capture {
+    capacity: usize,
+    exchanges: Arc<Mutex<VecDeque<Exchange>>>,
+    file: Option<Arc<Mutex<File>>>,
+}
+
+impl Capture {
+    /// Creates a capture keeping the given number of recent exchanges in
+    /// memory.
+    pub fn new(capacity: usize) -> Capture {
+        Capture {
+            capacity,
+            exchanges: Default::default(),
+            file: None,
+        }
+    }
+
+    /// Creates a capture that appends every exchange to the given file as
+    /// well as keeping the given number of recent exchanges in memory.
+    pub fn with_file<P>(path: P, capacity: usize) -> StorageResult<Capture>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| {
+                error::invalid_settings(Some(&format!(
+                    "Unable to open capture file {}: {}",
+                    path.display(),
+                    e
+                )))
+            })?;
+
+        Ok(Capture {
+            file: Some(Arc::new(Mutex::new(file))),
+            ..Capture::new(capacity)
+        })
+    }
+
+    /// The captured exchanges, oldest first.
+    pub fn exchanges(&self) -> Vec<Exchange> {
+        self.exchanges.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Discards the exchanges held in memory.
+    pub fn clear(&self) {
+        self.exchanges.lock().unwrap().clear();
+    }
+
+    fn store(&self, exchange: Exchange) {
+        if let Some(ref file) = self.file {
+            let written = serde_json::to_string(&exchange)
+                .map_err(|e| e.to_string())
+                .and_then(|line| {
+                    writeln!(file.lock().unwrap(), "{}", line).map_err(|e| e.to_string())
+                });
+            if let Err(e) = written {
+                warn!("Failed to write to capture file: {}", e);
+            }
+        }
+
+        let mut exchanges = self.exchanges.lock().unwrap();
+        if self.capacity == 0 {
+            return;
+        }
+        while exchanges.len() >= self.capacity {
+            exchanges.pop_front();
+        }
+        exchanges.push_back(exchange);
+    }
+
+    /// Captures the request, returning the exchange to be completed by
+    /// [`finish`](#method.finish) and a request to send in its place.
+    pub(crate) async fn start(
+        &self,
+        request: Request<Body>,
+        custom_headers: &[HeaderName],
+    ) -> Result<(Exchange, Request<Body>), TransportError> {
+        let (parts, body) = request.into_parts();
+        let (request_body, body) = if is_small(&parts.headers, true) {
+            let data = body.try_concat().await.map_err(TransportError::Http)?;
+            (Some(sanitize_body(&data)), Body::from(data.into_bytes()))
+        } else {
+            (None, body)
+        };
+
+        let exchange = Exchange {
+            method: parts.method.to_string(),
+            uri: sanitize_uri(&parts.uri.to_string()),
+            request_headers: sanitize_headers(&parts.headers, custom_headers),
+            request_body,
+            status: None,
+            response_headers: Vec::new(),
+            response_body: None,
+            error: None,
+        };
+
+        Ok((exchange, Request::from_parts(parts, body)))
+    }
+
+    /// Captures the result of sending a request.
+    pub(crate) async fn finish(
+        &self,
+        mut exchange: Exchange,
+        result: Result<Response<Body>, TransportError>,
+    ) -> Result<Response<Body>, TransportError> {
+        let response = match result {
+            Ok(response) => response,
+            Err(e) => {
+                exchange.error = Some(e.to_string());
+                self.store(exchange);
+                return Err(e);
+            }
+        };
+
+        let (parts, body) = response.into_parts();
+        exchange.status = Some(parts.status.as_u16());
+        exchange.response_headers = sanitize_headers(&parts.headers, &[]);
+
+        let body = if is_small(&parts.headers, false) {
+            match body.try_concat().await {
+                Ok(data) => {
+                    exchange.response_body = Some(sanitize_body(&data));
+                    Body::from(data.into_bytes())
+                }
+                Err(e) => {
+                    exchange.error = Some(e.to_string());
+                    self.store(exchange);
+                    return Err(TransportError::Http(e));
+                }
+            }
+        } else {
+            body
+        };
+
+        self.store(exchange);
+        Ok(Response::from_parts(parts, body))
+    }
+}
+
+/// Whether a body is small enough to capture. Requests without a length are
+/// assumed to be small API calls, responses without one could be anything.
+fn is_small(headers: &HeaderMap, default: bool) -> bool {
+    match headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+    {
+        Some(length) => length <= MAX_BODY_SIZE,
+        None => default,
+    }
+}
+
+/// Whether a header holds credentials. Custom headers are always treated as
+/// secret since they often carry them.
+fn is_secret_header(name: &HeaderName, custom_headers: &[HeaderName]) -> bool {
+    name == header::AUTHORIZATION
+        || name == header::PROXY_AUTHORIZATION
+        || name == header::COOKIE
+        || name == header::SET_COOKIE
+        || name.as_str().ends_with("-customer-key")
+        || custom_headers.contains(name)
+}
+
+pub(super) fn sanitize_headers(
+    headers: &HeaderMap,
+    custom_headers: &[HeaderName],
+) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if is_secret_header(name, custom_headers) {
+                REDACTED.to_owned()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).into_owned()
+            };
+            (name.as_str().to_owned(), value)
+        })
+        .collect()
+}
+
+fn is_secret_field(name: &str) -> bool {
+    let name = name.to_lowercase();
+    name.ends_with("token") || name == "applicationkey" || name == "customerkey"
+}
+
+/// Redacts credentials passed in a URI's query, such as the authorization
+/// token on B2 download URLs.
+pub(super) fn sanitize_uri(uri: &str) -> String {
+    let (base, query) = match uri.find('?') {
+        Some(pos) => (&uri[..pos], &uri[pos + 1..]),
+        None => return uri.to_owned(),
+    };
+
+    let query: Vec<String> = query
+        .split('&')
+        .map(|pair| {
+            let name = pair.splitn(2, '=').next().unwrap_or(pair);
+            if name.eq_ignore_ascii_case("authorization") || is_secret_field(name) {
+                format!("{}={}", name, REDACTED)
+            } else {
+                pair.to_owned()
+            }
+        })
+        .collect();
+    format!("{}?{}", base, query.join("&"))
+}
+
+fn sanitize_value(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (name, value) in map.iter_mut() {
+                if is_secret_field(name) {
+                    *value = Value::String(REDACTED.to_owned());
+                } else {
+                    sanitize_value(value);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(sanitize_value),
+        _ => (),
+    }
+}
+
+/// Redacts any tokens or keys in a JSON body, `None` if it isn't JSON.
+pub(super) fn sanitize_json(data: &[u8]) -> Option<String> {
+    let mut value = serde_json::from_slice::<Value>(data).ok()?;
+    sanitize_value(&mut value);
+    Some(value.to_string())
+}
+
+fn sanitize_body(data: &[u8]) -> String {
+    sanitize_json(data).unwrap_or_else(|| String::from_utf8_lossy(data).into_owned())
+}
@@ -0,0 +1,247 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Recording HTTP interactions and replaying them later.
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::str;
+use std::sync::{Arc, Mutex};
+
+use base64::{decode, encode};
+use futures::stream::TryStreamExt;
+use hyper::{Body, Request, Response};
+use log::trace;
+use serde::{Deserialize, Serialize};
+
+use super::capture::{sanitize_headers, sanitize_json, sanitize_uri};
+use super::{send_direct, Client, TransportError};
+use crate::types::error;
+use crate::types::*;
+
+/// A request or response body. Bodies that are valid UTF-8 are stored as is
+/// to keep cassettes readable, except that tokens and keys in JSON bodies are
+/// redacted.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+enum RecordedBody {
+    Text(String),
+    Binary(String),
+}
+
+impl RecordedBody {
+    fn new(data: &[u8]) -> RecordedBody {
+        if let Some(json) = sanitize_json(data) {
+            return RecordedBody::Text(json);
+        }
+
+        match str::from_utf8(data) {
+            Ok(text) => RecordedBody::Text(text.to_owned()),
+            Err(_) => RecordedBody::Binary(encode(data)),
+        }
+    }
+
+    fn data(&self) -> StorageResult<Vec<u8>> {
+        match self {
+            RecordedBody::Text(text) => Ok(text.clone().into_bytes()),
+            RecordedBody::Binary(data) => decode(data).map_err(|e| {
+                error::invalid_data(Some(&format!("Invalid body in cassette: {}", e)))
+            }),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct Interaction {
+    method: String,
+    uri: String,
+    request: RecordedBody,
+    status: u16,
+    headers: Vec<(String, String)>,
+    response: RecordedBody,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Mode {
+    Record,
+    Replay,
+}
+
+/// Records the HTTP requests a backend makes or replays earlier recordings.
+///
+/// When recording every request is sent as normal and the request and its
+/// response are kept until [`save`](#method.save) writes them to the
+/// cassette's file. When replaying nothing touches the network, each request
+/// is answered with the recorded response for the same method, path and body.
+/// A request that was never recorded fails, so changes to what a backend
+/// sends show up as test failures.
+///
+/// The host is ignored when matching requests so a cassette recorded against
+/// one server can be replayed with the backend pointed anywhere. Requests
+/// need not be replayed in the order they were recorded.
+///
+/// Authorization and key headers and any tokens or keys in JSON bodies or the
+/// query are redacted before they are recorded and requests are redacted the same way
+/// before they are matched, so a replaying backend works with the redacted
+/// tokens. Everything else, including file contents, is kept.
+#[derive(Clone, Debug)]
+pub struct Cassette {
+    mode: Mode,
+    path: PathBuf,
+    interactions: Arc<Mutex<Vec<Interaction>>>,
+}
+
+impl Cassette {
+    /// Creates a cassette that records requests to be saved to the given file.
+    pub fn record<P>(path: P) -> Cassette
+    where
+        P: AsRef<Path>,
+    {
+        Cassette {
+            mode: Mode::Record,
+            path: path.as_ref().to_owned(),
+            interactions: Default::default(),
+        }
+    }
+
+    /// Loads a cassette from the given file to replay its responses.
+    pub fn replay<P>(path: P) -> StorageResult<Cassette>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref().to_owned();
+        let invalid = |detail: &dyn Display| {
+            error::invalid_settings(Some(&format!(
+                "Unable to load cassette {}: {}",
+                path.display(),
+                detail
+            )))
+        };
+
+        let file = File::open(&path).map_err(|e| invalid(&e))?;
+        let interactions: Vec<Interaction> =
+            serde_json::from_reader(BufReader::new(file)).map_err(|e| invalid(&e))?;
+
+        Ok(Cassette {
+            mode: Mode::Replay,
+            path,
+            interactions: Arc::new(Mutex::new(interactions)),
+        })
+    }
+
+    /// Writes the recorded requests to the cassette's file.
+    ///
+    /// Does nothing for a cassette that is replaying.
+    pub fn save(&self) -> StorageResult<()> {
+        if self.mode == Mode::Replay {
+            return Ok(());
+        }
+
+        let failed = |detail: &dyn Display| {
+            error::other_error(Some(&format!(
+                "Unable to save cassette {}: {}",
+                self.path.display(),
+                detail
+            )))
+        };
+
+        let file = File::create(&self.path).map_err(|e| failed(&e))?;
+        let interactions = self.interactions.lock().unwrap();
+        serde_json::to_writer_pretty(BufWriter::new(file), &*interactions).map_err(|e| failed(&e))
+    }
+
+    /// The number of recorded requests that have not been replayed yet.
+    pub fn remaining(&self) -> usize {
+        match self.mode {
+            Mode::Record => 0,
+            Mode::Replay => self.interactions.lock().unwrap().len(),
+        }
+    }
+
+    fn replay_request(
+        &self,
+        method: &str,
+        uri: &str,
+        request: &RecordedBody,
+    ) -> StorageResult<Response<Body>> {
+        let interaction = {
+            let mut interactions = self.interactions.lock().unwrap();
+            let position = interactions
+                .iter()
+                .position(|i| i.method == method && i.uri == uri && &i.request == request)
+                .ok_or_else(|| {
+                    error::other_error(Some(&format!(
+                        "The cassette {} has no response for {} {}.",
+                        self.path.display(),
+                        method,
+                        uri
+                    )))
+                })?;
+            interactions.remove(position)
+        };
+
+        trace!("Replaying {} {} from cassette", method, uri);
+        let mut builder = Response::builder();
+        builder.status(interaction.status);
+        for (name, value) in interaction.headers.iter() {
+            builder.header(name.as_str(), value.as_str());
+        }
+
+        builder
+            .body(Body::from(interaction.response.data()?))
+            .map_err(|e| error::invalid_data(Some(&format!("Invalid response in cassette: {}", e))))
+    }
+
+    pub(crate) async fn send(
+        &self,
+        client: &Client,
+        request: Request<Body>,
+    ) -> Result<Response<Body>, TransportError> {
+        let (parts, body) = request.into_parts();
+        let request_data = body.try_concat().await.map_err(TransportError::Http)?;
+        let method = parts.method.to_string();
+        let uri = parts
+            .uri
+            .path_and_query()
+            .map(|p| sanitize_uri(p.as_str()))
+            .unwrap_or_default();
+        let recorded_request = RecordedBody::new(&request_data);
+
+        if self.mode == Mode::Replay {
+            return self
+                .replay_request(&method, &uri, &recorded_request)
+                .map_err(TransportError::Cassette);
+        }
+
+        let request = Request::from_parts(parts, Body::from(request_data.into_bytes()));
+        let response = send_direct(client, request).await?;
+        let (parts, body) = response.into_parts();
+        let response_data = body.try_concat().await.map_err(TransportError::Http)?;
+
+        trace!("Recording {} {} to cassette", method, uri);
+        self.interactions.lock().unwrap().push(Interaction {
+            method,
+            uri,
+            request: recorded_request,
+            status: parts.status.as_u16(),
+            headers: sanitize_headers(&parts.headers, &[]),
+            response: RecordedBody::new(&response_data),
+        });
+
+        Ok(Response::from_parts(
+            parts,
+            Body::from(response_data.into_bytes()),
+        ))
+    }
+}
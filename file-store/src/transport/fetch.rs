@@ -0,0 +1,111 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Sends requests with the JavaScript runtime's `fetch` when compiled to
+//! WebAssembly.
+use futures::stream::TryStreamExt;
+use hyper::{Body, Request, Response};
+use js_sys::{global, try_iter, Array, Function, Promise, Reflect, Uint8Array};
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{Headers, RequestInit};
+
+use super::runtime::SingleThreaded;
+use super::TransportError;
+use crate::types::error;
+use crate::types::*;
+
+fn js_error(value: JsValue) -> TransportError {
+    let message = match value.dyn_ref::<js_sys::Error>() {
+        Some(e) => String::from(e.message()),
+        None => format!("{:?}", value),
+    };
+    TransportError::Fetch(error::connection_failed(Some(&message)))
+}
+
+/// Finds the global `fetch`, which exists in browsers, workers and edge
+/// runtimes alike.
+fn global_fetch() -> Result<Function, TransportError> {
+    Reflect::get(&global(), &JsValue::from_str("fetch"))
+        .and_then(|f| f.dyn_into::<Function>())
+        .map_err(js_error)
+}
+
+/// The client used in place of hyper's when running in WebAssembly.
+///
+/// The runtime manages its own connections so this holds no state.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct FetchClient;
+
+impl FetchClient {
+    /// Sends a request. The request body is collected before it is sent and the
+    /// response body is read in full before the response is returned.
+    pub async fn request(&self, request: Request<Body>) -> Result<Response<Body>, TransportError> {
+        let (parts, body) = request.into_parts();
+        let data = body.try_concat().await.map_err(TransportError::Http)?;
+
+        SingleThreaded::new(async move {
+            let headers = Headers::new().map_err(js_error)?;
+            for (name, value) in parts.headers.iter() {
+                let value = value.to_str().map_err(|e| {
+                    TransportError::Fetch(error::invalid_data(Some(&e.to_string())))
+                })?;
+                headers.append(name.as_str(), value).map_err(js_error)?;
+            }
+
+            let mut init = RequestInit::new();
+            init.method(parts.method.as_str());
+            init.headers(&headers);
+            if !data.is_empty() {
+                let body = Uint8Array::from(&data[..]);
+                init.body(Some(body.as_ref()));
+            }
+
+            let request = web_sys::Request::new_with_str_and_init(&parts.uri.to_string(), &init)
+                .map_err(js_error)?;
+            let promise: Promise = global_fetch()?
+                .call1(&global(), &request)
+                .and_then(|p| p.dyn_into())
+                .map_err(js_error)?;
+            let response: web_sys::Response = JsFuture::from(promise)
+                .await
+                .map_err(js_error)?
+                .dyn_into()
+                .map_err(js_error)?;
+
+            let mut builder = Response::builder();
+            builder.status(response.status());
+            if let Some(entries) = try_iter(&response.headers()).map_err(js_error)? {
+                for entry in entries {
+                    let entry: Array = entry.map_err(js_error)?.dyn_into().map_err(js_error)?;
+                    if let (Some(name), Some(value)) =
+                        (entry.get(0).as_string(), entry.get(1).as_string())
+                    {
+                        builder.header(name.as_str(), value.as_str());
+                    }
+                }
+            }
+
+            let buffer = JsFuture::from(response.array_buffer().map_err(js_error)?)
+                .await
+                .map_err(js_error)?;
+            let body = Body::from(Uint8Array::new(&buffer).to_vec());
+
+            builder
+                .body(body)
+                .map_err(|e| TransportError::Fetch(StorageError::from(e)))
+        })
+        .await
+    }
+}
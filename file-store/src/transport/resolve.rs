@@ -0,0 +1,140 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Support for resolving host names other than through the system resolver.
+use std::collections::HashMap;
+use std::fmt;
+#[cfg(not(target_arch = "wasm32"))]
+use std::future::Future;
+use std::io;
+use std::net::IpAddr;
+#[cfg(not(target_arch = "wasm32"))]
+use std::pin::Pin;
+use std::sync::Arc;
+#[cfg(not(target_arch = "wasm32"))]
+use std::vec;
+
+#[cfg(not(target_arch = "wasm32"))]
+use futures::future::ready;
+use futures::future::BoxFuture;
+#[cfg(not(target_arch = "wasm32"))]
+use hyper::client::connect::dns::{GaiResolver, Name, Resolve};
+
+/// Future returned by [`Resolver::resolve`](trait.Resolver.html#tymethod.resolve).
+///
+/// Any `Send` future can be boxed into this with `Box::pin`.
+pub type ResolveFuture = BoxFuture<'static, io::Result<Vec<IpAddr>>>;
+
+/// Resolves host names to addresses for the HTTP based backends.
+///
+/// Implement this to use something other than the system's resolver, for
+/// example for split-horizon DNS. Host names overridden with a fixed address
+/// on the backend's builder never reach the resolver.
+pub trait Resolver: Send + Sync + 'static {
+    /// Looks up the addresses for a host name. The addresses are tried in
+    /// order until a connection succeeds.
+    fn resolve(&self, host: &str) -> ResolveFuture;
+}
+
+impl<F> Resolver for F
+where
+    F: Fn(&str) -> ResolveFuture + Send + Sync + 'static,
+{
+    fn resolve(&self, host: &str) -> ResolveFuture {
+        self(host)
+    }
+}
+
+/// How host names are resolved.
+#[derive(Clone, Default)]
+pub(crate) struct ResolverSettings {
+    /// Fixed addresses for hosts, keyed by the lowercase host name.
+    pub overrides: HashMap<String, Vec<IpAddr>>,
+    /// The resolver used for any other host, `None` uses the system resolver.
+    pub resolver: Option<Arc<dyn Resolver>>,
+}
+
+impl fmt::Debug for ResolverSettings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ResolverSettings")
+            .field("overrides", &self.overrides)
+            .field("custom", &self.resolver.is_some())
+            .finish()
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl ResolverSettings {
+    pub fn build(&self) -> DnsResolver {
+        DnsResolver {
+            overrides: Arc::new(self.overrides.clone()),
+            resolver: self.resolver.clone(),
+            system: GaiResolver::new(),
+        }
+    }
+}
+
+/// The resolver given to the HTTP connector.
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Clone)]
+pub(crate) struct DnsResolver {
+    overrides: Arc<HashMap<String, Vec<IpAddr>>>,
+    resolver: Option<Arc<dyn Resolver>>,
+    system: GaiResolver,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl fmt::Debug for DnsResolver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DnsResolver")
+            .field("overrides", &self.overrides)
+            .field("custom", &self.resolver.is_some())
+            .finish()
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Resolve for DnsResolver {
+    type Addrs = vec::IntoIter<IpAddr>;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Self::Addrs>> + Send>>;
+
+    fn resolve(&self, name: Name) -> Self::Future {
+        if let Some(addrs) = self.overrides.get(&name.as_str().to_lowercase()) {
+            return Box::pin(ready(Ok(addrs.clone().into_iter())));
+        }
+
+        match self.resolver {
+            Some(ref resolver) => {
+                let future = resolver.resolve(name.as_str());
+                Box::pin(async move {
+                    let addrs = future.await?;
+                    if addrs.is_empty() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::NotFound,
+                            format!("No addresses found for '{}'.", name.as_str()),
+                        ));
+                    }
+                    Ok(addrs.into_iter())
+                })
+            }
+            None => {
+                let future = self.system.resolve(name);
+                Box::pin(async move {
+                    let addrs = future.await?;
+                    Ok(addrs.collect::<Vec<IpAddr>>().into_iter())
+                })
+            }
+        }
+    }
+}
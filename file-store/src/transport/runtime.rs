@@ -0,0 +1,94 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Timers, tasks and clocks for the HTTP based backends. Natively these come
+//! from tokio and std, in WebAssembly they come from the JavaScript runtime.
+pub(crate) use instant::Instant;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) use tokio_executor::spawn;
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) use tokio_timer::delay_for;
+
+#[cfg(target_arch = "wasm32")]
+pub(crate) use self::wasm::{delay_for, spawn, SingleThreaded};
+
+#[cfg(target_arch = "wasm32")]
+mod wasm {
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+
+    use js_sys::{global, Function, Promise, Reflect};
+    use wasm_bindgen::{JsCast, JsValue};
+    use wasm_bindgen_futures::{spawn_local, JsFuture};
+
+    /// Wraps a future that holds JavaScript values so it can be used where a
+    /// `Send` future is needed.
+    pub(crate) struct SingleThreaded<T>(Pin<Box<dyn Future<Output = T>>>);
+
+    // SAFETY: Without the atomics target feature wasm32 has no threads, so the
+    // wrapped future can never be sent to or polled from another thread and
+    // the JavaScript values it holds never leave the thread that made them.
+    // Only the future is vouched for, its output must be `Send` on its own.
+    #[cfg(not(target_feature = "atomics"))]
+    unsafe impl<T: Send> Send for SingleThreaded<T> {}
+
+    impl<T> SingleThreaded<T> {
+        pub fn new<F>(future: F) -> SingleThreaded<T>
+        where
+            F: Future<Output = T> + 'static,
+        {
+            SingleThreaded(Box::pin(future))
+        }
+    }
+
+    impl<T> Future for SingleThreaded<T> {
+        type Output = T;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<T> {
+            self.0.as_mut().poll(cx)
+        }
+    }
+
+    /// Runs a future in the background on the JavaScript event loop.
+    pub(crate) fn spawn<F>(future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        spawn_local(future)
+    }
+
+    /// Resolves after the given duration using the runtime's `setTimeout`.
+    /// Looking it up on the global object works in browsers, workers and edge
+    /// runtimes alike.
+    pub(crate) fn delay_for(duration: Duration) -> SingleThreaded<()> {
+        let millis = duration.as_millis().min(i32::max_value() as u128) as i32;
+        let promise = Promise::new(&mut |resolve, reject| {
+            let scope = global();
+            let result = Reflect::get(&scope, &JsValue::from_str("setTimeout"))
+                .and_then(|f| f.dyn_into::<Function>())
+                .and_then(|f| f.call2(&scope, &resolve, &JsValue::from(millis)));
+            if let Err(e) = result {
+                let _ = reject.call1(&JsValue::undefined(), &e);
+            }
+        });
+
+        SingleThreaded::new(async move {
+            // A timer that cannot be started just doesn't wait.
+            let _ = JsFuture::from(promise).await;
+        })
+    }
+}
@@ -0,0 +1,379 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Moving deleted objects into a trash instead of removing them.
+//!
+//! A [`Trash`](struct.Trash.html) keeps deleted objects under a prefix of the
+//! store they were deleted from. Each deleted object is stored as
+//! `<prefix>/<time>/<name>` where `<time>` is the number of milliseconds since
+//! the unix epoch when it was deleted and `<name>` is the object's original
+//! path with `%` and `/` characters percent encoded.
+//!
+//! [`Trash::store`](struct.Trash.html#method.store) wraps a store so that
+//! deleting objects moves them into the trash. The file backend can do this
+//! itself, see
+//! [`FileBackendBuilder::trash`](../backends/file/struct.FileBackendBuilder.html#method.trash),
+//! and a [`Trash`](struct.Trash.html) using the same prefix can then be used to
+//! restore or purge what was deleted.
+//!
+//! Either way objects under the prefix are not included when listing objects
+//! outside of it and deleting an object that is already in the trash removes
+//! it permanently.
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::future::{ready, TryFutureExt};
+use futures::stream::TryStreamExt;
+
+use crate::backends::Backend;
+use crate::dynamic::{self, DynamicStore};
+use crate::types::*;
+use crate::{FileStore, ObjectInfo, StorageBackend};
+
+/// Future returned when listing the contents of a trash.
+pub type TrashListFuture = WrappedFuture<StorageResult<Vec<TrashedObject>>>;
+
+/// Checks whether a path is inside the trash.
+pub(crate) fn contains(trash: &ObjectPath, path: &ObjectPath) -> bool {
+    let trash = trash.parts();
+    !trash.is_empty() && path.parts().starts_with(&trash)
+}
+
+pub(crate) fn encode(path: &ObjectPath) -> String {
+    path.to_string().replace('%', "%25").replace('/', "%2F")
+}
+
+pub(crate) fn decode(name: &str) -> String {
+    name.replace("%2F", "/").replace("%25", "%")
+}
+
+fn last_part(path: &ObjectPath) -> Option<String> {
+    path.parts()
+        .into_iter()
+        .filter(|p| !p.is_empty())
+        .last()
+        .map(str::to_owned)
+}
+
+/// Gets where an object deleted at the given time is kept in the trash.
+pub(crate) fn location(trash: &ObjectPath, path: &ObjectPath, deleted: SystemTime) -> ObjectPath {
+    let millis = deleted
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0);
+
+    let mut location = trash.clone();
+    location.push_part(&millis.to_string());
+    location.push_part(&encode(path));
+    location
+}
+
+/// Removes objects in the trash from a listing unless the listing is of the
+/// trash itself.
+pub(crate) fn hide(
+    stream: ObjectStream,
+    trash: Option<ObjectPath>,
+    listed: &ObjectPath,
+) -> ObjectStream {
+    match trash {
+        Some(trash) if !contains(&trash, listed) => ObjectStream::from_stream(
+            stream.try_filter(move |object| ready(!contains(&trash, &object.path()))),
+        ),
+        _ => stream,
+    }
+}
+
+/// An object that has been moved into a trash.
+#[derive(Clone, Debug)]
+pub struct TrashedObject {
+    path: ObjectPath,
+    location: ObjectPath,
+    deleted: SystemTime,
+    object_type: ObjectType,
+}
+
+impl TrashedObject {
+    /// Gets the path the object was deleted from.
+    pub fn path(&self) -> &ObjectPath {
+        &self.path
+    }
+
+    /// Gets the path of the object in the trash.
+    pub fn location(&self) -> &ObjectPath {
+        &self.location
+    }
+
+    /// Gets the time the object was deleted.
+    pub fn deleted(&self) -> SystemTime {
+        self.deleted
+    }
+
+    /// Gets the type of the object. Only the file backend moves directories
+    /// into the trash.
+    pub fn object_type(&self) -> ObjectType {
+        self.object_type
+    }
+}
+
+/// Manages the objects that have been deleted into a prefix of a store.
+///
+/// See the [`trash`](index.html) module.
+#[derive(Clone, Debug)]
+pub struct Trash {
+    store: FileStore,
+    prefix: ObjectPath,
+}
+
+impl Trash {
+    /// Creates a trash that keeps deleted objects under the given prefix of
+    /// the store.
+    pub fn new(store: FileStore, prefix: ObjectPath) -> StorageResult<Trash> {
+        if prefix.parts().iter().all(|p| p.is_empty()) {
+            return Err(error::invalid_path(
+                prefix,
+                Some("The trash cannot be the root of the store."),
+            ));
+        }
+
+        Ok(Trash { store, prefix })
+    }
+
+    /// Wraps the store so that deleting objects through the returned store
+    /// moves them into this trash.
+    pub fn store(&self) -> FileStore {
+        FileStore::from(DynamicStore::new(TrashStore {
+            store: self.store.clone(),
+            prefix: self.prefix.clone(),
+        }))
+    }
+
+    /// Lists the objects in the trash, oldest deletions first.
+    pub fn list(&self) -> TrashListFuture {
+        TrashListFuture::from_future(list(self.store.clone(), self.prefix.clone()))
+    }
+
+    /// Moves an object out of the trash back to the path it was deleted from.
+    ///
+    /// Fails with an [`AlreadyExists`](../enum.StorageErrorKind.html#variant.AlreadyExists)
+    /// error if something has since been created at that path.
+    pub fn restore(&self, object: &TrashedObject) -> OperationCompleteFuture {
+        async fn restore(store: FileStore, object: TrashedObject) -> StorageResult<()> {
+            move_object(&store, object.location.clone(), object.path.clone()).await?;
+
+            // The file backend has real directories which must be cleaned up.
+            if store.backend_type() == Backend::File {
+                let mut directory = object.location.clone();
+                directory.pop_part();
+                let remaining: Vec<Object> = store
+                    .list_directory(directory.clone())
+                    .await?
+                    .try_collect()
+                    .await?;
+                if remaining.is_empty() {
+                    store.delete_object(directory).await?;
+                }
+            }
+
+            Ok(())
+        }
+
+        OperationCompleteFuture::from_future(restore(self.store.clone(), object.clone()))
+    }
+
+    /// Permanently deletes the objects in the trash that were deleted before
+    /// the given time, or everything if no time is given.
+    pub fn purge(&self, before: Option<SystemTime>) -> OperationCompleteFuture {
+        async fn purge(
+            store: FileStore,
+            prefix: ObjectPath,
+            before: Option<SystemTime>,
+        ) -> StorageResult<()> {
+            let mut directories: Vec<ObjectPath> = Vec::new();
+            for object in list(store.clone(), prefix).await? {
+                if before.map_or(false, |t| object.deleted >= t) {
+                    continue;
+                }
+
+                if store.backend_type() == Backend::File {
+                    // Everything in a directory was deleted at the same time.
+                    let mut directory = object.location;
+                    directory.pop_part();
+                    if !directories.contains(&directory) {
+                        directories.push(directory);
+                    }
+                } else {
+                    store.delete_object(object.location).await?;
+                }
+            }
+
+            for directory in directories {
+                store.delete_object(directory).await?;
+            }
+
+            Ok(())
+        }
+
+        OperationCompleteFuture::from_future(purge(self.store.clone(), self.prefix.clone(), before))
+    }
+}
+
+async fn list(store: FileStore, prefix: ObjectPath) -> StorageResult<Vec<TrashedObject>> {
+    let mut objects = Vec::new();
+
+    let directories: Vec<Object> = store
+        .list_directory(prefix.clone())
+        .await?
+        .try_collect()
+        .await?;
+    for directory in directories {
+        let name = match last_part(&directory.path()) {
+            Some(name) => name,
+            None => continue,
+        };
+        let deleted = match name.parse::<u64>() {
+            Ok(millis) => UNIX_EPOCH + Duration::from_millis(millis),
+            Err(_) => continue,
+        };
+
+        let mut path = prefix.clone();
+        path.push_part(&name);
+        let entries: Vec<Object> = store
+            .list_directory(path.clone())
+            .await?
+            .try_collect()
+            .await?;
+        for entry in entries {
+            let name = match last_part(&entry.path()) {
+                Some(name) => name,
+                None => continue,
+            };
+            let original = match ObjectPath::new(decode(&name)) {
+                Ok(original) => original,
+                Err(_) => continue,
+            };
+
+            let mut location = path.clone();
+            location.push_part(&name);
+            objects.push(TrashedObject {
+                path: original,
+                location,
+                deleted,
+                object_type: entry.object_type(),
+            });
+        }
+    }
+
+    objects.sort_by_key(|o| o.deleted);
+    Ok(objects)
+}
+
+/// Moves an object without replacing anything at the target.
+async fn move_object(
+    store: &FileStore,
+    source: ObjectPath,
+    target: ObjectPath,
+) -> StorageResult<()> {
+    match store {
+        #[cfg(feature = "file")]
+        FileStore::File(ref backend) => backend.rename_object(source, target).await,
+        _ => {
+            let mut info = UploadInfo::from(target);
+            info.options.mode = WriteMode::FailIfExists;
+            store
+                .move_file(source, info)
+                .map_err(|e| match e {
+                    TransferError::SourceError(e) => e,
+                    TransferError::TargetError(e) => e,
+                })
+                .await
+        }
+    }
+}
+
+/// Wraps a store moving deleted objects into the trash.
+struct TrashStore {
+    store: FileStore,
+    prefix: ObjectPath,
+}
+
+// Only StorageBackend is in scope so calls on the wrapped store are not
+// ambiguous.
+impl dynamic::DynamicBackend for TrashStore {
+    fn backend_type(&self) -> Backend {
+        self.store.backend_type()
+    }
+
+    fn authorize(&self) -> OperationCompleteFuture {
+        self.store.authorize()
+    }
+
+    fn validate_path(&self, path: &ObjectPath) -> StorageResult<()> {
+        self.store.validate_path(path)
+    }
+
+    fn list_objects(&self, prefix: ObjectPath) -> ObjectStreamFuture {
+        let trash = self.prefix.clone();
+        ObjectStreamFuture::from_future(
+            self.store
+                .list_objects(prefix.clone())
+                .map_ok(move |stream| hide(stream, Some(trash), &prefix)),
+        )
+    }
+
+    fn list_directory(&self, dir: ObjectPath) -> ObjectStreamFuture {
+        let trash = self.prefix.clone();
+        ObjectStreamFuture::from_future(
+            self.store
+                .list_directory(dir.clone())
+                .map_ok(move |stream| hide(stream, Some(trash), &dir)),
+        )
+    }
+
+    fn get_object(&self, path: ObjectPath) -> ObjectFuture {
+        self.store.get_object(path)
+    }
+
+    fn get_file_stream(&self, path: ObjectPath) -> DataStreamFuture {
+        self.store.get_file_stream(path)
+    }
+
+    fn get_file_stream_with_options(
+        &self,
+        path: ObjectPath,
+        options: ReadOptions,
+    ) -> DataStreamFuture {
+        self.store.get_file_stream_with_options(path, options)
+    }
+
+    fn copy_file(&self, source: ObjectPath, target: UploadInfo) -> CopyCompleteFuture {
+        self.store.copy_file(source, target)
+    }
+
+    fn move_file(&self, source: ObjectPath, target: UploadInfo) -> MoveCompleteFuture {
+        self.store.move_file(source, target)
+    }
+
+    fn delete_object(&self, path: ObjectPath) -> OperationCompleteFuture {
+        if contains(&self.prefix, &path) {
+            return self.store.delete_object(path);
+        }
+
+        let store = self.store.clone();
+        let target = location(&self.prefix, &path, SystemTime::now());
+        OperationCompleteFuture::from_future(async move { move_object(&store, path, target).await })
+    }
+
+    fn write_file_from_stream(&self, info: UploadInfo, stream: DataStream) -> WriteCompleteFuture {
+        self.store.write_file_from_stream(info, stream)
+    }
+}
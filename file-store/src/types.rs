@@ -17,20 +17,20 @@ pub(crate) mod error;
 pub(crate) mod future;
 pub(crate) mod objects;
 pub(crate) mod path;
+pub(crate) mod retry;
 pub(crate) mod stream;
 
-use std::io;
-
-use bytes::buf::Buf;
-use bytes::{Bytes, IntoBuf};
-use futures::executor::{block_on_stream, BlockingStream};
-use futures::stream::Stream;
+use bytes::Bytes;
 
 use super::FileStore;
 pub use error::{StorageError, StorageErrorKind, StorageResult, TransferError};
 pub use future::WrappedFuture;
-pub use objects::{Object, ObjectInfo, ObjectType, UploadInfo};
+pub use objects::{
+    CustomObject, CustomerKey, Encryption, Object, ObjectInfo, ObjectType, ReadOptions,
+    RestoreStatus, StorageClass, UploadInfo, WriteMode, WriteOptions,
+};
 pub use path::ObjectPath;
+pub use retry::RetryPolicy;
 pub use stream::WrappedStream;
 
 /// The data type used for streaming data from and to files.
@@ -46,57 +46,22 @@ pub type ObjectStream = WrappedStream<StorageResult<Object>>;
 pub type ObjectStreamFuture = WrappedFuture<StorageResult<ObjectStream>>;
 /// A future that returns an [`Object`](enum.Object.html).
 pub type ObjectFuture = WrappedFuture<StorageResult<Object>>;
+/// A future that returns an [`Object`](enum.Object.html) if one was found.
+pub type OptionalObjectFuture = WrappedFuture<StorageResult<Option<Object>>>;
+/// A future that returns a list of [`Object`s](enum.Object.html).
+pub type ObjectsFuture = WrappedFuture<StorageResult<Vec<Object>>>;
 /// A future that resolves whenever the requested operation is complete.
 pub type OperationCompleteFuture = WrappedFuture<StorageResult<()>>;
 /// A future that resolves when a write operation is complete.
 pub type WriteCompleteFuture = WrappedFuture<Result<(), TransferError>>;
 /// A future that resolves to a [`DataStream`](type.DataStream.html).
 pub type DataStreamFuture = WrappedFuture<StorageResult<DataStream>>;
+/// A stream that returns [`Data`](type.Data.html) along with the offset in the
+/// file that it starts at.
+pub type OffsetDataStream = WrappedStream<StorageResult<(u64, Data)>>;
+/// A future that resolves to an [`OffsetDataStream`](type.OffsetDataStream.html).
+pub type OffsetDataStreamFuture = WrappedFuture<StorageResult<OffsetDataStream>>;
 /// A future that resolves when the copy is complete.
 pub type CopyCompleteFuture = WrappedFuture<Result<(), TransferError>>;
 /// A future that resolves when the move is complete.
 pub type MoveCompleteFuture = WrappedFuture<Result<(), TransferError>>;
-
-pub(crate) struct BlockingStreamReader<S>
-where
-    S: Unpin + Stream,
-{
-    stream: BlockingStream<S>,
-    reader: Option<Box<dyn io::Read>>,
-}
-
-impl<S> BlockingStreamReader<S>
-where
-    S: Unpin + Stream,
-{
-    pub fn from_stream(stream: S) -> BlockingStreamReader<S> {
-        BlockingStreamReader {
-            stream: block_on_stream(stream),
-            reader: None,
-        }
-    }
-}
-
-impl<S, O, E> io::Read for BlockingStreamReader<S>
-where
-    S: Unpin + Stream<Item = Result<O, E>>,
-    O: IntoBuf,
-    <O as IntoBuf>::Buf: 'static,
-    E: Into<StorageError>,
-{
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        match self.reader {
-            Some(ref mut r) => r.read(buf),
-            None => match self.stream.next() {
-                Some(Ok(d)) => {
-                    let mut reader = d.into_buf().reader();
-                    let count = reader.read(buf)?;
-                    self.reader = Some(Box::new(reader));
-                    Ok(count)
-                }
-                Some(Err(e)) => Err(e.into().into()),
-                None => Ok(0),
-            },
-        }
-    }
-}
@@ -20,6 +20,7 @@ use std::io;
 use log::error;
 
 use super::ObjectPath;
+use crate::correlation::OperationId;
 
 /// The kind of an [`StorageError`](struct.StorageError.html).
 #[derive(Clone, Debug, PartialEq)]
@@ -51,6 +52,14 @@ pub enum StorageErrorKind {
     InvalidSettings,
     /// Some kind of limit on use use of the service has been reached.
     OverQuota,
+    /// A usage cap set on the account has been reached. Retrying will fail
+    /// until the cap is raised.
+    QuotaExceeded,
+    /// There is not enough free space in storage to write the file.
+    InsufficientSpace,
+    /// The service is limiting the rate of requests. Trying again later may
+    /// succeed.
+    RateLimited,
     /// An internal failure, please report a bug!
     InternalError,
     /// Any other type of error (normally will have an inner error).
@@ -59,18 +68,23 @@ pub enum StorageErrorKind {
 
 /// Errors hit while interacting with storage backends. Generally wrapped by an
 /// `io::Error`. Can be reached with `TryFrom`.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct StorageError {
     kind: StorageErrorKind,
     detail: Option<String>,
+    operation_id: Option<OperationId>,
 }
 
 impl StorageError {
     /// Creates a new `StorageError`.
+    ///
+    /// The error is for the [current operation](../correlation/struct.OperationId.html#method.current),
+    /// if any.
     pub fn new(kind: StorageErrorKind, detail: Option<&str>) -> StorageError {
         StorageError {
             kind,
             detail: detail.map(ToOwned::to_owned),
+            operation_id: OperationId::current(),
         }
     }
 
@@ -79,6 +93,13 @@ impl StorageError {
         self.kind.clone()
     }
 
+    /// Returns the id of the operation that hit this error.
+    ///
+    /// See the [`correlation`](../correlation/index.html) module.
+    pub fn operation_id(&self) -> Option<OperationId> {
+        self.operation_id
+    }
+
     // fn write<A, B>(&self, f: &mut fmt::Formatter, with_detail: A, without_detail: B) -> fmt::Result
     // where
     //     A: AsRef<str>,
@@ -136,6 +157,15 @@ impl fmt::Display for StorageError {
             StorageErrorKind::OverQuota => {
                 self.default_write(f, "A storage limit has been reached")
             }
+            StorageErrorKind::QuotaExceeded => {
+                self.default_write(f, "A usage cap on the account has been reached")
+            }
+            StorageErrorKind::InsufficientSpace => {
+                self.default_write(f, "There is not enough free space in storage")
+            }
+            StorageErrorKind::RateLimited => {
+                self.default_write(f, "Too many requests were made to the storage system")
+            }
             StorageErrorKind::ServiceError => {
                 self.default_write(f, "The storage system encountered an error")
             }
@@ -161,6 +191,9 @@ impl From<StorageError> for io::Error {
             StorageErrorKind::AccessExpired => io::ErrorKind::PermissionDenied,
             StorageErrorKind::ServiceError => io::ErrorKind::Other,
             StorageErrorKind::OverQuota => io::ErrorKind::Other,
+            StorageErrorKind::QuotaExceeded => io::ErrorKind::Other,
+            StorageErrorKind::InsufficientSpace => io::ErrorKind::Other,
+            StorageErrorKind::RateLimited => io::ErrorKind::Other,
         };
 
         io::Error::new(kind, error)
@@ -183,10 +216,7 @@ impl From<io::Error> for StorageError {
             _ => StorageErrorKind::Other,
         };
 
-        StorageError {
-            kind,
-            detail: Some(error.to_string()),
-        }
+        StorageError::new(kind, Some(&error.to_string()))
     }
 }
 
@@ -228,6 +258,18 @@ pub fn over_quota(detail: Option<&str>) -> StorageError {
     StorageError::new(StorageErrorKind::OverQuota, detail)
 }
 
+pub fn quota_exceeded(detail: Option<&str>) -> StorageError {
+    StorageError::new(StorageErrorKind::QuotaExceeded, detail)
+}
+
+pub fn insufficient_space(detail: Option<&str>) -> StorageError {
+    StorageError::new(StorageErrorKind::InsufficientSpace, detail)
+}
+
+pub fn rate_limited(detail: Option<&str>) -> StorageError {
+    StorageError::new(StorageErrorKind::RateLimited, detail)
+}
+
 pub fn access_denied(detail: Option<&str>) -> StorageError {
     StorageError::new(StorageErrorKind::AccessDenied, detail)
 }
@@ -25,6 +25,9 @@ pub(crate) type FuturePoll<R> = Poll<R>;
 pub(crate) type PinnedFuture<R> = Pin<Box<dyn Future<Output = R> + Send + 'static>>;
 
 /// Wraps a future of an unknown type into a concrete type.
+///
+/// Backends implemented outside of this crate use this to return the futures
+/// that [`StorageBackend`](trait.StorageBackend.html) requires.
 pub struct WrappedFuture<R>
 where
     R: Send + 'static,
@@ -36,7 +39,8 @@ impl<R> WrappedFuture<R>
 where
     R: Send + 'static,
 {
-    pub(crate) fn from_future<F>(base: F) -> WrappedFuture<F::Output>
+    /// Wraps a future.
+    pub fn from_future<F>(base: F) -> WrappedFuture<F::Output>
     where
         F: Future + Send + 'static,
         F::Output: Send,
@@ -46,7 +50,8 @@ where
         }
     }
 
-    pub(crate) fn from_value(value: R) -> WrappedFuture<R> {
+    /// Creates a future that resolves immediately to the given value.
+    pub fn from_value(value: R) -> WrappedFuture<R> {
         WrappedFuture {
             base: Box::pin(ready(value)),
         }
@@ -102,6 +102,7 @@ impl fmt::Display for ObjectType {
 pub enum Object {
     B2(B2Object),
     File(FileObject),
+    Custom(CustomObject),
 }
 
 impl PartialEq for Object {
@@ -152,6 +153,40 @@ pub trait ObjectInfo {
     /// Gets the last modification time for the object.
     fn modified(&self) -> Option<SystemTime>;
 
+    /// Gets the time the object was created.
+    ///
+    /// Only the file backend reports this and only on platforms and
+    /// filesystems that record it.
+    fn created(&self) -> Option<SystemTime> {
+        None
+    }
+
+    /// Gets the time the object was last accessed.
+    ///
+    /// Only the file backend reports this. Many filesystems update it lazily
+    /// or not at all so it should only be treated as a hint.
+    fn accessed(&self) -> Option<SystemTime> {
+        None
+    }
+
+    /// Gets the storage class that the object is stored in.
+    ///
+    /// Neither the file nor the B2 backend offer different storage classes
+    /// so their objects are always
+    /// [`Standard`](enum.StorageClass.html#variant.Standard).
+    fn storage_class(&self) -> StorageClass {
+        StorageClass::Standard
+    }
+
+    /// Gets whether the object can be read or must be restored from an
+    /// archive first.
+    ///
+    /// Objects from backends without cold tiers are always
+    /// [`Available`](enum.RestoreStatus.html#variant.Available).
+    fn restore_status(&self) -> RestoreStatus {
+        RestoreStatus::Available
+    }
+
     /// Creates an [`UploadInfo`](struct.UploadInfo.html) for uploading this
     /// object to a new path.
     fn as_upload<P>(&self, path: P) -> StorageResult<UploadInfo>
@@ -167,6 +202,78 @@ pub trait ObjectInfo {
     }
 }
 
+/// A generic object for backends implemented outside of this crate.
+///
+/// See the [`dynamic`](dynamic/index.html) module for more details.
+#[derive(Clone, Debug)]
+pub struct CustomObject {
+    path: ObjectPath,
+    object_type: ObjectType,
+    len: u64,
+    modified: Option<SystemTime>,
+    storage_class: StorageClass,
+    restore_status: RestoreStatus,
+}
+
+impl CustomObject {
+    /// Creates a new object.
+    pub fn new(
+        path: ObjectPath,
+        object_type: ObjectType,
+        len: u64,
+        modified: Option<SystemTime>,
+    ) -> CustomObject {
+        CustomObject {
+            path,
+            object_type,
+            len,
+            modified,
+            storage_class: StorageClass::Standard,
+            restore_status: RestoreStatus::Available,
+        }
+    }
+
+    /// Sets the storage class the object is stored in, the default is
+    /// [`Standard`](enum.StorageClass.html#variant.Standard).
+    pub fn with_storage_class(mut self, storage_class: StorageClass) -> CustomObject {
+        self.storage_class = storage_class;
+        self
+    }
+
+    /// Sets whether the object can be read, the default is
+    /// [`Available`](enum.RestoreStatus.html#variant.Available).
+    pub fn with_restore_status(mut self, restore_status: RestoreStatus) -> CustomObject {
+        self.restore_status = restore_status;
+        self
+    }
+}
+
+impl ObjectInfo for CustomObject {
+    fn path(&self) -> ObjectPath {
+        self.path.clone()
+    }
+
+    fn len(&self) -> u64 {
+        self.len
+    }
+
+    fn object_type(&self) -> ObjectType {
+        self.object_type
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        self.modified
+    }
+
+    fn storage_class(&self) -> StorageClass {
+        self.storage_class
+    }
+
+    fn restore_status(&self) -> RestoreStatus {
+        self.restore_status
+    }
+}
+
 /// Information used to upload a file.
 ///
 /// This allows attempting to set various properties of a file on upload. Not
@@ -183,6 +290,8 @@ pub struct UploadInfo {
     pub path: ObjectPath,
     /// Sets the last modified time for the file.
     pub modified: Option<SystemTime>,
+    /// Options controlling how the file is written.
+    pub options: WriteOptions,
 }
 
 impl<I> From<I> for UploadInfo
@@ -193,6 +302,7 @@ where
         UploadInfo {
             path: info.path(),
             modified: info.modified(),
+            options: Default::default(),
         }
     }
 }
@@ -202,6 +312,7 @@ impl From<ObjectPath> for UploadInfo {
         UploadInfo {
             path,
             modified: None,
+            options: Default::default(),
         }
     }
 }
@@ -213,3 +324,192 @@ impl TryFrom<&str> for UploadInfo {
         Ok(ObjectPath::new(s)?.into())
     }
 }
+
+/// Options controlling how a file is written.
+///
+/// Like [`ReadOptions`](struct.ReadOptions.html) these are hints and any
+/// option left unset uses the backend's default.
+#[derive(Clone, Debug, Default)]
+pub struct WriteOptions {
+    /// The number of chunks of data read ahead from the source stream.
+    ///
+    /// Data is read from the source into a buffer of this many chunks while
+    /// earlier chunks are still being written so a slow backend doesn't stop
+    /// a fast source from producing data and vice versa. Larger values use
+    /// more memory. `Some(0)` still allows a single chunk to be read ahead.
+    pub buffer_depth: Option<usize>,
+    /// Deletes whatever was written if the write fails.
+    ///
+    /// Without this a failed write may leave a partial file or, for backends
+    /// that upload in parts, an unfinished upload behind.
+    pub delete_on_failure: bool,
+    /// Controls what happens if something already exists at the path.
+    pub mode: WriteMode,
+    /// How the storage service should encrypt the file.
+    ///
+    /// When unset the backend's default is used. Backends that store files
+    /// without a storage service, like the file backend, ignore this.
+    pub encryption: Option<Encryption>,
+    /// Gives a copied file the source's last modification time.
+    ///
+    /// Only used by `copy_file` and only when
+    /// [`UploadInfo::modified`](struct.UploadInfo.html#structfield.modified)
+    /// is unset. Mirroring tools that compare modification times need this
+    /// to avoid copying files again that have not changed.
+    pub preserve_modified: bool,
+    /// Waits for the file to reach stable storage before the write completes.
+    ///
+    /// The file backend syncs the file and, on unix, its directory so neither
+    /// the data nor the new name is lost if the system crashes. This makes
+    /// writes slower. Other backends ignore this, their writes are already
+    /// stored durably once they complete.
+    pub durable: bool,
+    /// The number of bytes that will be written, if known.
+    ///
+    /// The file backend uses this to check there is enough free space before
+    /// writing anything, failing with an
+    /// [`InsufficientSpace`](enum.StorageErrorKind.html#variant.InsufficientSpace)
+    /// error if not. Copies within the file backend always check.
+    pub content_length: Option<u64>,
+    /// The storage class to store the file in.
+    ///
+    /// When unset the backend's default is used. The file and B2 backends
+    /// have a single class so ignore this, backends for services with tiers
+    /// map the classes to the nearest tier they offer.
+    pub storage_class: Option<StorageClass>,
+}
+
+/// How a write treats an object that already exists at the target path.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WriteMode {
+    /// Replaces whatever is at the path.
+    Overwrite,
+    /// Fails with an [`AlreadyExists`](enum.StorageErrorKind.html#variant.AlreadyExists)
+    /// error if anything is at the path.
+    ///
+    /// The file backend creates the file exclusively so when two writers race
+    /// to create the same file exactly one will succeed. Backends without an
+    /// atomic create check for an existing object before writing so a race
+    /// there may still see one writer replace the other's file.
+    FailIfExists,
+}
+
+impl Default for WriteMode {
+    fn default() -> WriteMode {
+        WriteMode::Overwrite
+    }
+}
+
+/// How a storage service stores a file, trading the cost of keeping the file
+/// against the cost and delay of reading it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum StorageClass {
+    /// For files that are read often.
+    Standard,
+    /// Cheaper to store but more expensive to read, for files that are read
+    /// rarely but need to be available immediately.
+    InfrequentAccess,
+    /// The cheapest to store, for files that are almost never read. Services
+    /// may need to restore these files before they can be read.
+    Archive,
+}
+
+impl Default for StorageClass {
+    fn default() -> StorageClass {
+        StorageClass::Standard
+    }
+}
+
+impl fmt::Display for StorageClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StorageClass::Standard => f.pad("standard"),
+            StorageClass::InfrequentAccess => f.pad("infrequent-access"),
+            StorageClass::Archive => f.pad("archive"),
+        }
+    }
+}
+
+/// Whether an object can be read or must be restored from an archive first.
+///
+/// See [`StorageBackend::restore_object`](trait.StorageBackend.html#method.restore_object).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RestoreStatus {
+    /// The object can be read.
+    Available,
+    /// The object is archived and must be restored before it can be read.
+    Archived,
+    /// A restore has been requested but the object cannot be read yet.
+    PendingRestore,
+    /// The object has been restored and can be read until the given time,
+    /// after which it is archived again.
+    Restored(SystemTime),
+}
+
+/// Server-side encryption of a file's contents.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Encryption {
+    /// Stored unencrypted, or with the storage service's default.
+    None,
+    /// Encrypted with keys that the storage service manages.
+    Managed,
+    /// Encrypted with a key supplied by the client.
+    ///
+    /// The storage service does not keep the key so the same key must be
+    /// supplied again to read the file.
+    Customer(CustomerKey),
+}
+
+/// A client supplied 256-bit key for server-side encryption.
+///
+/// The key is never included in `Debug` output.
+#[derive(Clone, PartialEq)]
+pub struct CustomerKey {
+    key: [u8; 32],
+}
+
+impl CustomerKey {
+    /// Creates a key from 32 bytes of data.
+    pub fn new(key: &[u8]) -> StorageResult<CustomerKey> {
+        if key.len() != 32 {
+            return Err(error::invalid_settings(Some(&format!(
+                "Customer keys must be 32 bytes long, not {}.",
+                key.len()
+            ))));
+        }
+
+        let mut data = [0; 32];
+        data.copy_from_slice(key);
+        Ok(CustomerKey { key: data })
+    }
+
+    /// Gets the key's data.
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+}
+
+impl fmt::Debug for CustomerKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad("CustomerKey(..)")
+    }
+}
+
+/// Options used when reading a file.
+///
+/// These are hints, backends that cannot make use of an option will ignore it.
+/// Any option left unset uses the value configured for the store.
+#[derive(Clone, Debug, Default)]
+pub struct ReadOptions {
+    /// The size of the buffers that data is read into.
+    ///
+    /// Each stream holds one buffer of this size while reading so smaller
+    /// values use less memory when many files are read at once.
+    pub buffer_size: Option<usize>,
+    /// Once the space left in a buffer drops below this size a new buffer is
+    /// allocated.
+    pub min_buffer_size: Option<usize>,
+    /// The key needed to read a file encrypted with
+    /// [`Encryption::Customer`](enum.Encryption.html#variant.Customer).
+    pub customer_key: Option<CustomerKey>,
+}
@@ -0,0 +1,147 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Retrying failed operations.
+
+use std::collections::HashMap;
+use std::mem::{discriminant, Discriminant};
+use std::time::Duration;
+
+use super::StorageErrorKind;
+
+const DEFAULT_MAX_ATTEMPTS: usize = 5;
+const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(32);
+
+/// Controls how backends retry operations that fail.
+///
+/// Each backend decides which errors are transient, for example B2 retries
+/// dropped connections and rate limiting while the file backend treats every
+/// error as permanent. Transient errors are retried until the operation has
+/// been attempted `max_attempts` times. Overrides for a kind of error replace
+/// both the backend's decision and the maximum attempts. No attempt is started
+/// once the time budget, measured from the start of the first attempt, would
+/// be exceeded.
+///
+/// By default operations are attempted 5 times with no time budget and
+/// backoffs starting at one second and doubling up to 32 seconds.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    max_attempts: usize,
+    budget: Option<Duration>,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    overrides: HashMap<Discriminant<StorageErrorKind>, usize>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            budget: None,
+            initial_backoff: DEFAULT_INITIAL_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+            overrides: HashMap::new(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Creates the default policy.
+    pub fn new() -> RetryPolicy {
+        Default::default()
+    }
+
+    /// Creates a policy that never retries.
+    pub fn never() -> RetryPolicy {
+        RetryPolicy::new().max_attempts(1)
+    }
+
+    /// Sets the number of times an operation that fails with a transient error
+    /// is attempted, including the first attempt. Zero is treated as one.
+    pub fn max_attempts(mut self, attempts: usize) -> RetryPolicy {
+        self.max_attempts = attempts.max(1);
+        self
+    }
+
+    /// Sets the total time that attempts, and waiting between them, may take.
+    pub fn budget(mut self, budget: Duration) -> RetryPolicy {
+        self.budget = Some(budget);
+        self
+    }
+
+    /// Sets the delay before the first retry and the most it can grow to.
+    /// Backends only wait between attempts for errors that ask for it, like
+    /// rate limiting.
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> RetryPolicy {
+        self.initial_backoff = initial;
+        self.max_backoff = max.max(initial);
+        self
+    }
+
+    /// Sets the number of times an operation that fails with the given kind of
+    /// error is attempted, including the first attempt, whether or not the
+    /// backend considers the error transient. Only the kind is compared so
+    /// any path in [`NotFound`](enum.StorageErrorKind.html#variant.NotFound)
+    /// is ignored.
+    pub fn attempts_for(mut self, kind: &StorageErrorKind, attempts: usize) -> RetryPolicy {
+        self.overrides.insert(discriminant(kind), attempts.max(1));
+        self
+    }
+
+    /// The number of times an operation failing with this kind of error is
+    /// attempted.
+    pub fn attempts(&self, kind: &StorageErrorKind, transient: bool) -> usize {
+        match self.overrides.get(&discriminant(kind)) {
+            Some(attempts) => *attempts,
+            None if transient => self.max_attempts,
+            None => 1,
+        }
+    }
+
+    /// The delay before the given retry, counting from one, when backing off.
+    pub fn backoff_delay(&self, retry: usize) -> Duration {
+        let exponent = retry.saturating_sub(1).min(31) as u32;
+        self.initial_backoff
+            .checked_mul(2u32.pow(exponent))
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff)
+    }
+
+    /// The longest delay between attempts.
+    pub(crate) fn max_backoff(&self) -> Duration {
+        self.max_backoff
+    }
+
+    /// Checks whether another attempt can be made after `attempts` attempts
+    /// that have taken `elapsed` so far with the last failing with this kind
+    /// of error, waiting `delay` first.
+    pub(crate) fn should_retry(
+        &self,
+        kind: &StorageErrorKind,
+        transient: bool,
+        attempts: usize,
+        elapsed: Duration,
+        delay: Duration,
+    ) -> bool {
+        if attempts >= self.attempts(kind, transient) {
+            return false;
+        }
+
+        match self.budget {
+            Some(budget) => elapsed + delay < budget,
+            None => true,
+        }
+    }
+}
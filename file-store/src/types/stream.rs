@@ -13,12 +13,19 @@
 // limitations under the License.
 
 //! A module with some useful tools for working with streams.
+use std::ops::Range;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
-use futures::stream::Stream;
+use futures::channel::oneshot;
+use futures::future::{ready, FutureExt, TryFutureExt};
+use futures::stream::{iter, Stream, StreamExt, TryStreamExt};
 
-use super::StorageResult;
+use super::{
+    error, CopyCompleteFuture, Data, DataStream, DataStreamFuture, Object, ObjectInfo, ObjectPath,
+    ObjectStream, ObjectStreamFuture, ObjectType, ObjectsFuture, OffsetDataStream,
+    OptionalObjectFuture, StorageResult, TransferError, UploadInfo, WrappedFuture,
+};
 
 pub(crate) type StreamPoll<R> = Poll<Option<R>>;
 pub(crate) type ResultStreamPoll<R> = StreamPoll<StorageResult<R>>;
@@ -26,6 +33,10 @@ pub(crate) type ResultStreamPoll<R> = StreamPoll<StorageResult<R>>;
 pub(crate) type PinnedStream<R> = Pin<Box<dyn Stream<Item = R> + Send + 'static>>;
 
 /// Wraps a stream of an unknown type into a concrete type.
+///
+/// This is what listings and downloads return, see
+/// [`ObjectStream`](type.ObjectStream.html) and
+/// [`DataStream`](type.DataStream.html).
 pub struct WrappedStream<R>
 where
     R: Send + 'static,
@@ -37,7 +48,8 @@ impl<R> WrappedStream<R>
 where
     R: Send + 'static,
 {
-    pub(crate) fn from_stream<S>(base: S) -> WrappedStream<S::Item>
+    /// Wraps a stream.
+    pub fn from_stream<S>(base: S) -> WrappedStream<S::Item>
     where
         S: Stream + Send + 'static,
         S::Item: Send,
@@ -59,6 +71,70 @@ where
     }
 }
 
+impl ObjectStream {
+    /// Gathers the listed objects into a `Vec`, failing with the first error.
+    ///
+    /// With a `limit` listing stops once that many objects have been found
+    /// and just those are returned.
+    pub fn collect_all(self, limit: Option<usize>) -> ObjectsFuture {
+        async fn collect(
+            mut stream: ObjectStream,
+            limit: Option<usize>,
+        ) -> StorageResult<Vec<Object>> {
+            let mut objects = Vec::new();
+            while limit.map_or(true, |limit| objects.len() < limit) {
+                match stream.next().await {
+                    Some(object) => objects.push(object?),
+                    None => break,
+                }
+            }
+
+            Ok(objects)
+        }
+
+        WrappedFuture::from_future(collect(self, limit))
+    }
+
+    /// Finds the first listed object that matches the predicate, failing with
+    /// the first error. Listing stops as soon as a match is found.
+    pub fn first_match<P>(self, predicate: P) -> OptionalObjectFuture
+    where
+        P: FnMut(&Object) -> bool + Send + 'static,
+    {
+        async fn find<P>(
+            mut stream: ObjectStream,
+            mut predicate: P,
+        ) -> StorageResult<Option<Object>>
+        where
+            P: FnMut(&Object) -> bool + Send + 'static,
+        {
+            while let Some(object) = stream.next().await {
+                let object = object?;
+                if predicate(&object) {
+                    return Ok(Some(object));
+                }
+            }
+
+            Ok(None)
+        }
+
+        WrappedFuture::from_future(find(self, predicate))
+    }
+}
+
+impl DataStream {
+    /// Annotates each chunk of data with its offset in the file, given the
+    /// offset that the stream starts at.
+    pub fn with_offsets(self, start: u64) -> OffsetDataStream {
+        let mut offset = start;
+        WrappedStream::from_stream(self.map_ok(move |data| {
+            let chunk = (offset, data);
+            offset += chunk.1.len() as u64;
+            chunk
+        }))
+    }
+}
+
 /// Merges a set of streams into a single stream that returns results whenever
 /// they arrive, not necessarily in the order the streams were added.
 ///
@@ -183,3 +259,179 @@ where
         result
     }
 }
+
+/// Fails a data stream that ends before delivering the expected number of
+/// bytes.
+///
+/// A connection that is closed cleanly partway through a download otherwise
+/// just looks like the end of the file.
+pub(crate) struct LengthCheckedStream<S>
+where
+    S: Stream<Item = StorageResult<Data>> + Send + 'static,
+{
+    inner: Pin<Box<S>>,
+    expected: u64,
+    received: u64,
+    done: bool,
+}
+
+impl<S> LengthCheckedStream<S>
+where
+    S: Stream<Item = StorageResult<Data>> + Send + 'static,
+{
+    pub fn new(stream: S, expected: u64) -> LengthCheckedStream<S> {
+        LengthCheckedStream {
+            inner: Box::pin(stream),
+            expected,
+            received: 0,
+            done: false,
+        }
+    }
+}
+
+impl<S> Stream for LengthCheckedStream<S>
+where
+    S: Stream<Item = StorageResult<Data>> + Send + 'static,
+{
+    type Item = StorageResult<Data>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> ResultStreamPoll<Data> {
+        if self.done {
+            return Poll::Ready(None);
+        }
+
+        match self.inner.as_mut().poll_next(cx) {
+            Poll::Ready(Some(Ok(data))) => {
+                self.received += data.len() as u64;
+                Poll::Ready(Some(Ok(data)))
+            }
+            Poll::Ready(None) => {
+                self.done = true;
+                if self.received < self.expected {
+                    Poll::Ready(Some(Err(error::connection_closed(Some(&format!(
+                        "Expected {} bytes but only received {}",
+                        self.expected, self.received
+                    ))))))
+                } else {
+                    Poll::Ready(None)
+                }
+            }
+            other => other,
+        }
+    }
+}
+
+/// Limits a data stream to a range of bytes.
+pub(crate) struct RangeStream {
+    stream: DataStream,
+    skip: u64,
+    remaining: u64,
+}
+
+impl RangeStream {
+    /// Skips `skip` bytes of the stream then ends it after `count` more.
+    pub fn new(stream: DataStream, skip: u64, count: u64) -> RangeStream {
+        RangeStream {
+            stream,
+            skip,
+            remaining: count,
+        }
+    }
+}
+
+/// Limits the listing that a future resolves to to just the directories.
+pub(crate) fn directories_only(future: ObjectStreamFuture) -> ObjectStreamFuture {
+    ObjectStreamFuture::from_future(future.map_ok(|stream| {
+        ObjectStream::from_stream(
+            stream.try_filter(|object| ready(object.object_type() == ObjectType::Directory)),
+        )
+    }))
+}
+
+/// Streams the sources, in order, into the target.
+///
+/// Sources that are also the target are opened before the write starts so
+/// that writing the target cannot clear them before they are read. The rest
+/// are only opened once the previous source has been read.
+pub(crate) fn compose_streams<O, W>(
+    sources: Vec<ObjectPath>,
+    target: UploadInfo,
+    open: O,
+    write: W,
+) -> CopyCompleteFuture
+where
+    O: Fn(ObjectPath) -> DataStreamFuture,
+    W: FnOnce(UploadInfo, DataStream) -> CopyCompleteFuture,
+{
+    let mut early = Vec::new();
+    let mut streams = Vec::new();
+    for path in sources {
+        if path == target.path {
+            let (sender, receiver) = oneshot::channel();
+            early.push((open(path), sender));
+            streams.push(DataStreamFuture::from_future(receiver.map(|result| {
+                result.unwrap_or_else(|_| Err(error::cancelled(None)))
+            })));
+        } else {
+            streams.push(open(path));
+        }
+    }
+
+    let stream = iter(streams).then(|stream| stream).try_flatten();
+    let write = write(target, DataStream::from_stream(stream));
+    if early.is_empty() {
+        return write;
+    }
+
+    CopyCompleteFuture::from_future(async move {
+        for (opening, sender) in early {
+            let stream = opening.await.map_err(TransferError::SourceError)?;
+            let _ = sender.send(Ok(stream));
+        }
+
+        write.await
+    })
+}
+
+/// Limits the stream that a future resolves to to the bytes within `range`.
+pub(crate) fn range_stream(future: DataStreamFuture, range: Range<u64>) -> DataStreamFuture {
+    let count = range.end.saturating_sub(range.start);
+    DataStreamFuture::from_future(future.map_ok(move |stream| {
+        DataStream::from_stream(RangeStream::new(stream, range.start, count))
+    }))
+}
+
+impl Stream for RangeStream {
+    type Item = StorageResult<Data>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> ResultStreamPoll<Data> {
+        loop {
+            if self.remaining == 0 {
+                return Poll::Ready(None);
+            }
+
+            let mut data = match self.stream.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(data))) => data,
+                other => return other,
+            };
+
+            let len = data.len() as u64;
+            if self.skip >= len {
+                self.skip -= len;
+                continue;
+            }
+
+            if self.skip > 0 {
+                data.advance(self.skip as usize);
+                self.skip = 0;
+            }
+
+            if data.len() as u64 > self.remaining {
+                data.truncate(self.remaining as usize);
+            }
+            self.remaining -= data.len() as u64;
+
+            return Poll::Ready(Some(Ok(data)));
+        }
+    }
+}
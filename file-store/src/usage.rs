@@ -0,0 +1,123 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Summarizing how much storage is used beneath a directory.
+//!
+//! [`FileStore::summarize`](../enum.FileStore.html#method.summarize) works
+//! like `du`, totalling the number of files and their sizes for a directory
+//! and each of its subdirectories down to a given depth. Everything is
+//! computed from a single listing of the directory:
+//!
+//! ```no_run
+//! # use file_store::FileStore;
+//! # async fn example(store: FileStore) {
+//! for usage in store.summarize("photos", 1).await.unwrap() {
+//!     println!("{:>12} {:>6} {}", usage.bytes, usage.files, usage.path);
+//! }
+//! # }
+//! ```
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+
+use futures::stream::StreamExt;
+
+use crate::sync::directory;
+use crate::types::*;
+use crate::{FileStore, ObjectInfo, StorageBackend};
+
+/// Future returned by [`FileStore::summarize`](../enum.FileStore.html#method.summarize).
+pub type SummaryFuture = WrappedFuture<StorageResult<Vec<DirectoryUsage>>>;
+
+/// The storage used beneath a directory.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DirectoryUsage {
+    /// The directory, relative to the summarized directory which itself has
+    /// an empty path.
+    pub path: ObjectPath,
+    /// How many directories deep this directory is, the summarized directory
+    /// is at depth 0.
+    pub depth: usize,
+    /// The number of files beneath the directory, including those in all of
+    /// its subdirectories.
+    pub files: u64,
+    /// The total size of the files beneath the directory.
+    pub bytes: u64,
+}
+
+impl FileStore {
+    /// Totals the files beneath `prefix` for it and each subdirectory down to
+    /// `depth` directories deep, resolving to one entry per directory sorted
+    /// by path. Files in deeper directories are included in the totals of
+    /// their ancestors. A depth of 0 only totals `prefix` itself.
+    ///
+    /// See the [`usage`](usage/index.html) module.
+    pub fn summarize<P>(&self, prefix: P, depth: usize) -> SummaryFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        async fn run(
+            store: FileStore,
+            prefix: ObjectPath,
+            depth: usize,
+        ) -> StorageResult<Vec<DirectoryUsage>> {
+            let skip = prefix.parts().len();
+            let mut listing = prefix.clone();
+            if !listing.is_empty() {
+                listing.push_part("");
+            }
+
+            let mut totals: BTreeMap<Vec<String>, (u64, u64)> = BTreeMap::new();
+            totals.insert(Vec::new(), (0, 0));
+
+            let mut objects = store.list_objects(listing).await?;
+            while let Some(object) = objects.next().await {
+                let object = object?;
+                if object.object_type() != ObjectType::File {
+                    continue;
+                }
+
+                // Directories that contain the file, excluding the file itself.
+                let path = object.path();
+                let parts = path.parts();
+                let dirs: Vec<String> = parts[skip..parts.len() - 1]
+                    .iter()
+                    .map(|part| (*part).to_owned())
+                    .collect();
+                for end in 0..=dirs.len().min(depth) {
+                    let total = totals.entry(dirs[..end].to_vec()).or_insert((0, 0));
+                    total.0 += 1;
+                    total.1 += object.len();
+                }
+            }
+
+            totals
+                .into_iter()
+                .map(|(parts, (files, bytes))| {
+                    Ok(DirectoryUsage {
+                        path: ObjectPath::new(parts.join("/"))?,
+                        depth: parts.len(),
+                        files,
+                        bytes,
+                    })
+                })
+                .collect()
+        }
+
+        match prefix.try_into().map_err(Into::into).and_then(directory) {
+            Ok(prefix) => SummaryFuture::from_future(run(self.clone(), prefix, depth)),
+            Err(e) => SummaryFuture::from_value(Err(e)),
+        }
+    }
+}
@@ -14,7 +14,8 @@
 
 //! A set of useful utilities for converting between the different asynchronous
 //! types that this crate uses.
-use std::convert::Infallible;
+use std::any::Any;
+use std::convert::{Infallible, TryInto};
 use std::fmt;
 use std::future::Future;
 use std::io;
@@ -25,12 +26,22 @@ use std::task::{Context, Poll, Waker};
 
 use bytes::buf::FromBuf;
 use bytes::{BytesMut, IntoBuf};
-use futures::future::FutureExt;
-use futures::stream::{Stream, StreamExt};
+use futures::channel::mpsc;
+use futures::executor::block_on;
+use futures::future::{select, Either, FutureExt};
+use futures::pin_mut;
+use futures::sink::SinkExt;
+use futures::stream::{unfold, Stream, StreamExt};
+#[cfg(feature = "codec")]
+use tokio_codec::Encoder;
+use tokio_executor::blocking;
 use tokio_io::{AsyncRead, BufReader};
 
 use crate::future::WrappedFuture;
-use crate::types::{Data, StorageError};
+#[cfg(feature = "codec")]
+use crate::types::error;
+use crate::types::{Data, DataStream, ObjectPath, StorageError, UploadInfo, WriteCompleteFuture};
+use crate::{FileStore, StorageBackend};
 
 /// Converts an AsyncRead into a stream that emits [`Data`](../type.Data.html).
 pub struct ReaderStream<R>
@@ -63,34 +74,23 @@ where
     where
         T: AsyncRead + Send + 'static,
     {
-        let buf_reader = BufReader::new(reader);
-
-        let mut buffer = BytesMut::with_capacity(initial_buffer_size);
-        unsafe {
-            buffer.set_len(initial_buffer_size);
-            buf_reader.prepare_uninitialized_buffer(&mut buffer);
-        }
-
         ReaderStream {
-            reader: Box::pin(buf_reader),
-            buffer,
+            reader: Box::pin(BufReader::new(reader)),
+            buffer: BytesMut::with_capacity(initial_buffer_size),
             initial_buffer_size,
             minimum_buffer_size,
         }
     }
 
     fn inner_poll(&mut self, cx: &mut Context) -> Poll<Option<io::Result<Data>>> {
-        match self.reader.as_mut().poll_read(cx, &mut self.buffer) {
+        // The buffer is always empty here, reading fills its spare capacity.
+        match self.reader.as_mut().poll_read_buf(cx, &mut self.buffer) {
             Poll::Ready(Ok(0)) => Poll::Ready(None),
-            Poll::Ready(Ok(size)) => {
-                let data = self.buffer.split_to(size);
+            Poll::Ready(Ok(_)) => {
+                let data = self.buffer.take();
 
-                if self.buffer.len() < self.minimum_buffer_size {
+                if self.buffer.capacity() < self.minimum_buffer_size.max(1) {
                     self.buffer = BytesMut::with_capacity(self.initial_buffer_size);
-                    unsafe {
-                        self.buffer.set_len(self.initial_buffer_size);
-                        self.reader.prepare_uninitialized_buffer(&mut self.buffer);
-                    }
                 }
 
                 Poll::Ready(Some(Ok(data.freeze())))
@@ -112,18 +112,273 @@ where
     }
 }
 
+/// Converts a stream of [`Data`](../type.Data.html) into an `AsyncRead`.
+///
+/// This allows wrapping a [`DataStream`](../type.DataStream.html) in a
+/// `FramedRead` to decode the contents of a file with a codec.
+pub struct StreamReader<S> {
+    stream: S,
+    current: Data,
+}
+
+impl<S, E> StreamReader<S>
+where
+    S: Stream<Item = Result<Data, E>> + Unpin,
+    E: Into<StorageError>,
+{
+    /// Creates a reader that returns the data from the stream.
+    pub fn new(stream: S) -> StreamReader<S> {
+        StreamReader {
+            stream,
+            current: Data::new(),
+        }
+    }
+}
+
+impl<S, E> AsyncRead for StreamReader<S>
+where
+    S: Stream<Item = Result<Data, E>> + Unpin,
+    E: Into<StorageError>,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        while self.current.is_empty() {
+            match self.stream.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(data))) => self.current = data,
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(e.into().into())),
+                Poll::Ready(None) => return Poll::Ready(Ok(0)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        let count = buf.len().min(self.current.len());
+        buf[..count].copy_from_slice(&self.current.split_to(count));
+        Poll::Ready(Ok(count))
+    }
+}
+
+/// Encodes a stream of items with a codec generating a stream of
+/// [`Data`](../type.Data.html).
+///
+/// The result can be passed to [`write_file_from_stream`](../trait.StorageBackend.html#tymethod.write_file_from_stream).
+/// Any error from the encoder will be returned as an
+/// [`InvalidData`](../enum.StorageErrorKind.html#variant.InvalidData) error.
+///
+/// Included with the "codec" feature.
+#[cfg(feature = "codec")]
+pub fn encode_stream<S, C>(
+    stream: S,
+    mut encoder: C,
+) -> impl Stream<Item = Result<Data, StorageError>>
+where
+    S: Stream<Item = C::Item> + Send + 'static,
+    C: Encoder + Send + 'static,
+    C::Error: fmt::Display,
+{
+    stream.map(move |item| {
+        let mut buffer = BytesMut::new();
+        match encoder.encode(item, &mut buffer) {
+            Ok(()) => Ok(buffer.freeze()),
+            Err(e) => Err(error::invalid_data(Some(&e.to_string()))),
+        }
+    })
+}
+
+// The size of the chunks read from a reader when writing a file.
+const WRITE_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Reads a reader in chunks.
+pub(crate) struct ChunkReader<R>
+where
+    R: io::Read,
+{
+    reader: R,
+    done: bool,
+}
+
+impl<R> ChunkReader<R>
+where
+    R: io::Read,
+{
+    pub fn new(reader: R) -> ChunkReader<R> {
+        ChunkReader {
+            reader,
+            done: false,
+        }
+    }
+}
+
+impl<R> Iterator for ChunkReader<R>
+where
+    R: io::Read,
+{
+    type Item = io::Result<Data>;
+
+    fn next(&mut self) -> Option<io::Result<Data>> {
+        if self.done {
+            return None;
+        }
+
+        let mut buffer = vec![0; WRITE_CHUNK_SIZE];
+        loop {
+            match self.reader.read(&mut buffer) {
+                Ok(0) => {
+                    self.done = true;
+                    return None;
+                }
+                Ok(count) => {
+                    buffer.truncate(count);
+                    return Some(Ok(Data::from(buffer)));
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+/// Converts a blocking iterator of [`Data`](../type.Data.html) into a
+/// [`DataStream`](../type.DataStream.html).
+///
+/// Each chunk is pulled from the iterator on the runtime's blocking thread
+/// pool so the iterator may block for as long as it needs to without tying up
+/// the executor. Chunks are only read as the stream is polled and the
+/// iterator is dropped along with the stream. Any error from the iterator is
+/// passed on as a [`StorageError`](../struct.StorageError.html).
+pub fn blocking_stream<I>(iter: I) -> DataStream
+where
+    I: IntoIterator<Item = io::Result<Data>>,
+    I::IntoIter: Send + 'static,
+{
+    DataStream::from_stream(unfold(iter.into_iter(), |mut iter| async move {
+        let (item, iter) = blocking::run(move || (iter.next(), iter)).await;
+        item.map(|item| (item.map_err(StorageError::from), iter))
+    }))
+}
+
+/// Converts a blocking `Read` into a [`DataStream`](../type.DataStream.html).
+///
+/// The reader is read in chunks of up to 1MB on the blocking thread pool, see
+/// [`blocking_stream`](fn.blocking_stream.html).
+pub fn read_stream<R>(reader: R) -> DataStream
+where
+    R: io::Read + Send + 'static,
+{
+    blocking_stream(ChunkReader::new(reader))
+}
+
+impl FileStore {
+    /// Writes the data from a blocking iterator to the file at the given path.
+    ///
+    /// See [`blocking_stream`](utils/fn.blocking_stream.html) and
+    /// [`write_file_from_stream`](trait.StorageBackend.html#tymethod.write_file_from_stream).
+    pub fn write_file_from_iter<P, I>(&self, info: P, iter: I) -> WriteCompleteFuture
+    where
+        P: TryInto<UploadInfo>,
+        P::Error: Into<StorageError>,
+        I: IntoIterator<Item = io::Result<Data>>,
+        I::IntoIter: Send + 'static,
+    {
+        self.write_file_from_stream(info, blocking_stream(iter))
+    }
+
+    /// Writes the contents of a blocking reader to the file at the given path.
+    ///
+    /// See [`read_stream`](utils/fn.read_stream.html) and
+    /// [`write_file_from_stream`](trait.StorageBackend.html#tymethod.write_file_from_stream).
+    pub fn write_file_from_reader<P, R>(&self, info: P, reader: R) -> WriteCompleteFuture
+    where
+        P: TryInto<UploadInfo>,
+        P::Error: Into<StorageError>,
+        R: io::Read + Send + 'static,
+    {
+        self.write_file_from_stream(info, read_stream(reader))
+    }
+}
+
+/// Converts a buffer into [`Data`](../type.Data.html).
+///
+/// `Data`, `BytesMut` and `Vec<u8>` are converted without copying, anything
+/// else is copied into a new buffer.
+pub(crate) fn into_data<I>(buf: I) -> Data
+where
+    I: IntoBuf + 'static,
+{
+    let mut slot = Some(buf);
+    let any = &mut slot as &mut dyn Any;
+
+    if let Some(data) = any.downcast_mut::<Option<Data>>() {
+        return data.take().unwrap();
+    }
+
+    if let Some(data) = any.downcast_mut::<Option<BytesMut>>() {
+        return data.take().unwrap().freeze();
+    }
+
+    if let Some(data) = any.downcast_mut::<Option<Vec<u8>>>() {
+        return Data::from(data.take().unwrap());
+    }
+
+    Data::from_buf(slot.take().unwrap())
+}
+
 pub(crate) fn into_data_stream<S, I, E>(stream: S) -> impl Stream<Item = Result<Data, StorageError>>
 where
     S: Stream<Item = Result<I, E>> + Send + 'static,
-    I: IntoBuf,
+    I: IntoBuf + 'static,
     E: Into<StorageError>,
 {
     stream.map(|r| match r {
-        Ok(d) => Ok(Data::from_buf(d)),
+        Ok(d) => Ok(into_data(d)),
         Err(e) => Err(e.into()),
     })
 }
 
+/// The number of chunks read ahead of a write when not set in the
+/// [`WriteOptions`](../struct.WriteOptions.html).
+pub(crate) const DEFAULT_WRITE_BUFFER_DEPTH: usize = 4;
+
+/// Passes a stream to `consumer` through a buffer of up to `depth` items.
+///
+/// The source stream continues to be polled while the consumer is busy so a
+/// slow consumer doesn't stop the source from making progress until the buffer
+/// is full. If the consumer completes before the source the rest of the source
+/// is dropped.
+pub(crate) async fn buffered<S, F, Fut>(source: S, depth: usize, consumer: F) -> Fut::Output
+where
+    S: Stream + Send,
+    F: FnOnce(mpsc::Receiver<S::Item>) -> Fut,
+    Fut: Future,
+{
+    let (mut sender, receiver) = mpsc::channel(depth);
+    let producer = async move {
+        pin_mut!(source);
+        while let Some(item) = source.next().await {
+            if sender.send(item).await.is_err() {
+                // The consumer has gone away.
+                break;
+            }
+        }
+    };
+
+    let consumer = consumer(receiver);
+    pin_mut!(producer, consumer);
+    match select(consumer, producer).await {
+        Either::Left((result, _)) => result,
+        Either::Right(((), consumer)) => consumer.await,
+    }
+}
+
 struct PoolState<C, T, E>
 where
     C: fmt::Debug,
@@ -437,3 +692,160 @@ where
         self.inner.acquire().await
     }
 }
+
+/// Limits the number of operations that can happen at once.
+///
+/// Each operation holds a [`Permit`](struct.Permit.html) while it runs. Once
+/// the limit is reached [`acquire`](#method.acquire) waits until an existing
+/// permit is dropped. Clones share the same limit.
+#[derive(Debug, Clone)]
+pub struct Limiter {
+    pool: InfalliblePool<(), ()>,
+}
+
+impl Limiter {
+    /// Creates a limiter allowing up to `limit` permits at once. A limit of
+    /// zero is treated as one.
+    pub fn new(limit: usize) -> Limiter {
+        Limiter::with_limit(Some(limit.max(1)))
+    }
+
+    /// Creates a limiter that never waits.
+    pub fn unlimited() -> Limiter {
+        Limiter::with_limit(None)
+    }
+
+    fn with_limit(limit: Option<usize>) -> Limiter {
+        Limiter {
+            pool: InfalliblePool::new((), limit, |_| WrappedFuture::from_value(())),
+        }
+    }
+
+    /// Waits for a permit to become available.
+    pub async fn acquire(&self) -> Permit {
+        Permit {
+            _inner: self.pool.acquire().await,
+        }
+    }
+}
+
+/// Allows an operation limited by a [`Limiter`](struct.Limiter.html) to run.
+///
+/// The permit is returned to the limiter when dropped.
+pub struct Permit {
+    _inner: Acquired<(), (), Infallible>,
+}
+
+impl fmt::Debug for Permit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Permit").finish()
+    }
+}
+
+/// Content types for common file extensions.
+const CONTENT_TYPES: &[(&str, &str)] = &[
+    ("css", "text/css"),
+    ("gif", "image/gif"),
+    ("htm", "text/html"),
+    ("html", "text/html"),
+    ("ico", "image/x-icon"),
+    ("jpeg", "image/jpeg"),
+    ("jpg", "image/jpeg"),
+    ("js", "application/javascript"),
+    ("json", "application/json"),
+    ("mp4", "video/mp4"),
+    ("pdf", "application/pdf"),
+    ("png", "image/png"),
+    ("svg", "image/svg+xml"),
+    ("txt", "text/plain"),
+    ("wasm", "application/wasm"),
+    ("webp", "image/webp"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("xml", "application/xml"),
+    ("zip", "application/zip"),
+];
+
+/// Guesses the content type of a file from its extension.
+pub(crate) fn content_type(path: &ObjectPath) -> &'static str {
+    let name = path.parts().last().cloned().unwrap_or("");
+    if let Some(pos) = name.rfind('.') {
+        let extension = name[pos + 1..].to_lowercase();
+        for &(ext, mime) in CONTENT_TYPES {
+            if extension == ext {
+                return mime;
+            }
+        }
+    }
+
+    "application/octet-stream"
+}
+
+/// Calculates the hex encoded SHA-1 hash of a file.
+#[cfg(any(feature = "backup", feature = "index"))]
+pub(crate) async fn hash<S>(store: &S, path: ObjectPath) -> crate::StorageResult<String>
+where
+    S: crate::StorageBackend,
+{
+    let mut stream = store.get_file_stream(path).await?;
+    let mut hasher = sha1::Sha1::new();
+    while let Some(data) = stream.next().await {
+        hasher.update(&data?);
+    }
+    Ok(hasher.hexdigest())
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future::poll_fn;
+    use futures::stream::iter;
+
+    use super::*;
+    use crate::types::error;
+
+    fn reader(chunks: Vec<Result<&'static str, StorageError>>) -> impl AsyncRead + Unpin {
+        StreamReader::new(iter(
+            chunks
+                .into_iter()
+                .map(|chunk| chunk.map(|s| Data::from_static(s.as_bytes()))),
+        ))
+    }
+
+    fn read<R: AsyncRead + Unpin>(reader: &mut R, size: usize) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0; size];
+        let count = block_on(poll_fn(|cx| Pin::new(&mut *reader).poll_read(cx, &mut buf)))?;
+        buf.truncate(count);
+        Ok(buf)
+    }
+
+    #[test]
+    fn test_stream_reader_chunks() {
+        let mut r = reader(vec![Ok("abc"), Ok("defgh"), Ok(""), Ok("ij")]);
+
+        // Reads never span chunks and empty chunks are skipped.
+        assert_eq!(read(&mut r, 4).unwrap(), b"abc");
+        assert_eq!(read(&mut r, 4).unwrap(), b"defg");
+        assert_eq!(read(&mut r, 0).unwrap(), b"");
+        assert_eq!(read(&mut r, 4).unwrap(), b"h");
+        assert_eq!(read(&mut r, 1).unwrap(), b"i");
+        assert_eq!(read(&mut r, 4).unwrap(), b"j");
+        assert_eq!(read(&mut r, 4).unwrap(), b"");
+        assert_eq!(read(&mut r, 4).unwrap(), b"");
+    }
+
+    #[test]
+    fn test_stream_reader_error() {
+        let mut r = reader(vec![
+            Ok("abc"),
+            Err(error::invalid_data(Some("Broken."))),
+            Ok("def"),
+        ]);
+
+        assert_eq!(read(&mut r, 2).unwrap(), b"ab");
+        // Buffered data is returned before the error.
+        assert_eq!(read(&mut r, 2).unwrap(), b"c");
+        let error = read(&mut r, 2).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(read(&mut r, 2).unwrap(), b"de");
+    }
+}
@@ -0,0 +1,489 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Walking the files beneath a directory with filters.
+//!
+//! A [`Walk`](struct.Walk.html) lists a directory and its descendants one
+//! directory at a time so that directories excluded by a filter or deeper than
+//! the maximum depth are never listed. Files are yielded as they are found.
+//!
+//! Paths are matched against [`Glob`](struct.Glob.html) patterns relative to
+//! the walked directory. In a pattern `?` matches any character other than
+//! `/`, `*` matches any number of characters other than `/`, `**` as a whole
+//! path part matches any number of directories, `[abc]`, `[a-z]` and `[!abc]`
+//! match one character from (or not from) a set and `\` matches the next
+//! character literally.
+//!
+//! [`FileStore::copy_matching`](../enum.FileStore.html#method.copy_matching)
+//! copies the files matching a glob to another store.
+use std::convert::TryInto;
+use std::time::SystemTime;
+
+use futures::future::TryFutureExt;
+use futures::stream::{iter, unfold, StreamExt, TryStreamExt};
+
+use crate::sync::directory;
+use crate::types::*;
+use crate::{FileStore, ObjectInfo, StorageBackend};
+
+/// Future returned by [`FileStore::copy_matching`](../enum.FileStore.html#method.copy_matching).
+pub type CopyMatchingFuture = WrappedFuture<Result<Vec<ObjectPath>, TransferError>>;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Literal(char),
+    Any,
+    Star,
+    /// `**/`, matching any number of directories.
+    AnyDirs,
+    /// A trailing `**`, matching everything.
+    AnyPath,
+    Class(bool, Vec<(char, char)>),
+}
+
+/// A pattern matching paths.
+///
+/// See the [`walk`](index.html) module for the syntax.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Glob {
+    pattern: String,
+    tokens: Vec<Token>,
+}
+
+fn parse_class(chars: &[char], start: usize) -> Option<(Token, usize)> {
+    let mut pos = start;
+    let negated = chars.get(pos) == Some(&'!');
+    if negated {
+        pos += 1;
+    }
+
+    let mut ranges = Vec::new();
+    loop {
+        let c = *chars.get(pos)?;
+        // A `]` first in the class is part of the set.
+        if c == ']' && !ranges.is_empty() {
+            return Some((Token::Class(negated, ranges), pos + 1));
+        }
+
+        if chars.get(pos + 1) == Some(&'-') && chars.get(pos + 2).map_or(false, |c| *c != ']') {
+            ranges.push((c, chars[pos + 2]));
+            pos += 3;
+        } else {
+            ranges.push((c, c));
+            pos += 1;
+        }
+    }
+}
+
+impl Glob {
+    /// Parses a pattern.
+    pub fn new(pattern: &str) -> StorageResult<Glob> {
+        let chars: Vec<char> = pattern.chars().collect();
+        let mut tokens = Vec::new();
+        let mut pos = 0;
+
+        while pos < chars.len() {
+            match chars[pos] {
+                '*' if chars.get(pos + 1) == Some(&'*') && (pos == 0 || chars[pos - 1] == '/') => {
+                    match chars.get(pos + 2) {
+                        Some('/') => {
+                            tokens.push(Token::AnyDirs);
+                            pos += 3;
+                        }
+                        None => {
+                            tokens.push(Token::AnyPath);
+                            pos += 2;
+                        }
+                        _ => {
+                            tokens.push(Token::Star);
+                            pos += 2;
+                        }
+                    }
+                }
+                '*' => {
+                    tokens.push(Token::Star);
+                    pos += 1;
+                }
+                '?' => {
+                    tokens.push(Token::Any);
+                    pos += 1;
+                }
+                '[' => match parse_class(&chars, pos + 1) {
+                    Some((token, next)) => {
+                        tokens.push(token);
+                        pos = next;
+                    }
+                    None => {
+                        return Err(error::invalid_settings(Some(&format!(
+                            "The pattern '{}' contains an unterminated character class.",
+                            pattern
+                        ))))
+                    }
+                },
+                '\\' => match chars.get(pos + 1) {
+                    Some(c) => {
+                        tokens.push(Token::Literal(*c));
+                        pos += 2;
+                    }
+                    None => {
+                        return Err(error::invalid_settings(Some(&format!(
+                            "The pattern '{}' ends with an escape character.",
+                            pattern
+                        ))))
+                    }
+                },
+                c => {
+                    tokens.push(Token::Literal(c));
+                    pos += 1;
+                }
+            }
+        }
+
+        Ok(Glob {
+            pattern: pattern.to_owned(),
+            tokens,
+        })
+    }
+
+    /// The pattern this glob was parsed from.
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    /// Checks whether a path matches the pattern.
+    pub fn matches(&self, path: &str) -> bool {
+        let chars: Vec<char> = path.chars().collect();
+        matches(&self.tokens, &chars)
+    }
+}
+
+fn matches(tokens: &[Token], text: &[char]) -> bool {
+    let token = match tokens.first() {
+        Some(token) => token,
+        None => return text.is_empty(),
+    };
+    let rest = &tokens[1..];
+
+    match token {
+        Token::Literal(c) => text.first() == Some(c) && matches(rest, &text[1..]),
+        Token::Any => text.first().map_or(false, |c| *c != '/') && matches(rest, &text[1..]),
+        Token::Class(negated, ranges) => match text.first() {
+            Some('/') | None => false,
+            Some(c) => {
+                let found = ranges.iter().any(|(low, high)| low <= c && c <= high);
+                found != *negated && matches(rest, &text[1..])
+            }
+        },
+        Token::Star => {
+            for i in 0..=text.len() {
+                if matches(rest, &text[i..]) {
+                    return true;
+                }
+                if i < text.len() && text[i] == '/' {
+                    break;
+                }
+            }
+            false
+        }
+        Token::AnyDirs => {
+            if matches(rest, text) {
+                return true;
+            }
+            (0..text.len()).any(|i| text[i] == '/' && matches(rest, &text[i + 1..]))
+        }
+        Token::AnyPath => true,
+    }
+}
+
+/// Lists the files beneath a directory that pass a set of filters.
+///
+/// See the [`walk`](index.html) module.
+#[derive(Clone, Debug)]
+pub struct Walk {
+    store: FileStore,
+    root: ObjectPath,
+    max_depth: Option<usize>,
+    include: Vec<Glob>,
+    exclude: Vec<Glob>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    modified_since: Option<SystemTime>,
+}
+
+impl Walk {
+    /// Creates a walk over every file beneath `root`.
+    pub fn new(store: FileStore, root: ObjectPath) -> Walk {
+        Walk {
+            store,
+            root,
+            max_depth: None,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            min_size: None,
+            max_size: None,
+            modified_since: None,
+        }
+    }
+
+    /// Only descends `depth` levels. The files directly inside the root are
+    /// at depth 1 so a depth of 0 yields nothing.
+    pub fn max_depth(mut self, depth: usize) -> Walk {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    /// Only yields files whose relative path matches the glob. When called
+    /// more than once files matching any of the globs are yielded.
+    pub fn include(mut self, glob: Glob) -> Walk {
+        self.include.push(glob);
+        self
+    }
+
+    /// Skips files and directories whose relative path matches the glob.
+    /// Excluded directories are not listed.
+    pub fn exclude(mut self, glob: Glob) -> Walk {
+        self.exclude.push(glob);
+        self
+    }
+
+    /// Only yields files of at least `size` bytes.
+    pub fn min_size(mut self, size: u64) -> Walk {
+        self.min_size = Some(size);
+        self
+    }
+
+    /// Only yields files of at most `size` bytes.
+    pub fn max_size(mut self, size: u64) -> Walk {
+        self.max_size = Some(size);
+        self
+    }
+
+    /// Only yields files modified at or after `time`. Files whose
+    /// modification time is unknown are skipped.
+    pub fn modified_since(mut self, time: SystemTime) -> Walk {
+        self.modified_since = Some(time);
+        self
+    }
+
+    /// Starts the walk, returning a stream of the files found.
+    pub fn objects(&self) -> ObjectStream {
+        let root = match directory(self.root.clone()) {
+            Ok(root) => root,
+            Err(e) => return ObjectStream::from_stream(iter(vec![Err(e)])),
+        };
+
+        let state = WalkState {
+            skip: root.parts().len(),
+            walk: self.clone(),
+            pending: vec![(root, 0)],
+            current: None,
+        };
+
+        ObjectStream::from_stream(unfold(state, |mut state| async move {
+            loop {
+                let (stream, depth) = match state.current {
+                    Some((ref mut stream, depth)) => (stream, depth),
+                    None => {
+                        let (dir, depth) = state.pending.pop()?;
+                        match state.walk.store.list_directory(dir).await {
+                            Ok(stream) => state.current = Some((stream, depth + 1)),
+                            Err(e) => return Some((Err(e), state)),
+                        }
+                        continue;
+                    }
+                };
+
+                let object = match stream.try_next().await {
+                    Ok(Some(object)) => object,
+                    Ok(None) => {
+                        state.current = None;
+                        continue;
+                    }
+                    Err(e) => return Some((Err(e), state)),
+                };
+
+                if let Some(object) = state.visit(object, depth) {
+                    return Some((Ok(object), state));
+                }
+            }
+        }))
+    }
+
+    fn accepts(&self, relative: &str, object: &Object) -> bool {
+        if !self.include.is_empty() && !self.include.iter().any(|g| g.matches(relative)) {
+            return false;
+        }
+
+        let len = object.len();
+        if self.min_size.map_or(false, |min| len < min)
+            || self.max_size.map_or(false, |max| len > max)
+        {
+            return false;
+        }
+
+        match self.modified_since {
+            Some(since) => object.modified().map_or(false, |m| m >= since),
+            None => true,
+        }
+    }
+}
+
+struct WalkState {
+    walk: Walk,
+    /// The number of parts in the root's path.
+    skip: usize,
+    /// Directories still to be listed with their depth.
+    pending: Vec<(ObjectPath, usize)>,
+    /// The listing in progress with the depth of its contents.
+    current: Option<(ObjectStream, usize)>,
+}
+
+impl WalkState {
+    /// Decides what to do with a listed object, returning it if it should be
+    /// yielded.
+    fn visit(&mut self, object: Object, depth: usize) -> Option<Object> {
+        let path = object.path();
+        let parts: Vec<&str> = path.parts().into_iter().filter(|p| !p.is_empty()).collect();
+        if parts.len() <= self.skip {
+            return None;
+        }
+        let relative = parts[self.skip..].join("/");
+
+        if self.walk.exclude.iter().any(|g| g.matches(&relative)) {
+            return None;
+        }
+
+        match object.object_type() {
+            ObjectType::Directory => {
+                if self.walk.max_depth.map_or(true, |max| depth < max) {
+                    self.pending
+                        .push((ObjectPath::new(parts.join("/")).ok()?, depth));
+                }
+                None
+            }
+            ObjectType::File => {
+                if self.walk.max_depth.map_or(false, |max| depth > max)
+                    || !self.walk.accepts(&relative, &object)
+                {
+                    None
+                } else {
+                    Some(object)
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Copies a file to another store, keeping its modification time.
+pub(crate) async fn copy_file(
+    source: FileStore,
+    object: Object,
+    target: FileStore,
+    target_path: ObjectPath,
+) -> Result<(), TransferError> {
+    let mut info = UploadInfo::from(target_path);
+    info.modified = object.modified();
+    info.options.content_length = Some(object.len());
+    let stream = source
+        .get_file_stream(object.path())
+        .await
+        .map_err(TransferError::SourceError)?;
+    target.write_file_from_stream(info, stream).await
+}
+
+impl FileStore {
+    /// Copies the files beneath `source_prefix` whose path relative to it
+    /// matches `glob` to the same relative path beneath `target_prefix` in
+    /// `target`, resolving to the relative paths copied.
+    ///
+    /// Up to `concurrency` files are copied at once. Files are copied while
+    /// the source is still being listed. The first failure stops the copy,
+    /// files already copied are left in place.
+    ///
+    /// See the [`walk`](walk/index.html) module.
+    pub fn copy_matching<P, Q>(
+        &self,
+        source_prefix: P,
+        glob: &Glob,
+        target: &FileStore,
+        target_prefix: Q,
+        concurrency: usize,
+    ) -> CopyMatchingFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+        Q: TryInto<ObjectPath>,
+        Q::Error: Into<StorageError>,
+    {
+        async fn run(
+            source: FileStore,
+            walk: Walk,
+            skip: usize,
+            target: FileStore,
+            target_prefix: ObjectPath,
+            concurrency: usize,
+        ) -> Result<Vec<ObjectPath>, TransferError> {
+            let tasks = walk.objects().map(move |result| {
+                let source = source.clone();
+                let target = target.clone();
+                let target_prefix = target_prefix.clone();
+                async move {
+                    let object = result.map_err(TransferError::SourceError)?;
+                    let relative = ObjectPath::new(object.path().parts()[skip..].join("/"))
+                        .map_err(TransferError::SourceError)?;
+                    copy_file(source, object, target, target_prefix.join(&relative))
+                        .map_ok(move |()| relative)
+                        .await
+                }
+            });
+
+            let mut copied: Vec<ObjectPath> = tasks
+                .buffer_unordered(concurrency.max(1))
+                .try_collect()
+                .await?;
+            copied.sort();
+            Ok(copied)
+        }
+
+        let source_prefix = match source_prefix
+            .try_into()
+            .map_err(Into::into)
+            .and_then(directory)
+        {
+            Ok(p) => p,
+            Err(e) => return CopyMatchingFuture::from_value(Err(TransferError::SourceError(e))),
+        };
+
+        let target_prefix = match target_prefix
+            .try_into()
+            .map_err(Into::into)
+            .and_then(directory)
+        {
+            Ok(p) => p,
+            Err(e) => return CopyMatchingFuture::from_value(Err(TransferError::TargetError(e))),
+        };
+
+        let skip = source_prefix.parts().len();
+        let walk = Walk::new(self.clone(), source_prefix).include(glob.clone());
+        CopyMatchingFuture::from_future(run(
+            self.clone(),
+            walk,
+            skip,
+            target.clone(),
+            target_prefix,
+            concurrency,
+        ))
+    }
+}
@@ -81,3 +81,1295 @@ mod retries {
 
     build_tests!("test1", Backend::B2, build_fs, cleanup);
 }
+
+mod lazy {
+    use futures::channel::oneshot::Sender;
+
+    use file_store::backends::b2::B2Backend;
+    use file_store::backends::Backend;
+    use file_store::{FileStore, StorageBackend};
+
+    use crate::mocks::b2_server::start_server;
+    use crate::runner::{TestContext, TestError, TestResult};
+
+    async fn build_fs(context: &TestContext) -> TestResult<(FileStore, Sender<()>)> {
+        let (addr, sender) = start_server(context.get_fs_root(), 20000)?;
+
+        let fs = B2Backend::builder("foo", "bar")
+            .host(&format!("http://{}", addr))
+            .limit_small_file_size(20 * 1024 * 1024)
+            .limit_requests(5)
+            .lazy_authentication(true)
+            .connect()
+            .await?;
+        fs.authorize().await?;
+        Ok((fs, sender))
+    }
+
+    async fn cleanup(sender: Sender<()>) -> TestResult<()> {
+        sender.send(()).map_err(|()| {
+            TestError::HarnessFailure(String::from("Failed to send shutdown to mock b2 server."))
+        })
+    }
+
+    build_tests!("test1", Backend::B2, build_fs, cleanup);
+}
+
+mod failures {
+    use std::time::Duration;
+
+    use futures::channel::oneshot::Sender;
+
+    use file_store::backends::b2::B2Backend;
+    use file_store::backends::Backend;
+    use file_store::FileStore;
+
+    use crate::mocks::b2_server::start_failing_server;
+    use crate::runner::faults::{Fault, FaultSchedule, VirtualClock};
+    use crate::runner::{TestContext, TestError, TestResult};
+
+    async fn build_fs(context: &TestContext) -> TestResult<(FileStore, Sender<()>)> {
+        let (addr, sender) = start_failing_server(
+            context.get_fs_root(),
+            20000,
+            FaultSchedule::Every(7, Fault::Unavailable(Duration::from_secs(0))),
+            VirtualClock::new(),
+        )?;
+
+        let fs = B2Backend::builder("foo", "bar")
+            .host(&format!("http://{}", addr))
+            .limit_small_file_size(20 * 1024 * 1024)
+            .limit_requests(5)
+            .connect()
+            .await?;
+        Ok((fs, sender))
+    }
+
+    async fn cleanup(sender: Sender<()>) -> TestResult<()> {
+        sender.send(()).map_err(|()| {
+            TestError::HarnessFailure(String::from("Failed to send shutdown to mock b2 server."))
+        })
+    }
+
+    build_tests!("test1", Backend::B2, build_fs, cleanup);
+}
+
+mod cassette {
+    use std::fs::read_to_string;
+
+    use futures::stream::TryStreamExt;
+    use tempfile::tempdir;
+
+    use file_store::backends::b2::B2Backend;
+    use file_store::backends::Backend;
+    use file_store::transport::Cassette;
+    use file_store::{ObjectInfo, StorageBackend};
+
+    use crate::mocks::b2_server::start_server;
+    use crate::runner::{prepare_test, run, TestError, TestResult};
+
+    async fn list(cassette: Cassette, host: &str) -> TestResult<Vec<String>> {
+        let fs = B2Backend::builder("foo", "bar")
+            .host(host)
+            .cassette(cassette)
+            .connect()
+            .await?;
+
+        let mut names: Vec<String> = fs
+            .list_directory("test1/dir1/")
+            .await?
+            .map_ok(|o| o.path().to_string())
+            .try_collect()
+            .await?;
+        names.sort();
+        Ok(names)
+    }
+
+    #[test]
+    fn test_record_replay() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::B2, "test1")?;
+            let temp = tempdir().unwrap();
+            let file = temp.path().join("cassette.json");
+
+            let (addr, sender) = start_server(context.get_fs_root(), 20000)?;
+            let cassette = Cassette::record(&file);
+            let recorded = list(cassette.clone(), &format!("http://{}", addr)).await?;
+            cassette.save()?;
+
+            let contents = read_to_string(&file).unwrap();
+            test_assert!(
+                contents.contains("authorizationToken"),
+                "Should have recorded the authorization."
+            );
+            test_assert_eq!(
+                contents.matches("authorizationToken").count(),
+                contents
+                    .matches(r#"authorizationToken\":\"<redacted>\""#)
+                    .count(),
+                "Should have redacted every token."
+            );
+
+            sender.send(()).map_err(|()| {
+                TestError::HarnessFailure(String::from(
+                    "Failed to send shutdown to mock b2 server.",
+                ))
+            })?;
+
+            // Nothing is listening here so any request that isn't replayed
+            // will fail.
+            let cassette = Cassette::replay(&file)?;
+            let replayed = list(cassette.clone(), &format!("http://{}", addr)).await?;
+
+            test_assert_eq!(replayed, recorded, "Should have replayed the same listing.");
+            test_assert_eq!(
+                cassette.remaining(),
+                0,
+                "Should have replayed every request."
+            );
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+mod capture {
+    use file_store::backends::b2::B2Backend;
+    use file_store::backends::Backend;
+    use file_store::transport::Capture;
+    use file_store::StorageBackend;
+
+    use crate::mocks::b2_server::start_server;
+    use crate::runner::{prepare_test, run, TestResult};
+
+    #[test]
+    fn test_capture() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::B2, "test1")?;
+            let (addr, _sender) = start_server(context.get_fs_root(), 20000)?;
+            let host = format!("http://{}", addr);
+
+            let capture = Capture::new(20);
+            let fs = B2Backend::builder("foo", "bar")
+                .host(&host)
+                .header("X-Api-Key", "secret")
+                .capture(capture.clone())
+                .connect()
+                .await?;
+            fs.get_object("test1/dir1/smallfile.txt").await?;
+
+            let exchanges = capture.exchanges();
+            let authorize = match exchanges
+                .iter()
+                .find(|e| e.uri.ends_with("/b2_authorize_account"))
+            {
+                Some(e) => e,
+                None => test_fail!("Should have captured the authorization."),
+            };
+            test_assert_eq!(authorize.status, Some(200));
+            test_assert!(authorize
+                .response_body
+                .as_ref()
+                .map(|body| body.contains("\"authorizationToken\":\"<redacted>\""))
+                .unwrap_or(false));
+
+            for exchange in exchanges.iter() {
+                for (name, value) in exchange.request_headers.iter() {
+                    if name == "authorization" {
+                        test_assert_eq!(value, "<redacted>");
+                    }
+                }
+                test_assert!(
+                    exchange
+                        .request_headers
+                        .iter()
+                        .any(|(name, value)| name == "x-api-key" && value == "<redacted>"),
+                    "Should have redacted the custom header."
+                );
+            }
+
+            let last = &exchanges[exchanges.len() - 1];
+            test_assert!(last.uri.ends_with("/b2_list_file_versions"));
+            test_assert!(last
+                .response_body
+                .as_ref()
+                .map(|body| body.contains("smallfile.txt"))
+                .unwrap_or(false));
+
+            let capture = Capture::new(1);
+            let fs = B2Backend::builder("foo", "bar")
+                .host(&host)
+                .capture(capture.clone())
+                .connect()
+                .await?;
+            fs.get_object("test1/dir1/smallfile.txt").await?;
+            let exchanges = capture.exchanges();
+            test_assert_eq!(exchanges.len(), 1, "Should only keep the latest exchange.");
+            test_assert!(exchanges[0].uri.ends_with("/b2_list_file_versions"));
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+
+    #[test]
+    fn test_custom_user_agent() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::B2, "test1")?;
+            let (addr, _sender) = start_server(context.get_fs_root(), 20000)?;
+
+            let capture = Capture::new(20);
+            let fs = B2Backend::builder("foo", "bar")
+                .host(&format!("http://{}", addr))
+                .header("User-Agent", "file-store-tests/1.0")
+                .capture(capture.clone())
+                .connect()
+                .await?;
+            fs.get_object("test1/dir1/smallfile.txt").await?;
+
+            for exchange in capture.exchanges() {
+                let agents: Vec<&str> = exchange
+                    .request_headers
+                    .iter()
+                    .filter(|(name, _)| name == "user-agent")
+                    .map(|(_, value)| value.as_str())
+                    .collect();
+                test_assert_eq!(agents, vec!["file-store-tests/1.0"]);
+            }
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+mod outage {
+    use std::time::Duration;
+
+    use file_store::backends::b2::B2Backend;
+    use file_store::backends::Backend;
+    use file_store::{StorageBackend, StorageErrorKind};
+
+    use crate::mocks::b2_server::start_failing_server;
+    use crate::runner::faults::{Fault, FaultSchedule, VirtualClock};
+    use crate::runner::{prepare_test, run, TestError, TestResult};
+
+    #[test]
+    fn test_outage() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::B2, "test1")?;
+            let clock = VirtualClock::new();
+            let (addr, sender) = start_failing_server(
+                context.get_fs_root(),
+                20000,
+                FaultSchedule::Until(
+                    Duration::from_secs(60),
+                    Fault::Unavailable(Duration::from_secs(0)),
+                ),
+                clock.clone(),
+            )?;
+
+            let fs = B2Backend::builder("foo", "bar")
+                .host(&format!("http://{}", addr))
+                .connect()
+                .await?;
+
+            match fs.get_object("test1/dir1/smallfile.txt").await {
+                Err(e) => test_assert_eq!(
+                    e.kind(),
+                    StorageErrorKind::RateLimited,
+                    "Should have given up retrying."
+                ),
+                Ok(_) => test_fail!("Should have failed during the outage."),
+            }
+
+            clock.advance(Duration::from_secs(60));
+            fs.get_object("test1/dir1/smallfile.txt").await?;
+
+            sender.send(()).map_err(|()| {
+                TestError::HarnessFailure(String::from(
+                    "Failed to send shutdown to mock b2 server.",
+                ))
+            })
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+mod encryption {
+    use futures::future::ready;
+    use futures::stream::{iter, TryStreamExt};
+
+    use file_store::backends::b2::B2Backend;
+    use file_store::backends::Backend;
+    use file_store::*;
+
+    use crate::mocks::b2_server::start_server;
+    use crate::runner::{prepare_test, run, TestResult};
+
+    async fn read_all(fs: &FileStore, path: &str, options: ReadOptions) -> StorageResult<Vec<u8>> {
+        fs.get_file_stream_with_options(path, options)
+            .await?
+            .try_fold(Vec::new(), |mut result, data| {
+                result.extend_from_slice(&data);
+                ready(Ok(result))
+            })
+            .await
+    }
+
+    #[test]
+    fn test_customer_key() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::B2, "test1")?;
+            let (addr, _sender) = start_server(context.get_fs_root(), 20000)?;
+            let host = format!("http://{}", addr);
+            let key = CustomerKey::new(&[7u8; 32])?;
+
+            let encrypted = B2Backend::builder("foo", "bar")
+                .host(&host)
+                .encryption(Encryption::Customer(key.clone()))
+                .connect()
+                .await?;
+            let plain = B2Backend::builder("foo", "bar")
+                .host(&host)
+                .connect()
+                .await?;
+
+            encrypted
+                .write_file_from_stream(
+                    "test1/encrypted",
+                    iter(vec![Ok::<_, StorageError>(vec![5u8; 20])]),
+                )
+                .await?;
+            encrypted
+                .copy_file("test1/encrypted", "test1/copied")
+                .await?;
+
+            for path in &["test1/encrypted", "test1/copied"] {
+                test_assert_eq!(
+                    read_all(&encrypted, path, Default::default()).await?,
+                    vec![5u8; 20],
+                    "Should have used the configured key to read."
+                );
+
+                test_assert!(
+                    read_all(&plain, path, Default::default()).await.is_err(),
+                    "Should not have been able to read without the key."
+                );
+
+                let mut options = ReadOptions::default();
+                options.customer_key = Some(key.clone());
+                test_assert_eq!(
+                    read_all(&plain, path, options).await?,
+                    vec![5u8; 20],
+                    "Should have read with the supplied key."
+                );
+            }
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+mod buckets {
+    use std::collections::HashMap;
+    use std::convert::TryInto;
+    use std::time::Duration;
+
+    use file_store::backends::b2::{B2Backend, BucketType, BucketUpdate, CorsRule};
+    use file_store::backends::Backend;
+
+    use crate::mocks::b2_server::start_server;
+    use crate::runner::{prepare_test, run, TestError, TestResult};
+
+    #[test]
+    fn test_update_bucket() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::B2, "test1")?;
+            let (addr, _sender) = start_server(context.get_fs_root(), 20000)?;
+
+            let fs: B2Backend = B2Backend::builder("foo", "bar")
+                .host(&format!("http://{}", addr))
+                .connect()
+                .await?
+                .try_into()
+                .map_err(|_| {
+                    TestError::HarnessFailure(String::from("Should have built a B2 backend."))
+                })?;
+
+            let settings = fs.bucket_settings("test1").await?;
+            test_assert_eq!(settings.bucket_type, BucketType::Public);
+            test_assert!(settings.cors_rules.is_empty());
+
+            let rule = CorsRule {
+                name: String::from("uploads"),
+                allowed_origins: vec![String::from("https://example.com")],
+                allowed_operations: vec![String::from("b2_upload_file")],
+                allowed_headers: vec![String::from("authorization")],
+                expose_headers: Vec::new(),
+                max_age: Duration::from_secs(3600),
+            };
+            let mut info = HashMap::new();
+            info.insert(String::from("owner"), String::from("tests"));
+
+            let updated = fs
+                .update_bucket(
+                    "test1",
+                    BucketUpdate {
+                        bucket_type: Some(BucketType::Private),
+                        bucket_info: Some(info.clone()),
+                        cors_rules: Some(vec![rule.clone()]),
+                        if_revision_is: Some(settings.revision),
+                    },
+                )
+                .await?;
+            test_assert_eq!(updated.bucket_type, BucketType::Private);
+            test_assert_eq!(updated.bucket_info, info);
+            test_assert_eq!(updated.cors_rules, vec![rule.clone()]);
+            test_assert!(updated.revision > settings.revision);
+
+            let settings = fs.bucket_settings("test1").await?;
+            test_assert_eq!(settings.bucket_type, BucketType::Private);
+            test_assert_eq!(settings.cors_rules, vec![rule]);
+
+            let stale = BucketUpdate {
+                bucket_type: Some(BucketType::Public),
+                if_revision_is: Some(0),
+                ..Default::default()
+            };
+            test_assert!(
+                fs.update_bucket("test1", stale).await.is_err(),
+                "Should not have updated from an old revision."
+            );
+
+            test_assert!(
+                fs.bucket_settings("missing").await.is_err(),
+                "Should have failed to find the bucket."
+            );
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+mod hidden {
+    use std::convert::TryInto;
+
+    use futures::stream::TryStreamExt;
+
+    use file_store::backends::b2::B2Backend;
+    use file_store::backends::Backend;
+    use file_store::*;
+
+    use crate::mocks::b2_server::start_server;
+    use crate::runner::{prepare_test, run, TestError, TestResult};
+
+    async fn names(stream: ObjectStreamFuture) -> TestResult<Vec<String>> {
+        let mut names: Vec<String> = stream
+            .await?
+            .map_ok(|o| o.path().to_string())
+            .try_collect()
+            .await?;
+        names.sort();
+        Ok(names)
+    }
+
+    #[test]
+    fn test_hide_on_delete() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::B2, "test1")?;
+            let (addr, _sender) = start_server(context.get_fs_root(), 20000)?;
+
+            let fs: B2Backend = B2Backend::builder("foo", "bar")
+                .host(&format!("http://{}", addr))
+                .hide_on_delete(true)
+                .connect()
+                .await?
+                .try_into()
+                .map_err(|_| {
+                    TestError::HarnessFailure(String::from("Should have built a B2 backend."))
+                })?;
+
+            let path = "test1/dir1/smallfile.txt";
+            fs.delete_object(path).await?;
+
+            match fs.get_object(path).await {
+                Err(e) => {
+                    test_assert_eq!(e.kind(), StorageErrorKind::NotFound(ObjectPath::new(path)?))
+                }
+                Ok(_) => test_fail!("Should not have found the hidden file."),
+            }
+            test_assert!(
+                !names(fs.list_objects("test1/dir1/"))
+                    .await?
+                    .contains(&path.to_owned()),
+                "Should not have listed the hidden file."
+            );
+            test_assert!(fs.get_file_stream(path).await.is_err());
+            test_assert_eq!(
+                names(fs.list_hidden("test1/")).await?,
+                vec![path.to_owned()]
+            );
+            test_assert!(
+                fs.delete_object(path).await.is_err(),
+                "Should not be able to hide a file twice."
+            );
+
+            fs.unhide(path).await?;
+            let object = fs.get_object(path).await?;
+            test_assert_eq!(object.object_type(), ObjectType::File);
+            test_assert!(names(fs.list_hidden("test1/")).await?.is_empty());
+            test_assert!(
+                fs.unhide(path).await.is_err(),
+                "Should not be able to unhide a visible file."
+            );
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+mod cap_exceeded {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use file_store::backends::b2::B2Backend;
+    use file_store::backends::Backend;
+    use file_store::{StorageBackend, StorageErrorKind};
+
+    use crate::mocks::b2_server::start_failing_server;
+    use crate::runner::faults::{Fault, FaultSchedule, VirtualClock};
+    use crate::runner::{prepare_test, run, TestResult};
+
+    #[test]
+    fn test_cap_exceeded() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::B2, "test1")?;
+            let attempts = Arc::new(AtomicUsize::new(0));
+            let counter = attempts.clone();
+            let (addr, _sender) = start_failing_server(
+                context.get_fs_root(),
+                20000,
+                FaultSchedule::Custom(Arc::new(move |_, path, _| {
+                    if path.ends_with("/b2_list_file_versions") {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                        Some(Fault::CapExceeded)
+                    } else {
+                        None
+                    }
+                })),
+                VirtualClock::new(),
+            )?;
+
+            let fs = B2Backend::builder("foo", "bar")
+                .host(&format!("http://{}", addr))
+                .connect()
+                .await?;
+
+            match fs.get_object("test1/dir1/smallfile.txt").await {
+                Err(e) => test_assert_eq!(e.kind(), StorageErrorKind::QuotaExceeded),
+                Ok(_) => test_fail!("Should have failed once the cap was reached."),
+            }
+            test_assert_eq!(
+                attempts.load(Ordering::SeqCst),
+                1,
+                "Should not have retried the request."
+            );
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+mod download_host {
+    use file_store::backends::b2::B2Backend;
+    use file_store::backends::Backend;
+    use file_store::transport::Capture;
+    use file_store::StorageBackend;
+
+    use crate::mocks::b2_server::start_server;
+    use crate::runner::{prepare_test, run, TestResult};
+
+    #[test]
+    fn test_download_host() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::B2, "test1")?;
+            let (addr, _sender) = start_server(context.get_fs_root(), 20000)?;
+            let download_host = format!("http://localhost:{}/download/", addr.port());
+
+            let capture = Capture::new(20);
+            let fs = B2Backend::builder("foo", "bar")
+                .host(&format!("http://{}", addr))
+                .download_host(&download_host)
+                .capture(capture.clone())
+                .connect()
+                .await?;
+            fs.get_file_stream("test1/dir1/smallfile.txt").await?;
+
+            let exchanges = capture.exchanges();
+            let last = &exchanges[exchanges.len() - 1];
+            test_assert_eq!(
+                last.uri,
+                format!(
+                    "http://localhost:{}/download/file/test1/dir1/smallfile.txt",
+                    addr.port()
+                ),
+                "Should have downloaded from the download host."
+            );
+            test_assert!(exchanges[0].uri.starts_with(&format!("http://{}/", addr)));
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+mod trash {
+    use file_store::backends::b2::B2Backend;
+    use file_store::backends::Backend;
+    use file_store::trash::Trash;
+    use file_store::{ObjectInfo, ObjectPath, StorageBackend, StorageErrorKind};
+
+    use crate::mocks::b2_server::start_server;
+    use crate::runner::{prepare_test, run, TestResult};
+
+    #[test]
+    fn test_trash() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::B2, "test1")?;
+            let (addr, _sender) = start_server(context.get_fs_root(), 20000)?;
+            let store = B2Backend::builder("foo", "bar")
+                .host(&format!("http://{}", addr))
+                .connect()
+                .await?;
+            let trash = Trash::new(store.clone(), ObjectPath::new("test1/trash")?)?;
+            let fs = trash.store();
+
+            fs.delete_object("test1/dir1/smallfile.txt").await?;
+            match fs.get_object("test1/dir1/smallfile.txt").await {
+                Err(e) => match e.kind() {
+                    StorageErrorKind::NotFound(_) => (),
+                    kind => test_fail!("Unexpected error: {:?}", kind),
+                },
+                Ok(o) => test_fail!("Should have moved the file: {:?}", o),
+            }
+
+            let trashed = trash.list().await?;
+            test_assert_eq!(trashed.len(), 1);
+            test_assert_eq!(trashed[0].path().to_string(), "test1/dir1/smallfile.txt");
+            let object = store.get_object(trashed[0].location().clone()).await?;
+            test_assert_eq!(object.len(), 27, "Should have kept the file's data.");
+
+            trash.restore(&trashed[0]).await?;
+            let object = fs.get_object("test1/dir1/smallfile.txt").await?;
+            test_assert_eq!(object.len(), 27, "Should have restored the file.");
+            test_assert!(
+                trash.list().await?.is_empty(),
+                "Should have emptied the trash."
+            );
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+mod unfinished {
+    use std::convert::TryInto;
+    use std::io;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use futures::stream::iter;
+
+    use file_store::backends::b2::B2Backend;
+    use file_store::backends::Backend;
+    use file_store::*;
+
+    use crate::mocks::b2_server::start_server;
+    use crate::runner::{prepare_test, run, TestError, TestResult};
+
+    #[test]
+    fn test_unfinished_uploads() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::B2, "test1")?;
+            let (addr, _sender) = start_server(context.get_fs_root(), 20000)?;
+
+            let fs: B2Backend = B2Backend::builder("foo", "bar")
+                .host(&format!("http://{}", addr))
+                .limit_small_file_size(0)
+                .connect()
+                .await?
+                .try_into()
+                .map_err(|_| {
+                    TestError::HarnessFailure(String::from("Should have built a B2 backend."))
+                })?;
+
+            test_assert!(fs.unfinished_uploads("test1/").await?.is_empty());
+
+            let broken: StorageError = io::Error::new(io::ErrorKind::Other, "Broken.").into();
+            let data = iter(vec![Ok(vec![5u8; 1000]), Err(broken)]);
+            test_assert!(
+                fs.write_file_from_stream("test1/dir1/broken", data)
+                    .await
+                    .is_err(),
+                "Should have failed to write the file."
+            );
+
+            let uploads = fs.unfinished_uploads("test1/dir1/").await?;
+            test_assert_eq!(uploads.len(), 1);
+            test_assert_eq!(uploads[0].path().to_string(), "test1/dir1/broken");
+            test_assert!(fs.unfinished_uploads("test1/dir2/").await?.is_empty());
+
+            test_assert!(
+                fs.cancel_unfinished_uploads("test1/", UNIX_EPOCH)
+                    .await?
+                    .is_empty(),
+                "Should not have cancelled recent uploads."
+            );
+            let cancelled = fs
+                .cancel_unfinished_uploads("test1/", SystemTime::now())
+                .await?;
+            test_assert_eq!(cancelled, uploads);
+            test_assert!(fs.unfinished_uploads("test1/").await?.is_empty());
+            test_assert!(
+                fs.cancel_upload(&uploads[0]).await.is_err(),
+                "Should not be able to cancel an upload twice."
+            );
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+#[cfg(feature = "redirect")]
+mod redirect {
+    use std::convert::TryInto;
+    use std::time::Duration;
+
+    use futures::future::ready;
+    use http::header::{self, HeaderMap};
+    use http::{Method, StatusCode};
+    use hyper::{Body, Request};
+
+    use file_store::backends::b2::B2Backend;
+    use file_store::backends::Backend;
+    use file_store::redirect::RedirectProxy;
+    use file_store::*;
+
+    use crate::mocks::b2_server::start_server;
+    use crate::runner::{prepare_test, run, TestError, TestResult};
+
+    fn request(method: Method, path: &str, token: &str) -> TestResult<Request<Body>> {
+        Request::builder()
+            .method(method)
+            .uri(path)
+            .header(header::AUTHORIZATION, token)
+            .body(Body::empty())
+            .map_err(|e| TestError::HarnessFailure(e.to_string()))
+    }
+
+    #[test]
+    fn test_redirect() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::B2, "test1")?;
+            let (addr, _sender) = start_server(context.get_fs_root(), 20000)?;
+
+            let fs = B2Backend::builder("foo", "bar")
+                .host(&format!("http://{}", addr))
+                .connect()
+                .await?;
+            let backend: B2Backend = fs.clone().try_into().map_err(|_| {
+                TestError::HarnessFailure(String::from("Should have built a B2 backend."))
+            })?;
+
+            let url = backend
+                .presigned_url("test1/dir1/smallfile.txt", Duration::from_secs(60))
+                .await?;
+            let expected = format!("http://{}/download/file/test1/dir1/smallfile.txt", addr);
+            test_assert!(
+                url.starts_with(&format!("{}?Authorization=", expected)),
+                "Unexpected url {}",
+                url
+            );
+            test_assert!(
+                backend
+                    .presigned_url("test1/dir1/smallfile.txt", Duration::from_secs(0))
+                    .await
+                    .is_err(),
+                "Should not allow urls that are never valid."
+            );
+            test_assert!(
+                backend
+                    .presigned_url("test1/dir1/", Duration::from_secs(60))
+                    .await
+                    .is_err(),
+                "Should not presign directories."
+            );
+
+            let proxy = RedirectProxy::new(
+                fs,
+                ObjectPath::new("test1")?,
+                |_: &ObjectPath, headers: &HeaderMap| {
+                    ready(headers.get(header::AUTHORIZATION).map(|v| v.as_bytes()) == Some(b"good"))
+                },
+            );
+
+            let response = proxy
+                .serve(&request(Method::GET, "/dir1/smallfile.txt", "bad")?)
+                .await;
+            test_assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+            let response = proxy
+                .serve(&request(Method::GET, "/dir1/smallfile.txt", "good")?)
+                .await;
+            test_assert_eq!(response.status(), StatusCode::FOUND);
+            let location = response
+                .headers()
+                .get(header::LOCATION)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or("");
+            test_assert!(
+                location.starts_with(&format!("{}?Authorization=", expected)),
+                "Unexpected location {}",
+                location
+            );
+
+            let response = proxy
+                .serve(&request(Method::HEAD, "/dir1/smallfile.txt", "good")?)
+                .await;
+            test_assert_eq!(response.status(), StatusCode::OK);
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+mod retry_policy {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use file_store::backends::b2::B2Backend;
+    use file_store::backends::Backend;
+    use file_store::{RetryPolicy, StorageBackend, StorageErrorKind};
+
+    use crate::mocks::b2_server::start_failing_server;
+    use crate::runner::faults::{Fault, FaultSchedule, VirtualClock};
+    use crate::runner::{prepare_test, run, TestResult};
+
+    /// Gets an object while listing file versions always fails, returning the
+    /// error and the number of attempts.
+    async fn attempts(
+        fault: Fault,
+        policy: RetryPolicy,
+    ) -> TestResult<(Option<StorageErrorKind>, usize)> {
+        let context = prepare_test(Backend::B2, "test1")?;
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let counter = attempts.clone();
+        let (addr, _sender) = start_failing_server(
+            context.get_fs_root(),
+            20000,
+            FaultSchedule::Custom(Arc::new(move |_, path, _| {
+                if path.ends_with("/b2_list_file_versions") {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    Some(fault)
+                } else {
+                    None
+                }
+            })),
+            VirtualClock::new(),
+        )?;
+
+        let fs = B2Backend::builder("foo", "bar")
+            .host(&format!("http://{}", addr))
+            .retry_policy(policy)
+            .connect()
+            .await?;
+
+        let kind = fs
+            .get_object("test1/dir1/smallfile.txt")
+            .await
+            .err()
+            .map(|e| e.kind());
+        Ok((kind, attempts.load(Ordering::SeqCst)))
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        let result: TestResult<()> = run(async {
+            let (kind, count) = attempts(Fault::ServerError, RetryPolicy::default()).await?;
+            test_assert_eq!(kind, Some(StorageErrorKind::ServiceError));
+            test_assert_eq!(count, 5);
+
+            let (kind, count) =
+                attempts(Fault::ServerError, RetryPolicy::new().max_attempts(2)).await?;
+            test_assert_eq!(kind, Some(StorageErrorKind::ServiceError));
+            test_assert_eq!(count, 2);
+
+            let (kind, count) = attempts(Fault::ServerError, RetryPolicy::never()).await?;
+            test_assert_eq!(kind, Some(StorageErrorKind::ServiceError));
+            test_assert_eq!(count, 1);
+
+            // Overrides apply to errors that are not normally retried.
+            let policy = RetryPolicy::new().attempts_for(&StorageErrorKind::QuotaExceeded, 3);
+            let (kind, count) = attempts(Fault::CapExceeded, policy).await?;
+            test_assert_eq!(kind, Some(StorageErrorKind::QuotaExceeded));
+            test_assert_eq!(count, 3);
+
+            // And can stop transient errors from being retried.
+            let policy = RetryPolicy::new().attempts_for(&StorageErrorKind::ServiceError, 1);
+            let (kind, count) = attempts(Fault::ServerError, policy).await?;
+            test_assert_eq!(kind, Some(StorageErrorKind::ServiceError));
+            test_assert_eq!(count, 1);
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+mod resolver {
+    use std::net::IpAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use file_store::backends::b2::B2Backend;
+    use file_store::backends::Backend;
+    use file_store::transport::ResolveFuture;
+    use file_store::StorageBackend;
+
+    use crate::mocks::b2_server::start_server;
+    use crate::runner::{prepare_test, run, TestError, TestResult};
+
+    #[test]
+    fn test_resolver() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::B2, "test1")?;
+            let (addr, sender) = start_server(context.get_fs_root(), 20000)?;
+            let host = format!("http://b2.file-store.test:{}", addr.port());
+
+            let fs = B2Backend::builder("foo", "bar")
+                .host(&host)
+                .resolve("B2.File-Store.Test", &[addr.ip()])
+                .connect()
+                .await?;
+            fs.get_object("test1/dir1/smallfile.txt").await?;
+
+            let lookups = Arc::new(AtomicUsize::new(0));
+            let counter = lookups.clone();
+            let ip = addr.ip();
+            let fs = B2Backend::builder("foo", "bar")
+                .host(&host)
+                .resolver(move |host: &str| -> ResolveFuture {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    let addrs: Vec<IpAddr> = if host == "b2.file-store.test" {
+                        vec![ip]
+                    } else {
+                        Vec::new()
+                    };
+                    Box::pin(async move { Ok(addrs) })
+                })
+                .connect()
+                .await?;
+            fs.get_object("test1/dir1/smallfile.txt").await?;
+            test_assert!(lookups.load(Ordering::SeqCst) > 0);
+
+            let fs = B2Backend::builder("foo", "bar")
+                .host(&host)
+                .resolver(|_: &str| -> ResolveFuture { Box::pin(async { Ok(Vec::new()) }) })
+                .retry_policy(file_store::RetryPolicy::never())
+                .connect()
+                .await;
+            test_assert!(fs.is_err(), "Should not have found the server.");
+
+            sender.send(()).map_err(|()| {
+                TestError::HarnessFailure(String::from(
+                    "Failed to send shutdown to mock b2 server.",
+                ))
+            })
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+mod validate_path {
+    use file_store::backends::b2::B2Backend;
+    use file_store::{ObjectPath, StorageBackend, StorageErrorKind};
+
+    use crate::runner::{run, TestResult};
+
+    #[test]
+    fn test_validate_path() {
+        let result: TestResult<()> = run(async {
+            // Validating never needs to talk to B2.
+            let fs = B2Backend::builder("foo", "bar")
+                .host("http://localhost:1")
+                .lazy_authentication(true)
+                .connect()
+                .await?;
+
+            fs.validate_path(&ObjectPath::new("bucket/some/file.txt")?)?;
+
+            let invalid = vec![
+                ObjectPath::new("bucket/dir/")?,
+                ObjectPath::new("bucket/tab\there")?,
+                ObjectPath::new(format!("bucket/{}", "a".repeat(251)))?,
+                ObjectPath::new(format!("bucket/{}", "abcdefgh/".repeat(120) + "file"))?,
+            ];
+
+            for path in invalid {
+                match fs.validate_path(&path) {
+                    Ok(()) => test_fail!("Should have rejected {}.", path),
+                    Err(e) => test_assert_eq!(e.kind(), StorageErrorKind::InvalidPath(path)),
+                }
+            }
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+mod upload_watchdog {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use futures::stream::iter;
+
+    use file_store::backends::b2::{B2Backend, B2BackendBuilder};
+    use file_store::backends::Backend;
+    use file_store::{
+        ObjectInfo, RetryPolicy, StorageBackend, StorageError, StorageErrorKind, TransferError,
+    };
+
+    use crate::mocks::b2_server::start_failing_server;
+    use crate::runner::faults::{Fault, FaultSchedule, VirtualClock};
+    use crate::runner::{prepare_test, run, TestResult};
+
+    /// Writes a file to a server that never answers the first upload,
+    /// returning the result and the number of uploads attempted.
+    async fn write(
+        policy: RetryPolicy,
+        watch: fn(B2BackendBuilder) -> B2BackendBuilder,
+    ) -> TestResult<(Result<(), TransferError>, usize)> {
+        let context = prepare_test(Backend::B2, "test1")?;
+        let uploads = Arc::new(AtomicUsize::new(0));
+        let counter = uploads.clone();
+        let (addr, _sender) = start_failing_server(
+            context.get_fs_root(),
+            20000,
+            FaultSchedule::Custom(Arc::new(move |_, path, _| {
+                if path.starts_with("/upload/file/") && counter.fetch_add(1, Ordering::SeqCst) == 0
+                {
+                    Some(Fault::Stall)
+                } else {
+                    None
+                }
+            })),
+            VirtualClock::new(),
+        )?;
+
+        let builder = B2Backend::builder("foo", "bar")
+            .host(&format!("http://{}", addr))
+            .retry_policy(policy);
+        let fs = watch(builder).connect().await?;
+
+        let result = fs
+            .write_file_from_stream(
+                "test1/watched",
+                iter(vec![Ok::<_, StorageError>(vec![5u8; 20])]),
+            )
+            .await;
+        if result.is_ok() {
+            let object = fs.get_object("test1/watched").await?;
+            test_assert_eq!(object.len(), 20);
+        }
+
+        Ok((result, uploads.load(Ordering::SeqCst)))
+    }
+
+    fn throughput(builder: B2BackendBuilder) -> B2BackendBuilder {
+        builder.min_upload_throughput(1024, Duration::from_millis(200))
+    }
+
+    fn deadline(builder: B2BackendBuilder) -> B2BackendBuilder {
+        builder.upload_deadline(Duration::from_millis(200))
+    }
+
+    #[test]
+    fn test_stalled_upload() {
+        let result: TestResult<()> = run(async {
+            let (result, count) = write(RetryPolicy::default(), throughput).await?;
+            test_assert!(result.is_ok(), "Should have retried the stalled upload.");
+            test_assert_eq!(count, 2);
+
+            let (result, count) = write(RetryPolicy::never(), throughput).await?;
+            match result {
+                Err(TransferError::TargetError(e)) => {
+                    test_assert_eq!(e.kind(), StorageErrorKind::ConnectionFailed)
+                }
+                result => test_fail!("Unexpected result: {:?}", result),
+            }
+            test_assert_eq!(count, 1);
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+
+    #[test]
+    fn test_upload_deadline() {
+        let result: TestResult<()> = run(async {
+            let (result, count) = write(RetryPolicy::default(), deadline).await?;
+            test_assert!(result.is_ok(), "Should have retried the late upload.");
+            test_assert_eq!(count, 2);
+
+            let (result, count) = write(RetryPolicy::never(), deadline).await?;
+            match result {
+                Err(TransferError::TargetError(e)) => {
+                    test_assert_eq!(e.kind(), StorageErrorKind::ConnectionFailed)
+                }
+                result => test_fail!("Unexpected result: {:?}", result),
+            }
+            test_assert_eq!(count, 1);
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+mod compose {
+    use futures::stream::TryStreamExt;
+
+    use file_store::backends::b2::B2Backend;
+    use file_store::backends::Backend;
+    use file_store::transport::{Capture, Exchange};
+    use file_store::{ObjectPath, StorageBackend};
+
+    use crate::mocks::b2_server::start_server;
+    use crate::runner::{prepare_test, run, TestResult};
+
+    async fn read_all(fs: &B2Backend, path: ObjectPath) -> TestResult<Vec<u8>> {
+        Ok(fs.get_file_stream(path).await?.try_concat().await?.to_vec())
+    }
+
+    fn count(exchanges: &[Exchange], method: &str) -> usize {
+        exchanges.iter().filter(|e| e.uri.contains(method)).count()
+    }
+
+    #[test]
+    fn test_server_side() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::B2, "test1")?;
+            let (addr, _sender) = start_server(context.get_fs_root(), 20000)?;
+
+            let capture = Capture::new(100);
+            let fs = B2Backend::builder("foo", "bar")
+                .host(&format!("http://{}", addr))
+                .capture(capture.clone())
+                .connect()
+                .await?;
+
+            let medium = context.get_path("test1/dir1/mediumfile");
+            let small = context.get_path("test1/dir1/smallfile.txt");
+            let joined = context.get_path("test1/dir1/joined");
+            let mut expected = read_all(&fs, medium.clone()).await?;
+            let small_data = read_all(&fs, small.clone()).await?;
+            expected.extend_from_slice(&small_data);
+
+            capture.clear();
+            fs.compose(vec![medium.clone(), small.clone()], joined.clone())
+                .await?;
+            let exchanges = capture.exchanges();
+            test_assert_eq!(count(&exchanges, "/b2_copy_part"), 2);
+            test_assert_eq!(
+                count(&exchanges, "download_file"),
+                0,
+                "Should not have downloaded the sources."
+            );
+            test_assert_eq!(read_all(&fs, joined.clone()).await?, expected);
+
+            // Compacting into one of the sources copies the version that was
+            // current when the compose started.
+            fs.compose(vec![joined.clone(), small.clone()], joined.clone())
+                .await?;
+            expected.extend_from_slice(&small_data);
+            test_assert_eq!(read_all(&fs, joined.clone()).await?, expected);
+
+            // The small file is below the minimum part size so this has to be
+            // streamed through.
+            capture.clear();
+            fs.compose(vec![small.clone(), medium.clone()], joined.clone())
+                .await?;
+            test_assert_eq!(count(&capture.exchanges(), "/b2_copy_part"), 0);
+            let mut expected = small_data.clone();
+            expected.extend_from_slice(&read_all(&fs, medium).await?);
+            test_assert_eq!(read_all(&fs, joined).await?, expected);
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
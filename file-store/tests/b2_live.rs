@@ -0,0 +1,109 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Runs the tests against the real B2 service.
+//!
+//! These only run when `FILE_STORE_B2_KEY_ID`, `FILE_STORE_B2_KEY` and
+//! `FILE_STORE_B2_BUCKET` are set, otherwise they are skipped. Each test
+//! uploads the test files to a new prefix in the bucket and deletes everything
+//! under that prefix when it completes.
+//!
+//! Only the read tests are run as the write tests check their results against
+//! the local filesystem.
+#![cfg(feature = "b2")]
+
+extern crate file_store;
+
+#[macro_use]
+mod runner;
+
+mod live {
+    use std::env;
+    use std::fs::{metadata, read, read_dir};
+    use std::io;
+    use std::path::Path;
+
+    use futures::stream::{iter, TryStreamExt};
+    use uuid::Uuid;
+
+    use file_store::backends::b2::B2Backend;
+    use file_store::backends::Backend;
+    use file_store::{FileStore, ObjectInfo, ObjectPath, StorageBackend, UploadInfo};
+
+    use crate::runner::{TestContext, TestError, TestResult};
+
+    const UPLOAD_CHUNK: usize = 1024 * 1024;
+
+    fn var(name: &str) -> TestResult<String> {
+        env::var(name).map_err(|_| TestError::Skipped(format!("{} is not set.", name)))
+    }
+
+    fn local_error(error: io::Error) -> TestError {
+        TestError::HarnessFailure(error.to_string())
+    }
+
+    async fn upload(fs: &FileStore, dir: &Path, path: ObjectPath) -> TestResult<()> {
+        for entry in read_dir(dir).map_err(local_error)? {
+            let entry = entry.map_err(local_error)?;
+            let mut target = path.clone();
+            target.push_part(&entry.file_name().to_string_lossy());
+
+            let meta = metadata(entry.path()).map_err(local_error)?;
+            if meta.is_dir() {
+                upload(fs, &entry.path(), target).await?;
+            } else {
+                let data = read(entry.path()).map_err(local_error)?;
+                let chunks: Vec<Result<Vec<u8>, io::Error>> =
+                    data.chunks(UPLOAD_CHUNK).map(|c| Ok(c.to_vec())).collect();
+
+                let info = UploadInfo {
+                    path: target,
+                    modified: Some(meta.modified().map_err(local_error)?),
+                    options: Default::default(),
+                };
+                fs.write_file_from_stream(info, iter(chunks)).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn build_fs(context: &TestContext) -> TestResult<(FileStore, FileStore)> {
+        let key_id = var("FILE_STORE_B2_KEY_ID")?;
+        let key = var("FILE_STORE_B2_KEY")?;
+        let bucket = var("FILE_STORE_B2_BUCKET")?;
+
+        let mut prefix = ObjectPath::new(&bucket)?;
+        prefix.push_part(&format!("file-store-test-{}", Uuid::new_v4()));
+
+        let fs = B2Backend::builder(&key_id, &key)
+            .prefix(prefix)
+            .connect()
+            .await?;
+        upload(&fs, &context.get_fs_root(), ObjectPath::empty()).await?;
+
+        Ok((fs.clone(), fs))
+    }
+
+    async fn cleanup(fs: FileStore) -> TestResult<()> {
+        let objects: Vec<_> = fs.list_objects("").await?.try_collect().await?;
+        for object in objects {
+            fs.delete_object(object.path()).await?;
+        }
+
+        Ok(())
+    }
+
+    build_read_tests!("test1", Backend::B2, build_fs, cleanup);
+}
@@ -0,0 +1,63 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![cfg(all(feature = "file", feature = "blocking"))]
+
+extern crate file_store;
+
+use std::io::{Cursor, Read};
+
+use tempfile::tempdir;
+
+use file_store::backends::file::FileBackend;
+use file_store::blocking::BlockingFileStore;
+use file_store::*;
+
+#[test]
+fn test_blocking() {
+    let temp = tempdir().unwrap();
+    let fs = BlockingFileStore::connect(FileBackend::connect(temp.path())).unwrap();
+
+    let content: Vec<u8> = (0..5000).map(|i| (i % 256) as u8).collect();
+    fs.write_file("dir/file", Cursor::new(content.clone()))
+        .unwrap();
+
+    let object = fs.get_object("dir/file").unwrap();
+    assert_eq!(object.len(), content.len() as u64);
+
+    let mut data = Vec::new();
+    fs.get_file_reader("dir/file")
+        .unwrap()
+        .read_to_end(&mut data)
+        .unwrap();
+    assert_eq!(data, content);
+
+    let paths: Vec<String> = fs
+        .list_objects("")
+        .unwrap()
+        .map(|o| o.unwrap())
+        .filter(|o| o.object_type() == ObjectType::File)
+        .map(|o| o.path().to_string())
+        .collect();
+    assert_eq!(paths, vec!["dir/file".to_owned()]);
+
+    fs.delete_object("dir/file").unwrap();
+    match fs.get_object("dir/file") {
+        Err(e) => match e.kind() {
+            StorageErrorKind::NotFound(_) => (),
+            _ => panic!("Unexpected error {}", e),
+        },
+        Ok(_) => panic!("The file should have been deleted."),
+    }
+}
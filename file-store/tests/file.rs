@@ -19,6 +19,126 @@ extern crate file_store;
 #[macro_use]
 mod runner;
 
+mod archive {
+    use std::fs::read;
+    use std::time::UNIX_EPOCH;
+
+    use crate::runner::{prepare_test, run, TestResult};
+    use file_store::archive::{archive, extract};
+    use file_store::backends::file::FileBackend;
+    use file_store::backends::Backend;
+    use file_store::*;
+
+    #[test]
+    fn test_archive() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1")?;
+            let root = context.get_fs_root();
+            let fs = FileBackend::connect(&root).await?;
+
+            archive(&fs, "dir2", &fs, "dir2.tar").await?;
+            let data = read(root.join("dir2.tar")).unwrap();
+            // A header for each file, two blocks for daz and the end marker.
+            test_assert_eq!(data.len(), 512 * 12);
+            test_assert_eq!(&data[..5], b"0foo\0");
+            test_assert_eq!(&data[257..263], b"ustar\0");
+
+            let extracted = extract(&fs, "dir2.tar", &fs, "extracted").await?;
+            let paths: Vec<String> = extracted.iter().map(|p| p.to_string()).collect();
+            test_assert_eq!(
+                paths,
+                vec!["0foo", "1bar", "5diz", "bar", "daz", "foo", "hop", "yu"]
+            );
+            test_assert_eq!(
+                read(root.join("extracted").join("daz")).unwrap(),
+                read(root.join("dir2").join("daz")).unwrap()
+            );
+            let seconds = |object: Object| {
+                object
+                    .modified()
+                    .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                    .map(|d| d.as_secs())
+            };
+            test_assert_eq!(
+                seconds(fs.get_object("extracted/daz").await?),
+                seconds(fs.get_object("dir2/daz").await?),
+                "Should have kept the modification time."
+            );
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+#[cfg(feature = "backup")]
+mod backup {
+    use std::fs::{read, write};
+
+    use crate::runner::{prepare_test, run, TestResult};
+    use file_store::backends::file::FileBackend;
+    use file_store::backends::Backend;
+    use file_store::backup::Repository;
+    use file_store::*;
+
+    fn paths(paths: &[ObjectPath]) -> Vec<String> {
+        paths.iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn test_backup() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1")?;
+            let root = context.get_fs_root();
+            let fs = FileBackend::connect(&root).await?;
+            let repository = Repository::new(fs.clone(), ObjectPath::new("backups")?)?;
+
+            let summary = repository.backup(&fs, "dir2").await?;
+            test_assert_eq!(summary.manifest.entries().len(), 8);
+            // All of the empty files share the same contents.
+            test_assert_eq!(paths(&summary.uploaded), vec!["0foo", "daz"]);
+            test_assert_eq!(summary.bytes, 300);
+
+            write(root.join("dir2").join("foo"), "Some data").unwrap();
+            let summary = repository.backup(&fs, "dir2").await?;
+            test_assert_eq!(paths(&summary.uploaded), vec!["foo"]);
+            test_assert_eq!(summary.bytes, 9);
+
+            let manifests = repository.manifests().await?;
+            test_assert_eq!(manifests.len(), 2);
+            test_assert!(
+                manifests[0].created() < manifests[1].created(),
+                "Should have listed the oldest backup first."
+            );
+
+            repository.restore(&manifests[0], &fs, "restored").await?;
+            test_assert_eq!(
+                read(root.join("restored").join("foo")).unwrap(),
+                Vec::<u8>::new()
+            );
+            test_assert_eq!(
+                read(root.join("restored").join("daz")).unwrap(),
+                read(root.join("dir2").join("daz")).unwrap()
+            );
+
+            repository.restore(&manifests[1], &fs, "restored").await?;
+            test_assert_eq!(
+                read(root.join("restored").join("foo")).unwrap(),
+                b"Some data".to_vec()
+            );
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
 mod dir1 {
     use crate::runner::{TestContext, TestResult};
     use file_store::backends::file::FileBackend;
@@ -52,3 +172,3588 @@ mod test1 {
 
     build_tests!("test1", Backend::File, build_fs, cleanup);
 }
+
+mod dynamic {
+    use crate::runner::{TestContext, TestResult};
+    use file_store::backends::file::FileBackend;
+    use file_store::backends::Backend;
+    use file_store::dynamic::DynamicStore;
+    use file_store::FileStore;
+
+    async fn build_fs(context: &TestContext) -> TestResult<(FileStore, ())> {
+        let fs = FileBackend::connect(&context.get_fs_root()).await?;
+        Ok((FileStore::from(DynamicStore::new(fs)), ()))
+    }
+
+    async fn cleanup(_: ()) -> TestResult<()> {
+        Ok(())
+    }
+
+    build_tests!("test1", Backend::File, build_fs, cleanup);
+}
+
+mod ordered {
+    use crate::runner::{TestContext, TestResult};
+    use file_store::backends::file::FileBackend;
+    use file_store::backends::Backend;
+    use file_store::FileStore;
+
+    async fn build_fs(context: &TestContext) -> TestResult<(FileStore, ())> {
+        let fs = FileBackend::builder(&context.get_fs_root())
+            .list_concurrency(1)
+            .ordered_listing(true)
+            .connect()
+            .await?;
+        Ok((fs, ()))
+    }
+
+    async fn cleanup(_: ()) -> TestResult<()> {
+        Ok(())
+    }
+
+    build_tests!("test1", Backend::File, build_fs, cleanup);
+}
+
+#[cfg(feature = "expire")]
+mod expire {
+    use std::time::{Duration, SystemTime};
+
+    use filetime::{set_file_mtime, FileTime};
+    use futures::stream::StreamExt;
+
+    use crate::runner::{prepare_test, run, TestResult};
+    use file_store::backends::file::FileBackend;
+    use file_store::backends::Backend;
+    use file_store::expire::Sweeper;
+    use file_store::*;
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    #[test]
+    fn test_sweep() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1")?;
+            let root = context.get_fs_root();
+            let old = FileTime::from_system_time(SystemTime::now() - DAY * 2);
+            set_file_mtime(root.join("dir2").join("foo"), old).unwrap();
+            set_file_mtime(root.join("smallfile.txt"), old).unwrap();
+            let fs = FileBackend::connect(&root).await?;
+
+            let sweeper = Sweeper::new(fs.clone())
+                .expire(ObjectPath::new("dir2")?, DAY)
+                .expire(ObjectPath::new("dir2/")?, DAY * 3);
+            let deleted = sweeper.sweep().await?;
+            test_assert_eq!(deleted, vec![ObjectPath::new("dir2/foo")?]);
+            test_assert!(
+                !root.join("dir2").join("foo").exists(),
+                "Should have deleted the expired file."
+            );
+            test_assert!(
+                root.join("smallfile.txt").exists(),
+                "Should not have deleted a file outside of the rules."
+            );
+
+            set_file_mtime(root.join("dir2").join("bar"), old).unwrap();
+            let mut sweeps = sweeper.schedule(Duration::from_millis(10));
+            test_assert_eq!(
+                sweeps.next().await.transpose()?,
+                Some(vec![ObjectPath::new("dir2/bar")?])
+            );
+            test_assert_eq!(sweeps.next().await.transpose()?, Some(vec![]));
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+#[cfg(feature = "index")]
+mod index {
+    use std::fs::{remove_file, write};
+
+    use crate::runner::{prepare_test, run, TestResult};
+    use file_store::backends::file::FileBackend;
+    use file_store::backends::Backend;
+    use file_store::index::{Index, Query};
+    use file_store::*;
+
+    fn paths(entries: &[&index::IndexEntry]) -> Vec<String> {
+        entries.iter().map(|e| e.path().to_string()).collect()
+    }
+
+    #[test]
+    fn test_index() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1")?;
+            let root = context.get_fs_root();
+            let fs = FileBackend::connect(&root).await?;
+
+            let index = Index::new().refresh(&fs, "").await?;
+            test_assert_eq!(
+                paths(&index.search(&Query::new().min_size(1_000_000))),
+                vec!["largefile", "mediumfile"]
+            );
+            test_assert_eq!(
+                paths(&index.search(&Query::new().content_type("text/"))),
+                vec!["smallfile.txt"]
+            );
+            test_assert_eq!(
+                index
+                    .search(&Query::new().prefix(ObjectPath::new("dir2")?).max_size(0))
+                    .len(),
+                7
+            );
+
+            let index = Index::with_hashes().refresh(&fs, "dir2").await?;
+            test_assert_eq!(index.len(), 8);
+            test_assert_eq!(
+                index
+                    .get(&ObjectPath::new("dir2/foo")?)
+                    .and_then(|e| e.hash()),
+                Some("da39a3ee5e6b4b0d3255bfef95601890afd80709")
+            );
+
+            write(root.join("dir2").join("new.PNG"), "png").unwrap();
+            remove_file(root.join("dir2").join("bar")).unwrap();
+            let index = index.refresh(&fs, "dir2").await?;
+            test_assert!(
+                index.get(&ObjectPath::new("dir2/bar")?).is_none(),
+                "Should have removed the deleted file."
+            );
+            test_assert_eq!(
+                paths(&index.search(&Query::new().extension("png"))),
+                vec!["dir2/new.PNG"]
+            );
+            test_assert_eq!(
+                paths(&index.search(&Query::new().content_type("image/png"))),
+                vec!["dir2/new.PNG"]
+            );
+
+            write(root.join("dir2").join("foo"), "Some data").unwrap();
+            let index = index.update(&fs, "dir2/foo").await?;
+            test_assert_eq!(
+                index.get(&ObjectPath::new("dir2/foo")?).map(|e| e.len()),
+                Some(9)
+            );
+
+            index.save(&fs, "index").await?;
+            let loaded = Index::load(&fs, "index").await?;
+            test_assert!(loaded.has_hashes(), "Should have loaded the settings.");
+            test_assert_eq!(
+                loaded.entries().collect::<Vec<_>>(),
+                index.entries().collect::<Vec<_>>()
+            );
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+mod limited {
+    use crate::runner::{TestContext, TestResult};
+    use file_store::backends::file::FileBackend;
+    use file_store::backends::Backend;
+    use file_store::FileStore;
+
+    async fn build_fs(context: &TestContext) -> TestResult<(FileStore, ())> {
+        let fs = FileBackend::builder(&context.get_fs_root())
+            .limit_operations(2)
+            .limit_open_files(2)
+            .connect()
+            .await?;
+        Ok((fs, ()))
+    }
+
+    async fn cleanup(_: ()) -> TestResult<()> {
+        Ok(())
+    }
+
+    build_tests!("test1", Backend::File, build_fs, cleanup);
+}
+
+mod traversal {
+    use crate::runner::{prepare_test, run, TestResult};
+    use file_store::backends::file::FileBackend;
+    use file_store::backends::Backend;
+    use file_store::{StorageBackend, StorageErrorKind};
+
+    #[test]
+    fn test_parent_paths() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1/dir2")?;
+            let fs = FileBackend::connect(&context.get_fs_root()).await?;
+
+            for path in &[
+                "../smallfile.txt",
+                "foo/../../smallfile.txt",
+                "./foo",
+                "foo//bar",
+            ] {
+                match fs.get_object(*path).await {
+                    Err(e) => match e.kind() {
+                        StorageErrorKind::InvalidPath(_) => (),
+                        kind => test_fail!("Unexpected error for {}: {:?}", path, kind),
+                    },
+                    Ok(_) => test_fail!("Should not have been able to access {}.", path),
+                }
+            }
+
+            #[cfg(windows)]
+            for path in &["foo\\bar", "C:foo", "foo:stream"] {
+                test_assert!(
+                    fs.get_object(*path).await.is_err(),
+                    "Should not have been able to access {}.",
+                    path
+                );
+            }
+
+            test_assert!(
+                fs.delete_object("../smallfile.txt").await.is_err(),
+                "Should not have been able to delete outside of the root."
+            );
+            test_assert!(
+                context.get_fs_root().join("../smallfile.txt").exists(),
+                "File outside of the root should still exist."
+            );
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+#[cfg(unix)]
+mod non_unicode {
+    use std::ffi::OsStr;
+    use std::fs::File;
+    use std::os::unix::ffi::OsStrExt;
+
+    use futures::stream::TryStreamExt;
+
+    use crate::runner::{prepare_test, run, TestResult};
+    use file_store::backends::file::{FileBackend, NonUnicodeNames};
+    use file_store::backends::Backend;
+    use file_store::{ObjectInfo, StorageBackend};
+
+    async fn list_names(
+        context: &crate::runner::TestContext,
+        policy: NonUnicodeNames,
+    ) -> TestResult<Vec<String>> {
+        let fs = FileBackend::builder(&context.get_fs_root())
+            .non_unicode_names(policy)
+            .connect()
+            .await?;
+
+        let mut names: Vec<String> = fs
+            .list_directory("")
+            .await?
+            .map_ok(|o| o.path().to_string())
+            .try_collect()
+            .await?;
+        names.sort();
+        Ok(names)
+    }
+
+    #[test]
+    fn test_non_unicode_names() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1/dir2")?;
+            let name = OsStr::from_bytes(b"bad\xff%name");
+            File::create(context.get_fs_root().join(name)).unwrap();
+
+            let names = list_names(&context, NonUnicodeNames::Skip).await?;
+            test_assert_eq!(names.len(), 8, "Should have skipped the file.");
+
+            let names = list_names(&context, NonUnicodeNames::Escape).await?;
+            test_assert!(
+                names.contains(&"bad%FF%25name".to_owned()),
+                "Should have escaped the name."
+            );
+
+            test_assert!(
+                list_names(&context, NonUnicodeNames::Fail).await.is_err(),
+                "Should have failed to list."
+            );
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+mod readonly {
+    use std::fs::{metadata, set_permissions};
+
+    use crate::runner::{prepare_test, run, TestResult};
+    use file_store::backends::file::FileBackend;
+    use file_store::backends::Backend;
+    use file_store::StorageBackend;
+
+    #[test]
+    fn test_delete_readonly() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1")?;
+            let target = context.get_fs_root().join("smallfile.txt");
+
+            let mut permissions = metadata(&target).unwrap().permissions();
+            permissions.set_readonly(true);
+            set_permissions(&target, permissions).unwrap();
+
+            let fs = FileBackend::connect(&context.get_fs_root()).await?;
+            fs.delete_object("smallfile.txt").await?;
+            test_assert!(!target.exists(), "Read-only file should have been deleted.");
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+#[cfg(unix)]
+mod symlinks {
+    use std::os::unix::fs::symlink;
+
+    use futures::stream::TryStreamExt;
+
+    use crate::runner::{prepare_test, run, TestResult};
+    use file_store::backends::file::FileBackend;
+    use file_store::backends::Backend;
+    use file_store::{Object, ObjectInfo, ObjectType, StorageBackend};
+
+    #[test]
+    fn test_symlink_cycle() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1")?;
+            let root = context.get_fs_root();
+            symlink(&root, root.join("dir2").join("up")).unwrap();
+
+            let fs = FileBackend::builder(&root)
+                .follow_symlinks(true)
+                .connect()
+                .await?;
+
+            let objects: Vec<_> = fs.list_objects("").await?.try_collect().await?;
+            let up = objects
+                .iter()
+                .find(|o| o.path().to_string() == "dir2/up")
+                .map(|o| o.object_type());
+            test_assert_eq!(
+                up,
+                Some(ObjectType::Directory),
+                "Should have listed the link as a directory."
+            );
+            test_assert!(
+                !objects
+                    .iter()
+                    .any(|o| o.path().to_string().starts_with("dir2/up/")),
+                "Should not have listed the contents of the link."
+            );
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+
+    #[test]
+    fn test_follow_file_link() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1")?;
+            let root = context.get_fs_root();
+            let target = root.join("smallfile.txt");
+            let length = target.metadata().unwrap().len();
+            symlink(&target, root.join("link.txt")).unwrap();
+
+            let fs = FileBackend::connect(&root).await?;
+            let object = fs.get_object("link.txt").await?;
+            test_assert_eq!(
+                object.object_type(),
+                ObjectType::Symlink,
+                "Should have reported the link as a symlink."
+            );
+            test_assert_eq!(object.len(), 0, "Should have reported no size.");
+
+            let fs = FileBackend::builder(&root)
+                .follow_symlinks(true)
+                .connect()
+                .await?;
+            let object = fs.get_object("link.txt").await?;
+            test_assert_eq!(
+                object.object_type(),
+                ObjectType::File,
+                "Should have reported the link as a file."
+            );
+            test_assert_eq!(
+                object.len(),
+                length,
+                "Should have reported the target's size."
+            );
+
+            let data: Vec<u8> = fs
+                .get_file_stream("link.txt")
+                .await?
+                .map_ok(|data| data.to_vec())
+                .try_concat()
+                .await?;
+            test_assert_eq!(
+                data,
+                std::fs::read(&target).unwrap(),
+                "Should have read the target's content."
+            );
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+
+    #[test]
+    fn test_list_directory_links() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1")?;
+            let root = context.get_fs_root();
+            let target = root.join("smallfile.txt");
+            let length = target.metadata().unwrap().len();
+            symlink(&target, root.join("dir2").join("link.txt")).unwrap();
+
+            let find = |objects: &[Object]| {
+                objects
+                    .iter()
+                    .find(|o| o.path().to_string() == "dir2/link.txt")
+                    .map(|o| (o.object_type(), o.len()))
+            };
+
+            let fs = FileBackend::connect(&root).await?;
+            let objects: Vec<Object> = fs.list_directory("dir2/").await?.try_collect().await?;
+            test_assert_eq!(
+                find(&objects),
+                Some((ObjectType::Symlink, 0)),
+                "Should have listed the link as a symlink."
+            );
+
+            let fs = FileBackend::builder(&root)
+                .follow_symlinks(true)
+                .connect()
+                .await?;
+            let objects: Vec<Object> = fs.list_directory("dir2/").await?.try_collect().await?;
+            test_assert_eq!(
+                find(&objects),
+                Some((ObjectType::File, length)),
+                "Should have listed the link as its target."
+            );
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+mod exclusive {
+    use std::fs::read;
+
+    use futures::stream::iter;
+
+    use crate::runner::{prepare_test, run, TestResult};
+    use file_store::backends::file::FileBackend;
+    use file_store::backends::Backend;
+    use file_store::*;
+
+    fn exclusive(path: &str) -> TestResult<UploadInfo> {
+        let mut info = UploadInfo::from(ObjectPath::new(path)?);
+        info.options.mode = WriteMode::FailIfExists;
+        Ok(info)
+    }
+
+    #[test]
+    fn test_fail_if_exists() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1")?;
+            let root = context.get_fs_root();
+            let original = read(root.join("smallfile.txt")).unwrap();
+            let fs = FileBackend::connect(&root).await?;
+
+            let data = || iter(vec![Ok::<_, StorageError>(vec![5u8; 20])]);
+            match fs
+                .write_file_from_stream(exclusive("smallfile.txt")?, data())
+                .await
+            {
+                Err(TransferError::TargetError(e)) => match e.kind() {
+                    StorageErrorKind::AlreadyExists(_) => (),
+                    kind => test_fail!("Unexpected error: {:?}", kind),
+                },
+                result => test_fail!("Should have failed to overwrite: {:?}", result),
+            }
+            test_assert_eq!(
+                read(root.join("smallfile.txt")).unwrap(),
+                original,
+                "Should not have changed the existing file."
+            );
+
+            match fs.copy_file("largefile", exclusive("dir2")?).await {
+                Err(TransferError::TargetError(e)) => match e.kind() {
+                    StorageErrorKind::AlreadyExists(_) => (),
+                    kind => test_fail!("Unexpected error: {:?}", kind),
+                },
+                result => test_fail!("Should have failed to replace a directory: {:?}", result),
+            }
+
+            fs.write_file_from_stream(exclusive("newfile")?, data())
+                .await?;
+            test_assert_eq!(
+                read(root.join("newfile")).unwrap(),
+                vec![5u8; 20],
+                "Should have written the new file."
+            );
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+mod same_file {
+    #[cfg(unix)]
+    use std::fs::hard_link;
+    use std::fs::read;
+
+    use crate::runner::{prepare_test, run, TestResult};
+    use file_store::backends::file::FileBackend;
+    use file_store::backends::Backend;
+    use file_store::*;
+
+    fn check_rejected(result: Result<(), TransferError>) -> TestResult<()> {
+        match result {
+            Err(TransferError::TargetError(e)) => match e.kind() {
+                StorageErrorKind::InvalidPath(_) => Ok(()),
+                kind => test_fail!("Unexpected error: {:?}", kind),
+            },
+            result => test_fail!("Should have refused to copy over the source: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_copy_over_self() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1")?;
+            let root = context.get_fs_root();
+            let original = read(root.join("smallfile.txt")).unwrap();
+            let fs = FileBackend::connect(&root).await?;
+
+            check_rejected(fs.copy_file("smallfile.txt", "smallfile.txt").await)?;
+            check_rejected(fs.move_file("smallfile.txt", "smallfile.txt").await)?;
+            test_assert_eq!(
+                read(root.join("smallfile.txt")).unwrap(),
+                original,
+                "Should not have changed the file."
+            );
+
+            // Only unix can identify hard links.
+            #[cfg(unix)]
+            {
+                hard_link(root.join("smallfile.txt"), root.join("linked")).unwrap();
+                check_rejected(fs.copy_file("smallfile.txt", "linked").await)?;
+                test_assert_eq!(
+                    read(root.join("smallfile.txt")).unwrap(),
+                    original,
+                    "Should not have changed the linked file."
+                );
+            }
+
+            fs.copy_file("smallfile.txt", "copied").await?;
+            test_assert_eq!(read(root.join("copied")).unwrap(), original);
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+mod durable {
+    use std::fs::read;
+
+    use futures::stream::iter;
+
+    use crate::runner::{prepare_test, run, TestResult};
+    use file_store::backends::file::FileBackend;
+    use file_store::backends::Backend;
+    use file_store::*;
+
+    fn durable(path: &str) -> TestResult<UploadInfo> {
+        let mut info = UploadInfo::from(ObjectPath::new(path)?);
+        info.options.durable = true;
+        Ok(info)
+    }
+
+    #[test]
+    fn test_durable_writes() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1")?;
+            let root = context.get_fs_root();
+            let fs = FileBackend::connect(&root).await?;
+
+            let data = iter(vec![Ok::<_, StorageError>(vec![5u8; 20])]);
+            fs.write_file_from_stream(durable("dir2/newfile")?, data)
+                .await?;
+            test_assert_eq!(
+                read(root.join("dir2").join("newfile")).unwrap(),
+                vec![5u8; 20],
+                "Should have written the file."
+            );
+
+            fs.copy_file("smallfile.txt", durable("dir2/copied")?)
+                .await?;
+            test_assert_eq!(
+                read(root.join("dir2").join("copied")).unwrap(),
+                read(root.join("smallfile.txt")).unwrap(),
+                "Should have copied the file."
+            );
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+#[cfg(unix)]
+mod space {
+    use std::fs::read;
+
+    use futures::stream::iter;
+
+    use crate::runner::{prepare_test, run, TestResult};
+    use file_store::backends::file::FileBackend;
+    use file_store::backends::Backend;
+    use file_store::*;
+
+    #[test]
+    fn test_insufficient_space() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1")?;
+            let root = context.get_fs_root();
+            let fs = FileBackend::connect(&root).await?;
+
+            let mut info = UploadInfo::from(ObjectPath::new("huge")?);
+            info.options.content_length = Some(u64::max_value());
+            let data = iter(vec![Ok::<_, StorageError>(vec![5u8; 20])]);
+            match fs.write_file_from_stream(info, data).await {
+                Err(TransferError::TargetError(e)) => match e.kind() {
+                    StorageErrorKind::InsufficientSpace => (),
+                    kind => test_fail!("Unexpected error: {:?}", kind),
+                },
+                result => test_fail!("Should have failed to write: {:?}", result),
+            }
+            test_assert!(
+                !root.join("huge").exists(),
+                "Should not have created the file."
+            );
+
+            let original = read(root.join("smallfile.txt")).unwrap();
+            let mut info = UploadInfo::from(ObjectPath::new("smallfile.txt")?);
+            info.options.content_length = Some(u64::max_value());
+            let data = iter(vec![Ok::<_, StorageError>(vec![5u8; 20])]);
+            match fs.write_file_from_stream(info, data).await {
+                Err(TransferError::TargetError(e)) => match e.kind() {
+                    StorageErrorKind::InsufficientSpace => (),
+                    kind => test_fail!("Unexpected error: {:?}", kind),
+                },
+                result => test_fail!("Should have failed to overwrite: {:?}", result),
+            }
+            test_assert_eq!(
+                read(root.join("smallfile.txt")).unwrap(),
+                original,
+                "Should have left the original file in place."
+            );
+
+            let mut info = UploadInfo::from(ObjectPath::new("small")?);
+            info.options.content_length = Some(20);
+            let data = iter(vec![Ok::<_, StorageError>(vec![5u8; 20])]);
+            fs.write_file_from_stream(info, data).await?;
+            test_assert!(root.join("small").exists(), "Should have written the file.");
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+mod sync {
+    use std::fs::{read, remove_file, write};
+    use std::time::{Duration, SystemTime};
+
+    use filetime::{set_file_mtime, FileTime};
+
+    use crate::runner::{prepare_test, run, TestError, TestResult};
+    use file_store::backends::file::FileBackend;
+    use file_store::backends::Backend;
+    use file_store::sync::{sync, two_way, Comparison, ConflictPolicy, SyncOptions, TwoWayOptions};
+    use file_store::*;
+
+    fn paths(paths: &[ObjectPath]) -> Vec<String> {
+        paths.iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn test_sync() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1")?;
+            let root = context.get_fs_root();
+            let fs = FileBackend::connect(&root).await?;
+            write(root.join("dir2").join("foo"), "Some data").unwrap();
+
+            let summary = sync(&fs, "dir2", &fs, "mirror", Default::default()).await?;
+            test_assert_eq!(summary.copied.len(), 8);
+            test_assert_eq!(summary.unchanged, 0);
+            test_assert_eq!(summary.bytes, 309);
+            test_assert_eq!(
+                read(root.join("mirror").join("foo")).unwrap(),
+                b"Some data".to_vec(),
+                "Should have copied the file."
+            );
+
+            let summary = sync(&fs, "dir2/", &fs, "mirror/", Default::default()).await?;
+            test_assert!(
+                summary.copied.is_empty(),
+                "Should not have copied anything."
+            );
+            test_assert_eq!(summary.unchanged, 8);
+
+            write(root.join("dir2").join("foo"), "Some news").unwrap();
+            write(root.join("mirror").join("extra"), "Extra").unwrap();
+            let options = SyncOptions {
+                comparison: Comparison::Size,
+                delete: true,
+                dry_run: true,
+                ..Default::default()
+            };
+            let summary = sync(&fs, "dir2", &fs, "mirror", options.clone()).await?;
+            test_assert!(summary.copied.is_empty(), "Should not have seen a change.");
+            test_assert_eq!(paths(&summary.deleted), vec!["extra"]);
+            test_assert!(
+                root.join("mirror").join("extra").exists(),
+                "Should not have deleted anything in a dry run."
+            );
+
+            let options = SyncOptions {
+                comparison: Comparison::Contents,
+                dry_run: false,
+                ..options
+            };
+            let summary = sync(&fs, "dir2", &fs, "mirror", options).await?;
+            test_assert_eq!(paths(&summary.copied), vec!["foo"]);
+            test_assert_eq!(paths(&summary.deleted), vec!["extra"]);
+            test_assert_eq!(
+                read(root.join("mirror").join("foo")).unwrap(),
+                b"Some news".to_vec(),
+                "Should have copied the changed file."
+            );
+            test_assert!(
+                !root.join("mirror").join("extra").exists(),
+                "Should have deleted the extra file."
+            );
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+
+    #[test]
+    fn test_two_way() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1")?;
+            let root = context.get_fs_root();
+            let fs = FileBackend::connect(&root).await?;
+            let first = root.join("dir2");
+            let second = root.join("other");
+            let older = FileTime::from_system_time(SystemTime::now() - Duration::from_secs(600));
+            let newer = FileTime::from_system_time(SystemTime::now() - Duration::from_secs(300));
+
+            let summary =
+                two_way(&fs, "dir2", &fs, "other", "dir2/.sync", Default::default()).await?;
+            test_assert_eq!(summary.to_second.len(), 8);
+            test_assert!(
+                summary.to_first.is_empty(),
+                "Should not have copied anything back."
+            );
+            test_assert!(
+                first.join(".sync").exists(),
+                "Should have saved the manifest."
+            );
+            test_assert!(
+                !second.join(".sync").exists(),
+                "Should not have synced the manifest."
+            );
+
+            let summary =
+                two_way(&fs, "dir2", &fs, "other", "dir2/.sync", Default::default()).await?;
+            test_assert!(
+                summary.to_first.is_empty(),
+                "Should not have copied anything."
+            );
+            test_assert!(
+                summary.to_second.is_empty(),
+                "Should not have copied anything."
+            );
+            test_assert_eq!(summary.unchanged, 8);
+
+            write(second.join("foo"), "Changed").unwrap();
+            write(second.join("new"), "New").unwrap();
+            remove_file(first.join("bar")).unwrap();
+            let summary =
+                two_way(&fs, "dir2", &fs, "other", "dir2/.sync", Default::default()).await?;
+            test_assert_eq!(paths(&summary.to_first), vec!["foo", "new"]);
+            test_assert_eq!(paths(&summary.deleted_second), vec!["bar"]);
+            test_assert_eq!(read(first.join("foo")).unwrap(), b"Changed".to_vec());
+            test_assert!(
+                !second.join("bar").exists(),
+                "Should have deleted the file."
+            );
+
+            write(first.join("hop"), "First").unwrap();
+            set_file_mtime(first.join("hop"), older).unwrap();
+            write(second.join("hop"), "Second").unwrap();
+            set_file_mtime(second.join("hop"), newer).unwrap();
+            let options = TwoWayOptions {
+                conflicts: ConflictPolicy::NewerWins,
+                ..Default::default()
+            };
+            let summary = two_way(&fs, "dir2", &fs, "other", "dir2/.sync", options).await?;
+            test_assert_eq!(paths(&summary.conflicts), vec!["hop"]);
+            test_assert_eq!(paths(&summary.to_first), vec!["hop"]);
+            test_assert_eq!(read(first.join("hop")).unwrap(), b"Second".to_vec());
+
+            write(first.join("yu"), "One").unwrap();
+            write(second.join("yu"), "Three").unwrap();
+            let summary =
+                two_way(&fs, "dir2", &fs, "other", "dir2/.sync", Default::default()).await?;
+            test_assert_eq!(paths(&summary.conflicts), vec!["yu"]);
+            test_assert_eq!(paths(&summary.to_first), vec!["yu (conflicted copy)"]);
+            test_assert_eq!(paths(&summary.to_second), vec!["yu"]);
+            test_assert_eq!(read(second.join("yu")).unwrap(), b"One".to_vec());
+            test_assert_eq!(
+                read(first.join("yu (conflicted copy)")).unwrap(),
+                b"Three".to_vec()
+            );
+            test_assert_eq!(
+                read(second.join("yu (conflicted copy)")).unwrap(),
+                b"Three".to_vec()
+            );
+
+            write(first.join("5diz"), "A").unwrap();
+            write(second.join("5diz"), "Bb").unwrap();
+            let options = TwoWayOptions {
+                conflicts: ConflictPolicy::Error,
+                ..Default::default()
+            };
+            match two_way(&fs, "dir2", &fs, "other", "dir2/.sync", options).await {
+                Ok(_) => test_fail!("Should have failed to sync a conflict."),
+                Err(TransferError::TargetError(e)) => match e.kind() {
+                    StorageErrorKind::AlreadyExists(path) => {
+                        test_assert_eq!(path.to_string(), "other/5diz")
+                    }
+                    kind => test_fail!("Unexpected error: {:?}", kind),
+                },
+                Err(e) => return Err(TestError::UnexpectedTransferError(e)),
+            }
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+mod times {
+    use std::convert::TryFrom;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use filetime::{set_file_mtime, FileTime};
+
+    use crate::runner::{prepare_test, run, TestResult};
+    use file_store::backends::file::FileBackend;
+    use file_store::backends::Backend;
+    use file_store::*;
+
+    #[test]
+    fn test_times() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1")?;
+            let root = context.get_fs_root();
+            let modified = UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+            set_file_mtime(
+                root.join("smallfile.txt"),
+                FileTime::from_system_time(modified),
+            )
+            .unwrap();
+            let fs = FileBackend::connect(&root).await?;
+
+            let object = fs.get_object("smallfile.txt").await?;
+            let metadata = root.join("smallfile.txt").metadata().unwrap();
+            test_assert_eq!(
+                object.accessed(),
+                metadata.accessed().ok(),
+                "Should have reported the access time."
+            );
+            test_assert_eq!(
+                object.created(),
+                metadata.created().ok(),
+                "Should have reported the creation time."
+            );
+
+            fs.copy_file("smallfile.txt", "plaincopy").await?;
+            let copy = fs.get_object("plaincopy").await?;
+            test_assert!(
+                copy.modified() != Some(modified),
+                "Should not have kept the modification time."
+            );
+
+            let mut info = UploadInfo::try_from("preserved")?;
+            info.options.preserve_modified = true;
+            fs.copy_file("smallfile.txt", info).await?;
+            let copy = fs.get_object("preserved").await?;
+            test_assert_eq!(
+                copy.modified(),
+                Some(modified),
+                "Should have kept the modification time."
+            );
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+#[cfg(unix)]
+mod permissions {
+    use std::convert::TryInto;
+    use std::fs::{metadata, set_permissions, Permissions};
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    use crate::runner::{prepare_test, run, TestError, TestResult};
+    use file_store::backends::file::FileBackend;
+    use file_store::backends::Backend;
+    use file_store::*;
+
+    #[test]
+    fn test_permissions() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1")?;
+            let root = context.get_fs_root();
+            let target = root.join("smallfile.txt");
+            set_permissions(&target, Permissions::from_mode(0o640)).unwrap();
+            let fs: FileBackend = FileBackend::connect(&root).await?.try_into().map_err(|_| {
+                TestError::HarnessFailure(String::from("Should have built a file backend."))
+            })?;
+
+            let object = match fs.get_object("smallfile.txt").await? {
+                Object::File(object) => object,
+                object => test_fail!("Unexpected object: {:?}", object),
+            };
+            let expected = metadata(&target).unwrap();
+            test_assert_eq!(object.mode(), Some(0o640), "Should have read the mode.");
+            test_assert_eq!(
+                object.uid(),
+                Some(expected.uid()),
+                "Should have read the uid."
+            );
+            test_assert_eq!(
+                object.gid(),
+                Some(expected.gid()),
+                "Should have read the gid."
+            );
+
+            fs.set_permissions("smallfile.txt", 0o755).await?;
+            test_assert_eq!(
+                metadata(&target).unwrap().mode() & 0o7777,
+                0o755,
+                "Should have changed the mode."
+            );
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+mod trash {
+    use futures::stream::TryStreamExt;
+
+    use crate::runner::{prepare_test, run, TestResult};
+    use file_store::backends::file::FileBackend;
+    use file_store::backends::Backend;
+    use file_store::trash::Trash;
+    use file_store::*;
+
+    #[test]
+    fn test_trash() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1")?;
+            let root = context.get_fs_root();
+            let fs = FileBackend::builder(&root)
+                .trash(ObjectPath::new("trash")?)
+                .connect()
+                .await?;
+            let trash = Trash::new(fs.clone(), ObjectPath::new("trash")?)?;
+
+            fs.delete_object("smallfile.txt").await?;
+            fs.delete_object("dir2").await?;
+            test_assert!(
+                !root.join("smallfile.txt").exists(),
+                "Should have moved the file."
+            );
+            test_assert!(
+                !root.join("dir2").exists(),
+                "Should have moved the directory."
+            );
+
+            let objects: Vec<Object> = fs.list_objects("").await?.try_collect().await?;
+            test_assert!(
+                !objects
+                    .iter()
+                    .any(|o| o.path().to_string().starts_with("trash")),
+                "Should not have listed the trash."
+            );
+
+            let trashed = trash.list().await?;
+            let mut paths: Vec<String> = trashed.iter().map(|o| o.path().to_string()).collect();
+            paths.sort();
+            test_assert_eq!(paths, vec!["dir2", "smallfile.txt"]);
+
+            let file = match trashed
+                .iter()
+                .find(|o| o.path().to_string() == "smallfile.txt")
+            {
+                Some(o) => o,
+                None => test_fail!("Should have found the trashed file."),
+            };
+            test_assert_eq!(file.object_type(), ObjectType::File);
+            trash.restore(file).await?;
+            test_assert!(
+                root.join("smallfile.txt").exists(),
+                "Should have restored the file."
+            );
+
+            trash.purge(None).await?;
+            test_assert!(
+                trash.list().await?.is_empty(),
+                "Should have purged the trash."
+            );
+            test_assert!(
+                !root.join("dir2").exists(),
+                "Should not have restored the directory."
+            );
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+
+    #[test]
+    fn test_trash_store() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1")?;
+            let root = context.get_fs_root();
+            let trash = Trash::new(
+                FileBackend::connect(&root).await?,
+                ObjectPath::new("trash")?,
+            )?;
+            let fs = trash.store();
+
+            fs.delete_object("dir2/daz").await?;
+            test_assert!(
+                !root.join("dir2/daz").exists(),
+                "Should have moved the file."
+            );
+
+            let trashed = trash.list().await?;
+            test_assert_eq!(trashed.len(), 1);
+            test_assert_eq!(trashed[0].path().to_string(), "dir2/daz");
+
+            trash.restore(&trashed[0]).await?;
+            test_assert!(
+                root.join("dir2/daz").exists(),
+                "Should have restored the file."
+            );
+            test_assert!(
+                trash.list().await?.is_empty(),
+                "Should have emptied the trash."
+            );
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+mod observed {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use futures::stream::{iter, TryStreamExt};
+
+    use crate::runner::{prepare_test, run, TestContext, TestResult};
+    use file_store::backends::file::FileBackend;
+    use file_store::backends::Backend;
+    use file_store::observe::{Observer, OperationInfo, OperationKind};
+    use file_store::*;
+
+    #[derive(Clone, Debug, PartialEq)]
+    enum Event {
+        Start(OperationKind, String),
+        Bytes(OperationKind, u64),
+        Complete(OperationKind),
+        Error(OperationKind),
+    }
+
+    #[derive(Clone, Default)]
+    struct Recorder {
+        events: Arc<Mutex<Vec<Event>>>,
+    }
+
+    impl Recorder {
+        fn take(&self) -> Vec<Event> {
+            self.events.lock().unwrap().drain(..).collect()
+        }
+
+        fn push(&self, event: Event) {
+            self.events.lock().unwrap().push(event);
+        }
+    }
+
+    impl Observer for Recorder {
+        fn on_operation_start(&self, operation: &OperationInfo) {
+            self.push(Event::Start(operation.kind, operation.path.to_string()));
+        }
+
+        fn on_bytes_transferred(&self, operation: &OperationInfo, bytes: u64) {
+            self.push(Event::Bytes(operation.kind, bytes));
+        }
+
+        fn on_operation_complete(&self, operation: &OperationInfo, _duration: Duration) {
+            self.push(Event::Complete(operation.kind));
+        }
+
+        fn on_operation_error(&self, operation: &OperationInfo, _error: &StorageError) {
+            self.push(Event::Error(operation.kind));
+        }
+    }
+
+    struct Ignore;
+
+    impl Observer for Ignore {}
+
+    async fn build_fs(context: &TestContext) -> TestResult<(FileStore, ())> {
+        let fs = FileBackend::connect(&context.get_fs_root()).await?;
+        Ok((fs.observe(Ignore), ()))
+    }
+
+    async fn cleanup(_: ()) -> TestResult<()> {
+        Ok(())
+    }
+
+    build_tests!("test1", Backend::File, build_fs, cleanup);
+
+    #[test]
+    fn test_events() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1")?;
+            let recorder = Recorder::default();
+            let fs = FileBackend::connect(&context.get_fs_root())
+                .await?
+                .observe(recorder.clone());
+
+            let data: Vec<u8> = fs
+                .get_file_stream("smallfile.txt")
+                .await?
+                .map_ok(|data| data.to_vec())
+                .try_concat()
+                .await?;
+            let mut events = recorder.take();
+            let bytes: u64 = events
+                .iter()
+                .map(|event| match event {
+                    Event::Bytes(OperationKind::GetFileStream, bytes) => *bytes,
+                    _ => 0,
+                })
+                .sum();
+            test_assert_eq!(
+                bytes,
+                data.len() as u64,
+                "Should have reported the bytes read."
+            );
+            events.retain(|event| match event {
+                Event::Bytes(_, _) => false,
+                _ => true,
+            });
+            test_assert_eq!(
+                events,
+                vec![
+                    Event::Start(OperationKind::GetFileStream, "smallfile.txt".to_owned()),
+                    Event::Complete(OperationKind::GetFileStream),
+                ]
+            );
+
+            let chunks = vec![Ok::<_, StorageError>(vec![5u8; 20]), Ok(vec![6u8; 10])];
+            fs.write_file_from_stream("newfile", iter(chunks)).await?;
+            test_assert_eq!(
+                recorder.take(),
+                vec![
+                    Event::Start(OperationKind::WriteFile, "newfile".to_owned()),
+                    Event::Bytes(OperationKind::WriteFile, 20),
+                    Event::Bytes(OperationKind::WriteFile, 10),
+                    Event::Complete(OperationKind::WriteFile),
+                ]
+            );
+
+            test_assert!(fs.delete_object("nothere").await.is_err());
+            test_assert_eq!(
+                recorder.take(),
+                vec![
+                    Event::Start(OperationKind::DeleteObject, "nothere".to_owned()),
+                    Event::Error(OperationKind::DeleteObject),
+                ]
+            );
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+#[cfg(feature = "transfers")]
+mod transfers {
+    use futures::stream::StreamExt;
+
+    use crate::runner::{prepare_test, run, TestError, TestResult};
+    use file_store::backends::file::FileBackend;
+    use file_store::backends::Backend;
+    use file_store::transfers::*;
+    use file_store::*;
+
+    /// Collects events, ignoring progress, until the job has the given event.
+    async fn until(
+        events: &mut JobEvents,
+        job: JobId,
+        kind: JobEventKind,
+    ) -> TestResult<Vec<JobEvent>> {
+        let mut seen = Vec::new();
+        while let Some(event) = events.next().await {
+            if let JobEventKind::Progress(_) = event.kind {
+                continue;
+            }
+
+            let done = event.job == job && event.kind == kind;
+            seen.push(event);
+            if done {
+                return Ok(seen);
+            }
+        }
+
+        Err(TestError::TestFailure(String::from(
+            "Events ended unexpectedly.",
+        )))
+    }
+
+    #[test]
+    fn test_transfer_queue() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1")?;
+            let fs = FileBackend::connect(&context.get_fs_root()).await?;
+
+            let job = |name: &str, priority: i32| -> TestResult<TransferJob> {
+                Ok(TransferJob::new(
+                    fs.clone(),
+                    ObjectPath::new(format!("dir2/{}", name))?,
+                    fs.clone(),
+                    ObjectPath::new(format!("copies/{}", name))?,
+                )
+                .priority(priority))
+            };
+
+            let manager = TransferManager::new();
+            manager.set_concurrency(0);
+            manager.set_bandwidth(Some(1_000_000));
+            let mut events = manager.events();
+
+            let low = manager.add(job("foo", 0)?);
+            let high = manager.add(job("daz", 5)?);
+            let paused = manager.add(job("bar", 5)?);
+            let cancelled = manager.add(job("hop", 1)?);
+            manager.pause(paused);
+            manager.cancel(cancelled);
+            test_assert_eq!(manager.status(low), Some(JobStatus::Queued));
+            test_assert_eq!(manager.status(paused), Some(JobStatus::Paused));
+            test_assert_eq!(manager.status(cancelled), None);
+
+            manager.set_concurrency(1);
+            let seen = until(&mut events, low, JobEventKind::Completed).await?;
+            let expected = vec![
+                (low, JobEventKind::Queued),
+                (high, JobEventKind::Queued),
+                (paused, JobEventKind::Queued),
+                (cancelled, JobEventKind::Queued),
+                (paused, JobEventKind::Paused),
+                (cancelled, JobEventKind::Cancelled),
+                (high, JobEventKind::Started),
+                (high, JobEventKind::Completed),
+                (low, JobEventKind::Started),
+                (low, JobEventKind::Completed),
+            ];
+            test_assert_eq!(
+                seen.into_iter()
+                    .map(|e| (e.job, e.kind))
+                    .collect::<Vec<(JobId, JobEventKind)>>(),
+                expected
+            );
+            test_assert_eq!(manager.status(paused), Some(JobStatus::Paused));
+
+            manager.resume(paused);
+            until(&mut events, paused, JobEventKind::Completed).await?;
+            test_assert_eq!(manager.status(paused), None);
+
+            test_assert_eq!(fs.get_object("copies/daz").await?.len(), 300);
+            test_assert_eq!(fs.get_object("copies/bar").await?.len(), 0);
+            test_assert!(
+                fs.get_object("copies/hop").await.is_err(),
+                "Should not have copied the cancelled job."
+            );
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+mod chunked {
+    use std::fs::read;
+
+    use futures::stream::{iter, TryStreamExt};
+
+    use crate::runner::{prepare_test, run, TestResult};
+    use file_store::backends::file::FileBackend;
+    use file_store::backends::Backend;
+    use file_store::chunked::ChunkedStore;
+    use file_store::*;
+
+    async fn read_all(fs: &FileStore, path: &str) -> TestResult<Vec<u8>> {
+        Ok(fs
+            .get_file_stream(path)
+            .await?
+            .map_ok(|data| data.to_vec())
+            .try_concat()
+            .await?)
+    }
+
+    #[test]
+    fn test_chunked() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1")?;
+            let root = context.get_fs_root();
+            let chunked = ChunkedStore::new(
+                FileBackend::connect(&root).await?,
+                ObjectPath::new("chunks")?,
+                100,
+            )?;
+            let fs = chunked.store();
+
+            let data: Vec<u8> = (0..250).map(|i| i as u8).collect();
+            let pieces: Vec<StorageResult<Vec<u8>>> =
+                data.chunks(30).map(|c| Ok(c.to_vec())).collect();
+            fs.write_file_from_stream("large", iter(pieces)).await?;
+
+            test_assert!(
+                read(root.join("large")).unwrap().len() < 250,
+                "Should have written a manifest."
+            );
+            test_assert_eq!(fs.get_object("large").await?.len(), 250);
+            test_assert_eq!(read_all(&fs, "large").await?, data);
+
+            let objects: Vec<Object> = fs.list_objects("").await?.try_collect().await?;
+            test_assert!(
+                !objects
+                    .iter()
+                    .any(|o| o.path().to_string().starts_with("chunks")),
+                "Should not have listed the chunks."
+            );
+            match objects.iter().find(|o| o.path().to_string() == "large") {
+                Some(o) => test_assert_eq!(o.len(), 250),
+                None => test_fail!("Should have listed the file."),
+            }
+
+            fs.copy_file("large", "copied").await?;
+            test_assert_eq!(read_all(&fs, "copied").await?, data);
+
+            let chunks = vec![Ok::<_, StorageError>(b"Now a small file.".to_vec())];
+            fs.write_file_from_stream("large", iter(chunks)).await?;
+            test_assert_eq!(
+                read(root.join("large")).unwrap(),
+                b"Now a small file.".to_vec()
+            );
+            test_assert_eq!(fs.get_object("large").await?.len(), 17);
+            test_assert!(
+                !root.join("chunks").join("large").exists(),
+                "Should have removed the old chunks."
+            );
+
+            test_assert_eq!(
+                read_all(&fs, "smallfile.txt").await?,
+                b"This is quite a short file.".to_vec()
+            );
+
+            fs.delete_object("copied").await?;
+            test_assert!(
+                !root.join("copied").exists(),
+                "Should have deleted the manifest."
+            );
+            test_assert!(
+                !root.join("chunks").join("copied").exists(),
+                "Should have deleted the chunks."
+            );
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+#[cfg(feature = "lock")]
+mod lock {
+    use std::fs::{create_dir_all, read_dir, write};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use futures::future::join_all;
+    use futures::stream::StreamExt;
+
+    use crate::runner::{prepare_test, run, TestResult};
+    use file_store::backends::file::FileBackend;
+    use file_store::backends::Backend;
+    use file_store::lock::LockManager;
+    use file_store::*;
+
+    #[test]
+    fn test_lock() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1")?;
+            let root = context.get_fs_root();
+            let fs = FileBackend::connect(&root).await?;
+            let locks = ObjectPath::new("locks")?;
+            let first =
+                LockManager::new(fs.clone(), locks.clone(), "first", Duration::from_secs(60))?;
+            let second = LockManager::new(fs.clone(), locks, "second", Duration::from_millis(50))?;
+            let prefix = ObjectPath::new("dir2")?;
+
+            let lease = first.try_acquire(prefix.clone()).await?;
+            test_assert!(
+                root.join("locks").join("dir2.lock").exists(),
+                "Should have written the lock."
+            );
+            match second.try_acquire(prefix.clone()).await {
+                Err(e) => test_assert_eq!(
+                    e.kind(),
+                    StorageErrorKind::AlreadyExists(lease.path().clone())
+                ),
+                Ok(_) => test_fail!("Should not have acquired a held lock."),
+            }
+            match second.holder(prefix.clone()).await? {
+                Some(holder) => test_assert_eq!(holder.owner(), "first"),
+                None => test_fail!("Should have found the holder."),
+            }
+
+            let expires = lease.expires();
+            test_assert!(lease.renew().await? >= expires);
+
+            lease.release().await?;
+            test_assert_eq!(first.holder(prefix.clone()).await?, None);
+
+            let lease = second.try_acquire(prefix.clone()).await?;
+            let mut heartbeat = lease.heartbeat(Duration::from_millis(10));
+            test_assert!(heartbeat.next().await.transpose()?.is_some());
+            drop(heartbeat);
+
+            sleep(Duration::from_millis(100));
+            let taken = first.try_acquire(prefix.clone()).await?;
+            match lease.renew().await {
+                Err(e) => test_assert_eq!(e.kind(), StorageErrorKind::AccessExpired),
+                Ok(_) => test_fail!("Should not have renewed an expired lease."),
+            }
+
+            lease.release().await?;
+            match first.holder(prefix).await? {
+                Some(holder) => test_assert_eq!(holder.owner(), "first"),
+                None => test_fail!("Should not have released the lock taken over."),
+            }
+            taken.release().await?;
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+
+    #[test]
+    fn test_concurrent_acquire() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1")?;
+            let root = context.get_fs_root();
+            let fs = FileBackend::connect(&root).await?;
+            let locks = ObjectPath::new("locks")?;
+            let prefix = ObjectPath::new("dir2")?;
+
+            // An exclusively created lock is empty until its data is written.
+            create_dir_all(root.join("locks")).unwrap();
+            write(root.join("locks").join("dir2.lock"), b"").unwrap();
+            let manager =
+                LockManager::new(fs.clone(), locks.clone(), "first", Duration::from_secs(60))?;
+            match manager.try_acquire(prefix.clone()).await {
+                Err(e) => test_assert_eq!(
+                    e.kind(),
+                    StorageErrorKind::AlreadyExists(ObjectPath::new("locks/dir2.lock")?)
+                ),
+                Ok(_) => test_fail!("Should not have acquired a lock being written."),
+            }
+            fs.delete_object("locks/dir2.lock").await?;
+
+            let holders = Arc::new(AtomicUsize::new(0));
+            let mut workers = Vec::new();
+            for index in 0..8 {
+                let manager = LockManager::new(
+                    fs.clone(),
+                    locks.clone(),
+                    &format!("worker{}", index),
+                    Duration::from_secs(60),
+                )?;
+                let prefix = prefix.clone();
+                let holders = holders.clone();
+                workers.push(async move {
+                    let lease = manager.acquire(prefix, Duration::from_millis(5)).await?;
+                    test_assert_eq!(
+                        holders.fetch_add(1, Ordering::SeqCst),
+                        0,
+                        "Only one worker should hold the lock."
+                    );
+                    for _ in 0..3 {
+                        lease.renew().await?;
+                    }
+                    holders.fetch_sub(1, Ordering::SeqCst);
+                    lease.release().await?;
+                    TestResult::<()>::Ok(())
+                });
+            }
+
+            for result in join_all(workers).await {
+                result?;
+            }
+
+            test_assert_eq!(manager.holder(prefix).await?, None);
+            test_assert_eq!(
+                read_dir(root.join("locks")).unwrap().count(),
+                0,
+                "Should not have left temporary locks behind."
+            );
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+#[cfg(feature = "changes")]
+mod changes {
+    use std::fs::{remove_file, write};
+    use std::path::{Path, PathBuf};
+    use std::thread::{sleep, spawn};
+    use std::time::Duration;
+
+    use futures::stream::StreamExt;
+
+    use crate::runner::{prepare_test, run, TestResult};
+    use file_store::backends::file::FileBackend;
+    use file_store::backends::Backend;
+    use file_store::changes::*;
+    use file_store::*;
+
+    fn later<F>(change: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        spawn(move || {
+            sleep(Duration::from_millis(50));
+            change();
+        });
+    }
+
+    async fn next(changes: &mut ChangeStream) -> TestResult<(String, ChangeKind)> {
+        match changes.next().await {
+            Some(change) => {
+                let change = change?;
+                Ok((change.path().to_string(), change.kind()))
+            }
+            None => test_fail!("Should not have ended the changes."),
+        }
+    }
+
+    fn file(root: &Path, name: &str) -> PathBuf {
+        root.join("dir2").join(name)
+    }
+
+    #[test]
+    fn test_polling() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1")?;
+            let root = context.get_fs_root();
+            let fs = FileBackend::connect(&root).await?;
+            let feed = PollingFeed::new(fs.clone(), Duration::from_millis(10))
+                .cursors(fs.clone(), ObjectPath::new("cursors")?);
+
+            let mut changes = feed.changes(ObjectPath::new("dir2")?);
+            let path = file(&root, "new");
+            later(move || write(path, "Some data").unwrap());
+            test_assert_eq!(
+                next(&mut changes).await?,
+                ("dir2/new".to_owned(), ChangeKind::Created)
+            );
+
+            let path = file(&root, "foo");
+            later(move || remove_file(path).unwrap());
+            test_assert_eq!(
+                next(&mut changes).await?,
+                ("dir2/foo".to_owned(), ChangeKind::Deleted)
+            );
+            drop(changes);
+
+            write(file(&root, "other"), "Some data").unwrap();
+            let mut changes = feed.changes(ObjectPath::new("dir2")?);
+            test_assert_eq!(
+                next(&mut changes).await?,
+                ("dir2/foo".to_owned(), ChangeKind::Deleted),
+                "Should have repeated the change taken before the cursor was saved."
+            );
+            test_assert_eq!(
+                next(&mut changes).await?,
+                ("dir2/other".to_owned(), ChangeKind::Created)
+            );
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_watch() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1")?;
+            let root = context.get_fs_root();
+            let fs = FileBackend::connect(&root).await?;
+
+            let mut changes = WatchFeed::new(&fs)?.changes(ObjectPath::new("dir2")?);
+            write(file(&root, "new"), "Some data").unwrap();
+            test_assert_eq!(
+                next(&mut changes).await?,
+                ("dir2/new".to_owned(), ChangeKind::Created)
+            );
+            test_assert_eq!(
+                next(&mut changes).await?,
+                ("dir2/new".to_owned(), ChangeKind::Modified)
+            );
+
+            remove_file(file(&root, "foo")).unwrap();
+            test_assert_eq!(
+                next(&mut changes).await?,
+                ("dir2/foo".to_owned(), ChangeKind::Deleted)
+            );
+
+            write(root.join("smallfile.txt"), "Outside").unwrap();
+            remove_file(file(&root, "bar")).unwrap();
+            test_assert_eq!(
+                next(&mut changes).await?,
+                ("dir2/bar".to_owned(), ChangeKind::Deleted),
+                "Should not have reported changes outside of the prefix."
+            );
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+mod tags {
+    use futures::stream::TryStreamExt;
+
+    use crate::runner::{prepare_test, run, TestResult};
+    use file_store::backends::file::FileBackend;
+    use file_store::backends::Backend;
+    use file_store::tags::{TagStore, Tags};
+    use file_store::*;
+
+    async fn tagged(tags: &TagStore, key: &str, value: Option<&str>) -> TestResult<Vec<String>> {
+        let objects: Vec<Object> = tags
+            .list_tagged(ObjectPath::empty(), key, value)
+            .await?
+            .try_collect()
+            .await?;
+        let mut paths: Vec<String> = objects.iter().map(|o| o.path().to_string()).collect();
+        paths.sort();
+        Ok(paths)
+    }
+
+    #[test]
+    fn test_tags() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1")?;
+            let root = context.get_fs_root();
+            let tags = TagStore::new(FileBackend::connect(&root).await?, ObjectPath::new("tags")?)?;
+            let fs = tags.store();
+
+            test_assert!(tags
+                .get_tags(ObjectPath::new("dir2/foo")?)
+                .await?
+                .is_empty());
+
+            let mut set = Tags::new();
+            set.insert("colour".to_owned(), "red".to_owned());
+            set.insert("size".to_owned(), "small".to_owned());
+            tags.set_tags(ObjectPath::new("dir2/foo")?, set.clone())
+                .await?;
+            let mut other = Tags::new();
+            other.insert("colour".to_owned(), "blue".to_owned());
+            tags.set_tags(ObjectPath::new("smallfile.txt")?, other)
+                .await?;
+            test_assert_eq!(tags.get_tags(ObjectPath::new("dir2/foo")?).await?, set);
+
+            test_assert_eq!(
+                tagged(&tags, "colour", None).await?,
+                vec!["dir2/foo", "smallfile.txt"]
+            );
+            test_assert_eq!(
+                tagged(&tags, "colour", Some("red")).await?,
+                vec!["dir2/foo"]
+            );
+            test_assert!(tagged(&tags, "shape", None).await?.is_empty());
+
+            let mut invalid = Tags::new();
+            invalid.insert("a=b".to_owned(), "c".to_owned());
+            test_assert!(
+                tags.set_tags(ObjectPath::new("dir2/foo")?, invalid)
+                    .await
+                    .is_err(),
+                "Should not have allowed an invalid key."
+            );
+            test_assert!(
+                tags.set_tags(ObjectPath::new("missing")?, set.clone())
+                    .await
+                    .is_err(),
+                "Should not have tagged a missing file."
+            );
+
+            fs.move_file("dir2/foo", "dir2/moved").await?;
+            test_assert_eq!(tags.get_tags(ObjectPath::new("dir2/moved")?).await?, set);
+            test_assert_eq!(
+                tagged(&tags, "colour", Some("red")).await?,
+                vec!["dir2/moved"]
+            );
+
+            let objects: Vec<Object> = fs.list_objects("").await?.try_collect().await?;
+            test_assert!(
+                !objects
+                    .iter()
+                    .any(|o| o.path().to_string().starts_with("tags")),
+                "Should not have listed the tags."
+            );
+
+            fs.write_file_from_stream(
+                "dir2/moved",
+                futures::stream::iter(vec![Ok::<_, StorageError>(b"New".to_vec())]),
+            )
+            .await?;
+            test_assert!(
+                tags.get_tags(ObjectPath::new("dir2/moved")?)
+                    .await?
+                    .is_empty(),
+                "Should have cleared the tags of a rewritten file."
+            );
+
+            fs.delete_object("smallfile.txt").await?;
+            test_assert!(tagged(&tags, "colour", None).await?.is_empty());
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+mod keys {
+    use std::fs::read_to_string;
+
+    use futures::stream::{iter, TryStreamExt};
+
+    use crate::runner::{prepare_test, run, TestResult};
+    use file_store::backends::file::FileBackend;
+    use file_store::backends::Backend;
+    use file_store::keys::{Keys, StaticKeys};
+    use file_store::*;
+
+    #[test]
+    fn test_keys() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1")?;
+            let root = context.get_fs_root();
+            let fs = FileBackend::connect(&root).await?;
+            let first = CustomerKey::new(&[1; 32])?;
+            let second = CustomerKey::new(&[2; 32])?;
+
+            let old = StaticKeys::new("first", first.clone())?;
+            let keys = Keys::new(fs.clone(), ObjectPath::new("keys")?, old)?;
+            let chunks = vec![Ok::<_, StorageError>(b"Secret data".to_vec())];
+            keys.store()
+                .write_file_from_stream("dir2/secret", iter(chunks))
+                .await?;
+            test_assert!(read_to_string(root.join("keys").join("dir2%2Fsecret"))
+                .unwrap()
+                .contains("first"));
+
+            let new = StaticKeys::new("first", first)?.rotate("second", second)?;
+            let keys = Keys::new(fs.clone(), ObjectPath::new("keys")?, new)?;
+            test_assert!(keys.rotate(ObjectPath::new("other")?).await?.is_empty());
+            test_assert_eq!(
+                keys.rotate(ObjectPath::empty()).await?,
+                vec![ObjectPath::new("dir2/secret")?]
+            );
+            test_assert!(read_to_string(root.join("keys").join("dir2%2Fsecret"))
+                .unwrap()
+                .contains("second"));
+            test_assert!(keys.rotate(ObjectPath::empty()).await?.is_empty());
+
+            let store = keys.store();
+            let data = store
+                .get_file_stream("dir2/secret")
+                .await?
+                .map_ok(|d| d.to_vec())
+                .try_concat()
+                .await?;
+            test_assert_eq!(data, b"Secret data".to_vec());
+
+            let objects: Vec<Object> = store.list_objects("").await?.try_collect().await?;
+            test_assert!(
+                !objects
+                    .iter()
+                    .any(|o| o.path().to_string().starts_with("keys")),
+                "Should not have listed the keys."
+            );
+
+            store.delete_object("dir2/secret").await?;
+            test_assert!(
+                !root.join("keys").join("dir2%2Fsecret").exists(),
+                "Should have deleted the key."
+            );
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+mod cache {
+    use std::fs::create_dir;
+
+    use futures::stream::{iter, TryStreamExt};
+
+    use crate::runner::{prepare_test, run, TestResult};
+    use file_store::backends::file::FileBackend;
+    use file_store::backends::Backend;
+    use file_store::cache::{Cache, WritePolicy};
+    use file_store::*;
+
+    async fn read_all(fs: &FileStore, path: &str) -> TestResult<Vec<u8>> {
+        Ok(fs
+            .get_file_stream(path)
+            .await?
+            .map_ok(|data| data.to_vec())
+            .try_concat()
+            .await?)
+    }
+
+    async fn write(fs: &FileStore, path: &str, data: Vec<u8>) -> TestResult<()> {
+        fs.write_file_from_stream(path, iter(vec![Ok::<Vec<u8>, StorageError>(data)]))
+            .await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_cache() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1")?;
+            let root = context.get_fs_root();
+            let cache_root = root.with_file_name("cache");
+            create_dir(&cache_root).unwrap();
+
+            let origin = FileBackend::connect(&root).await?;
+            let cache = Cache::new(
+                origin.clone(),
+                FileBackend::connect(&cache_root).await?,
+                350,
+            );
+            let fs = cache.store();
+
+            let original = b"This is quite a short file.".to_vec();
+            test_assert_eq!(read_all(&fs, "smallfile.txt").await?, original);
+            test_assert_eq!(read_all(&fs, "smallfile.txt").await?, original);
+            test_assert!(
+                cache_root.join("smallfile.txt").is_file(),
+                "Should have copied the file into the cache."
+            );
+            let stats = cache.stats();
+            test_assert_eq!(stats.hits, 1);
+            test_assert_eq!(stats.misses, 1);
+            test_assert_eq!(stats.files, 1);
+            test_assert_eq!(stats.size, 27);
+
+            // Changes made elsewhere are only seen once invalidated.
+            write(&origin, "smallfile.txt", b"Changed".to_vec()).await?;
+            test_assert_eq!(read_all(&fs, "smallfile.txt").await?, original);
+            cache.invalidate(ObjectPath::new("smallfile.txt")?).await?;
+            test_assert_eq!(read_all(&fs, "smallfile.txt").await?, b"Changed".to_vec());
+
+            cache.pin(ObjectPath::new("smallfile.txt")?).await?;
+            test_assert_eq!(read_all(&fs, "dir2/daz").await?.len(), 300);
+            write(&fs, "written", vec![7; 100]).await?;
+            test_assert_eq!(read_all(&origin, "written").await?, vec![7; 100]);
+
+            let stats = cache.stats();
+            test_assert_eq!(stats.evictions, 1);
+            test_assert_eq!(stats.pinned, 1);
+            test_assert!(
+                !cache_root.join("dir2/daz").exists(),
+                "Should have evicted the least recently used file."
+            );
+            test_assert!(
+                cache_root.join("smallfile.txt").is_file(),
+                "Should have kept the pinned file."
+            );
+
+            cache.set_policy(WritePolicy::WriteBack);
+            write(&fs, "later", b"Later".to_vec()).await?;
+            test_assert!(
+                origin.get_object("later").await.is_err(),
+                "Should not have written the file yet."
+            );
+            test_assert_eq!(fs.get_object("later").await?.len(), 5);
+            test_assert_eq!(cache.stats().dirty, 1);
+
+            cache.invalidate_prefix(ObjectPath::empty()).await?;
+            test_assert_eq!(cache.stats().files, 1);
+            test_assert_eq!(read_all(&fs, "later").await?, b"Later".to_vec());
+
+            test_assert_eq!(cache.flush().await?, vec![ObjectPath::new("later")?]);
+            test_assert_eq!(read_all(&origin, "later").await?, b"Later".to_vec());
+            test_assert_eq!(cache.stats().dirty, 0);
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+mod walk {
+    use std::fs::create_dir;
+    use std::time::{Duration, SystemTime};
+
+    use futures::stream::TryStreamExt;
+
+    use crate::runner::{prepare_test, run, TestResult};
+    use file_store::backends::file::FileBackend;
+    use file_store::backends::Backend;
+    use file_store::walk::{Glob, Walk};
+    use file_store::*;
+
+    async fn walked(walk: Walk) -> TestResult<Vec<String>> {
+        let objects: Vec<Object> = walk.objects().try_collect().await?;
+        let mut paths: Vec<String> = objects.iter().map(|o| o.path().to_string()).collect();
+        paths.sort();
+        Ok(paths)
+    }
+
+    #[test]
+    fn test_glob() {
+        let result: TestResult<()> = run(async {
+            let glob = Glob::new("*.txt")?;
+            test_assert!(glob.matches("smallfile.txt"));
+            test_assert!(!glob.matches("dir/smallfile.txt"));
+
+            let glob = Glob::new("**/*.txt")?;
+            test_assert!(glob.matches("smallfile.txt"));
+            test_assert!(glob.matches("dir/deeper/smallfile.txt"));
+            test_assert!(!glob.matches("smallfile.txt.bak"));
+
+            let glob = Glob::new("dir2/**")?;
+            test_assert!(glob.matches("dir2/foo"));
+            test_assert!(glob.matches("dir2/foo/bar"));
+            test_assert!(!glob.matches("dir3/foo"));
+
+            let glob = Glob::new("[0-9]?o*")?;
+            test_assert!(glob.matches("0foo"));
+            test_assert!(!glob.matches("foo"));
+            test_assert!(!glob.matches("5diz"));
+
+            test_assert!(Glob::new("[!f]*")?.matches("bar"));
+            test_assert!(!Glob::new("[!f]*")?.matches("foo"));
+            test_assert!(Glob::new("\\*")?.matches("*"));
+            test_assert!(!Glob::new("\\*")?.matches("a"));
+            test_assert!(Glob::new("[abc").is_err());
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+
+    #[test]
+    fn test_walk() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1")?;
+            let fs = FileBackend::connect(&context.get_fs_root()).await?;
+
+            test_assert_eq!(
+                walked(Walk::new(fs.clone(), ObjectPath::empty()).max_depth(1)).await?,
+                vec!["largefile", "mediumfile", "smallfile.txt"]
+            );
+            test_assert!(
+                walked(Walk::new(fs.clone(), ObjectPath::empty()).max_depth(0))
+                    .await?
+                    .is_empty(),
+                "Should not have found anything."
+            );
+
+            test_assert_eq!(
+                walked(
+                    Walk::new(fs.clone(), ObjectPath::new("dir2")?)
+                        .include(Glob::new("?[ao]*")?)
+                        .exclude(Glob::new("bar")?)
+                )
+                .await?,
+                vec!["dir2/daz", "dir2/foo", "dir2/hop"]
+            );
+
+            test_assert_eq!(
+                walked(
+                    Walk::new(fs.clone(), ObjectPath::empty())
+                        .exclude(Glob::new("maybedir")?)
+                        .min_size(1)
+                        .max_size(1000)
+                )
+                .await?,
+                vec!["dir2/daz", "smallfile.txt"]
+            );
+
+            test_assert_eq!(
+                walked(
+                    Walk::new(fs.clone(), ObjectPath::empty())
+                        .include(Glob::new("maybedir/**")?)
+                        .max_depth(2)
+                )
+                .await?,
+                vec!["maybedir/bar", "maybedir/baz", "maybedir/foo"]
+            );
+
+            let future = SystemTime::now() + Duration::from_secs(60 * 60);
+            test_assert!(
+                walked(Walk::new(fs, ObjectPath::empty()).modified_since(future))
+                    .await?
+                    .is_empty(),
+                "Should not have found files modified in the future."
+            );
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+
+    #[test]
+    fn test_copy_matching() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1")?;
+            let root = context.get_fs_root();
+            let target_root = root.with_file_name("target");
+            create_dir(&target_root).unwrap();
+
+            let fs = FileBackend::connect(&root).await?;
+            let target = FileBackend::connect(&target_root).await?;
+
+            let copied = fs
+                .copy_matching("dir2", &Glob::new("?a*")?, &target, "copied", 2)
+                .await?;
+            test_assert_eq!(
+                copied,
+                vec![ObjectPath::new("bar")?, ObjectPath::new("daz")?]
+            );
+            test_assert_eq!(target.get_object("copied/daz").await?.len(), 300);
+            test_assert_eq!(
+                target.get_object("copied/daz").await?.modified(),
+                fs.get_object("dir2/daz").await?.modified()
+            );
+            test_assert!(
+                target.get_object("copied/foo").await.is_err(),
+                "Should not have copied files that do not match."
+            );
+
+            let copied = fs
+                .copy_matching("", &Glob::new("**/*.txt")?, &target, "", 4)
+                .await?;
+            test_assert_eq!(copied, vec![ObjectPath::new("smallfile.txt")?]);
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+mod local {
+    use std::fs::create_dir;
+    #[cfg(unix)]
+    use std::os::unix::fs::symlink;
+
+    use crate::runner::{prepare_test, run, TestResult};
+    use file_store::backends::file::FileBackend;
+    use file_store::backends::Backend;
+    use file_store::local::{download_dir, upload_dir, DirOptions, SymlinkPolicy};
+    use file_store::*;
+
+    fn paths(paths: &[&str]) -> TestResult<Vec<ObjectPath>> {
+        Ok(paths
+            .iter()
+            .map(|p| ObjectPath::new(*p))
+            .collect::<StorageResult<Vec<ObjectPath>>>()?)
+    }
+
+    #[test]
+    fn test_upload_download() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1")?;
+            let root = context.get_fs_root();
+            let local = root.join("maybedir");
+            create_dir(local.join("empty")).unwrap();
+            let target_root = root.with_file_name("target");
+            create_dir(&target_root).unwrap();
+            let target = FileBackend::connect(&target_root).await?;
+
+            let options = DirOptions {
+                concurrency: 2,
+                empty_dir_marker: Some(".bzEmpty".to_owned()),
+                ..Default::default()
+            };
+
+            let uploaded = upload_dir(&local, &target, "up", options.clone()).await?;
+            test_assert_eq!(
+                uploaded,
+                paths(&[
+                    "bar",
+                    "baz",
+                    "empty/.bzEmpty",
+                    "foo",
+                    "foobar/bar",
+                    "foobar/foo"
+                ])?
+            );
+            test_assert!(
+                target_root.join("up/empty/.bzEmpty").is_file(),
+                "Should have uploaded a marker for the empty directory."
+            );
+
+            #[cfg(unix)]
+            {
+                symlink(local.join("foo"), local.join("link")).unwrap();
+                let uploaded = upload_dir(&local, &target, "skipped", options.clone()).await?;
+                test_assert!(
+                    !uploaded.contains(&ObjectPath::new("link")?),
+                    "Should have skipped the symlink."
+                );
+
+                let mut failing = options.clone();
+                failing.symlinks = SymlinkPolicy::Error;
+                test_assert!(
+                    upload_dir(&local, &target, "failed", failing)
+                        .await
+                        .is_err(),
+                    "Should have refused to upload a symlink."
+                );
+
+                let mut following = options.clone();
+                following.symlinks = SymlinkPolicy::Follow;
+                let uploaded = upload_dir(&local, &target, "followed", following).await?;
+                test_assert!(
+                    uploaded.contains(&ObjectPath::new("link")?),
+                    "Should have followed the symlink."
+                );
+            }
+
+            let down = root.with_file_name("down");
+            let downloaded = download_dir(&target, "up", &down, options).await?;
+            test_assert_eq!(
+                downloaded,
+                paths(&["bar", "baz", "foo", "foobar/bar", "foobar/foo"])?
+            );
+            test_assert!(
+                down.join("empty").is_dir(),
+                "Should have created the directory."
+            );
+            test_assert!(
+                !down.join("empty/.bzEmpty").exists(),
+                "Should not have downloaded the marker."
+            );
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+mod tee {
+    use futures::future::join3;
+    use futures::stream::TryStreamExt;
+
+    use crate::runner::{prepare_test, run, TestResult};
+    use file_store::backends::file::FileBackend;
+    use file_store::backends::Backend;
+    use file_store::tee::tee;
+    use file_store::*;
+
+    async fn read_all(stream: DataStream) -> StorageResult<Vec<u8>> {
+        stream.map_ok(|data| data.to_vec()).try_concat().await
+    }
+
+    #[test]
+    fn test_tee() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1")?;
+            let fs = FileBackend::builder(&context.get_fs_root())
+                .read_buffer_size(4)
+                .min_read_buffer_size(4)
+                .connect()
+                .await?;
+
+            let original = b"This is quite a short file.".to_vec();
+
+            let stream = fs.get_file_stream("smallfile.txt").await?;
+            let mut streams = tee(stream, 3, 1);
+            test_assert_eq!(streams.len(), 3);
+            let third = streams.pop().unwrap();
+            let second = streams.pop().unwrap();
+            let first = streams.pop().unwrap();
+
+            let (first, second, third) =
+                join3(read_all(first), read_all(second), read_all(third)).await;
+            test_assert_eq!(first?, original);
+            test_assert_eq!(second?, original);
+            test_assert_eq!(third?, original);
+
+            // A dropped consumer does not hold back the others.
+            let stream = fs.get_file_stream("smallfile.txt").await?;
+            let mut streams = tee(stream, 2, 1);
+            drop(streams.pop());
+            test_assert_eq!(read_all(streams.pop().unwrap()).await?, original);
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+#[cfg(feature = "hash")]
+mod hash {
+    use crate::runner::{prepare_test, run, TestResult};
+    use file_store::backends::file::FileBackend;
+    use file_store::backends::Backend;
+    use file_store::hash::HashAlgorithm;
+    use file_store::*;
+
+    #[test]
+    fn test_compute_hash() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1")?;
+            let fs = FileBackend::connect(&context.get_fs_root()).await?;
+
+            test_assert_eq!(
+                fs.compute_hash("smallfile.txt", HashAlgorithm::Sha1)
+                    .await?,
+                "e0cb6e019b6a67e2e58e0b81c4a7a322522bdbda"
+            );
+            test_assert_eq!(
+                fs.compute_hash("smallfile.txt", HashAlgorithm::Md5).await?,
+                "5f6ac452002d84ae40afb29d16e2668d"
+            );
+
+            let result = fs.compute_hash("missing", HashAlgorithm::Sha1).await;
+            test_assert!(result.is_err());
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+mod correlation {
+    use std::sync::{Arc, Mutex};
+
+    use file_store::backends::file::FileBackend;
+    use file_store::backends::Backend;
+    use file_store::correlation::{with_operation_id, OperationId};
+    use file_store::observe::{Observer, OperationInfo};
+    use file_store::*;
+
+    use crate::runner::{prepare_test, run, TestResult};
+
+    #[derive(Clone, Default)]
+    struct Ids(Arc<Mutex<Vec<OperationId>>>);
+
+    impl Observer for Ids {
+        fn on_operation_start(&self, operation: &OperationInfo) {
+            self.0.lock().unwrap().push(operation.operation_id);
+        }
+    }
+
+    #[test]
+    fn test_operation_ids() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1")?;
+            let fs = FileBackend::connect(&context.get_fs_root()).await?;
+            let id = OperationId::new(0x1234);
+
+            test_assert_eq!(format!("{}", id), "0000000000001234");
+            test_assert!(OperationId::generate() != OperationId::generate());
+            test_assert_eq!(OperationId::current(), None);
+
+            // Errors are for a generated operation by default.
+            match fs.get_object("missing").await {
+                Err(e) => test_assert!(e.operation_id().is_some()),
+                Ok(_) => test_fail!("The object should not exist."),
+            }
+
+            match id.scope(|| fs.get_object("missing")).await {
+                Err(e) => test_assert_eq!(e.operation_id(), Some(id)),
+                Ok(_) => test_fail!("The object should not exist."),
+            }
+            test_assert_eq!(OperationId::current(), None);
+
+            let ids = Ids::default();
+            let observed = fs.clone().observe(ids.clone());
+            let result = with_operation_id(id, async {
+                observed.get_object("smallfile.txt").await?;
+                observed.get_object("missing").await
+            })
+            .await;
+            match result {
+                Err(e) => test_assert_eq!(e.operation_id(), Some(id)),
+                Ok(_) => test_fail!("The object should not exist."),
+            }
+            test_assert_eq!(ids.0.lock().unwrap().clone(), vec![id, id]);
+
+            observed.get_object("smallfile.txt").await?;
+            let recorded = ids.0.lock().unwrap().clone();
+            test_assert_eq!(recorded.len(), 3);
+            test_assert!(recorded[2] != id);
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+mod casefold {
+    use futures::stream::{iter, TryStreamExt};
+
+    use crate::runner::{prepare_test, run, TestResult};
+    use file_store::backends::file::FileBackend;
+    use file_store::backends::Backend;
+    use file_store::casefold::CaseInsensitive;
+    use file_store::*;
+
+    async fn read_all(fs: &FileStore, path: &str) -> TestResult<Vec<u8>> {
+        Ok(fs
+            .get_file_stream(path)
+            .await?
+            .map_ok(|data| data.to_vec())
+            .try_concat()
+            .await?)
+    }
+
+    async fn write(fs: &FileStore, path: &str, data: &[u8]) -> TestResult<()> {
+        fs.write_file_from_stream(path, iter(vec![Ok::<Vec<u8>, StorageError>(data.to_vec())]))
+            .await?;
+        Ok(())
+    }
+
+    async fn exists(fs: &FileStore, path: &str) -> TestResult<bool> {
+        match fs.get_object(path).await {
+            Ok(_) => Ok(true),
+            Err(e) => match e.kind() {
+                StorageErrorKind::NotFound(_) => Ok(false),
+                _ => Err(e.into()),
+            },
+        }
+    }
+
+    #[test]
+    fn test_case_insensitive() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1")?;
+            let origin = FileBackend::connect(&context.get_fs_root()).await?;
+            let layer = CaseInsensitive::new(origin.clone());
+            let fs = layer.store();
+
+            test_assert_eq!(
+                read_all(&fs, "SMALLFILE.TXT").await?,
+                b"This is quite a short file.".to_vec()
+            );
+            test_assert_eq!(
+                fs.get_object("Dir2/FOO").await?.path().to_string(),
+                "dir2/foo"
+            );
+
+            // Writes replace the existing file.
+            write(&fs, "SmallFile.txt", b"Replaced").await?;
+            test_assert_eq!(
+                read_all(&origin, "smallfile.txt").await?,
+                b"Replaced".to_vec()
+            );
+            test_assert!(!exists(&origin, "SmallFile.txt").await?);
+
+            // New files use the case of the existing directory.
+            write(&fs, "DIR2/New.txt", b"New").await?;
+            test_assert!(exists(&origin, "dir2/New.txt").await?);
+            test_assert_eq!(read_all(&fs, "dir2/NEW.TXT").await?, b"New".to_vec());
+
+            // Moves can change the case of a file.
+            fs.move_file("dir2/new.txt", "dir2/Newer.txt").await?;
+            test_assert!(exists(&origin, "dir2/Newer.txt").await?);
+            test_assert!(!exists(&origin, "dir2/New.txt").await?);
+            fs.move_file("DIR2/NEWER.TXT", "dir2/newer.txt").await?;
+            test_assert!(exists(&origin, "dir2/newer.txt").await?);
+            test_assert!(!exists(&origin, "dir2/Newer.txt").await?);
+
+            test_assert_eq!(layer.conflicts().await?, Vec::<Vec<ObjectPath>>::new());
+
+            // Files that already differ only in case.
+            write(&origin, "dir2/FOO", b"Upper").await?;
+            layer.rebuild().await?;
+            test_assert_eq!(
+                layer.conflicts().await?,
+                vec![vec![
+                    ObjectPath::new("dir2/FOO")?,
+                    ObjectPath::new("dir2/foo")?
+                ]]
+            );
+            test_assert_eq!(read_all(&fs, "dir2/foo").await?, b"Upper".to_vec());
+
+            fs.delete_object("dir2/foo").await?;
+            test_assert!(!exists(&origin, "dir2/FOO").await?);
+            test_assert!(exists(&origin, "dir2/foo").await?);
+            test_assert_eq!(layer.conflicts().await?, Vec::<Vec<ObjectPath>>::new());
+            test_assert!(exists(&fs, "DIR2/FOO").await?);
+
+            fs.delete_object("DIR2/FOO").await?;
+            test_assert!(!exists(&fs, "dir2/foo").await?);
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+#[cfg(feature = "inventory")]
+mod inventory {
+    use futures::stream::TryStreamExt;
+
+    use crate::runner::{prepare_test, run, TestResult};
+    use file_store::backends::file::FileBackend;
+    use file_store::backends::Backend;
+    use file_store::hash::HashAlgorithm;
+    use file_store::inventory::{Format, Inventory};
+    use file_store::*;
+
+    async fn export(fs: &FileStore, inventory: Inventory) -> TestResult<Vec<String>> {
+        let objects = fs.list_objects("dir2/").await?;
+        let data = inventory
+            .export(objects)
+            .map_ok(|data| data.to_vec())
+            .try_concat()
+            .await?;
+        let mut lines: Vec<String> = String::from_utf8(data)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect();
+        lines.sort();
+        Ok(lines)
+    }
+
+    #[test]
+    fn test_inventory() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1")?;
+            let fs = FileBackend::connect(&context.get_fs_root()).await?;
+            let modified = fs
+                .get_object("dir2/daz")
+                .await?
+                .modified()
+                .and_then(|m| m.duration_since(std::time::UNIX_EPOCH).ok())
+                .map(|d| d.as_millis())
+                .unwrap();
+            let hash = fs.compute_hash("dir2/daz", HashAlgorithm::Sha1).await?;
+
+            let lines = export(&fs, Inventory::new(Format::Csv)).await?;
+            test_assert_eq!(lines.len(), 9);
+            test_assert!(lines.contains(&String::from("path,size,type,modified,hash")));
+            test_assert!(lines.contains(&format!("dir2/daz,300,file,{},", modified)));
+
+            let inventory = Inventory::new(Format::Csv).hashes(fs.clone(), HashAlgorithm::Sha1);
+            let lines = export(&fs, inventory).await?;
+            test_assert!(lines.contains(&format!("dir2/daz,300,file,{},{}", modified, hash)));
+
+            let lines = export(&fs, Inventory::new(Format::JsonLines)).await?;
+            test_assert_eq!(lines.len(), 8);
+            test_assert!(lines.contains(&format!(
+                "{{\"hash\":null,\"modified\":{},\"path\":\"dir2/daz\",\"size\":300,\"type\":\"file\"}}",
+                modified
+            )));
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+mod connect_url {
+    use crate::runner::{prepare_test, run, TestResult};
+    use file_store::backends::file::FileBackend;
+    use file_store::backends::{connect_url, register_scheme, Backend};
+    use file_store::*;
+
+    #[test]
+    fn test_connect_url() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1")?;
+            let root = context.get_fs_root();
+
+            let fs = connect_url(&format!("file://{}", root.display())).await?;
+            test_assert_eq!(fs.get_object("smallfile.txt").await?.len(), 27);
+
+            match connect_url("unknown://foo").await {
+                Ok(_) => test_fail!("Should not have connected to an unknown scheme."),
+                Err(e) => test_assert_eq!(e.kind(), StorageErrorKind::InvalidSettings),
+            }
+
+            let custom_root = root.join("dir2");
+            register_scheme("test-custom", move |_: &str| {
+                FileBackend::connect(&custom_root)
+            });
+            let fs = connect_url("Test-Custom://anything").await?;
+            test_assert_eq!(fs.get_object("daz").await?.len(), 300);
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+mod validate_path {
+    use futures::stream::iter;
+
+    use crate::runner::{prepare_test, run, TestResult};
+    use file_store::backends::file::FileBackend;
+    use file_store::backends::Backend;
+    use file_store::chunked::ChunkedStore;
+    use file_store::trash::Trash;
+    use file_store::*;
+
+    #[test]
+    fn test_validate_path() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1")?;
+            let fs = FileBackend::connect(&context.get_fs_root()).await?;
+
+            fs.validate_path(&ObjectPath::new("dir2/daz")?)?;
+            fs.validate_path(&ObjectPath::new("not/there/yet")?)?;
+
+            let long = ObjectPath::new(format!("dir2/{}", "a".repeat(256)))?;
+            let invalid = vec![
+                ObjectPath::new("dir2/")?,
+                ObjectPath::new("dir2/../smallfile.txt")?,
+                ObjectPath::new("dir2/null\0byte")?,
+                long.clone(),
+            ];
+
+            for path in invalid {
+                match fs.validate_path(&path) {
+                    Ok(()) => test_fail!("Should have rejected {}.", path),
+                    Err(e) => test_assert_eq!(e.kind(), StorageErrorKind::InvalidPath(path)),
+                }
+            }
+
+            match fs.get_object(long.clone()).await {
+                Ok(_) => test_fail!("Should have rejected the path."),
+                Err(e) => test_assert_eq!(e.kind(), StorageErrorKind::InvalidPath(long)),
+            }
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+
+    #[test]
+    fn test_wrapped() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1")?;
+            let root = context.get_fs_root();
+            let long = ObjectPath::new(format!("dir2/{}", "a".repeat(256)))?;
+
+            let trash = Trash::new(
+                FileBackend::connect(&root).await?,
+                ObjectPath::new("trash")?,
+            )?;
+            match trash.store().validate_path(&long) {
+                Ok(()) => test_fail!("Should have rejected {}.", long),
+                Err(e) => test_assert_eq!(e.kind(), StorageErrorKind::InvalidPath(long.clone())),
+            }
+
+            // Writes are checked before the wrapper does any work.
+            let chunked = ChunkedStore::new(
+                FileBackend::connect(&root).await?,
+                ObjectPath::new("chunks")?,
+                10,
+            )?;
+            let fs = chunked.store();
+            fs.validate_path(&ObjectPath::new("dir2/daz")?)?;
+
+            let data = iter(vec![Ok::<_, StorageError>(vec![5u8; 50])]);
+            match fs.write_file_from_stream(long.clone(), data).await {
+                Err(TransferError::TargetError(e)) => {
+                    test_assert_eq!(e.kind(), StorageErrorKind::InvalidPath(long.clone()))
+                }
+                result => test_fail!("Should have rejected the path: {:?}", result),
+            }
+            test_assert!(
+                !root.join("chunks").exists(),
+                "Should not have written any chunks."
+            );
+
+            match fs.copy_file("smallfile.txt", long.clone()).await {
+                Err(TransferError::TargetError(e)) => {
+                    test_assert_eq!(e.kind(), StorageErrorKind::InvalidPath(long))
+                }
+                result => test_fail!("Should have rejected the path: {:?}", result),
+            }
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+#[cfg(feature = "migrate")]
+mod migrate {
+    use std::fs::read;
+
+    use crate::runner::{prepare_test, run, TestResult};
+    use file_store::backends::file::FileBackend;
+    use file_store::backends::Backend;
+    use file_store::migrate::{move_between, MoveOptions};
+    use file_store::*;
+
+    #[test]
+    fn test_move_between() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1")?;
+            let root = context.get_fs_root();
+            let source = FileBackend::connect(&root).await?;
+            let target = FileBackend::connect(&root.join("dir2")).await?;
+            let expected = read(root.join("smallfile.txt")).unwrap();
+
+            move_between(
+                &source,
+                "smallfile.txt",
+                &target,
+                "moved.txt",
+                MoveOptions::default(),
+            )
+            .await?;
+            test_assert!(
+                !root.join("smallfile.txt").exists(),
+                "Should have deleted the source."
+            );
+            test_assert_eq!(read(root.join("dir2").join("moved.txt")).unwrap(), expected);
+
+            let result = move_between(
+                &source,
+                "missing",
+                &target,
+                "missing",
+                MoveOptions::default(),
+            )
+            .await;
+            match result {
+                Err(TransferError::SourceError(_)) => (),
+                _ => test_fail!("Should have failed to read the source."),
+            }
+            test_assert!(!root.join("dir2").join("missing").exists());
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+#[cfg(feature = "decompress")]
+mod decompress {
+    use std::fs::write;
+    use std::io::Write;
+
+    use flate2::write::GzEncoder;
+    use futures::stream::TryStreamExt;
+
+    use crate::runner::{prepare_test, run, TestResult};
+    use file_store::backends::file::FileBackend;
+    use file_store::backends::Backend;
+    use file_store::*;
+
+    const LOG: &[u8] = b"A line of log output.\nAnother line of log output.\n";
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    async fn read_all(fs: &FileStore, path: &str) -> TestResult<Vec<u8>> {
+        Ok(fs
+            .get_file_stream(path)
+            .await?
+            .map_ok(|data| data.to_vec())
+            .try_concat()
+            .await?)
+    }
+
+    #[test]
+    fn test_decompress_reads() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1")?;
+            let root = context.get_fs_root();
+            write(root.join("app.log.gz"), gzip(LOG)).unwrap();
+            write(root.join("app.log.zst"), zstd::encode_all(LOG, 0).unwrap()).unwrap();
+            write(root.join("app.log"), gzip(LOG)).unwrap();
+            write(root.join("broken.gz"), b"Not compressed").unwrap();
+
+            let fs = FileBackend::connect(&root).await?.decompress_reads();
+            test_assert_eq!(read_all(&fs, "app.log.gz").await?, LOG.to_vec());
+            test_assert_eq!(read_all(&fs, "app.log.zst").await?, LOG.to_vec());
+            test_assert_eq!(
+                read_all(&fs, "app.log").await?,
+                LOG.to_vec(),
+                "Should have found the compression from the data."
+            );
+            test_assert_eq!(
+                read_all(&fs, "smallfile.txt").await?,
+                b"This is quite a short file.".to_vec()
+            );
+            test_assert_eq!(read_all(&fs, "dir2/foo").await?, Vec::<u8>::new());
+
+            let result = read_all(&fs, "broken.gz").await;
+            test_assert!(result.is_err(), "Should have failed to decompress.");
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+mod storage_class {
+    use std::time::SystemTime;
+
+    use futures::stream::iter;
+
+    use crate::runner::{prepare_test, run, TestResult};
+    use file_store::backends::file::FileBackend;
+    use file_store::backends::Backend;
+    use file_store::*;
+
+    #[test]
+    fn test_storage_class() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1")?;
+            let fs = FileBackend::connect(&context.get_fs_root()).await?;
+
+            // The file backend has a single class so ignores the option.
+            let mut info = UploadInfo::from(ObjectPath::new("dir2/archived")?);
+            info.options.storage_class = Some(StorageClass::Archive);
+            let data = iter(vec![Ok::<_, StorageError>(vec![5u8; 20])]);
+            fs.write_file_from_stream(info, data).await?;
+            test_assert_eq!(
+                fs.get_object("dir2/archived").await?.storage_class(),
+                StorageClass::Standard
+            );
+
+            let object = CustomObject::new(
+                ObjectPath::new("custom")?,
+                ObjectType::File,
+                5,
+                Some(SystemTime::now()),
+            );
+            test_assert_eq!(object.storage_class(), StorageClass::Standard);
+            let object = object.with_storage_class(StorageClass::InfrequentAccess);
+            test_assert_eq!(
+                Object::from(object).storage_class(),
+                StorageClass::InfrequentAccess
+            );
+            test_assert_eq!(StorageClass::Archive.to_string(), "archive");
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+mod server {
+    use std::net::{TcpListener, TcpStream};
+    use std::time::Duration;
+
+    use futures::future::FutureExt;
+    use futures::stream::TryStreamExt;
+    use http::header::{self, HeaderMap, HeaderName};
+    use http::{Method, StatusCode};
+    use hyper::client::HttpConnector;
+    use hyper::{Body, Client, Request};
+    use serde_json::{from_slice, json, Value};
+    use tokio::spawn;
+    use tokio::timer::delay_for;
+
+    use crate::runner::{prepare_test, run, TestError, TestResult};
+    use file_store::backends::file::FileBackend;
+    use file_store::backends::Backend;
+    use file_store::server::StorageServer;
+    use file_store::*;
+
+    struct Reply {
+        status: StatusCode,
+        headers: HeaderMap,
+        body: Vec<u8>,
+    }
+
+    impl Reply {
+        fn header(&self, name: HeaderName) -> Option<&str> {
+            self.headers.get(name).and_then(|v| v.to_str().ok())
+        }
+
+        fn text(&self) -> String {
+            String::from_utf8_lossy(&self.body).into_owned()
+        }
+
+        fn json(&self) -> TestResult<Value> {
+            from_slice(&self.body).map_err(|e| TestError::HarnessFailure(e.to_string()))
+        }
+
+        fn lines(&self) -> TestResult<Vec<Value>> {
+            self.text()
+                .lines()
+                .map(|line| {
+                    from_slice(line.as_bytes())
+                        .map_err(|e| TestError::HarnessFailure(e.to_string()))
+                })
+                .collect()
+        }
+    }
+
+    struct Session {
+        client: Client<HttpConnector>,
+        base: String,
+    }
+
+    impl Session {
+        async fn send(
+            &self,
+            method: Method,
+            path: &str,
+            headers: &[(&str, &str)],
+            body: Body,
+        ) -> TestResult<Reply> {
+            let mut builder = Request::builder();
+            builder.method(method).uri(format!("{}{}", self.base, path));
+            for (name, value) in headers {
+                builder.header(*name, *value);
+            }
+            let request = builder
+                .body(body)
+                .map_err(|e| TestError::HarnessFailure(e.to_string()))?;
+
+            let response = self
+                .client
+                .request(request)
+                .await
+                .map_err(|e| TestError::HarnessFailure(e.to_string()))?;
+            let status = response.status();
+            let headers = response.headers().clone();
+            let body = response
+                .into_body()
+                .try_concat()
+                .await
+                .map_err(|e| TestError::HarnessFailure(e.to_string()))?;
+
+            Ok(Reply {
+                status,
+                headers,
+                body: body.to_vec(),
+            })
+        }
+
+        async fn get(&self, path: &str) -> TestResult<Reply> {
+            self.send(Method::GET, path, &[], Body::empty()).await
+        }
+    }
+
+    /// Starts a server on a free loopback port once it is accepting
+    /// connections.
+    async fn start(fs: FileStore) -> TestResult<Session> {
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .map_err(|e| TestError::HarnessFailure(e.to_string()))?;
+        spawn(StorageServer::new(fs).run(addr).map(|_| ()));
+
+        for _ in 0..100 {
+            if TcpStream::connect(addr).is_ok() {
+                return Ok(Session {
+                    client: Client::new(),
+                    base: format!("http://{}", addr),
+                });
+            }
+            delay_for(Duration::from_millis(10)).await;
+        }
+
+        Err(TestError::HarnessFailure(String::from(
+            "The server never started listening.",
+        )))
+    }
+
+    fn paths(objects: &[Value]) -> Vec<String> {
+        let mut paths: Vec<String> = objects
+            .iter()
+            .filter_map(|object| object["path"].as_str().map(String::from))
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn test_server() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1")?;
+            let fs = FileBackend::connect(&context.get_fs_root()).await?;
+            let server = start(fs).await?;
+
+            let reply = server.get("/info/smallfile.txt").await?;
+            test_assert_eq!(reply.status, StatusCode::OK);
+            test_assert_eq!(reply.header(header::CONTENT_TYPE), Some("application/json"));
+            test_assert_eq!(
+                reply.json()?,
+                json!({
+                    "path": "smallfile.txt",
+                    "type": "file",
+                    "size": 27,
+                    "modified": 1_603_257_714u64,
+                })
+            );
+
+            let reply = server.get("/info/dir2").await?;
+            test_assert_eq!(reply.status, StatusCode::OK);
+            test_assert_eq!(reply.json()?["type"], json!("dir"));
+
+            let reply = server.get("/info/missing").await?;
+            test_assert_eq!(reply.status, StatusCode::NOT_FOUND);
+            test_assert!(!reply.body.is_empty(), "Should have described the error.");
+
+            let reply = server.get("/list/dir2/").await?;
+            test_assert_eq!(reply.status, StatusCode::OK);
+            test_assert_eq!(
+                reply.header(header::CONTENT_TYPE),
+                Some("application/x-ndjson")
+            );
+            let objects = reply.lines()?;
+            test_assert_eq!(
+                paths(&objects),
+                vec![
+                    "dir2/0foo",
+                    "dir2/1bar",
+                    "dir2/5diz",
+                    "dir2/bar",
+                    "dir2/daz",
+                    "dir2/foo",
+                    "dir2/hop",
+                    "dir2/yu",
+                ]
+            );
+            test_assert!(objects.iter().all(|o| o["type"] == json!("file")));
+
+            // Without the trailing `/` the prefix also matches the directory.
+            let objects = server.get("/list/dir2").await?.lines()?;
+            test_assert!(
+                objects.iter().any(|o| o["path"] == json!("dir2")),
+                "Should have listed the directory itself."
+            );
+
+            let reply = server.get("/dir/").await?;
+            test_assert_eq!(reply.status, StatusCode::OK);
+            let objects = reply.lines()?;
+            test_assert!(
+                objects
+                    .iter()
+                    .any(|o| o["path"] == json!("dir2") && o["type"] == json!("dir")),
+                "Should have listed the directory."
+            );
+            test_assert!(
+                objects
+                    .iter()
+                    .any(|o| o["path"] == json!("smallfile.txt") && o["size"] == json!(27)),
+                "Should have listed the file."
+            );
+
+            let reply = server.get("/dir/missing").await?;
+            test_assert_eq!(reply.status, StatusCode::OK);
+            test_assert!(reply.body.is_empty(), "Should have listed nothing.");
+
+            let reply = server.get("/files/smallfile.txt").await?;
+            test_assert_eq!(reply.status, StatusCode::OK);
+            test_assert_eq!(reply.text(), "This is quite a short file.");
+
+            let reply = server
+                .send(Method::HEAD, "/files/smallfile.txt", &[], Body::empty())
+                .await?;
+            test_assert_eq!(reply.status, StatusCode::OK);
+            test_assert_eq!(reply.header(header::CONTENT_LENGTH), Some("27"));
+            test_assert!(reply.body.is_empty(), "Should not have sent a body.");
+
+            let reply = server.get("/files/missing").await?;
+            test_assert_eq!(reply.status, StatusCode::NOT_FOUND);
+
+            let reply = server.get("/files/dir2/../smallfile.txt").await?;
+            test_assert_eq!(reply.status, StatusCode::BAD_REQUEST);
+
+            let reply = server
+                .send(Method::PUT, "/files/new/file.txt", &[], Body::from("Hello"))
+                .await?;
+            test_assert_eq!(reply.status, StatusCode::NO_CONTENT);
+            test_assert_eq!(server.get("/files/new/file.txt").await?.text(), "Hello");
+
+            let reply = server
+                .send(
+                    Method::POST,
+                    "/copy/new/file.txt",
+                    &[("Destination", "/new/copied.txt")],
+                    Body::empty(),
+                )
+                .await?;
+            test_assert_eq!(reply.status, StatusCode::NO_CONTENT);
+            test_assert_eq!(server.get("/files/new/copied.txt").await?.text(), "Hello");
+            test_assert_eq!(server.get("/files/new/file.txt").await?.text(), "Hello");
+
+            let reply = server
+                .send(
+                    Method::POST,
+                    "/move/new/copied.txt",
+                    &[("Destination", "new/moved.txt")],
+                    Body::empty(),
+                )
+                .await?;
+            test_assert_eq!(reply.status, StatusCode::NO_CONTENT);
+            test_assert_eq!(server.get("/files/new/moved.txt").await?.text(), "Hello");
+            test_assert_eq!(
+                server.get("/info/new/copied.txt").await?.status,
+                StatusCode::NOT_FOUND
+            );
+
+            let reply = server
+                .send(Method::POST, "/copy/new/file.txt", &[], Body::empty())
+                .await?;
+            test_assert_eq!(reply.status, StatusCode::BAD_REQUEST);
+            test_assert_eq!(reply.text(), "A valid Destination header is required.");
+
+            let reply = server
+                .send(
+                    Method::POST,
+                    "/move/missing",
+                    &[("Destination", "/elsewhere")],
+                    Body::empty(),
+                )
+                .await?;
+            test_assert_eq!(reply.status, StatusCode::NOT_FOUND);
+
+            let reply = server
+                .send(Method::DELETE, "/files/new/file.txt", &[], Body::empty())
+                .await?;
+            test_assert_eq!(reply.status, StatusCode::NO_CONTENT);
+            test_assert_eq!(
+                server.get("/files/new/file.txt").await?.status,
+                StatusCode::NOT_FOUND
+            );
+
+            let reply = server
+                .send(Method::DELETE, "/files/new/file.txt", &[], Body::empty())
+                .await?;
+            test_assert_eq!(reply.status, StatusCode::NOT_FOUND);
+
+            let reply = server
+                .send(Method::PUT, "/info/smallfile.txt", &[], Body::empty())
+                .await?;
+            test_assert_eq!(reply.status, StatusCode::METHOD_NOT_ALLOWED);
+
+            let reply = server.get("/unknown/smallfile.txt").await?;
+            test_assert_eq!(reply.status, StatusCode::NOT_FOUND);
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+
+    #[test]
+    fn test_static_files() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1")?;
+            let fs = FileBackend::connect(&context.get_fs_root()).await?;
+            let server = start(fs).await?;
+
+            let etag = "W/\"1b-5f8fc572\"";
+            let modified = "Wed, 21 Oct 2020 05:21:54 GMT";
+
+            let reply = server.get("/files/smallfile.txt").await?;
+            test_assert_eq!(reply.status, StatusCode::OK);
+            test_assert_eq!(reply.header(header::ACCEPT_RANGES), Some("bytes"));
+            test_assert_eq!(reply.header(header::ETAG), Some(etag));
+            test_assert_eq!(reply.header(header::LAST_MODIFIED), Some(modified));
+
+            // Single byte ranges.
+            let small = "/files/smallfile.txt";
+            let reply = server
+                .send(
+                    Method::GET,
+                    small,
+                    &[("range", "bytes=5-11")],
+                    Body::empty(),
+                )
+                .await?;
+            test_assert_eq!(reply.status, StatusCode::PARTIAL_CONTENT);
+            test_assert_eq!(reply.header(header::CONTENT_RANGE), Some("bytes 5-11/27"));
+            test_assert_eq!(reply.header(header::CONTENT_LENGTH), Some("7"));
+            test_assert_eq!(reply.text(), "is quit");
+
+            let reply = server
+                .send(Method::GET, small, &[("range", "bytes=-5")], Body::empty())
+                .await?;
+            test_assert_eq!(reply.status, StatusCode::PARTIAL_CONTENT);
+            test_assert_eq!(reply.header(header::CONTENT_RANGE), Some("bytes 22-26/27"));
+            test_assert_eq!(reply.text(), "file.");
+
+            let reply = server
+                .send(
+                    Method::HEAD,
+                    small,
+                    &[("range", "bytes=5-11")],
+                    Body::empty(),
+                )
+                .await?;
+            test_assert_eq!(reply.status, StatusCode::PARTIAL_CONTENT);
+            test_assert_eq!(reply.header(header::CONTENT_LENGTH), Some("7"));
+            test_assert!(reply.body.is_empty(), "Should not have sent a body.");
+
+            // Ranges deep inside a larger file.
+            let medium = "/files/mediumfile";
+            let full = server.get(medium).await?.body;
+            test_assert_eq!(full.len(), 5 * 1024 * 1024);
+            let reply = server
+                .send(
+                    Method::GET,
+                    medium,
+                    &[("range", "bytes=3000000-3999999")],
+                    Body::empty(),
+                )
+                .await?;
+            test_assert_eq!(reply.status, StatusCode::PARTIAL_CONTENT);
+            test_assert_eq!(
+                reply.header(header::CONTENT_RANGE),
+                Some("bytes 3000000-3999999/5242880")
+            );
+            test_assert!(
+                reply.body[..] == full[3_000_000..4_000_000],
+                "Should have sent the requested range."
+            );
+
+            // Ranges past the end of the file.
+            let reply = server
+                .send(Method::GET, small, &[("range", "bytes=27-")], Body::empty())
+                .await?;
+            test_assert_eq!(reply.status, StatusCode::RANGE_NOT_SATISFIABLE);
+            test_assert_eq!(reply.header(header::CONTENT_RANGE), Some("bytes */27"));
+            test_assert!(reply.body.is_empty(), "Should not have sent a body.");
+
+            // Conditional requests.
+            let reply = server
+                .send(
+                    Method::GET,
+                    small,
+                    &[("if-none-match", etag)],
+                    Body::empty(),
+                )
+                .await?;
+            test_assert_eq!(reply.status, StatusCode::NOT_MODIFIED);
+            test_assert_eq!(reply.header(header::ETAG), Some(etag));
+            test_assert!(reply.body.is_empty(), "Should not have sent a body.");
+
+            let reply = server
+                .send(
+                    Method::GET,
+                    small,
+                    &[("if-none-match", "W/\"other\", \"1b-5f8fc572\"")],
+                    Body::empty(),
+                )
+                .await?;
+            test_assert_eq!(reply.status, StatusCode::NOT_MODIFIED);
+
+            let reply = server
+                .send(
+                    Method::GET,
+                    small,
+                    &[("if-none-match", "\"other\"")],
+                    Body::empty(),
+                )
+                .await?;
+            test_assert_eq!(reply.status, StatusCode::OK);
+            test_assert_eq!(reply.text(), "This is quite a short file.");
+
+            let reply = server
+                .send(
+                    Method::GET,
+                    small,
+                    &[("if-modified-since", modified)],
+                    Body::empty(),
+                )
+                .await?;
+            test_assert_eq!(reply.status, StatusCode::NOT_MODIFIED);
+            test_assert_eq!(reply.header(header::LAST_MODIFIED), Some(modified));
+            test_assert!(reply.body.is_empty(), "Should not have sent a body.");
+
+            let reply = server
+                .send(
+                    Method::GET,
+                    small,
+                    &[("if-modified-since", "Mon, 01 Jan 2001 00:00:00 GMT")],
+                    Body::empty(),
+                )
+                .await?;
+            test_assert_eq!(reply.status, StatusCode::OK);
+            test_assert_eq!(reply.text(), "This is quite a short file.");
+
+            // If-None-Match takes precedence over If-Modified-Since.
+            let reply = server
+                .send(
+                    Method::GET,
+                    small,
+                    &[
+                        ("if-none-match", "\"other\""),
+                        ("if-modified-since", modified),
+                    ],
+                    Body::empty(),
+                )
+                .await?;
+            test_assert_eq!(reply.status, StatusCode::OK);
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+#[cfg(feature = "tower")]
+mod service {
+    use std::fs::read_to_string;
+
+    use futures::future::poll_fn;
+    use futures::stream::{iter, TryStreamExt};
+    use tower_service::Service;
+
+    use crate::runner::{prepare_test, run, TestResult};
+    use file_store::backends::file::FileBackend;
+    use file_store::backends::Backend;
+    use file_store::service::{StorageRequest, StorageResponse};
+    use file_store::*;
+
+    async fn call(fs: &mut FileStore, request: StorageRequest) -> StorageResult<StorageResponse> {
+        poll_fn(|cx| fs.poll_ready(cx)).await?;
+        fs.call(request).await
+    }
+
+    fn path(path: &str) -> TestResult<ObjectPath> {
+        Ok(ObjectPath::new(path)?)
+    }
+
+    #[test]
+    fn test_service() {
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1")?;
+            let root = context.get_fs_root();
+            let mut fs = FileBackend::connect(&root).await?;
+
+            match call(&mut fs, StorageRequest::ListObjects(path("dir2/")?)).await? {
+                StorageResponse::Objects(stream) => {
+                    let mut paths: Vec<String> = stream
+                        .map_ok(|object| object.path().to_string())
+                        .try_collect()
+                        .await?;
+                    paths.sort();
+                    test_assert_eq!(
+                        paths,
+                        vec![
+                            "dir2/0foo",
+                            "dir2/1bar",
+                            "dir2/5diz",
+                            "dir2/bar",
+                            "dir2/daz",
+                            "dir2/foo",
+                            "dir2/hop",
+                            "dir2/yu",
+                        ]
+                    );
+                }
+                _ => test_fail!("Listing objects should have returned objects."),
+            }
+
+            match call(&mut fs, StorageRequest::ListDirectory(ObjectPath::empty())).await? {
+                StorageResponse::Objects(stream) => {
+                    let objects: Vec<Object> = stream.try_collect().await?;
+                    let dir = objects.iter().find(|o| o.path() == path("dir2").unwrap());
+                    test_assert_eq!(dir.map(|o| o.object_type()), Some(ObjectType::Directory));
+                    test_assert!(
+                        objects.iter().all(|o| o.path().parts().len() == 1),
+                        "Should only have listed the top directory."
+                    );
+                }
+                _ => test_fail!("Listing a directory should have returned objects."),
+            }
+
+            match call(&mut fs, StorageRequest::GetObject(path("smallfile.txt")?)).await? {
+                StorageResponse::Object(object) => {
+                    test_assert_eq!(object.path(), path("smallfile.txt")?);
+                    test_assert_eq!(object.object_type(), ObjectType::File);
+                    test_assert_eq!(object.len(), 27);
+                }
+                _ => test_fail!("Getting an object should have returned the object."),
+            }
+
+            match call(
+                &mut fs,
+                StorageRequest::GetFileStream(path("smallfile.txt")?),
+            )
+            .await?
+            {
+                StorageResponse::Data(stream) => {
+                    let data: Vec<u8> = stream.map_ok(|data| data.to_vec()).try_concat().await?;
+                    test_assert_eq!(data, b"This is quite a short file.".to_vec());
+                }
+                _ => test_fail!("Getting a file stream should have returned data."),
+            }
+
+            let request = StorageRequest::CopyFile(path("smallfile.txt")?, path("copied")?.into());
+            match call(&mut fs, request).await? {
+                StorageResponse::Complete => (),
+                _ => test_fail!("Copying should have completed."),
+            }
+            test_assert_eq!(
+                read_to_string(root.join("copied")).unwrap(),
+                "This is quite a short file."
+            );
+
+            let request = StorageRequest::MoveFile(path("copied")?, path("dir2/moved")?.into());
+            match call(&mut fs, request).await? {
+                StorageResponse::Complete => (),
+                _ => test_fail!("Moving should have completed."),
+            }
+            test_assert!(!root.join("copied").exists(), "Should have moved the file.");
+            test_assert_eq!(
+                read_to_string(root.join("dir2/moved")).unwrap(),
+                "This is quite a short file."
+            );
+
+            let data = DataStream::from_stream(iter(vec![
+                Ok::<_, StorageError>(Data::from("Hello ")),
+                Ok(Data::from("world")),
+            ]));
+            let request = StorageRequest::WriteFile(path("written")?.into(), data);
+            match call(&mut fs, request).await? {
+                StorageResponse::Complete => (),
+                _ => test_fail!("Writing should have completed."),
+            }
+            test_assert_eq!(read_to_string(root.join("written")).unwrap(), "Hello world");
+
+            match call(&mut fs, StorageRequest::DeleteObject(path("written")?)).await? {
+                StorageResponse::Complete => (),
+                _ => test_fail!("Deleting should have completed."),
+            }
+            test_assert!(
+                !root.join("written").exists(),
+                "Should have deleted the file."
+            );
+
+            // Errors are returned directly, including those from transfers.
+            let missing = path("missing")?;
+            let requests = vec![
+                StorageRequest::GetObject(missing.clone()),
+                StorageRequest::GetFileStream(missing.clone()),
+                StorageRequest::CopyFile(missing.clone(), path("target")?.into()),
+                StorageRequest::MoveFile(missing.clone(), path("target")?.into()),
+                StorageRequest::DeleteObject(missing.clone()),
+            ];
+            for request in requests {
+                match call(&mut fs, request).await {
+                    Err(e) => {
+                        test_assert_eq!(e.kind(), StorageErrorKind::NotFound(missing.clone()))
+                    }
+                    Ok(_) => test_fail!("Should have failed to find the missing file."),
+                }
+            }
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
@@ -26,12 +26,12 @@ use std::net::SocketAddr;
 use std::net::TcpListener;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::{Duration, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use base64::encode;
 use filetime::{set_file_mtime, FileTime};
 use futures::channel::oneshot::{channel, Sender};
-use futures::future::FutureExt;
+use futures::future::{pending, FutureExt};
 use futures::lock::Mutex;
 use futures::stream::{iter, TryStreamExt};
 use http::header;
@@ -50,9 +50,11 @@ use storage_types::b2::v2::requests::*;
 use storage_types::b2::v2::responses::*;
 use storage_types::b2::v2::{
     percent_decode, BucketType, FileAction, Int, UserFileInfo, B2_HEADER_CONTENT_SHA1,
-    B2_HEADER_FILE_INFO_PREFIX, B2_HEADER_FILE_NAME, B2_HEADER_PART_NUMBER, LAST_MODIFIED_KEY,
+    B2_HEADER_FILE_INFO_PREFIX, B2_HEADER_FILE_NAME, B2_HEADER_PART_NUMBER,
+    B2_HEADER_SSE_C_KEY_MD5, LAST_MODIFIED_KEY,
 };
 
+use crate::runner::faults::{Fault, FaultSchedule, VirtualClock};
 use crate::runner::TestResult;
 
 const TEST_KEY_ID: &str = "foo";
@@ -63,6 +65,7 @@ const TEST_ACCOUNT_ID: &str = "foobarbaz";
 const DEFAULT_FILE_COUNT: usize = 2;
 const BUCKET_ID_PREFIX: &str = "bkt_";
 const FILE_ID_PREFIX: &str = "id_";
+const HIDE_ID_PREFIX: &str = "hide_";
 
 type B2Result = Result<Response<Body>, B2Error>;
 
@@ -71,6 +74,7 @@ struct B2Error {
     status: StatusCode,
     code: String,
     message: String,
+    retry_after: Option<Duration>,
 }
 
 impl B2Error {
@@ -83,6 +87,7 @@ impl B2Error {
             status,
             code: code.to_string(),
             message: message.to_string(),
+            retry_after: None,
         }
     }
 
@@ -154,6 +159,36 @@ impl B2Error {
     {
         B2Error::new(StatusCode::INTERNAL_SERVER_ERROR, "server_error", message)
     }
+
+    fn from_fault(fault: Fault) -> B2Error {
+        match fault {
+            Fault::Unavailable(retry_after) => B2Error {
+                retry_after: Some(retry_after),
+                ..B2Error::new(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "service_unavailable",
+                    "Injected failure.",
+                )
+            },
+            Fault::ServerError => B2Error::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal_error",
+                "Injected failure.",
+            ),
+            Fault::Timeout => B2Error::request_timeout("Injected failure."),
+            Fault::ExpiredAuth => B2Error::new(
+                StatusCode::UNAUTHORIZED,
+                "expired_auth_token",
+                "Injected failure.",
+            ),
+            Fault::CapExceeded => B2Error::new(
+                StatusCode::FORBIDDEN,
+                "transaction_cap_exceeded",
+                "Injected failure.",
+            ),
+            Fault::Stall => unreachable!("Stalled requests are never answered."),
+        }
+    }
 }
 
 impl Display for B2Error {
@@ -180,8 +215,13 @@ impl From<B2Error> for Response<Body> {
             message: error.message.clone(),
         };
 
-        Response::builder()
-            .status(error.status)
+        let mut builder = Response::builder();
+        builder.status(error.status);
+        if let Some(retry_after) = error.retry_after {
+            builder.header(header::RETRY_AFTER, retry_after.as_secs());
+        }
+
+        builder
             .body(
                 to_string_pretty(&response)
                     .expect("Failed to serialize error.")
@@ -353,6 +393,7 @@ impl FileLister {
                                     file_id: None,
                                     file_info: Default::default(),
                                     file_name: file_path,
+                                    server_side_encryption: None,
                                     upload_timestamp: 0,
                                 });
                             }
@@ -378,6 +419,7 @@ impl FileLister {
                             file_id: Some(format!("{}{}", FILE_ID_PREFIX, entry.path().display())),
                             file_info: info,
                             file_name: file_path,
+                            server_side_encryption: None,
                             upload_timestamp: 0,
                         })
                     } else {
@@ -426,30 +468,124 @@ struct LargeUpload {
     bucket_id: String,
     auth: HashSet<String>,
     parts: HashMap<usize, (Vec<Chunk>, String)>,
+    key_md5: Option<String>,
+    started: Int,
 }
 
 impl LargeUpload {
-    fn new(file_name: &str, bucket_id: &str) -> LargeUpload {
+    fn new(file_name: &str, bucket_id: &str, key_md5: Option<String>) -> LargeUpload {
         LargeUpload {
             file_name: file_name.to_owned(),
             bucket_id: bucket_id.to_owned(),
             auth: Default::default(),
             parts: Default::default(),
+            key_md5,
+            started: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as Int)
+                .unwrap_or_default(),
         }
     }
 }
 
+/// Parses a `bytes=start-end` range header into inclusive offsets.
+fn parse_range(header: &header::HeaderValue, len: usize) -> Option<(usize, usize)> {
+    let spec = header.to_str().ok()?;
+    if !spec.starts_with("bytes=") {
+        return None;
+    }
+
+    let mut parts = spec[6..].splitn(2, '-');
+    let start = parts.next()?.parse::<usize>().ok()?;
+    let end = parts.next()?.parse::<usize>().ok()?;
+    if start > end || start >= len {
+        return None;
+    }
+
+    Some((start, end.min(len - 1)))
+}
+
+fn customer_key_md5(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(B2_HEADER_SSE_C_KEY_MD5)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_owned())
+}
+
 #[derive(Default)]
 struct B2ServerState {
     authorizations: HashMap<String, usize>,
     upload_authorizations: HashMap<String, String>,
     large_uploads: HashMap<String, LargeUpload>,
+    /// The md5 of the customer key that each encrypted file was written with.
+    encrypted: HashMap<PathBuf, String>,
+    /// Buckets whose settings have been changed from the defaults.
+    buckets: HashMap<String, Bucket>,
+    /// Files that have been hidden.
+    hidden: HashSet<PathBuf>,
+    requests: usize,
 }
 
 impl B2ServerState {
     fn new() -> B2ServerState {
         Default::default()
     }
+
+    fn bucket(&self, name: &str) -> Bucket {
+        match self.buckets.get(name) {
+            Some(bucket) => bucket.clone(),
+            None => Bucket {
+                account_id: String::from(TEST_ACCOUNT_ID),
+                bucket_id: format!("{}{}", BUCKET_ID_PREFIX, name),
+                bucket_name: name.to_owned(),
+                bucket_type: BucketType::Public,
+                bucket_info: Default::default(),
+                cors_rules: Default::default(),
+                lifecycle_rules: Default::default(),
+                revision: 0,
+            },
+        }
+    }
+
+    /// The hide marker to list before a file's uploaded version if it is
+    /// hidden.
+    fn hide_marker(&self, info: &FileInfo) -> Option<FileInfo> {
+        let path = match (&info.action, &info.file_id) {
+            (FileAction::Upload, Some(id)) => Path::new(&id[FILE_ID_PREFIX.len()..]),
+            _ => return None,
+        };
+
+        if !self.hidden.contains(path) {
+            return None;
+        }
+
+        Some(FileInfo {
+            account_id: TEST_ACCOUNT_ID.to_owned(),
+            action: FileAction::Hide,
+            bucket_id: info.bucket_id.clone(),
+            content_length: 0,
+            content_sha1: None,
+            content_type: None,
+            file_id: Some(format!("{}{}", HIDE_ID_PREFIX, path.display())),
+            file_info: Default::default(),
+            file_name: info.file_name.clone(),
+            server_side_encryption: None,
+            upload_timestamp: info.upload_timestamp + 1,
+        })
+    }
+
+    /// Records that a new version of a file was uploaded, unhiding it.
+    fn uploaded(&mut self, path: &Path, key_md5: Option<String>) {
+        self.hidden.remove(path);
+        match key_md5 {
+            Some(md5) => {
+                self.encrypted.insert(path.to_owned(), md5);
+            }
+            None => {
+                self.encrypted.remove(path);
+            }
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -457,6 +593,8 @@ struct B2Server {
     addr: SocketAddr,
     root: PathBuf,
     auth_timeout: usize,
+    faults: FaultSchedule,
+    clock: VirtualClock,
     state: Arc<Mutex<B2ServerState>>,
 }
 
@@ -537,20 +675,13 @@ impl B2Server {
                 return Err(B2Error::not_found(&path));
             }
 
+            let bucket = self.state.lock().await.bucket(&name);
             return api_response!(ListBucketsResponse {
-                buckets: vec![Bucket {
-                    account_id: String::from(TEST_ACCOUNT_ID),
-                    bucket_id: format!("{}{}", BUCKET_ID_PREFIX, &name),
-                    bucket_name: name.to_owned(),
-                    bucket_type: BucketType::Public,
-                    bucket_info: Default::default(),
-                    cors_rules: Default::default(),
-                    lifecycle_rules: Default::default(),
-                    revision: 0,
-                }]
+                buckets: vec![bucket]
             });
         }
 
+        let state = self.state.lock().await;
         let buckets: Vec<Bucket> = read_dir(&self.root)
             .into_path_err(&self.root)?
             .filter_map(|result| {
@@ -573,22 +704,61 @@ impl B2Server {
                     _ => panic!("Path at {} uses an invalid name.", entry.path().display()),
                 };
 
-                Some(Bucket {
-                    account_id: String::from(TEST_ACCOUNT_ID),
-                    bucket_id: format!("{}{}", BUCKET_ID_PREFIX, name),
-                    bucket_name: name.to_owned(),
-                    bucket_type: BucketType::Public,
-                    bucket_info: Default::default(),
-                    cors_rules: Default::default(),
-                    lifecycle_rules: Default::default(),
-                    revision: 0,
-                })
+                Some(state.bucket(&name))
             })
             .collect();
 
         api_response!(ListBucketsResponse { buckets })
     }
 
+    async fn b2_update_bucket(self, _head: Parts, body: UpdateBucketRequest) -> B2Result {
+        if body.account_id != TEST_ACCOUNT_ID {
+            return Err(B2Error::new(
+                StatusCode::UNAUTHORIZED,
+                "unauthorized",
+                "This key cannot access buckets from the requested account.",
+            ));
+        }
+
+        if !body.bucket_id.starts_with(BUCKET_ID_PREFIX) {
+            return Err(B2Error::invalid_bucket_id(&body.bucket_id));
+        }
+
+        let name = &body.bucket_id[BUCKET_ID_PREFIX.len()..];
+        let mut path = self.root.clone();
+        path.push(name);
+        if !metadata(&path).into_path_err(&path)?.is_dir() {
+            return Err(B2Error::not_found(&path));
+        }
+
+        let mut state = self.state.lock().await;
+        let mut bucket = state.bucket(name);
+
+        if let Some(revision) = body.if_revision_is {
+            if revision != bucket.revision {
+                return Err(B2Error::new(
+                    StatusCode::CONFLICT,
+                    "conflict",
+                    "ifRevisionIs does not match the current revision.",
+                ));
+            }
+        }
+
+        if let Some(bucket_type) = body.bucket_type {
+            bucket.bucket_type = bucket_type;
+        }
+        if let Some(bucket_info) = body.bucket_info {
+            bucket.bucket_info = bucket_info;
+        }
+        if let Some(cors_rules) = body.cors_rules {
+            bucket.cors_rules = cors_rules;
+        }
+        bucket.revision += 1;
+
+        state.buckets.insert(name.to_owned(), bucket.clone());
+        api_response!(bucket)
+    }
+
     async fn b2_list_file_names(self, _head: Parts, body: ListFileNamesRequest) -> B2Result {
         if !body.bucket_id.starts_with(BUCKET_ID_PREFIX) {
             return Err(B2Error::invalid_bucket_id(&body.bucket_id));
@@ -654,12 +824,19 @@ impl B2Server {
             next_file_id: None,
         };
 
+        // Every version of a file is returned in the same response.
+        let state = self.state.lock().await;
+        let mut count: usize = 0;
         for result in lister {
             let info = result?;
 
-            if response.files.len() < DEFAULT_FILE_COUNT {
+            if count < DEFAULT_FILE_COUNT {
+                count += 1;
+                if let Some(marker) = state.hide_marker(&info) {
+                    response.files.push(marker);
+                }
                 response.files.push(info);
-            } else if response.files.len() == DEFAULT_FILE_COUNT {
+            } else {
                 response.next_file_name = Some(info.file_name);
                 response.next_file_id = info.file_id;
                 break;
@@ -674,6 +851,22 @@ impl B2Server {
         _head: Parts,
         body: DeleteFileVersionRequest,
     ) -> B2Result {
+        if body.file_id.starts_with(HIDE_ID_PREFIX) {
+            let path = Path::new(&body.file_id[HIDE_ID_PREFIX.len()..]);
+            if !self.state.lock().await.hidden.remove(path) {
+                return Err(B2Error::new(
+                    StatusCode::BAD_REQUEST,
+                    "file_not_present",
+                    format!("File not present: {} {}", body.file_name, body.file_id),
+                ));
+            }
+
+            return api_response!(DeleteFileVersionResponse {
+                file_id: body.file_id,
+                file_name: body.file_name,
+            });
+        }
+
         if !body.file_id.starts_with(FILE_ID_PREFIX) {
             return Err(B2Error::new(
                 StatusCode::BAD_REQUEST,
@@ -715,7 +908,7 @@ impl B2Server {
         }
     }
 
-    async fn b2_download_file(self, path: &str) -> B2Result {
+    async fn b2_download_file(self, path: &str, headers: &HeaderMap) -> B2Result {
         let path = match percent_decode(path) {
             Ok(s) => s,
             Err(_) => return Err(B2Error::invalid_parameters("File path was invalid utf-8.")),
@@ -724,12 +917,66 @@ impl B2Server {
         let mut file = self.root.clone();
         file.push(path);
 
+        self.send_file(file, headers).await
+    }
+
+    async fn b2_download_file_by_id(self, query: &str, headers: &HeaderMap) -> B2Result {
+        let id = match query.split('&').find(|p| p.starts_with("fileId=")) {
+            Some(param) => match percent_decode(&param[7..]) {
+                Ok(s) => s,
+                Err(_) => return Err(B2Error::invalid_parameters("File id was invalid utf-8.")),
+            },
+            None => return Err(B2Error::invalid_parameters("No file id was given.")),
+        };
+
+        if !id.starts_with(FILE_ID_PREFIX) {
+            return Err(B2Error::invalid_parameters(format!(
+                "Invalid file id: {}",
+                id
+            )));
+        }
+
+        self.send_file(PathBuf::from(&id[FILE_ID_PREFIX.len()..]), headers)
+            .await
+    }
+
+    async fn send_file(self, file: PathBuf, headers: &HeaderMap) -> B2Result {
         let meta = metadata(&file).into_path_err(&file)?;
         if !meta.is_file() {
             return Err(B2Error::not_found(&file));
         }
 
-        let source = read(&file).into_path_err(file)?;
+        let state = self.state.lock().await;
+        if state.hidden.contains(&file) {
+            return Err(B2Error::not_found(&file));
+        }
+
+        if let Some(md5) = state.encrypted.get(&file) {
+            if customer_key_md5(headers).as_ref() != Some(md5) {
+                return Err(B2Error::invalid_parameters(
+                    "The file was encrypted with a different key.",
+                ));
+            }
+        }
+
+        let mut source = read(&file).into_path_err(file)?;
+        let mut status = StatusCode::OK;
+        if let Some(range) = headers.get(header::RANGE) {
+            let (start, end) = match parse_range(range, source.len()) {
+                Some(r) => r,
+                None => {
+                    return Err(B2Error::new(
+                        StatusCode::RANGE_NOT_SATISFIABLE,
+                        "range_not_satisfiable",
+                        "The range was invalid.",
+                    ));
+                }
+            };
+
+            source = source[start..=end].to_vec();
+            status = StatusCode::PARTIAL_CONTENT;
+        }
+
         let mut len = source.len() / 5;
         if len == 0 {
             len = 1;
@@ -745,11 +992,46 @@ impl B2Server {
             .collect();
 
         Ok(Response::builder()
-            .status(StatusCode::OK)
+            .status(status)
+            .header(header::CONTENT_LENGTH, source.len())
             .body(Body::wrap_stream(iter(blocks)))
             .expect("Failed to build response."))
     }
 
+    async fn b2_hide_file(self, _head: Parts, body: HideFileRequest) -> B2Result {
+        if !body.bucket_id.starts_with(BUCKET_ID_PREFIX) {
+            return Err(B2Error::invalid_bucket_id(&body.bucket_id));
+        }
+
+        let mut path = self.root.clone();
+        path.push(&body.bucket_id[BUCKET_ID_PREFIX.len()..]);
+        path.push(&body.file_name);
+
+        if !metadata(&path).into_path_err(&path)?.is_file() {
+            return Err(B2Error::new(
+                StatusCode::BAD_REQUEST,
+                "no_such_file",
+                format!("File not present: {}", body.file_name),
+            ));
+        }
+
+        self.state.lock().await.hidden.insert(path.clone());
+
+        api_response!(HideFileResponse {
+            account_id: TEST_ACCOUNT_ID.to_owned(),
+            action: FileAction::Hide,
+            bucket_id: body.bucket_id,
+            content_length: 0,
+            content_sha1: None,
+            content_type: None,
+            file_id: Some(format!("{}{}", HIDE_ID_PREFIX, path.display())),
+            file_info: Default::default(),
+            file_name: body.file_name,
+            server_side_encryption: None,
+            upload_timestamp: 1,
+        })
+    }
+
     async fn b2_get_upload_url(self, _head: Parts, body: GetUploadUrlRequest) -> B2Result {
         if !&body.bucket_id.starts_with(BUCKET_ID_PREFIX) {
             return Err(B2Error::new(
@@ -866,6 +1148,11 @@ impl B2Server {
             }
         }
 
+        self.state
+            .lock()
+            .await
+            .uploaded(&path, customer_key_md5(&head.headers));
+
         api_response!(UploadFileResponse {
             account_id: TEST_ACCOUNT_ID.to_owned(),
             action: FileAction::Upload,
@@ -876,6 +1163,7 @@ impl B2Server {
             file_id: Some(format!("{}", path.display())),
             file_info: Default::default(),
             file_name: file.to_owned(),
+            server_side_encryption: None,
             upload_timestamp: 0,
         })
     }
@@ -899,9 +1187,12 @@ impl B2Server {
             )));
         }
 
+        let key_md5 = body
+            .server_side_encryption
+            .and_then(|sse| sse.customer_key_md5);
         state.large_uploads.insert(
             file_id.clone(),
-            LargeUpload::new(&body.file_name, &body.bucket_id),
+            LargeUpload::new(&body.file_name, &body.bucket_id, key_md5),
         );
 
         api_response!(StartLargeFileResponse {
@@ -914,6 +1205,7 @@ impl B2Server {
             file_id: Some(file_id),
             file_info: Default::default(),
             file_name: body.file_name,
+            server_side_encryption: None,
             upload_timestamp: 0,
         })
     }
@@ -1005,6 +1297,12 @@ impl B2Server {
             }
         };
 
+        if customer_key_md5(&head.headers) != upload.key_md5 {
+            return Err(B2Error::invalid_parameters(
+                "Parts must be encrypted with the key the file was started with.",
+            ));
+        }
+
         upload
             .parts
             .insert(part_number - 1, (data, expected_sha1.clone()));
@@ -1018,6 +1316,127 @@ impl B2Server {
         })
     }
 
+    async fn b2_copy_part(self, _head: Parts, body: CopyPartRequest) -> B2Result {
+        if body.part_number < 1 {
+            return Err(B2Error::invalid_parameters("Invalid part number."));
+        }
+
+        if body.range.is_some() {
+            return Err(B2Error::invalid_parameters("Ranges are not supported."));
+        }
+
+        if !body.source_file_id.starts_with(FILE_ID_PREFIX) {
+            return Err(B2Error::invalid_parameters(format!(
+                "Invalid file id: {}",
+                body.source_file_id
+            )));
+        }
+
+        let source = Path::new(&body.source_file_id[FILE_ID_PREFIX.len()..]);
+        let data = read(source).into_path_err(source)?;
+
+        let mut hasher = Sha1::new();
+        hasher.update(&data);
+        let content_sha1 = hasher.hexdigest();
+        let content_length = data.len() as Int;
+
+        let mut state = self.state.lock().await;
+        let upload = match state.large_uploads.get_mut(&body.large_file_id) {
+            Some(u) => u,
+            None => return Err(B2Error::invalid_parameters("Unknown file id.")),
+        };
+
+        upload.parts.insert(
+            body.part_number - 1,
+            (vec![Chunk::from(data)], content_sha1.clone()),
+        );
+
+        api_response!(CopyPartResponse {
+            file_id: body.large_file_id,
+            part_number: body.part_number,
+            content_length,
+            content_sha1,
+            upload_timestamp: 0,
+        })
+    }
+
+    async fn b2_list_unfinished_large_files(
+        self,
+        _head: Parts,
+        body: ListUnfinishedLargeFilesRequest,
+    ) -> B2Result {
+        if !body.bucket_id.starts_with(BUCKET_ID_PREFIX) {
+            return Err(B2Error::invalid_bucket_id(&body.bucket_id));
+        }
+
+        let state = self.state.lock().await;
+        let mut files: Vec<FileInfo> = state
+            .large_uploads
+            .iter()
+            .filter(|(_, upload)| upload.bucket_id == body.bucket_id)
+            .filter(|(_, upload)| match body.name_prefix {
+                Some(ref prefix) => upload.file_name.starts_with(prefix),
+                None => true,
+            })
+            .map(|(file_id, upload)| FileInfo {
+                account_id: TEST_ACCOUNT_ID.to_owned(),
+                action: FileAction::Start,
+                bucket_id: upload.bucket_id.clone(),
+                content_length: 0,
+                content_sha1: None,
+                content_type: None,
+                file_id: Some(file_id.clone()),
+                file_info: Default::default(),
+                file_name: upload.file_name.clone(),
+                server_side_encryption: None,
+                upload_timestamp: upload.started,
+            })
+            .collect();
+        files.sort_by(|a, b| a.file_id.cmp(&b.file_id));
+
+        api_response!(ListUnfinishedLargeFilesResponse {
+            files,
+            next_file_id: None,
+        })
+    }
+
+    async fn b2_cancel_large_file(self, _head: Parts, body: CancelLargeFileRequest) -> B2Result {
+        let mut state = self.state.lock().await;
+        match state.large_uploads.remove(&body.file_id) {
+            Some(upload) => api_response!(CancelLargeFileResponse {
+                file_id: body.file_id,
+                account_id: TEST_ACCOUNT_ID.to_owned(),
+                bucket_id: upload.bucket_id,
+                file_name: upload.file_name,
+            }),
+            None => Err(B2Error::invalid_parameters("Unknown file id.")),
+        }
+    }
+
+    async fn b2_get_download_authorization(
+        self,
+        _head: Parts,
+        body: GetDownloadAuthorizationRequest,
+    ) -> B2Result {
+        if !body.bucket_id.starts_with(BUCKET_ID_PREFIX) {
+            return Err(B2Error::new(
+                StatusCode::BAD_REQUEST,
+                "bad_request",
+                format!("Invalid bucket id: {}", body.bucket_id),
+            ));
+        }
+
+        if body.valid_duration_in_seconds < 1 || body.valid_duration_in_seconds > 604_800 {
+            return Err(B2Error::invalid_parameters("Invalid duration."));
+        }
+
+        api_response!(GetDownloadAuthorizationResponse {
+            bucket_id: body.bucket_id,
+            file_name_prefix: body.file_name_prefix,
+            authorization_token: Uuid::new_v4().to_string(),
+        })
+    }
+
     async fn b2_finish_large_file(self, _head: Parts, body: FinishLargeFileRequest) -> B2Result {
         let mut upload = {
             let mut state = self.state.lock().await;
@@ -1054,6 +1473,11 @@ impl B2Server {
             }
         }
 
+        self.state
+            .lock()
+            .await
+            .uploaded(path, upload.key_md5.take());
+
         api_response!(FinishLargeFileResponse {
             account_id: String::from(TEST_ACCOUNT_ID),
             action: FileAction::Upload,
@@ -1064,6 +1488,7 @@ impl B2Server {
             file_id: Some(body.file_id),
             file_info: Default::default(),
             file_name: upload.file_name,
+            server_side_encryption: None,
             upload_timestamp: 0,
         })
     }
@@ -1093,15 +1518,36 @@ impl B2Server {
         }
     }
 
+    /// Counts a request and decides whether it should fail.
+    async fn check_failure(&self, path: &str) -> Result<(), B2Error> {
+        let fault = {
+            let mut state = self.state.lock().await;
+            state.requests += 1;
+            self.faults.fault(state.requests, path, &self.clock)
+        };
+
+        match fault {
+            Some(Fault::Stall) => pending().await,
+            Some(fault) => Err(B2Error::from_fault(fault)),
+            None => Ok(()),
+        }
+    }
+
     async fn call_api(self, method: &str, head: Parts, data: Chunk) -> B2Result {
         api_method!(b2_list_buckets, self, method, head, data);
+        api_method!(b2_update_bucket, self, method, head, data);
         api_method!(b2_list_file_names, self, method, head, data);
         api_method!(b2_list_file_versions, self, method, head, data);
         api_method!(b2_delete_file_version, self, method, head, data);
+        api_method!(b2_hide_file, self, method, head, data);
         api_method!(b2_get_upload_url, self, method, head, data);
         api_method!(b2_start_large_file, self, method, head, data);
         api_method!(b2_get_upload_part_url, self, method, head, data);
+        api_method!(b2_copy_part, self, method, head, data);
         api_method!(b2_finish_large_file, self, method, head, data);
+        api_method!(b2_list_unfinished_large_files, self, method, head, data);
+        api_method!(b2_cancel_large_file, self, method, head, data);
+        api_method!(b2_get_download_authorization, self, method, head, data);
 
         Err(B2Error::invalid_parameters("Invalid API method requested."))
     }
@@ -1163,6 +1609,11 @@ impl B2Server {
             }
         };
 
+        // Authorization failures aren't retried so never inject those.
+        if !path.starts_with("/b2api/v2/b2_authorize_account") {
+            self.check_failure(&path).await?;
+        }
+
         if path.starts_with("/b2api/v2/b2_authorize_account") {
             self.b2_authorize_account(&auth).await
         } else if path.starts_with("/api/b2api/v2/") {
@@ -1174,10 +1625,14 @@ impl B2Server {
                 B2Error::invalid_parameters(format!("Failed to receive entire body: {}", e))
             })?;
             self.call_api(method, head, data).await
+        } else if path == "/download/b2api/v2/b2_download_file_by_id" {
+            self.check_auth(&auth).await?;
+            let query = head.uri.query().unwrap_or("").to_owned();
+            self.b2_download_file_by_id(&query, &head.headers).await
         } else if path.starts_with("/download/file/") {
             let target = &path[15..];
             self.check_auth(&auth).await?;
-            self.b2_download_file(target).await
+            self.b2_download_file(target, &head.headers).await
         } else if path.starts_with("/upload/file/") {
             if head.method != "POST" {
                 return Err(B2Error::method_not_allowed(
@@ -1268,6 +1723,17 @@ impl B2Server {
 }
 
 pub fn start_server(root: PathBuf, auth_timeout: usize) -> TestResult<(SocketAddr, Sender<()>)> {
+    start_failing_server(root, auth_timeout, Default::default(), VirtualClock::new())
+}
+
+/// Starts a server that fails requests other than authorization according to
+/// the given schedule.
+pub fn start_failing_server(
+    root: PathBuf,
+    auth_timeout: usize,
+    faults: FaultSchedule,
+    clock: VirtualClock,
+) -> TestResult<(SocketAddr, Sender<()>)> {
     let (shutdown_sender, shutdown_receiver) = channel::<()>();
 
     let addr: SocketAddr = ([127, 0, 0, 1], 0).into();
@@ -1279,6 +1745,8 @@ pub fn start_server(root: PathBuf, auth_timeout: usize) -> TestResult<(SocketAdd
     let b2_server = B2Server {
         addr,
         auth_timeout,
+        faults,
+        clock,
         state: Arc::new(Mutex::new(B2ServerState::new())),
         root,
     };
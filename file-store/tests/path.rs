@@ -0,0 +1,99 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate file_store;
+
+use proptest::prelude::*;
+
+use file_store::ObjectPath;
+
+const PART: &str = "[a-zA-Z0-9 ._~-]{1,8}";
+const PATH: &str = "([a-zA-Z0-9 ._~-]{1,8}(/[a-zA-Z0-9 ._~-]{0,8}){0,4})?";
+
+fn path(s: &str) -> ObjectPath {
+    ObjectPath::new(s).unwrap()
+}
+
+proptest! {
+    #[test]
+    fn parse_round_trips(s in any::<String>()) {
+        match ObjectPath::new(&s) {
+            Ok(p) => {
+                prop_assert!(!s.starts_with('/'));
+                prop_assert_eq!(p.to_string(), s);
+            }
+            Err(_) => prop_assert!(s.starts_with('/')),
+        }
+    }
+
+    #[test]
+    fn parts_join_to_path(s in PATH) {
+        let p = path(&s);
+        prop_assert_eq!(p.is_empty(), s.is_empty());
+        prop_assert_eq!(p.parts().join("/"), s);
+    }
+
+    #[test]
+    fn push_then_pop(s in PATH, part in PART) {
+        let mut p = path(&s);
+        p.push_part(&part);
+        prop_assert_eq!(p.parts().last(), Some(&part.as_str()));
+        prop_assert_eq!(p.pop_part(), Some(part));
+        prop_assert_eq!(p, path(&s));
+    }
+
+    #[test]
+    fn shift_then_unshift(s in PATH, part in PART) {
+        let mut p = path(&s);
+        p.shift_part(&part);
+        prop_assert_eq!(p.parts().first(), Some(&part.as_str()));
+        prop_assert_eq!(p.unshift_part(), Some(part));
+        prop_assert_eq!(p, path(&s));
+    }
+
+    #[test]
+    fn pop_everything(s in PATH) {
+        let mut p = path(&s);
+        let mut parts = Vec::new();
+        while let Some(part) = p.pop_part() {
+            parts.insert(0, part);
+        }
+        prop_assert!(p.is_empty());
+        prop_assert_eq!(parts, path(&s).parts());
+    }
+
+    #[test]
+    fn join_concatenates(a in PATH, b in PATH) {
+        let joined = path(&a).join(&path(&b));
+        let expected = if a.is_empty() {
+            b.clone()
+        } else if b.is_empty() {
+            a.clone()
+        } else {
+            format!("{}/{}", a, b)
+        };
+
+        prop_assert_eq!(joined.to_string(), expected);
+        prop_assert_eq!(
+            joined.parts().len(),
+            path(&a).parts().len() + path(&b).parts().len()
+        );
+        prop_assert!(joined.starts_with(&path(&a)));
+    }
+
+    #[test]
+    fn starts_with_matches_string(a in PATH, b in PATH) {
+        prop_assert_eq!(path(&a).starts_with(&path(&b)), a.starts_with(&b));
+    }
+}
@@ -0,0 +1,128 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A virtual clock and fault schedules for mock servers.
+//!
+//! Mock servers consult a [`FaultSchedule`] for every request they receive to
+//! decide whether to fail it. Schedules can depend on the number of requests
+//! seen or on a [`VirtualClock`] that only moves when a test advances it, so
+//! tests of retries and outages never rely on real time passing.
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A clock that starts at zero and only moves when advanced.
+#[derive(Clone, Debug, Default)]
+pub struct VirtualClock {
+    millis: Arc<AtomicU64>,
+}
+
+impl VirtualClock {
+    pub fn new() -> VirtualClock {
+        Default::default()
+    }
+
+    /// The time elapsed since the clock was created.
+    pub fn now(&self) -> Duration {
+        Duration::from_millis(self.millis.load(Ordering::SeqCst))
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.millis
+            .fetch_add(by.as_millis() as u64, Ordering::SeqCst);
+    }
+}
+
+/// A failure that a mock server responds to a request with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fault {
+    /// The service is unavailable and asks to be retried after a delay.
+    Unavailable(Duration),
+    /// An unexpected internal error.
+    ServerError,
+    /// The request took too long to arrive.
+    Timeout,
+    /// The authorization used for the request has expired.
+    ExpiredAuth,
+    /// A usage cap on the account has been reached.
+    CapExceeded,
+    /// The request is never answered, like a connection that has hung.
+    Stall,
+}
+
+type FaultFn = dyn Fn(usize, &str, Duration) -> Option<Fault> + Send + Sync;
+
+/// Decides which requests a mock server fails.
+///
+/// Requests are numbered from 1 in the order the server sees them.
+#[derive(Clone)]
+pub enum FaultSchedule {
+    /// Never fail.
+    Never,
+    /// Fail every nth request.
+    Every(usize, Fault),
+    /// Fail the requests with the given numbers.
+    At(Vec<(usize, Fault)>),
+    /// Fail every request until the clock reaches the given time.
+    Until(Duration, Fault),
+    /// Calls a function with the request number, the request path and the
+    /// current time.
+    Custom(Arc<FaultFn>),
+}
+
+impl Default for FaultSchedule {
+    fn default() -> FaultSchedule {
+        FaultSchedule::Never
+    }
+}
+
+impl fmt::Debug for FaultSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FaultSchedule::Never => write!(f, "Never"),
+            FaultSchedule::Every(n, fault) => write!(f, "Every({}, {:?})", n, fault),
+            FaultSchedule::At(faults) => write!(f, "At({:?})", faults),
+            FaultSchedule::Until(time, fault) => write!(f, "Until({:?}, {:?})", time, fault),
+            FaultSchedule::Custom(_) => write!(f, "Custom"),
+        }
+    }
+}
+
+impl FaultSchedule {
+    pub fn fault(&self, request: usize, path: &str, clock: &VirtualClock) -> Option<Fault> {
+        match self {
+            FaultSchedule::Never => None,
+            FaultSchedule::Every(n, fault) => {
+                if request % n == 0 {
+                    Some(*fault)
+                } else {
+                    None
+                }
+            }
+            FaultSchedule::At(faults) => faults
+                .iter()
+                .find(|(r, _)| *r == request)
+                .map(|(_, fault)| *fault),
+            FaultSchedule::Until(time, fault) => {
+                if clock.now() < *time {
+                    Some(*fault)
+                } else {
+                    None
+                }
+            }
+            FaultSchedule::Custom(func) => func(request, path, clock.now()),
+        }
+    }
+}
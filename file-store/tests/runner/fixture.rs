@@ -0,0 +1,133 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Declarative descriptions of the files that tests run against.
+//!
+//! ```ignore
+//! tree! {
+//!     "dir1" => {
+//!         "smallfile.txt" => text("A short file."),
+//!         "largefile" => generated(0, 100 * MB).modified(LARGE_FILE_MODIFIED()),
+//!         "empty" => {},
+//!     },
+//! }
+//! .create(&root)?;
+//! ```
+use std::fs::{create_dir_all, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::SystemTime;
+
+use filetime::{set_file_mtime, FileTime};
+
+use super::utils::ContentIterator;
+use super::{IntoTestResult, TestResult};
+
+const WRITE_CHUNK: usize = 64 * 1024;
+
+/// The content of a file.
+#[derive(Clone, Debug)]
+pub enum Content {
+    Bytes(Vec<u8>),
+    /// Generated by a [`ContentIterator`](../utils/struct.ContentIterator.html).
+    Generated(u8, u64),
+}
+
+/// A file or directory in a tree.
+#[derive(Clone, Debug)]
+pub enum Node {
+    File(Content, Option<SystemTime>),
+    Directory(Vec<(String, Node)>),
+}
+
+/// A file containing the given text.
+pub fn text(text: &str) -> Node {
+    Node::File(Content::Bytes(text.as_bytes().to_owned()), None)
+}
+
+/// A file containing `length` bytes of generated content.
+pub fn generated(seed: u8, length: u64) -> Node {
+    Node::File(Content::Generated(seed, length), None)
+}
+
+/// An empty file.
+pub fn empty() -> Node {
+    Node::File(Content::Bytes(Vec::new()), None)
+}
+
+impl Node {
+    /// Sets the modification time of a file.
+    pub fn modified(self, time: SystemTime) -> Node {
+        match self {
+            Node::File(content, _) => Node::File(content, Some(time)),
+            Node::Directory(_) => panic!("Cannot set the modification time of a directory."),
+        }
+    }
+
+    /// Creates this node at the given path.
+    pub fn create(&self, target: &Path) -> TestResult<()> {
+        match self {
+            Node::Directory(entries) => {
+                create_dir_all(target).into_test_result()?;
+                for (name, node) in entries {
+                    node.create(&target.join(name))?;
+                }
+            }
+            Node::File(content, modified) => {
+                let mut writer = BufWriter::new(File::create(target).into_test_result()?);
+                match content {
+                    Content::Bytes(bytes) => writer.write_all(bytes).into_test_result()?,
+                    Content::Generated(seed, length) => {
+                        let mut iterator = ContentIterator::new(*seed, *length);
+                        let mut buffer: Vec<u8> = Vec::with_capacity(WRITE_CHUNK);
+                        loop {
+                            buffer.clear();
+                            buffer.extend(iterator.by_ref().take(WRITE_CHUNK));
+                            if buffer.is_empty() {
+                                break;
+                            }
+                            writer.write_all(&buffer).into_test_result()?;
+                        }
+                    }
+                }
+                writer.flush().into_test_result()?;
+
+                if let Some(time) = modified {
+                    set_file_mtime(target, FileTime::from_system_time(*time)).into_test_result()?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Builds a directory [`Node`](enum.Node.html) from a list of named entries.
+///
+/// Each entry is either `name => { ... }` for a directory or `name => node`
+/// where `node` is any expression giving a [`Node`](enum.Node.html).
+macro_rules! tree {
+    (@entries [$($done:tt)*]) => {
+        crate::runner::fixture::Node::Directory(vec![$($done)*])
+    };
+    (@entries [$($done:tt)*] $name:expr => { $($inner:tt)* } $(, $($rest:tt)*)?) => {
+        tree!(@entries [$($done)* ($name.to_owned(), tree!($($inner)*)),] $($($rest)*)?)
+    };
+    (@entries [$($done:tt)*] $name:expr => $node:expr $(, $($rest:tt)*)?) => {
+        tree!(@entries [$($done)* ($name.to_owned(), $node),] $($($rest)*)?)
+    };
+    ($($entries:tt)*) => {
+        tree!(@entries [] $($entries)*)
+    };
+}
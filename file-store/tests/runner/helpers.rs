@@ -0,0 +1,407 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::io::{self, Cursor};
+
+use futures::stream::{iter, Stream, TryStreamExt};
+
+use super::utils::*;
+use super::*;
+
+use file_store::backends::Backend;
+use file_store::transform::transfer;
+use file_store::usage::DirectoryUsage;
+use file_store::*;
+
+async fn read_all(fs: &FileStore, path: ObjectPath) -> TestResult<Vec<u8>> {
+    Ok(fs
+        .get_file_stream(path)
+        .await?
+        .map_ok(|data| data.to_vec())
+        .try_concat()
+        .await?)
+}
+
+async fn collect(stream: DataStream) -> TestResult<Vec<u8>> {
+    Ok(stream.map_ok(|data| data.to_vec()).try_concat().await?)
+}
+
+pub async fn test_collect_objects(fs: &FileStore, context: &TestContext) -> TestResult<()> {
+    let dir2 = context.get_path("test1/dir1/dir2/");
+
+    let objects = fs
+        .list_objects(dir2.clone())
+        .await?
+        .collect_all(None)
+        .await?;
+    test_assert_eq!(objects.len(), 8);
+
+    let objects = fs
+        .list_objects(dir2.clone())
+        .await?
+        .collect_all(Some(3))
+        .await?;
+    test_assert_eq!(objects.len(), 3);
+
+    let found = fs
+        .list_objects(context.get_path("test1/dir1/"))
+        .await?
+        .first_match(|o| o.len() == 300)
+        .await?;
+    match found {
+        Some(object) => test_assert_eq!(object.path(), context.get_path("test1/dir1/dir2/daz")),
+        None => test_fail!("Should have found a match."),
+    }
+
+    let found = fs
+        .list_objects(dir2)
+        .await?
+        .first_match(|o| o.object_type() == ObjectType::Directory)
+        .await?;
+    test_assert!(found.is_none());
+
+    Ok(())
+}
+
+pub async fn test_summarize(fs: &FileStore, context: &TestContext) -> TestResult<()> {
+    fn usage(path: &str, depth: usize, files: u64, bytes: u64) -> TestResult<DirectoryUsage> {
+        Ok(DirectoryUsage {
+            path: ObjectPath::new(path)?,
+            depth,
+            files,
+            bytes,
+        })
+    }
+
+    let total = 27 + 105 * MB + 300;
+    let expected = if fs.backend_type() == Backend::File {
+        vec![
+            usage("", 0, 16, total)?,
+            usage("dir2", 1, 8, 300)?,
+            usage("maybedir", 1, 5, 0)?,
+        ]
+    } else {
+        // Without real directories maybedir is just an empty file.
+        vec![usage("", 0, 12, total)?, usage("dir2", 1, 8, 300)?]
+    };
+    test_assert_eq!(
+        fs.summarize(context.get_path("test1/dir1/"), 1).await?,
+        expected
+    );
+
+    if fs.backend_type() == Backend::File {
+        test_assert_eq!(
+            fs.summarize(context.get_path("test1/dir1/maybedir/"), 2)
+                .await?,
+            vec![usage("", 0, 5, 0)?, usage("foobar", 1, 2, 0)?]
+        );
+    }
+
+    test_assert_eq!(
+        fs.summarize(context.get_path("test1/dir1/dir2"), 0).await?,
+        vec![usage("", 0, 8, 300)?]
+    );
+
+    test_assert_eq!(
+        fs.summarize(context.get_path("test1/dir1/missing"), 3)
+            .await?,
+        vec![usage("", 0, 0, 0)?]
+    );
+
+    Ok(())
+}
+
+pub async fn test_blocking_sources(fs: &FileStore, context: &TestContext) -> TestResult<()> {
+    let path = context.get_path("test1/dir1/iter.txt");
+    let chunks = vec![
+        Ok(Data::from_static(b"Hello ")),
+        Ok(Data::from_static(b"world")),
+    ];
+    fs.write_file_from_iter(path.clone(), chunks).await?;
+    test_assert_eq!(read_all(fs, path).await?, b"Hello world".to_vec());
+
+    let path = context.get_path("test1/dir1/reader");
+    let data = vec![7u8; 3 * MB as usize + 5];
+    fs.write_file_from_reader(path.clone(), Cursor::new(data.clone()))
+        .await?;
+    test_assert_eq!(read_all(fs, path).await?, data);
+
+    let failing = vec![
+        Ok(Data::from_static(b"Partial")),
+        Err(io::Error::new(io::ErrorKind::Other, "Broken source")),
+    ];
+    let result = fs
+        .write_file_from_iter(context.get_path("test1/dir1/failed.txt"), failing)
+        .await;
+    test_assert!(result.is_err(), "Should have seen the iterator's error.");
+
+    Ok(())
+}
+
+pub async fn test_handle(fs: &FileStore, context: &TestContext) -> TestResult<()> {
+    let path = context.get_path("test1/dir1/smallfile.txt");
+
+    let small = fs.object(path.clone())?;
+    test_assert_eq!(small.path(), &path);
+    test_assert_eq!(small.metadata().await?.len(), 27);
+    test_assert_eq!(
+        collect(small.read().await?).await?,
+        b"This is quite a short file.".to_vec()
+    );
+    test_assert_eq!(
+        collect(small.read_range(5..13).await?).await?,
+        b"is quite".to_vec()
+    );
+    test_assert_eq!(
+        collect(small.read_range(20..100).await?).await?,
+        b"t file.".to_vec()
+    );
+    test_assert_eq!(
+        collect(small.read_range(30..40).await?).await?,
+        Vec::<u8>::new()
+    );
+
+    let object = fs.get_object(path.clone()).await?;
+    test_assert_eq!(
+        collect(fs.get_object_range(&object, 5..13).await?).await?,
+        b"is quite".to_vec()
+    );
+
+    let listed = fs.handle_for(object);
+    test_assert_eq!(listed.path(), &path);
+    test_assert_eq!(listed.object().map(|o| o.len()), Some(27));
+    test_assert_eq!(
+        collect(listed.read_range(5..13).await?).await?,
+        b"is quite".to_vec()
+    );
+
+    let new = fs.object(context.get_path("test1/dir1/dir2/new"))?;
+    new.write("Some data").await?;
+    test_assert_eq!(collect(new.read().await?).await?, b"Some data".to_vec());
+
+    new.copy_to(context.get_path("test1/dir1/dir2/copied"))
+        .await?;
+    test_assert_eq!(
+        fs.object(context.get_path("test1/dir1/dir2/copied"))?
+            .metadata()
+            .await?
+            .len(),
+        9
+    );
+    new.move_to(context.get_path("test1/dir1/dir2/moved"))
+        .await?;
+    test_assert!(
+        new.metadata().await.is_err(),
+        "Should have moved the file away."
+    );
+
+    let moved = fs.object(context.get_path("test1/dir1/dir2/moved"))?;
+    moved.delete().await?;
+    test_assert!(
+        moved.metadata().await.is_err(),
+        "Should have deleted the file."
+    );
+
+    Ok(())
+}
+
+pub async fn test_read_parallel(fs: &FileStore, context: &TestContext) -> TestResult<()> {
+    let expected: Vec<u8> = ContentIterator::new(58, 5 * MB).collect();
+    let mut parts: Vec<(u64, Data)> = fs
+        .object(context.get_path("test1/dir1/mediumfile"))?
+        .read_parallel(MB, 3)
+        .await?
+        .try_collect()
+        .await?;
+    test_assert_eq!(parts.len(), 5);
+
+    parts.sort_by_key(|(offset, _)| *offset);
+    let mut data = Vec::new();
+    for (offset, part) in parts {
+        test_assert_eq!(offset, data.len() as u64);
+        data.extend_from_slice(&part);
+    }
+    test_assert!(data == expected, "Should have read the whole file.");
+
+    let chunks: Vec<(u64, Data)> = fs
+        .get_file_stream(context.get_path("test1/dir1/smallfile.txt"))
+        .await?
+        .with_offsets(10)
+        .try_collect()
+        .await?;
+    test_assert_eq!(chunks[0].0, 10);
+
+    Ok(())
+}
+
+pub async fn test_compose(fs: &FileStore, context: &TestContext) -> TestResult<()> {
+    let first = context.get_path("test1/dir1/dir2/foo");
+    let second = context.get_path("test1/dir1/dir2/bar");
+    let joined = context.get_path("test1/dir1/joined");
+    fs.write_file_from_iter(first.clone(), vec![Ok(Data::from_static(b"First "))])
+        .await?;
+    fs.write_file_from_iter(second.clone(), vec![Ok(Data::from_static(b"second"))])
+        .await?;
+
+    fs.compose(
+        vec![
+            first.clone(),
+            second,
+            context.get_path("test1/dir1/dir2/hop"),
+        ],
+        joined.clone(),
+    )
+    .await?;
+    test_assert_eq!(
+        read_all(fs, joined.clone()).await?,
+        b"First second".to_vec()
+    );
+    test_assert_eq!(
+        fs.get_object(first.clone()).await?.len(),
+        6,
+        "Should have kept the sources."
+    );
+
+    // The target may also be one of the sources.
+    fs.compose(vec![joined.clone(), first.clone()], joined.clone())
+        .await?;
+    test_assert_eq!(read_all(fs, joined).await?, b"First secondFirst ".to_vec());
+
+    let result = fs
+        .compose(
+            vec![first, context.get_path("test1/dir1/missing")],
+            context.get_path("test1/dir1/broken"),
+        )
+        .await;
+    match result {
+        Err(TransferError::SourceError(_)) => (),
+        _ => test_fail!("Should have failed to read the missing source."),
+    }
+
+    Ok(())
+}
+
+pub async fn test_restore_object(fs: &FileStore, context: &TestContext) -> TestResult<()> {
+    let path = context.get_path("test1/dir1/smallfile.txt");
+
+    // None of the backends archive so restoring just checks the object exists.
+    fs.restore_object(path.clone(), 7).await?;
+    test_assert_eq!(
+        fs.get_object(path).await?.restore_status(),
+        RestoreStatus::Available
+    );
+
+    match fs
+        .restore_object(context.get_path("test1/dir1/missing"), 7)
+        .await
+    {
+        Ok(()) => test_fail!("Should have failed to restore a missing file."),
+        Err(e) => match e.kind() {
+            StorageErrorKind::NotFound(_) => (),
+            _ => test_fail!("Unexpected error: {}", e),
+        },
+    }
+
+    Ok(())
+}
+
+pub async fn test_transform(fs: &FileStore, context: &TestContext) -> TestResult<()> {
+    fn upper(_: &ObjectPath, stream: DataStream) -> impl Stream<Item = StorageResult<Data>> {
+        stream.map_ok(|data| Data::from(data.to_ascii_uppercase()))
+    }
+
+    fn lower(_: &ObjectPath, stream: DataStream) -> impl Stream<Item = StorageResult<Data>> {
+        stream.map_ok(|data| Data::from(data.to_ascii_lowercase()))
+    }
+
+    let small = context.get_path("test1/dir1/smallfile.txt");
+    let written = context.get_path("test1/dir1/written");
+
+    let reads = fs.clone().transform_reads(upper);
+    test_assert_eq!(
+        read_all(&reads, small.clone()).await?,
+        b"THIS IS QUITE A SHORT FILE.".to_vec()
+    );
+
+    let writes = fs.clone().transform_writes(upper);
+    let chunks = vec![Ok::<_, StorageError>(b"Some Data".to_vec())];
+    writes
+        .write_file_from_stream(written.clone(), iter(chunks))
+        .await?;
+    test_assert_eq!(read_all(fs, written.clone()).await?, b"SOME DATA".to_vec());
+    test_assert_eq!(
+        read_all(&writes, written.clone()).await?,
+        b"SOME DATA".to_vec(),
+        "Should not have transformed reads."
+    );
+
+    let copied = context.get_path("test1/dir1/copied");
+    writes.copy_file(small, copied.clone()).await?;
+    test_assert_eq!(
+        read_all(fs, copied).await?,
+        b"This is quite a short file.".to_vec(),
+        "Should not have transformed copies."
+    );
+
+    let transferred = context.get_path("test1/dir1/transferred");
+    transfer(fs, written, fs, transferred.clone(), lower).await?;
+    test_assert_eq!(read_all(fs, transferred).await?, b"some data".to_vec());
+
+    Ok(())
+}
+
+#[cfg(all(feature = "indicatif", feature = "transfers"))]
+pub async fn test_transfer_progress(fs: &FileStore, context: &TestContext) -> TestResult<()> {
+    use indicatif::{ProgressBar, ProgressDrawTarget};
+
+    use file_store::progress::*;
+    use file_store::transfers::*;
+
+    let manager = TransferManager::new();
+    let mut progress = TransferProgress::with_draw_target(&manager, ProgressDrawTarget::hidden());
+    for name in &["foo", "daz", "missing"] {
+        progress.add(
+            &manager,
+            TransferJob::new(
+                fs.clone(),
+                context.get_path(&format!("test1/dir1/dir2/{}", name)),
+                fs.clone(),
+                context.get_path(&format!("test1/dir1/copies/{}", name)),
+            ),
+            None,
+        );
+    }
+
+    // Resolves once every job has either completed or failed.
+    progress.run().await;
+    test_assert_eq!(
+        fs.get_object(context.get_path("test1/dir1/copies/daz"))
+            .await?
+            .len(),
+        300
+    );
+
+    let bar = ProgressBar::hidden();
+    let stream = progress_stream(
+        fs.get_file_stream(context.get_path("test1/dir1/dir2/daz"))
+            .await?,
+        bar.clone(),
+    );
+    let data = stream.map_ok(|data| data.to_vec()).try_concat().await?;
+    test_assert_eq!(data.len(), 300);
+    test_assert_eq!(bar.position(), 300);
+    test_assert!(bar.is_finished());
+
+    Ok(())
+}
@@ -14,17 +14,22 @@
 
 #[macro_use]
 mod utils;
+#[allow(dead_code)]
+pub mod faults;
+#[macro_use]
+#[allow(dead_code)]
+pub mod fixture;
+#[allow(dead_code)]
+pub mod helpers;
 pub mod read;
+#[allow(dead_code)]
 pub mod write;
 
 use std::fmt;
-use std::fs::create_dir_all;
 use std::future::Future;
-use std::iter::empty;
 use std::path::PathBuf;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use filetime::{set_file_mtime, FileTime};
 use futures::future::FutureExt;
 use std::sync::Once;
 use tempfile::{tempdir, TempDir};
@@ -32,6 +37,7 @@ use tokio::executor::spawn as tokio_spawn;
 use tokio::runtime::current_thread::Runtime;
 use tokio::sync::oneshot;
 
+use fixture::{empty, generated, text, Node};
 use utils::*;
 
 use file_store::backends::Backend;
@@ -57,6 +63,8 @@ pub enum TestError {
     UnexpectedTransferError(TransferError),
     HarnessFailure(String),
     TestFailure(String),
+    /// The test cannot run in this environment.
+    Skipped(String),
 }
 
 impl TestError {
@@ -80,6 +88,7 @@ impl fmt::Display for TestError {
             },
             TestError::HarnessFailure(message) => f.pad(message),
             TestError::TestFailure(message) => f.pad(message),
+            TestError::Skipped(reason) => write!(f, "Skipped: {}", reason),
         }
     }
 }
@@ -176,60 +185,61 @@ impl TestContext {
     }
 }
 
+/// The tree of files that most tests run against.
+pub fn test_tree(backend: Backend) -> Node {
+    // Only the file backend has real directories.
+    let maybedir = if backend == Backend::File {
+        tree! {
+            "foo" => empty(),
+            "bar" => empty(),
+            "baz" => empty(),
+            "foobar" => {
+                "foo" => empty(),
+                "bar" => empty(),
+            },
+        }
+    } else {
+        empty()
+    };
+
+    tree! {
+        "test1" => {
+            "dir1" => {
+                "smallfile.txt" => text("This is quite a short file.")
+                    .modified(SMALL_FILE_MODIFIED()),
+                "largefile" => generated(0, 100 * MB).modified(LARGE_FILE_MODIFIED()),
+                "mediumfile" => generated(58, 5 * MB),
+                "maybedir" => maybedir,
+                "dir2" => {
+                    "foo" => empty(),
+                    "bar" => empty(),
+                    "0foo" => empty(),
+                    "5diz" => empty(),
+                    "1bar" => empty(),
+                    "daz" => generated(72, 300),
+                    "hop" => empty(),
+                    "yu" => empty(),
+                },
+            },
+        },
+    }
+}
+
 /// Creates a filesystem used for testing.
 pub fn prepare_test(backend: Backend, test_root: &str) -> TestResult<TestContext> {
-    let temp = tempdir().into_test_result()?;
+    prepare_tree(&test_tree(backend), test_root)
+}
 
-    let mut dir = PathBuf::from(temp.path());
+/// Creates a filesystem from the given tree.
+pub fn prepare_tree(tree: &Node, test_root: &str) -> TestResult<TestContext> {
+    let temp = tempdir().into_test_result()?;
+    tree.create(temp.path())?;
 
-    let context = TestContext {
+    Ok(TestContext {
+        root: PathBuf::from(temp.path()),
         _temp: temp,
-        root: dir.clone(),
         fs_root: test_root.to_owned(),
-    };
-
-    dir.push("test1");
-    dir.push("dir1");
-    create_dir_all(dir.clone()).into_test_result()?;
-
-    let file = write_file(
-        &dir,
-        "smallfile.txt",
-        b"This is quite a short file.".iter().cloned(),
-    )?;
-    set_file_mtime(file, FileTime::from_system_time(SMALL_FILE_MODIFIED())).into_test_result()?;
-    let file = write_file(&dir, "largefile", ContentIterator::new(0, 100 * MB))?;
-    set_file_mtime(file, FileTime::from_system_time(LARGE_FILE_MODIFIED())).into_test_result()?;
-    write_file(&dir, "mediumfile", ContentIterator::new(58, 5 * MB))?;
-
-    if backend == Backend::File {
-        let mut em = dir.clone();
-        em.push("maybedir");
-        create_dir_all(&em).into_test_result()?;
-        write_file(&em, "foo", empty())?;
-        write_file(&em, "bar", empty())?;
-        write_file(&em, "baz", empty())?;
-
-        em.push("foobar");
-        create_dir_all(&em).into_test_result()?;
-        write_file(&em, "foo", empty())?;
-        write_file(&em, "bar", empty())?;
-    } else {
-        write_file(&dir, "maybedir", empty())?;
-    }
-
-    dir.push("dir2");
-    create_dir_all(dir.clone()).into_test_result()?;
-    write_file(&dir, "foo", empty())?;
-    write_file(&dir, "bar", empty())?;
-    write_file(&dir, "0foo", empty())?;
-    write_file(&dir, "5diz", empty())?;
-    write_file(&dir, "1bar", empty())?;
-    write_file(&dir, "daz", ContentIterator::new(72, 300))?;
-    write_file(&dir, "hop", empty())?;
-    write_file(&dir, "yu", empty())?;
-
-    Ok(context)
+    })
 }
 
 macro_rules! make_test {
@@ -240,23 +250,27 @@ macro_rules! make_test {
             let result: crate::runner::TestResult<()> = crate::runner::run(async {
                 let test_context = crate::runner::prepare_test($backend, $root)?;
                 let (fs, backend_context) = $setup(&test_context).await?;
-                crate::runner::$pkg::$name(&fs, &test_context).await?;
+                let result = crate::runner::$pkg::$name(&fs, &test_context).await;
                 $cleanup(backend_context).await?;
-                Ok(())
+                result
             });
 
             match result {
                 Ok(()) => (),
+                Err(crate::runner::TestError::Skipped(reason)) => {
+                    eprintln!("{} skipped: {}", stringify!($name), reason)
+                }
                 Err(error) => panic!(error.to_string()),
             }
         }
     };
 }
 
-macro_rules! build_tests {
+macro_rules! build_read_tests {
     ($root:expr, $backend:expr, $setup:expr, $cleanup:expr) => {
         make_test!($root, $backend, read, test_list_objects, $setup, $cleanup);
         make_test!($root, $backend, read, test_list_directory, $setup, $cleanup);
+        make_test!($root, $backend, read, test_list_prefixes, $setup, $cleanup);
         make_test!($root, $backend, read, test_get_object, $setup, $cleanup);
         make_test!(
             $root,
@@ -274,6 +288,11 @@ macro_rules! build_tests {
             $setup,
             $cleanup
         );
+    };
+}
+
+macro_rules! build_write_tests {
+    ($root:expr, $backend:expr, $setup:expr, $cleanup:expr) => {
         make_test!($root, $backend, write, test_copy_file, $setup, $cleanup);
         make_test!($root, $backend, write, test_move_file, $setup, $cleanup);
         make_test!($root, $backend, write, test_delete_object, $setup, $cleanup);
@@ -287,3 +306,61 @@ macro_rules! build_tests {
         );
     };
 }
+
+macro_rules! build_helper_tests {
+    ($root:expr, $backend:expr, $setup:expr, $cleanup:expr) => {
+        make_test!(
+            $root,
+            $backend,
+            helpers,
+            test_collect_objects,
+            $setup,
+            $cleanup
+        );
+        make_test!($root, $backend, helpers, test_summarize, $setup, $cleanup);
+        make_test!(
+            $root,
+            $backend,
+            helpers,
+            test_blocking_sources,
+            $setup,
+            $cleanup
+        );
+        make_test!($root, $backend, helpers, test_handle, $setup, $cleanup);
+        make_test!(
+            $root,
+            $backend,
+            helpers,
+            test_read_parallel,
+            $setup,
+            $cleanup
+        );
+        make_test!($root, $backend, helpers, test_compose, $setup, $cleanup);
+        make_test!(
+            $root,
+            $backend,
+            helpers,
+            test_restore_object,
+            $setup,
+            $cleanup
+        );
+        make_test!($root, $backend, helpers, test_transform, $setup, $cleanup);
+        #[cfg(all(feature = "indicatif", feature = "transfers"))]
+        make_test!(
+            $root,
+            $backend,
+            helpers,
+            test_transfer_progress,
+            $setup,
+            $cleanup
+        );
+    };
+}
+
+macro_rules! build_tests {
+    ($root:expr, $backend:expr, $setup:expr, $cleanup:expr) => {
+        build_read_tests!($root, $backend, $setup, $cleanup);
+        build_write_tests!($root, $backend, $setup, $cleanup);
+        build_helper_tests!($root, $backend, $setup, $cleanup);
+    };
+}
@@ -202,6 +202,11 @@ pub async fn test_list_objects(fs: &FileStore, context: &TestContext) -> TestRes
 
     test_list(fs, context, "test1/dir1/dir", prefixed.clone()).await?;
 
+    // Prefixes that match nothing list nothing.
+    test_list(fs, context, "test1/dir1/nonexistent/", vec![]).await?;
+    test_list(fs, context, "test1/dir1/nonexistent/dir/", vec![]).await?;
+    test_list(fs, context, "test1/dir1/smallfile.txt/", vec![]).await?;
+
     Ok(())
 }
 
@@ -278,6 +283,56 @@ pub async fn test_list_directory(fs: &FileStore, context: &TestContext) -> TestR
 
     test_list(fs, context, "test1/dir1/dir2", dir2).await?;
 
+    // Directories that don't exist contain nothing.
+    test_list(fs, context, "test1/dir1/nonexistent", vec![]).await?;
+    test_list(fs, context, "test1/dir1/nonexistent/dir", vec![]).await?;
+    test_list(fs, context, "test1/dir1/smallfile.txt", vec![]).await?;
+
+    Ok(())
+}
+
+pub async fn test_list_prefixes(fs: &FileStore, context: &TestContext) -> TestResult<()> {
+    async fn test_list<'a>(
+        fs: &'a FileStore,
+        context: &'a TestContext,
+        path: &'static str,
+        dirs: Vec<&'static str>,
+    ) -> TestResult<()> {
+        if !context.contains(path) {
+            return Ok(());
+        }
+
+        let mut expected_paths: Vec<ObjectPath> = dirs
+            .iter()
+            .filter(|path| {
+                context.contains(path) && context.get_target(&context.get_path(path)).is_dir()
+            })
+            .map(|path| context.get_path(path))
+            .collect();
+
+        let mut results = fs
+            .list_prefixes(context.get_path(path))
+            .await?
+            .map_ok(|object| object.path())
+            .try_collect::<Vec<ObjectPath>>()
+            .await?;
+        results.sort();
+        expected_paths.sort();
+
+        test_assert_eq!(
+            results,
+            expected_paths,
+            "Should have seen only the directories."
+        );
+
+        Ok(())
+    }
+
+    let dir1 = vec!["test1/dir1/dir2", "test1/dir1/maybedir"];
+    test_list(fs, context, "test1/dir1", dir1).await?;
+    test_list(fs, context, "test1/dir1/dir2", vec![]).await?;
+    test_list(fs, context, "test1/dir1/nonexistent", vec![]).await?;
+
     Ok(())
 }
 
@@ -289,6 +344,13 @@ pub async fn test_get_object(fs: &FileStore, context: &TestContext) -> TestResul
         test_assert_eq!(&result.path(), &path, "Should have seen the right path.");
         test_file_matches(&context.get_target(&path), result)?;
 
+        match fs.get_object_opt(path.clone()).await? {
+            Some(result) => {
+                test_assert_eq!(&result.path(), &path, "Should have seen the right path.")
+            }
+            None => test_fail!("Should have found {}.", path),
+        }
+
         Ok(())
     }
 
@@ -299,11 +361,17 @@ pub async fn test_get_object(fs: &FileStore, context: &TestContext) -> TestResul
         if let Err(e) = result {
             test_assert_eq!(
                 e.kind(),
-                StorageErrorKind::NotFound(fspath),
+                StorageErrorKind::NotFound(fspath.clone()),
                 "Should have returned a NotFound error."
             );
         }
 
+        test_assert!(
+            fs.get_object_opt(fspath.clone()).await?.is_none(),
+            "Should not have found {}.",
+            fspath
+        );
+
         Ok(())
     }
 
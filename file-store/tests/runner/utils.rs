@@ -12,9 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::fs::File;
-use std::io::{BufWriter, Error, Write};
-use std::path::PathBuf;
+use std::io::Error;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
@@ -23,8 +21,6 @@ use futures::stream::Stream;
 
 use file_store::*;
 
-use super::{IntoTestResult, TestResult};
-
 pub const MB: u64 = 1024 * 1024;
 
 macro_rules! test_fail {
@@ -160,26 +156,3 @@ impl Iterator for ContentIterator {
         Some(self.value)
     }
 }
-
-pub fn write_file<I>(dir: &PathBuf, name: &str, content: I) -> TestResult<PathBuf>
-where
-    I: IntoIterator<Item = u8>,
-{
-    let mut target = dir.clone();
-    target.push(name);
-
-    let file = File::create(&target).into_test_result()?;
-    let mut writer = BufWriter::new(file);
-
-    for b in content {
-        loop {
-            if writer.write(&[b]).into_test_result()? == 1 {
-                break;
-            }
-        }
-    }
-
-    writer.flush().into_test_result()?;
-
-    Ok(target)
-}
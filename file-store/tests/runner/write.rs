@@ -13,9 +13,12 @@
 // limitations under the License.
 
 use std::fs::{symlink_metadata, File};
-use std::io::{BufReader, ErrorKind, Read};
+use std::io::{BufReader, Error, ErrorKind, Read};
 use std::path::Path;
 
+use futures::future::ready;
+use futures::stream::{once, StreamExt};
+
 use super::utils::*;
 use super::*;
 
@@ -147,6 +150,7 @@ pub async fn test_copy_file(fs: &FileStore, context: &TestContext) -> TestResult
         UploadInfo {
             path: context.get_path("test1/dir1/testfile"),
             modified: None,
+            options: Default::default(),
         },
         58,
         5 * MB,
@@ -159,6 +163,7 @@ pub async fn test_copy_file(fs: &FileStore, context: &TestContext) -> TestResult
         UploadInfo {
             path: context.get_path("test1/dir1/dir2/hop"),
             modified: None,
+            options: Default::default(),
         },
         0,
         100 * MB,
@@ -171,6 +176,7 @@ pub async fn test_copy_file(fs: &FileStore, context: &TestContext) -> TestResult
         UploadInfo {
             path: context.get_path("test1/dir1/bazza"),
             modified: Some(UNIX_EPOCH + Duration::from_millis(1_703_257_714)),
+            options: Default::default(),
         },
         72,
         300,
@@ -257,6 +263,7 @@ pub async fn test_move_file(fs: &FileStore, context: &TestContext) -> TestResult
         UploadInfo {
             path: context.get_path("test1/dir1/testfile"),
             modified: Some(UNIX_EPOCH + Duration::from_millis(1_703_257_714)),
+            options: Default::default(),
         },
         58,
         5 * MB,
@@ -269,6 +276,7 @@ pub async fn test_move_file(fs: &FileStore, context: &TestContext) -> TestResult
         UploadInfo {
             path: context.get_path("test1/dir1/dir2/hop"),
             modified: None,
+            options: Default::default(),
         },
         0,
         100 * MB,
@@ -281,6 +289,7 @@ pub async fn test_move_file(fs: &FileStore, context: &TestContext) -> TestResult
         UploadInfo {
             path: context.get_path("test1/dir1/bazza"),
             modified: None,
+            options: Default::default(),
         },
         72,
         300,
@@ -375,12 +384,45 @@ pub async fn test_write_file_from_stream(fs: &FileStore, context: &TestContext)
         Ok(())
     }
 
+    async fn test_write_fail(fs: &FileStore, context: &TestContext, path: &str) -> TestResult<()> {
+        let remote_target = context.get_path(path);
+        let local_target = context.get_target(&remote_target);
+
+        let mut target = UploadInfo::from(remote_target.clone());
+        target.options.delete_on_failure = true;
+
+        let stream = stream_iterator(ContentIterator::new(12, 300), 30).chain(once(ready(Err(
+            Error::new(ErrorKind::Other, "Source failed."),
+        ))));
+        let result = fs.write_file_from_stream(target, stream).await;
+
+        match result {
+            Err(TransferError::SourceError(_)) => (),
+            Err(e) => test_fail!("Should have received a source error: {:?}", e),
+            Ok(()) => test_fail!("Expected to fail to write {}.", remote_target),
+        }
+
+        let result = symlink_metadata(local_target);
+        match result {
+            Err(e) => test_assert_eq!(
+                e.kind(),
+                ErrorKind::NotFound,
+                "File {} should not exist.",
+                remote_target
+            ),
+            Ok(_) => test_fail!("File {} should not exist.", remote_target),
+        }
+
+        Ok(())
+    }
+
     test_write(
         fs,
         context,
         UploadInfo {
             path: context.get_path("test1/dir1/foobar"),
             modified: Some(UNIX_EPOCH + Duration::from_millis(1_703_257_714)),
+            options: Default::default(),
         },
         58,
         300,
@@ -392,6 +434,7 @@ pub async fn test_write_file_from_stream(fs: &FileStore, context: &TestContext)
         UploadInfo {
             path: context.get_path("test1/dir1/maybedir"),
             modified: None,
+            options: Default::default(),
         },
         27,
         500,
@@ -403,11 +446,13 @@ pub async fn test_write_file_from_stream(fs: &FileStore, context: &TestContext)
         UploadInfo {
             path: context.get_path("test1/dir1/dir2/daz"),
             modified: None,
+            options: Default::default(),
         },
         27,
         100 * MB,
     )
     .await?;
+    test_write_fail(fs, context, "test1/dir1/failed").await?;
 
     Ok(())
 }
@@ -33,6 +33,12 @@ pub mod v2 {
     pub const B2_HEADER_FILE_NAME: &str = "X-Bz-File-Name";
     pub const B2_HEADER_CONTENT_SHA1: &str = "X-Bz-Content-Sha1";
     pub const B2_HEADER_PART_NUMBER: &str = "X-Bz-Part-Number";
+    pub const B2_HEADER_SSE: &str = "X-Bz-Server-Side-Encryption";
+    pub const B2_HEADER_SSE_C_ALGORITHM: &str = "X-Bz-Server-Side-Encryption-Customer-Algorithm";
+    pub const B2_HEADER_SSE_C_KEY: &str = "X-Bz-Server-Side-Encryption-Customer-Key";
+    pub const B2_HEADER_SSE_C_KEY_MD5: &str = "X-Bz-Server-Side-Encryption-Customer-Key-Md5";
+
+    pub const SSE_ALGORITHM: &str = "AES256";
 
     pub const LAST_MODIFIED_KEY: &str = "src_last_modified_millis";
 
@@ -14,11 +14,23 @@
 
 use serde::{Deserialize, Serialize};
 
-use super::{BucketTypes, Int, UserFileInfo};
+use super::responses::CorsRule;
+use super::{BucketType, BucketTypes, Int, Map, UserFileInfo};
 
 pub const B2_API_HOST: &str = "https://api.backblazeb2.com";
 pub const B2_VERSION: &str = "v2";
 
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerSideEncryption {
+    pub mode: String,
+    pub algorithm: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub customer_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub customer_key_md5: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ListBucketsRequest {
@@ -28,6 +40,21 @@ pub struct ListBucketsRequest {
     pub bucket_types: BucketTypes,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateBucketRequest {
+    pub account_id: String,
+    pub bucket_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bucket_type: Option<BucketType>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bucket_info: Option<Map>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cors_rules: Option<Vec<CorsRule>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub if_revision_is: Option<Int>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct GetFileInfoRequest {
@@ -62,6 +89,13 @@ pub struct DeleteFileVersionRequest {
     pub file_id: String,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HideFileRequest {
+    pub bucket_id: String,
+    pub file_name: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct GetUploadUrlRequest {
@@ -75,6 +109,8 @@ pub struct StartLargeFileRequest {
     pub file_name: String,
     pub content_type: String,
     pub file_info: Option<UserFileInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_side_encryption: Option<ServerSideEncryption>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -83,9 +119,50 @@ pub struct GetUploadPartUrlRequest {
     pub file_id: String,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CopyPartRequest {
+    pub source_file_id: String,
+    pub large_file_id: String,
+    pub part_number: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub range: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct FinishLargeFileRequest {
     pub file_id: String,
     pub part_sha1_array: Vec<String>,
 }
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ListUnfinishedLargeFilesRequest {
+    pub bucket_id: String,
+    pub name_prefix: Option<String>,
+    pub start_file_id: Option<String>,
+    pub max_file_count: Option<Int>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ListPartsRequest {
+    pub file_id: String,
+    pub start_part_number: Option<Int>,
+    pub max_part_count: Option<Int>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CancelLargeFileRequest {
+    pub file_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetDownloadAuthorizationRequest {
+    pub bucket_id: String,
+    pub file_name_prefix: String,
+    pub valid_duration_in_seconds: Int,
+}
@@ -56,12 +56,14 @@ pub struct LifecycleRule {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct CorsRule {
-    cors_rule_name: String,
-    allowed_origins: Vec<String>,
-    allowed_operations: Vec<String>,
-    allowed_headers: Option<Vec<String>>,
-    expose_headers: Option<Vec<String>>,
-    max_age_seconds: Int,
+    pub cors_rule_name: String,
+    pub allowed_origins: Vec<String>,
+    pub allowed_operations: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allowed_headers: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expose_headers: Option<Vec<String>>,
+    pub max_age_seconds: Int,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -83,6 +85,17 @@ pub struct ListBucketsResponse {
     pub buckets: Vec<Bucket>,
 }
 
+pub type UpdateBucketResponse = Bucket;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct FileEncryption {
+    #[serde(default)]
+    pub mode: Option<String>,
+    #[serde(default)]
+    pub algorithm: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct FileInfo {
@@ -95,6 +108,8 @@ pub struct FileInfo {
     pub file_id: Option<String>,
     pub file_info: UserFileInfo,
     pub file_name: String,
+    #[serde(default)]
+    pub server_side_encryption: Option<FileEncryption>,
     pub upload_timestamp: Int,
 }
 
@@ -133,6 +148,8 @@ pub struct DeleteFileVersionResponse {
     pub file_id: String,
 }
 
+pub type HideFileResponse = FileInfo;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct GetUploadUrlResponse {
@@ -163,4 +180,37 @@ pub struct UploadPartResponse {
     pub upload_timestamp: Int,
 }
 
+pub type CopyPartResponse = UploadPartResponse;
+
 pub type FinishLargeFileResponse = FileInfo;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ListUnfinishedLargeFilesResponse {
+    pub files: Vec<FileInfo>,
+    pub next_file_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ListPartsResponse {
+    pub parts: Vec<UploadPartResponse>,
+    pub next_part_number: Option<Int>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CancelLargeFileResponse {
+    pub file_id: String,
+    pub account_id: String,
+    pub bucket_id: String,
+    pub file_name: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetDownloadAuthorizationResponse {
+    pub bucket_id: String,
+    pub file_name_prefix: String,
+    pub authorization_token: String,
+}